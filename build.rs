@@ -0,0 +1,28 @@
+// Copyright (c) 2024-2025, The tinyavif contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+// Record which compiler built this binary, so that it can be reported by
+// `tinyavif --capabilities` (see src/buildinfo.rs)
+
+use std::env;
+use std::process::Command;
+
+fn main() {
+  let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".into());
+  let version = Command::new(rustc)
+    .arg("--version")
+    .output()
+    .ok()
+    .and_then(|output| String::from_utf8(output.stdout).ok())
+    .map(|version| version.trim().to_string())
+    .unwrap_or_else(|| "unknown".into());
+
+  println!("cargo:rustc-env=TINYAVIF_RUSTC_VERSION={}", version);
+  println!("cargo:rerun-if-env-changed=RUSTC");
+}
@@ -0,0 +1,22 @@
+// Copyright (c) 2024-2025, The tinyavif contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+// Parse arbitrary data as a Y4M stream header. Any input must either parse
+// or produce an error, never a panic.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use tinyavif::y4m::Y4MReader;
+
+fuzz_target!(|data: &[u8]| {
+  if let Ok(header) = Y4MReader::parse_header(data) {
+    assert!(header.width > 0 && header.height > 0);
+  }
+});
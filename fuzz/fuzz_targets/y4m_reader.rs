@@ -0,0 +1,33 @@
+// Copyright (c) 2024-2025, The tinyavif contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+// Read every frame from an arbitrary Y4M stream, checking the frame headers
+// as well as the stream header
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use tinyavif::y4m::Y4MReader;
+
+fuzz_target!(|data: &[u8]| {
+  let Ok(mut reader) = Y4MReader::new(data) else {
+    return;
+  };
+
+  // Each frame is allocated before its pixels are read, so skip headers which
+  // declare frames larger than the input could possibly contain. Otherwise the
+  // fuzzer spends its time allocating frames which are immediately discarded
+  let header = reader.header();
+  let frame_size = header.width * header.height * 3 / 2;
+  if frame_size > data.len() {
+    return;
+  }
+
+  while reader.read_frame().is_ok() {}
+});
@@ -0,0 +1,235 @@
+// Copyright (c) 2024-2025, The tinyavif contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+// First-pass analysis, for two-pass encoding
+//
+// The first pass measures the "activity" (texture) of each superblock. The second pass
+// then uses this to adjust the quantizer per superblock: heavily textured areas hide
+// compression artifacts well, so can use a higher qindex, while flat areas show
+// artifacts clearly, so get a lower qindex. The average qindex stays roughly the same.
+//
+// It also decides where to search for a better luma transform than the DCT. The only
+// alternative in the reduced transform set for our 8x8 luma blocks is the identity
+// transform, which only wins on sharp detail such as text and line art, so it's only
+// tried in superblocks which are busier than average.
+//
+// The stats can be saved to a file, and loaded again to encode the same image without
+// repeating the analysis, or with hand-edited stats to steer the second pass.
+//
+// The same measure is used for activity masking with --tune ssim (see masking_weight()).
+
+use std::fmt::Write as _;
+
+use crate::array2d::Array2D;
+use crate::config::ConfigError;
+use crate::frame::Frame;
+use crate::ratecost::{log2_fixed, COST_SHIFT};
+use crate::util::*;
+
+const STATS_FILE_MAGIC: &str = "tinyavif-firstpass 1";
+
+// Change in qindex per doubling of a superblock's activity, relative to the average
+const DELTA_Q_PER_DOUBLING: i32 = 6;
+
+// Largest change in qindex that we apply to any superblock
+const MAX_DELTA_Q: i32 = 32;
+
+#[derive(Clone)]
+pub struct FirstPassStats {
+  // Average variance of the 8x8 luma blocks in each superblock
+  pub activity: Array2D<u32>,
+}
+
+// Variance of a single 8x8 block, times 64
+fn block_variance(pixels: &Array2D<u8>, y0: usize, x0: usize) -> u32 {
+  let mut sum = 0u32;
+  let mut sum_sq = 0u32;
+  for i in 0..8 {
+    for &p in &pixels[y0 + i][x0 .. x0 + 8] {
+      sum += p as u32;
+      sum_sq += (p as u32) * (p as u32);
+    }
+  }
+  return sum_sq - (sum * sum) / 64;
+}
+
+pub fn analyze(source: &Frame) -> FirstPassStats {
+  let pixels = source.y().pixels();
+  let block_rows = pixels.rows() / 8;
+  let block_cols = pixels.cols() / 8;
+  let sb_rows = block_rows.div_ceil(8);
+  let sb_cols = block_cols.div_ceil(8);
+
+  let mut activity = Array2D::zeroed(sb_rows, sb_cols);
+  for sb_row in 0..sb_rows {
+    for sb_col in 0..sb_cols {
+      let mut total = 0u64;
+      let mut count = 0u64;
+      for block_row in sb_row * 8 .. min(sb_row * 8 + 8, block_rows) {
+        for block_col in sb_col * 8 .. min(sb_col * 8 + 8, block_cols) {
+          total += (block_variance(pixels, block_row * 8, block_col * 8) / 64) as u64;
+          count += 1;
+        }
+      }
+      activity[sb_row][sb_col] = (total / count) as u32;
+    }
+  }
+
+  FirstPassStats {
+    activity: activity
+  }
+}
+
+impl FirstPassStats {
+  // log2 of each superblock's activity, and the mean of those, in the fixed-point format
+  // of log2_fixed(). We work in the log domain, as the perceived effect of texture is
+  // roughly logarithmic
+  fn log_activity(&self) -> (Array2D<i64>, i64) {
+    let rows = self.activity.rows();
+    let cols = self.activity.cols();
+    let log_activity = Array2D::new_with(rows, cols, |i, j| log2_fixed(self.activity[i][j] + 1) as i64);
+
+    let mut log_sum = 0i64;
+    for i in 0..rows {
+      log_sum += log_activity[i].iter().sum::<i64>();
+    }
+    let log_mean = log_sum / ((rows * cols) as i64);
+    return (log_activity, log_mean);
+  }
+
+  // Choose a qindex for each superblock
+  pub fn qindex_map(&self, base_qindex: u8) -> Array2D<u8> {
+    let (log_activity, log_mean) = self.log_activity();
+    return Array2D::new_with(log_activity.rows(), log_activity.cols(), |i, j| {
+      let log_ratio = log_activity[i][j] - log_mean;
+      let delta = round2_signed(log_ratio * (DELTA_Q_PER_DOUBLING as i64), COST_SHIFT) as i32;
+      let delta = clamp(delta, -MAX_DELTA_Q, MAX_DELTA_Q);
+      clamp(base_qindex as i32 + delta, 1, 255) as u8
+    });
+  }
+
+  // Choose which superblocks should try the identity transform for luma, as well as
+  // the DCT: those which are busier than average
+  pub fn tx_search_map(&self) -> Array2D<bool> {
+    let (log_activity, log_mean) = self.log_activity();
+    return Array2D::new_with(log_activity.rows(), log_activity.cols(), |i, j| log_activity[i][j] > log_mean);
+  }
+
+  // Format stats as a simple text file: a header line, the size in superblocks,
+  // then one line of activity values per superblock row.
+  // Reading and writing the file itself is left to the caller.
+  pub fn to_text(&self) -> String {
+    let mut text = String::new();
+    writeln!(text, "{}", STATS_FILE_MAGIC).unwrap();
+    writeln!(text, "{} {}", self.activity.cols(), self.activity.rows()).unwrap();
+    for i in 0..self.activity.rows() {
+      let row: Vec<String> = self.activity[i].iter().map(|a| a.to_string()).collect();
+      writeln!(text, "{}", row.join(" ")).unwrap();
+    }
+    return text;
+  }
+
+  // Parse stats produced by to_text()
+  pub fn from_text(text: &str) -> Result<Self, ConfigError> {
+    let mut lines = text.lines();
+    if lines.next() != Some(STATS_FILE_MAGIC) {
+      return Err(ConfigError::Parse(format!("Not a first-pass stats file: the first line should be \"{}\"",
+                                            STATS_FILE_MAGIC)));
+    }
+    let parse_numbers = |line: Option<&str>, what: &str| -> Result<Vec<u32>, ConfigError> {
+      let line = line.ok_or_else(|| ConfigError::Parse(format!("First-pass stats file ends before the {}", what)))?;
+      line.split_whitespace().map(|value| {
+        value.parse().map_err(|_| ConfigError::Parse(format!("Invalid value \"{}\" in the {} of the first-pass stats file",
+                                                             value, what)))
+      }).collect()
+    };
+    let size = parse_numbers(lines.next(), "size")?;
+    let (cols, rows) = match size[..] {
+      [cols, rows] if cols > 0 && rows > 0 => (cols as usize, rows as usize),
+      _ => return Err(ConfigError::Parse("The size in the first-pass stats file must be two nonzero numbers".into())),
+    };
+    // The rows are read before allocating anything, so that a bad size can't make us
+    // allocate more than the file itself holds
+    let values = lines.filter(|line| !line.trim().is_empty()).enumerate().map(|(i, line)| {
+      parse_numbers(Some(line), &format!("activity of superblock row {}", i))
+    }).collect::<Result<Vec<_>, _>>()?;
+    if values.len() != rows {
+      return Err(ConfigError::Parse(format!("The first-pass stats file has {} superblock rows, not {}",
+                                            values.len(), rows)));
+    }
+    if let Some(i) = values.iter().position(|row| row.len() != cols) {
+      return Err(ConfigError::Parse(format!("Superblock row {} of the first-pass stats file has {} values, not {}",
+                                            i, values[i].len(), cols)));
+    }
+    let activity = Array2D::new_with(rows, cols, |i, j| values[i][j]);
+    Ok(Self {
+      activity: activity
+    })
+  }
+}
+
+// Activity masking, for --tune ssim
+//
+// SSIM compares the error in each area against that area's own contrast, so the same
+// error costs much more SSIM in a flat block than in a textured one. With --tune ssim,
+// the distortion of each 8x8 block in rate-distortion decisions is multiplied by
+// (reference + C) / (variance + C), where `variance` is the block's luma variance per
+// pixel, `reference` is the geometric mean of that over the whole image, and C is the
+// constant which SSIM adds for the same reason. So a typical block keeps a weight near 1.
+
+// SSIM's C2 constant for 8-bit images, (0.03 * 255)^2, rounded
+const MASKING_C: u32 = 59;
+
+// Weights are fixed-point numbers with this many fractional bits
+pub const MASKING_WEIGHT_SHIFT: u32 = 8;
+
+// Weights are limited to between 1/4 and 4, so that no block is ignored entirely
+const MIN_MASKING_WEIGHT: u32 = 1 << (MASKING_WEIGHT_SHIFT - 2);
+const MAX_MASKING_WEIGHT: u32 = 4 << MASKING_WEIGHT_SHIFT;
+
+// Per-pixel variance of an 8x8 block, plus MASKING_C
+fn masked_variance(pixels: &Array2D<u8>, y0: usize, x0: usize) -> u32 {
+  return block_variance(pixels, y0, x0) / 64 + MASKING_C;
+}
+
+// The reference value for masking_weight(), from the 8x8 luma blocks of `source`
+pub fn masking_reference(source: &Frame) -> u32 {
+  let pixels = source.y().pixels();
+  let block_rows = pixels.rows() / 8;
+  let block_cols = pixels.cols() / 8;
+
+  let mut log_sum = 0u64;
+  for block_row in 0..block_rows {
+    for block_col in 0..block_cols {
+      log_sum += log2_fixed(masked_variance(pixels, block_row * 8, block_col * 8)) as u64;
+    }
+  }
+  let log_mean = (log_sum / ((block_rows * block_cols) as u64)) as u32;
+
+  // Undo the logarithm, by searching for the smallest value whose log is at least the mean.
+  // The variance of 8-bit pixels is at most 128^2
+  let mut lo = MASKING_C;
+  let mut hi = 128 * 128 + MASKING_C;
+  while lo < hi {
+    let mid = (lo + hi) / 2;
+    if log2_fixed(mid) >= log_mean {
+      hi = mid;
+    } else {
+      lo = mid + 1;
+    }
+  }
+  return lo;
+}
+
+// Weight for the distortion of the 8x8 luma block at (y0, x0), as a fixed-point number
+// with MASKING_WEIGHT_SHIFT fractional bits
+pub fn masking_weight(reference: u32, pixels: &Array2D<u8>, y0: usize, x0: usize) -> u32 {
+  let weight = (reference << MASKING_WEIGHT_SHIFT) / masked_variance(pixels, y0, x0);
+  return clamp(weight, MIN_MASKING_WEIGHT, MAX_MASKING_WEIGHT);
+}
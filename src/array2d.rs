@@ -13,7 +13,8 @@ use bytemuck::allocation::zeroed_slice_box;
 use std::ops::{Index, IndexMut};
 
 // Two-dimensional array type
-#[derive(Clone, Debug)]
+// The default is an empty 0x0 array, which doesn't allocate
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
 pub struct Array2D<T> {
   rows: usize,
   cols: usize,
@@ -29,6 +30,103 @@ impl<T> Array2D<T> {
   pub fn cols(&self) -> usize {
     self.cols
   }
+
+  // Borrow a rectangular region of this array, of size h x w with its top-left corner
+  // at (y0, x0). The result is indexed relative to that corner.
+  pub fn slice(&self, y0: usize, x0: usize, h: usize, w: usize) -> Slice2D<'_, T> {
+    let (start, end) = self.region_bounds(y0, x0, h, w);
+    Slice2D {
+      rows: h,
+      cols: w,
+      stride: self.stride,
+      data: &self.data[start .. end],
+    }
+  }
+
+  pub fn slice_mut(&mut self, y0: usize, x0: usize, h: usize, w: usize) -> Slice2DMut<'_, T> {
+    let (start, end) = self.region_bounds(y0, x0, h, w);
+    Slice2DMut {
+      rows: h,
+      cols: w,
+      stride: self.stride,
+      data: &mut self.data[start .. end],
+    }
+  }
+
+  // Borrow a run of whole rows as one contiguous slice, including any padding
+  // between the end of each row and the start of the next
+  pub fn rows_mut(&mut self, start: usize, count: usize) -> &mut [T] {
+    let end = start.checked_add(count).unwrap();
+    if end > self.rows {
+      panic!("Array2D row indices out of bounds (index {}..{} vs. size {})", start, end, self.rows);
+    }
+    &mut self.data[start * self.stride .. end * self.stride]
+  }
+
+  // Range of `data` covered by a region, checking that the region is in bounds
+  fn region_bounds(&self, y0: usize, x0: usize, h: usize, w: usize) -> (usize, usize) {
+    let row_end = y0.checked_add(h).unwrap();
+    let col_end = x0.checked_add(w).unwrap();
+
+    if row_end > self.rows {
+      panic!("Array2D row indices out of bounds (index {}..{} vs. size {})", y0, row_end, self.rows);
+    }
+    if col_end > self.cols {
+      panic!("Array2D column indices out of bounds (index {}..{} vs. size {})", x0, col_end, self.cols);
+    }
+
+    // Due to the above checks, these calculations should never overflow
+    let start = y0 * self.stride + x0;
+    let end = if h == 0 { start } else { (row_end - 1) * self.stride + col_end };
+    return (start, end);
+  }
+}
+
+// Borrowed view of a rectangular region of an Array2D, see Array2D::slice()
+#[derive(Clone, Copy)]
+pub struct Slice2D<'a, T> {
+  rows: usize,
+  cols: usize,
+  stride: usize,
+  data: &'a [T],
+}
+
+// Mutable version of Slice2D, see Array2D::slice_mut()
+pub struct Slice2DMut<'a, T> {
+  rows: usize,
+  cols: usize,
+  stride: usize,
+  data: &'a mut [T],
+}
+
+impl<'a, T> Slice2D<'a, T> {
+  pub fn rows(&self) -> usize {
+    self.rows
+  }
+
+  pub fn cols(&self) -> usize {
+    self.cols
+  }
+}
+
+impl<'a, T> Slice2DMut<'a, T> {
+  pub fn rows(&self) -> usize {
+    self.rows
+  }
+
+  pub fn cols(&self) -> usize {
+    self.cols
+  }
+
+  // Reborrow as an immutable view
+  pub fn as_slice(&self) -> Slice2D<'_, T> {
+    Slice2D {
+      rows: self.rows,
+      cols: self.cols,
+      stride: self.stride,
+      data: self.data,
+    }
+  }
 }
 
 impl<T> Array2D<T> {
@@ -41,6 +139,16 @@ impl<T> Array2D<T> {
   }
 }
 
+impl<T: Copy> Array2D<T> {
+  // Overwrite this array with the contents of a region of the same size
+  pub fn copy_from(&mut self, src: Slice2D<T>) {
+    assert!(src.rows() == self.rows && src.cols() == self.cols);
+    for i in 0..self.rows {
+      self[i].copy_from_slice(&src[i]);
+    }
+  }
+}
+
 impl<T: Clone> Array2D<T> {
   // Fill a region of a given size with (cloned) copies of `value`
   pub fn fill_region(&mut self, row_start: usize, col_start: usize, rows: usize, cols: usize, value: &T) {
@@ -77,6 +185,11 @@ impl<T: Zeroable> Array2D<T> {
     }
   }
 
+  // Reset every element to zero, keeping the allocation
+  pub fn fill_zeroed(&mut self) {
+    self.data.fill_with(T::zeroed);
+  }
+
   // TODO: Figure out how to make this not require Zeroable
   pub fn new_with<F: FnMut(usize, usize) -> T>(rows: usize, cols: usize, f: F) -> Self {
     let mut result = Array2D::zeroed(rows, cols);
@@ -139,3 +252,71 @@ impl<T> IndexMut<usize> for Array2D<T> {
     &mut self.data[start_index .. end_index]
   }
 }
+
+// Allow indexing by array[(row, col)] as well, which checks both indices
+impl<T> Index<(usize, usize)> for Array2D<T> {
+  type Output = T;
+  fn index(&self, (row, col): (usize, usize)) -> &T {
+    &self[row][col]
+  }
+}
+
+impl<T> IndexMut<(usize, usize)> for Array2D<T> {
+  fn index_mut(&mut self, (row, col): (usize, usize)) -> &mut T {
+    &mut self[row][col]
+  }
+}
+
+// Slices are indexed the same way as full arrays
+// The row slices returned here only cover the columns within the view
+impl<'a, T> Index<usize> for Slice2D<'a, T> {
+  type Output = [T];
+  fn index(&self, index: usize) -> &[T] {
+    if index >= self.rows {
+      panic!("Slice2D row index out of bounds (index {} vs. size {})", index, self.rows);
+    }
+    let start_index = index * self.stride;
+    &self.data[start_index .. start_index + self.cols]
+  }
+}
+
+impl<'a, T> Index<(usize, usize)> for Slice2D<'a, T> {
+  type Output = T;
+  fn index(&self, (row, col): (usize, usize)) -> &T {
+    &self[row][col]
+  }
+}
+
+impl<'a, T> Index<usize> for Slice2DMut<'a, T> {
+  type Output = [T];
+  fn index(&self, index: usize) -> &[T] {
+    if index >= self.rows {
+      panic!("Slice2D row index out of bounds (index {} vs. size {})", index, self.rows);
+    }
+    let start_index = index * self.stride;
+    &self.data[start_index .. start_index + self.cols]
+  }
+}
+
+impl<'a, T> IndexMut<usize> for Slice2DMut<'a, T> {
+  fn index_mut(&mut self, index: usize) -> &mut [T] {
+    if index >= self.rows {
+      panic!("Slice2D row index out of bounds (index {} vs. size {})", index, self.rows);
+    }
+    let start_index = index * self.stride;
+    &mut self.data[start_index .. start_index + self.cols]
+  }
+}
+
+impl<'a, T> Index<(usize, usize)> for Slice2DMut<'a, T> {
+  type Output = T;
+  fn index(&self, (row, col): (usize, usize)) -> &T {
+    &self[row][col]
+  }
+}
+
+impl<'a, T> IndexMut<(usize, usize)> for Slice2DMut<'a, T> {
+  fn index_mut(&mut self, (row, col): (usize, usize)) -> &mut T {
+    &mut self[row][col]
+  }
+}
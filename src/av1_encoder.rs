@@ -7,76 +7,392 @@
 // Media Patent License 1.0 was not distributed with this source code in the
 // PATENTS file, you can obtain it at www.aomedia.org/license/patent.
 
-use bytemuck::Zeroable;
+use std::fmt;
 use std::io;
-use std::fs::File;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
 
+use crate::analysis::{analyze, masking_reference, FirstPassStats};
 use crate::array2d::Array2D;
 use crate::bitcode::BitWriter;
-use crate::cdf::*;
+use crate::classify::resolve_tune;
+use crate::colorconv::ColorConversion;
+use crate::config::{ConfigError, EncoderConfig, ObuLayout, Tune};
 use crate::consts::*;
+use crate::decode::{CoeffBlock, TileDecoder};
 use crate::entropycode::EntropyWriter;
-use crate::enums::*;
-use crate::frame::Frame;
-use crate::recon::*;
+use crate::enums::PredictionMode;
+use crate::frame::{Frame, FrameGeometry, Plane, RowSource};
+use crate::hls::{pack_avif, pack_obus, replace_tile_data, unpack_avif, AvifConfig, AvifItem, AvifItemKind,
+                SequenceParams};
+use crate::levels::check_level;
+use crate::memory::{format_bytes, frame_bytes, MemoryEstimate};
+use crate::metrics::{frame_psnr, plane_ssim};
+use crate::prefilter::{apply_prefilters, prefilters};
+use crate::roi::roi_offset_map;
+use crate::scaling::{resize, superres_coded_width, superres_upscale};
+use crate::segmentation::{foreground_segment_map, SegmentationParams};
+use crate::stats::{EncodeStats, StageTimes, Stopwatch};
+use crate::threads::ThreadPool;
+use crate::tile::{block_decision_bytes, block_scratch_bytes, BlockDecision, ModeInfo, SuperblockContext, TileAnalyzer,
+                  TileBuffers, TileParams, TileWriter};
 use crate::util::*;
 use crate::y4m::*;
 
+// The intra edge filter smooths the neighbouring pixels used by directional prediction.
+// It's cheap and generally helps, so we always enable it.
+const ENABLE_INTRA_EDGE_FILTER: bool = true;
+
+// Parameters for the decoder model, when signalled. The buffer delays are in units
+// of 1/90000 seconds, and are the values the spec assumes for operating points
+// without their own parameters
+const DECODER_MODEL_DELAY_BITS: usize = 17;
+const DECODER_MODEL_REMOVAL_TIME_BITS: usize = 10;
+const DECODER_BUFFER_DELAY: u32 = 70000;
+const ENCODER_BUFFER_DELAY: u32 = 20000;
+
+// Number of bits used for each of the frame width and height (minus 1) in the sequence
+// header. AV1 allows up to 16, which is what limits images to 65536x65536
+const FRAME_SIZE_BITS: usize = 16;
+const MAX_FRAME_SIZE: usize = 1 << FRAME_SIZE_BITS;
+
+// How far through an image the encoder is, as passed to the progress callback
+#[derive(Clone, Copy, Debug)]
+pub struct EncodeProgress {
+  pub sb_rows_done: usize,
+  pub sb_rows: usize,
+}
+
+impl EncodeProgress {
+  pub fn percent(&self) -> f64 {
+    100.0 * (self.sb_rows_done as f64) / (self.sb_rows as f64)
+  }
+}
+
+// Flag which can be set from another thread to stop an encode early
+// Clones share the same flag, so keep one and pass a clone to set_cancel_token().
+#[derive(Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+  pub fn new() -> Self {
+    Self(Arc::new(AtomicBool::new(false)))
+  }
+
+  pub fn cancel(&self) {
+    self.0.store(true, Ordering::Relaxed);
+  }
+
+  pub fn is_cancelled(&self) -> bool {
+    self.0.load(Ordering::Relaxed)
+  }
+}
+
+#[derive(Debug)]
+pub enum EncodeError {
+  // The encode was stopped through a CancelToken
+  Cancelled,
+  // The source image could not be read (streaming encodes only)
+  Io(io::Error),
+  // The settings were rejected by AV1Encoder::new(), or the AvifConfig by pack_avif()
+  Config(ConfigError),
+  // The image data passed to encode_from_yuv_buffers(), encode_from_rgb() or
+  // Frame::from_planes() doesn't match its dimensions
+  InvalidInput(String),
+}
+
+impl fmt::Display for EncodeError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      EncodeError::Cancelled => write!(f, "Encode cancelled"),
+      EncodeError::Io(e) => write!(f, "{}", e),
+      EncodeError::Config(e) => write!(f, "{}", e),
+      EncodeError::InvalidInput(msg) => write!(f, "{}", msg),
+    }
+  }
+}
+
+impl std::error::Error for EncodeError {}
+
+impl From<io::Error> for EncodeError {
+  fn from(e: io::Error) -> Self {
+    EncodeError::Io(e)
+  }
+}
+
+impl From<ConfigError> for EncodeError {
+  fn from(e: ConfigError) -> Self {
+    EncodeError::Config(e)
+  }
+}
+
 // Top-level encoder state
 pub struct AV1Encoder {
+  config: EncoderConfig,
+
+  // Debug option: check the bitstream against an independent decoder after every superblock
+  audit: bool,
+  // Debug option: check that the coefficients decoded from the final bitstream match
+  // the ones we meant to encode
+  verify_coeffs: bool,
+  // Debug option: log every symbol written to the tile data
+  trace: bool,
+
+  // Called after each superblock row is encoded
+  progress_callback: Option<Box<dyn Fn(&EncodeProgress) + Send + Sync>>,
+  // Checked before each superblock row is encoded
+  cancel_token: Option<CancelToken>,
+
   // Size used for encoding - always padded to a multiple of 8x8 luma pixels
   y_width: usize,
   y_height: usize,
   uv_width: usize,
   uv_height: usize,
 
-  // Original image size
+  // Original image size, or with superres, the size of the downscaled image we code
   y_crop_width: usize,
   y_crop_height: usize,
   uv_crop_width: usize,
   uv_crop_height: usize,
+
+  // Width of the image after superres upscaling, which is the original width
+  upscaled_width: usize,
+
+  // Segment ID to use for each 4x4 unit, if segmentation is enabled
+  segment_map: Option<Array2D<u8>>,
+
+  // qindex offset for each superblock from a region-of-interest map, if one has been set
+  roi_offsets: Option<Array2D<i8>>,
+
+  // Stats to use instead of running the first pass, if they've been set
+  first_pass_stats: Option<FirstPassStats>,
+
+  // Buffers kept from the last encode_into() call, for the next one to reuse
+  buffers: TileBuffers,
 }
 
-// "Mode info" unit - a struct representing the state of a single 4x4 luma pixel unit.
-// The values in here can be used as contexts when encoding later blocks
-#[derive(Zeroable, Clone)]
-pub struct ModeInfo {
-  // "Level context" for each plane
-  // This is the sum of absolute values of the coefficients in each block,
-  // capped at 63, and is used as part of the context for coefficient sizes
-  //
-  // Note: As we don't use transform partitioning, this is never actually
-  // used for luma in this encoder. But it is required for chroma.
-  level_ctx: [u8; 3],
-
-  // Sign of the DC coefficient for each plane
-  // This is stored differently to what the spec says: we store
-  // -1 if the DC coefficient is negative, 0 if zero, 1 if positive.
-  // This way, we can compare the number of nearby +ve and -ve DC coefficients by
-  // simply summing this value over nearby blocks.
-  dc_sign: [i8; 3],
+// A finished superblock row, as passed from the analysis threads to the writer
+struct FinishedRow {
+  sb_row: usize,
+  decisions: Vec<Vec<BlockDecision>>,
+  // The band this row was analysed in, whose last `rows` luma rows are this superblock row
+  recon: Frame,
+  rows: usize,
 }
 
-// Mutable state used while encoding a single tile
-pub struct TileEncoder<'a> {
-  encoder: &'a AV1Encoder,
-  bitstream: EntropyWriter,
+// Result of encoding one image
+pub struct EncodedImage {
+  // Entropy-coded tile data, ready to be packed into an OBU
+  pub tile_data: Box<[u8]>,
+  pub stats: EncodeStats,
+  // Results of the analysis pass, when two-pass encoding is used
+  pub first_pass: Option<FirstPassStats>,
+  // The image which a decoder should produce from tile_data. Its crop region is the
+  // size of the image, but the pixels are padded out to a multiple of 8x8 luma pixels.
+  // Streaming encodes don't keep the whole image, so leave this as None
+  pub recon: Option<Frame>,
+  // Log of every symbol written, if enabled with AV1Encoder::set_trace()
+  pub trace: Option<String>,
+}
 
-  base_qindex: u8,
+// A rectangle within an image, in luma pixels
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Region {
+  pub x: usize,
+  pub y: usize,
+  pub width: usize,
+  pub height: usize,
+}
 
-  // Mode info per 4x4 luma pixel unit
-  mode_info: Array2D<ModeInfo>,
+// Minimum quality for search_qindex() to aim for, measured on the luma plane
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QualityTarget {
+  // PSNR in dB
+  Psnr(f64),
+  // SSIM, from 0 to 1
+  Ssim(f64),
+}
 
-  // Source frame
-  // This is the image we are trying to reproduce
-  // This must be pre-padded to match encoder.y_{width/height}, not the crop size
-  source: &'a Frame,
+impl QualityTarget {
+  fn is_met_by(&self, source: &Frame, recon: &Frame) -> bool {
+    match *self {
+      QualityTarget::Psnr(target) => frame_psnr(source, recon)[0] >= target,
+      QualityTarget::Ssim(target) => plane_ssim(source.y(), recon.y()) >= target,
+    }
+  }
+}
 
-  // Reconstructed frame
-  recon: Frame,
+// Find the highest qindex (ie. the smallest file) whose output still meets the target quality,
+// by binary search over test encodes. This takes around 8 encodes, and assumes that quality
+// only ever gets worse as the qindex rises, which is true in all but rare corner cases.
+// If even qindex 1 doesn't meet the target, returns 1. The other settings come from `config`,
+// and quality is measured against `source` as given, ie. before any pre-filters.
+pub fn search_qindex(config: &EncoderConfig, source: &Frame, target: QualityTarget) -> Result<u8, EncodeError> {
+  let width = source.y().crop_width();
+  let height = source.y().crop_height();
+  let mut config = resolve_tune(config, source);
+  let mut meets_target = |qindex: u8| -> Result<bool, EncodeError> {
+    config.qindex = qindex;
+    let encoded = AV1Encoder::new(&config, width, height)?.encode_image(source)?;
+    return Ok(target.is_met_by(source, encoded.recon.as_ref().unwrap()));
+  };
+
+  // Invariant: the answer is in [lo, hi]
+  let mut lo = 1u8;
+  let mut hi = 255u8;
+  while lo < hi {
+    let mid = (lo as usize + hi as usize).div_ceil(2) as u8;
+    if meets_target(mid)? {
+      lo = mid;
+    } else {
+      hi = mid - 1;
+    }
+  }
+  return Ok(lo);
+}
+
+// Encode an 8-bit 4:2:0 image, held in memory as three tightly packed planes, to an AVIF file
+// Each chroma plane is ceil(width/2) x ceil(height/2) pixels.
+//
+// This avoids all file access, so is the entry point to use from environments such as
+// WebAssembly, where there is no filesystem.
+pub fn encode_from_yuv_buffers(y: &[u8], u: &[u8], v: &[u8], width: usize, height: usize,
+                               config: &EncoderConfig) -> Result<Vec<u8>, EncodeError> {
+  let size = FrameGeometry::new(width, height);
+  let uv_len = size.uv_crop_width * size.uv_crop_height;
+  for (name, data, expected_len) in [("Y", y, width * height), ("U", u, uv_len), ("V", v, uv_len)] {
+    if data.len() != expected_len {
+      return Err(EncodeError::InvalidInput(format!("{} plane has {} bytes, but a {}x{} image needs {}",
+                                                   name, data.len(), width, height, expected_len)));
+    }
+  }
+  let strides = [width, size.uv_crop_width, size.uv_crop_width];
+  let source = Frame::from_planes(y, u, v, width, height, strides)
+    .map_err(|e| EncodeError::InvalidInput(e.to_string()))?;
+
+  return encode_frame(&source, config);
+}
+
+// Encode an 8-bit RGB or RGBA image, held in memory with `stride` bytes per row, to an AVIF file
+// The image is converted to YUV using the matrix given by the config's matrix_coefficients,
+// or BT.601 if that is unspecified (see colorconv.rs). Alpha is ignored.
+pub fn encode_from_rgb(data: &[u8], width: usize, height: usize, stride: usize, channels: usize,
+                       config: &EncoderConfig) -> Result<Vec<u8>, EncodeError> {
+  if channels != 3 && channels != 4 {
+    return Err(EncodeError::InvalidInput(format!("RGB images must have 3 or 4 channels, not {}", channels)));
+  }
+  if stride < width * channels {
+    return Err(EncodeError::InvalidInput(format!("Stride of {} bytes is too small for {} pixels of {} bytes",
+                                                 stride, width, channels)));
+  }
+  // The last row doesn't need to be padded out to the full stride
+  let expected_len = if height == 0 { 0 } else { stride * (height - 1) + width * channels };
+  if data.len() < expected_len {
+    return Err(EncodeError::InvalidInput(format!("RGB data has {} bytes, but a {}x{} image needs {}",
+                                                 data.len(), width, height, expected_len)));
+  }
+
+  let (conversion, config) = rgb_conversion(config, width, height)?;
+  let source = conversion.rgb_to_frame(data, width, height, stride, channels);
+  return encode_frame(&source, &config);
+}
+
+// Encode an image from the `image` crate to an AVIF file
+// Colour images are converted to YUV as for encode_from_rgb(), and greyscale images are
+// given neutral chroma. Alpha is ignored. Images with more than 8 bits per channel, eg.
+// 16-bit PNGs and TIFFs, are converted at full precision and dithered down to 8 bits.
+// There is no 10-bit encode path, so EncoderConfig::validate() rejects higher bit depths.
+#[cfg(feature = "image")]
+pub fn encode_image(image: &image::DynamicImage, config: &EncoderConfig) -> Result<Vec<u8>, EncodeError> {
+  let width = image.width() as usize;
+  let height = image.height() as usize;
+
+  let (conversion, config) = rgb_conversion(config, width, height)?;
+  let color = image.color();
+  let high_depth = color.bytes_per_pixel() > color.channel_count();
+  let source = match (color.has_color(), high_depth) {
+    (true, false) => conversion.rgb_to_frame(image.to_rgb8().as_raw(), width, height, width * 3, 3),
+    (false, false) => conversion.gray_to_frame(image.to_luma8().as_raw(), width, height, width),
+    (true, true) => conversion.rgb16_to_frame(image.to_rgb16().as_raw(), width, height, width * 3, 3),
+    (false, true) => conversion.gray16_to_frame(image.to_luma16().as_raw(), width, height, width),
+  };
+  return encode_frame(&source, &config);
+}
+
+// Work out how to convert an RGB image for the given config, and update the config
+// to signal the matrix which will actually be used
+fn rgb_conversion(config: &EncoderConfig, width: usize, height: usize)
+                  -> Result<(ColorConversion, EncoderConfig), EncodeError> {
+  let conversion = ColorConversion::from_config(config)?;
+  let mut config = config.clone();
+  config.matrix_coefficients = conversion.matrix.matrix_coefficients();
+  // Check the size before converting, as the conversion can't handle empty images
+  AV1Encoder::new(&config, width, height)?;
+  return Ok((conversion, config));
+}
+
+// Encode an image which is already in memory as a Frame to an AVIF file. Frames can be
+// built from planes in any layout with Frame::from_planes(), so this is the entry point
+// for callers which decode images themselves. The other in-memory encode functions
+// all end up here.
+pub fn encode_frame(source: &Frame, config: &EncoderConfig) -> Result<Vec<u8>, EncodeError> {
+  let width = source.y().crop_width();
+  let height = source.y().crop_height();
+  let config = &resolve_tune(config, source);
+  let encoder = AV1Encoder::new(config, width, height)?;
+
+  let sequence_header = encoder.generate_sequence_header();
+  let frame_header = encoder.generate_frame_header(config.obu_layout == ObuLayout::Separate);
+  let encoded = encoder.encode_image(source)?;
+  let av1_data = pack_obus(&sequence_header, &frame_header, &encoded.tile_data, true, config.extension_header(),
+                           config.obu_layout);
+  let avif_config = AvifConfig::for_image(config, width, height);
+  return Ok(pack_avif(&av1_data, width, height, config, &[], &avif_config)?.into_vec());
 }
 
-fn get_qctx(base_qindex: u8) -> usize {
+// Encode a grayscale image, such as an alpha channel, depth map or gain map, as an extra
+// item to pass to pack_avif(). Only the luma of `plane` is used, and its size is the
+// item's size. It's coded at the same qindex and speed as `config`, in full range, since
+// it isn't an image to be shown as such.
+pub fn encode_plane_item(plane: &Plane, kind: AvifItemKind, config: &EncoderConfig) -> Result<AvifItem, EncodeError> {
+  if let AvifItemKind::GainMap(metadata) = &kind {
+    metadata.validate()?;
+  }
+  let item_config = EncoderConfig::builder()
+    .qindex(config.qindex)
+    .speed(config.speed)
+    .tune(Tune::Psnr)
+    .full_range(true)
+    .deterministic(config.deterministic)
+    .build()?;
+
+  let width = plane.crop_width();
+  let height = plane.crop_height();
+  let mut frame = Frame::new(height, width);
+  frame.y_mut().copy_rows_from(plane, 0, 0, plane.height());
+  frame.y_mut().fill_padding();
+  for chroma in 1..3 {
+    frame.plane_mut(chroma).pixels_mut().fill_with(|_, _| 128);
+  }
+
+  let encoder = AV1Encoder::new(&item_config, width, height)?;
+  let sequence_header = encoder.generate_sequence_header();
+  let frame_header = encoder.generate_frame_header(false);
+  let encoded = encoder.encode_image(&frame)?;
+  let av1_data = pack_obus(&sequence_header, &frame_header, &encoded.tile_data, true, None, ObuLayout::Frame);
+
+  return Ok(AvifItem {
+    kind: kind,
+    width: width,
+    height: height,
+    config: item_config,
+    av1_data: av1_data,
+  });
+}
+
+pub fn get_qctx(base_qindex: u8) -> usize {
   if base_qindex <= 20 {
     0
   } else if base_qindex <= 60 {
@@ -89,62 +405,261 @@ fn get_qctx(base_qindex: u8) -> usize {
 }
 
 impl AV1Encoder {
-  pub fn new(y_crop_width: usize, y_crop_height: usize) -> Self {
+  pub fn new(config: &EncoderConfig, y_crop_width: usize, y_crop_height: usize) -> Result<Self, ConfigError> {
+    config.validate()?;
+
     // Check limits imposed by AV1
-    assert!(0 < y_crop_width && y_crop_width <= 65536);
-    assert!(0 < y_crop_height && y_crop_height <= 65536);
+    if y_crop_width == 0 || y_crop_height == 0 {
+      return Err(ConfigError::Invalid(format!("Invalid image size {}x{}, must be between 1x1 and {}x{}",
+                                              y_crop_width, y_crop_height, MAX_FRAME_SIZE, MAX_FRAME_SIZE)));
+    }
+    if y_crop_width > MAX_FRAME_SIZE || y_crop_height > MAX_FRAME_SIZE {
+      return Err(ConfigError::TooLarge(format!("Image size {}x{} is too large, AV1 allows at most {}x{}",
+                                               y_crop_width, y_crop_height, MAX_FRAME_SIZE, MAX_FRAME_SIZE)));
+    }
 
-    let y_width = y_crop_width.next_multiple_of(8);
-    let y_height = y_crop_height.next_multiple_of(8);
+    // With superres, everything is coded at a reduced width
+    let upscaled_width = y_crop_width;
+    let y_crop_width = coded_width(config, upscaled_width);
 
-    let uv_crop_width = round2(y_crop_width, 1);
-    let uv_crop_height = round2(y_crop_height, 1);
+    let size = FrameGeometry::new(y_crop_width, y_crop_height);
+    let (y_width, y_height) = (size.y_width, size.y_height);
 
-    let uv_width = y_width / 2;
-    let uv_height = y_height / 2;
+    // Check that the image will fit in one tile
+    if y_width > 4096 || y_width * y_height > 4096 * 2304 {
+      return Err(ConfigError::TooLarge(format!("Image size {}x{} (padded to {}x{}) is too large to fit in a single tile",
+                                               y_crop_width, y_crop_height, y_width, y_height)));
+    }
+
+    check_level(config, upscaled_width, y_crop_height)?;
 
-    Self {
+    Ok(Self {
+      config: config.clone(),
+      audit: false,
+      verify_coeffs: false,
+      trace: false,
+      progress_callback: None,
+      cancel_token: None,
       y_width: y_width,
       y_height: y_height,
-      uv_width: uv_width,
-      uv_height: uv_height,
+      uv_width: size.uv_width,
+      uv_height: size.uv_height,
       y_crop_width: y_crop_width,
       y_crop_height: y_crop_height,
-      uv_crop_width: uv_crop_width,
-      uv_crop_height: uv_crop_height,
+      uv_crop_width: size.uv_crop_width,
+      uv_crop_height: size.uv_crop_height,
+      upscaled_width: upscaled_width,
+      segment_map: None,
+      roi_offsets: None,
+      first_pass_stats: None,
+      buffers: TileBuffers::default(),
+    })
+  }
+
+  pub fn config(&self) -> &EncoderConfig {
+    &self.config
+  }
+
+  // Enable "audit mode": after each superblock, everything encoded so far is decoded again
+  // and compared against the encoder's reconstruction, panicking at the first block which
+  // doesn't match. This makes encoding roughly quadratic in the image size, so is only
+  // intended for debugging changes to the bitstream syntax.
+  pub fn set_audit(&mut self, audit: bool) {
+    self.audit = audit;
+  }
+
+  // Enable coefficient verification: once the image is encoded, the tile data is parsed
+  // again and every block's quantized coefficients are compared against the values which
+  // TileWriter coded, panicking at the first difference. Unlike audit mode, this is cheap
+  // enough to leave on while testing, but only checks the residual syntax.
+  pub fn set_verify_coeffs(&mut self, verify_coeffs: bool) {
+    self.verify_coeffs = verify_coeffs;
+  }
+
+  // Enable the syntax trace: every symbol written to the tile data is logged, along with
+  // the syntax element it belongs to, its context, and its cost in bits. The log is
+  // returned in EncodedImage::trace.
+  pub fn set_trace(&mut self, trace: bool) {
+    self.trace = trace;
+  }
+
+  // Code the foreground of the image, as marked by bright pixels in `mask`, at the
+  // configured qindex, and the background at EncoderConfig::background_qindex. The mask
+  // is stretched to cover the image if it's a different size.
+  pub fn set_foreground_mask(&mut self, mask: &Plane) -> Result<(), ConfigError> {
+    if self.config.background_qindex == 0 {
+      return Err(ConfigError::Invalid("A foreground mask needs a background qindex".into()));
+    }
+    self.segment_map = Some(foreground_segment_map(mask, self.y_crop_width, self.y_crop_height,
+                                                   self.y_height / 4, self.y_width / 4));
+    Ok(())
+  }
+
+  // Adjust the qindex of each superblock by a region-of-interest map, where mid-gray leaves
+  // the qindex unchanged, brighter areas get a lower qindex and darker areas a higher
+  // one (see roi.rs). The map is stretched to cover the image if it's a different size.
+  pub fn set_roi_map(&mut self, map: &Plane) {
+    self.roi_offsets = Some(roi_offset_map(map, self.y_crop_width, self.y_crop_height,
+                                           self.y_height.div_ceil(64), self.y_width.div_ceil(64)));
+  }
+
+  // Use first-pass stats saved from an earlier encode of the same image (see
+  // FirstPassStats::from_text()), rather than analysing the image again. Only allowed with
+  // two-pass encoding, and the stats must cover the same number of superblocks as the
+  // image, which is coded after any superres downscaling.
+  pub fn set_first_pass_stats(&mut self, stats: FirstPassStats) -> Result<(), ConfigError> {
+    if self.config.passes != 2 {
+      return Err(ConfigError::Invalid("First-pass stats can only be used with two-pass encoding".into()));
     }
+    let sb_rows = self.y_height.div_ceil(64);
+    let sb_cols = self.y_width.div_ceil(64);
+    if stats.activity.rows() != sb_rows || stats.activity.cols() != sb_cols {
+      return Err(ConfigError::Invalid(format!("The first-pass stats are for {}x{} superblocks, but the image has {}x{}",
+                                              stats.activity.cols(), stats.activity.rows(), sb_cols, sb_rows)));
+    }
+    self.first_pass_stats = Some(stats);
+    Ok(())
+  }
+
+  // Set a function to be called with the encoder's progress after each superblock row.
+  // This is called on the thread which is running the encode.
+  pub fn set_progress_callback<F: Fn(&EncodeProgress) + Send + Sync + 'static>(&mut self, callback: F) {
+    self.progress_callback = Some(Box::new(callback));
+  }
+
+  // Allow encodes to be cancelled through the given token. Cancellation is checked
+  // between superblock rows, after which the encode returns EncodeError::Cancelled.
+  pub fn set_cancel_token(&mut self, token: CancelToken) {
+    self.cancel_token = Some(token);
+  }
+
+  // Report that a superblock row has been finished, and check whether to keep going
+  fn row_done(&self, sb_rows_done: usize, sb_rows: usize) -> Result<(), EncodeError> {
+    if let Some(callback) = &self.progress_callback {
+      callback(&EncodeProgress { sb_rows_done: sb_rows_done, sb_rows: sb_rows });
+    }
+    if self.cancel_token.as_ref().is_some_and(|token| token.is_cancelled()) {
+      return Err(EncodeError::Cancelled);
+    }
+    Ok(())
   }
 
   pub fn generate_sequence_header(&self) -> Box<[u8]> {
     let mut w = BitWriter::new();
     
-    w.write_bits(0, 3); // "Main" profile: 8 or 10 bits, YUV 4:2:0 or monochrome
+    // The simplified headers can't describe layers or timing, so use the full headers
+    // when an OBU extension header or timing info is needed
+    let extension = self.config.extension_header();
+    let reduced_still_picture_header = self.config.reduced_still_picture_header();
+    let params = SequenceParams::from_config(&self.config, self.upscaled_width, self.y_crop_height);
+
+    w.write_bits(params.seq_profile as u64, 3);
     w.write_bit(1); // Still picture
-    w.write_bit(1); // with simplified headers
-  
-    w.write_bits(31, 5); // Level = 31, a special value meaning no level-based constraints apply
-  
-    // Width and height - we first code how many bits to use for each value (here just use 16,
-    // for simplicity), then one less than the actual width and height
-    w.write_bits(15, 4);
-    w.write_bits(15, 4);
-    w.write_bits((self.y_crop_width-1) as u64, 16);
-    w.write_bits((self.y_crop_height-1) as u64, 16);
-  
-    // Now to disable a bunch of features we aren't going to use
-    // 6 zero bits means:
-    // * 64x64 superblocks
-    // * Disable filter-intra and intra-edge-filter
-    // * Disable superres, CDEF, and loop restoration
-    w.write_bits(0, 6);
+    w.write_bit(reduced_still_picture_header as u8); // with or without simplified headers
+
+    if !reduced_still_picture_header {
+      w.write_bit(self.config.timing_info() as u8);
+      if self.config.timing_info() {
+        // timing_info()
+        w.write_bits(self.config.num_units_in_display_tick as u64, 32);
+        w.write_bits(self.config.time_scale as u64, 32);
+        w.write_bit(1); // Equal picture interval...
+        w.write_uvlc(0); // ...of one tick per picture (coded as ticks minus 1)
+
+        w.write_bit(self.config.decoder_model as u8);
+        if self.config.decoder_model {
+          // decoder_model_info()
+          w.write_bits((DECODER_MODEL_DELAY_BITS - 1) as u64, 5);
+          w.write_bits(self.config.num_units_in_display_tick as u64, 32); // num_units_in_decoding_tick
+          w.write_bits((DECODER_MODEL_REMOVAL_TIME_BITS - 1) as u64, 5);
+          // frame_presentation_time_length_minus_1, unused as the picture interval is fixed
+          w.write_bits((DECODER_MODEL_REMOVAL_TIME_BITS - 1) as u64, 5);
+        }
+      }
+      w.write_bit(0); // No initial display delay info
+      w.write_bits(0, 5); // One operating point...
+      // ...which contains just our layer, or everything if there are no layers
+      let operating_point_idc = extension.map_or(0, |ext| ext.operating_point_idc());
+      w.write_bits(operating_point_idc as u64, 12);
+    }
+
+    // Level, where 31 is a special value meaning no level-based constraints apply
+    // In the simplified header, the tier is implicitly the main tier
+    w.write_bits(params.seq_level_idx as u64, 5);
+    if !reduced_still_picture_header && params.seq_level_idx > 7 {
+      w.write_bit(params.seq_tier);
+    }
+    if self.config.decoder_model {
+      w.write_bit(1); // Decoder model present for this operating point
+      // operating_parameters_info()
+      w.write_bits(DECODER_BUFFER_DELAY as u64, DECODER_MODEL_DELAY_BITS);
+      w.write_bits(ENCODER_BUFFER_DELAY as u64, DECODER_MODEL_DELAY_BITS);
+      w.write_bit(0); // Not low delay mode
+    }
+
+    // Width and height - we first code how many bits to use for each value (here just use
+    // the maximum, for simplicity), then one less than the actual width and height
+    w.write_bits((FRAME_SIZE_BITS - 1) as u64, 4);
+    w.write_bits((FRAME_SIZE_BITS - 1) as u64, 4);
+    w.write_bits((self.upscaled_width-1) as u64, FRAME_SIZE_BITS);
+    w.write_bits((self.y_crop_height-1) as u64, FRAME_SIZE_BITS);
+
+    if !reduced_still_picture_header {
+      w.write_bit(0); // No frame IDs
+    }
+
+    w.write_bit(0); // 64x64 superblocks
+    w.write_bit(0); // Disable filter-intra
+    w.write_bit(ENABLE_INTRA_EDGE_FILTER as u8);
+
+    if !reduced_still_picture_header {
+      // Disable the inter-prediction tools. The 5 zero bits are:
+      // * Disable interintra and masked compound, warped motion, and dual filters
+      // * Disable order hints (which implies no jnt_comp or ref_frame_mvs)
+      w.write_bits(0, 5);
+      // Allow screen content tools and integer MVs to be selected per frame,
+      // to match what the simplified headers imply
+      w.write_bit(1); // seq_choose_screen_content_tools
+      w.write_bit(1); // seq_choose_integer_mv
+    }
+
+    w.write_bit(self.superres() as u8);
+    // 2 zero bits to disable CDEF and loop restoration
+    w.write_bits(0, 2);
   
     // Colour configuration
-    w.write_bit(0); // 8 bits per pixel
-    w.write_bit(0); // Not monochrome, ie. we have chroma
-    w.write_bit(0); // No colour info for now - we can put it in the AVIF headers later
-    w.write_bit(0); // "TV" colour range
-    w.write_bits(0, 2); // Unknown chroma sample position
-    w.write_bit(0); // UV channels have shared delta-q values
+    w.write_bit(params.high_bitdepth as u8);
+    if params.seq_profile == 2 && params.high_bitdepth {
+      w.write_bit(params.twelve_bit as u8);
+    }
+    if params.seq_profile != 1 {
+      w.write_bit(params.mono_chrome as u8);
+    }
+    let color_description = (self.config.color_primaries, self.config.transfer_function,
+                             self.config.matrix_coefficients);
+    let color_description_present = self.config.obu_color_description && color_description != (2, 2, 2);
+    w.write_bit(color_description_present as u8);
+    if color_description_present {
+      // The identity matrix is rejected for 4:2:0 by config.validate(), so this can't hit
+      // the sRGB special case, which would leave out the colour range
+      w.write_bits(color_description.0 as u64, 8);
+      w.write_bits(color_description.1 as u64, 8);
+      w.write_bits(color_description.2 as u64, 8);
+    }
+    w.write_bit(self.config.full_range as u8);
+    if !params.mono_chrome {
+      // Subsampling is implied by the profile, except for 12-bit images in profile 2
+      if params.seq_profile == 2 && params.twelve_bit {
+        w.write_bit(params.subsampling_x as u8);
+        if params.subsampling_x {
+          w.write_bit(params.subsampling_y as u8);
+        }
+      }
+      if params.subsampling_x && params.subsampling_y {
+        w.write_bits(params.chroma_sample_position as u64, 2);
+      }
+      w.write_bit(0); // UV channels have shared delta-q values
+    }
   
     w.write_bit(0); // No film grain
   
@@ -152,12 +667,52 @@ impl AV1Encoder {
     return w.finalize(true);
   }
   
-  pub fn generate_frame_header(&self, base_qindex: u8, add_trailing_one_bit: bool) -> Box<[u8]> {
+  pub fn generate_frame_header(&self, add_trailing_one_bit: bool) -> Box<[u8]> {
+    let base_qindex = self.config.qindex;
     let mut w = BitWriter::new();
-    
+
+    // With the full headers (see generate_sequence_header()), we need to say explicitly
+    // that this is a shown key frame. Error resilient mode, the refresh flags, and so on
+    // are then implied
+    let reduced_still_picture_header = self.config.reduced_still_picture_header();
+    if !reduced_still_picture_header {
+      w.write_bit(0); // Not showing an existing frame
+      w.write_bits(0, 2); // Key frame
+      w.write_bit(1); // Show frame
+    }
+
     w.write_bit(1); // Disable CDF updates
-    w.write_bit(0); // Disable screen content tools
-    w.write_bit(0); // Render size = frame size
+    if self.screen_content_tools() {
+      w.write_bit(1); // Enable screen content tools
+      w.write_bit(1); // force_integer_mv, which is irrelevant for intra frames
+    } else {
+      w.write_bit(0); // Disable screen content tools
+    }
+    if !reduced_still_picture_header {
+      w.write_bit(0); // No frame size override
+      // Order hints are disabled, so there's no order hint here
+      if self.config.decoder_model {
+        // The decoder can remove the frame from its buffer as soon as it arrives
+        w.write_bit(0); // No buffer removal times
+      }
+    }
+    if self.superres() {
+      // superres_params()
+      w.write_bit(1); // Use superres
+      w.write_bits((self.config.superres_denominator - SUPERRES_DENOM_MIN as u8) as u64, SUPERRES_DENOM_BITS);
+    }
+    // The render size is only signalled if it differs from the frame size
+    if let Some((render_width, render_height)) = self.render_size() {
+      w.write_bit(1);
+      w.write_bits((render_width - 1) as u64, FRAME_SIZE_BITS);
+      w.write_bits((render_height - 1) as u64, FRAME_SIZE_BITS);
+    } else {
+      w.write_bit(0);
+    }
+    // Intra block copy can't be used along with superres, so isn't signalled then
+    if self.screen_content_tools() && !self.superres() {
+      w.write_bit(0); // Disable intra block copy
+    }
   
     // Tile info
     // We need to code a tiling mode, then two zero bits to select 1x1 tiling.
@@ -174,10 +729,44 @@ impl AV1Encoder {
   
     w.write_bits(base_qindex as u64, 8);
   
-    w.write_bits(0, 3); // No frame-level delta-qs (three bits: Y DC, UV DC, UV AC)
+    // Frame-level delta-qs: luma DC is never adjusted, but chroma may be. As the sequence
+    // header says that U and V share their delta-q values, there is no separate V pair
+    let delta_q = self.frame_delta_q();
+    for delta in [delta_q[0][0], delta_q[1][0], delta_q[1][1]] {
+      if delta == 0 {
+        w.write_bit(0);
+      } else {
+        w.write_bit(1);
+        w.write_su(delta as i64, 7);
+      }
+    }
     w.write_bit(0); // Don't use quantizer matrices
-    w.write_bit(0); // No segmentation
-    w.write_bit(0); // No superblock-level delta-q (=> no superblock-level delta-lf)
+    if let Some(segmentation) = self.segmentation() {
+      // segmentation_params(). For key frames, the map and data are always updated
+      w.write_bit(1);
+      for segment in &segmentation.features {
+        for (j, feature) in segment.iter().enumerate() {
+          w.write_bit(feature.is_some() as u8);
+          if let Some(value) = *feature {
+            assert!(value.abs() <= segmentation_feature_max[j]);
+            if segmentation_feature_signed[j] {
+              w.write_su(value as i64, 1 + segmentation_feature_bits[j]);
+            } else {
+              w.write_bits(value as u64, segmentation_feature_bits[j]);
+            }
+          }
+        }
+      }
+    } else {
+      w.write_bit(0); // No segmentation
+    }
+    if self.delta_q_present() {
+      w.write_bit(1); // Superblock-level delta-q
+      w.write_bits(0, 2); // with a resolution of 1 qindex unit
+      w.write_bit(0); // No superblock-level delta-lf
+    } else {
+      w.write_bit(0); // No superblock-level delta-q (=> no superblock-level delta-lf)
+    }
   
     // Deblocking params
     w.write_bits(0, 6); // Strength 0 = 0
@@ -194,464 +783,680 @@ impl AV1Encoder {
     return w.finalize(add_trailing_one_bit);
   }
 
-  pub fn encode_image(&self, source: &Frame, base_qindex: u8) -> Box<[u8]> {
-    // Encode a single tile for now
-    assert!(source.y().width() == self.y_width);
-    assert!(source.y().height() == self.y_height);
+  // qindex offsets for the DC and AC coefficients of each plane
+  fn frame_delta_q(&self) -> [[i8; 2]; 3] {
+    let chroma = [self.config.chroma_dc_qoffset, self.config.chroma_ac_qoffset];
+    return [[0, 0], chroma, chroma];
+  }
 
-    // We don't currently support lossless mode
-    assert!(base_qindex != 0);
+  // Superblock-level delta-q is used to implement two-pass encoding and region-of-interest maps
+  fn delta_q_present(&self) -> bool {
+    self.config.passes == 2 || self.roi_offsets.is_some()
+  }
 
-    // Allocate MI array
-    let mi_rows = self.y_height / 4;
-    let mi_cols = self.y_width / 4;
+  // qindex for each superblock, if delta-q is used: the qindexes chosen from the first
+  // pass, if there was one, adjusted by the region-of-interest map, if there is one
+  fn qindex_map(&self, first_pass: Option<&FirstPassStats>) -> Option<Array2D<u8>> {
+    if !self.delta_q_present() {
+      return None;
+    }
+    let base_qindex = self.config.qindex;
+    let mut qindex_map = match first_pass {
+      Some(stats) => stats.qindex_map(base_qindex),
+      None => Array2D::new_with(self.y_height.div_ceil(64), self.y_width.div_ceil(64), |_, _| base_qindex),
+    };
+    if let Some(roi_offsets) = &self.roi_offsets {
+      qindex_map.map(|i, j, qindex| clamp(qindex as i32 + roi_offsets[i][j] as i32, 1, 255) as u8);
+    }
+    return Some(qindex_map);
+  }
+
+  // Segmentation is only used to code the foreground and background at different qindexes
+  fn segmentation(&self) -> Option<SegmentationParams> {
+    self.segment_map.as_ref().map(|_| {
+      SegmentationParams::foreground_background(self.config.qindex, self.config.background_qindex)
+    })
+  }
+
+  // Screen content tools (which for us just means palette mode) are only worth
+  // signalling for screen content. Tune::Auto should have been resolved before getting
+  // here, and is treated like Tune::Psnr if not
+  fn screen_content_tools(&self) -> bool {
+    self.config.tune == Tune::Screen
+  }
+
+  // Whether the image is coded at a reduced width, then upscaled
+  fn superres(&self) -> bool {
+    self.upscaled_width != self.y_crop_width
+  }
+
+  // Activity masking needs the whole image up front, so is left out when `source` is None
+  // (when streaming, which check_streaming() doesn't allow with --tune ssim). So is the
+  // luma transform search, as that's chosen by the first pass
+  fn tile_params(&self, source: Option<&Frame>, first_pass: Option<&FirstPassStats>) -> TileParams {
+    let masking = self.config.tune == Tune::Ssim;
+    TileParams {
+      mi_rows: self.y_height / 4,
+      mi_cols: self.y_width / 4,
+      base_qindex: self.config.qindex,
+      delta_q: self.frame_delta_q(),
+      bit_depth: self.config.bit_depth,
+      delta_q_present: self.delta_q_present(),
+      segmentation: self.segmentation(),
+      screen_content_tools: self.screen_content_tools(),
+      enable_intra_edge_filter: ENABLE_INTRA_EDGE_FILTER,
+      sharpness: self.config.sharpness,
+      masking_reference: source.filter(|_| masking).map(masking_reference),
+      tx_search_map: first_pass.map(FirstPassStats::tx_search_map),
+    }
+  }
+
+  pub fn encode_image(&self, source: &Frame) -> Result<EncodedImage, EncodeError> {
+    return self.encode_with_buffers(source, &mut TileBuffers::default());
+  }
+
+  // Encode an image to a complete AV1 stream (as written by pack_obus()), replacing the
+  // contents of `out`. The image must be the size the encoder was created for.
+  //
+  // This is for encoding many images of the same size, eg. the frames of an animation,
+  // or a batch of thumbnails: the encoder's largest buffers, and `out` itself, are kept
+  // from one call to the next rather than allocated again for each image.
+  pub fn encode_into(&mut self, source: &Frame, out: &mut Vec<u8>) -> Result<EncodeStats, EncodeError> {
+    let mut buffers = std::mem::take(&mut self.buffers);
+    let mut result = self.encode_with_buffers(source, &mut buffers);
+    if let Ok(encoded) = &mut result {
+      // Without superres, the reconstruction is the recon buffer itself
+      if buffers.recon.is_none() {
+        buffers.recon = encoded.recon.take();
+      }
+    }
+    self.buffers = buffers;
+    let encoded = result?;
+
+    let sequence_header = self.generate_sequence_header();
+    let frame_header = self.generate_frame_header(self.config.obu_layout == ObuLayout::Separate);
+    let av1_data = pack_obus(&sequence_header, &frame_header, &encoded.tile_data, true,
+                             self.config.extension_header(), self.config.obu_layout);
+    out.clear();
+    out.extend_from_slice(&av1_data);
+    return Ok(encoded.stats);
+  }
+
+  // The tiles which cover `region`, as (tile row, tile column) pairs. Every image is
+  // currently coded as a single tile, so this is always just that tile, but callers
+  // shouldn't rely on it.
+  pub fn tiles_in_region(&self, region: Region) -> Result<Vec<(usize, usize)>, EncodeError> {
+    // The region comes from the caller, so its far edges may not even fit in a usize
+    let right = region.x.checked_add(region.width);
+    let bottom = region.y.checked_add(region.height);
+    if region.width == 0 || region.height == 0 ||
+       right.is_none_or(|r| r > self.upscaled_width) || bottom.is_none_or(|b| b > self.y_crop_height) {
+      return Err(EncodeError::InvalidInput(format!(
+        "Region {}x{} at ({}, {}) is empty or outside the {}x{} image",
+        region.width, region.height, region.x, region.y, self.upscaled_width, self.y_crop_height)));
+    }
+    return Ok(vec![(0, 0)]);
+  }
+
+  // Update an existing AV1 stream after an edit to `region` of the image: the tiles
+  // covering the region are re-encoded from `source`, which is the whole edited image, and
+  // spliced into `existing`. Everything else in `existing` is kept as it is, so it must
+  // have been produced by an encoder with the same settings as this one; the frame
+  // headers are compared, and a mismatch is an error.
+  //
+  // This is for interactive tools, which can then skip re-encoding the rest of the
+  // image after a localized edit. Until multi-tile encoding lands, the one tile covers
+  // the whole image, so this costs as much as a full encode.
+  pub fn reencode_region(&self, existing: &[u8], source: &Frame, region: Region) -> Result<Vec<u8>, EncodeError> {
+    self.tiles_in_region(region)?;
+    let encoded = self.encode_image(source)?;
+    let frame_header = self.generate_frame_header(self.config.obu_layout == ObuLayout::Separate);
+    let av1_data = replace_tile_data(existing, &frame_header, &encoded.tile_data)
+      .map_err(|e| EncodeError::InvalidInput(e.to_string()))?;
+    return Ok(av1_data);
+  }
+
+  // As reencode_region(), for an AVIF file. The file is written again with `avif_config`,
+  // so container-level settings which differ from AvifConfig::for_image() must be given
+  // again here.
+  pub fn reencode_region_avif(&self, existing: &[u8], source: &Frame, region: Region,
+                              avif_config: &AvifConfig) -> Result<Vec<u8>, EncodeError> {
+    let existing = unpack_avif(existing).map_err(|e| EncodeError::InvalidInput(e.to_string()))?;
+    let av1_data = self.reencode_region(&existing, source, region)?;
+    let avif = pack_avif(&av1_data, self.upscaled_width, self.y_crop_height, &self.config, &[], avif_config)?;
+    return Ok(avif.into_vec());
+  }
+
+  // Encode an image, taking whole-image buffers from `buffers` where possible. The mode
+  // info, and with superres the reconstruction before upscaling, are left in `buffers`
+  // afterwards; otherwise the reconstruction is returned in EncodedImage::recon.
+  fn encode_with_buffers(&self, source: &Frame, buffers: &mut TileBuffers) -> Result<EncodedImage, EncodeError> {
+    assert!(source.y().crop_width() == self.upscaled_width);
+    assert!(source.y().crop_height() == self.y_crop_height);
+    self.check_memory(false)?;
+
+    // Everything from here on, including the first pass, sees the downscaled and filtered image
+    let start = Stopwatch::start();
+    let downscaled = self.superres().then(|| {
+      resize(source, self.y_crop_width, self.y_crop_height, self.config.deterministic)
+    });
+    let source = downscaled.as_ref().unwrap_or(source);
+    let filtered = apply_prefilters(&self.config, source);
+    let source = filtered.as_ref().unwrap_or(source);
+    let prefilter_time = start.elapsed();
+
+    let start = Stopwatch::start();
+    let first_pass = if self.config.passes == 2 {
+      Some(self.first_pass_stats.clone().unwrap_or_else(|| analyze(source)))
+    } else {
+      None
+    };
+    let first_pass_time = start.elapsed();
+    let qindex_map = self.qindex_map(first_pass.as_ref());
+
+    let start = Stopwatch::start();
+    let params = self.tile_params(Some(source), first_pass.as_ref());
+    let sb_rows = self.y_height.div_ceil(64);
+    let threads = self.analysis_threads(sb_rows);
+    let (writer, recon) = if threads > 1 {
+      self.encode_tile_wavefront(&params, source, qindex_map.as_ref(), threads, buffers)?
+    } else {
+      self.encode_tile_serial(&params, source, qindex_map.as_ref(), buffers)?
+    };
+    // The reconstruction covers the padded frame, but only the image itself is output
+    let mut recon = recon;
+    recon.set_crop_size(self.y_crop_width, self.y_crop_height);
+    //self.dump_recon(std::fs::File::create("recon.y4m").unwrap(), &recon).unwrap();
+    let recon = if self.superres() {
+      let upscaled = superres_upscale(&recon, self.y_crop_width, self.upscaled_width);
+      buffers.recon = Some(recon);
+      upscaled
+    } else {
+      recon
+    };
 
-    let mut tile = TileEncoder {
-      encoder: &self,
-      bitstream: EntropyWriter::new(),
-      base_qindex: base_qindex,
-      mode_info: Array2D::zeroed(mi_rows, mi_cols),
-      source: source,
-      recon: Frame::new(self.y_height, self.y_width),
+    let (tile_data, mut stats, trace) = self.finish_tile(writer);
+    stats.stage_times = StageTimes {
+      prefilter: prefilter_time,
+      first_pass: first_pass_time,
+      tile_coding: start.elapsed(),
     };
 
-    tile.encode();
-    //tile.dump_recon("recon.y4m").unwrap();
-    return tile.bitstream.finalize();
+    return Ok(EncodedImage {
+      tile_data: tile_data,
+      stats: stats,
+      first_pass: first_pass,
+      recon: Some(recon),
+      trace: trace,
+    });
   }
-}
 
-impl<'a> TileEncoder<'a> {
-  pub fn encode(&mut self) {
-    let mi_rows = self.mode_info.rows();
-    let mi_cols = self.mode_info.cols();
-    let sb_rows = mi_rows.div_ceil(16);
-    let sb_cols = mi_cols.div_ceil(16);
+  // Number of threads to use for analysis in encode_image()
+  // Audit mode checks each superblock as soon as it's written, so needs everything to
+  // happen in order.
+  fn analysis_threads(&self, sb_rows: usize) -> usize {
+    if self.audit {
+      return 1;
+    }
+    return min(ThreadPool::new(self.config.threads).threads(), sb_rows);
+  }
+
+  // Analyse and write each superblock in turn, on the current thread
+  fn encode_tile_serial(&self, params: &TileParams, source: &Frame, qindex_map: Option<&Array2D<u8>>,
+                        buffers: &mut TileBuffers) -> Result<(TileWriter, Frame), EncodeError> {
+    let sb_rows = self.y_height.div_ceil(64);
+    let sb_cols = self.y_width.div_ceil(64);
+
+    let mut tile = TileAnalyzer::with_buffers(params, source, qindex_map, self.segment_map.as_ref(), 0,
+                                              std::mem::take(buffers));
+    let mut writer = TileWriter::new(params, self.verify_coeffs, self.trace);
 
     for sb_row in 0..sb_rows {
       for sb_col in 0..sb_cols {
-        self.encode_superblock(sb_row, sb_col);
+        let decisions = tile.analyze_superblock(sb_row, sb_col);
+        writer.write_superblock(sb_row, sb_col, &decisions);
+        if self.audit {
+          self.audit_superblock(writer.bitstream(), tile.recon(), sb_row, sb_col);
+        }
       }
+      self.row_done(sb_row + 1, sb_rows)?;
     }
+
+    *buffers = tile.into_buffers();
+    return Ok((writer, buffers.recon.take().unwrap()));
   }
 
-  fn encode_superblock(&mut self, sb_row: usize, sb_col: usize) {
-    let mi_row = sb_row * 16;
-    let mi_col = sb_col * 16;
-    self.encode_partition(mi_row, mi_col, 64);
-  }
-
-  fn encode_partition(&mut self, mi_row: usize, mi_col: usize, bsize: usize) {
-    //println!("Encoding {:2}x{:2} partition at mi_row={:3}, mi_col={:3}", bsize, bsize, mi_row, mi_col);
-    // Always split down to 8x8 blocks
-    // For each partition symbol, the context depends on whether the above and/or left
-    // blocks are partitioned to a size smaller than what we're currently considering.
-    // For blocks at one of the frame edges, the missing neighbour is assumed to be
-    // the maximum possible size.
-    //
-    // Because we always split down to the same size, this ends up implying that the
-    // context is:
-    //
-    // Current partition is 8x8: context = 0
-    // Otherwise:
-    //   Top-left corner: context = 0
-    //   Left edge: context = 1
-    //   Top edge: context = 2
-    //   Everywhere else: context = 3
-    if bsize == 8 {
-      self.bitstream.write_symbol(0, &partition_8x8_cdf); // PARTITION_NONE
-      self.encode_block(mi_row, mi_col, bsize);
-    } else {
-      let mi_rows = self.mode_info.rows();
-      let mi_cols = self.mode_info.cols();
-
-      let sub_rows = if (mi_row + bsize/8) < mi_rows { 2 } else { 1 };
-      let sub_cols = if (mi_col + bsize/8) < mi_cols { 2 } else { 1 };
-
-      let above_ctx = if mi_row > 0 { 1 } else { 0 };
-      let left_ctx = if mi_col > 0 { 1 } else { 0 };
-      let ctx = 2 * left_ctx + above_ctx;
-
-      let cdf = match bsize {
-        16 => &partition_16x16_cdf[ctx],
-        32 => &partition_32x32_cdf[ctx],
-        64 => &partition_64x64_cdf[ctx],
-        _ => panic!("Reached an unexpected partition size")
-      };
-
-      if sub_rows > 1 && sub_cols > 1 {
-        // Normal case, all partitions are available
-        // Always choose PARTITION_SPLIT
-        self.bitstream.write_symbol(3, cdf);
-      } else if sub_cols > 1 {
-        // The bottom edge of the frame falls in the top half of this partition, so
-        // we must split horizontally. The only useful choice is whether to split the
-        // in-bounds part in half vertically.
-        //
-        // Thus we use a binary CDF to pick between PARTITION_HORZ (0) or PARTITION_SPLIT (1).
-        // The probability of PARTITION_SPLIT is calculated by summing the probabilities
-        // of the following options using the original CDF:
-        let p_split = get_prob(Partition::VERT as usize, cdf) +
-                      get_prob(Partition::SPLIT as usize, cdf) +
-                      get_prob(Partition::HORZ_A as usize, cdf) +
-                      get_prob(Partition::VERT_A as usize, cdf) +
-                      get_prob(Partition::VERT_B as usize, cdf) +
-                      get_prob(Partition::VERT_4 as usize, cdf);
-        self.bitstream.write_bit(1, 32768 - p_split);
-      } else if sub_rows > 1 {
-        // The right edge of the frame falls in the left half of this partition, so
-        // we must split vertically. The only useful choice is whether to split the
-        // in-bounds part in half horizontally.
-        //
-        // Thus we use a binary CDF to pick between PARTITION_VERT (0) or PARTITION_SPLIT (1).
-        // The probability of PARTITION_SPLIT is calculated by summing the probabilities
-        // of the following options using the original CDF:
-        let p_split = get_prob(Partition::HORZ as usize, cdf) +
-                      get_prob(Partition::SPLIT as usize, cdf) +
-                      get_prob(Partition::HORZ_A as usize, cdf) +
-                      get_prob(Partition::HORZ_B as usize, cdf) +
-                      get_prob(Partition::VERT_A as usize, cdf) +
-                      get_prob(Partition::HORZ_4 as usize, cdf);
-        self.bitstream.write_bit(1, 32768 - p_split);
-      } else {
-        // The bottom-right corner of the frame falls in the top-left quadrant of this partition,
-        // so PARTITION_SPLIT is forced. Therefore we don't need to signal anything.
+  // Analyse several superblock rows at once, in a wavefront: each row runs on its own
+  // thread, and starts each superblock once the row above has finished the superblocks
+  // above and above-right of it, which is all that intra prediction can depend on.
+  // The finished rows are written on the current thread, in order, so the output is
+  // identical to encode_tile_serial().
+  fn encode_tile_wavefront(&self, params: &TileParams, source: &Frame, qindex_map: Option<&Array2D<u8>>,
+                           threads: usize, buffers: &mut TileBuffers) -> Result<(TileWriter, Frame), EncodeError> {
+    let sb_rows = self.y_height.div_ceil(64);
+    let sb_cols = self.y_width.div_ceil(64);
+
+    // Rows are handed out in order, so the row above any row being analysed has always
+    // been started already, and every row can make progress
+    let next_row = AtomicUsize::new(0);
+    // Set if the encode is cancelled, to stop the analysis threads early
+    let stop = AtomicBool::new(false);
+
+    // Channels for passing finished superblocks down to the next row. Each row's thread
+    // takes the receiving end from above and the sending end to below, so if a thread
+    // stops early, the row below sees its channel close rather than waiting forever
+    let mut links: Vec<(Option<Receiver<SuperblockContext>>, Option<Sender<SuperblockContext>>)> =
+      (0..sb_rows).map(|_| (None, None)).collect();
+    for sb_row in 1..sb_rows {
+      let (sender, receiver) = channel();
+      links[sb_row - 1].1 = Some(sender);
+      links[sb_row].0 = Some(receiver);
+    }
+    let links: Vec<Mutex<_>> = links.into_iter().map(Mutex::new).collect();
+
+    let (results_sender, results) = channel();
+
+    let mut writer = TileWriter::new(params, self.verify_coeffs, self.trace);
+    let mut recon = buffers.take_recon(self.y_height, self.y_width);
+    let mut result = Ok(());
+
+    std::thread::scope(|scope| {
+      for _ in 0..threads {
+        let results_sender = results_sender.clone();
+        let (next_row, stop, links) = (&next_row, &stop, &links);
+        scope.spawn(move || {
+          loop {
+            let sb_row = next_row.fetch_add(1, Ordering::SeqCst);
+            if sb_row >= sb_rows {
+              break;
+            }
+            let (above, below) = std::mem::take(&mut *links[sb_row].lock().unwrap());
+            let Some(row) = self.analyze_row(params, source, qindex_map, sb_row, above, below, stop) else {
+              break;
+            };
+            if results_sender.send(row).is_err() {
+              break;
+            }
+          }
+        });
       }
+      // Only the analysis threads hold senders now, so if they all exit early
+      // (which means one of them has panicked), the loop below stops waiting
+      drop(results_sender);
+
+      let mut pending: Vec<Option<FinishedRow>> = (0..sb_rows).map(|_| None).collect();
+      'rows: for sb_row in 0..sb_rows {
+        while pending[sb_row].is_none() {
+          let Ok(row) = results.recv() else {
+            break 'rows;
+          };
+          let index = row.sb_row;
+          pending[index] = Some(row);
+        }
+        let row = pending[sb_row].take().unwrap();
 
-      let offset = bsize / 8;
-      for i in 0..sub_rows {
-        for j in 0..sub_cols {
-          self.encode_partition(mi_row + i*offset, mi_col + j*offset, bsize/2);
+        for (sb_col, decisions) in row.decisions.iter().enumerate() {
+          writer.write_superblock(sb_row, sb_col, decisions);
+        }
+        let top = row.recon.y().height() - row.rows;
+        for plane in 0..3 {
+          let subsampling = if plane > 0 { 1 } else { 0 };
+          recon.plane_mut(plane).copy_rows_from(row.recon.plane(plane), top >> subsampling,
+                                                (sb_row * 64) >> subsampling, row.rows >> subsampling);
+        }
+
+        if let Err(e) = self.row_done(sb_row + 1, sb_rows) {
+          stop.store(true, Ordering::Relaxed);
+          result = Err(e);
+          break;
         }
       }
-    }
-  }
+    });
 
-  fn encode_block(&mut self, mi_row: usize, mi_col: usize, bsize: usize) {
-    assert!(bsize == 8);
+    result?;
+    return Ok((writer, recon));
+  }
 
-    //println!("Encoding 8x8 block at mi_row={:3}, mi_col={:3}", mi_row, mi_col);
+  // Analyse one superblock row for encode_tile_wavefront(), in a band which also holds the
+  // row above. Finished superblocks from the row above arrive through `above`, and this
+  // row's superblocks are passed on through `below`.
+  // Returns None if the encode is stopped before the row is finished.
+  fn analyze_row(&self, params: &TileParams, source: &Frame, qindex_map: Option<&Array2D<u8>>, sb_row: usize,
+                 above: Option<Receiver<SuperblockContext>>, below: Option<Sender<SuperblockContext>>,
+                 stop: &AtomicBool) -> Option<FinishedRow> {
+    let mi_cols = self.y_width / 4;
+    let sb_cols = mi_cols.div_ceil(16);
 
-    // Allocate a ModeInfo struct to hold information about the current block
-    let mut this_mi = ModeInfo::zeroed();
+    let y0 = sb_row * 64;
+    let top = if sb_row > 0 { 64 } else { 0 };
+    let rows = min(64, self.y_height - y0);
 
-    // For skip, the context depends on the above and left skip flags,
-    // defaulting to false if those aren't present
-    // As we always set skip = false, this context is always 0
-    // skip = false
-    self.bitstream.write_symbol(0, &skip_cdf);
-  
-    // For intra_frame_y_mode, the context depends on the above and left Y modes,
-    // defaulting to DC_PRED if those aren't present
-    // As we always choose DC_PRED, this context is always 0
-    // intra_frame_y_mode(context=0,0) = DC_PRED
-    self.bitstream.write_symbol(0, &y_mode_cdf);
-
-    // For uv_mode, the context is simply y_mode combined with whether CFL is allowed
-    // Here the y mode is always DC_PRED and CFL is always allowed for 8x8 blocks,
-    // so we always end up with the same context
-    // uv_mode(context=0, CFL allowed) = DC_PRED
-    self.bitstream.write_symbol(0, &uv_mode_cdf);
-
-    // Encode residuals
+    let mut band_source = Frame::new(top + rows, self.y_width);
     for plane in 0..3 {
       let subsampling = if plane > 0 { 1 } else { 0 };
-      let y0 = (mi_row * 4) >> subsampling;
-      let x0 = (mi_col * 4) >> subsampling;
-      let h = bsize >> subsampling;
-      let w = bsize >> subsampling;
+      band_source.plane_mut(plane).copy_rows_from(source.plane(plane), (y0 - top) >> subsampling, 0,
+                                                  (top + rows) >> subsampling);
+    }
+
+    let mut tile = TileAnalyzer::new(params, &band_source, qindex_map, self.segment_map.as_ref(), (y0 - top) / 4);
+
+    let mut decisions = Vec::with_capacity(sb_cols);
+    let mut received = 0;
+    for sb_col in 0..sb_cols {
+      if let Some(above) = &above {
+        while received < min(sb_col + 2, sb_cols) {
+          let sb = above.recv().ok()?;
+          tile.set_superblock(0, received, &sb);
+          received += 1;
+        }
+      }
+      if stop.load(Ordering::Relaxed) {
+        return None;
+      }
 
-      dc_predict(self.recon.plane_mut(plane).pixels_mut(), y0, x0, h, w);
-      let mut residual = compute_residual(self.source.plane(plane).pixels(),
-                                          self.recon.plane(plane).pixels(),
-                                          y0, x0, h, w);
-      quantize(&mut residual, self.base_qindex);
+      decisions.push(tile.analyze_superblock(top / 64, sb_col));
+      if let Some(below) = &below {
+        below.send(tile.get_superblock(top / 64, sb_col)).ok()?;
+      }
+    }
 
-      // Encode the quantized coefficients while we have them,
-      // before we consume them to finalize the reconstructed image
-      self.encode_coeffs(plane, mi_row, mi_col, bsize, &mut this_mi, &residual);
+    return Some(FinishedRow {
+      sb_row: sb_row,
+      decisions: decisions,
+      recon: tile.into_recon(),
+      rows: rows,
+    });
+  }
 
-      dequantize(&mut residual, self.base_qindex);
-      apply_residual(self.recon.plane_mut(plane).pixels_mut(), residual, y0, x0, h, w);
+  // Collect the statistics and tile data once every superblock has been written
+  fn finish_tile(&self, writer: TileWriter) -> (Box<[u8]>, EncodeStats, Option<String>) {
+    let (tile_data, stats, coeff_log, trace) = writer.finish();
+    if let Some(coeff_log) = &coeff_log {
+      self.check_coeffs(&tile_data, coeff_log, self.config.qindex);
     }
+    return (tile_data, stats, trace);
+  }
 
-    // Save mode info
-    self.mode_info.fill_region(mi_row, mi_col, bsize/4, bsize/4, &this_mi);
+  // Render size to signal in the frame header, if one was requested and it differs from
+  // the size of the image
+  fn render_size(&self) -> Option<(usize, usize)> {
+    let render_size = (self.config.render_width, self.config.render_height);
+    if render_size == (0, 0) || render_size == (self.upscaled_width, self.y_crop_height) {
+      return None;
+    }
+    return Some(render_size);
   }
 
-  fn encode_coeffs(&mut self, plane: usize, mi_row: usize, mi_col: usize, bsize: usize, this_mi: &mut ModeInfo,
-                   coeffs: &Array2D<i32>) {
-    if bsize != 8 {
-      todo!();
+  // Check whether the current settings can be used with encode_image_streaming()
+  // Anything which needs to see the whole image at once can't be.
+  pub fn check_streaming(&self) -> Result<(), ConfigError> {
+    check_streaming(&self.config, self.upscaled_width)?;
+    if self.audit {
+      return Err(ConfigError::Unsupported("Auditing streamed images".into()));
     }
+    Ok(())
+  }
 
-    // Make sure there are the right number of coefficients
-    let txsize = if plane > 0 { bsize/2 } else { bsize };
-    let txs_ctx = if txsize == 8 { 1 } else { 0 };
-    let num_coeffs = txsize * txsize;
-    assert!(coeffs.rows() == txsize);
-    assert!(coeffs.cols() == txsize);
+  // See check_memory()
+  pub fn check_memory(&self, streaming: bool) -> Result<(), ConfigError> {
+    check_memory(&self.config, self.upscaled_width, self.y_crop_height, streaming)
+  }
 
-    let scan: &[(u8, u8)] = scan_order_2d[txs_ctx];
+  // Encode an image which is read in one superblock row at a time, so that only two
+  // superblock rows of the source and reconstruction (the current row, plus the previous
+  // row to predict from) are held in memory at once. The output is identical to what
+  // encode_image() would produce for the same image.
+  //
+  // The settings must have passed check_streaming()
+  pub fn encode_image_streaming(&self, source: &mut dyn RowSource) -> Result<EncodedImage, EncodeError> {
+    assert!(self.check_streaming().is_ok());
+    self.check_memory(true)?;
 
-    let qctx = get_qctx(self.base_qindex);
+    let mi_rows = self.y_height / 4;
+    let mi_cols = self.y_width / 4;
+    let sb_rows = mi_rows.div_ceil(16);
+    let sb_cols = mi_cols.div_ceil(16);
 
-    let ptype = if plane == 0 { 0 } else { 1 };
+    // Reading the source is interleaved with coding, so is included in the tile coding time
+    let start = Stopwatch::start();
 
-    // Find the "end of block" location
-    // This is one past the last nonzero coefficient, or 0 if all coeffs are zero
-    let mut eob = 0;
-    let mut culLevel = 0; // "Cumulative level", gets stored into this_mi.level_ctx
-    for c in 0..num_coeffs {
-      let (row, col) = scan[c];
-      let coeff = coeffs[row as usize][col as usize];
-      culLevel += abs(coeff);
-      if coeff != 0 {
-        eob = c + 1;
-      }
-    }
-    this_mi.level_ctx[plane] = min(culLevel, 63) as u8;
+    // State which carries over from one band to the next
+    let params = self.tile_params(None, None);
+    let qindex_map = self.qindex_map(None);
+    let mut writer = TileWriter::new(&params, self.verify_coeffs, self.trace);
+    let mut prev_row: Vec<SuperblockContext> = Vec::new();
 
-    let all_zero = eob == 0;
+    for sb_row in 0..sb_rows {
+      // Each band holds the previous superblock row (if any) followed by this one
+      let y0 = sb_row * 64;
+      let top = if sb_row > 0 { 64 } else { 0 };
+      let rows = min(64, self.y_crop_height - y0);
+
+      let mut band_source = Frame::new(top + rows, self.y_crop_width);
+      source.read_rows(y0, &mut band_source, top)?;
+      for plane in 0..3 {
+        band_source.plane_mut(plane).fill_padding();
+      }
 
-    // The all_zero symbol has a complex dependency on the nearby transform coefficients.
-    // For luma, there is a special case where this is short-circuited to 0 for max-size
-    // transforms (ie, transform size == block size), so we can ignore the complex logic.
-    // But for chroma it is mandatory.
-    let all_zero_ctx = if plane == 0 {
-      0
-    } else {
-      let mut above = false;
-      let mut left = false;
-      // In theory we need to scan all blocks above and left of the current block here
-      // However, because all blocks are currently 8x8, there's always exactly one
-      // block above and one block left
-      if mi_row > 0 {
-        let above_block = &self.mode_info[mi_row - 1][mi_col];
-        above |= above_block.level_ctx[plane] != 0;
-        above |= above_block.dc_sign[plane] != 0;
+      let mut tile = TileAnalyzer::new(&params, &band_source, qindex_map.as_ref(), self.segment_map.as_ref(),
+                                       (y0 - top) / 4);
+      for (sb_col, sb) in prev_row.iter().enumerate() {
+        tile.set_superblock(0, sb_col, sb);
       }
-      if mi_col > 0 {
-        let left_block = &self.mode_info[mi_row][mi_col - 1];
-        left |= left_block.level_ctx[plane] != 0;
-        left |= left_block.dc_sign[plane] != 0;
+      prev_row.clear();
+
+      for sb_col in 0..sb_cols {
+        let decisions = tile.analyze_superblock(top / 64, sb_col);
+        writer.write_superblock(sb_row, sb_col, &decisions);
+        if sb_row + 1 < sb_rows {
+          prev_row.push(tile.get_superblock(top / 64, sb_col));
+        }
       }
-      7 + (above as usize) + (left as usize)
-    };
+      self.row_done(sb_row + 1, sb_rows)?;
+    }
 
-    self.bitstream.write_symbol(all_zero as usize, &all_zero_cdf[qctx][txs_ctx][all_zero_ctx]);
-    if all_zero {
-      return;
-    }
-
-    // Transform type - only coded for luma
-    // As we selected the reduced transform set in the frame header,
-    // we end up looking at the TX_SET_INTRA_2 set, which consists of
-    // { IDTX, DCT_DCT, ADST_ADST, ADST_DCT, DCT_ADST }, in that order.
-    // We want DCT_DCT, so we want to encode index 1.
-    if plane == 0 {
-      self.bitstream.write_symbol(1, &tx_type_cdf);
-    }
-
-    // Number of coefficients, encoded as a logarithmic class + value within that class
-    // Here, the contexts are qindex, plane type, and (for 16x16 and smaller)
-    // whether the selected transform type is 1D (last context = 1) or 2D
-    // (last context = 0). We always choose DCT_DCT, which counts as a 2D transform
-    //
-    // The EOB is split into a class plus optional extra bits. Each class has the following range:
-    // Class 0 => EOB = 1
-    // Class 1 => EOB = 2
-    // Class 2 => EOB = 3-4
-    // Class 3 => EOB = 5-8
-    // ...
-    // up to a maximum class which depends on the transform size
-    // For 4x4 the largest class is class 4 (EOB = 9-16), for 8x8 it's class 6 (EOB = 33-64)
-    let eob_class = ceil_log2(eob) as usize;
-    let eob_class_cdf: &[u16] = if plane == 0 {
-      &eob_class_64_cdf[qctx][ptype]
-    } else {
-      &eob_class_16_cdf[qctx][ptype]
-    };
-    self.bitstream.write_symbol(eob_class, eob_class_cdf);
-
-    if eob_class > 1 {
-      let eob_class_low = (1 << (eob_class - 1)) + 1;
-      let eob_class_hi = 1 << eob_class;
-      assert!(eob_class_low <= eob && eob <= eob_class_hi);
-
-      // EOB classes 2+ require extra bits
-      // The first extra bit is coded with a special CDF, the rest are literal bits
-      // Context = (qctx, tx size, ptype, eob_class - 2)
-      // For 8x8 and luma, this gives:
-      let first_extra_bit_cdf = if plane == 0 {
-        &eob_extra_8x8_cdf[qctx][ptype][eob_class - 2]
-      } else {
-        &eob_extra_4x4_cdf[qctx][ptype][eob_class - 2]
-      };
-      let eob_shift = eob_class - 2;
-      let extra_bit = ((eob - eob_class_low) >> eob_shift) & 1;
-      self.bitstream.write_symbol(extra_bit, first_extra_bit_cdf);
-
-      // Write any remaining bits as a literal
-      // Note: The AV1 decoder spec gives a more detailed process here,
-      // but it's just writing individual bits from high to low,
-      // which is exactly what write_literal() does
-      let remainder = eob - eob_class_low - (extra_bit << eob_shift);
-      let remainder_bits = eob_class - 2;
-      self.bitstream.write_literal(remainder as u32, remainder_bits as u32);
-    }
-
-    // Write "base range" for each coefficient, in high-to-low index order
-    for c in (0..eob).rev() {
-      // Split coefficient into absolute value and sign, as these are coded separately
-      let (row, col) = scan[c];
-      let coeff = coeffs[row as usize][col as usize];
-      let abs_value = unsigned_abs(coeff) as usize;
-
-      // Code coeff_base symbol, which can indicate values 0, 1, 2, or 3+
-      if c == eob - 1 {
-        // Last nonzero coefficient, so we know this can't be zero
-        // Therefore we use a separate set of CDFs and contexts
-        let base_eob_ctx = if c == 0 {
-          0
-        } else if c <= num_coeffs/8 {
-          1
-        } else if c <= num_coeffs/4 {
-          2
-        } else {
-          3
-        };
-        assert!(abs_value >= 1);
-        let coded_value = min(abs_value - 1, 2);
-        self.bitstream.write_symbol(coded_value, &coeff_base_eob_cdf[qctx][txs_ctx][ptype][base_eob_ctx]);
-      } else {
-        // Context depends on the base values of coefficients below and to the right,
-        // which have already been encoded
-        let base_ctx = if c == 0 {
-          0
-        } else {
-          let mut mag = 0;
-
-          for (row_off, col_off) in Sig_Ref_Diff_Offset {
-            let ref_row = (row + row_off) as usize;
-            let ref_col = (col + col_off) as usize;
-            if ref_row < txsize && ref_col < txsize {
-              mag += min(abs(coeffs[ref_row][ref_col]), 3);
-            }
-          }
+    let (tile_data, mut stats, trace) = self.finish_tile(writer);
+    stats.stage_times.tile_coding = start.elapsed();
 
-          let mag_part = min(round2(mag, 1), 4) as usize;
-          let loc_part = Coeff_Base_Ctx_Offset_8x8[min(row, 4) as usize][min(col, 4) as usize] as usize;
-          mag_part + loc_part
-        };
+    return Ok(EncodedImage {
+      tile_data: tile_data,
+      stats: stats,
+      first_pass: None,
+      recon: None,
+      trace: trace,
+    });
+  }
 
-        let coded_value = min(abs_value, 3);
-        self.bitstream.write_symbol(coded_value, &coeff_base_cdf[qctx][txs_ctx][ptype][base_ctx]);
+  // Parse the coefficients back out of the tile data and compare them against
+  // what we meant to encode
+  fn check_coeffs(&self, tile_data: &[u8], expected: &[CoeffBlock], base_qindex: u8) {
+    let mut decoder = TileDecoder::new(tile_data, self.y_width, self.y_height,
+                                       base_qindex, self.frame_delta_q(), self.delta_q_present(),
+                                       self.segmentation(), self.screen_content_tools(), ENABLE_INTRA_EDGE_FILTER);
+    decoder.log_coeffs();
+    let result = decoder.decode();
+    let decoded = decoder.take_coeff_log();
+
+    // Check the blocks which were decoded before reporting any decode error,
+    // as a coefficient mismatch is usually what causes the error
+    for (i, (ours, theirs)) in expected.iter().zip(&decoded).enumerate() {
+      assert!(ours.plane == theirs.plane && ours.mi_row == theirs.mi_row && ours.mi_col == theirs.mi_col,
+              "Coefficient verification failed: block {} was coded as plane {} at mi_row={}, mi_col={}, but decoded as plane {} at mi_row={}, mi_col={}",
+              i, ours.plane, ours.mi_row, ours.mi_col, theirs.plane, theirs.mi_row, theirs.mi_col);
+      if ours.coeffs != theirs.coeffs {
+        let txsize = ours.coeffs.rows();
+        for row in 0..txsize {
+          for col in 0..txsize {
+            assert!(ours.coeffs[row][col] == theirs.coeffs[row][col],
+                    "Coefficient verification failed in plane {} of the 8x8 block at mi_row={}, mi_col={}: coefficient ({}, {}) was coded as {} but decodes as {}",
+                    ours.plane, ours.mi_row, ours.mi_col, row, col, ours.coeffs[row][col], theirs.coeffs[row][col]);
+          }
+        }
       }
+    }
 
-      // If coeff_base is 3, we can encode up to 4 symbols to increment the
-      // absolute value further. This can directly encode values up to 14,
-      // or the value 15 for all larger coefficients, in which case the remainder
-      // is Golomb encoded in a separate pass
-      if abs_value > 2 {
-        // All four coeff_br symbols use the same context and CDF, so compute that first
-        let br_ctx = {
-          let mut mag = 0;
-
-          for (row_off, col_off) in Mag_Ref_Offset {
-            let ref_row = (row + row_off) as usize;
-            let ref_col = (col + col_off) as usize;
-            if ref_row < txsize && ref_col < txsize {
-              mag += min(abs(coeffs[ref_row][ref_col]), 15);
-            }
-          }
+    if let Err(e) = result {
+      panic!("Coefficient verification failed after {} transform blocks: {}", decoded.len(), e);
+    }
+    assert!(decoded.len() == expected.len(),
+            "Coefficient verification failed: coded {} transform blocks, but decoded {}", expected.len(), decoded.len());
+  }
 
-          let mag_part = min(round2(mag, 1), 6) as usize;
-          let loc_part = if c == 0 {
-            0
-          } else if row < 2 && col < 2 {
-            7
-          } else {
-            14
-          };
-          mag_part + loc_part
-        };
-
-        // Now encode the coeff_br symbols
-        let mut level = 3;
-        for _ in 0..4 {
-          let coeff_br = min(abs_value - level, 3);
-          self.bitstream.write_symbol(coeff_br as usize, &coeff_br_cdf[qctx][txs_ctx][ptype][br_ctx]);
-          level += coeff_br;
-          if coeff_br < 3 {
-            break;
-          }
-        }
+  // Decode everything written so far, and check that the most recent superblock
+  // decodes to the same pixels as we reconstructed
+  fn audit_superblock(&self, bitstream: &EntropyWriter, recon: &Frame, sb_row: usize, sb_col: usize) {
+    let data = bitstream.clone().finalize();
+    let mut decoder = TileDecoder::new(&data, self.y_width, self.y_height,
+                                       self.config.qindex, self.frame_delta_q(), self.delta_q_present(),
+                                       self.segmentation(), self.screen_content_tools(), ENABLE_INTRA_EDGE_FILTER);
+
+    // Earlier superblocks have already been checked, so only report errors in this one
+    let sb_cols = decoder.sb_cols();
+    for sb_index in 0 ..= sb_row * sb_cols + sb_col {
+      if let Err(e) = decoder.decode_superblock(sb_index / sb_cols, sb_index % sb_cols) {
+        panic!("Audit failed in superblock at row {}, column {}: {}", sb_row, sb_col, e);
       }
     }
 
-    // Code DC sign + golomb bits
-    let dc_coeff = coeffs[0][0];
-    if dc_coeff != 0 {
-      // The DC sign context depends on whether there are more +ve signs, more -ve signs,
-      // or an equal number, among all above and left 4x4 units. Since we always use 8x8
-      // blocks, there is exactly one above and one left neighbour.
-      //
-      // Also, for the chroma planes, in theory we're only meant to look at the blocks which are "chroma references",
-      // i.e. the ones which contain an MI unit with odd mi_row and mi_col. This matters if we ever support 4x4
-      // block sizes, but as we currently don't, that's just every block.
-      //
-      // Therefore we can simplify the scan given in the spec, into just looking at the single above and single left
-      // block, if they exist.
-      //
-      // As we store the DC sign in ModeInfo::dc_sign as -1 / 0 / +1, we can do this by
-      // simply summing the DC signs of all surrounding blocks
-      let mut net_neighbour_sign = 0;
-      if mi_row > 0 {
-        net_neighbour_sign += self.mode_info[mi_row - 1][mi_col].dc_sign[plane];
+    // Check each 8x8 block in coding order (which is Z-order within a superblock),
+    // so that the first mismatch reported is the one which caused any later ones
+    for block_index in 0..64 {
+      let mut block_row = 0;
+      let mut block_col = 0;
+      for bit in 0..3 {
+        block_col |= ((block_index >> (2 * bit)) & 1) << bit;
+        block_row |= ((block_index >> (2 * bit + 1)) & 1) << bit;
       }
-      if mi_col > 0 {
-        net_neighbour_sign += self.mode_info[mi_row][mi_col - 1].dc_sign[plane];
+      let mi_row = sb_row * 16 + block_row * 2;
+      let mi_col = sb_col * 16 + block_col * 2;
+      if mi_row >= self.y_height / 4 || mi_col >= self.y_width / 4 {
+        continue;
       }
-  
-      // Map result to the appropriate context
-      let dc_sign_ctx = if net_neighbour_sign == 0 {
-        0
-      } else if net_neighbour_sign < 0 {
-        1
-      } else {
-        2
-      };
 
-      let sign = if dc_coeff < 0 { 1 } else { 0 };
-      self.bitstream.write_symbol(sign, &dc_sign_cdf[qctx][ptype][dc_sign_ctx]);
-    }
-    if abs(dc_coeff) >= 15 {
-      self.bitstream.write_golomb(unsigned_abs(dc_coeff) - 15);
+      for plane in 0..3 {
+        let subsampling = if plane > 0 { 1 } else { 0 };
+        let y0 = (mi_row * 4) >> subsampling;
+        let x0 = (mi_col * 4) >> subsampling;
+        let size = 8 >> subsampling;
+        let ours = recon.plane(plane).pixels();
+        let theirs = decoder.recon().plane(plane).pixels();
+        for i in y0 .. y0 + size {
+          for j in x0 .. x0 + size {
+            if ours[i][j] != theirs[i][j] {
+              panic!("Audit failed in 8x8 block at mi_row={}, mi_col={}: plane {} pixel ({}, {}) was reconstructed as {} but decodes as {}",
+                     mi_row, mi_col, plane, i, j, ours[i][j], theirs[i][j]);
+            }
+          }
+        }
+      }
     }
+  }
 
-    // Store DC sign for reference by later blocks
-    this_mi.dc_sign[plane] = signum(dc_coeff) as i8;
+  fn dump_recon<W: Write>(&self, w: W, recon: &Frame) -> Result<(), io::Error> {
+    let mut y4m = Y4MWriter::new(w, recon.y().crop_width(), recon.y().crop_height())?;
+    y4m.write_frame(recon)?;
+    Ok(())
+  }
+}
 
-    // Code sign + golomb bits for the rest of coefficients
-    // Note that this is done in low-to-high index order, in contrast to the earlier loop
-    for c in 1..eob {
-      let (row, col) = scan[c];
-      let coeff = coeffs[row as usize][col as usize];
-      if coeff != 0 {
-        let sign = if coeff < 0 { 1 } else { 0 };
-        self.bitstream.write_literal(sign, 1);
-      }
+// Width of the image which is actually coded: with superres, this is less than the
+// width of the upscaled image
+fn coded_width(config: &EncoderConfig, upscaled_width: usize) -> usize {
+  if config.superres_denominator > SUPERRES_NUM as u8 {
+    superres_coded_width(upscaled_width, config.superres_denominator as usize)
+  } else {
+    upscaled_width
+  }
+}
 
-      if abs(coeff) >= 15 {
-        self.bitstream.write_golomb(unsigned_abs(coeff) - 15);
-      }
+// Check whether `config` can be used with encode_image_streaming() for an image of the
+// given width. Anything which needs to see the whole image at once can't be.
+pub fn check_streaming(config: &EncoderConfig, width: usize) -> Result<(), ConfigError> {
+  if config.passes != 1 {
+    return Err(ConfigError::Unsupported("Two-pass encoding of streamed images".into()));
+  }
+  if !prefilters(config).is_empty() {
+    return Err(ConfigError::Unsupported("Pre-filtering of streamed images".into()));
+  }
+  if coded_width(config, width) != width {
+    return Err(ConfigError::Unsupported("Superres with streamed images".into()));
+  }
+  if config.tune == Tune::Ssim {
+    return Err(ConfigError::Unsupported("SSIM tuning of streamed images".into()));
+  }
+  Ok(())
+}
+
+// Estimate the memory which encoding a `width`x`height` image with `config` needs at once,
+// including the source image itself (see memory.rs). With `streaming`, this is for
+// encode_image_streaming(), which only holds two superblock rows of the image at a time.
+//
+// This only depends on the settings and the image size, so can be worked out before
+// creating an encoder or reading the image
+pub fn memory_estimate(config: &EncoderConfig, width: usize, height: usize, streaming: bool) -> MemoryEstimate {
+  let upscaled_width = width;
+  let crop_width = coded_width(config, upscaled_width);
+  let superres = crop_width != upscaled_width;
+  let size = FrameGeometry::new(crop_width, height);
+  let (y_width, y_height) = (size.y_width, size.y_height);
+
+  let mi_rows = y_height / 4;
+  let mi_cols = y_width / 4;
+  let mode_info_bytes = |rows: usize| (rows / 4) * mi_cols * std::mem::size_of::<ModeInfo>();
+  // Bands hold a superblock row and the row above it
+  let band_rows = min(128, y_height);
+  // Decisions are made for each 8x8 block, and a superblock row of them can be waiting
+  // to be written
+  let sb_row_decisions = (mi_cols / 2) * 8 * block_decision_bytes();
+
+  let mut estimate = MemoryEstimate::default();
+  if streaming {
+    estimate.add("source band", frame_bytes(crop_width, band_rows));
+    estimate.add("recon band", frame_bytes(y_width, band_rows));
+    estimate.add("mode info", mode_info_bytes(band_rows));
+    estimate.add("block decisions", 64 * block_decision_bytes());
+    estimate.add("block scratch", block_scratch_bytes());
+  } else {
+    estimate.add("source", frame_bytes(upscaled_width, height));
+    if superres {
+      estimate.add("downscaled source", frame_bytes(crop_width, height));
+    }
+    if !prefilters(config).is_empty() {
+      estimate.add("filtered source", frame_bytes(crop_width, height));
+    }
+    estimate.add("recon", frame_bytes(y_width, y_height));
+    if superres {
+      estimate.add("upscaled recon", frame_bytes(upscaled_width, height));
     }
+    // This may be more threads than the encoder actually uses (eg. with --audit), which
+    // only makes the estimate more cautious
+    let threads = min(ThreadPool::new(config.threads).threads(), y_height.div_ceil(64));
+    if threads > 1 {
+      // Each analysis thread has its own band of the source and reconstruction, and
+      // holds the decisions for its whole row until the writer takes them
+      estimate.add("analysis bands", threads * (2 * frame_bytes(y_width, band_rows) + mode_info_bytes(band_rows)));
+      estimate.add("block decisions", threads * sb_row_decisions);
+    } else {
+      estimate.add("mode info", mode_info_bytes(y_height));
+      estimate.add("block decisions", 64 * block_decision_bytes());
+    }
+    estimate.add("block scratch", threads * block_scratch_bytes());
   }
+  // The statistics keep the mode and size of every 4x4 block
+  estimate.add("statistics", mi_rows * mi_cols * (std::mem::size_of::<PredictionMode>() + 1));
+  // The tile data only approaches the size of the image itself at the lowest qindexes,
+  // but allow for that, so that the limit holds whatever the image looks like
+  estimate.add("output", frame_bytes(crop_width, height));
+  return estimate;
+}
 
-  fn dump_recon(&mut self, path: &str) -> Result<(), io::Error> {
-    let mut y4m = Y4MWriter::new(File::create(path)?, self.encoder.y_width, self.encoder.y_height)?;
-    y4m.write_frame(&self.recon)?;
-    Ok(())
+// Check memory_estimate() against the limit in `config`, if there is one
+pub fn check_memory(config: &EncoderConfig, width: usize, height: usize, streaming: bool) -> Result<(), ConfigError> {
+  let estimate = memory_estimate(config, width, height, streaming);
+  if config.max_memory != 0 && estimate.total() > config.max_memory {
+    return Err(ConfigError::TooLarge(format!("Encoding this {}x{} image {}needs about {}, more than the limit of {}",
+                                             width, height, if streaming { "one row at a time " } else { "" },
+                                             estimate, format_bytes(config.max_memory))));
   }
+  Ok(())
 }
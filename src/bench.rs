@@ -0,0 +1,144 @@
+// Copyright (c) 2024-2025, The tinyavif contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+// Benchmarks of the encoder's hot spots, run with `tinyavif bench`
+//
+// These live in the crate, rather than using an external harness, so that they can
+// call the internal transform, quantization and prediction functions directly. They are
+// for checking performance-motivated changes (eg. SIMD versions of a function, or changes
+// to Array2D): run them before and after, on the same machine, and compare.
+//
+// Each benchmark runs its code in batches, doubling the batch size until one batch takes
+// at least the requested time, and reports the average time per iteration of that batch.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use crate::array2d::Array2D;
+use crate::av1_encoder::AV1Encoder;
+use crate::config::{EncoderConfig, Tune};
+use crate::enums::TxType;
+use crate::frame::Frame;
+use crate::recon::{dc_predict, dequantize, quant_rounding_bias, quantize, Quantizer};
+use crate::testutil::{gradient_frame, screen_frame, XorShift32};
+use crate::txfm::{fwd_txfm2d, inv_txfm2d};
+
+pub struct BenchResult {
+  pub name: &'static str,
+  pub iterations: u64,
+  pub time_per_iteration: Duration,
+}
+
+// qindex used for the quantization and whole-image benchmarks, the encoder's default
+const BENCH_QINDEX: u8 = 35;
+
+// Size of the synthetic images for the whole-image benchmarks
+const BENCH_WIDTH: usize = 256;
+const BENCH_HEIGHT: usize = 256;
+
+// Run `f` until a batch of iterations takes at least `min_time`
+fn measure<F: FnMut()>(name: &'static str, min_time: Duration, mut f: F) -> BenchResult {
+  let mut iterations = 1;
+  loop {
+    let start = Instant::now();
+    for _ in 0..iterations {
+      f();
+    }
+    let elapsed = start.elapsed();
+    if elapsed >= min_time {
+      return BenchResult {
+        name: name,
+        iterations: iterations,
+        time_per_iteration: elapsed.div_f64(iterations as f64),
+      };
+    }
+    iterations *= 2;
+  }
+}
+
+// A residual block of the given size, with values like those from a real image
+fn residual_block(size: usize, rng: &mut XorShift32) -> Array2D<i32> {
+  let mut residual = Array2D::zeroed(size, size);
+  residual.map(|i, j, _| (i as i32 - j as i32) * 4 + (rng.next_u32() % 33) as i32 - 16);
+  return residual;
+}
+
+// Encode a whole image, from the prefilters through to the finished tile data
+fn encode(source: &Frame, tune: Tune) -> Box<[u8]> {
+  let config = EncoderConfig::builder()
+    .qindex(BENCH_QINDEX)
+    .tune(tune)
+    .build()
+    .unwrap();
+  let encoder = AV1Encoder::new(&config, source.y().crop_width(), source.y().crop_height()).unwrap();
+  return encoder.encode_image(source).unwrap().tile_data;
+}
+
+// Names of all the benchmarks, in the order they run
+pub const BENCHMARKS: [&str; 7] = [
+  "fwd_dct8x8", "inv_dct8x8", "quantize8x8", "dequantize8x8", "dc_predict8x8",
+  "encode_gradient_256x256", "encode_screen_256x256",
+];
+
+// Run every benchmark whose name contains `filter`, or all of them if there is no filter
+pub fn run_benchmarks(filter: Option<&str>, min_time: Duration) -> Vec<BenchResult> {
+  let mut rng = XorShift32::new(0x2545F491);
+  let residual = residual_block(8, &mut rng);
+  let mut coeffs = residual.clone();
+  fwd_txfm2d(&mut coeffs, 8, 8, TxType::DCT_DCT);
+  let quantizer = Quantizer::new(BENCH_QINDEX, 0, 0, 8);
+  let bias = quant_rounding_bias(BENCH_QINDEX, 0);
+  let mut quantized = coeffs.clone();
+  quantize(&mut quantized, &quantizer, bias);
+  let gradient = gradient_frame(BENCH_WIDTH, BENCH_HEIGHT, 0x9E3779B9);
+  let screen = screen_frame(BENCH_WIDTH, BENCH_HEIGHT);
+  // An 8x8 block inside a larger area, so that it has both neighbours to predict from
+  let mut pixels = gradient.y().pixels().clone();
+
+  let mut results = Vec::new();
+  for name in BENCHMARKS {
+    if filter.is_some_and(|filter| !name.contains(filter)) {
+      continue;
+    }
+    let result = match name {
+      "fwd_dct8x8" => measure(name, min_time, || {
+        let mut block = residual.clone();
+        fwd_txfm2d(black_box(&mut block), 8, 8, TxType::DCT_DCT);
+        black_box(&block);
+      }),
+      "inv_dct8x8" => measure(name, min_time, || {
+        let mut block = coeffs.clone();
+        inv_txfm2d(black_box(&mut block), 8, 8, TxType::DCT_DCT);
+        black_box(&block);
+      }),
+      "quantize8x8" => measure(name, min_time, || {
+        let mut block = coeffs.clone();
+        quantize(black_box(&mut block), &quantizer, bias);
+        black_box(&block);
+      }),
+      "dequantize8x8" => measure(name, min_time, || {
+        let mut block = quantized.clone();
+        dequantize(black_box(&mut block), &quantizer);
+        black_box(&block);
+      }),
+      "dc_predict8x8" => measure(name, min_time, || {
+        dc_predict(black_box(&mut pixels), 8, 8, 8, 8);
+      }),
+      "encode_gradient_256x256" => measure(name, min_time, || {
+        black_box(encode(&gradient, Tune::Psnr));
+      }),
+      "encode_screen_256x256" => measure(name, min_time, || {
+        black_box(encode(&screen, Tune::Screen));
+      }),
+      _ => unreachable!(),
+    };
+    results.push(result);
+  }
+  return results;
+}
@@ -10,6 +10,8 @@
 // AV1-compatible bitwise reader/writer structs
 // In AV1, bytes are written most-significant-bit-first
 
+use std::io;
+
 use crate::util::write_be_bytes;
 
 pub struct BitWriter {
@@ -46,7 +48,7 @@ impl BitWriter {
   pub fn write_bits(&mut self, bits: u64, nbits: usize) {
     // Only allow up to 56 bits in a single write, to simplify some later logic
     assert!(nbits <= 56);
-    assert!(bits < (1u64 << nbits));
+    assert!(bits < (1u64 << nbits), "Value {} doesn't fit in a {}-bit field", bits, nbits);
 
     // Pack the current pending bits + new bits into one 64-bit value
     // This will always fit because we require nbits <= 56 above
@@ -61,6 +63,41 @@ impl BitWriter {
     self.bitpos += nbits;
   }
 
+  // Signed value in two's complement form, the spec's su(n)
+  pub fn write_su(&mut self, value: i64, nbits: usize) {
+    assert!(1 <= nbits && nbits <= 56);
+    let limit = 1i64 << (nbits - 1);
+    assert!(-limit <= value && value < limit, "Value {} doesn't fit in su({})", value, nbits);
+    self.write_bits((value as u64) & ((1u64 << nbits) - 1), nbits);
+  }
+
+  // Exp-Golomb style variable-length value, the spec's uvlc(): the number of bits in
+  // value + 1, as that many zeros minus one, then value + 1 itself.
+  // Note that u32::MAX can't be coded, as decoders treat anything longer than 32 leading
+  // zeros as u32::MAX instead
+  pub fn write_uvlc(&mut self, value: u32) {
+    let value_plus_one = value as u64 + 1;
+    let leading_zeros = 63 - value_plus_one.leading_zeros() as usize;
+    assert!(leading_zeros < 32, "Value {} can't be coded as uvlc()", value);
+    self.write_bits(0, leading_zeros);
+    self.write_bits(value_plus_one, leading_zeros + 1);
+  }
+
+  // Value in the range [0, n), the spec's ns(n). This uses one bit less than a plain
+  // ceil(log2(n))-bit value for the smallest values when n isn't a power of 2
+  pub fn write_ns(&mut self, value: u64, n: u64) {
+    assert!(value < n, "Value {} doesn't fit in ns({})", value, n);
+    let w = 64 - n.leading_zeros() as usize;
+    let m = (1u64 << w) - n;
+    if value < m {
+      self.write_bits(value, w - 1);
+    } else {
+      let extended = value + m;
+      self.write_bits(extended >> 1, w - 1);
+      self.write_bit((extended & 1) as u8);
+    }
+  }
+
   pub fn byte_align(&mut self) {
     let partial_bits = self.bitpos % 8;
     if partial_bits == 0 {
@@ -87,3 +124,103 @@ impl BitWriter {
     return self.data.into_boxed_slice();
   }
 }
+
+pub struct BitReader<'a> {
+  data: &'a [u8],
+  bitpos: usize
+}
+
+impl<'a> BitReader<'a> {
+  pub fn new(data: &'a [u8]) -> Self {
+    Self {
+      data: data,
+      bitpos: 0
+    }
+  }
+
+  pub fn read_bit(&mut self) -> Result<u8, io::Error> {
+    let byte = match self.data.get(self.bitpos / 8) {
+      Some(byte) => *byte,
+      None => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Unexpected end of bitstream")),
+    };
+    let bit = (byte >> (7 - self.bitpos % 8)) & 1;
+    self.bitpos += 1;
+    Ok(bit)
+  }
+
+  pub fn read_bool(&mut self) -> Result<bool, io::Error> {
+    Ok(self.read_bit()? != 0)
+  }
+
+  pub fn read_bits(&mut self, nbits: usize) -> Result<u64, io::Error> {
+    assert!(nbits <= 56);
+    let mut value = 0u64;
+    for _ in 0..nbits {
+      value = (value << 1) | (self.read_bit()? as u64);
+    }
+    Ok(value)
+  }
+
+  // Signed value in two's complement form, the spec's su(n)
+  pub fn read_su(&mut self, nbits: usize) -> Result<i64, io::Error> {
+    let value = self.read_bits(nbits)? as i64;
+    let sign_mask = 1i64 << (nbits - 1);
+    Ok((value ^ sign_mask) - sign_mask)
+  }
+
+  // Exp-Golomb style variable-length value, the spec's uvlc()
+  pub fn read_uvlc(&mut self) -> Result<u32, io::Error> {
+    let mut leading_zeros = 0;
+    while !self.read_bool()? {
+      leading_zeros += 1;
+    }
+    if leading_zeros >= 32 {
+      return Ok(u32::MAX);
+    }
+    let value = self.read_bits(leading_zeros)? as u32;
+    Ok(value + ((1u64 << leading_zeros) - 1) as u32)
+  }
+
+  // Value in the range [0, n), the spec's ns(n)
+  pub fn read_ns(&mut self, n: u64) -> Result<u64, io::Error> {
+    let w = 64 - n.leading_zeros() as usize;
+    let m = (1u64 << w) - n;
+    let value = self.read_bits(w - 1)?;
+    if value < m {
+      return Ok(value);
+    }
+    let extra_bit = self.read_bit()? as u64;
+    Ok((value << 1) - m + extra_bit)
+  }
+
+  // Variable-length size field, as used in OBU headers. Must be byte aligned
+  pub fn read_leb128(&mut self) -> Result<u64, io::Error> {
+    assert!(self.bitpos % 8 == 0);
+    let mut value = 0u64;
+    for i in 0..8 {
+      let byte = self.read_bits(8)?;
+      value |= (byte & 0x7F) << (7 * i);
+      if byte & 0x80 == 0 {
+        break;
+      }
+    }
+    if value > u32::MAX as u64 {
+      return Err(io::Error::new(io::ErrorKind::InvalidData, format!("leb128 value {} is larger than 2^32 - 1", value)));
+    }
+    Ok(value)
+  }
+
+  pub fn byte_align(&mut self) {
+    self.bitpos = self.bitpos.next_multiple_of(8);
+  }
+
+  // Current position, in bytes. Must be byte aligned
+  pub fn byte_pos(&self) -> usize {
+    assert!(self.bitpos % 8 == 0);
+    self.bitpos / 8
+  }
+
+  pub fn bit_pos(&self) -> usize {
+    self.bitpos
+  }
+}
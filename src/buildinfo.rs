@@ -0,0 +1,86 @@
+// Copyright (c) 2024-2025, The tinyavif contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+// Information about how this binary was built, and what it can use at runtime
+//
+// Output from different builds of tinyavif *should* be byte-identical, but if
+// a bug report shows differing output, this lets us quickly tell whether the two
+// builds took different code paths (eg. SIMD vs. scalar)
+
+use std::env;
+use std::fmt;
+
+use crate::simd::simd_level;
+pub use crate::simd::SimdLevel;
+
+pub struct BuildInfo {
+  pub version: &'static str,
+  pub rustc_version: &'static str,
+  pub profile: &'static str,
+  pub target_arch: &'static str,
+  pub target_os: &'static str,
+  pub target_endian: &'static str,
+
+  // Cargo features enabled at compile time
+  pub features: Vec<&'static str>,
+  // CPU features enabled at compile time (eg. via -C target-cpu=native)
+  pub target_features: Vec<&'static str>,
+  // SIMD instruction set selected at runtime
+  pub simd_level: SimdLevel,
+}
+
+pub fn build_info() -> BuildInfo {
+  let mut features = Vec::new();
+  if cfg!(feature = "image") {
+    features.push("image");
+  }
+
+  let mut target_features = Vec::new();
+  if cfg!(target_feature = "sse4.1") {
+    target_features.push("sse4.1");
+  }
+  if cfg!(target_feature = "avx2") {
+    target_features.push("avx2");
+  }
+  if cfg!(target_feature = "neon") {
+    target_features.push("neon");
+  }
+
+  BuildInfo {
+    version: env!("CARGO_PKG_VERSION"),
+    rustc_version: env!("TINYAVIF_RUSTC_VERSION"),
+    profile: if cfg!(debug_assertions) { "debug" } else { "release" },
+    target_arch: env::consts::ARCH,
+    target_os: env::consts::OS,
+    target_endian: if cfg!(target_endian = "little") { "little" } else { "big" },
+    features: features,
+    target_features: target_features,
+    simd_level: simd_level(),
+  }
+}
+
+fn list_or_none(items: &[&str]) -> String {
+  if items.is_empty() {
+    "none".into()
+  } else {
+    items.join(", ")
+  }
+}
+
+impl fmt::Display for BuildInfo {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    writeln!(f, "tinyavif {}", self.version)?;
+    writeln!(f, "Compiler: {}", self.rustc_version)?;
+    writeln!(f, "Profile: {}", self.profile)?;
+    writeln!(f, "Target: {} ({}, {}-endian)", self.target_arch, self.target_os, self.target_endian)?;
+    writeln!(f, "Cargo features: {}", list_or_none(&self.features))?;
+    writeln!(f, "Compile-time CPU features: {}", list_or_none(&self.target_features))?;
+    write!(f, "Runtime SIMD: {:?}", self.simd_level)
+  }
+}
@@ -0,0 +1,37 @@
+// Copyright (c) 2024-2025, The tinyavif contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+// CDFs for block-level syntax other than modes, palettes and transforms
+
+use crate::consts::*;
+
+// The skip context is the number of neighbouring blocks (above and left) which were skipped
+pub const skip_cdf: [[u16; 1]; SKIP_CONTEXTS] = [[31671], [16515], [4576]];
+
+// Segment IDs, coded relative to a prediction from the above and left blocks
+// The context is how many of the above-left, above, and left segment IDs agree
+pub const segment_id_cdf: [[u16; MAX_SEGMENTS - 1]; SEGMENT_ID_CONTEXTS] = [
+  [5622, 7893, 16093, 18233, 27809, 28373, 32533],
+  [14274, 18230, 22557, 24935, 29980, 30851, 32344],
+  [27527, 28487, 28723, 28890, 32397, 32647, 32679]
+];
+
+// Superblock-level delta-q and delta-lf
+// The absolute value is coded as 0, 1, 2, or "3+" (DELTA_Q_SMALL), with larger values
+// being followed by literal bits. With delta_lf_multi, each loop filter level has its own CDF
+pub const delta_q_abs_cdf: [u16; 3] = [28160, 32120, 32677];
+
+pub const delta_lf_abs_cdf: [u16; 3] = [28160, 32120, 32677];
+
+pub const delta_lf_multi_cdf: [[u16; 3]; FRAME_LF_COUNT] = [
+  [28160, 32120, 32677],
+  [28160, 32120, 32677],
+  [28160, 32120, 32677],
+  [28160, 32120, 32677]
+];
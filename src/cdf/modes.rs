@@ -0,0 +1,148 @@
+// Copyright (c) 2024-2025, The tinyavif contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+// Intra prediction mode CDFs
+
+use crate::consts::*;
+use crate::enums::{DIRECTIONAL_MODES, INTRA_MODES};
+
+// intra_frame_y_mode, indexed by the contexts of the above and left modes (see
+// Intra_Mode_Context in the spec), in that order
+pub const y_mode_cdf: [[[u16; 12]; INTRA_MODE_CONTEXTS]; INTRA_MODE_CONTEXTS] = [
+  // above context = 0
+  [
+    [15588, 17027, 19338, 20218, 20682, 21110, 21825, 23244, 24189, 28165, 29093, 30466],
+    [12016, 18066, 19516, 20303, 20719, 21444, 21888, 23032, 24434, 28658, 30172, 31409],
+    [10052, 10771, 22296, 22788, 23055, 23239, 24133, 25620, 26160, 29336, 29929, 31567],
+    [14091, 15406, 16442, 18808, 19136, 19546, 19998, 22096, 24746, 29585, 30958, 32462],
+    [12122, 13265, 15603, 16501, 18609, 20033, 22391, 25583, 26437, 30261, 31073, 32475]
+  ],
+  // above context = 1
+  [
+    [10023, 19585, 20848, 21440, 21832, 22760, 23089, 24023, 25381, 29014, 30482, 31436],
+    [5983, 24099, 24560, 24886, 25066, 25795, 25913, 26423, 27610, 29905, 31276, 31794],
+    [7444, 12781, 20177, 20728, 21077, 21607, 22170, 23405, 24469, 27915, 29090, 30492],
+    [8537, 14689, 15432, 17087, 17408, 18172, 18408, 19825, 24649, 29153, 31096, 32210],
+    [7543, 14231, 15496, 16195, 17905, 20717, 21984, 24516, 26001, 29675, 30981, 31994]
+  ],
+  // above context = 2
+  [
+    [12613, 13591, 21383, 22004, 22312, 22577, 23401, 25055, 25729, 29538, 30305, 32077],
+    [9687, 13470, 18506, 19230, 19604, 20147, 20695, 22062, 23219, 27743, 29211, 30907],
+    [6183, 6505, 26024, 26252, 26366, 26434, 27082, 28354, 28555, 30467, 30794, 32086],
+    [10718, 11734, 14954, 17224, 17565, 17924, 18561, 21523, 23878, 28975, 30287, 32252],
+    [9194, 9858, 16501, 17263, 18424, 19171, 21563, 25961, 26561, 30072, 30737, 32463]
+  ],
+  // above context = 3
+  [
+    [12602, 14399, 15488, 18381, 18778, 19315, 19724, 21419, 25060, 29696, 30917, 32409],
+    [8203, 13821, 14524, 17105, 17439, 18131, 18404, 19468, 25225, 29485, 31158, 32342],
+    [8451, 9731, 15004, 17643, 18012, 18425, 19070, 21538, 24605, 29118, 30078, 32018],
+    [7714, 9048, 9516, 16667, 16817, 16994, 17153, 18767, 26743, 30389, 31536, 32528],
+    [8843, 10280, 11496, 15317, 16652, 17943, 19108, 22718, 25769, 29953, 30983, 32485]
+  ],
+  // above context = 4
+  [
+    [12578, 13671, 15979, 16834, 19075, 20913, 22989, 25449, 26219, 30214, 31150, 32477],
+    [9563, 13626, 15080, 15892, 17756, 20863, 22207, 24236, 25380, 29653, 31143, 32277],
+    [8356, 8901, 17616, 18256, 19350, 20106, 22598, 25947, 26466, 29900, 30523, 32261],
+    [10835, 11815, 13124, 16042, 17018, 18039, 18947, 22753, 24615, 29489, 30883, 32482],
+    [7618, 8288, 9859, 10509, 15386, 18657, 22903, 28776, 29180, 31355, 31802, 32593]
+  ]
+];
+
+// uv_mode, indexed by y_mode. Which set is used depends on whether CFL is allowed,
+// which it is for blocks up to 32x32
+pub const uv_mode_cfl_not_allowed_cdf: [[u16; 12]; INTRA_MODES] = [
+  [22631, 24152, 25378, 25661, 25986, 26520, 27055, 27923, 28244, 30059, 30941, 31961], // DC_PRED
+  [9513, 26881, 26973, 27046, 27118, 27664, 27739, 27824, 28359, 29505, 29800, 31796], // V_PRED
+  [9845, 9915, 28663, 28704, 28757, 28780, 29198, 29822, 29854, 30764, 31777, 32029], // H_PRED
+  [13639, 13897, 14171, 25331, 25606, 25727, 25953, 27148, 28577, 30612, 31355, 32493], // D45_PRED
+  [9764, 9835, 9930, 9954, 25386, 27053, 27958, 28148, 28243, 31101, 31744, 32363], // D135_PRED
+  [11825, 13589, 13677, 13720, 15048, 29213, 29301, 29458, 29711, 31161, 31441, 32550], // D113_PRED
+  [14175, 14399, 16608, 16821, 17718, 17775, 28551, 30200, 30245, 31837, 32342, 32667], // D157_PRED
+  [12885, 13038, 14978, 15590, 15673, 15748, 16176, 29128, 29267, 30643, 31961, 32461], // D203_PRED
+  [12026, 13661, 13874, 15305, 15490, 15726, 15995, 16273, 28443, 30388, 30767, 32416], // D67_PRED
+  [19052, 19840, 20579, 20916, 21150, 21467, 21885, 22719, 23174, 28861, 30379, 32175], // SMOOTH_PRED
+  [18627, 19649, 20974, 21219, 21492, 21816, 22199, 23119, 23527, 27053, 31397, 32148], // SMOOTH_V_PRED
+  [17026, 19004, 19997, 20339, 20586, 21103, 21349, 21907, 22482, 25896, 26541, 31819], // SMOOTH_H_PRED
+  [12124, 13759, 14959, 14992, 15007, 15051, 15078, 15166, 15255, 15753, 16039, 16606] // PAETH_PRED
+];
+
+pub const uv_mode_cfl_allowed_cdf: [[u16; 13]; INTRA_MODES] = [
+  [10407, 11208, 12900, 13181, 13823, 14175, 14899, 15656, 15986, 20086, 20995, 22455, 24212], // DC_PRED
+  [4532, 19780, 20057, 20215, 20428, 21071, 21199, 21451, 22099, 24228, 24693, 27032, 29472], // V_PRED
+  [5273, 5379, 20177, 20270, 20385, 20439, 20949, 21695, 21774, 23138, 24256, 24703, 26679], // H_PRED
+  [6740, 7167, 7662, 14152, 14536, 14785, 15034, 16741, 18371, 21520, 22206, 23389, 24182], // D45_PRED
+  [4987, 5368, 5928, 6068, 19114, 20315, 21857, 22253, 22411, 24911, 25380, 26027, 26376], // D135_PRED
+  [5370, 6889, 7247, 7393, 9498, 21114, 21402, 21753, 21981, 24780, 25386, 26517, 27176], // D113_PRED
+  [4816, 4961, 7204, 7326, 8765, 8930, 20169, 20682, 20803, 23188, 23763, 24455, 24940], // D157_PRED
+  [6608, 6740, 8529, 9049, 9257, 9356, 9735, 18827, 19059, 22336, 23204, 23964, 24793], // D203_PRED
+  [5998, 7419, 7781, 8933, 9255, 9549, 9753, 10417, 18898, 22494, 23139, 24764, 25989], // D67_PRED
+  [10660, 11298, 12550, 12957, 13322, 13624, 14040, 15004, 15534, 20714, 21789, 23443, 24861], // SMOOTH_PRED
+  [10522, 11530, 12552, 12963, 13378, 13779, 14245, 15235, 15902, 20102, 22696, 23774, 25838], // SMOOTH_V_PRED
+  [10099, 10691, 12639, 13049, 13386, 13665, 14125, 15163, 15636, 19676, 20474, 23519, 25208], // SMOOTH_H_PRED
+  [3144, 5087, 7382, 7504, 7593, 7690, 7801, 8064, 8232, 9248, 9875, 10521, 29048] // PAETH_PRED
+];
+
+// Angle delta for the directional modes, indexed by mode - V_PRED
+pub const angle_delta_cdf: [[u16; 6]; DIRECTIONAL_MODES] = [
+  [2180, 5032, 7567, 22776, 26989, 30217], // V_PRED
+  [2301, 5608, 8801, 23487, 26974, 30330], // H_PRED
+  [3780, 11018, 13699, 19354, 23083, 31286], // D45_PRED
+  [4581, 11226, 15147, 17138, 21834, 28397], // D135_PRED
+  [1737, 10927, 14509, 19588, 22745, 28823], // D113_PRED
+  [2664, 10176, 12485, 17650, 21600, 30495], // D157_PRED
+  [2240, 11096, 15453, 20341, 22561, 28917], // D203_PRED
+  [3605, 10428, 12459, 17676, 21244, 30655] // D67_PRED
+];
+
+// Chroma from luma: the joint sign of the U and V scaling factors, then the magnitude
+// of each nonzero one, indexed by a context derived from the joint sign
+pub const cfl_sign_cdf: [u16; 7] = [1418, 2123, 13340, 18405, 26972, 28343, 32294];
+
+pub const cfl_alpha_cdf: [[u16; 15]; CFL_ALPHA_CONTEXTS] = [
+  [7637, 20719, 31401, 32481, 32657, 32688, 32692, 32696, 32700, 32704, 32708, 32712, 32716, 32720, 32724],
+  [14365, 23603, 28135, 31168, 32167, 32395, 32487, 32573, 32620, 32647, 32668, 32672, 32676, 32680, 32684],
+  [11532, 22380, 28445, 31360, 32349, 32523, 32584, 32649, 32673, 32677, 32681, 32685, 32689, 32693, 32697],
+  [26990, 31402, 32282, 32571, 32692, 32696, 32700, 32704, 32708, 32712, 32716, 32720, 32724, 32728, 32732],
+  [17248, 26058, 28904, 30608, 31305, 31877, 32126, 32321, 32394, 32464, 32516, 32560, 32576, 32593, 32622],
+  [14738, 21678, 25779, 27901, 29024, 30302, 30980, 31843, 32144, 32413, 32520, 32594, 32622, 32656, 32660]
+];
+
+// Whether to use filter intra, indexed by block size, and which filter to use
+pub const filter_intra_cdf: [[u16; 1]; BLOCK_SIZES] = [
+  [4621], // 4x4
+  [6743], // 4x8
+  [5893], // 8x4
+  [7866], // 8x8
+  [12551], // 8x16
+  [9394], // 16x8
+  [12408], // 16x16
+  [14301], // 16x32
+  [12756], // 32x16
+  [22343], // 32x32
+  [16384], // 32x64
+  [16384], // 64x32
+  [16384], // 64x64
+  [16384], // 64x128
+  [16384], // 128x64
+  [16384], // 128x128
+  [12770], // 4x16
+  [10368], // 16x4
+  [20229], // 8x32
+  [18101], // 32x8
+  [16384], // 16x64
+  [16384] // 64x16
+];
+
+pub const filter_intra_mode_cdf: [u16; 4] = [8949, 12776, 17211, 29558];
+
+// Whether to use intra block copy, when the frame allows it
+pub const intrabc_cdf: [u16; 1] = [30531];
@@ -0,0 +1,143 @@
+// Copyright (c) 2024-2025, The tinyavif contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+// Palette mode CDFs (see palette.rs)
+
+use crate::consts::*;
+
+// has_palette_y, indexed by block size context (log2 of the block's width plus height,
+// in 4x4 units, minus 2) and then the number of neighbouring blocks (above and left)
+// with a luma palette
+pub const palette_y_mode_cdf: [[[u16; 1]; PALETTE_Y_MODE_CONTEXTS]; PALETTE_BLOCK_SIZE_CONTEXTS] = [
+  [[31676], [3419], [1261]], // 8x8
+  [[31912], [2859], [980]], // 8x16 and 16x8
+  [[31823], [3400], [781]], // 16x16
+  [[32030], [3561], [904]], // 16x32 and 32x16
+  [[32309], [7337], [1462]], // 32x32
+  [[32265], [4015], [1521]], // 32x64 and 64x32
+  [[32450], [7946], [129]] // 64x64
+];
+
+// has_palette_uv, indexed by whether this block has a luma palette
+pub const palette_uv_mode_cdf: [[u16; 1]; PALETTE_UV_MODE_CONTEXTS] = [[32461], [21488]];
+
+// Palette sizes, indexed by block size context
+pub const palette_y_size_cdf: [[u16; 6]; PALETTE_BLOCK_SIZE_CONTEXTS] = [
+  [7952, 13000, 18149, 21478, 25527, 29241], // 8x8
+  [7139, 11421, 16195, 19544, 23666, 28073], // 8x16 and 16x8
+  [7788, 12741, 17325, 20500, 24315, 28530], // 16x16
+  [8271, 14064, 18246, 21564, 25071, 28533], // 16x32 and 32x16
+  [12725, 19180, 21863, 24839, 27535, 30120], // 32x32
+  [9711, 14888, 16923, 21052, 25661, 27875], // 32x64 and 64x32
+  [14940, 20797, 21678, 24186, 27033, 28999] // 64x64
+];
+
+pub const palette_uv_size_cdf: [[u16; 6]; PALETTE_BLOCK_SIZE_CONTEXTS] = [
+  [8713, 19979, 27128, 29609, 31331, 32272], // 8x8
+  [5839, 15573, 23581, 26947, 29848, 31700], // 8x16 and 16x8
+  [4426, 11260, 17999, 21483, 25863, 29430], // 16x16
+  [3228, 9464, 14993, 18089, 22523, 27420], // 16x32 and 32x16
+  [3768, 8886, 13091, 17852, 22495, 27207], // 32x32
+  [2464, 8451, 12861, 21632, 25525, 28555], // 32x64 and 64x32
+  [1269, 5435, 10433, 18963, 21700, 25865] // 64x64
+];
+
+// Colour index CDFs, one set per palette size, indexed by the colour context
+// (see palette::color_context())
+pub const palette_size_2_y_color_cdf: [[u16; 1]; PALETTE_COLOR_CONTEXTS] = [
+  [28710], [16384], [10553], [27036], [31603]
+];
+pub const palette_size_3_y_color_cdf: [[u16; 2]; PALETTE_COLOR_CONTEXTS] = [
+  [27877, 30490],
+  [11532, 25697],
+  [6544, 30234],
+  [23018, 28072],
+  [31915, 32385]
+];
+pub const palette_size_4_y_color_cdf: [[u16; 3]; PALETTE_COLOR_CONTEXTS] = [
+  [25572, 28046, 30045],
+  [9478, 21590, 27256],
+  [7248, 26837, 29824],
+  [19167, 24486, 28349],
+  [31400, 31825, 32250]
+];
+pub const palette_size_5_y_color_cdf: [[u16; 4]; PALETTE_COLOR_CONTEXTS] = [
+  [24779, 26955, 28576, 30282],
+  [8669, 20364, 24073, 28093],
+  [4255, 27565, 29377, 31067],
+  [19864, 23674, 26716, 29530],
+  [31646, 31893, 32147, 32426]
+];
+pub const palette_size_6_y_color_cdf: [[u16; 5]; PALETTE_COLOR_CONTEXTS] = [
+  [23132, 25407, 26970, 28435, 30073],
+  [7443, 17242, 20717, 24762, 27982],
+  [6300, 24862, 26944, 28784, 30671],
+  [18916, 22895, 25267, 27435, 29652],
+  [31270, 31550, 31808, 32059, 32353]
+];
+pub const palette_size_7_y_color_cdf: [[u16; 6]; PALETTE_COLOR_CONTEXTS] = [
+  [23105, 25199, 26464, 27684, 28931, 30318],
+  [6950, 15447, 18952, 22681, 25567, 28563],
+  [7560, 23474, 25490, 27203, 28921, 30708],
+  [18544, 22373, 24457, 26195, 28119, 30045],
+  [31198, 31451, 31670, 31882, 32123, 32391]
+];
+pub const palette_size_8_y_color_cdf: [[u16; 7]; PALETTE_COLOR_CONTEXTS] = [
+  [21689, 23883, 25163, 26352, 27506, 28827, 30195],
+  [6892, 15385, 17840, 21606, 24287, 26753, 29204],
+  [5651, 23182, 25042, 26518, 27982, 29392, 30900],
+  [19349, 22578, 24418, 25994, 27524, 29031, 30448],
+  [31028, 31270, 31504, 31705, 31927, 32153, 32392]
+];
+
+pub const palette_size_2_uv_color_cdf: [[u16; 1]; PALETTE_COLOR_CONTEXTS] = [
+  [29089], [16384], [8713], [29257], [31610]
+];
+pub const palette_size_3_uv_color_cdf: [[u16; 2]; PALETTE_COLOR_CONTEXTS] = [
+  [25257, 29145],
+  [12287, 27293],
+  [7033, 27960],
+  [20145, 25405],
+  [30608, 31639]
+];
+pub const palette_size_4_uv_color_cdf: [[u16; 3]; PALETTE_COLOR_CONTEXTS] = [
+  [24210, 27175, 29903],
+  [9888, 22386, 27214],
+  [5901, 26053, 29293],
+  [18318, 22152, 28333],
+  [30459, 31136, 31926]
+];
+pub const palette_size_5_uv_color_cdf: [[u16; 4]; PALETTE_COLOR_CONTEXTS] = [
+  [22980, 25479, 27781, 29986],
+  [8413, 21408, 24859, 28874],
+  [2257, 29449, 30594, 31598],
+  [19189, 21202, 25915, 28620],
+  [31844, 32044, 32281, 32518]
+];
+pub const palette_size_6_uv_color_cdf: [[u16; 5]; PALETTE_COLOR_CONTEXTS] = [
+  [22217, 24567, 26637, 28683, 30548],
+  [7307, 16406, 19636, 24632, 28424],
+  [4441, 25064, 26879, 28942, 30919],
+  [17210, 20528, 23319, 26750, 29582],
+  [30674, 30953, 31396, 31735, 32207]
+];
+pub const palette_size_7_uv_color_cdf: [[u16; 6]; PALETTE_COLOR_CONTEXTS] = [
+  [21239, 23168, 25044, 26962, 28705, 30506],
+  [6545, 15012, 18004, 21817, 25503, 28701],
+  [3448, 26295, 27437, 28704, 30126, 31442],
+  [15889, 18323, 21704, 24698, 26976, 29690],
+  [30988, 31204, 31479, 31734, 31983, 32325]
+];
+pub const palette_size_8_uv_color_cdf: [[u16; 7]; PALETTE_COLOR_CONTEXTS] = [
+  [21442, 23288, 24758, 26246, 27649, 28980, 30563],
+  [5863, 14933, 17552, 20668, 23683, 26411, 29273],
+  [3415, 25810, 26877, 27990, 29223, 30394, 31618],
+  [17965, 20084, 22232, 23974, 26274, 28402, 30390],
+  [31190, 31329, 31516, 31679, 31825, 32026, 32322]
+];
@@ -0,0 +1,18 @@
+// Copyright (c) 2024-2025, The tinyavif contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+// Loop restoration CDFs, for the type of filter used in each restoration unit
+
+// With RESTORE_SWITCHABLE: none, Wiener, or self-guided
+pub const restoration_type_cdf: [u16; 2] = [9413, 22581];
+
+// With RESTORE_WIENER or RESTORE_SGRPROJ: whether to use that filter
+pub const use_wiener_cdf: [u16; 1] = [11570];
+
+pub const use_sgrproj_cdf: [u16; 1] = [16855];
@@ -0,0 +1,126 @@
+// Copyright (c) 2024-2025, The tinyavif contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+// Transform size and type CDFs for intra blocks
+
+use crate::consts::*;
+use crate::enums::INTRA_MODES;
+
+// tx_depth, for how many times to split the largest transform which fits the block,
+// indexed by the maximum transform size and then a context from the neighbouring
+// transform sizes. 8x8 blocks can split at most once, larger ones up to twice
+pub const tx_depth_8x8_cdf: [[u16; 1]; TX_SIZE_CONTEXTS] = [[19968], [19968], [24320]];
+
+pub const tx_depth_16x16_cdf: [[u16; 2]; TX_SIZE_CONTEXTS] = [
+  [12272, 30172],
+  [12272, 30172],
+  [18677, 30848]
+];
+
+pub const tx_depth_32x32_cdf: [[u16; 2]; TX_SIZE_CONTEXTS] = [
+  [12986, 15180],
+  [12986, 15180],
+  [24302, 25602]
+];
+
+pub const tx_depth_64x64_cdf: [[u16; 2]; TX_SIZE_CONTEXTS] = [
+  [5782, 11475],
+  [5782, 11475],
+  [16803, 22759]
+];
+
+// intra_tx_type, indexed by the square-up transform size and then the intra mode.
+// TX_SET_INTRA_1 has 7 transform types and is used for 4x4 and 8x8 transforms;
+// TX_SET_INTRA_2 has 5 and is used up to 16x16, and for all sizes with the
+// reduced transform set. Larger transforms are always DCT_DCT
+pub const intra_tx_type_set1_cdf: [[[u16; 6]; INTRA_MODES]; 2] = [
+  // 4x4
+  [
+    [1535, 8035, 9461, 12751, 23467, 27825], // DC_PRED
+    [564, 3335, 9709, 10870, 18143, 28094], // V_PRED
+    [672, 3247, 3676, 11982, 19415, 23127], // H_PRED
+    [5279, 13885, 15487, 18044, 23527, 30252], // D45_PRED
+    [4423, 6074, 7985, 10416, 25693, 29298], // D135_PRED
+    [1486, 4241, 9460, 10662, 16456, 27694], // D113_PRED
+    [439, 2838, 3522, 6737, 18058, 23754], // D157_PRED
+    [1190, 4233, 4855, 11670, 20281, 24377], // D203_PRED
+    [1045, 4312, 8647, 10159, 18644, 29335], // D67_PRED
+    [202, 3734, 4747, 7298, 17127, 24016], // SMOOTH_PRED
+    [447, 4312, 6819, 8884, 16010, 23858], // SMOOTH_V_PRED
+    [277, 4369, 5255, 8905, 16465, 22271], // SMOOTH_H_PRED
+    [3409, 5436, 10599, 15599, 19687, 24040] // PAETH_PRED
+  ],
+  // 8x8
+  [
+    [1870, 13742, 14530, 16498, 23770, 27698], // DC_PRED
+    [326, 8796, 14632, 15079, 19272, 27486], // V_PRED
+    [484, 7576, 7712, 14443, 19159, 22591], // H_PRED
+    [1126, 15340, 15895, 17023, 20896, 30279], // D45_PRED
+    [655, 4854, 5249, 5913, 22099, 27138], // D135_PRED
+    [1299, 6458, 8885, 9290, 14851, 25497], // D113_PRED
+    [311, 5295, 5552, 6885, 16107, 22672], // D157_PRED
+    [883, 8059, 8270, 11258, 17289, 21549], // D203_PRED
+    [741, 7580, 9318, 10345, 16688, 29046], // D67_PRED
+    [110, 7406, 7915, 9195, 16041, 23329], // SMOOTH_PRED
+    [363, 7974, 9357, 10673, 15629, 24474], // SMOOTH_V_PRED
+    [153, 7647, 8112, 9936, 15307, 19996], // SMOOTH_H_PRED
+    [3511, 6332, 11165, 15335, 19323, 23594] // PAETH_PRED
+  ]
+];
+
+pub const intra_tx_type_set2_cdf: [[[u16; 4]; INTRA_MODES]; 3] = [
+  // 4x4
+  [
+    [6554, 13107, 19661, 26214], // DC_PRED
+    [6554, 13107, 19661, 26214], // V_PRED
+    [6554, 13107, 19661, 26214], // H_PRED
+    [6554, 13107, 19661, 26214], // D45_PRED
+    [6554, 13107, 19661, 26214], // D135_PRED
+    [6554, 13107, 19661, 26214], // D113_PRED
+    [6554, 13107, 19661, 26214], // D157_PRED
+    [6554, 13107, 19661, 26214], // D203_PRED
+    [6554, 13107, 19661, 26214], // D67_PRED
+    [6554, 13107, 19661, 26214], // SMOOTH_PRED
+    [6554, 13107, 19661, 26214], // SMOOTH_V_PRED
+    [6554, 13107, 19661, 26214], // SMOOTH_H_PRED
+    [6554, 13107, 19661, 26214] // PAETH_PRED
+  ],
+  // 8x8
+  [
+    [6554, 13107, 19661, 26214], // DC_PRED
+    [6554, 13107, 19661, 26214], // V_PRED
+    [6554, 13107, 19661, 26214], // H_PRED
+    [6554, 13107, 19661, 26214], // D45_PRED
+    [6554, 13107, 19661, 26214], // D135_PRED
+    [6554, 13107, 19661, 26214], // D113_PRED
+    [6554, 13107, 19661, 26214], // D157_PRED
+    [6554, 13107, 19661, 26214], // D203_PRED
+    [6554, 13107, 19661, 26214], // D67_PRED
+    [6554, 13107, 19661, 26214], // SMOOTH_PRED
+    [6554, 13107, 19661, 26214], // SMOOTH_V_PRED
+    [6554, 13107, 19661, 26214], // SMOOTH_H_PRED
+    [6554, 13107, 19661, 26214] // PAETH_PRED
+  ],
+  // 16x16
+  [
+    [1127, 12814, 22772, 27483], // DC_PRED
+    [145, 6761, 11980, 26667], // V_PRED
+    [362, 5887, 11678, 16725], // H_PRED
+    [385, 15213, 18587, 30693], // D45_PRED
+    [25, 2914, 23134, 27903], // D135_PRED
+    [60, 4470, 11749, 23991], // D113_PRED
+    [37, 3332, 14511, 21448], // D157_PRED
+    [157, 6320, 13036, 17439], // D203_PRED
+    [119, 6719, 12906, 29396], // D67_PRED
+    [47, 5537, 12576, 21499], // SMOOTH_PRED
+    [269, 6076, 11258, 23115], // SMOOTH_V_PRED
+    [83, 5615, 12001, 17228], // SMOOTH_H_PRED
+    [1968, 5556, 12023, 18547] // PAETH_PRED
+  ]
+];
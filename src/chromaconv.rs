@@ -0,0 +1,136 @@
+// Copyright (c) 2024-2025, The tinyavif contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+// Conversion of 4:2:2 and 4:4:4 YUV images into the 4:2:0 format which the encoder works in
+//
+// In the input, 4:4:4 chroma sits on every luma sample, and 4:2:2 chroma on the even luma
+// columns of every row, as in BT.601 and Y4M. The output chroma is resampled so that
+// each sample lands where the sequence header's chroma_sample_position says it does, as
+// colorconv.rs does for RGB input: for 4:2:2 with a centred position, that means shifting
+// chroma by a quarter of a chroma sample, as well as halving it vertically.
+//
+// The filter is applied separably, first horizontally and then vertically, and samples
+// past the edge of the image are taken from the nearest edge sample.
+
+use crate::array2d::Array2D;
+use crate::config::{ChromaFilter, ChromaSamplePosition, EncoderConfig};
+use crate::frame::Plane;
+use crate::scaling::lanczos;
+use crate::util::*;
+
+// Filter taps for one output position: the source positions used, with the (normalized)
+// weight of each
+struct FilterTaps {
+  positions: Vec<usize>,
+  weights: Vec<f32>,
+}
+
+fn kernel(filter: ChromaFilter, x: f32) -> f32 {
+  match filter {
+    // Samples exactly on the edge of the box are shared with the neighbouring output
+    ChromaFilter::Box => if x.abs() < 0.5 { 1.0 } else if x.abs() == 0.5 { 0.5 } else { 0.0 },
+    ChromaFilter::Bilinear => (1.0 - x.abs()).max(0.0),
+    ChromaFilter::Lanczos => lanczos(x, portable_sin),
+  }
+}
+
+// Number of source samples (before stretching) on each side of the centre which can
+// contribute to an output sample
+fn kernel_radius(filter: ChromaFilter) -> f32 {
+  match filter {
+    ChromaFilter::Box => 0.5,
+    ChromaFilter::Bilinear => 1.0,
+    ChromaFilter::Lanczos => 3.0,
+  }
+}
+
+// Compute the taps for every output position along one dimension, where output sample i
+// sits at source position `step * i + offset`. When downsampling (step 2), the kernel is
+// stretched to match, so that it also acts as an anti-aliasing filter.
+fn chroma_taps(filter: ChromaFilter, src_size: usize, dst_size: usize, step: usize, offset: f32) -> Vec<FilterTaps> {
+  let stretch = step as f32;
+  let support = kernel_radius(filter) * stretch;
+
+  let mut taps = Vec::with_capacity(dst_size);
+  for i in 0..dst_size {
+    let centre = (step * i) as f32 + offset;
+    let start = (centre - support).ceil() as isize;
+    let end = (centre + support).floor() as isize;
+
+    let positions: Vec<usize> = (start ..= end).map(|k| clamp(k, 0, src_size as isize - 1) as usize).collect();
+    let mut weights: Vec<f32> = (start ..= end).map(|k| kernel(filter, (k as f32 - centre) / stretch)).collect();
+    let total: f32 = weights.iter().sum();
+    for weight in weights.iter_mut() {
+      *weight /= total;
+    }
+    taps.push(FilterTaps { positions: positions, weights: weights });
+  }
+  return taps;
+}
+
+// Settings for converting chroma to 4:2:0
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChromaConversion {
+  pub chroma_sample_position: ChromaSamplePosition,
+  pub filter: ChromaFilter,
+}
+
+impl ChromaConversion {
+  pub fn from_config(config: &EncoderConfig) -> Self {
+    Self {
+      chroma_sample_position: config.chroma_sample_position,
+      filter: config.chroma_filter,
+    }
+  }
+
+  // Downsample one chroma plane, which is either full size (4:4:4) or has half as many
+  // columns as luma, rounded up (4:2:2), into `dst`, which must already have the 4:2:0
+  // chroma crop size for the image.
+  pub fn downsample_plane(&self, src: &Array2D<u8>, subsampled_x: bool, dst: &mut Plane) {
+    let src_width = src.cols();
+    let src_height = src.rows();
+    let dst_crop_width = dst.crop_width();
+    let dst_crop_height = dst.crop_height();
+    assert!(dst_crop_height == src_height.div_ceil(2));
+
+    // Unknown position is treated as centred, as in colorconv.rs
+    let (cosited_x, cosited_y) = match self.chroma_sample_position {
+      ChromaSamplePosition::Unknown => (false, false),
+      ChromaSamplePosition::Vertical => (true, false),
+      ChromaSamplePosition::Colocated => (true, true),
+    };
+    // Position of each output sample, in units of input chroma samples
+    let horizontal_taps = if subsampled_x {
+      chroma_taps(self.filter, src_width, dst_crop_width, 1, if cosited_x { 0.0 } else { 0.25 })
+    } else {
+      chroma_taps(self.filter, src_width, dst_crop_width, 2, if cosited_x { 0.0 } else { 0.5 })
+    };
+    let vertical_taps = chroma_taps(self.filter, src_height, dst_crop_height, 2, if cosited_y { 0.0 } else { 0.5 });
+
+    // Horizontal pass, into an intermediate buffer which keeps full precision
+    let mut tmp = Array2D::<f32>::zeroed(src_height, dst_crop_width);
+    for i in 0..src_height {
+      let src_row = &src[i];
+      for (j, taps) in horizontal_taps.iter().enumerate() {
+        tmp[i][j] = taps.positions.iter().zip(&taps.weights).map(|(&col, weight)| weight * src_row[col] as f32).sum();
+      }
+    }
+
+    // Vertical pass
+    let dst_pixels = dst.pixels_mut();
+    for (i, taps) in vertical_taps.iter().enumerate() {
+      for j in 0..dst_crop_width {
+        let sum: f32 = taps.positions.iter().zip(&taps.weights).map(|(&row, weight)| weight * tmp[row][j]).sum();
+        dst_pixels[i][j] = sum.round().clamp(0.0, 255.0) as u8;
+      }
+    }
+
+    dst.fill_padding();
+  }
+}
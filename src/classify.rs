@@ -0,0 +1,126 @@
+// Copyright (c) 2024-2025, The tinyavif contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+// Content type detection, for --tune auto
+//
+// Screenshots, diagrams and text are made of flat areas of a few distinct colours,
+// separated by sharp edges. Photos and other natural images have texture and noise
+// nearly everywhere, and where they are smooth, they tend to be smooth gradients rather
+// than perfectly flat. So we look at each 16x16 luma block, and count the blocks which
+// have only a few colours, those of them which contain a sharp edge, and the blocks which
+// are smooth gradients. A perfectly flat block says nothing on its own, so only counts
+// if it borders a sharp edge.
+// This follows the same idea as libaom's screen content detection.
+
+use crate::config::{EncoderConfig, Tune};
+use crate::frame::Frame;
+use crate::util::*;
+
+// Size of the blocks which are classified
+const BLOCK_SIZE: usize = 16;
+
+// Blocks with at most this many distinct luma values count as having few colours
+const FEW_COLORS: usize = 4;
+
+// A step of at least this much between neighbouring pixels counts as a sharp edge.
+// Steps from the block's last row and column into the next block count too, so that
+// edges which fall exactly on block boundaries are seen
+const EDGE_THRESHOLD: u8 = 32;
+
+// Blocks with more than FEW_COLORS colours, where no step between neighbouring pixels
+// is more than this, count as smooth gradients
+const SMOOTH_THRESHOLD: u8 = 4;
+
+// Statistics used to classify an image. Each is a count of 16x16 luma blocks
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ContentFeatures {
+  // Number of blocks looked at
+  pub blocks: usize,
+  // Blocks with at most FEW_COLORS distinct values, other than flat blocks away from
+  // any sharp edges
+  pub few_colors: usize,
+  // Blocks with few colours, which also contain a sharp edge
+  pub sharp_edges: usize,
+  // Blocks with many colours, but only small steps between them
+  pub smooth: usize,
+}
+
+impl ContentFeatures {
+  pub fn measure(source: &Frame) -> Self {
+    let plane = source.y();
+    let pixels = plane.pixels();
+    let crop_width = plane.crop_width();
+    let crop_height = plane.crop_height();
+
+    let mut features = Self::default();
+    // Images smaller than one block are looked at as a single, smaller block
+    for y0 in (0..crop_height).step_by(BLOCK_SIZE) {
+      for x0 in (0..crop_width).step_by(BLOCK_SIZE) {
+        let y1 = min(y0 + BLOCK_SIZE, crop_height);
+        let x1 = min(x0 + BLOCK_SIZE, crop_width);
+
+        let mut seen = [false; 256];
+        let mut colors = 0;
+        let mut max_step = 0;
+        for i in y0..y1 {
+          for j in x0..x1 {
+            let p = pixels[i][j];
+            if !seen[p as usize] {
+              seen[p as usize] = true;
+              colors += 1;
+            }
+            if j + 1 < crop_width {
+              max_step = max(max_step, p.abs_diff(pixels[i][j + 1]));
+            }
+            if i + 1 < crop_height {
+              max_step = max(max_step, p.abs_diff(pixels[i + 1][j]));
+            }
+          }
+        }
+
+        features.blocks += 1;
+        let sharp = max_step >= EDGE_THRESHOLD;
+        if colors <= FEW_COLORS && (colors > 1 || sharp) {
+          features.few_colors += 1;
+          if sharp {
+            features.sharp_edges += 1;
+          }
+        } else if colors > FEW_COLORS && max_step <= SMOOTH_THRESHOLD {
+          features.smooth += 1;
+        }
+      }
+    }
+    return features;
+  }
+
+  // Screen content needs a good share of the image to be blocks of few colours, enough
+  // of those to have sharp edges, and fewer smooth gradients than few-colour blocks
+  pub fn is_screen_content(&self) -> bool {
+    return self.few_colors * 10 > self.blocks &&
+           self.sharp_edges * 12 > self.blocks &&
+           self.few_colors > self.smooth;
+  }
+}
+
+// Pick the tuning for an image, for Tune::Auto
+pub fn detect_tune(source: &Frame) -> Tune {
+  if ContentFeatures::measure(source).is_screen_content() {
+    return Tune::Screen;
+  }
+  return Tune::Psnr;
+}
+
+// Copy of `config` for encoding `source`, with Tune::Auto replaced by the detected tuning
+pub fn resolve_tune(config: &EncoderConfig, source: &Frame) -> EncoderConfig {
+  let mut config = config.clone();
+  if config.tune == Tune::Auto {
+    config.tune = detect_tune(source);
+  }
+  return config;
+}
@@ -0,0 +1,264 @@
+// Copyright (c) 2024-2025, The tinyavif contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+// Conversion of RGB images into the YUV 4:2:0 format which the encoder works in
+//
+// This supports the BT.601, BT.709, and BT.2020 (non-constant luminance) matrices, in
+// either limited ("TV") or full range. The chroma planes are downsampled so that each
+// chroma sample lands where the sequence header's chroma_sample_position says it does.
+//
+// The matrix used must match the matrix_coefficients value signalled in the colr box,
+// otherwise the image will decode with the wrong colours.
+
+use crate::array2d::Array2D;
+use crate::config::{ChromaSamplePosition, ConfigError, EncoderConfig};
+use crate::frame::Frame;
+use crate::util::*;
+
+// Fractional bits used for the conversion coefficients
+const COEFF_BITS: u32 = 16;
+
+// Fractional bits kept from 16-bit input, on top of the 8 bits of the output. This
+// matches the 16 levels of the dither pattern, and keeps the conversion within 32 bits
+const HIGH_DEPTH_FRAC_BITS: u32 = 4;
+
+// 4x4 Bayer matrix, giving the order in which pixels round up as the fractional part
+// of their value rises
+const DITHER_PATTERN: [[i32; 4]; 4] = [
+  [0, 8, 2, 10],
+  [12, 4, 14, 6],
+  [3, 11, 1, 9],
+  [15, 7, 13, 5],
+];
+
+// Scale a 16-bit value to 0-255, with HIGH_DEPTH_FRAC_BITS fractional bits
+fn reduce_16bit(value: u16) -> i32 {
+  let scale = 255 << HIGH_DEPTH_FRAC_BITS;
+  return ((value as u32 * scale + 32767) / 65535) as i32;
+}
+
+// Amount to add to pixel (i, j) before dropping the low `bits` bits. Without fractional
+// bits in the input, this rounds to nearest; with them, the pixel is rounded up when
+// its fractional part passes its threshold in the dither pattern, so that the average
+// over an area keeps the input's precision.
+fn rounding(i: usize, j: usize, bits: u32, frac_bits: u32) -> i32 {
+  if frac_bits == 0 {
+    return 1 << (bits - 1);
+  }
+  let threshold = 2 * DITHER_PATTERN[i % 4][j % 4] + 1;
+  return threshold << (bits - 5);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Matrix {
+  Bt601,
+  Bt709,
+  Bt2020,
+}
+
+impl Matrix {
+  // Map a matrix_coefficients value (as in ISO/IEC 23091-4) to the matrix it describes
+  pub fn from_matrix_coefficients(matrix_coefficients: u16) -> Option<Self> {
+    match matrix_coefficients {
+      1 => Some(Matrix::Bt709),
+      // 5 and 6 (BT.470 B/G and BT.601 525-line) use the same matrix
+      5 | 6 => Some(Matrix::Bt601),
+      9 => Some(Matrix::Bt2020),
+      _ => None,
+    }
+  }
+
+  pub fn matrix_coefficients(self) -> u16 {
+    match self {
+      Matrix::Bt601 => 6,
+      Matrix::Bt709 => 1,
+      Matrix::Bt2020 => 9,
+    }
+  }
+
+  // Contributions of red and blue to luma. Green makes up the rest.
+  fn kr_kb(self) -> (f64, f64) {
+    match self {
+      Matrix::Bt601 => (0.299, 0.114),
+      Matrix::Bt709 => (0.2126, 0.0722),
+      Matrix::Bt2020 => (0.2627, 0.0593),
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ColorConversion {
+  pub matrix: Matrix,
+  pub full_range: bool,
+  pub chroma_sample_position: ChromaSamplePosition,
+}
+
+impl ColorConversion {
+  // The conversion described by an encoder config
+  // A config which leaves the matrix unspecified gets BT.601, the traditional choice
+  // for 8-bit images. Other matrices, such as identity or YCgCo, aren't supported.
+  pub fn from_config(config: &EncoderConfig) -> Result<Self, ConfigError> {
+    let matrix = match config.matrix_coefficients {
+      2 => Matrix::Bt601,
+      value => Matrix::from_matrix_coefficients(value).ok_or_else(|| {
+        ConfigError::Unsupported(format!("Converting RGB images with matrix coefficients {}", value))
+      })?,
+    };
+    Ok(Self {
+      matrix: matrix,
+      full_range: config.full_range,
+      chroma_sample_position: config.chroma_sample_position,
+    })
+  }
+
+  // Convert an interleaved RGB image to YUV
+  // `channels` is the number of bytes per pixel, which must be 3 (RGB) or 4 (RGBA, where
+  // alpha is ignored), and `stride` is the number of bytes per row.
+  pub fn rgb_to_frame(&self, data: &[u8], width: usize, height: usize, stride: usize, channels: usize) -> Frame {
+    assert!(channels == 3 || channels == 4);
+    assert!(stride >= width * channels);
+    assert!(data.len() >= stride * (height - 1) + width * channels);
+
+    return self.convert(width, height, 0, |i, j| {
+      let offset = i * stride + j * channels;
+      [data[offset] as i32, data[offset + 1] as i32, data[offset + 2] as i32]
+    });
+  }
+
+  // As rgb_to_frame(), for 16 bits per channel, with `stride` counted in samples rather
+  // than bytes. The extra precision is kept through the conversion, and the result is
+  // reduced to 8 bits with ordered dithering, so that smooth gradients don't band.
+  pub fn rgb16_to_frame(&self, data: &[u16], width: usize, height: usize, stride: usize, channels: usize) -> Frame {
+    assert!(channels == 3 || channels == 4);
+    assert!(stride >= width * channels);
+    assert!(data.len() >= stride * (height - 1) + width * channels);
+
+    return self.convert(width, height, HIGH_DEPTH_FRAC_BITS, |i, j| {
+      let offset = i * stride + j * channels;
+      [0, 1, 2].map(|c| reduce_16bit(data[offset + c]))
+    });
+  }
+
+  // Convert a greyscale image, with one byte per pixel and `stride` bytes per row, to YUV
+  // This gives the same result as converting an RGB image with R = G = B, ie. neutral chroma.
+  pub fn gray_to_frame(&self, data: &[u8], width: usize, height: usize, stride: usize) -> Frame {
+    assert!(stride >= width);
+    assert!(data.len() >= stride * (height - 1) + width);
+
+    return self.convert(width, height, 0, |i, j| {
+      let value = data[i * stride + j] as i32;
+      [value, value, value]
+    });
+  }
+
+  // As gray_to_frame(), for 16 bits per pixel, with `stride` counted in samples. The
+  // result is dithered as for rgb16_to_frame()
+  pub fn gray16_to_frame(&self, data: &[u16], width: usize, height: usize, stride: usize) -> Frame {
+    assert!(stride >= width);
+    assert!(data.len() >= stride * (height - 1) + width);
+
+    return self.convert(width, height, HIGH_DEPTH_FRAC_BITS, |i, j| {
+      let value = reduce_16bit(data[i * stride + j]);
+      [value, value, value]
+    });
+  }
+
+  // Fixed-point coefficients to multiply R, G, and B by to give Y, U, and V,
+  // without the offsets which bring the results into range
+  fn coefficients(&self) -> [[i32; 3]; 3] {
+    let (kr, kb) = self.matrix.kr_kb();
+    let kg = 1.0 - kr - kb;
+    let (y_scale, c_scale) = if self.full_range { (1.0, 1.0) } else { (219.0 / 255.0, 224.0 / 255.0) };
+
+    let u_scale = c_scale / (2.0 * (1.0 - kb));
+    let v_scale = c_scale / (2.0 * (1.0 - kr));
+    let coeffs = [[kr * y_scale, kg * y_scale, kb * y_scale],
+                  [-kr * u_scale, -kg * u_scale, (1.0 - kb) * u_scale],
+                  [(1.0 - kr) * v_scale, -kg * v_scale, -kb * v_scale]];
+    return coeffs.map(|row| row.map(|c| (c * (1 << COEFF_BITS) as f64).round() as i32));
+  }
+
+  // Build a frame from a function giving the RGB value of each pixel, on a scale of 0-255
+  // with `frac_bits` fractional bits
+  fn convert<F: Fn(usize, usize) -> [i32; 3]>(&self, width: usize, height: usize, frac_bits: u32, pixel: F) -> Frame {
+    let coeffs = self.coefficients();
+    let y_offset = if self.full_range { 0 } else { 16 };
+    let bits = COEFF_BITS + frac_bits;
+    let dot = |c: &[i32; 3], rgb: &[i32; 3]| c[0] * rgb[0] + c[1] * rgb[1] + c[2] * rgb[2];
+
+    let mut frame = Frame::new(height, width);
+
+    // Chroma is computed at full resolution, and kept at full precision until it has
+    // been downsampled
+    let mut u_full = Array2D::<i32>::zeroed(height, width);
+    let mut v_full = Array2D::<i32>::zeroed(height, width);
+    let y = frame.y_mut().pixels_mut();
+    for i in 0..height {
+      for j in 0..width {
+        let rgb = pixel(i, j);
+        y[i][j] = (y_offset + ((dot(&coeffs[0], &rgb) + rounding(i, j, bits, frac_bits)) >> bits)).clamp(0, 255) as u8;
+        u_full[i][j] = dot(&coeffs[1], &rgb);
+        v_full[i][j] = dot(&coeffs[2], &rgb);
+      }
+    }
+
+    // Each chroma sample is a weighted average of the nearby full-resolution values,
+    // centred on the sample's position. Horizontally, chroma is either co-sited with the
+    // even luma columns (filter [1, 2, 1]) or halfway between columns (filter [1, 1]),
+    // and likewise vertically.
+    let (cosited_x, cosited_y) = match self.chroma_sample_position {
+      // Unknown position is treated as centred, as in JPEG
+      ChromaSamplePosition::Unknown => (false, false),
+      ChromaSamplePosition::Vertical => (true, false),
+      ChromaSamplePosition::Colocated => (true, true),
+    };
+    let uv_width = frame.u().crop_width();
+    let uv_height = frame.u().crop_height();
+    for i in 0..uv_height {
+      let row_taps = downsample_taps(i, height, cosited_y);
+      for j in 0..uv_width {
+        let col_taps = downsample_taps(j, width, cosited_x);
+        let mut u_sum = 0i64;
+        let mut v_sum = 0i64;
+        for (row, row_weight) in row_taps {
+          for (col, col_weight) in col_taps {
+            let weight = (row_weight * col_weight) as i64;
+            u_sum += weight * u_full[row][col] as i64;
+            v_sum += weight * v_full[row][col] as i64;
+          }
+        }
+        // The weights sum to 16
+        let offset = (rounding(i, j, bits, frac_bits) as i64) << 4;
+        let u = 128 + ((u_sum + offset) >> (bits + 4)) as i32;
+        let v = 128 + ((v_sum + offset) >> (bits + 4)) as i32;
+        frame.u_mut().pixels_mut()[i][j] = u.clamp(0, 255) as u8;
+        frame.v_mut().pixels_mut()[i][j] = v.clamp(0, 255) as u8;
+      }
+    }
+
+    for plane in 0..3 {
+      frame.plane_mut(plane).fill_padding();
+    }
+    return frame;
+  }
+}
+
+// Positions and weights of the full-resolution samples which contribute to chroma sample
+// `pos` along one axis, with the weights summing to 4. Positions past the edge of the image
+// are clamped to the last sample.
+fn downsample_taps(pos: usize, size: usize, cosited: bool) -> [(usize, i32); 3] {
+  let centre = 2 * pos;
+  let next = (centre + 1).min(size - 1);
+  if cosited {
+    let prev = centre.saturating_sub(1);
+    return [(prev, 1), (centre, 2), (next, 1)];
+  } else {
+    return [(centre, 2), (next, 2), (centre, 0)];
+  }
+}
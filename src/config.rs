@@ -0,0 +1,920 @@
+// Copyright (c) 2024-2025, The tinyavif contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+// Encoder configuration, plus (de)serialisation of settings "profiles"
+//
+// Profiles are stored as TOML files containing the fields of EncoderConfig,
+// so that tuned settings can be shared between users and batch jobs.
+
+use std::cmp::max;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::hls::ObuExtension;
+use crate::levels::*;
+use crate::prefilter::MAX_PREFILTER_STRENGTH;
+use crate::threads::MAX_THREADS;
+
+// Version number written into saved profiles
+//
+// Adding a new option does *not* require bumping this: when loading a profile,
+// any fields it doesn't mention are set to their defaults, and any fields we don't
+// recognize (eg. from a newer version of tinyavif) are ignored. This only needs
+// to change if the meaning of an existing field changes.
+pub const CONFIG_VERSION: u32 = 1;
+
+// Chroma subsampling modes
+// Names are as used in profiles, eg. `subsampling = "420"`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChromaSubsampling {
+  #[serde(rename = "420")]
+  Yuv420,
+  #[serde(rename = "422")]
+  Yuv422,
+  #[serde(rename = "444")]
+  Yuv444,
+  #[serde(rename = "400")]
+  Monochrome,
+}
+
+impl ChromaSubsampling {
+  pub fn name(self) -> &'static str {
+    match self {
+      ChromaSubsampling::Yuv420 => "4:2:0",
+      ChromaSubsampling::Yuv422 => "4:2:2",
+      ChromaSubsampling::Yuv444 => "4:4:4",
+      ChromaSubsampling::Monochrome => "monochrome",
+    }
+  }
+}
+
+// AV1 profiles, which limit the bit depths and chroma subsamplings a stream can use.
+// "auto" picks the lowest profile which supports the image's format
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AV1Profile {
+  #[serde(rename = "auto")]
+  Auto,
+  // 8 and 10-bit 4:2:0 and monochrome
+  #[serde(rename = "main")]
+  Main,
+  // Adds 8 and 10-bit 4:4:4
+  #[serde(rename = "high")]
+  High,
+  // Adds 8 and 10-bit 4:2:2, and 12-bit in every format
+  #[serde(rename = "professional")]
+  Professional,
+}
+
+impl FromStr for AV1Profile {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "auto" => Ok(AV1Profile::Auto),
+      "main" | "0" => Ok(AV1Profile::Main),
+      "high" | "1" => Ok(AV1Profile::High),
+      "professional" | "2" => Ok(AV1Profile::Professional),
+      _ => Err(format!("Unknown AV1 profile \"{}\", must be \"auto\", \"main\", \"high\" or \"professional\"", s)),
+    }
+  }
+}
+
+// AV1 tiers. The high tier allows higher bitrates than the main tier at the same level
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Tier {
+  #[serde(rename = "main")]
+  Main,
+  #[serde(rename = "high")]
+  High,
+}
+
+impl FromStr for Tier {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "main" => Ok(Tier::Main),
+      "high" => Ok(Tier::High),
+      _ => Err(format!("Unknown tier \"{}\", must be \"main\" or \"high\"", s)),
+    }
+  }
+}
+
+// How the frame is split into OBUs. Decoders must accept either layout
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ObuLayout {
+  // A single OBU_FRAME, holding both the frame header and the tile data
+  #[serde(rename = "frame")]
+  Frame,
+  // An OBU_FRAME_HEADER followed by an OBU_TILE_GROUP
+  #[serde(rename = "separate")]
+  Separate,
+}
+
+impl FromStr for ObuLayout {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "frame" => Ok(ObuLayout::Frame),
+      "separate" => Ok(ObuLayout::Separate),
+      _ => Err(format!("Unknown OBU layout \"{}\", must be \"frame\" or \"separate\"", s)),
+    }
+  }
+}
+
+// Position of each chroma sample relative to the luma samples, for 4:2:0 images
+// Values are as signalled in the sequence header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChromaSamplePosition {
+  #[serde(rename = "unknown")]
+  Unknown = 0,
+  // Horizontally co-sited with the even luma columns, vertically halfway between rows,
+  // as in MPEG-2
+  #[serde(rename = "vertical")]
+  Vertical = 1,
+  // Co-sited with the top-left luma sample of each 2x2 block
+  #[serde(rename = "colocated")]
+  Colocated = 2,
+}
+
+impl FromStr for ChromaSamplePosition {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "unknown" => Ok(ChromaSamplePosition::Unknown),
+      "vertical" => Ok(ChromaSamplePosition::Vertical),
+      "colocated" => Ok(ChromaSamplePosition::Colocated),
+      _ => Err(format!("Unknown chroma sample position \"{}\", must be \"unknown\", \"vertical\" or \"colocated\"", s)),
+    }
+  }
+}
+
+// Named sets of colour primaries, transfer function and matrix coefficients (the CICP
+// values signalled in the 'colr' box), for the common colour spaces
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorPreset {
+  // BT.709 primaries with the sRGB transfer function, as used by most images on the web.
+  // The matrix is BT.601, as libavif and most browsers expect for sRGB
+  Srgb,
+  // HD video
+  Bt709,
+  // Display P3, as used by recent phones and Macs: P3 primaries with the sRGB transfer
+  // function
+  DisplayP3,
+  // HDR10: BT.2020 primaries and matrix, with the PQ transfer function
+  Rec2020Pq,
+}
+
+impl ColorPreset {
+  // Colour primaries, transfer function and matrix coefficients
+  pub fn cicp(self) -> (u16, u16, u16) {
+    match self {
+      ColorPreset::Srgb => (1, 13, 6),
+      ColorPreset::Bt709 => (1, 1, 1),
+      ColorPreset::DisplayP3 => (12, 13, 6),
+      ColorPreset::Rec2020Pq => (9, 16, 9),
+    }
+  }
+}
+
+impl FromStr for ColorPreset {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "srgb" => Ok(ColorPreset::Srgb),
+      "bt709" => Ok(ColorPreset::Bt709),
+      "p3" => Ok(ColorPreset::DisplayP3),
+      "rec2020-pq" => Ok(ColorPreset::Rec2020Pq),
+      _ => Err(format!("Unknown colour preset \"{}\", must be \"srgb\", \"bt709\", \"p3\" or \"rec2020-pq\"", s)),
+    }
+  }
+}
+
+// Filter used to downsample the chroma of 4:2:2 and 4:4:4 input to 4:2:0 (see chromaconv.rs)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChromaFilter {
+  // Average of the samples covered by each output sample. Cheapest, but doesn't shift
+  // 4:2:2 chroma horizontally, so is slightly off for centred positions
+  #[serde(rename = "box")]
+  Box,
+  // Linear interpolation, stretched to cover twice the output spacing
+  #[serde(rename = "bilinear")]
+  Bilinear,
+  // Lanczos-3, which keeps more detail, at the cost of some ringing at sharp edges
+  #[serde(rename = "lanczos")]
+  Lanczos,
+}
+
+impl FromStr for ChromaFilter {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "box" => Ok(ChromaFilter::Box),
+      "bilinear" => Ok(ChromaFilter::Bilinear),
+      "lanczos" => Ok(ChromaFilter::Lanczos),
+      _ => Err(format!("Unknown chroma filter \"{}\", must be \"box\", \"bilinear\" or \"lanczos\"", s)),
+    }
+  }
+}
+
+// Which kind of content to optimize for
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Tune {
+  // Photographs and other natural images
+  #[serde(rename = "psnr")]
+  Psnr,
+  // Screenshots, diagrams and text, which have few distinct colours and sharp edges.
+  // This enables the screen content tools, ie. palette mode
+  #[serde(rename = "screen")]
+  Screen,
+  // Natural images, weighting the distortion of each block by how visible it is, to
+  // improve SSIM rather than PSNR. Errors are harder to see in textured areas than in
+  // flat ones, so more of the bits go to the flat areas (see analysis.rs)
+  #[serde(rename = "ssim")]
+  Ssim,
+  // Choose between the above for each image, based on its content (see classify.rs).
+  // Code which only sees the settings, and not the image, treats this like Psnr
+  #[serde(rename = "auto")]
+  Auto,
+}
+
+impl FromStr for Tune {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "psnr" => Ok(Tune::Psnr),
+      "screen" => Ok(Tune::Screen),
+      "ssim" => Ok(Tune::Ssim),
+      "auto" => Ok(Tune::Auto),
+      _ => Err(format!("Unknown tuning \"{}\", must be \"psnr\", \"screen\", \"ssim\" or \"auto\"", s)),
+    }
+  }
+}
+
+// Highest value on the --quality scale
+pub const MAX_QUALITY: u8 = 100;
+
+// Points on the quality scale, and the qindex which each maps to, with linear
+// interpolation between them. Quality falls off faster at high qindex, so the points
+// are closer together in qindex at the top of the scale. Blocking and ringing around
+// text and sharp edges are more visible than in photos, so screen content uses a lower
+// qindex for the same quality.
+const QUALITY_TO_QINDEX_PHOTO: [(u8, u8); 6] = [(0, 255), (25, 180), (50, 110), (75, 50), (90, 20), (100, 1)];
+const QUALITY_TO_QINDEX_SCREEN: [(u8, u8); 6] = [(0, 255), (25, 150), (50, 85), (75, 35), (90, 12), (100, 1)];
+
+// Map a quality from 0 (worst) to 100 (best) to a qindex
+// Tune::Auto uses the mapping for photos, so callers which can see the image should
+// resolve it first (see classify::detect_tune())
+pub fn quality_to_qindex(quality: u8, tune: Tune) -> u8 {
+  assert!(quality <= MAX_QUALITY);
+  let points = match tune {
+    Tune::Psnr | Tune::Ssim | Tune::Auto => &QUALITY_TO_QINDEX_PHOTO,
+    Tune::Screen => &QUALITY_TO_QINDEX_SCREEN,
+  };
+  let i = points.iter().rposition(|&(q, _)| q <= quality).unwrap();
+  let (q0, qindex0) = points[i];
+  if q0 == quality {
+    return qindex0;
+  }
+  let (q1, qindex1) = points[i + 1];
+  // Round to nearest, going down the qindex scale
+  let span = (q1 - q0) as i32;
+  let offset = (qindex0 as i32 - qindex1 as i32) * (quality - q0) as i32;
+  return (qindex0 as i32 - (offset + span / 2) / span) as u8;
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EncoderConfig {
+  // Profile format version, see CONFIG_VERSION above
+  pub version: u32,
+
+  // Quantizer to use. Valid range is 1-255, inclusive
+  pub qindex: u8,
+
+  // Pixel format
+  pub bit_depth: u8,
+  pub subsampling: ChromaSubsampling,
+
+  // Number of tile columns and rows
+  pub tile_cols: usize,
+  pub tile_rows: usize,
+
+  // Colour description, written into the AVIF container
+  pub color_primaries: u16,
+  pub transfer_function: u16,
+  pub matrix_coefficients: u16,
+  // Whether to also write the three values above into the AV1 sequence header, so that
+  // raw .obu output, and players which go by the bitstream, get them too. They're left
+  // out anyway when all three are unspecified, as that's what leaving them out means
+  pub obu_color_description: bool,
+  // Where the chroma samples sit, written into the sequence header. When converting
+  // from RGB (see colorconv.rs), this also controls how chroma is downsampled.
+  pub chroma_sample_position: ChromaSamplePosition,
+  // How to downsample chroma when the input is 4:2:2 or 4:4:4
+  pub chroma_filter: ChromaFilter,
+  // Whether pixel values use the full 0-255 range, as in screenshots and sRGB images,
+  // rather than the "TV" range of 16-235 (luma) and 16-240 (chroma)
+  pub full_range: bool,
+  // Size the decoded image should be displayed at, signalled in the frame header, or 0x0
+  // to display it at its own size. Players may ignore this, as AVIF gives the size separately
+  pub render_width: usize,
+  pub render_height: usize,
+  // Superres denominator, from 8 (off) to 16. Above 8, the image is coded at a width of
+  // 8/denominator times its real width, and decoders upscale it horizontally afterwards
+  pub superres_denominator: u8,
+
+  // Speed preset, from 0 (slowest, best compression) to 10 (fastest)
+  // There is currently only one encoding strategy, so this has no effect yet
+  pub speed: u8,
+
+  // Number of passes: 1, or 2 to run an analysis pass first and use the results
+  // to vary the qindex per superblock (see analysis.rs)
+  pub passes: u8,
+
+  // Content type to tune for
+  pub tune: Tune,
+
+  // Sharpness, from 0 to 7. Higher values quantize less aggressively, so that fewer small
+  // coefficients are rounded down to zero. This keeps more fine detail and texture, at the
+  // cost of a larger file for the same qindex.
+  pub sharpness: u8,
+
+  // Offsets added to the qindex for the chroma DC and AC coefficients, signalled as
+  // frame-level delta-q values. Valid range is -64 to 63. Positive values spend fewer
+  // bits on chroma; negative values spend more.
+  pub chroma_dc_qoffset: i8,
+  pub chroma_ac_qoffset: i8,
+
+  // qindex for the background, when a foreground mask is given (see
+  // AV1Encoder::set_foreground_mask()), or 0 if none has been set. The foreground
+  // uses the main qindex
+  pub background_qindex: u8,
+
+  // Strength of the pre-filters applied to the source image, from 0 (off) to 10
+  // (see prefilter.rs)
+  pub denoise: u8,
+  pub sharpen: u8,
+
+  // AV1 level, as "X.Y", "auto" for the lowest level which fits the image, or "max" for
+  // no level constraints (see levels.rs)
+  pub level: String,
+  pub tier: Tier,
+  // AV1 profile to signal in the sequence header and av1C box
+  pub av1_profile: AV1Profile,
+
+  // Whether to tag the frame with an OBU extension header, giving its temporal and
+  // spatial layer IDs. This is needed when the image is to be embedded in a layered
+  // stream; the sequence header then declares a single operating point for this layer.
+  pub obu_extension: bool,
+  pub temporal_id: u8,
+  pub spatial_id: u8,
+
+  // Timing info for the sequence header, as a clock rate (time_scale) in ticks per
+  // second and the number of ticks per frame, or 0 for neither to leave it out.
+  // With decoder_model, the header also describes the decoder buffer model for each
+  // operating point. Both force the full sequence header to be used
+  pub time_scale: u32,
+  pub num_units_in_display_tick: u32,
+  pub decoder_model: bool,
+
+  // Always use the full sequence and frame headers, even when the simplified ones for
+  // still pictures could describe the image. This costs a few bytes, but some decoders
+  // mishandle reduced_still_picture_header
+  pub full_headers: bool,
+
+  // Whether to code the frame as one OBU_FRAME, or as separate frame header and tile
+  // group OBUs. This doesn't change the decoded image, but is useful for testing decoders
+  pub obu_layout: ObuLayout,
+
+  // Guarantee byte-identical output on every platform, for the same input and settings.
+  // The encoder itself only uses integer arithmetic, and its output doesn't depend on
+  // the number of threads, but the pre-filters use sin() and exp() from the platform's
+  // maths library, which may round differently. This makes them use portable versions.
+  pub deterministic: bool,
+
+  // Maximum number of threads to run at once, or 0 for one per physical CPU core (see threads.rs)
+  pub threads: usize,
+
+  // Limit on the memory an encode can use, in bytes, or 0 for no limit. Encodes which
+  // would need more fail with ConfigError::TooLarge before allocating anything large,
+  // so callers can try again with encode_image_streaming() (see memory.rs)
+  pub max_memory: usize,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+  Io(io::Error),
+  Parse(String),
+  // The configuration is self-contradictory or out of range
+  Invalid(String),
+  // The configuration is valid AV1, but needs features this encoder doesn't support yet
+  Unsupported(String),
+  // The image is too large for AV1, for a single tile, or for the configured level
+  TooLarge(String),
+}
+
+impl fmt::Display for ConfigError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      ConfigError::Io(e) => write!(f, "{}", e),
+      ConfigError::Parse(msg) => write!(f, "{}", msg),
+      ConfigError::Invalid(msg) => write!(f, "{}", msg),
+      ConfigError::Unsupported(msg) => write!(f, "{} is not supported yet", msg),
+      ConfigError::TooLarge(msg) => write!(f, "{}", msg),
+    }
+  }
+}
+
+impl From<io::Error> for ConfigError {
+  fn from(e: io::Error) -> Self {
+    ConfigError::Io(e)
+  }
+}
+
+impl Default for EncoderConfig {
+  fn default() -> Self {
+    Self {
+      version: CONFIG_VERSION,
+      qindex: 35,
+      bit_depth: 8,
+      subsampling: ChromaSubsampling::Yuv420,
+      tile_cols: 1,
+      tile_rows: 1,
+      // 2 = "unspecified" for all three colour parameters
+      color_primaries: 2,
+      transfer_function: 2,
+      matrix_coefficients: 2,
+      obu_color_description: true,
+      chroma_sample_position: ChromaSamplePosition::Unknown,
+      chroma_filter: ChromaFilter::Bilinear,
+      full_range: false,
+      render_width: 0,
+      render_height: 0,
+      superres_denominator: 8,
+      speed: 6,
+      passes: 1,
+      tune: Tune::Auto,
+      sharpness: 0,
+      chroma_dc_qoffset: 0,
+      chroma_ac_qoffset: 0,
+      background_qindex: 0,
+      denoise: 0,
+      sharpen: 0,
+      level: LEVEL_AUTO.into(),
+      av1_profile: AV1Profile::Auto,
+      tier: Tier::Main,
+      obu_extension: false,
+      temporal_id: 0,
+      spatial_id: 0,
+      time_scale: 0,
+      num_units_in_display_tick: 0,
+      decoder_model: false,
+      full_headers: false,
+      obu_layout: ObuLayout::Frame,
+      deterministic: false,
+      threads: 0,
+      max_memory: 0,
+    }
+  }
+}
+
+impl EncoderConfig {
+  pub fn builder() -> EncoderConfigBuilder {
+    EncoderConfigBuilder {
+      config: EncoderConfig::default(),
+      quality: None
+    }
+  }
+
+  // Check that this configuration can actually be encoded
+  // This is the single place where option values are checked, and is called
+  // both when building a config and when constructing an encoder, so that
+  // configs which were loaded from a profile or modified in-place also get checked.
+  pub fn validate(&self) -> Result<(), ConfigError> {
+    if self.qindex == 0 {
+      // qindex 0 means lossless mode, which we don't implement
+      return Err(ConfigError::Unsupported("Lossless mode (qindex 0)".into()));
+    }
+
+    if ![8, 10, 12].contains(&self.bit_depth) {
+      return Err(ConfigError::Invalid(format!("Invalid bit depth {}, must be 8, 10, or 12", self.bit_depth)));
+    }
+    if self.av1_profile != AV1Profile::Auto && !self.profile_supports_format(self.av1_profile) {
+      return Err(ConfigError::Invalid(format!("The {:?} AV1 profile can't signal {}-bit {} images",
+                                              self.av1_profile, self.bit_depth, self.subsampling.name())));
+    }
+    if self.bit_depth != 8 {
+      return Err(ConfigError::Unsupported(format!("Bit depth {}", self.bit_depth)));
+    }
+
+    if self.subsampling != ChromaSubsampling::Yuv420 {
+      return Err(ConfigError::Unsupported(format!("Chroma subsampling {:?}", self.subsampling)));
+    }
+
+    if self.tile_cols == 0 || self.tile_rows == 0 {
+      return Err(ConfigError::Invalid("Tile columns and rows must be at least 1".into()));
+    }
+    if self.tile_cols != 1 || self.tile_rows != 1 {
+      return Err(ConfigError::Unsupported("Multiple tiles".into()));
+    }
+
+    for (name, value) in [("colour primaries", self.color_primaries),
+                          ("transfer function", self.transfer_function),
+                          ("matrix coefficients", self.matrix_coefficients)] {
+      // These are stored in 8 bits in the AV1 sequence header
+      if value > 255 {
+        return Err(ConfigError::Invalid(format!("Invalid {} {}, must be at most 255", name, value)));
+      }
+    }
+
+    // Reserved values (as listed in the AV1 spec, section 6.4.2) have no meaning, so
+    // decoders would have to guess
+    let reserved_primaries = matches!(self.color_primaries, 0 | 3 | 13 ..= 21 | 23 ..);
+    let reserved_transfer = matches!(self.transfer_function, 0 | 3 | 19 ..);
+    let reserved_matrix = matches!(self.matrix_coefficients, 3 | 15 ..);
+    for (name, value, reserved) in [("colour primaries", self.color_primaries, reserved_primaries),
+                                    ("transfer function", self.transfer_function, reserved_transfer),
+                                    ("matrix coefficients", self.matrix_coefficients, reserved_matrix)] {
+      if reserved {
+        return Err(ConfigError::Invalid(format!("The {} value {} is reserved", name, value)));
+      }
+    }
+    // The identity matrix stores RGB directly, which can't have its chroma subsampled
+    if self.matrix_coefficients == 0 && self.subsampling != ChromaSubsampling::Yuv444 {
+      return Err(ConfigError::Invalid(format!("The identity matrix (0) can't be used with {} images",
+                                              self.subsampling.name())));
+    }
+
+    if self.speed > 10 {
+      return Err(ConfigError::Invalid(format!("Invalid speed {}, must be between 0 and 10", self.speed)));
+    }
+
+    if self.passes != 1 && self.passes != 2 {
+      return Err(ConfigError::Invalid(format!("Invalid number of passes {}, must be 1 or 2", self.passes)));
+    }
+
+    if self.sharpness > 7 {
+      return Err(ConfigError::Invalid(format!("Invalid sharpness {}, must be between 0 and 7", self.sharpness)));
+    }
+
+    // Frame-level delta-q values are coded as 7-bit signed integers
+    for offset in [self.chroma_dc_qoffset, self.chroma_ac_qoffset] {
+      if !(-64 ..= 63).contains(&offset) {
+        return Err(ConfigError::Invalid(format!("Invalid chroma qindex offset {}, must be between -64 and 63", offset)));
+      }
+    }
+
+    if self.denoise > MAX_PREFILTER_STRENGTH {
+      return Err(ConfigError::Invalid(format!("Invalid denoise strength {}, must be between 0 and {}",
+                                              self.denoise, MAX_PREFILTER_STRENGTH)));
+    }
+
+    if self.sharpen > MAX_PREFILTER_STRENGTH {
+      return Err(ConfigError::Invalid(format!("Invalid sharpen strength {}, must be between 0 and {}",
+                                              self.sharpen, MAX_PREFILTER_STRENGTH)));
+    }
+
+    // Limits which depend on the image size are checked by levels::check_level()
+    let seq_level_idx = if self.level == LEVEL_AUTO {
+      None
+    } else {
+      let Some(seq_level_idx) = parse_level(&self.level) else {
+        return Err(ConfigError::Invalid(format!("Unknown level \"{}\", must be \"auto\", \"max\" or one of {}", self.level,
+                                                LEVEL_LIMITS.iter().map(|l| level_name(l.seq_level_idx)).collect::<Vec<_>>().join(", "))));
+      };
+      Some(seq_level_idx)
+    };
+    if self.tier == Tier::High {
+      if seq_level_idx.is_some_and(|idx| idx < MIN_HIGH_TIER_LEVEL) {
+        return Err(ConfigError::Invalid(format!("Level {} has no high tier, use level {} or higher",
+                                                self.level, level_name(MIN_HIGH_TIER_LEVEL))));
+      }
+      // The reduced still picture header has no way to signal the tier, so the full
+      // headers are needed. These code the tier for any level which has a high tier
+      if self.reduced_still_picture_header() {
+        return Err(ConfigError::Invalid("High tier needs the full sequence header (--full-headers), as the reduced still picture header can't signal the tier".into()));
+      }
+    }
+
+    // The render size is coded as 16-bit values minus 1
+    let render_size_valid = (1..=65536).contains(&self.render_width) && (1..=65536).contains(&self.render_height);
+    if (self.render_width, self.render_height) != (0, 0) && !render_size_valid {
+      return Err(ConfigError::Invalid(format!("Invalid render size {}x{}, must be between 1x1 and 65536x65536",
+                                              self.render_width, self.render_height)));
+    }
+
+    if !(8..=16).contains(&self.superres_denominator) {
+      return Err(ConfigError::Invalid(format!("Invalid superres denominator {}, must be between 8 and 16",
+                                              self.superres_denominator)));
+    }
+
+    if self.temporal_id > 7 {
+      return Err(ConfigError::Invalid(format!("Invalid temporal ID {}, must be between 0 and 7", self.temporal_id)));
+    }
+    if self.spatial_id > 3 {
+      return Err(ConfigError::Invalid(format!("Invalid spatial ID {}, must be between 0 and 3", self.spatial_id)));
+    }
+    if !self.obu_extension && (self.temporal_id != 0 || self.spatial_id != 0) {
+      return Err(ConfigError::Invalid("Temporal and spatial IDs can only be set when the OBU extension header is enabled".into()));
+    }
+
+    if (self.time_scale == 0) != (self.num_units_in_display_tick == 0) {
+      return Err(ConfigError::Invalid(format!("Invalid timing info {}/{}, both values must be nonzero",
+                                              self.time_scale, self.num_units_in_display_tick)));
+    }
+    if self.decoder_model && !self.timing_info() {
+      return Err(ConfigError::Invalid("The decoder model can only be signalled along with timing info".into()));
+    }
+
+    if self.threads > MAX_THREADS {
+      return Err(ConfigError::Invalid(format!("Invalid number of threads {}, must be between 0 (one per core) and {}",
+                                              self.threads, MAX_THREADS)));
+    }
+
+    Ok(())
+  }
+
+  // Whether the given profile can signal this config's bit depth and chroma subsampling
+  fn profile_supports_format(&self, profile: AV1Profile) -> bool {
+    let twelve_bit = self.bit_depth == 12;
+    match profile {
+      AV1Profile::Auto => true,
+      AV1Profile::Main => !twelve_bit && matches!(self.subsampling, ChromaSubsampling::Yuv420 | ChromaSubsampling::Monochrome),
+      AV1Profile::High => !twelve_bit && self.subsampling == ChromaSubsampling::Yuv444,
+      // Below 12 bits, the subsampling is implied to be 4:2:2 unless the image is monochrome
+      AV1Profile::Professional => twelve_bit ||
+                                  matches!(self.subsampling, ChromaSubsampling::Yuv422 | ChromaSubsampling::Monochrome),
+    }
+  }
+
+  // Profile (seq_profile) to signal in the sequence header and av1C box. Only valid after validate()
+  pub fn seq_profile(&self) -> u8 {
+    let profile = if self.av1_profile != AV1Profile::Auto {
+      self.av1_profile
+    } else {
+      [AV1Profile::Main, AV1Profile::High, AV1Profile::Professional].into_iter()
+        .find(|&profile| self.profile_supports_format(profile)).unwrap()
+    };
+    return match profile {
+      AV1Profile::Main => 0,
+      AV1Profile::High => 1,
+      _ => 2,
+    };
+  }
+
+  // Level to signal in the sequence header and av1C box for an image of the given size.
+  // Only valid after validate()
+  pub fn seq_level_idx(&self, width: usize, height: usize) -> u8 {
+    if self.level == LEVEL_AUTO {
+      // Levels below 4.0 have no high tier, and the tier isn't even coded for them
+      let min_level = min_level(width, height, self.tile_cols, self.tile_rows);
+      if self.tier == Tier::High { max(min_level, MIN_HIGH_TIER_LEVEL) } else { min_level }
+    } else {
+      parse_level(&self.level).unwrap()
+    }
+  }
+
+  // Layer IDs for the OBU extension header, if enabled
+  pub fn extension_header(&self) -> Option<ObuExtension> {
+    if self.obu_extension {
+      Some(ObuExtension {
+        temporal_id: self.temporal_id,
+        spatial_id: self.spatial_id,
+      })
+    } else {
+      None
+    }
+  }
+
+  // Whether the sequence header carries timing info
+  pub fn timing_info(&self) -> bool {
+    return self.time_scale != 0;
+  }
+
+  // Whether the sequence and frame headers can be the simplified ones for still pictures.
+  // Layers, timing info and the decoder model can only be described by the full headers
+  pub fn reduced_still_picture_header(&self) -> bool {
+    return !self.full_headers && !self.obu_extension && !self.timing_info();
+  }
+
+  pub fn from_toml(text: &str) -> Result<Self, ConfigError> {
+    let config: EncoderConfig = toml::from_str(text).map_err(|e| ConfigError::Parse(e.to_string()))?;
+    if config.version > CONFIG_VERSION {
+      // Still usable, as unknown fields are skipped, but the user should know
+      // that some settings may not have been applied
+      eprintln!("Warning: Settings profile has version {}, but this version of tinyavif only understands up to version {}",
+                config.version, CONFIG_VERSION);
+    }
+    return Ok(config);
+  }
+
+  pub fn to_toml(&self) -> String {
+    // Always write out the current version, even if this config was loaded
+    // from an older profile
+    let mut config = self.clone();
+    config.version = CONFIG_VERSION;
+    // Serializing a plain struct of integers can't fail
+    toml::to_string_pretty(&config).unwrap()
+  }
+}
+
+// Builder for EncoderConfig
+// Starts from the default settings; build() validates the result
+pub struct EncoderConfigBuilder {
+  config: EncoderConfig,
+  // Applied in build(), once the tuning is known
+  quality: Option<u8>
+}
+
+impl EncoderConfigBuilder {
+  pub fn qindex(mut self, qindex: u8) -> Self {
+    self.config.qindex = qindex;
+    self
+  }
+
+  // Set the qindex from a quality between 0 and 100 instead (see quality_to_qindex())
+  pub fn quality(mut self, quality: u8) -> Self {
+    self.quality = Some(quality);
+    self
+  }
+
+  pub fn bit_depth(mut self, bit_depth: u8) -> Self {
+    self.config.bit_depth = bit_depth;
+    self
+  }
+
+  pub fn subsampling(mut self, subsampling: ChromaSubsampling) -> Self {
+    self.config.subsampling = subsampling;
+    self
+  }
+
+  pub fn tiles(mut self, tile_cols: usize, tile_rows: usize) -> Self {
+    self.config.tile_cols = tile_cols;
+    self.config.tile_rows = tile_rows;
+    self
+  }
+
+  pub fn color_description(mut self, color_primaries: u16, transfer_function: u16, matrix_coefficients: u16) -> Self {
+    self.config.color_primaries = color_primaries;
+    self.config.transfer_function = transfer_function;
+    self.config.matrix_coefficients = matrix_coefficients;
+    self
+  }
+
+  pub fn obu_color_description(mut self, obu_color_description: bool) -> Self {
+    self.config.obu_color_description = obu_color_description;
+    self
+  }
+
+  pub fn color_preset(self, preset: ColorPreset) -> Self {
+    let (color_primaries, transfer_function, matrix_coefficients) = preset.cicp();
+    return self.color_description(color_primaries, transfer_function, matrix_coefficients);
+  }
+
+  pub fn chroma_sample_position(mut self, chroma_sample_position: ChromaSamplePosition) -> Self {
+    self.config.chroma_sample_position = chroma_sample_position;
+    self
+  }
+
+  pub fn chroma_filter(mut self, chroma_filter: ChromaFilter) -> Self {
+    self.config.chroma_filter = chroma_filter;
+    self
+  }
+
+  pub fn full_range(mut self, full_range: bool) -> Self {
+    self.config.full_range = full_range;
+    self
+  }
+
+  pub fn render_size(mut self, width: usize, height: usize) -> Self {
+    self.config.render_width = width;
+    self.config.render_height = height;
+    self
+  }
+
+  pub fn superres_denominator(mut self, denominator: u8) -> Self {
+    self.config.superres_denominator = denominator;
+    self
+  }
+
+  pub fn speed(mut self, speed: u8) -> Self {
+    self.config.speed = speed;
+    self
+  }
+
+  pub fn passes(mut self, passes: u8) -> Self {
+    self.config.passes = passes;
+    self
+  }
+
+  pub fn tune(mut self, tune: Tune) -> Self {
+    self.config.tune = tune;
+    self
+  }
+
+  pub fn sharpness(mut self, sharpness: u8) -> Self {
+    self.config.sharpness = sharpness;
+    self
+  }
+
+  pub fn chroma_qoffset(mut self, dc_offset: i8, ac_offset: i8) -> Self {
+    self.config.chroma_dc_qoffset = dc_offset;
+    self.config.chroma_ac_qoffset = ac_offset;
+    self
+  }
+
+  pub fn background_qindex(mut self, qindex: u8) -> Self {
+    self.config.background_qindex = qindex;
+    self
+  }
+
+  pub fn denoise(mut self, strength: u8) -> Self {
+    self.config.denoise = strength;
+    self
+  }
+
+  pub fn sharpen(mut self, strength: u8) -> Self {
+    self.config.sharpen = strength;
+    self
+  }
+
+  pub fn level(mut self, level: &str, tier: Tier) -> Self {
+    self.config.level = level.into();
+    self.config.tier = tier;
+    self
+  }
+
+  pub fn av1_profile(mut self, profile: AV1Profile) -> Self {
+    self.config.av1_profile = profile;
+    self
+  }
+
+  pub fn obu_extension(mut self, temporal_id: u8, spatial_id: u8) -> Self {
+    self.config.obu_extension = true;
+    self.config.temporal_id = temporal_id;
+    self.config.spatial_id = spatial_id;
+    self
+  }
+
+  pub fn timing_info(mut self, time_scale: u32, num_units_in_display_tick: u32) -> Self {
+    self.config.time_scale = time_scale;
+    self.config.num_units_in_display_tick = num_units_in_display_tick;
+    self
+  }
+
+  pub fn decoder_model(mut self, decoder_model: bool) -> Self {
+    self.config.decoder_model = decoder_model;
+    self
+  }
+
+  pub fn full_headers(mut self, full_headers: bool) -> Self {
+    self.config.full_headers = full_headers;
+    self
+  }
+
+  pub fn obu_layout(mut self, layout: ObuLayout) -> Self {
+    self.config.obu_layout = layout;
+    self
+  }
+
+  pub fn deterministic(mut self, deterministic: bool) -> Self {
+    self.config.deterministic = deterministic;
+    self
+  }
+
+  pub fn threads(mut self, threads: usize) -> Self {
+    self.config.threads = threads;
+    self
+  }
+
+  pub fn max_memory(mut self, max_memory: usize) -> Self {
+    self.config.max_memory = max_memory;
+    self
+  }
+
+  pub fn build(mut self) -> Result<EncoderConfig, ConfigError> {
+    if let Some(quality) = self.quality {
+      if quality > MAX_QUALITY {
+        return Err(ConfigError::Invalid(format!("Invalid quality {}, must be between 0 and {}", quality, MAX_QUALITY)));
+      }
+      self.config.qindex = quality_to_qindex(quality, self.config.tune);
+    }
+    self.config.validate()?;
+    Ok(self.config)
+  }
+}
@@ -0,0 +1,235 @@
+// Copyright (c) 2024-2025, The tinyavif contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+// Conformance checks against external decoders, run with `tinyavif conformance`
+//
+// `--verify` only checks the output against our own decoder, which was written alongside
+// the encoder, so can share its misreadings of the spec. This encodes a corpus of images
+// at a range of qindexes, then decodes each result with the built-in decoder and with any
+// reference decoders (dav1d, aomdec) which can be found, and checks that every decoder
+// gives exactly the encoder's reconstruction.
+//
+// The decoders are run as separate processes, through files in a working directory,
+// so that failing streams can be kept for debugging.
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use crate::av1_encoder::{AV1Encoder, EncodeError};
+use crate::classify::resolve_tune;
+use crate::config::{EncoderConfig, ObuLayout};
+use crate::decode::decode_obus;
+use crate::frame::Frame;
+use crate::hls::pack_obus;
+use crate::metrics::frame_psnr;
+use crate::testutil::{gradient_frame, noise_frame, screen_frame};
+use crate::y4m::Y4MReader;
+
+// qindexes to test at when none are given: lossless-adjacent, the default, and points
+// spread up to the coarsest quantizer
+pub const DEFAULT_QINDEXES: [u8; 6] = [1, 20, 35, 100, 180, 255];
+
+// Kinds of external decoder that we know how to run
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecoderKind {
+  Dav1d,
+  Aomdec,
+}
+
+impl DecoderKind {
+  pub fn name(&self) -> &'static str {
+    match self {
+      DecoderKind::Dav1d => "dav1d",
+      DecoderKind::Aomdec => "aomdec",
+    }
+  }
+
+  // Arguments to decode a raw .obu file (which both decoders detect from its contents)
+  // to a .y4m file
+  fn args(&self, input: &Path, output: &Path) -> Vec<PathBuf> {
+    match self {
+      DecoderKind::Dav1d => vec!["-q".into(), "-i".into(), input.into(), "-o".into(), output.into()],
+      DecoderKind::Aomdec => vec![input.into(), "-o".into(), output.into()],
+    }
+  }
+}
+
+// An external decoder, and the command used to run it
+#[derive(Clone, Debug)]
+pub struct ExternalDecoder {
+  pub kind: DecoderKind,
+  pub command: PathBuf,
+}
+
+impl ExternalDecoder {
+  // Whether the command can be run at all. Decoders differ in what they do with
+  // `--help`, so only whether it could be started counts, not the exit status
+  pub fn is_available(&self) -> bool {
+    let status = Command::new(&self.command).arg("--help")
+      .stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null())
+      .status();
+    return status.is_ok();
+  }
+
+  fn decode(&self, input: &Path, output: &Path) -> Result<Frame, String> {
+    let result = Command::new(&self.command).args(self.kind.args(input, output))
+      .stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::piped())
+      .output();
+    let result = result.map_err(|e| format!("failed to run {}: {}", self.command.display(), e))?;
+    if !result.status.success() {
+      let stderr = String::from_utf8_lossy(&result.stderr);
+      let message = stderr.lines().next_back().unwrap_or("").trim();
+      return Err(format!("{} ({})", result.status, message));
+    }
+    return read_y4m(output).map_err(|e| format!("unreadable output: {}", e));
+  }
+}
+
+// Result of decoding one stream with one decoder
+#[derive(Clone, Debug, PartialEq)]
+pub enum Outcome {
+  // The decoded image is identical to the encoder's reconstruction
+  Match,
+  // The decoded image differs, with this PSNR per plane against the reconstruction
+  Mismatch([f64; 3]),
+  // The decoder rejected the stream, or produced an image of the wrong size
+  Failed(String),
+}
+
+impl fmt::Display for Outcome {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Outcome::Match => write!(f, "ok"),
+      Outcome::Mismatch([y, u, v]) => write!(f, "MISMATCH (PSNR Y {:.2}, U {:.2}, V {:.2} dB)", y, u, v),
+      Outcome::Failed(message) => write!(f, "FAILED ({})", message),
+    }
+  }
+}
+
+// Results for one image at one qindex
+#[derive(Clone, Debug)]
+pub struct CaseResult {
+  pub image: String,
+  pub qindex: u8,
+  // Path of the encoded stream
+  pub stream: PathBuf,
+  // Outcome for each decoder, starting with the built-in one
+  pub outcomes: Vec<(&'static str, Outcome)>,
+}
+
+impl CaseResult {
+  pub fn passed(&self) -> bool {
+    return self.outcomes.iter().all(|(_, outcome)| *outcome == Outcome::Match);
+  }
+}
+
+// Synthetic images to test with when no corpus is given. The sizes are chosen so that
+// the padding, partial superblocks and odd chroma sizes all get exercised.
+pub fn default_corpus() -> Vec<(String, Frame)> {
+  return vec![
+    ("gradient-97x61".into(), gradient_frame(97, 61, 0x9E3779B9)),
+    ("gradient-256x192".into(), gradient_frame(256, 192, 0x2545F491)),
+    ("noise-65x33".into(), noise_frame(65, 33, 0x12345678)),
+    ("screen-320x200".into(), screen_frame(320, 200)),
+    ("screen-31x130".into(), screen_frame(31, 130)),
+  ];
+}
+
+fn read_y4m(path: &Path) -> Result<Frame, io::Error> {
+  let mut reader = Y4MReader::new(File::open(path)?)?;
+  let frame = reader.read_frame().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+  return Ok(*frame);
+}
+
+// Load the images in `paths`, which are .y4m files, or directories whose .y4m files
+// are all used (in name order)
+pub fn load_corpus(paths: &[PathBuf]) -> Result<Vec<(String, Frame)>, io::Error> {
+  let mut files = Vec::new();
+  for path in paths {
+    if path.is_dir() {
+      let mut entries: Vec<PathBuf> = fs::read_dir(path)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<_, _>>()?;
+      entries.retain(|entry| entry.extension().is_some_and(|ext| ext == "y4m"));
+      entries.sort();
+      files.extend(entries);
+    } else {
+      files.push(path.clone());
+    }
+  }
+
+  let mut corpus = Vec::with_capacity(files.len());
+  for file in files {
+    let frame = read_y4m(&file).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", file.display(), e)))?;
+    let name = file.file_stem().map_or("image".into(), |stem| stem.to_string_lossy().into_owned());
+    corpus.push((name, frame));
+  }
+  return Ok(corpus);
+}
+
+// Compare a decoded image against the encoder's reconstruction, which is padded, so
+// is only compared within the decoded image's crop region. That must be the size of
+// the source image
+fn compare(decoded: &Frame, recon: &Frame, source: &Frame) -> Outcome {
+  let (width, height) = (decoded.y().crop_width(), decoded.y().crop_height());
+  let (expected_width, expected_height) = (source.y().crop_width(), source.y().crop_height());
+  if (width, height) != (expected_width, expected_height) {
+    return Outcome::Failed(format!("decoded {}x{}, expected {}x{}", width, height, expected_width, expected_height));
+  }
+  let psnr = frame_psnr(decoded, recon);
+  if psnr.iter().all(|p| p.is_infinite()) {
+    return Outcome::Match;
+  }
+  return Outcome::Mismatch(psnr);
+}
+
+// Encode `source` at `qindex` with the other settings from `config`, write the stream
+// into `work_dir`, and decode it with the built-in decoder and each of `decoders`
+pub fn check_case(config: &EncoderConfig, name: &str, source: &Frame, qindex: u8,
+                  decoders: &[ExternalDecoder], work_dir: &Path) -> Result<CaseResult, EncodeError> {
+  let mut config = resolve_tune(config, source);
+  config.qindex = qindex;
+  let width = source.y().crop_width();
+  let height = source.y().crop_height();
+
+  let encoder = AV1Encoder::new(&config, width, height)?;
+  let sequence_header = encoder.generate_sequence_header();
+  let frame_header = encoder.generate_frame_header(config.obu_layout == ObuLayout::Separate);
+  let encoded = encoder.encode_image(source)?;
+  let av1_data = pack_obus(&sequence_header, &frame_header, &encoded.tile_data, true, config.extension_header(),
+                           config.obu_layout);
+  let recon = encoded.recon.unwrap();
+
+  let stem = format!("{}-q{}", name, qindex);
+  let stream = work_dir.join(format!("{}.obu", stem));
+  fs::write(&stream, &av1_data)?;
+
+  let mut outcomes = Vec::with_capacity(decoders.len() + 1);
+  outcomes.push(("internal", match decode_obus(&av1_data) {
+    Ok(decoded) => compare(&decoded, &recon, source),
+    Err(e) => Outcome::Failed(e.to_string()),
+  }));
+  for decoder in decoders {
+    let output = work_dir.join(format!("{}-{}.y4m", stem, decoder.kind.name()));
+    outcomes.push((decoder.kind.name(), match decoder.decode(&stream, &output) {
+      Ok(decoded) => compare(&decoded, &recon, source),
+      Err(message) => Outcome::Failed(message),
+    }));
+  }
+
+  return Ok(CaseResult {
+    image: name.into(),
+    qindex: qindex,
+    stream: stream,
+    outcomes: outcomes,
+  });
+}
@@ -17,16 +17,75 @@ pub const SUPPORTED_TX_SIZES: usize = 2;
 // This is class 6, indicating that the EOB is in [33, 64], for an 8x8 transform
 pub const MAX_SUPPORTED_EOB_CLASS: usize = 6;
 
-// Values used in CDF sizes
+// Values used in CDF sizes (see cdf.rs)
+pub const PARTITION_CONTEXTS: usize = 4;
+pub const SKIP_CONTEXTS: usize = 3;
+pub const SEGMENT_ID_CONTEXTS: usize = 3;
+pub const FRAME_LF_COUNT: usize = 4;
+
+pub const INTRA_MODE_CONTEXTS: usize = 5;
+pub const CFL_ALPHA_CONTEXTS: usize = 6;
+pub const BLOCK_SIZES: usize = 22;
+
+pub const PALETTE_BLOCK_SIZE_CONTEXTS: usize = 7;
+pub const PALETTE_Y_MODE_CONTEXTS: usize = 3;
+pub const PALETTE_UV_MODE_CONTEXTS: usize = 2;
+pub const PALETTE_COLOR_CONTEXTS: usize = 5;
+
+pub const TX_SIZE_CONTEXTS: usize = 3;
+// Square transform sizes, from 4x4 to 64x64
+pub const TX_SIZES: usize = 5;
+
 pub const TOKEN_CDF_Q_CTXS: usize = 4;
 
 pub const TXB_SKIP_CONTEXTS: usize = 13;
 
-pub const COEFF_BASE_CONTEXTS: usize = 26; // for 2d transforms; there are a separate set of 16 contexts for 1d transforms
+pub const EOB_COEF_CONTEXTS: usize = 9;
+pub const SIG_COEF_CONTEXTS: usize = 42; // 26 for 2d transforms, followed by 16 for 1d transforms
 pub const COEFF_BASE_EOB_CONTEXTS: usize = 4;
 pub const COEFF_BR_CONTEXTS: usize = 21;
 pub const DC_SIGN_CONTEXTS: usize = 3;
 
+// Weights used by the SMOOTH family of intra predictors, indexed by block dimension
+pub const sm_weights_4: [u8; 4] = [255, 149, 85, 64];
+pub const sm_weights_8: [u8; 8] = [255, 197, 146, 105, 73, 50, 37, 32];
+pub const sm_weights_16: [u8; 16] = [255, 225, 196, 170, 145, 123, 102, 84, 68, 54, 43, 33, 26, 20, 17, 16];
+
+pub fn sm_weights(size: usize) -> &'static [u8] {
+  match size {
+    4 => &sm_weights_4,
+    8 => &sm_weights_8,
+    16 => &sm_weights_16,
+    _ => panic!("Unsupported SMOOTH_PRED block size {}", size)
+  }
+}
+
+// Directional intra prediction
+// Base angle of each prediction mode, in degrees. Only meaningful for the directional modes.
+pub const mode_to_angle: [i32; 13] = [0, 90, 180, 45, 135, 113, 157, 203, 67, 0, 0, 0, 0];
+
+// Each directional mode can be adjusted by up to 3 steps of this many degrees either way
+pub const ANGLE_STEP: i32 = 3;
+
+// Tangent of each reachable prediction angle, relative to the nearest edge, in units of 1/64.
+// Unreachable angles are left as 0.
+pub const dr_intra_derivative: [u16; 90] = [
+  0, 0, 0, 1023, 0, 0, 547, 0, 0, 372, 0, 0, 0, 0,
+  273, 0, 0, 215, 0, 0, 178, 0, 0, 151, 0, 0, 132, 0, 0,
+  116, 0, 0, 102, 0, 0, 0, 90, 0, 0, 80, 0, 0, 71, 0, 0,
+  64, 0, 0, 57, 0, 0, 51, 0, 0, 45, 0, 0, 0, 40, 0, 0,
+  35, 0, 0, 31, 0, 0, 27, 0, 0, 23, 0, 0, 19, 0, 0,
+  15, 0, 0, 0, 0, 11, 0, 0, 7, 0, 0, 3, 0, 0
+];
+
+// Smoothing filters for the intra edge filter, indexed by strength - 1
+pub const INTRA_EDGE_TAPS: usize = 5;
+pub const intra_edge_kernel: [[i32; INTRA_EDGE_TAPS]; 3] = [
+  [0, 4, 8, 4, 0],
+  [0, 5, 6, 5, 0],
+  [2, 4, 4, 4, 2]
+];
+
 // Scan orders for 2D (ie. not H_* or V_*) transforms
 // The input to this is an index in coefficient scan order,
 // the output is an index (row * tx_width + col) into the quantized
@@ -55,11 +114,78 @@ pub const default_scan_8x8: [(u8, u8); 64] = [
   (6, 5), (7, 4), (7, 5), (6, 6), (5, 7), (6, 7), (7, 6), (7, 7)
 ];
 
-pub const scan_order_2d: [&[(u8, u8)]; SUPPORTED_TX_SIZES] = [
-  &default_scan_4x4,
-  &default_scan_8x8,
+// Rectangular sizes
+// These scan each diagonal in the direction which goes from the long edge
+// towards the short edge, rather than alternating like the square scans do
+// 4x8 (4 wide, 8 high)
+pub const default_scan_4x8: [(u8, u8); 32] = [
+  (0, 0), (0, 1), (1, 0), (0, 2), (1, 1), (2, 0), (0, 3), (1, 2),
+  (2, 1), (3, 0), (1, 3), (2, 2), (3, 1), (4, 0), (2, 3), (3, 2),
+  (4, 1), (5, 0), (3, 3), (4, 2), (5, 1), (6, 0), (4, 3), (5, 2),
+  (6, 1), (7, 0), (5, 3), (6, 2), (7, 1), (6, 3), (7, 2), (7, 3)
+];
+
+// 8x4 (8 wide, 4 high)
+pub const default_scan_8x4: [(u8, u8); 32] = [
+  (0, 0), (1, 0), (0, 1), (2, 0), (1, 1), (0, 2), (3, 0), (2, 1),
+  (1, 2), (0, 3), (3, 1), (2, 2), (1, 3), (0, 4), (3, 2), (2, 3),
+  (1, 4), (0, 5), (3, 3), (2, 4), (1, 5), (0, 6), (3, 4), (2, 5),
+  (1, 6), (0, 7), (3, 5), (2, 6), (1, 7), (3, 6), (2, 7), (3, 7)
+];
+
+// 8x16 (8 wide, 16 high)
+pub const default_scan_8x16: [(u8, u8); 128] = [
+  (0, 0), (0, 1), (1, 0), (0, 2), (1, 1), (2, 0), (0, 3), (1, 2),
+  (2, 1), (3, 0), (0, 4), (1, 3), (2, 2), (3, 1), (4, 0), (0, 5),
+  (1, 4), (2, 3), (3, 2), (4, 1), (5, 0), (0, 6), (1, 5), (2, 4),
+  (3, 3), (4, 2), (5, 1), (6, 0), (0, 7), (1, 6), (2, 5), (3, 4),
+  (4, 3), (5, 2), (6, 1), (7, 0), (1, 7), (2, 6), (3, 5), (4, 4),
+  (5, 3), (6, 2), (7, 1), (8, 0), (2, 7), (3, 6), (4, 5), (5, 4),
+  (6, 3), (7, 2), (8, 1), (9, 0), (3, 7), (4, 6), (5, 5), (6, 4),
+  (7, 3), (8, 2), (9, 1), (10, 0), (4, 7), (5, 6), (6, 5), (7, 4),
+  (8, 3), (9, 2), (10, 1), (11, 0), (5, 7), (6, 6), (7, 5), (8, 4),
+  (9, 3), (10, 2), (11, 1), (12, 0), (6, 7), (7, 6), (8, 5), (9, 4),
+  (10, 3), (11, 2), (12, 1), (13, 0), (7, 7), (8, 6), (9, 5), (10, 4),
+  (11, 3), (12, 2), (13, 1), (14, 0), (8, 7), (9, 6), (10, 5), (11, 4),
+  (12, 3), (13, 2), (14, 1), (15, 0), (9, 7), (10, 6), (11, 5), (12, 4),
+  (13, 3), (14, 2), (15, 1), (10, 7), (11, 6), (12, 5), (13, 4), (14, 3),
+  (15, 2), (11, 7), (12, 6), (13, 5), (14, 4), (15, 3), (12, 7), (13, 6),
+  (14, 5), (15, 4), (13, 7), (14, 6), (15, 5), (14, 7), (15, 6), (15, 7)
+];
+
+// 16x8 (16 wide, 8 high)
+pub const default_scan_16x8: [(u8, u8); 128] = [
+  (0, 0), (1, 0), (0, 1), (2, 0), (1, 1), (0, 2), (3, 0), (2, 1),
+  (1, 2), (0, 3), (4, 0), (3, 1), (2, 2), (1, 3), (0, 4), (5, 0),
+  (4, 1), (3, 2), (2, 3), (1, 4), (0, 5), (6, 0), (5, 1), (4, 2),
+  (3, 3), (2, 4), (1, 5), (0, 6), (7, 0), (6, 1), (5, 2), (4, 3),
+  (3, 4), (2, 5), (1, 6), (0, 7), (7, 1), (6, 2), (5, 3), (4, 4),
+  (3, 5), (2, 6), (1, 7), (0, 8), (7, 2), (6, 3), (5, 4), (4, 5),
+  (3, 6), (2, 7), (1, 8), (0, 9), (7, 3), (6, 4), (5, 5), (4, 6),
+  (3, 7), (2, 8), (1, 9), (0, 10), (7, 4), (6, 5), (5, 6), (4, 7),
+  (3, 8), (2, 9), (1, 10), (0, 11), (7, 5), (6, 6), (5, 7), (4, 8),
+  (3, 9), (2, 10), (1, 11), (0, 12), (7, 6), (6, 7), (5, 8), (4, 9),
+  (3, 10), (2, 11), (1, 12), (0, 13), (7, 7), (6, 8), (5, 9), (4, 10),
+  (3, 11), (2, 12), (1, 13), (0, 14), (7, 8), (6, 9), (5, 10), (4, 11),
+  (3, 12), (2, 13), (1, 14), (0, 15), (7, 9), (6, 10), (5, 11), (4, 12),
+  (3, 13), (2, 14), (1, 15), (7, 10), (6, 11), (5, 12), (4, 13), (3, 14),
+  (2, 15), (7, 11), (6, 12), (5, 13), (4, 14), (3, 15), (7, 12), (6, 13),
+  (5, 14), (4, 15), (7, 13), (6, 14), (5, 15), (7, 14), (6, 15), (7, 15)
 ];
 
+// Default scan order for a given transform size
+pub fn default_scan(txh: usize, txw: usize) -> &'static [(u8, u8)] {
+  match (txw, txh) {
+    (4, 4) => &default_scan_4x4,
+    (8, 8) => &default_scan_8x8,
+    (4, 8) => &default_scan_4x8,
+    (8, 4) => &default_scan_8x4,
+    (8, 16) => &default_scan_8x16,
+    (16, 8) => &default_scan_16x8,
+    _ => panic!("No scan order for {}x{} transforms", txw, txh),
+  }
+}
+
 // Offsets of coefficients which are looked at to determine
 // the context for coeff_base
 // We only store the offsets for DCT_DCT for now
@@ -114,75 +240,280 @@ pub const av1_cospi_arr_data: [[i32; 64]; 4] = [
     1795, 1598, 1401, 1202, 1003, 803,  603,  402,  201 ]
 ];
 
-pub const av1_txfm_stages: [usize; SUPPORTED_TX_SIZES] = [
-  4, // 4X4
-  6, // 8X8
+// Constants for the 4-point ADST, sin(PI*j/9) * (2*sqrt(2)/3) * (1<<cos_bit), indexed as
+// av1_sinpi_arr_data[cos_bit - 10][j] like the cospi table above
+pub const av1_sinpi_arr_data: [[i32; 5]; 4] = [
+  [ 0, 330, 621, 836, 951 ],
+  [ 0, 660, 1241, 1672, 1901 ],
+  [ 0, 1321, 2482, 3344, 3803 ],
+  [ 0, 2642, 4964, 6689, 7606 ]
 ];
 
-pub const av1_txfm_fwd_shift: [[i32; 3]; SUPPORTED_TX_SIZES] = [
-  [ 2,  0, 0 ], // 4x4
-  [ 2, -1, 0 ], // 8x8
+// Parameters for each 1D transform length: DCT4, DCT8, DCT16
+pub const TXFM_1D_SIZES: usize = 3;
+
+// Most stages of any 1D transform
+pub const MAX_TXFM_STAGES: usize = 8;
+
+pub const av1_txfm_stages: [usize; TXFM_1D_SIZES] = [
+  4, // DCT4
+  6, // DCT8
+  8, // DCT16
 ];
 
 // Maximum range of values after each forward transform stage,
 // rounded up to powers of 2
-pub const av1_txfm_fwd_range_mult2: [[i32; 6]; SUPPORTED_TX_SIZES] = [
-  [ 0, 2, 3, 3, 0, 0 ], // 4x4
-  [ 0, 2, 4, 5, 5, 5 ], // 8x8
+pub const av1_txfm_fwd_range_mult2: [[i32; 8]; TXFM_1D_SIZES] = [
+  [ 0, 2, 3, 3, 0, 0, 0, 0 ], // DCT4
+  [ 0, 2, 4, 5, 5, 5, 0, 0 ], // DCT8
+  [ 0, 2, 4, 6, 7, 7, 7, 7 ], // DCT16
+];
+
+// Parameters for each 2D transform size supported by fwd_txfm2d() and inv_txfm2d()
+// This is more sizes than the rest of the encoder can use so far (see SUPPORTED_TX_SIZES)
+// Sizes are listed as width x height, in the same order as in the spec
+pub const TXFM_2D_SIZES: usize = 6;
+
+pub const av1_txfm_fwd_shift: [[i32; 3]; TXFM_2D_SIZES] = [
+  [ 2,  0, 0 ], // 4x4
+  [ 2, -1, 0 ], // 8x8
+  [ 2, -1, 0 ], // 4x8
+  [ 2, -1, 0 ], // 8x4
+  [ 2, -2, 0 ], // 8x16
+  [ 2, -2, 0 ], // 16x8
 ];
 
-pub const av1_txfm_inv_shift: [[i32; 2]; SUPPORTED_TX_SIZES] = [
+pub const av1_txfm_inv_shift: [[i32; 2]; TXFM_2D_SIZES] = [
   [  0, -4 ], // 4x4
   [ -1, -4 ], // 8x8
+  [  0, -4 ], // 4x8
+  [  0, -4 ], // 8x4
+  [ -1, -4 ], // 8x16
+  [ -1, -4 ], // 16x8
 ];
 
-pub const av1_txfm_inv_start_range: [i32; SUPPORTED_TX_SIZES] = [
-  5, // 4x4
-  6, // 8x8
-];
-
-// DC and AC quantizers for a given qindex
-pub const qindex_to_dc_q: [i32; 256] = [
-  4,    8,    8,    9,    10,  11,  12,  12,  13,  14,  15,   16,   17,   18,
-  19,   19,   20,   21,   22,  23,  24,  25,  26,  26,  27,   28,   29,   30,
-  31,   32,   32,   33,   34,  35,  36,  37,  38,  38,  39,   40,   41,   42,
-  43,   43,   44,   45,   46,  47,  48,  48,  49,  50,  51,   52,   53,   53,
-  54,   55,   56,   57,   57,  58,  59,  60,  61,  62,  62,   63,   64,   65,
-  66,   66,   67,   68,   69,  70,  70,  71,  72,  73,  74,   74,   75,   76,
-  77,   78,   78,   79,   80,  81,  81,  82,  83,  84,  85,   85,   87,   88,
-  90,   92,   93,   95,   96,  98,  99,  101, 102, 104, 105,  107,  108,  110,
-  111,  113,  114,  116,  117, 118, 120, 121, 123, 125, 127,  129,  131,  134,
-  136,  138,  140,  142,  144, 146, 148, 150, 152, 154, 156,  158,  161,  164,
-  166,  169,  172,  174,  177, 180, 182, 185, 187, 190, 192,  195,  199,  202,
-  205,  208,  211,  214,  217, 220, 223, 226, 230, 233, 237,  240,  243,  247,
-  250,  253,  257,  261,  265, 269, 272, 276, 280, 284, 288,  292,  296,  300,
-  304,  309,  313,  317,  322, 326, 330, 335, 340, 344, 349,  354,  359,  364,
-  369,  374,  379,  384,  389, 395, 400, 406, 411, 417, 423,  429,  435,  441,
-  447,  454,  461,  467,  475, 482, 489, 497, 505, 513, 522,  530,  539,  549,
-  559,  569,  579,  590,  602, 614, 626, 640, 654, 668, 684,  700,  717,  736,
-  755,  775,  796,  819,  843, 869, 896, 925, 955, 988, 1022, 1058, 1098, 1139,
-  1184, 1232, 1282, 1336
-];
-
-pub const qindex_to_ac_q: [i32; 256] = [
-  4,    8,    9,    10,   11,   12,   13,   14,   15,   16,   17,   18,   19,
-  20,   21,   22,   23,   24,   25,   26,   27,   28,   29,   30,   31,   32,
-  33,   34,   35,   36,   37,   38,   39,   40,   41,   42,   43,   44,   45,
-  46,   47,   48,   49,   50,   51,   52,   53,   54,   55,   56,   57,   58,
-  59,   60,   61,   62,   63,   64,   65,   66,   67,   68,   69,   70,   71,
-  72,   73,   74,   75,   76,   77,   78,   79,   80,   81,   82,   83,   84,
-  85,   86,   87,   88,   89,   90,   91,   92,   93,   94,   95,   96,   97,
-  98,   99,   100,  101,  102,  104,  106,  108,  110,  112,  114,  116,  118,
-  120,  122,  124,  126,  128,  130,  132,  134,  136,  138,  140,  142,  144,
-  146,  148,  150,  152,  155,  158,  161,  164,  167,  170,  173,  176,  179,
-  182,  185,  188,  191,  194,  197,  200,  203,  207,  211,  215,  219,  223,
-  227,  231,  235,  239,  243,  247,  251,  255,  260,  265,  270,  275,  280,
-  285,  290,  295,  300,  305,  311,  317,  323,  329,  335,  341,  347,  353,
-  359,  366,  373,  380,  387,  394,  401,  408,  416,  424,  432,  440,  448,
-  456,  465,  474,  483,  492,  501,  510,  520,  530,  540,  550,  560,  571,
-  582,  593,  604,  615,  627,  639,  651,  663,  676,  689,  702,  715,  729,
-  743,  757,  771,  786,  801,  816,  832,  848,  864,  881,  898,  915,  933,
-  951,  969,  988,  1007, 1026, 1046, 1066, 1087, 1108, 1129, 1151, 1173, 1196,
-  1219, 1243, 1267, 1292, 1317, 1343, 1369, 1396, 1423, 1451, 1479, 1508, 1537,
-  1567, 1597, 1628, 1660, 1692, 1725, 1759, 1793, 1828
+// Rectangular transforms with a 2:1 aspect ratio are scaled by sqrt(2) (forward)
+// or 1/sqrt(2) (inverse) to keep the overall scale the same as for square transforms.
+// These are the fixed-point versions of those factors
+pub const NewSqrt2Bits: u32 = 12;
+pub const NewSqrt2: i32 = 5793;
+pub const NewInvSqrt2: i32 = 2896;
+
+// DC and AC quantizers for a given qindex, for 8-, 10- and 12-bit images in turn
+// (Dc_Qlookup and Ac_Qlookup in the spec). Use dc_q() and ac_q() to look them up
+pub const qindex_to_dc_q: [[i32; 256]; 3] = [
+  [
+    4,    8,    8,    9,    10,  11,  12,  12,  13,  14,  15,   16,   17,   18,
+    19,   19,   20,   21,   22,  23,  24,  25,  26,  26,  27,   28,   29,   30,
+    31,   32,   32,   33,   34,  35,  36,  37,  38,  38,  39,   40,   41,   42,
+    43,   43,   44,   45,   46,  47,  48,  48,  49,  50,  51,   52,   53,   53,
+    54,   55,   56,   57,   57,  58,  59,  60,  61,  62,  62,   63,   64,   65,
+    66,   66,   67,   68,   69,  70,  70,  71,  72,  73,  74,   74,   75,   76,
+    77,   78,   78,   79,   80,  81,  81,  82,  83,  84,  85,   85,   87,   88,
+    90,   92,   93,   95,   96,  98,  99,  101, 102, 104, 105,  107,  108,  110,
+    111,  113,  114,  116,  117, 118, 120, 121, 123, 125, 127,  129,  131,  134,
+    136,  138,  140,  142,  144, 146, 148, 150, 152, 154, 156,  158,  161,  164,
+    166,  169,  172,  174,  177, 180, 182, 185, 187, 190, 192,  195,  199,  202,
+    205,  208,  211,  214,  217, 220, 223, 226, 230, 233, 237,  240,  243,  247,
+    250,  253,  257,  261,  265, 269, 272, 276, 280, 284, 288,  292,  296,  300,
+    304,  309,  313,  317,  322, 326, 330, 335, 340, 344, 349,  354,  359,  364,
+    369,  374,  379,  384,  389, 395, 400, 406, 411, 417, 423,  429,  435,  441,
+    447,  454,  461,  467,  475, 482, 489, 497, 505, 513, 522,  530,  539,  549,
+    559,  569,  579,  590,  602, 614, 626, 640, 654, 668, 684,  700,  717,  736,
+    755,  775,  796,  819,  843, 869, 896, 925, 955, 988, 1022, 1058, 1098, 1139,
+    1184, 1232, 1282, 1336
+  ],
+  [
+    4,    9,    10,   13,   15,   17,   20,   22,   25,   28,   31,   34,   37,
+    40,   43,   47,   50,   53,   57,   60,   64,   68,   71,   75,   78,   82,
+    86,   90,   93,   97,   101,  105,  109,  113,  116,  120,  124,  128,  132,
+    136,  140,  143,  147,  151,  155,  159,  163,  166,  170,  174,  178,  182,
+    185,  189,  193,  197,  200,  204,  208,  212,  215,  219,  223,  226,  230,
+    233,  237,  241,  244,  248,  251,  255,  259,  262,  266,  269,  273,  276,
+    280,  283,  287,  290,  293,  297,  300,  304,  307,  310,  314,  317,  321,
+    324,  327,  331,  334,  337,  343,  350,  356,  362,  369,  375,  381,  387,
+    394,  400,  406,  412,  418,  424,  430,  436,  442,  448,  454,  460,  466,
+    472,  478,  484,  490,  499,  507,  516,  525,  533,  542,  550,  559,  567,
+    576,  584,  592,  601,  609,  617,  625,  634,  644,  655,  666,  676,  687,
+    698,  708,  718,  729,  739,  749,  759,  770,  782,  795,  807,  819,  831,
+    844,  856,  868,  880,  891,  906,  920,  933,  947,  961,  975,  988,  1001,
+    1015, 1030, 1045, 1061, 1076, 1090, 1105, 1120, 1137, 1153, 1170, 1186, 1202,
+    1218, 1236, 1253, 1271, 1288, 1306, 1323, 1342, 1361, 1379, 1398, 1416, 1436,
+    1456, 1476, 1496, 1516, 1537, 1559, 1580, 1601, 1624, 1647, 1670, 1692, 1717,
+    1741, 1766, 1791, 1817, 1844, 1871, 1900, 1929, 1958, 1990, 2021, 2054, 2088,
+    2123, 2159, 2197, 2236, 2276, 2319, 2363, 2410, 2458, 2508, 2561, 2616, 2675,
+    2737, 2802, 2871, 2944, 3020, 3102, 3188, 3280, 3375, 3478, 3586, 3702, 3823,
+    3953, 4089, 4236, 4394, 4559, 4737, 4929, 5130, 5347
+  ],
+  [
+    4,     12,    18,    25,    33,    41,    50,    60,    70,    80,    91,    103,   115,
+    127,   140,   153,   166,   180,   194,   208,   222,   237,   251,   266,   281,   296,
+    312,   327,   343,   358,   374,   390,   405,   421,   437,   453,   469,   484,   500,
+    516,   532,   548,   564,   580,   596,   611,   627,   643,   659,   674,   690,   706,
+    721,   737,   752,   768,   783,   798,   814,   829,   844,   859,   874,   889,   904,
+    919,   934,   949,   964,   978,   993,   1008,  1022,  1037,  1051,  1065,  1080,  1094,
+    1108,  1122,  1136,  1151,  1165,  1179,  1192,  1206,  1220,  1234,  1248,  1261,  1275,
+    1288,  1302,  1315,  1329,  1342,  1368,  1393,  1419,  1444,  1469,  1494,  1519,  1544,
+    1569,  1594,  1618,  1643,  1668,  1692,  1717,  1741,  1765,  1789,  1814,  1838,  1862,
+    1885,  1909,  1933,  1957,  1992,  2027,  2061,  2096,  2130,  2165,  2199,  2233,  2267,
+    2300,  2334,  2367,  2400,  2434,  2467,  2499,  2532,  2575,  2618,  2661,  2704,  2746,
+    2788,  2830,  2872,  2913,  2954,  2995,  3036,  3076,  3127,  3177,  3226,  3275,  3324,
+    3373,  3421,  3469,  3517,  3565,  3621,  3677,  3733,  3788,  3843,  3897,  3951,  4005,
+    4058,  4119,  4181,  4241,  4301,  4361,  4420,  4479,  4546,  4612,  4677,  4742,  4807,
+    4871,  4942,  5013,  5083,  5153,  5222,  5291,  5367,  5442,  5517,  5591,  5665,  5745,
+    5825,  5905,  5984,  6063,  6149,  6234,  6319,  6404,  6495,  6587,  6678,  6769,  6867,
+    6966,  7064,  7163,  7269,  7376,  7483,  7599,  7715,  7832,  7958,  8085,  8214,  8352,
+    8492,  8635,  8788,  8945,  9104,  9275,  9450,  9639,  9832,  10031, 10245, 10465, 10702,
+    10946, 11210, 11482, 11776, 12081, 12409, 12750, 13118, 13501, 13913, 14343, 14807, 15290,
+    15812, 16356, 16943, 17575, 18237, 18949, 19718, 20521, 21387
+  ]
 ];
+
+pub const qindex_to_ac_q: [[i32; 256]; 3] = [
+  [
+    4,    8,    9,    10,   11,   12,   13,   14,   15,   16,   17,   18,   19,
+    20,   21,   22,   23,   24,   25,   26,   27,   28,   29,   30,   31,   32,
+    33,   34,   35,   36,   37,   38,   39,   40,   41,   42,   43,   44,   45,
+    46,   47,   48,   49,   50,   51,   52,   53,   54,   55,   56,   57,   58,
+    59,   60,   61,   62,   63,   64,   65,   66,   67,   68,   69,   70,   71,
+    72,   73,   74,   75,   76,   77,   78,   79,   80,   81,   82,   83,   84,
+    85,   86,   87,   88,   89,   90,   91,   92,   93,   94,   95,   96,   97,
+    98,   99,   100,  101,  102,  104,  106,  108,  110,  112,  114,  116,  118,
+    120,  122,  124,  126,  128,  130,  132,  134,  136,  138,  140,  142,  144,
+    146,  148,  150,  152,  155,  158,  161,  164,  167,  170,  173,  176,  179,
+    182,  185,  188,  191,  194,  197,  200,  203,  207,  211,  215,  219,  223,
+    227,  231,  235,  239,  243,  247,  251,  255,  260,  265,  270,  275,  280,
+    285,  290,  295,  300,  305,  311,  317,  323,  329,  335,  341,  347,  353,
+    359,  366,  373,  380,  387,  394,  401,  408,  416,  424,  432,  440,  448,
+    456,  465,  474,  483,  492,  501,  510,  520,  530,  540,  550,  560,  571,
+    582,  593,  604,  615,  627,  639,  651,  663,  676,  689,  702,  715,  729,
+    743,  757,  771,  786,  801,  816,  832,  848,  864,  881,  898,  915,  933,
+    951,  969,  988,  1007, 1026, 1046, 1066, 1087, 1108, 1129, 1151, 1173, 1196,
+    1219, 1243, 1267, 1292, 1317, 1343, 1369, 1396, 1423, 1451, 1479, 1508, 1537,
+    1567, 1597, 1628, 1660, 1692, 1725, 1759, 1793, 1828
+  ],
+  [
+    4,    9,    11,   13,   16,   18,   21,   24,   27,   30,   33,   37,   40,
+    44,   48,   51,   55,   59,   63,   67,   71,   75,   79,   83,   88,   92,
+    96,   100,  105,  109,  114,  118,  122,  127,  131,  136,  140,  145,  149,
+    154,  158,  163,  168,  172,  177,  181,  186,  190,  195,  199,  204,  208,
+    213,  217,  222,  226,  231,  235,  240,  244,  249,  253,  258,  262,  267,
+    271,  275,  280,  284,  289,  293,  297,  302,  306,  311,  315,  319,  324,
+    328,  332,  337,  341,  345,  349,  354,  358,  362,  367,  371,  375,  379,
+    384,  388,  392,  396,  401,  409,  417,  425,  433,  441,  449,  458,  466,
+    474,  482,  490,  498,  506,  514,  523,  531,  539,  547,  555,  563,  571,
+    579,  588,  596,  604,  616,  628,  640,  652,  664,  676,  688,  700,  713,
+    725,  737,  749,  761,  773,  785,  797,  809,  825,  841,  857,  873,  889,
+    905,  922,  938,  954,  970,  986,  1002, 1018, 1038, 1058, 1078, 1098, 1118,
+    1138, 1158, 1178, 1198, 1218, 1242, 1266, 1290, 1314, 1338, 1362, 1386, 1411,
+    1435, 1463, 1491, 1519, 1547, 1575, 1603, 1631, 1663, 1695, 1727, 1759, 1791,
+    1823, 1859, 1895, 1931, 1967, 2003, 2039, 2079, 2119, 2159, 2199, 2239, 2283,
+    2327, 2371, 2415, 2459, 2507, 2555, 2603, 2651, 2703, 2755, 2807, 2859, 2915,
+    2971, 3027, 3083, 3143, 3203, 3263, 3327, 3391, 3455, 3523, 3591, 3659, 3731,
+    3803, 3876, 3952, 4028, 4104, 4184, 4264, 4348, 4432, 4516, 4604, 4692, 4784,
+    4876, 4972, 5068, 5168, 5268, 5372, 5476, 5584, 5692, 5804, 5916, 6032, 6148,
+    6268, 6388, 6512, 6640, 6768, 6900, 7036, 7172, 7312
+  ],
+  [
+    4,     13,    19,    27,    35,    44,    54,    64,    75,    87,    99,    112,   126,
+    139,   154,   168,   183,   199,   214,   230,   247,   263,   280,   297,   314,   331,
+    349,   366,   384,   402,   420,   438,   456,   475,   493,   511,   530,   548,   567,
+    586,   604,   623,   642,   660,   679,   698,   716,   735,   753,   772,   791,   809,
+    828,   846,   865,   884,   902,   920,   939,   957,   976,   994,   1012,  1030,  1049,
+    1067,  1085,  1103,  1121,  1139,  1157,  1175,  1193,  1211,  1229,  1246,  1264,  1282,
+    1299,  1317,  1335,  1352,  1370,  1387,  1405,  1422,  1440,  1457,  1474,  1491,  1509,
+    1526,  1543,  1560,  1577,  1595,  1627,  1660,  1693,  1725,  1758,  1791,  1824,  1856,
+    1889,  1922,  1954,  1987,  2020,  2052,  2085,  2118,  2150,  2183,  2216,  2248,  2281,
+    2313,  2346,  2378,  2411,  2459,  2508,  2556,  2605,  2653,  2701,  2750,  2798,  2847,
+    2895,  2943,  2992,  3040,  3088,  3137,  3185,  3234,  3298,  3362,  3426,  3491,  3555,
+    3619,  3684,  3748,  3812,  3876,  3941,  4005,  4069,  4149,  4230,  4310,  4390,  4470,
+    4550,  4631,  4711,  4791,  4871,  4967,  5064,  5160,  5256,  5352,  5448,  5544,  5641,
+    5737,  5849,  5961,  6073,  6185,  6297,  6410,  6522,  6650,  6778,  6906,  7034,  7162,
+    7290,  7435,  7579,  7723,  7867,  8011,  8155,  8315,  8475,  8635,  8795,  8956,  9132,
+    9308,  9484,  9660,  9836,  10028, 10220, 10412, 10604, 10812, 11020, 11228, 11437, 11661,
+    11885, 12109, 12333, 12573, 12813, 13053, 13309, 13565, 13821, 14093, 14365, 14637, 14925,
+    15213, 15502, 15806, 16110, 16414, 16734, 17054, 17390, 17726, 18062, 18414, 18766, 19134,
+    19502, 19886, 20270, 20670, 21070, 21486, 21902, 22334, 22766, 23214, 23662, 24126, 24590,
+    25070, 25551, 26047, 26559, 27071, 27599, 28143, 28687, 29247
+  ]
+];
+
+// Quantizer lookups for a given bit depth, which must be 8, 10 or 12
+// (checked by EncoderConfig::validate())
+pub fn dc_q(qindex: u8, bit_depth: u8) -> i32 {
+  qindex_to_dc_q[((bit_depth - 8) >> 1) as usize][qindex as usize]
+}
+
+pub fn ac_q(qindex: u8, bit_depth: u8) -> i32 {
+  qindex_to_ac_q[((bit_depth - 8) >> 1) as usize][qindex as usize]
+}
+
+// Base quantizer rounding bias for each range of 32 qindex values, in units of 1/128 of
+// a quantizer step (see quant_rounding_bias() in recon.rs)
+// Tuned by BD-rate on natural images. At low qindex values there's little to gain from
+// dropping coefficients, so the bias stays close to rounding to nearest.
+pub const quant_rounding_bias_table: [u8; 8] = [58, 52, 46, 46, 48, 48, 48, 48];
+
+// Superresolution
+// Frames are coded at SUPERRES_NUM / denominator of their full width, where the
+// denominator is from SUPERRES_DENOM_MIN to SUPERRES_DENOM_MIN + 7
+pub const SUPERRES_NUM: usize = 8;
+pub const SUPERRES_DENOM_MIN: usize = 9;
+pub const SUPERRES_DENOM_BITS: usize = 3;
+// Positions during upscaling are tracked in units of 1/(1 << SUPERRES_SCALE_BITS) pixels,
+// and the top SUPERRES_FILTER_BITS of the fractional part select the filter phase
+pub const SUPERRES_SCALE_BITS: u32 = 14;
+pub const SUPERRES_FILTER_BITS: u32 = 6;
+pub const SUPERRES_EXTRA_BITS: u32 = SUPERRES_SCALE_BITS - SUPERRES_FILTER_BITS;
+pub const SUPERRES_FILTER_TAPS: usize = 8;
+pub const SUPERRES_FILTER_OFFSET: isize = 3;
+
+// Upscaling filter for each 1/64 pixel phase, with 7 fractional bits (Upscale_Filter in the spec)
+pub const upscale_filter: [[i32; SUPERRES_FILTER_TAPS]; 1 << SUPERRES_FILTER_BITS] = [
+  [0, 0, 0, 128, 0, 0, 0, 0], [0, 0, -1, 128, 2, -1, 0, 0],
+  [0, 1, -3, 127, 4, -2, 1, 0], [0, 1, -4, 127, 6, -3, 1, 0],
+  [0, 2, -6, 126, 8, -3, 1, 0], [0, 2, -7, 125, 11, -4, 1, 0],
+  [-1, 2, -8, 125, 13, -5, 2, 0], [-1, 3, -9, 124, 15, -6, 2, 0],
+  [-1, 3, -10, 123, 18, -6, 2, -1], [-1, 3, -11, 122, 20, -7, 3, -1],
+  [-1, 4, -12, 121, 22, -8, 3, -1], [-1, 4, -13, 120, 25, -9, 3, -1],
+  [-1, 4, -14, 118, 28, -9, 3, -1], [-1, 4, -15, 117, 30, -10, 4, -1],
+  [-1, 5, -16, 116, 32, -11, 4, -1], [-1, 5, -16, 114, 35, -12, 4, -1],
+  [-1, 5, -17, 112, 38, -12, 4, -1], [-1, 5, -18, 111, 40, -13, 5, -1],
+  [-1, 5, -18, 109, 43, -14, 5, -1], [-1, 6, -19, 107, 45, -14, 5, -1],
+  [-1, 6, -19, 105, 48, -15, 5, -1], [-1, 6, -19, 103, 51, -16, 5, -1],
+  [-1, 6, -20, 101, 53, -16, 6, -1], [-1, 6, -20, 99, 56, -17, 6, -1],
+  [-1, 6, -20, 97, 58, -17, 6, -1], [-1, 6, -20, 95, 61, -18, 6, -1],
+  [-2, 7, -20, 93, 64, -18, 6, -2], [-2, 7, -20, 91, 66, -19, 6, -1],
+  [-2, 7, -20, 88, 69, -19, 6, -1], [-2, 7, -20, 86, 71, -19, 6, -1],
+  [-2, 7, -20, 84, 74, -20, 7, -2], [-2, 7, -20, 81, 76, -20, 7, -1],
+  [-2, 7, -20, 79, 79, -20, 7, -2], [-1, 7, -20, 76, 81, -20, 7, -2],
+  [-2, 7, -20, 74, 84, -20, 7, -2], [-1, 6, -19, 71, 86, -20, 7, -2],
+  [-1, 6, -19, 69, 88, -20, 7, -2], [-1, 6, -19, 66, 91, -20, 7, -2],
+  [-2, 6, -18, 64, 93, -20, 7, -2], [-1, 6, -18, 61, 95, -20, 6, -1],
+  [-1, 6, -17, 58, 97, -20, 6, -1], [-1, 6, -17, 56, 99, -20, 6, -1],
+  [-1, 6, -16, 53, 101, -20, 6, -1], [-1, 5, -16, 51, 103, -19, 6, -1],
+  [-1, 5, -15, 48, 105, -19, 6, -1], [-1, 5, -14, 45, 107, -19, 6, -1],
+  [-1, 5, -14, 43, 109, -18, 5, -1], [-1, 5, -13, 40, 111, -18, 5, -1],
+  [-1, 4, -12, 38, 112, -17, 5, -1], [-1, 4, -12, 35, 114, -16, 5, -1],
+  [-1, 4, -11, 32, 116, -16, 5, -1], [-1, 4, -10, 30, 117, -15, 4, -1],
+  [-1, 3, -9, 28, 118, -14, 4, -1], [-1, 3, -9, 25, 120, -13, 4, -1],
+  [-1, 3, -8, 22, 121, -12, 4, -1], [-1, 3, -7, 20, 122, -11, 3, -1],
+  [-1, 2, -6, 18, 123, -10, 3, -1], [0, 2, -6, 15, 124, -9, 3, -1],
+  [0, 2, -5, 13, 125, -8, 2, -1], [0, 1, -4, 11, 125, -7, 2, 0],
+  [0, 1, -3, 8, 126, -6, 2, 0], [0, 1, -3, 6, 127, -4, 1, 0],
+  [0, 1, -2, 4, 127, -3, 1, 0], [0, 0, -1, 2, 128, -1, 0, 0],
+];
+
+// Segmentation
+// Each block belongs to one of up to MAX_SEGMENTS segments, and each segment can enable
+// any of SEG_LVL_MAX features, which adjust how its blocks are coded
+pub const MAX_SEGMENTS: usize = 8;
+pub const SEG_LVL_MAX: usize = 8;
+// Feature which adds an offset to the qindex
+pub const SEG_LVL_ALT_Q: usize = 0;
+
+// Number of bits used to code each feature's value, whether the value is signed,
+// and its maximum magnitude
+pub const segmentation_feature_bits: [usize; SEG_LVL_MAX] = [8, 6, 6, 6, 6, 3, 0, 0];
+pub const segmentation_feature_signed: [bool; SEG_LVL_MAX] = [true, true, true, true, true, false, false, false];
+pub const segmentation_feature_max: [i32; SEG_LVL_MAX] = [255, 63, 63, 63, 63, 7, 0, 0];
@@ -0,0 +1,78 @@
+// Copyright (c) 2024-2025, The tinyavif contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+// Debug images showing the encoder's decisions drawn over the source image, for
+// checking what the block-level decisions actually did when tuning them
+
+use std::cmp::min;
+
+use crate::enums::*;
+use crate::frame::Frame;
+use crate::stats::EncodeStats;
+
+// Colour for each luma prediction mode, in RGB
+// Related modes get related colours: the directional modes are shades of red through
+// yellow, the smooth modes are shades of blue, and DC and Paeth stand out from both
+const MODE_RGB: [[u8; 3]; INTRA_MODES] = [
+  [128, 128, 128], // DC_PRED
+  [255, 0, 0],     // V_PRED
+  [255, 128, 0],   // H_PRED
+  [255, 64, 128],  // D45_PRED
+  [192, 0, 64],    // D135_PRED
+  [255, 192, 0],   // D113_PRED
+  [192, 96, 0],    // D157_PRED
+  [255, 255, 0],   // D203_PRED
+  [255, 96, 96],   // D67_PRED
+  [0, 96, 255],    // SMOOTH_PRED
+  [0, 192, 255],   // SMOOTH_V_PRED
+  [96, 64, 255],   // SMOOTH_H_PRED
+  [0, 200, 0],     // PAETH_PRED
+];
+
+// Colours for the edges of blocks, and the (thicker) edges of superblocks
+const BLOCK_EDGE_RGB: [u8; 3] = [255, 255, 255];
+const SUPERBLOCK_EDGE_RGB: [u8; 3] = [0, 0, 0];
+
+// How much of the mode colour to mix into each block, with the rest being the source luma
+const MODE_WEIGHT: f64 = 0.5;
+
+// Render the partition grid and the luma prediction mode of each block over a greyscale
+// copy of the source. Each block is tinted with the colour of its mode and outlined
+// along its top and left edges, with superblock edges drawn two pixels wide.
+// Returns 3 bytes per pixel, at the crop size of the source.
+pub fn render_partition_overlay(source: &Frame, stats: &EncodeStats) -> Vec<u8> {
+  let width = source.y().crop_width();
+  let height = source.y().crop_height();
+  let src_y = source.y().pixels();
+  let mode_map = &stats.y_mode_map;
+  let size_map = &stats.block_size_map;
+
+  let mut rgb = vec![0u8; width * height * 3];
+  for i in 0..height {
+    for j in 0..width {
+      let mi_row = min(i / 4, mode_map.rows() - 1);
+      let mi_col = min(j / 4, mode_map.cols() - 1);
+      // Blocks are always aligned to a multiple of their size
+      let bsize = size_map[mi_row][mi_col] as usize;
+
+      let color = if i % 64 < 2 || j % 64 < 2 {
+        SUPERBLOCK_EDGE_RGB
+      } else if i % bsize == 0 || j % bsize == 0 {
+        BLOCK_EDGE_RGB
+      } else {
+        let mode = MODE_RGB[mode_map[mi_row][mi_col] as usize];
+        let grey = src_y[i][j] as f64;
+        mode.map(|c| (MODE_WEIGHT * c as f64 + (1.0 - MODE_WEIGHT) * grey).round() as u8)
+      };
+      rgb[(i * width + j) * 3 .. (i * width + j) * 3 + 3].copy_from_slice(&color);
+    }
+  }
+
+  return rgb;
+}
@@ -0,0 +1,1173 @@
+// Copyright (c) 2024-2025, The tinyavif contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+// Minimal AV1 decoder
+//
+// This only understands the subset of AV1 which this encoder produces, and is used to
+// check the encoder's output. It is deliberately written separately from the encoder,
+// following the structure of the decoding process in the spec: contexts are derived
+// from the spec's above/left context arrays rather than from the encoder's ModeInfo,
+// so that a mistake in the encoder's context logic shows up as a mismatch here.
+//
+// Reconstruction (prediction, dequantization, inverse transforms) is shared with the
+// encoder, as the bitstream syntax is the hard part to get right.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use bytemuck::Zeroable;
+
+use crate::array2d::Array2D;
+use crate::av1_encoder::get_qctx;
+use crate::bitcode::BitReader;
+use crate::cdf::*;
+use crate::consts::*;
+use crate::entropycode::EntropyReader;
+use crate::enums::*;
+use crate::frame::Frame;
+use crate::palette::*;
+use crate::recon::*;
+use crate::scaling::{superres_coded_width, superres_upscale};
+use crate::segmentation::*;
+use crate::util::*;
+
+#[derive(Debug)]
+pub enum DecodeError {
+  Io(io::Error),
+  // The bitstream uses a feature which this decoder doesn't implement
+  Unsupported(String),
+  // The bitstream is not valid AV1
+  Invalid(String),
+}
+
+impl fmt::Display for DecodeError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      DecodeError::Io(e) => write!(f, "{}", e),
+      DecodeError::Unsupported(what) => write!(f, "Unsupported bitstream feature: {}", what),
+      DecodeError::Invalid(reason) => write!(f, "Invalid bitstream: {}", reason),
+    }
+  }
+}
+
+impl Error for DecodeError {}
+
+impl From<io::Error> for DecodeError {
+  fn from(e: io::Error) -> Self {
+    DecodeError::Io(e)
+  }
+}
+
+// OBU types which we need to know about
+const OBU_SEQUENCE_HEADER: u8 = 1;
+const OBU_TEMPORAL_DELIMITER: u8 = 2;
+const OBU_FRAME_HEADER: u8 = 3;
+const OBU_TILE_GROUP: u8 = 4;
+const OBU_METADATA: u8 = 5;
+const OBU_FRAME: u8 = 6;
+const OBU_PADDING: u8 = 15;
+
+fn unsupported<T>(what: &str) -> Result<T, DecodeError> {
+  Err(DecodeError::Unsupported(what.into()))
+}
+
+fn invalid<T>(reason: &str) -> Result<T, DecodeError> {
+  Err(DecodeError::Invalid(reason.into()))
+}
+
+// The parts of the sequence header which we need
+pub struct SequenceHeader {
+  pub width: usize,
+  pub height: usize,
+  pub reduced_still_picture_header: bool,
+  pub enable_intra_edge_filter: bool,
+  pub enable_superres: bool,
+  pub seq_force_screen_content_tools: u8,
+  pub seq_force_integer_mv: u8,
+  pub order_hint_bits: usize,
+  // Timing info, as (time_scale, num_units_in_display_tick), if present
+  pub timing_info: Option<(u32, u32)>,
+  // Decoder model fields which affect the frame header
+  pub decoder_model_info_present: bool,
+  pub equal_picture_interval: bool,
+  pub buffer_removal_time_length: usize,
+  pub frame_presentation_time_length: usize,
+  // operating_point_idc of each operating point which has decoder model parameters
+  pub decoder_model_ops: Vec<u16>,
+}
+
+const SELECT_SCREEN_CONTENT_TOOLS: u8 = 2;
+const SELECT_INTEGER_MV: u8 = 2;
+
+// The parts of the frame header which we need
+pub struct FrameHeader {
+  // Coded width, which is less than the sequence header's width when superres is used
+  pub frame_width: usize,
+  pub base_qindex: u8,
+  // qindex offsets for the DC and AC coefficients of each plane
+  pub delta_q: [[i8; 2]; 3],
+  pub delta_q_present: bool,
+  pub segmentation: Option<SegmentationParams>,
+  pub allow_screen_content_tools: bool,
+  // Size the image should be displayed at, if different from its coded size
+  pub render_size: Option<(usize, usize)>,
+}
+
+// Decode a complete still image from a sequence of OBUs, as produced by hls::pack_obus()
+// The returned frame has the same padded size as the encoder's reconstruction.
+pub fn decode_obus(data: &[u8]) -> Result<Frame, DecodeError> {
+  let mut sequence_header = None;
+  let mut frame_header = None;
+  let mut result = None;
+
+  let mut pos = 0;
+  while pos < data.len() {
+    // OBU header
+    let mut r = BitReader::new(&data[pos..]);
+    if r.read_bit()? != 0 {
+      return invalid("OBU forbidden bit is set");
+    }
+    let obu_type = r.read_bits(4)? as u8;
+    let extension_flag = r.read_bool()?;
+    let has_size_field = r.read_bool()?;
+    r.read_bit()?; // Reserved
+    // The temporal and spatial IDs only matter for finding which operating points the
+    // frame header gives buffer removal times for
+    // Without an extension header, both are 0
+    let mut layer = (0, 0);
+    if extension_flag {
+      let temporal_id = r.read_bits(3)? as usize;
+      let spatial_id = r.read_bits(2)? as usize;
+      r.read_bits(3)?; // Reserved
+      layer = (temporal_id, spatial_id);
+    }
+    let obu_size = if has_size_field {
+      r.read_leb128()? as usize
+    } else {
+      data.len() - pos - r.byte_pos()
+    };
+
+    let start = pos + r.byte_pos();
+    let end = start + obu_size;
+    if end > data.len() {
+      return invalid("OBU extends past the end of the data");
+    }
+    let payload = &data[start..end];
+
+    match obu_type {
+      OBU_SEQUENCE_HEADER => {
+        sequence_header = Some(parse_sequence_header(payload)?);
+      },
+      OBU_FRAME_HEADER | OBU_FRAME => {
+        if frame_header.is_some() {
+          return unsupported("more than one frame");
+        }
+        let Some(seq) = &sequence_header else {
+          return invalid("frame header before sequence header");
+        };
+        let mut fr = BitReader::new(payload);
+        let header = parse_frame_header(&mut fr, seq, layer)?;
+        if obu_type == OBU_FRAME {
+          fr.byte_align();
+          let tile_data = &payload[fr.byte_pos()..];
+          result = Some(decode_tile(tile_data, seq, &header)?);
+        }
+        frame_header = Some(header);
+      },
+      OBU_TILE_GROUP => {
+        let (Some(seq), Some(header)) = (&sequence_header, &frame_header) else {
+          return invalid("tile group before frame header");
+        };
+        if result.is_some() {
+          return unsupported("more than one tile group");
+        }
+        // With only one tile, there is no tile group header
+        result = Some(decode_tile(payload, seq, header)?);
+      },
+      OBU_TEMPORAL_DELIMITER | OBU_METADATA | OBU_PADDING => {},
+      _ => {
+        return unsupported(&format!("OBU type {}", obu_type));
+      }
+    }
+
+    pos = end;
+  }
+
+  match result {
+    Some(frame) => Ok(frame),
+    None => invalid("no frame data found"),
+  }
+}
+
+pub fn parse_sequence_header(payload: &[u8]) -> Result<SequenceHeader, DecodeError> {
+  let mut r = BitReader::new(payload);
+
+  let seq_profile = r.read_bits(3)?;
+  if seq_profile != 0 {
+    return unsupported(&format!("profile {}", seq_profile));
+  }
+  let still_picture = r.read_bool()?;
+  let reduced_still_picture_header = r.read_bool()?;
+  if !still_picture {
+    return unsupported("sequences other than still pictures");
+  }
+
+  let mut timing_info = None;
+  let mut decoder_model_info_present = false;
+  let mut equal_picture_interval = false;
+  let mut buffer_delay_length = 0;
+  let mut buffer_removal_time_length = 0;
+  let mut frame_presentation_time_length = 0;
+  let mut decoder_model_ops = Vec::new();
+  if reduced_still_picture_header {
+    r.read_bits(5)?; // seq_level_idx
+  } else {
+    if r.read_bool()? {
+      // timing_info()
+      let num_units_in_display_tick = r.read_bits(32)? as u32;
+      let time_scale = r.read_bits(32)? as u32;
+      if num_units_in_display_tick == 0 || time_scale == 0 {
+        return invalid("zero in timing info");
+      }
+      timing_info = Some((time_scale, num_units_in_display_tick));
+      equal_picture_interval = r.read_bool()?;
+      if equal_picture_interval {
+        r.read_uvlc()?; // num_ticks_per_picture_minus_1
+      }
+      decoder_model_info_present = r.read_bool()?;
+      if decoder_model_info_present {
+        // decoder_model_info()
+        buffer_delay_length = r.read_bits(5)? as usize + 1;
+        r.read_bits(32)?; // num_units_in_decoding_tick
+        buffer_removal_time_length = r.read_bits(5)? as usize + 1;
+        frame_presentation_time_length = r.read_bits(5)? as usize + 1;
+      }
+    }
+    let initial_display_delay_present = r.read_bool()?;
+    let operating_points_cnt = r.read_bits(5)? + 1;
+    // As there is only one frame, every operating point decodes the same thing,
+    // so just skip over them, other than noting which ones the frame header
+    // gives buffer removal times for
+    for _ in 0..operating_points_cnt {
+      let operating_point_idc = r.read_bits(12)? as u16;
+      let seq_level_idx = r.read_bits(5)?;
+      if seq_level_idx > 7 {
+        r.read_bit()?; // seq_tier
+      }
+      if decoder_model_info_present && r.read_bool()? {
+        // operating_parameters_info()
+        r.read_bits(2 * buffer_delay_length)?; // decoder_buffer_delay, encoder_buffer_delay
+        r.read_bit()?; // low_delay_mode_flag
+        decoder_model_ops.push(operating_point_idc);
+      }
+      if initial_display_delay_present && r.read_bool()? {
+        r.read_bits(4)?; // initial_display_delay_minus_1
+      }
+    }
+  }
+
+  let frame_width_bits = r.read_bits(4)? as usize + 1;
+  let frame_height_bits = r.read_bits(4)? as usize + 1;
+  let width = r.read_bits(frame_width_bits)? as usize + 1;
+  let height = r.read_bits(frame_height_bits)? as usize + 1;
+
+  if !reduced_still_picture_header && r.read_bool()? {
+    return unsupported("frame IDs");
+  }
+
+  if r.read_bool()? {
+    return unsupported("128x128 superblocks");
+  }
+  if r.read_bool()? {
+    return unsupported("filter intra");
+  }
+  let enable_intra_edge_filter = r.read_bool()?;
+
+  let mut seq_force_screen_content_tools = SELECT_SCREEN_CONTENT_TOOLS;
+  let mut seq_force_integer_mv = SELECT_INTEGER_MV;
+  let mut order_hint_bits = 0;
+  if !reduced_still_picture_header {
+    // The inter-prediction tools don't matter for a key frame
+    r.read_bits(4)?; // enable_interintra_compound, masked_compound, warped_motion, dual_filter
+    let enable_order_hint = r.read_bool()?;
+    if enable_order_hint {
+      r.read_bits(2)?; // enable_jnt_comp, enable_ref_frame_mvs
+    }
+    if !r.read_bool()? {
+      // seq_choose_screen_content_tools = 0
+      seq_force_screen_content_tools = r.read_bits(1)? as u8;
+    }
+    if seq_force_screen_content_tools > 0 && !r.read_bool()? {
+      // seq_choose_integer_mv = 0
+      seq_force_integer_mv = r.read_bits(1)? as u8;
+    }
+    if enable_order_hint {
+      order_hint_bits = r.read_bits(3)? as usize + 1;
+    }
+  }
+  let enable_superres = r.read_bool()?;
+  if r.read_bool()? {
+    return unsupported("CDEF");
+  }
+  if r.read_bool()? {
+    return unsupported("loop restoration");
+  }
+
+  // color_config()
+  if r.read_bool()? {
+    return unsupported("high bit depth");
+  }
+  if r.read_bool()? {
+    return unsupported("monochrome");
+  }
+  if r.read_bool()? {
+    // Colour description; this is just metadata as far as decoding is concerned
+    let color_primaries = r.read_bits(8)?;
+    let transfer_characteristics = r.read_bits(8)?;
+    let matrix_coefficients = r.read_bits(8)?;
+    if color_primaries == 1 && transfer_characteristics == 13 && matrix_coefficients == 0 {
+      return unsupported("4:4:4 sRGB");
+    }
+  }
+  r.read_bit()?; // color_range
+  r.read_bits(2)?; // chroma_sample_position
+  if r.read_bool()? {
+    return unsupported("separate U and V delta-q values");
+  }
+
+  if r.read_bool()? {
+    return unsupported("film grain");
+  }
+
+  Ok(SequenceHeader {
+    width: width,
+    height: height,
+    reduced_still_picture_header: reduced_still_picture_header,
+    enable_intra_edge_filter: enable_intra_edge_filter,
+    enable_superres: enable_superres,
+    seq_force_screen_content_tools: seq_force_screen_content_tools,
+    seq_force_integer_mv: seq_force_integer_mv,
+    order_hint_bits: order_hint_bits,
+    timing_info: timing_info,
+    decoder_model_info_present: decoder_model_info_present,
+    equal_picture_interval: equal_picture_interval,
+    buffer_removal_time_length: buffer_removal_time_length,
+    frame_presentation_time_length: frame_presentation_time_length,
+    decoder_model_ops: decoder_model_ops,
+  })
+}
+
+// uncompressed_header(), for a shown key frame
+// layer gives the frame's temporal and spatial IDs, from its OBU extension header
+pub fn parse_frame_header(r: &mut BitReader, seq: &SequenceHeader, layer: (usize, usize)) -> Result<FrameHeader, DecodeError> {
+  if !seq.reduced_still_picture_header {
+    if r.read_bool()? {
+      return unsupported("show_existing_frame");
+    }
+    if r.read_bits(2)? != 0 {
+      return unsupported("frame types other than key frames");
+    }
+    if !r.read_bool()? {
+      return unsupported("hidden frames");
+    }
+    if seq.decoder_model_info_present && !seq.equal_picture_interval {
+      r.read_bits(seq.frame_presentation_time_length)?; // temporal_point_info()
+    }
+    // Shown key frames imply error_resilient_mode = 1
+  }
+
+  if !r.read_bool()? {
+    return unsupported("CDF updates");
+  }
+  let allow_screen_content_tools = if seq.seq_force_screen_content_tools == SELECT_SCREEN_CONTENT_TOOLS {
+    r.read_bool()?
+  } else {
+    seq.seq_force_screen_content_tools == 1
+  };
+  if allow_screen_content_tools && seq.seq_force_integer_mv == SELECT_INTEGER_MV {
+    r.read_bit()?; // force_integer_mv, which doesn't matter for intra frames
+  }
+
+  if !seq.reduced_still_picture_header {
+    if r.read_bool()? {
+      return unsupported("frame size override");
+    }
+    r.read_bits(seq.order_hint_bits)?; // order_hint
+    // primary_ref_frame is implied for key frames
+    if seq.decoder_model_info_present && r.read_bool()? {
+      for &operating_point_idc in &seq.decoder_model_ops {
+        let (temporal_id, spatial_id) = layer;
+        let in_temporal_layer = (operating_point_idc >> temporal_id) & 1 != 0;
+        let in_spatial_layer = (operating_point_idc >> (spatial_id + 8)) & 1 != 0;
+        if operating_point_idc == 0 || (in_temporal_layer && in_spatial_layer) {
+          r.read_bits(seq.buffer_removal_time_length)?; // buffer_removal_time
+        }
+      }
+    }
+    // refresh_frame_flags is implied for key frames
+  }
+
+  // frame_size() has nothing to read here, as the frame size can't be overridden
+  // superres_params()
+  let mut frame_width = seq.width;
+  if seq.enable_superres && r.read_bool()? {
+    let denominator = r.read_bits(SUPERRES_DENOM_BITS)? as usize + SUPERRES_DENOM_MIN;
+    frame_width = superres_coded_width(seq.width, denominator);
+  }
+  // The render size only affects display, not decoding
+  let render_size = if r.read_bool()? {
+    let render_width = r.read_bits(16)? as usize + 1;
+    let render_height = r.read_bits(16)? as usize + 1;
+    Some((render_width, render_height))
+  } else {
+    None
+  };
+  if allow_screen_content_tools && frame_width == seq.width && r.read_bool()? {
+    return unsupported("intra block copy");
+  }
+
+  // tile_info()
+  let mi_cols = 2 * frame_width.div_ceil(8);
+  let mi_rows = 2 * seq.height.div_ceil(8);
+  let sb_cols = mi_cols.div_ceil(16);
+  let sb_rows = mi_rows.div_ceil(16);
+  if !r.read_bool()? {
+    return unsupported("non-uniform tile spacing");
+  }
+  if sb_cols > 64 {
+    return unsupported("images wider than 64 superblocks");
+  }
+  if sb_cols > 1 && r.read_bool()? {
+    return unsupported("multiple tile columns");
+  }
+  if sb_rows > 1 && r.read_bool()? {
+    return unsupported("multiple tile rows");
+  }
+
+  // quantization_params()
+  let base_qindex = r.read_bits(8)? as u8;
+  // The sequence header has already rejected separate U and V delta-q values,
+  // so the V plane uses the same values as U
+  let mut read_delta_q = || -> Result<i8, DecodeError> {
+    if r.read_bool()? {
+      Ok(r.read_su(7)? as i8)
+    } else {
+      Ok(0)
+    }
+  };
+  let y_dc_delta = read_delta_q()?;
+  let u_dc_delta = read_delta_q()?;
+  let u_ac_delta = read_delta_q()?;
+  let delta_q = [[y_dc_delta, 0], [u_dc_delta, u_ac_delta], [u_dc_delta, u_ac_delta]];
+  if r.read_bool()? {
+    return unsupported("quantizer matrices");
+  }
+
+  // segmentation_params(). For key frames, the map and data are always updated
+  let segmentation = if r.read_bool()? {
+    let mut segmentation = SegmentationParams::new();
+    for i in 0..MAX_SEGMENTS {
+      for j in 0..SEG_LVL_MAX {
+        if r.read_bool()? {
+          let limit = segmentation_feature_max[j];
+          let value = if segmentation_feature_signed[j] {
+            clamp(r.read_su(1 + segmentation_feature_bits[j])? as i32, -limit, limit)
+          } else {
+            min(r.read_bits(segmentation_feature_bits[j])? as i32, limit)
+          };
+          segmentation.features[i][j] = Some(value);
+        }
+      }
+    }
+    for i in 0..MAX_SEGMENTS {
+      if (0..SEG_LVL_MAX).any(|j| j != SEG_LVL_ALT_Q && segmentation.features[i][j].is_some()) {
+        return unsupported("segmentation features other than alt-q");
+      }
+    }
+    Some(segmentation)
+  } else {
+    None
+  };
+
+  // delta_q_params() and delta_lf_params()
+  let mut delta_q_present = false;
+  if base_qindex > 0 {
+    delta_q_present = r.read_bool()?;
+  }
+  if delta_q_present {
+    if r.read_bits(2)? != 0 {
+      return unsupported("delta-q resolution other than 1");
+    }
+    if r.read_bool()? {
+      return unsupported("superblock-level delta-lf");
+    }
+  }
+  if base_qindex == 0 {
+    return unsupported("lossless mode");
+  }
+  // A segment whose qindex comes out as 0 is also lossless, unless delta-q values are used
+  if let Some(segmentation) = &segmentation {
+    let lossless = |i| segmentation.qindex(base_qindex, i) == 0 && delta_q.iter().flatten().all(|&d| d == 0);
+    if (0..MAX_SEGMENTS as u8).any(lossless) {
+      return unsupported("lossless segments");
+    }
+  }
+
+  // loop_filter_params()
+  let loop_filter_level_0 = r.read_bits(6)?;
+  let loop_filter_level_1 = r.read_bits(6)?;
+  if loop_filter_level_0 != 0 || loop_filter_level_1 != 0 {
+    return unsupported("deblocking");
+  }
+  r.read_bits(3)?; // loop_filter_sharpness
+  if r.read_bool()? {
+    return unsupported("loop filter deltas");
+  }
+
+  // cdef_params() and lr_params() are disabled in the sequence header
+
+  // read_tx_mode()
+  if r.read_bool()? {
+    return unsupported("per-block transform sizes");
+  }
+
+  if !r.read_bool()? {
+    return unsupported("full transform type set");
+  }
+
+  Ok(FrameHeader {
+    frame_width: frame_width,
+    base_qindex: base_qindex,
+    delta_q: delta_q,
+    delta_q_present: delta_q_present,
+    segmentation: segmentation,
+    allow_screen_content_tools: allow_screen_content_tools,
+    render_size: render_size,
+  })
+}
+
+fn decode_tile(tile_data: &[u8], seq: &SequenceHeader, header: &FrameHeader) -> Result<Frame, DecodeError> {
+  let y_width = header.frame_width.next_multiple_of(8);
+  let y_height = seq.height.next_multiple_of(8);
+  let mut decoder = TileDecoder::new(tile_data, y_width, y_height, header.base_qindex, header.delta_q,
+                                     header.delta_q_present, header.segmentation, header.allow_screen_content_tools,
+                                     seq.enable_intra_edge_filter);
+  decoder.decode()?;
+  let recon = decoder.into_recon();
+
+  // Copy into a frame with the correct crop size
+  let mut frame = Frame::new(seq.height, header.frame_width);
+  for plane in 0..3 {
+    let src = recon.plane(plane).pixels();
+    let dst = frame.plane_mut(plane).pixels_mut();
+    for row in 0..dst.rows() {
+      dst[row].copy_from_slice(&src[row]);
+    }
+  }
+  if header.frame_width != seq.width {
+    return Ok(superres_upscale(&frame, header.frame_width, seq.width));
+  }
+  Ok(frame)
+}
+
+// The quantized coefficients of one transform block, as read from the bitstream
+#[derive(Clone, PartialEq)]
+pub struct CoeffBlock {
+  pub plane: usize,
+  pub mi_row: usize,
+  pub mi_col: usize,
+  pub coeffs: Array2D<i32>,
+}
+
+pub struct TileDecoder<'a> {
+  bitstream: EntropyReader<'a>,
+
+  mi_rows: usize,
+  mi_cols: usize,
+
+  base_qindex: u8,
+  delta_q: [[i8; 2]; 3],
+  delta_q_present: bool,
+  segmentation: Option<SegmentationParams>,
+  current_qindex: u8,
+  read_deltas: bool,
+  allow_screen_content_tools: bool,
+  enable_intra_edge_filter: bool,
+
+  // log2 of the width of the block covering each 4x4 luma unit, in units of 4 pixels
+  // (the spec's Mi_Width_Log2[MiSizes[row][col]]). Used for partition contexts.
+  mi_width_log2: Array2D<u8>,
+
+  // Whether the block covering each 4x4 luma unit was skipped. Used for skip contexts.
+  skip: Array2D<bool>,
+
+  // Segment ID of the block covering each 4x4 luma unit, used to predict later segment IDs
+  segment_ids: Array2D<u8>,
+
+  // Luma and chroma palettes of the block covering each 4x4 luma unit
+  palettes: Array2D<[Palette; 2]>,
+
+  // Luma and chroma prediction modes of the block covering each 4x4 luma unit.
+  // Used to select the intra edge filter type.
+  modes: Array2D<[PredictionMode; 2]>,
+
+  // Per-plane context arrays, indexed in units of 4 pixels within each plane.
+  // The DC contexts use the spec's encoding: 0 = zero, 1 = negative, 2 = positive
+  above_level_context: [Vec<u8>; 3],
+  above_dc_context: [Vec<u8>; 3],
+  left_level_context: [Vec<u8>; 3],
+  left_dc_context: [Vec<u8>; 3],
+
+  recon: Frame,
+
+  // If enabled, a copy of every transform block's coefficients, in decoding order
+  coeff_log: Option<Vec<CoeffBlock>>,
+}
+
+impl<'a> TileDecoder<'a> {
+  // `y_width` and `y_height` are the padded frame size, as used by the encoder
+  pub fn new(tile_data: &'a [u8], y_width: usize, y_height: usize, base_qindex: u8,
+             delta_q: [[i8; 2]; 3], delta_q_present: bool, segmentation: Option<SegmentationParams>,
+             allow_screen_content_tools: bool,
+             enable_intra_edge_filter: bool) -> Self {
+    let mi_rows = y_height / 4;
+    let mi_cols = y_width / 4;
+
+    Self {
+      bitstream: EntropyReader::new(tile_data),
+      mi_rows: mi_rows,
+      mi_cols: mi_cols,
+      base_qindex: base_qindex,
+      delta_q: delta_q,
+      delta_q_present: delta_q_present,
+      segmentation: segmentation,
+      current_qindex: base_qindex,
+      read_deltas: false,
+      allow_screen_content_tools: allow_screen_content_tools,
+      enable_intra_edge_filter: enable_intra_edge_filter,
+      mi_width_log2: Array2D::zeroed(mi_rows, mi_cols),
+      skip: Array2D::zeroed(mi_rows, mi_cols),
+      segment_ids: Array2D::zeroed(mi_rows, mi_cols),
+      palettes: Array2D::zeroed(mi_rows, mi_cols),
+      modes: Array2D::zeroed(mi_rows, mi_cols),
+      above_level_context: [vec![0; mi_cols], vec![0; mi_cols], vec![0; mi_cols]],
+      above_dc_context: [vec![0; mi_cols], vec![0; mi_cols], vec![0; mi_cols]],
+      left_level_context: [vec![0; mi_rows], vec![0; mi_rows], vec![0; mi_rows]],
+      left_dc_context: [vec![0; mi_rows], vec![0; mi_rows], vec![0; mi_rows]],
+      recon: Frame::new(y_height, y_width),
+      coeff_log: None,
+    }
+  }
+
+  // Keep a copy of all of the coefficients decoded from now on, for comparison
+  // against what the encoder intended to code
+  pub fn log_coeffs(&mut self) {
+    self.coeff_log = Some(Vec::new());
+  }
+
+  pub fn take_coeff_log(&mut self) -> Vec<CoeffBlock> {
+    self.coeff_log.take().unwrap_or_default()
+  }
+
+  pub fn recon(&self) -> &Frame {
+    &self.recon
+  }
+
+  pub fn into_recon(self) -> Frame {
+    self.recon
+  }
+
+  pub fn sb_rows(&self) -> usize {
+    self.mi_rows.div_ceil(16)
+  }
+
+  pub fn sb_cols(&self) -> usize {
+    self.mi_cols.div_ceil(16)
+  }
+
+  // Decode the whole tile
+  pub fn decode(&mut self) -> Result<(), DecodeError> {
+    for sb_row in 0..self.sb_rows() {
+      for sb_col in 0..self.sb_cols() {
+        self.decode_superblock(sb_row, sb_col)?;
+      }
+    }
+    Ok(())
+  }
+
+  // Decode a single superblock. Superblocks must be decoded in raster order
+  pub fn decode_superblock(&mut self, sb_row: usize, sb_col: usize) -> Result<(), DecodeError> {
+    if sb_col == 0 {
+      // clear_left_context()
+      for plane in 0..3 {
+        self.left_level_context[plane].fill(0);
+        self.left_dc_context[plane].fill(0);
+      }
+    }
+    self.read_deltas = self.delta_q_present;
+    self.decode_partition(sb_row * 16, sb_col * 16, 4)
+  }
+
+  // `bsl` is the spec's Mi_Width_Log2[bSize], ie. log2(block size / 4)
+  fn decode_partition(&mut self, mi_row: usize, mi_col: usize, bsl: u8) -> Result<(), DecodeError> {
+    if mi_row >= self.mi_rows || mi_col >= self.mi_cols {
+      return Ok(());
+    }
+
+    let avail_u = mi_row > 0;
+    let avail_l = mi_col > 0;
+    let half_block_4x4 = (1usize << bsl) >> 1;
+    let has_rows = (mi_row + half_block_4x4) < self.mi_rows;
+    let has_cols = (mi_col + half_block_4x4) < self.mi_cols;
+
+    let above = avail_u && self.mi_width_log2[mi_row - 1][mi_col] < bsl;
+    let left = avail_l && self.mi_width_log2[mi_row][mi_col - 1] < bsl;
+    let ctx = 2 * (left as usize) + (above as usize);
+
+    let partition = if bsl == 1 {
+      if !(has_rows && has_cols) {
+        return Err(DecodeError::Invalid("8x8 partition crosses the frame edge".into()));
+      }
+      if ctx != 0 {
+        return Err(DecodeError::Unsupported(format!("8x8 partition context {}", ctx)));
+      }
+      self.bitstream.read_symbol(&partition_8x8_cdf[0])
+    } else {
+      let cdf: &[u16] = match bsl {
+        2 => &partition_16x16_cdf[ctx],
+        3 => &partition_32x32_cdf[ctx],
+        4 => &partition_64x64_cdf[ctx],
+        _ => unreachable!()
+      };
+
+      if has_rows && has_cols {
+        self.bitstream.read_symbol(cdf)
+      } else if has_cols {
+        // split_or_horz
+        let psum = get_prob(Partition::VERT as usize, cdf) +
+                   get_prob(Partition::SPLIT as usize, cdf) +
+                   get_prob(Partition::HORZ_A as usize, cdf) +
+                   get_prob(Partition::VERT_A as usize, cdf) +
+                   get_prob(Partition::VERT_B as usize, cdf) +
+                   get_prob(Partition::VERT_4 as usize, cdf);
+        if self.bitstream.read_bool(32768 - psum) { Partition::SPLIT as usize } else { Partition::HORZ as usize }
+      } else if has_rows {
+        // split_or_vert
+        let psum = get_prob(Partition::HORZ as usize, cdf) +
+                   get_prob(Partition::SPLIT as usize, cdf) +
+                   get_prob(Partition::HORZ_A as usize, cdf) +
+                   get_prob(Partition::HORZ_B as usize, cdf) +
+                   get_prob(Partition::VERT_A as usize, cdf) +
+                   get_prob(Partition::HORZ_4 as usize, cdf);
+        if self.bitstream.read_bool(32768 - psum) { Partition::SPLIT as usize } else { Partition::VERT as usize }
+      } else {
+        Partition::SPLIT as usize
+      }
+    };
+
+    if partition == Partition::SPLIT as usize {
+      if bsl == 1 {
+        return Err(DecodeError::Unsupported("blocks smaller than 8x8".into()));
+      }
+      for i in 0..2 {
+        for j in 0..2 {
+          self.decode_partition(mi_row + i * half_block_4x4, mi_col + j * half_block_4x4, bsl - 1)?;
+        }
+      }
+      Ok(())
+    } else if partition == Partition::NONE as usize {
+      if bsl != 1 {
+        return Err(DecodeError::Unsupported(format!("{}x{} blocks", 4 << bsl, 4 << bsl)));
+      }
+      self.decode_block(mi_row, mi_col)
+    } else {
+      Err(DecodeError::Unsupported(format!("partition type {}", partition)))
+    }
+  }
+
+  // Decode one 8x8 block
+  fn decode_block(&mut self, mi_row: usize, mi_col: usize) -> Result<(), DecodeError> {
+    // intra_frame_mode_info()
+    let mut skip_ctx = 0;
+    if mi_row > 0 && self.skip[mi_row - 1][mi_col] {
+      skip_ctx += 1;
+    }
+    if mi_col > 0 && self.skip[mi_row][mi_col - 1] {
+      skip_ctx += 1;
+    }
+    let skip = self.bitstream.read_symbol(&skip_cdf[skip_ctx]) != 0;
+
+    // intra_segment_id(), which comes after the skip flag as long as only the alt-q
+    // feature is used
+    let segment_id = match &self.segmentation {
+      Some(segmentation) => self.read_segment_id(mi_row, mi_col, skip, segmentation.last_active_segment()),
+      None => 0,
+    };
+
+    if self.read_deltas {
+      self.read_delta_qindex();
+      self.read_deltas = false;
+    }
+
+    // Similarly, the y mode context depends on modes which must be DC_PRED
+    let y_mode = self.bitstream.read_symbol(&y_mode_cdf[0][0]);
+    if y_mode != PredictionMode::DC_PRED as usize {
+      return Err(DecodeError::Unsupported(format!("luma prediction mode {:?}", ALL_PREDICTION_MODES[y_mode])));
+    }
+    let y_mode = ALL_PREDICTION_MODES[y_mode];
+    let uv_mode = ALL_PREDICTION_MODES[self.bitstream.read_symbol(&uv_mode_cfl_allowed_cdf[y_mode as usize])];
+    let supported = matches!(uv_mode, PredictionMode::DC_PRED | PredictionMode::SMOOTH_PRED | PredictionMode::PAETH_PRED);
+    if !supported && !is_directional_mode(uv_mode) {
+      return Err(DecodeError::Unsupported(format!("chroma prediction mode {:?}", uv_mode)));
+    }
+    let mut angle_delta_uv = 0;
+    if is_directional_mode(uv_mode) {
+      let cdf = &angle_delta_cdf[uv_mode as usize - PredictionMode::V_PRED as usize];
+      angle_delta_uv = self.bitstream.read_symbol(cdf) as i8 - MAX_ANGLE_DELTA;
+    }
+
+    let mut palettes = [Palette::zeroed(); 2];
+    if self.allow_screen_content_tools {
+      palettes = self.read_palette_mode_info(mi_row, mi_col, uv_mode);
+    }
+
+    // palette_tokens()
+    let mut color_maps = [None, None];
+    for plane_type in 0..2 {
+      let n = palettes[plane_type].size;
+      if n > 0 {
+        let size = 8 >> plane_type;
+        color_maps[plane_type] = Some(read_color_map(&mut self.bitstream, n, plane_type, size, size));
+      }
+    }
+
+    self.mi_width_log2.fill_region(mi_row, mi_col, 2, 2, &1);
+    self.skip.fill_region(mi_row, mi_col, 2, 2, &skip);
+    self.segment_ids.fill_region(mi_row, mi_col, 2, 2, &segment_id);
+    self.palettes.fill_region(mi_row, mi_col, 2, 2, &palettes);
+    self.modes.fill_region(mi_row, mi_col, 2, 2, &[y_mode, uv_mode]);
+
+    // residual()
+    let qindex = match &self.segmentation {
+      Some(segmentation) => segmentation.qindex(self.current_qindex, segment_id),
+      None => self.current_qindex,
+    };
+    for plane in 0..3 {
+      let subsampling = if plane > 0 { 1 } else { 0 };
+      let y0 = (mi_row * 4) >> subsampling;
+      let x0 = (mi_col * 4) >> subsampling;
+      let txsize = 8 >> subsampling;
+
+      let plane_type = if plane > 0 { 1 } else { 0 };
+      if let Some(map) = &color_maps[plane_type] {
+        let colors = &palettes[plane_type].colors[if plane == 2 { 1 } else { 0 }];
+        predict_palette(self.recon.plane_mut(plane).pixels_mut().slice_mut(y0, x0, txsize, txsize), colors, map);
+      } else {
+        let (mode, angle_delta) = if plane > 0 { (uv_mode, angle_delta_uv) } else { (y_mode, 0) };
+        let neighbours = self.intra_neighbours(mi_row, mi_col, plane_type);
+        intra_predict(self.recon.plane_mut(plane).pixels_mut(), &neighbours, mode, angle_delta,
+                      y0, x0, txsize, txsize);
+      }
+      // The chroma transform type follows from uv_mode, while luma signals its own
+      // whenever it has a residual
+      let (mut coeffs, tx_type) = if skip {
+        // No residual is coded, and the contexts are reset as for an all-zero block
+        self.set_contexts(plane, y0 / 4, x0 / 4, txsize / 4, 0, 0);
+        (Array2D::zeroed(txsize, txsize), TxType::DCT_DCT)
+      } else {
+        self.read_coeffs(plane, y0 / 4, x0 / 4, txsize, uv_tx_type(uv_mode))?
+      };
+      if let Some(log) = &mut self.coeff_log {
+        log.push(CoeffBlock {
+          plane: plane,
+          mi_row: mi_row,
+          mi_col: mi_col,
+          coeffs: coeffs.clone(),
+        });
+      }
+      if !skip {
+        let [dc_delta, ac_delta] = self.delta_q[plane];
+        // High bit depth streams are rejected when parsing the sequence header
+        dequantize(&mut coeffs, &Quantizer::new(qindex, dc_delta, ac_delta, 8));
+        apply_residual(self.recon.plane_mut(plane).pixels_mut().slice_mut(y0, x0, txsize, txsize), &mut coeffs, tx_type);
+      }
+    }
+
+    Ok(())
+  }
+
+  // palette_mode_info(), for an 8x8 block using DC_PRED for luma
+  // The chroma palette is only signalled if chroma also uses DC_PRED
+  fn read_palette_mode_info(&mut self, mi_row: usize, mi_col: usize, uv_mode: PredictionMode) -> [Palette; 2] {
+    let mut palettes = [Palette::zeroed(); 2];
+
+    let mut ctx = 0;
+    if mi_row > 0 && self.palettes[mi_row - 1][mi_col][0].size > 0 {
+      ctx += 1;
+    }
+    if mi_col > 0 && self.palettes[mi_row][mi_col - 1][0].size > 0 {
+      ctx += 1;
+    }
+    if self.bitstream.read_symbol(&palette_y_mode_cdf[0][ctx]) != 0 {
+      let size = self.bitstream.read_symbol(&palette_y_size_cdf[0]) + PALETTE_MIN_SIZE;
+      let cache = self.get_palette_cache(mi_row, mi_col, 0);
+      palettes[0] = read_palette_colors(&mut self.bitstream, size, &cache, 0);
+    }
+
+    let ctx = (palettes[0].size > 0) as usize;
+    if uv_mode == PredictionMode::DC_PRED && self.bitstream.read_symbol(&palette_uv_mode_cdf[ctx]) != 0 {
+      let size = self.bitstream.read_symbol(&palette_uv_size_cdf[0]) + PALETTE_MIN_SIZE;
+      let cache = self.get_palette_cache(mi_row, mi_col, 1);
+      palettes[1] = read_palette_colors(&mut self.bitstream, size, &cache, 1);
+    }
+
+    return palettes;
+  }
+
+  // The availability of the blocks around the current one, and whether they use SMOOTH modes
+  fn intra_neighbours(&self, mi_row: usize, mi_col: usize, plane_type: usize) -> IntraNeighbours {
+    let (have_above_right, have_below_left) = intra_neighbour_availability(mi_row / 2, mi_col / 2,
+                                                                           self.mi_rows / 2, self.mi_cols / 2);
+    let smooth_neighbour = (mi_row > 0 && is_smooth_mode(self.modes[mi_row - 1][mi_col][plane_type])) ||
+                           (mi_col > 0 && is_smooth_mode(self.modes[mi_row][mi_col - 1][plane_type]));
+    IntraNeighbours {
+      have_above_right: have_above_right,
+      have_below_left: have_below_left,
+      smooth_neighbour: smooth_neighbour,
+      edge_filter: self.enable_intra_edge_filter
+    }
+  }
+
+  fn get_palette_cache(&self, mi_row: usize, mi_col: usize, plane_type: usize) -> Vec<u8> {
+    let above = if (mi_row * 4) % 64 != 0 { Some(&self.palettes[mi_row - 1][mi_col][plane_type]) } else { None };
+    let left = if mi_col > 0 { Some(&self.palettes[mi_row][mi_col - 1][plane_type]) } else { None };
+    palette_cache(above, left)
+  }
+
+  // read_segment_id(). Skipped blocks take the predicted segment ID
+  fn read_segment_id(&mut self, mi_row: usize, mi_col: usize, skip: bool, last_active_segment: u8) -> u8 {
+    let above = if mi_row > 0 { Some(self.segment_ids[mi_row - 1][mi_col]) } else { None };
+    let left = if mi_col > 0 { Some(self.segment_ids[mi_row][mi_col - 1]) } else { None };
+    let above_left = if mi_row > 0 && mi_col > 0 { Some(self.segment_ids[mi_row - 1][mi_col - 1]) } else { None };
+    let (prediction, ctx) = predict_segment_id(above_left, above, left);
+    if skip {
+      return prediction;
+    }
+    let code = self.bitstream.read_symbol(&segment_id_cdf[ctx]);
+    let segment_id = neg_deinterleave(code, prediction as usize, last_active_segment as usize + 1);
+    return clamp(segment_id, 0, last_active_segment as isize) as u8;
+  }
+
+  fn read_delta_qindex(&mut self) {
+    let mut delta_q_abs = self.bitstream.read_symbol(&delta_q_abs_cdf) as u32;
+    if delta_q_abs == 3 {
+      let rem_bits = self.bitstream.read_literal(3) + 1;
+      let abs_bits = self.bitstream.read_literal(rem_bits);
+      delta_q_abs = abs_bits + (1 << rem_bits) + 1;
+    }
+    if delta_q_abs > 0 {
+      let sign = self.bitstream.read_literal(1);
+      let reduced_delta_qindex = if sign != 0 { -(delta_q_abs as i32) } else { delta_q_abs as i32 };
+      // delta_q_res is always 0 in our streams
+      self.current_qindex = clamp(self.current_qindex as i32 + reduced_delta_qindex, 1, 255) as u8;
+    }
+  }
+
+  // coeffs(), for a single square transform block using DCT_DCT or IDTX (both of which use
+  // the default scan), returning the coefficients and the transform type
+  // x4 and y4 are the position of the transform block within the plane, in units of 4 pixels
+  // Chroma blocks use `uv_tx_type`, while luma blocks signal their transform type
+  fn read_coeffs(&mut self, plane: usize, y4: usize, x4: usize, txsize: usize,
+                 uv_tx_type: TxType) -> Result<(Array2D<i32>, TxType), DecodeError> {
+    let txs_ctx = floor_log2(txsize) as usize - 2;
+    if txs_ctx >= SUPPORTED_TX_SIZES {
+      return Err(DecodeError::Unsupported(format!("{}x{} transforms", txsize, txsize)));
+    }
+    let w4 = txsize / 4;
+    let num_coeffs = txsize * txsize;
+    let ptype = if plane == 0 { 0 } else { 1 };
+    let qctx = get_qctx(self.base_qindex);
+    let scan = default_scan(txsize, txsize);
+
+    let mut quant = Array2D::<i32>::zeroed(txsize, txsize);
+
+    // all_zero context
+    let all_zero_ctx = if plane == 0 {
+      // Transform size == block size
+      0
+    } else {
+      let mut above = 0;
+      let mut left = 0;
+      for k in 0..w4 {
+        above |= self.above_level_context[plane][x4 + k] | self.above_dc_context[plane][x4 + k];
+        left |= self.left_level_context[plane][y4 + k] | self.left_dc_context[plane][y4 + k];
+      }
+      7 + ((above != 0) as usize) + ((left != 0) as usize)
+    };
+
+    let all_zero = self.bitstream.read_symbol(&all_zero_cdf[qctx][txs_ctx][all_zero_ctx]);
+    if all_zero != 0 {
+      self.set_contexts(plane, y4, x4, w4, 0, 0);
+      return Ok((quant, if plane > 0 { uv_tx_type } else { TxType::DCT_DCT }));
+    }
+
+    let tx_type = if plane > 0 {
+      uv_tx_type
+    } else {
+      let index = self.bitstream.read_symbol(&intra_tx_type_set2_cdf[txs_ctx][PredictionMode::DC_PRED as usize]);
+      match TX_SET_INTRA_2[index] {
+        tx_type @ (TxType::DCT_DCT | TxType::IDTX) => tx_type,
+        tx_type => return Err(DecodeError::Unsupported(format!("luma transform type {:?}", tx_type))),
+      }
+    };
+
+    // End of block position
+    let eob_pt = 1 + if num_coeffs == 64 {
+      self.bitstream.read_symbol(&eob_class_64_cdf[qctx][ptype][0])
+    } else {
+      self.bitstream.read_symbol(&eob_class_16_cdf[qctx][ptype][0])
+    };
+    let mut eob = if eob_pt < 2 { eob_pt } else { (1 << (eob_pt - 2)) + 1 };
+    if eob_pt >= 3 {
+      let eob_shift = eob_pt - 3;
+      if self.bitstream.read_symbol(&eob_extra_cdf[qctx][txs_ctx][ptype][eob_pt - 3]) != 0 {
+        eob += 1 << eob_shift;
+      }
+      for i in 1..(eob_pt - 2) {
+        let eob_shift = eob_pt - 2 - 1 - i;
+        if self.bitstream.read_literal(1) != 0 {
+          eob += 1 << eob_shift;
+        }
+      }
+    }
+    if eob > num_coeffs {
+      return Err(DecodeError::Invalid(format!("EOB {} is too large for a {}x{} transform", eob, txsize, txsize)));
+    }
+
+    // Base levels and ranges, in reverse scan order
+    for c in (0..eob).rev() {
+      let (row, col) = scan[c];
+      let (row, col) = (row as usize, col as usize);
+
+      let mut level = if c == eob - 1 {
+        let ctx = if c == 0 {
+          0
+        } else if c <= num_coeffs / 8 {
+          1
+        } else if c <= num_coeffs / 4 {
+          2
+        } else {
+          3
+        };
+        self.bitstream.read_symbol(&coeff_base_eob_cdf[qctx][txs_ctx][ptype][ctx]) as i32 + 1
+      } else {
+        let ctx = if c == 0 {
+          0
+        } else {
+          let mut mag = 0;
+          for (row_off, col_off) in [(0, 1), (1, 0), (1, 1), (0, 2), (2, 0)] {
+            let ref_row = row + row_off;
+            let ref_col = col + col_off;
+            if ref_row < txsize && ref_col < txsize {
+              mag += min(quant[ref_row][ref_col], 3);
+            }
+          }
+          let offset = Coeff_Base_Ctx_Offset_8x8[min(row, 4)][min(col, 4)] as usize;
+          min((mag + 1) >> 1, 4) as usize + offset
+        };
+        self.bitstream.read_symbol(&coeff_base_cdf[qctx][txs_ctx][ptype][ctx]) as i32
+      };
+
+      if level > 2 {
+        let mut mag = 0;
+        for (row_off, col_off) in [(0, 1), (1, 0), (1, 1)] {
+          let ref_row = row + row_off;
+          let ref_col = col + col_off;
+          if ref_row < txsize && ref_col < txsize {
+            mag += min(quant[ref_row][ref_col], 15);
+          }
+        }
+        let mag = min((mag + 1) >> 1, 6) as usize;
+        let ctx = if c == 0 {
+          mag
+        } else if row < 2 && col < 2 {
+          mag + 7
+        } else {
+          mag + 14
+        };
+
+        for _ in 0..4 {
+          let coeff_br = self.bitstream.read_symbol(&coeff_br_cdf[qctx][txs_ctx][ptype][ctx]) as i32;
+          level += coeff_br;
+          if coeff_br < 3 {
+            break;
+          }
+        }
+      }
+
+      quant[row][col] = level;
+    }
+
+    // Signs and Golomb-coded remainders, in forward scan order
+    let mut cul_level = 0u32;
+    let mut dc_category = 0;
+    for c in 0..eob {
+      let (row, col) = scan[c];
+      let (row, col) = (row as usize, col as usize);
+      let mut level = quant[row][col];
+
+      let sign = if level == 0 {
+        0
+      } else if c == 0 {
+        let mut dc_sign = 0i32;
+        for k in 0..w4 {
+          match self.above_dc_context[plane][x4 + k] {
+            1 => dc_sign -= 1,
+            2 => dc_sign += 1,
+            _ => {}
+          }
+          match self.left_dc_context[plane][y4 + k] {
+            1 => dc_sign -= 1,
+            2 => dc_sign += 1,
+            _ => {}
+          }
+        }
+        let ctx = if dc_sign < 0 { 1 } else if dc_sign > 0 { 2 } else { 0 };
+        self.bitstream.read_symbol(&dc_sign_cdf[qctx][ptype][ctx])
+      } else {
+        self.bitstream.read_literal(1) as usize
+      };
+
+      if level > 14 {
+        let golomb = self.bitstream.read_golomb();
+        if golomb > (1 << 20) {
+          return Err(DecodeError::Invalid(format!("coefficient Golomb remainder {} is too large", golomb)));
+        }
+        level = golomb as i32 + 15;
+      }
+
+      cul_level += level as u32;
+      if c == 0 && level != 0 {
+        dc_category = if sign != 0 { 1 } else { 2 };
+      }
+      quant[row][col] = if sign != 0 { -level } else { level };
+    }
+
+    self.set_contexts(plane, y4, x4, w4, min(cul_level, 63) as u8, dc_category);
+    Ok((quant, tx_type))
+  }
+
+  fn set_contexts(&mut self, plane: usize, y4: usize, x4: usize, w4: usize, level: u8, dc_category: u8) {
+    self.above_level_context[plane][x4 .. x4 + w4].fill(level);
+    self.above_dc_context[plane][x4 .. x4 + w4].fill(dc_category);
+    self.left_level_context[plane][y4 .. y4 + w4].fill(level);
+    self.left_dc_context[plane][y4 .. y4 + w4].fill(dc_category);
+  }
+}
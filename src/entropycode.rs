@@ -7,8 +7,123 @@
 // Media Patent License 1.0 was not distributed with this source code in the
 // PATENTS file, you can obtain it at www.aomedia.org/license/patent.
 
+use crate::ratecost::{log2_fixed, COST_SHIFT};
+use crate::stats::{BitCategory, BIT_CATEGORIES};
 use crate::util::*;
 
+// Common interface for things which consume entropy-coded symbols
+// This is implemented by EntropyWriter, which actually codes them, and by
+// ratecost::RateCounter, which just estimates how many bits they would take.
+// Syntax-writing code can be made generic over this trait, so that the
+// same logic can be used both for encoding and for rate estimation.
+pub trait SymbolWriter {
+  // Write an entropy-coded symbol using the given CDF
+  // See EntropyWriter::write_symbol() for details of the CDF format
+  fn write_symbol(&mut self, symbol: usize, cdf: &[u16]);
+
+  // Write an entropy-coded symbol using an adaptive CDF, then update the CDF as the decoder
+  // will. See update_cdf() for the format of adaptive CDFs.
+  // This is only valid in frames which have CDF updates enabled; elsewhere, the CDFs must
+  // stay fixed and write_symbol() must be used instead
+  fn write_symbol_with_update(&mut self, symbol: usize, cdf: &mut [u16]) {
+    self.write_symbol(symbol, &cdf[..cdf.len() - 1]);
+    update_cdf(symbol, cdf);
+  }
+
+  // Name the syntax element (and its context) which the following symbols belong to
+  // This is only used for the syntax trace, so by default it does nothing
+  fn trace_label(&mut self, _name: &'static str, _ctx: usize) {}
+
+  // Helper function: Write a single bit symbol, without needing extra syntax fluff to convert
+  // from a single probability to a CDF
+  // Note that, due to the way CDFs are encoded, the specified probability is the probability
+  // of this bit being zero
+  fn write_bit(&mut self, value: usize, p_zero: u16) {
+    assert!(value == 0 || value == 1);
+    self.write_symbol(value, &[p_zero]);
+  }
+
+  // Helper function: Write a flag which is logically a boolean
+  // This is just syntactic sugar over self.write_bit(), mapping false => 0 and true => 1
+  fn write_bool(&mut self, value: bool, p_false: u16) {
+    self.write_symbol(value as usize, &[p_false]);
+  }
+
+  // Write an N-bit literal value. This means N bits, which are encoded
+  // in high-to-low order with each bit having a 50:50 probability distribution
+  fn write_literal(&mut self, value: u32, nbits: u32) {
+    assert!(nbits <= 32);
+    assert!(nbits == 32 || value < (1 << nbits));
+    for shift in (0..nbits).rev() {
+      let bit = (value >> shift) & 1;
+      self.write_bit(bit as usize, 16384);
+    }
+  }
+
+  // Encode a given value using a Golomb code
+  fn write_golomb(&mut self, mut value: u32) {
+    //println!("  Golomb({})", value);
+    // Because the "standard" Golomb code cannot represent 0, we actually Golomb-code `value + 1`
+    value += 1;
+
+    let length = floor_log2(value);
+    // Write `length` zero bits, then the full value, including the leading 1 bit
+    // (which acts as a delimiter, allowing the decoder to figure out the correct length)
+    self.write_literal(0, length);
+    self.write_literal(value, length + 1);
+  }
+
+  // Encode a value in the range [0, n) using the spec's NS(n) code, which uses
+  // one bit fewer for the smallest values when n isn't a power of 2
+  fn write_ns(&mut self, value: u32, n: u32) {
+    assert!(value < n);
+    let w = floor_log2(n) + 1;
+    let m = (1 << w) - n;
+    if value < m {
+      self.write_literal(value, w - 1);
+    } else {
+      let v = value + m;
+      self.write_literal(v >> 1, w - 1);
+      self.write_literal(v & 1, 1);
+    }
+  }
+}
+
+// Shift used when moving an adaptive CDF towards the symbol just coded, for a CDF with
+// `num_symbols` symbols which has been used `count` times.
+// CDFs adapt quickly at first, while they have seen few symbols, then more slowly, and
+// CDFs with more symbols adapt more slowly than ones with fewer.
+pub fn cdf_adaptation_rate(num_symbols: usize, count: u16) -> u32 {
+  return 3 + (count > 15) as u32 + (count > 31) as u32 + min(floor_log2(num_symbols), 2);
+}
+
+// Update an adaptive CDF after coding `symbol` with it, following the spec's symbol
+// decoding process (section 8.2.6).
+// An adaptive CDF has one more entry than a fixed one: after the cumulative probabilities,
+// in the same format as for write_symbol(), comes a count of the symbols coded with it so
+// far. This starts at 0 and stops at 32, and sets the adaptation rate. So an adaptive CDF
+// can be made from one of the default CDFs by copying it and appending a 0.
+pub fn update_cdf(symbol: usize, cdf: &mut [u16]) {
+  let num_symbols = cdf.len();
+  let count = cdf[num_symbols - 1];
+  let rate = cdf_adaptation_rate(num_symbols, count);
+
+  // Each cumulative probability moves towards 0 if it's below the symbol just coded, or
+  // towards 32768 if it's at or above it
+  for i in 0..(num_symbols - 1) {
+    if i < symbol {
+      cdf[i] -= cdf[i] >> rate;
+    } else {
+      cdf[i] += (32768 - cdf[i]) >> rate;
+    }
+  }
+
+  if count < 32 {
+    cdf[num_symbols - 1] = count + 1;
+  }
+}
+
+#[derive(Clone)]
 pub struct EntropyWriter {
   // We need to be able to modify already-written bytes for carry propagation,
   // so we have to write into a Vec<u8> rather than a generic Write instance
@@ -16,7 +131,19 @@ pub struct EntropyWriter {
 
   low: u64,
   range: u32,
-  count: i32
+  count: i32,
+
+  // Bit accounting: the category of the symbols currently being written, the value of
+  // tell_frac() when it was selected, and the bits spent on each category before that
+  category: BitCategory,
+  category_start: u64,
+  bit_counts: [u64; BIT_CATEGORIES],
+
+  // Syntax trace, if enabled: the text so far, the syntax element and context named
+  // by the last trace_label() call, and the number of symbols written
+  trace: Option<String>,
+  trace_label: (&'static str, usize),
+  trace_symbols: u64,
 }
 
 impl EntropyWriter {
@@ -25,10 +152,75 @@ impl EntropyWriter {
       data: Vec::new(),
       low: 0u64,
       range: 0x8000u32,
-      count: -9i32
+      count: -9i32,
+      category: BitCategory::Partition,
+      category_start: 0,
+      bit_counts: [0; BIT_CATEGORIES],
+      trace: None,
+      trace_label: ("", 0),
+      trace_symbols: 0,
     }
   }
 
+  // Start logging every symbol written, similar to libaom's bitstream debug output
+  // Each line gives the symbol index, syntax element, context, value, alphabet size,
+  // and cost in bits
+  pub fn enable_trace(&mut self) {
+    self.trace = Some(String::new());
+  }
+
+  pub fn is_tracing(&self) -> bool {
+    return self.trace.is_some();
+  }
+
+  // Add a free-form line to the trace, eg. to mark the start of each block
+  pub fn trace_note(&mut self, note: &str) {
+    if let Some(trace) = &mut self.trace {
+      trace.push_str(note);
+      trace.push('\n');
+    }
+  }
+
+  // Take the trace written so far, if enabled
+  pub fn take_trace(&mut self) -> Option<String> {
+    return self.trace.as_mut().map(std::mem::take);
+  }
+
+  fn trace_symbol(&mut self, symbol: usize, num_symbols: usize, start: u64) {
+    let cost = (self.tell_frac() - start) as f64 / (1 << COST_SHIFT) as f64;
+    let (name, ctx) = self.trace_label;
+    if let Some(trace) = &mut self.trace {
+      trace.push_str(&format!("{:8} {:<24} ctx {:3} value {:3} of {:3} cost {:7.3}\n",
+                              self.trace_symbols, name, ctx, symbol, num_symbols, cost));
+    }
+    self.trace_symbols += 1;
+  }
+
+  // Number of bits written so far, in units of 1/(1 << COST_SHIFT) bits, including
+  // the fractional bits implied by the current range. This matches libaom's
+  // od_ec_enc_tell_frac(), except for the resolution.
+  pub fn tell_frac(&self) -> u64 {
+    let whole_bits = (8 * self.data.len() as i64 + self.count as i64 + 10) as u64;
+    // The range is always in [32768, 65536), so this is log2(range) - 15
+    let range_frac = log2_fixed(self.range) - (15 << COST_SHIFT);
+    return (whole_bits << COST_SHIFT) - range_frac as u64;
+  }
+
+  // Attribute the symbols written from now on to the given category
+  pub fn set_category(&mut self, category: BitCategory) {
+    let now = self.tell_frac();
+    self.bit_counts[self.category as usize] += now - self.category_start;
+    self.category = category;
+    self.category_start = now;
+  }
+
+  // Total bits spent on each category so far, in units of 1/(1 << COST_SHIFT) bits
+  pub fn bit_counts(&self) -> [u64; BIT_CATEGORIES] {
+    let mut counts = self.bit_counts;
+    counts[self.category as usize] += self.tell_frac() - self.category_start;
+    return counts;
+  }
+
   // Sometimes we need to propagate a carry into the existing bytes
   // This function handles the core loop of that operation
   // Note: This assumes that the incoming carry is always 1, as it should
@@ -51,7 +243,7 @@ impl EntropyWriter {
   }
 
   // Write an entropy-coded symbol using the given CDF
-  // This does not yet implement CDF adaptation, so that must be turned off in the sequence header
+  // This leaves the CDF unchanged; see write_symbol_with_update() for coding with adaptive CDFs
   //
   // Note: Each CDF contains two implicit values:
   // * cdf[-1] = 0, so that when symbol == 0 "lo" is implicitly 0
@@ -62,6 +254,7 @@ impl EntropyWriter {
   pub fn write_symbol(&mut self, symbol: usize, cdf: &[u16]) {
     //println!("  Symbol({}, CDF = {:?})", symbol, cdf);
     let num_symbols = cdf.len() + 1;
+    let trace_start = if self.trace.is_some() { Some(self.tell_frac()) } else { None };
     let inv_hi = if symbol == num_symbols - 1 { 0 } else { 32768 - (cdf[symbol] as u32) };
 
     // Update range to include new symbol
@@ -104,47 +297,12 @@ impl EntropyWriter {
     self.low <<= d;
     self.range <<= d;
     self.count = s;
-  }
-
-  // Helper function: Write a single bit symbol, without needing extra syntax fluff to convert
-  // from a single probability to a CDF
-  // Note that, due to the way CDFs are encoded, the specified probability is the probability
-  // of this bit being zero
-  pub fn write_bit(&mut self, value: usize, p_zero: u16) {
-    assert!(value == 0 || value == 1);
-    self.write_symbol(value, &[p_zero]);
-  }
 
-  // Helper function: Write a flag which is logically a boolean
-  // This is just syntactic sugar over self.write_bit(), mapping false => 0 and true => 1
-  pub fn write_bool(&mut self, value: bool, p_false: u16) {
-    self.write_symbol(value as usize, &[p_false]);
-  }
-
-  // Write an N-bit literal value. This means N bits, which are encoded
-  // in high-to-low order with each bit having a 50:50 probability distribution
-  pub fn write_literal(&mut self, value: u32, nbits: u32) {
-    assert!(nbits <= 32);
-    assert!(nbits == 32 || value < (1 << nbits));
-    for shift in (0..nbits).rev() {
-      let bit = (value >> shift) & 1;
-      self.write_bit(bit as usize, 16384);
+    if let Some(start) = trace_start {
+      self.trace_symbol(symbol, num_symbols, start);
     }
   }
 
-  // Encode a given value using a Golomb code
-  pub fn write_golomb(&mut self, mut value: u32) {
-    //println!("  Golomb({})", value);
-    // Because the "standard" Golomb code cannot represent 0, we actually Golomb-code `value + 1`
-    value += 1;
-
-    let length = floor_log2(value);
-    // Write `length` zero bits, then the full value, including the leading 1 bit
-    // (which acts as a delimiter, allowing the decoder to figure out the correct length)
-    self.write_literal(0, length);
-    self.write_literal(value, length + 1);
-  }
-
   // Finalize entropy block and return the generated bytes.
   // This takes care of two important requirements specified by AV1:
   // 1) The encoder must output enough extra bits to ensure that the decoder can
@@ -184,3 +342,142 @@ impl EntropyWriter {
     return self.data.into_boxed_slice();
   }
 }
+
+impl SymbolWriter for EntropyWriter {
+  fn write_symbol(&mut self, symbol: usize, cdf: &[u16]) {
+    EntropyWriter::write_symbol(self, symbol, cdf);
+  }
+
+  fn trace_label(&mut self, name: &'static str, ctx: usize) {
+    self.trace_label = (name, ctx);
+  }
+}
+
+// Entropy decoder, the inverse of EntropyWriter
+// This follows the symbol decoding process in the AV1 spec (section 8.2) fairly literally,
+// as it is used to check the encoder's output rather than for speed.
+pub struct EntropyReader<'a> {
+  data: &'a [u8],
+  bitpos: usize,
+
+  // These are named after the corresponding variables in the spec
+  value: u32,
+  range: u32,
+  max_bits: i32,
+}
+
+impl<'a> EntropyReader<'a> {
+  pub fn new(data: &'a [u8]) -> Self {
+    let mut reader = Self {
+      data: data,
+      bitpos: 0,
+      value: 0,
+      range: 1 << 15,
+      max_bits: 8 * (data.len() as i32) - 15,
+    };
+
+    let num_bits = min(8 * data.len(), 15);
+    let buf = reader.read_raw_bits(num_bits);
+    let padded_buf = buf << (15 - num_bits);
+    reader.value = ((1 << 15) - 1) ^ padded_buf;
+    return reader;
+  }
+
+  // Read bits directly from the underlying data, high bit first
+  // Reading past the end of the data is not an error here, and just returns zeros
+  // (the caller limits this using max_bits anyway)
+  fn read_raw_bits(&mut self, nbits: usize) -> u32 {
+    let mut result = 0;
+    for _ in 0..nbits {
+      let byte = self.data.get(self.bitpos / 8).copied().unwrap_or(0);
+      let bit = (byte >> (7 - self.bitpos % 8)) & 1;
+      result = (result << 1) | (bit as u32);
+      self.bitpos += 1;
+    }
+    return result;
+  }
+
+  // Read an entropy-coded symbol using the given CDF
+  // The CDF format is the same as for EntropyWriter::write_symbol()
+  pub fn read_symbol(&mut self, cdf: &[u16]) -> usize {
+    let num_symbols = cdf.len() + 1;
+
+    // Find the symbol whose sub-range contains the current value, searching from the top
+    let mut cur = self.range;
+    let mut prev;
+    let mut symbol = 0;
+    loop {
+      prev = cur;
+      let inv_hi = if symbol == num_symbols - 1 { 0 } else { 32768 - (cdf[symbol] as u32) };
+      cur = ((self.range >> 8) * (inv_hi >> 6) >> 1) + 4 * (num_symbols - symbol - 1) as u32;
+      if self.value >= cur {
+        break;
+      }
+      symbol += 1;
+    }
+    self.range = prev - cur;
+    self.value -= cur;
+
+    // Renormalize
+    let bits = 15 - floor_log2(self.range);
+    self.range <<= bits;
+    let num_bits = min(bits as i32, max(0, self.max_bits)) as u32;
+    let new_data = self.read_raw_bits(num_bits as usize);
+    let padded_data = new_data << (bits - num_bits);
+    self.value = padded_data ^ (((self.value + 1) << bits) - 1);
+    self.max_bits -= bits as i32;
+
+    return symbol;
+  }
+
+  // Read an entropy-coded symbol using an adaptive CDF, then update the CDF
+  // This is the inverse of SymbolWriter::write_symbol_with_update()
+  pub fn read_symbol_with_update(&mut self, cdf: &mut [u16]) -> usize {
+    let symbol = self.read_symbol(&cdf[..cdf.len() - 1]);
+    update_cdf(symbol, cdf);
+    return symbol;
+  }
+
+  pub fn read_bit(&mut self, p_zero: u16) -> usize {
+    self.read_symbol(&[p_zero])
+  }
+
+  pub fn read_bool(&mut self, p_false: u16) -> bool {
+    self.read_symbol(&[p_false]) != 0
+  }
+
+  pub fn read_literal(&mut self, nbits: u32) -> u32 {
+    assert!(nbits <= 32);
+    let mut value = 0;
+    for _ in 0..nbits {
+      value = (value << 1) | (self.read_bit(16384) as u32);
+    }
+    return value;
+  }
+
+  // Read a value coded with SymbolWriter::write_golomb()
+  pub fn read_golomb(&mut self) -> u32 {
+    let mut length = 0;
+    while self.read_bit(16384) == 0 {
+      length += 1;
+      if length == 32 {
+        // AV1 limits Golomb codes to 32 bits, so this is an invalid stream
+        return u32::MAX;
+      }
+    }
+    let value = (1 << length) | self.read_literal(length);
+    return value - 1;
+  }
+
+  // Read a value coded with SymbolWriter::write_ns()
+  pub fn read_ns(&mut self, n: u32) -> u32 {
+    let w = floor_log2(n) + 1;
+    let m = (1 << w) - n;
+    let v = self.read_literal(w - 1);
+    if v < m {
+      return v;
+    }
+    let extra_bit = self.read_literal(1);
+    return (v << 1) - m + extra_bit;
+  }
+}
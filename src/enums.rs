@@ -7,6 +7,8 @@
 // Media Patent License 1.0 was not distributed with this source code in the
 // PATENTS file, you can obtain it at www.aomedia.org/license/patent.
 
+use bytemuck::Zeroable;
+
 pub enum Partition {
   NONE = 0,
   HORZ = 1,
@@ -19,3 +21,98 @@ pub enum Partition {
   HORZ_4 = 8,
   VERT_4 = 9
 }
+
+// Intra prediction modes
+// UV_CFL_PRED is only available for chroma
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Zeroable)]
+#[repr(u8)]
+pub enum PredictionMode {
+  DC_PRED = 0,
+  V_PRED = 1,
+  H_PRED = 2,
+  D45_PRED = 3,
+  D135_PRED = 4,
+  D113_PRED = 5,
+  D157_PRED = 6,
+  D203_PRED = 7,
+  D67_PRED = 8,
+  SMOOTH_PRED = 9,
+  SMOOTH_V_PRED = 10,
+  SMOOTH_H_PRED = 11,
+  PAETH_PRED = 12,
+  UV_CFL_PRED = 13
+}
+
+pub const INTRA_MODES: usize = 13;
+pub const UV_INTRA_MODES: usize = 14;
+
+// The directional modes are V_PRED through D67_PRED, and can have an angle delta
+// of up to MAX_ANGLE_DELTA steps either side of their base angle
+pub const DIRECTIONAL_MODES: usize = 8;
+pub const MAX_ANGLE_DELTA: i8 = 3;
+
+pub fn is_directional_mode(mode: PredictionMode) -> bool {
+  let mode = mode as u8;
+  mode >= PredictionMode::V_PRED as u8 && mode <= PredictionMode::D67_PRED as u8
+}
+
+// Blocks next to one using a SMOOTH mode get a different intra edge filter
+pub fn is_smooth_mode(mode: PredictionMode) -> bool {
+  matches!(mode, PredictionMode::SMOOTH_PRED | PredictionMode::SMOOTH_V_PRED | PredictionMode::SMOOTH_H_PRED)
+}
+
+// Transform types. Only the ones available for intra blocks in the reduced transform
+// set are listed: the first half of the name is the vertical (column) transform, and
+// the second half is the horizontal (row) transform. IDTX is the identity transform in
+// both directions, which codes the residual pixels directly
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Zeroable)]
+#[repr(u8)]
+pub enum TxType {
+  DCT_DCT = 0,
+  ADST_DCT = 1,
+  DCT_ADST = 2,
+  ADST_ADST = 3,
+  IDTX = 9,
+}
+
+// The luma transform types which can be signalled for 8x8 intra blocks with the reduced
+// transform set, in the order they're coded in (TX_SET_INTRA_2 in the spec). We only
+// support the ADST for 4x4 chroma blocks, so luma only uses the first two
+pub const TX_SET_INTRA_2: [TxType; 5] = [TxType::IDTX, TxType::DCT_DCT, TxType::ADST_ADST, TxType::ADST_DCT,
+                                         TxType::DCT_ADST];
+
+// Index of a luma transform type within TX_SET_INTRA_2
+pub fn tx_set_intra_2_index(tx_type: TxType) -> usize {
+  TX_SET_INTRA_2.iter().position(|&t| t == tx_type).unwrap()
+}
+
+// The chroma transform type isn't signalled, but is derived from the chroma prediction
+// mode (Mode_To_Txfm in the spec). Every one of these is in the reduced transform set,
+// so is always used as-is
+pub fn uv_tx_type(uv_mode: PredictionMode) -> TxType {
+  match uv_mode {
+    PredictionMode::DC_PRED | PredictionMode::D45_PRED | PredictionMode::UV_CFL_PRED => TxType::DCT_DCT,
+    PredictionMode::V_PRED | PredictionMode::D113_PRED | PredictionMode::D67_PRED |
+    PredictionMode::SMOOTH_V_PRED => TxType::ADST_DCT,
+    PredictionMode::H_PRED | PredictionMode::D157_PRED | PredictionMode::D203_PRED |
+    PredictionMode::SMOOTH_H_PRED => TxType::DCT_ADST,
+    PredictionMode::D135_PRED | PredictionMode::SMOOTH_PRED | PredictionMode::PAETH_PRED => TxType::ADST_ADST,
+  }
+}
+
+pub const ALL_PREDICTION_MODES: [PredictionMode; UV_INTRA_MODES] = [
+  PredictionMode::DC_PRED,
+  PredictionMode::V_PRED,
+  PredictionMode::H_PRED,
+  PredictionMode::D45_PRED,
+  PredictionMode::D135_PRED,
+  PredictionMode::D113_PRED,
+  PredictionMode::D157_PRED,
+  PredictionMode::D203_PRED,
+  PredictionMode::D67_PRED,
+  PredictionMode::SMOOTH_PRED,
+  PredictionMode::SMOOTH_V_PRED,
+  PredictionMode::SMOOTH_H_PRED,
+  PredictionMode::PAETH_PRED,
+  PredictionMode::UV_CFL_PRED,
+];
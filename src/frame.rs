@@ -13,6 +13,10 @@ use std::io::prelude::*;
 use crate::array2d::Array2D;
 use crate::util::*;
 
+// Maximum amount of data to read at once when the rows of a plane need to be spread out
+// in memory, see Plane::read_rows_from()
+const READ_CHUNK_SIZE: usize = 1 << 20;
+
 pub struct Plane {
   // Pixel data
   // The width() / height() methods of this array give the padded size.
@@ -75,13 +79,48 @@ impl Plane {
   }
 
   pub fn read_from<R: Read>(&mut self, r: &mut R) -> Result<(), io::Error> {
-    for row in 0 .. self.crop_height {
-      r.read_exact(&mut self.pixels[row][0 .. self.crop_width])?;
-    }
+    self.read_rows_from(r, 0, self.crop_height)?;
     self.fill_padding();
     Ok(())
   }
 
+  // Read `count` rows of crop_width pixels each, starting at row `start`
+  // The caller is responsible for calling fill_padding() afterwards
+  pub fn read_rows_from<R: Read>(&mut self, r: &mut R, start: usize, count: usize) -> Result<(), io::Error> {
+    let crop_width = self.crop_width;
+
+    // If there's no padding to the right of the image, the rows are contiguous in
+    // memory, just like in the file, so can be read in one go
+    if crop_width == self.width() {
+      return r.read_exact(self.pixels.rows_mut(start, count));
+    }
+
+    // Otherwise, read several rows at a time into a temporary buffer, then spread them out.
+    // Making fewer, larger reads matters a lot for unbuffered readers, and the buffer
+    // size is capped so that this doesn't need much extra memory for large images
+    let rows_per_chunk = max(READ_CHUNK_SIZE / crop_width, 1);
+    let mut buffer = vec![0u8; min(rows_per_chunk, count) * crop_width];
+    let mut row = start;
+    while row < start + count {
+      let chunk_rows = min(rows_per_chunk, start + count - row);
+      let chunk = &mut buffer[.. chunk_rows * crop_width];
+      r.read_exact(chunk)?;
+      for (i, src_row) in chunk.chunks_exact(crop_width).enumerate() {
+        self.pixels[row + i][.. crop_width].copy_from_slice(src_row);
+      }
+      row += chunk_rows;
+    }
+    Ok(())
+  }
+
+  // Copy rows from another plane of the same width, including the padding
+  pub fn copy_rows_from(&mut self, src: &Plane, src_start: usize, dst_start: usize, count: usize) {
+    assert!(src.width() == self.width());
+    for row in 0..count {
+      self.pixels[dst_start + row].copy_from_slice(&src.pixels[src_start + row]);
+    }
+  }
+
   pub fn write_to<W: Write>(&self, w: &mut W) -> Result<(), io::Error> {
     for row in 0 .. self.crop_height {
       w.write_all(&self.pixels[row][0 .. self.crop_width])?;
@@ -90,42 +129,104 @@ impl Plane {
   }
 }
 
-pub struct Frame {
-  planes: [Plane; 3]
+// Plane sizes for a 4:2:0 image of a given size. This is the one place these are worked out,
+// so that frames and the encoder always agree.
+//
+// The coded size is padded to a multiple of 8x8 luma pixels, so that it's made up of whole
+// 8x8 blocks. The chroma crop size rounds up, as in the spec, so that images with an odd
+// width or height keep a chroma sample for their last luma column or row.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FrameGeometry {
+  pub y_width: usize,
+  pub y_height: usize,
+  pub uv_width: usize,
+  pub uv_height: usize,
+
+  pub y_crop_width: usize,
+  pub y_crop_height: usize,
+  pub uv_crop_width: usize,
+  pub uv_crop_height: usize,
 }
 
-impl Frame {
-  pub fn new(y_crop_height: usize, y_crop_width: usize) -> Self {
+impl FrameGeometry {
+  pub fn new(y_crop_width: usize, y_crop_height: usize) -> Self {
     let y_width = y_crop_width.next_multiple_of(8);
     let y_height = y_crop_height.next_multiple_of(8);
+    Self {
+      y_width: y_width,
+      y_height: y_height,
+      uv_width: y_width / 2,
+      uv_height: y_height / 2,
+      y_crop_width: y_crop_width,
+      y_crop_height: y_crop_height,
+      uv_crop_width: y_crop_width.div_ceil(2),
+      uv_crop_height: y_crop_height.div_ceil(2),
+    }
+  }
+}
 
-    let uv_crop_width = round2(y_crop_width, 1);
-    let uv_crop_height = round2(y_crop_height, 1);
-
-    let uv_width = y_width / 2;
-    let uv_height = y_height / 2;
+pub struct Frame {
+  planes: [Plane; 3]
+}
 
+impl Frame {
+  pub fn new(y_crop_height: usize, y_crop_width: usize) -> Self {
+    let size = FrameGeometry::new(y_crop_width, y_crop_height);
     Self {
       planes: [
         Plane {
-          pixels: Array2D::zeroed(y_height, y_width),
-          crop_width: y_crop_width,
-          crop_height: y_crop_height
+          pixels: Array2D::zeroed(size.y_height, size.y_width),
+          crop_width: size.y_crop_width,
+          crop_height: size.y_crop_height
         },
         Plane {
-          pixels: Array2D::zeroed(uv_height, uv_width),
-          crop_width: uv_crop_width,
-          crop_height: uv_crop_height
+          pixels: Array2D::zeroed(size.uv_height, size.uv_width),
+          crop_width: size.uv_crop_width,
+          crop_height: size.uv_crop_height
         },
         Plane {
-          pixels: Array2D::zeroed(uv_height, uv_width),
-          crop_width: uv_crop_width,
-          crop_height: uv_crop_height
+          pixels: Array2D::zeroed(size.uv_height, size.uv_width),
+          crop_width: size.uv_crop_width,
+          crop_height: size.uv_crop_height
         },
       ]
     }
   }
 
+  // Copy a 4:2:0 image from three separate planes in memory, as produced by most decoders,
+  // into a new frame. Each plane's rows are `strides[plane]` bytes apart, which must be
+  // at least the plane's width, and the last row doesn't need to be padded out to the
+  // full stride. Each chroma plane is ceil(width/2) x ceil(height/2) pixels.
+  pub fn from_planes(y: &[u8], u: &[u8], v: &[u8], width: usize, height: usize,
+                     strides: [usize; 3]) -> Result<Self, io::Error> {
+    if width == 0 || height == 0 {
+      return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid image size {}x{}", width, height)));
+    }
+
+    let mut frame = Self::new(height, width);
+    for (plane, (name, data)) in [("Y", y), ("U", u), ("V", v)].into_iter().enumerate() {
+      let stride = strides[plane];
+      let dst = frame.plane_mut(plane);
+      let (crop_width, crop_height) = (dst.crop_width, dst.crop_height);
+      if stride < crop_width {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                  format!("{} plane stride of {} bytes is too small for {} pixels",
+                                          name, stride, crop_width)));
+      }
+      let expected_len = stride * (crop_height - 1) + crop_width;
+      if data.len() < expected_len {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                  format!("{} plane has {} bytes, but a {}x{} plane with a stride of {} needs {}",
+                                          name, data.len(), crop_width, crop_height, stride, expected_len)));
+      }
+      for row in 0..crop_height {
+        dst.pixels[row][.. crop_width].copy_from_slice(&data[row * stride .. row * stride + crop_width]);
+      }
+      dst.fill_padding();
+    }
+    return Ok(frame);
+  }
+
   pub fn plane(&self, idx: usize) -> &Plane {
     &self.planes[idx]
   }
@@ -157,4 +258,53 @@ impl Frame {
   pub fn v_mut(&mut self) -> &mut Plane {
     &mut self.planes[2]
   }
+
+  // Zero every pixel, and reset the crop region to cover the whole padded frame, leaving
+  // the frame as if it had just been created with Frame::new(height(), width())
+  pub fn clear(&mut self) {
+    for plane in &mut self.planes {
+      plane.pixels.fill_zeroed();
+      plane.crop_width = plane.width();
+      plane.crop_height = plane.height();
+    }
+  }
+
+  // Shrink the crop region to the given luma size, keeping the pixels as they are.
+  // The encoder works on frames padded out to whole 8x8 blocks, with the padding
+  // counting as part of the crop region, so this is used to trim them back down to
+  // the size of the image before they're output or measured.
+  pub fn set_crop_size(&mut self, y_crop_width: usize, y_crop_height: usize) {
+    let size = FrameGeometry::new(y_crop_width, y_crop_height);
+    assert!(size.y_width <= self.y().width() && size.y_height <= self.y().height());
+    for (plane, (crop_width, crop_height)) in self.planes.iter_mut().zip([
+      (size.y_crop_width, size.y_crop_height),
+      (size.uv_crop_width, size.uv_crop_height),
+      (size.uv_crop_width, size.uv_crop_height),
+    ]) {
+      plane.crop_width = crop_width;
+      plane.crop_height = crop_height;
+    }
+  }
+}
+
+// A source of image rows, for encoding images which are too large to hold in memory at once
+// See AV1Encoder::encode_image_streaming()
+pub trait RowSource {
+  // Read the luma rows starting at `y0` into `dst`, starting at luma row `dst_y0` and
+  // continuing to the bottom of its crop region, along with the corresponding chroma rows.
+  // `y0` and `dst_y0` are always even. The caller is responsible for filling the padding.
+  fn read_rows(&mut self, y0: usize, dst: &mut Frame, dst_y0: usize) -> Result<(), io::Error>;
+}
+
+// Frames which are already in memory can also be used as a source of rows
+impl RowSource for Frame {
+  fn read_rows(&mut self, y0: usize, dst: &mut Frame, dst_y0: usize) -> Result<(), io::Error> {
+    for plane in 0..3 {
+      let subsampling = if plane > 0 { 1 } else { 0 };
+      let dst_plane = dst.plane_mut(plane);
+      let count = dst_plane.crop_height() - (dst_y0 >> subsampling);
+      dst_plane.copy_rows_from(self.plane(plane), y0 >> subsampling, dst_y0 >> subsampling, count);
+    }
+    Ok(())
+  }
 }
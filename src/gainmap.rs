@@ -0,0 +1,128 @@
+// Copyright (c) 2024-2025, The tinyavif contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+// Gain maps, which let one file hold both an SDR and an HDR rendition of an image
+//
+// This follows ISO 21496-1, which grew out of Adobe's gain map specification. The file
+// holds an ordinary SDR image, plus a second image, the gain map, which says how much
+// brighter each part of the HDR rendition is, as a log2 gain between gain_map_min (for
+// gain map value 0) and gain_map_max (for 255). Viewers apply as much of the gain as the
+// display has headroom for, and viewers which don't know about gain maps just show the
+// SDR image.
+//
+// In the file, the gain map is a hidden AV1 item, and a 'tmap' item, which holds the
+// metadata below, derives the HDR image from the two (see hls::pack_avif()). Gain maps
+// are encoded with av1_encoder::encode_plane_item().
+//
+// We only write single-channel gain maps, which apply the same gain to all three colour
+// channels. They are coded as the luma of a 4:2:0 image, with neutral chroma.
+
+use crate::config::ConfigError;
+
+// Denominator for all of the fractions in the metadata
+const DENOMINATOR: u32 = 1 << 16;
+
+// Offset added to both renditions before taking their ratio, so that black areas don't
+// need an infinite gain, as suggested by Adobe's specification
+pub const DEFAULT_OFFSET: f64 = 1.0 / 64.0;
+
+// How to apply a gain map. Gains and headrooms are log2 values, ie. in stops
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GainMapMetadata {
+  // Gains which gain map values of 0 and 255 stand for
+  pub gain_map_min: f64,
+  pub gain_map_max: f64,
+  // Gamma which the gain map values were encoded with, after scaling them to 0..1
+  pub gamma: f64,
+  // Offsets for the base (SDR) and alternate (HDR) renditions, in linear light
+  pub base_offset: f64,
+  pub alternate_offset: f64,
+  // Display headroom (how far above SDR white the display can go) at which the base and
+  // alternate renditions are shown exactly. In between, the gain is scaled to fit
+  pub base_hdr_headroom: f64,
+  pub alternate_hdr_headroom: f64,
+}
+
+impl GainMapMetadata {
+  // Metadata for an SDR base image, whose HDR rendition is shown in full on displays with
+  // `alternate_hdr_headroom` stops of headroom
+  pub fn new(gain_map_min: f64, gain_map_max: f64, alternate_hdr_headroom: f64) -> Self {
+    Self {
+      gain_map_min: gain_map_min,
+      gain_map_max: gain_map_max,
+      gamma: 1.0,
+      base_offset: DEFAULT_OFFSET,
+      alternate_offset: DEFAULT_OFFSET,
+      base_hdr_headroom: 0.0,
+      alternate_hdr_headroom: alternate_hdr_headroom,
+    }
+  }
+
+  pub fn validate(&self) -> Result<(), ConfigError> {
+    // Signed values are stored as 32-bit numerators over DENOMINATOR, and unsigned ones
+    // as 32-bit numerators
+    let signed_limit = (i32::MAX as u32 / DENOMINATOR) as f64;
+    let unsigned_limit = (u32::MAX / DENOMINATOR) as f64;
+    for (name, value, min, max) in [("gain map minimum", self.gain_map_min, -signed_limit, signed_limit),
+                                    ("gain map maximum", self.gain_map_max, -signed_limit, signed_limit),
+                                    ("gain map gamma", self.gamma, 0.0, unsigned_limit),
+                                    ("base offset", self.base_offset, -signed_limit, signed_limit),
+                                    ("alternate offset", self.alternate_offset, -signed_limit, signed_limit),
+                                    ("base HDR headroom", self.base_hdr_headroom, 0.0, unsigned_limit),
+                                    ("alternate HDR headroom", self.alternate_hdr_headroom, 0.0, unsigned_limit)] {
+      if !(min ..= max).contains(&value) {
+        return Err(ConfigError::Invalid(format!("Invalid {} {}, must be between {} and {}", name, value, min, max)));
+      }
+    }
+    if self.gain_map_min > self.gain_map_max {
+      return Err(ConfigError::Invalid(format!("The gain map minimum {} is more than the maximum {}",
+                                              self.gain_map_min, self.gain_map_max)));
+    }
+    if self.gamma == 0.0 {
+      return Err(ConfigError::Invalid("The gain map gamma must be more than 0".into()));
+    }
+    // Viewers interpolate between the two headrooms, so they need to be different
+    if self.base_hdr_headroom == self.alternate_hdr_headroom {
+      return Err(ConfigError::Invalid(format!("The base and alternate HDR headrooms are both {}",
+                                              self.base_hdr_headroom)));
+    }
+    Ok(())
+  }
+
+  // Contents of the 'tmap' item, in the binary format from ISO 21496-1
+  // Only valid after validate()
+  pub fn to_bytes(&self) -> Vec<u8> {
+    let signed = |value: f64| ((value * DENOMINATOR as f64).round() as i32).to_be_bytes();
+    let unsigned = |value: f64| ((value * DENOMINATOR as f64).round() as u32).to_be_bytes();
+
+    let mut data = Vec::new();
+    data.push(0); // Version
+    data.extend_from_slice(&0u16.to_be_bytes()); // Minimum version a reader must understand
+    data.extend_from_slice(&0u16.to_be_bytes()); // Writer version
+    // Flags: not multi-channel (bit 7), and the gain is applied in the base image's colour
+    // space (bit 6)
+    data.push(1 << 6);
+
+    // Each value is a numerator, then a denominator
+    for (numerator, denominator) in [
+      (unsigned(self.base_hdr_headroom), DENOMINATOR),
+      (unsigned(self.alternate_hdr_headroom), DENOMINATOR),
+      // The one channel
+      (signed(self.gain_map_min), DENOMINATOR),
+      (signed(self.gain_map_max), DENOMINATOR),
+      (unsigned(self.gamma), DENOMINATOR),
+      (signed(self.base_offset), DENOMINATOR),
+      (signed(self.alternate_offset), DENOMINATOR),
+    ] {
+      data.extend_from_slice(&numerator);
+      data.extend_from_slice(&denominator.to_be_bytes());
+    }
+    return data;
+  }
+}
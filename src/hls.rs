@@ -7,10 +7,97 @@
 // Media Patent License 1.0 was not distributed with this source code in the
 // PATENTS file, you can obtain it at www.aomedia.org/license/patent.
 
-use crate::isobmff::ISOBMFFWriter;
+use std::io;
+use std::str::FromStr;
+
+use crate::bitcode::BitReader;
+use crate::config::{ChromaSubsampling, ConfigError, EncoderConfig, ObuLayout, Tier};
+use crate::gainmap::GainMapMetadata;
+use crate::isobmff::{ISOBMFFBox, ISOBMFFReader, ISOBMFFWriter};
 use crate::util::write_leb128;
 
-pub fn pack_obus(sequence_header: &[u8], frame_header: &[u8], tile_data: &[u8], include_temporal_delimiter: bool) -> Box<[u8]> {
+// Layer IDs carried in the optional OBU extension header
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ObuExtension {
+  pub temporal_id: u8,
+  pub spatial_id: u8,
+}
+
+impl ObuExtension {
+  pub fn header_byte(&self) -> u8 {
+    // 3 bits temporal ID, 2 bits spatial ID, 3 reserved bits
+    (self.temporal_id << 5) | (self.spatial_id << 3)
+  }
+
+  // Value of operating_point_idc for an operating point containing just this layer:
+  // one bit per temporal layer in the low byte, and one bit per spatial layer above that
+  pub fn operating_point_idc(&self) -> u16 {
+    (1 << self.temporal_id) | (1 << (8 + self.spatial_id))
+  }
+}
+
+// Sequence-level parameters which are coded in both the sequence header and the av1C box
+// Both are generated from this, so that the container can't disagree with the bitstream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SequenceParams {
+  pub seq_profile: u8,
+  pub seq_level_idx: u8,
+  pub seq_tier: u8,
+  pub high_bitdepth: bool,
+  pub twelve_bit: bool,
+  pub mono_chrome: bool,
+  pub subsampling_x: bool,
+  pub subsampling_y: bool,
+  pub chroma_sample_position: u8,
+}
+
+impl SequenceParams {
+  // Only valid after config.validate()
+  pub fn from_config(config: &EncoderConfig, width: usize, height: usize) -> Self {
+    let (mono_chrome, subsampling_x, subsampling_y) = match config.subsampling {
+      ChromaSubsampling::Yuv420 => (false, true, true),
+      ChromaSubsampling::Yuv422 => (false, true, false),
+      ChromaSubsampling::Yuv444 => (false, false, false),
+      ChromaSubsampling::Monochrome => (true, true, true),
+    };
+    // The chroma sample position is only coded for 4:2:0
+    let chroma_sample_position = if subsampling_x && subsampling_y && !mono_chrome {
+      config.chroma_sample_position as u8
+    } else {
+      0
+    };
+    Self {
+      seq_profile: config.seq_profile(),
+      seq_level_idx: config.seq_level_idx(width, height),
+      seq_tier: (config.tier == Tier::High) as u8,
+      high_bitdepth: config.bit_depth > 8,
+      twelve_bit: config.bit_depth == 12,
+      mono_chrome: mono_chrome,
+      subsampling_x: subsampling_x,
+      subsampling_y: subsampling_y,
+      chroma_sample_position: chroma_sample_position,
+    }
+  }
+
+  // Contents of the av1C box (AV1CodecConfigurationRecord), without any config OBUs
+  pub fn av1c(&self) -> [u8; 4] {
+    return [
+      // 1-bit marker which must be 1, then 7-bit version = 1
+      0x81,
+      (self.seq_profile << 5) | self.seq_level_idx,
+      (self.seq_tier << 7) | ((self.high_bitdepth as u8) << 6) | ((self.twelve_bit as u8) << 5) |
+        ((self.mono_chrome as u8) << 4) | ((self.subsampling_x as u8) << 3) | ((self.subsampling_y as u8) << 2) |
+        self.chroma_sample_position,
+      // No presentation delay info
+      0x00,
+    ];
+  }
+}
+
+// The frame header must have been generated with a trailing 1 bit if and only if
+// the layout is ObuLayout::Separate (see AV1Encoder::generate_frame_header())
+pub fn pack_obus(sequence_header: &[u8], frame_header: &[u8], tile_data: &[u8], include_temporal_delimiter: bool,
+                 extension: Option<ObuExtension>, layout: ObuLayout) -> Box<[u8]> {
   let mut av1_data = Vec::new();
 
   // Optionally include temporal delimiter
@@ -33,35 +120,498 @@ pub fn pack_obus(sequence_header: &[u8], frame_header: &[u8], tile_data: &[u8],
   }
 
   av1_data.push(0b0000_1010); // Sequence header OBU
-  write_leb128(&mut av1_data, sequence_header.len()); // Payload size
+  write_obu_size(&mut av1_data, sequence_header.len()); // Payload size
   av1_data.extend_from_slice(&sequence_header); // Payload
 
-  av1_data.push(0b0011_0010); // Frame OBU: combined frame header + tile data
-  write_leb128(&mut av1_data, frame_header.len() + tile_data.len());
-  av1_data.extend_from_slice(&frame_header);
-  av1_data.extend_from_slice(&tile_data);
+  // Only the frame data is tagged with the layer IDs. Temporal delimiters and sequence
+  // headers apply to all layers, so never have an extension header
+  match layout {
+    ObuLayout::Frame => {
+      // Frame OBU: combined frame header + tile data
+      write_obu_header(&mut av1_data, OBU_FRAME, extension);
+      write_obu_size(&mut av1_data, frame_header.len() + tile_data.len());
+      av1_data.extend_from_slice(&frame_header);
+      av1_data.extend_from_slice(&tile_data);
+    },
+    ObuLayout::Separate => {
+      write_obu_header(&mut av1_data, OBU_FRAME_HEADER, extension);
+      write_obu_size(&mut av1_data, frame_header.len());
+      av1_data.extend_from_slice(&frame_header);
+
+      // With only one tile, the tile group has no header of its own, and the tile data
+      // already ends in the trailing bits required at the end of the OBU
+      write_obu_header(&mut av1_data, OBU_TILE_GROUP, extension);
+      write_obu_size(&mut av1_data, tile_data.len());
+      av1_data.extend_from_slice(&tile_data);
+    },
+  }
 
   return av1_data.into_boxed_slice();
 }
 
-pub fn pack_avif(av1_data: &[u8], crop_width: usize, crop_height: usize,
-                 color_primaries: u16,
-                 transfer_function: u16,
-                 matrix_coefficients: u16) -> Box<[u8]> {
+const OBU_FRAME_HEADER: u8 = 3;
+const OBU_TILE_GROUP: u8 = 4;
+const OBU_FRAME: u8 = 6;
+
+// AV1 limits OBU sizes (and all other leb128 values) to 32 bits. All of the OBUs we
+// write are far smaller than this, so exceeding it is an encoder bug
+const MAX_OBU_SIZE: usize = u32::MAX as usize;
+
+fn write_obu_size(av1_data: &mut Vec<u8>, size: usize) {
+  assert!(size <= MAX_OBU_SIZE, "OBU size {} is too large for AV1 (maximum 2^32 - 1)", size);
+  write_leb128(av1_data, size as u64);
+}
+
+// OBU header with a size field, plus the extension header if given
+fn write_obu_header(av1_data: &mut Vec<u8>, obu_type: u8, extension: Option<ObuExtension>) {
+  let has_extension = extension.is_some() as u8;
+  av1_data.push((obu_type << 3) | (has_extension << 2) | 0b010);
+  if let Some(ext) = extension {
+    av1_data.push(ext.header_byte());
+  }
+}
+
+// Wrap the AV1 data in an IVF file, as a single frame
+// IVF is a minimal container from libvpx, which many AV1 tools (eg. aomanalyzer and
+// the dav1d command line tool) accept more readily than raw OBUs.
+pub fn pack_ivf(av1_data: &[u8], crop_width: usize, crop_height: usize) -> Box<[u8]> {
+  // IVF stores the frame size in 16 bits, slightly less than AV1 allows, and the frame
+  // data size in 32 bits
+  assert!(crop_width <= u16::MAX as usize && crop_height <= u16::MAX as usize,
+          "Image size {}x{} is too large for an IVF file", crop_width, crop_height);
+  assert!(av1_data.len() <= u32::MAX as usize, "AV1 data is too large for an IVF file");
+  let mut ivf = Vec::with_capacity(32 + 12 + av1_data.len());
+
+  // File header, with all values little-endian
+  ivf.extend_from_slice(b"DKIF");
+  ivf.extend_from_slice(&0u16.to_le_bytes()); // Version
+  ivf.extend_from_slice(&32u16.to_le_bytes()); // Header size
+  ivf.extend_from_slice(b"AV01");
+  ivf.extend_from_slice(&(crop_width as u16).to_le_bytes());
+  ivf.extend_from_slice(&(crop_height as u16).to_le_bytes());
+  // Time base, as a frame rate: 1 frame per second, which is arbitrary for a still image
+  ivf.extend_from_slice(&1u32.to_le_bytes()); // Numerator
+  ivf.extend_from_slice(&1u32.to_le_bytes()); // Denominator
+  ivf.extend_from_slice(&1u32.to_le_bytes()); // Number of frames
+  ivf.extend_from_slice(&0u32.to_le_bytes()); // Unused
+
+  // Frame header: size, then a 64-bit timestamp
+  ivf.extend_from_slice(&(av1_data.len() as u32).to_le_bytes());
+  ivf.extend_from_slice(&0u64.to_le_bytes());
+  ivf.extend_from_slice(av1_data);
+
+  return ivf.into_boxed_slice();
+}
+
+// Brands listed in the 'ftyp' box, which say which specifications the file conforms to
+// Strict validators check that each compatible brand's constraints are actually met, so
+// for_image() only lists the ones which apply to the image being written.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileBrands {
+  pub major: [u8; 4],
+  pub compatible: Vec<[u8; 4]>,
+}
+
+impl FileBrands {
+  // Only valid after config.validate()
+  pub fn for_image(config: &EncoderConfig, crop_width: usize, crop_height: usize) -> Self {
+    let params = SequenceParams::from_config(config, crop_width, crop_height);
+    let mut compatible = vec![*b"avif", *b"mif1"];
+
+    // MIAF requires the image size to be a whole number of chroma samples. Odd-sized
+    // 4:2:0 and 4:2:2 images would need a 'clap' box to crop them, which we don't write
+    let odd_size = !params.mono_chrome && ((params.subsampling_x && crop_width % 2 != 0) ||
+                                           (params.subsampling_y && crop_height % 2 != 0));
+    if !odd_size {
+      compatible.push(*b"miaf");
+      // The AVIF profiles, which build on MIAF and limit the AV1 profile and level:
+      // Baseline is Main profile up to level 5.1, Advanced is High profile up to level 6.0
+      if params.seq_profile == 0 && params.seq_level_idx <= 13 {
+        compatible.push(*b"MA1B");
+      } else if params.seq_profile <= 1 && params.seq_level_idx <= 16 {
+        compatible.push(*b"MA1A");
+      }
+    }
+
+    Self {
+      major: *b"avif",
+      compatible: compatible,
+    }
+  }
+}
+
+// Where the AV1 data is stored in an AVIF file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ItemStorage {
+  // In a top-level 'mdat' box, after all of the metadata
+  Mdat,
+  // In an 'idat' box inside the 'meta' box. This keeps everything in one place, which can
+  // help for small images, but can't hold 4GB or more of data
+  Idat,
+}
+
+impl FromStr for ItemStorage {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "mdat" => Ok(ItemStorage::Mdat),
+      "idat" => Ok(ItemStorage::Idat),
+      _ => Err(format!("Unknown item storage \"{}\", must be \"mdat\" or \"idat\"", s)),
+    }
+  }
+}
+
+// Default for AvifConfig::inline_limit
+pub const DEFAULT_INLINE_LIMIT: usize = 1024;
+
+// Offset of the TIFF header in a block of Exif data, which is either at the start or
+// after an "Exif\0\0" marker. None if the data doesn't start with either
+pub fn exif_tiff_header_offset(exif: &[u8]) -> Option<usize> {
+  let is_tiff_header = |data: &[u8]| data.starts_with(b"II*\0") || data.starts_with(b"MM\0*");
+  if is_tiff_header(exif) {
+    return Some(0);
+  }
+  if exif.starts_with(b"Exif\0\0") && is_tiff_header(&exif[6..]) {
+    return Some(6);
+  }
+  return None;
+}
+
+// Container-level settings for pack_avif(), which don't affect the AV1 data
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AvifConfig {
+  pub brands: FileBrands,
+  // Name in the 'hdlr' box, which identifies the program which wrote the file
+  pub handler_name: String,
+  // Name of the image item in its 'infe' box
+  // Both names are written as null-terminated strings, so must not contain '\0'
+  pub item_name: String,
+  pub storage: ItemStorage,
+  // Items other than the main image which are no larger than this many bytes, such as Exif
+  // data or gain map metadata, go in the 'idat' box even when `storage` is Mdat. That
+  // keeps them with the rest of the metadata, where readers find them without seeking
+  pub inline_limit: usize,
+  // Whether to put the 'mdat' box before the 'meta' box, rather than after it
+  // Putting the metadata first is usually better, as readers can then parse it before the
+  // rest of the file arrives, but some tools expect the other order
+  pub mdat_first: bool,
+  // If nonzero, everything before the 'mdat' box is padded out to exactly this many bytes
+  // with a 'free' box, so that viewers which fetch files in pieces, such as over HTTP
+  // range requests, can get all of the headers with a first request of this size
+  pub header_size: usize,
+  // Exif metadata to attach to the image: a TIFF header and the data following it, which
+  // may be preceded by "Exif\0\0", as in JPEG files
+  pub exif: Option<Vec<u8>>,
+}
+
+impl AvifConfig {
+  // Only valid after config.validate()
+  pub fn for_image(config: &EncoderConfig, crop_width: usize, crop_height: usize) -> Self {
+    Self {
+      brands: FileBrands::for_image(config, crop_width, crop_height),
+      handler_name: "tinyavif".into(),
+      item_name: "Color".into(),
+      storage: ItemStorage::Mdat,
+      inline_limit: DEFAULT_INLINE_LIMIT,
+      mdat_first: false,
+      header_size: 0,
+      exif: None,
+    }
+  }
+}
+
+// A property of an item, written to the 'ipco' box
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum ItemProperty {
+  // "Image spatial extent" box: the width and height of the image
+  Ispe(usize, usize),
+  // "Pixel information" box: 3 channels, each of which is 8 bits per pixel
+  Pixi,
+  // AV1-specific info box
+  Av1C([u8; 4]),
+  // Colour info box: colour primaries, transfer function, matrix coefficients, and
+  // whether the image is full range
+  Colr(u16, u16, u16, bool),
+  // "Auxiliary type" box: the URN saying what an auxiliary image is
+  AuxC(String),
+}
+
+impl ItemProperty {
+  // Whether readers must understand this property to display the item correctly
+  fn essential(&self) -> bool {
+    // av1C is required to be essential by the AVIF spec
+    return matches!(self, ItemProperty::Av1C(_));
+  }
+
+  fn write(&self, ipco: &mut ISOBMFFBox) {
+    match self {
+      &ItemProperty::Ispe(width, height) => {
+        let mut ispe = ipco.open_box_with_version(b"ispe", 0, 0);
+        ispe.write_u32(width as u32);
+        ispe.write_u32(height as u32);
+      },
+      ItemProperty::Pixi => {
+        let mut pixi = ipco.open_box_with_version(b"pixi", 0, 0);
+        pixi.write_u8(3); // 3 channels...
+        pixi.write_u8(8);
+        pixi.write_u8(8);
+        pixi.write_u8(8); // ...each of which is 8 bits per pixel
+      },
+      ItemProperty::Av1C(av1c) => {
+        #[allow(non_snake_case)]
+        let mut av1C = ipco.open_box(b"av1C");
+        av1C.write_bytes(av1c);
+      },
+      &ItemProperty::Colr(color_primaries, transfer_function, matrix_coefficients, full_range) => {
+        let mut colr = ipco.open_box(b"colr");
+        colr.write_bytes(b"nclx"); // Required subtype
+        colr.write_u16(color_primaries);
+        colr.write_u16(transfer_function);
+        colr.write_u16(matrix_coefficients);
+        colr.write_u8(if full_range { 0x80 } else { 0 });
+      },
+      ItemProperty::AuxC(aux_type) => {
+        assert!(!aux_type.contains('\0'), "Auxiliary type {:?} contains a null character", aux_type);
+        #[allow(non_snake_case)]
+        let mut auxC = ipco.open_box_with_version(b"auxC", 0, 0);
+        auxC.write_bytes(aux_type.as_bytes());
+        auxC.write_u8(0);
+      },
+    }
+  }
+}
+
+// An item to be written to an AVIF file by write_avif()
+// Items are numbered from 1 in the order they're given, and the first is the primary item
+struct Item<'a> {
+  item_type: [u8; 4],
+  // Name in the item's 'infe' box, eg. "Color" to say that this is the main colour data,
+  // not, say, alpha data. Written as a null-terminated string, so must not contain '\0'
+  name: &'a str,
+  // Hidden items are only used to build other items, and aren't meant to be shown alone
+  hidden: bool,
+  data: &'a [u8],
+  properties: Vec<ItemProperty>,
+}
+
+// An AV1 image item, with the properties which describe it
+fn image_item<'a>(av1_data: &'a [u8], crop_width: usize, crop_height: usize, config: &EncoderConfig,
+                  name: &'a str, hidden: bool) -> Item<'a> {
+  let params = SequenceParams::from_config(config, crop_width, crop_height);
+  Item {
+    item_type: *b"av01",
+    name: name,
+    hidden: hidden,
+    data: av1_data,
+    properties: vec![
+      ItemProperty::Ispe(crop_width, crop_height),
+      ItemProperty::Pixi,
+      ItemProperty::Av1C(params.av1c()),
+      ItemProperty::Colr(config.color_primaries, config.transfer_function, config.matrix_coefficients,
+                         config.full_range),
+    ],
+  }
+}
+
+// A typed reference from one item to others, eg. 'dimg' from a derived image item to the
+// items it's derived from
+struct ItemReference {
+  reference_type: [u8; 4],
+  from: u16,
+  to: Vec<u16>,
+}
+
+// A group of items, eg. 'altr' for alternative versions of the same image, in order of
+// preference. Group IDs share a number space with item IDs, so must not match any item
+struct EntityGroup {
+  group_type: [u8; 4],
+  group_id: u32,
+  entities: Vec<u32>,
+}
+
+// URNs for the kinds of auxiliary image which readers know about, for AvifItemKind::Auxiliary
+pub const AUX_TYPE_ALPHA: &str = "urn:mpeg:mpegB:cicp:systems:auxiliary:alpha";
+pub const AUX_TYPE_DEPTH: &str = "urn:mpeg:hevc:2015:auxid:2";
+
+// What an extra item in an AVIF file is for
+#[derive(Clone, Debug, PartialEq)]
+pub enum AvifItemKind {
+  // An auxiliary image, such as an alpha channel or depth map, which belongs to the main
+  // image. The URN in its 'auxC' property says what kind it is
+  Auxiliary(String),
+  // A gain map, which lets viewers show an HDR version of the main image (see gainmap.rs)
+  GainMap(GainMapMetadata),
+}
+
+// An image stored alongside the main one in an AVIF file, as its own AV1 item
+// These can be encoded from a grayscale plane with av1_encoder::encode_plane_item()
+#[derive(Clone, Debug)]
+pub struct AvifItem {
+  pub kind: AvifItemKind,
+  pub width: usize,
+  pub height: usize,
+  // Settings the item was encoded with, which its properties are derived from
+  pub config: EncoderConfig,
+  pub av1_data: Box<[u8]>,
+}
+
+// Pack an image into an AVIF file, along with any extra items. The image is the primary
+// item, so it's what viewers which don't understand the extra items show.
+// Fails if there's more than one gain map, or `avif_config` is invalid.
+pub fn pack_avif(av1_data: &[u8], crop_width: usize, crop_height: usize, config: &EncoderConfig,
+                 extra_items: &[AvifItem], avif_config: &AvifConfig) -> Result<Box<[u8]>, ConfigError> {
+  let gain_maps = extra_items.iter().filter(|item| matches!(item.kind, AvifItemKind::GainMap(_))).count();
+  if gain_maps > 1 {
+    return Err(ConfigError::Invalid(format!("An AVIF file can only hold one gain map, not {}", gain_maps)));
+  }
+  // Contents of the 'tmap' item, which needs to outlive `items`
+  let tmap_data = extra_items.iter().find_map(|item| match &item.kind {
+    AvifItemKind::GainMap(metadata) => Some(metadata.to_bytes()),
+    _ => None,
+  });
+
+  // Exif items start with the offset of the TIFF header
+  let exif_data = match &avif_config.exif {
+    Some(exif) => {
+      let Some(offset) = exif_tiff_header_offset(exif) else {
+        return Err(ConfigError::Invalid("Exif data must start with a TIFF header".into()));
+      };
+      let mut data = (offset as u32).to_be_bytes().to_vec();
+      data.extend_from_slice(exif);
+      Some(data)
+    },
+    None => None,
+  };
+
+  let mut items = vec![image_item(av1_data, crop_width, crop_height, config, &avif_config.item_name, false)];
+  let mut references = Vec::new();
+  let mut groups = Vec::new();
+  let mut gain_map_id = 0;
+  for extra in extra_items {
+    let item_id = items.len() as u16 + 1;
+    match &extra.kind {
+      AvifItemKind::Auxiliary(aux_type) => {
+        let name = match aux_type.as_str() {
+          AUX_TYPE_ALPHA => "Alpha",
+          AUX_TYPE_DEPTH => "Depth",
+          _ => "Auxiliary",
+        };
+        let mut item = image_item(&extra.av1_data, extra.width, extra.height, &extra.config, name, false);
+        item.properties.push(ItemProperty::AuxC(aux_type.clone()));
+        items.push(item);
+        // An 'auxl' reference links the auxiliary image to the image it belongs to
+        references.push(ItemReference { reference_type: *b"auxl", from: item_id, to: vec![1] });
+      },
+      AvifItemKind::GainMap(_) => {
+        items.push(image_item(&extra.av1_data, extra.width, extra.height, &extra.config, "GainMap", true));
+        gain_map_id = item_id;
+      },
+    }
+  }
+
+  if let Some(exif_data) = &exif_data {
+    // Metadata items are linked to the image they describe by a 'cdsc' reference
+    let exif_id = items.len() as u16 + 1;
+    items.push(Item {
+      item_type: *b"Exif",
+      name: "Exif",
+      hidden: false,
+      data: exif_data,
+      properties: Vec::new(),
+    });
+    references.push(ItemReference { reference_type: *b"cdsc", from: exif_id, to: vec![1] });
+  }
+
+  let mut avif_config = avif_config.clone();
+  if let Some(tmap_data) = &tmap_data {
+    // The gain map is applied by a 'tmap' item, the tone-mapped image, which is derived
+    // from the main image and the gain map, in that order. Its colour description is
+    // for the HDR rendition, which is in the same colour space as the main image
+    let tmap_id = items.len() as u16 + 1;
+    items.push(Item {
+      item_type: *b"tmap",
+      name: "HDR",
+      hidden: false,
+      data: tmap_data,
+      properties: vec![
+        ItemProperty::Ispe(crop_width, crop_height),
+        ItemProperty::Pixi,
+        ItemProperty::Colr(config.color_primaries, config.transfer_function, config.matrix_coefficients,
+                           config.full_range),
+      ],
+    });
+    references.push(ItemReference { reference_type: *b"dimg", from: tmap_id, to: vec![1, gain_map_id] });
+    // It's also marked as the preferred alternative to the main image, for readers which
+    // can display it
+    groups.push(EntityGroup { group_type: *b"altr", group_id: tmap_id as u32 + 1,
+                              entities: vec![tmap_id as u32, 1] });
+
+    // Readers look for the 'tmap' brand to know that there may be a gain map
+    if !avif_config.brands.compatible.contains(b"tmap") {
+      avif_config.brands.compatible.push(*b"tmap");
+    }
+  }
+  return write_avif(&items, &references, &groups, &avif_config);
+}
+
+fn write_avif(items: &[Item], references: &[ItemReference], groups: &[EntityGroup],
+              avif_config: &AvifConfig) -> Result<Box<[u8]>, ConfigError> {
+  if avif_config.mdat_first && avif_config.header_size != 0 {
+    return Err(ConfigError::Invalid("The headers can only be padded when the 'mdat' box comes after them".into()));
+  }
+  let names = items.iter().map(|item| item.name);
+  for name in [avif_config.handler_name.as_str()].into_iter().chain(names) {
+    assert!(!name.contains('\0'), "Box name {:?} contains a null character", name);
+  }
   let mut avif = ISOBMFFWriter::new();
 
-  let content_pos_marker;
-  let content_size = av1_data.len();
+  // The main image goes wherever `storage` says. Other items small enough to be worth
+  // keeping with the metadata, such as Exif data, go in the 'idat' box either way
+  let in_idat: Vec<bool> = items.iter().enumerate().map(|(i, item)| {
+    avif_config.storage == ItemStorage::Idat || (i > 0 && item.data.len() <= avif_config.inline_limit)
+  }).collect();
+  let use_idat = in_idat.iter().any(|&inline| inline);
+  let use_mdat = in_idat.iter().any(|&inline| !inline);
+
+  // The data in each box is stored in item order
+  let mut content_pos_markers = Vec::new();
+  let content_size: usize = items.iter().map(|item| item.data.len()).sum();
+  // Offsets and lengths in the 'iloc' box are 4 bytes where possible, as that's what most
+  // readers expect. From 2GB of data, use 8 bytes, as the offset of the data (which comes
+  // after the much smaller headers) could then overflow a 32-bit field
+  let large = content_size >= (1 << 31);
+  let write_sized = |b: &mut ISOBMFFBox, value: usize| {
+    if large { b.write_u64(value as u64) } else { b.write_u32(value as u32) }
+  };
+
+  // Each distinct property is written once, and shared by every item which has it
+  let mut properties: Vec<&ItemProperty> = Vec::new();
+  let item_property_indices: Vec<Vec<usize>> = items.iter().map(|item| {
+    item.properties.iter().map(|property| {
+      match properties.iter().position(|&p| p == property) {
+        Some(index) => index,
+        None => {
+          properties.push(property);
+          properties.len() - 1
+        },
+      }
+    }).collect()
+  }).collect();
 
   // "File type" box
   let mut ftyp = avif.open_box(b"ftyp");
-  ftyp.write_bytes(b"avif"); // Main file type
-  ftyp.write_u32(0);         // AVIF version
-  ftyp.write_bytes(b"avifmif1miafMA1B"); // "compatible brands"
+  ftyp.write_bytes(&avif_config.brands.major); // Main file type
+  ftyp.write_u32(0);               // AVIF version
+  for brand in &avif_config.brands.compatible {
+    ftyp.write_bytes(brand);
+  }
   drop(ftyp);
 
   // Metadata box - contains the rest of the file header
-  let mut meta = avif.open_box_with_version(b"meta", 0, 0);
+  // This is built separately, so that the 'mdat' box can go on either side of it
+  let mut header = ISOBMFFWriter::new();
+  let mut meta = header.open_box_with_version(b"meta", 0, 0);
   {
     // "Handler" box
     // Per libavif, this *must* be the first child of the "meta" box, and must have type "pict".
@@ -72,7 +622,8 @@ pub fn pack_avif(av1_data: &[u8], crop_width: usize, crop_height: usize,
     hdlr.write_u32(0); // Must be zero
     hdlr.write_u32(0); // Must be zero
     hdlr.write_u32(0); // Must be zero
-    hdlr.write_bytes(b"tinyavif\0");
+    hdlr.write_bytes(avif_config.handler_name.as_bytes());
+    hdlr.write_u8(0);
     drop(hdlr);
 
     // "Primary item" box
@@ -81,99 +632,345 @@ pub fn pack_avif(av1_data: &[u8], crop_width: usize, crop_height: usize,
     drop(pitm);
 
     // "Item location" box
-    let mut iloc = meta.open_box_with_version(b"iloc", 0, 0);
-    iloc.write_u8(0x44); // 4 bytes each for offset and length
-    iloc.write_u8(0);    // No base offset; 4 reserved bits
-    iloc.write_u16(1);   // One item
-
-    iloc.write_u16(1); // Item ID 1:
-    iloc.write_u16(0); // "Data reference index" = 0
-    iloc.write_u16(1); // One extent
-    // Allocate space for the content position, but we'll need to come back and fill it in later
-    content_pos_marker = iloc.mark_u32();
-    iloc.write_u32(content_size as u32); // Content length
+    // Construction methods other than 0 (offset within the file) need version 1
+    let mut iloc = meta.open_box_with_version(b"iloc", use_idat as u8, 0);
+    iloc.write_u8(if large { 0x88 } else { 0x44 }); // Offset and length sizes
+    iloc.write_u8(0);    // No base offset; no extent index (version 1) or 4 reserved bits
+    iloc.write_u16(items.len() as u16);
+
+    let mut idat_offset = 0;
+    let mut mdat_offset = 0;
+    for (i, item) in items.iter().enumerate() {
+      iloc.write_u16(i as u16 + 1); // Item ID
+      if use_idat {
+        // Construction method 1 is an offset within the 'idat' box, 0 within the file
+        iloc.write_u16(in_idat[i] as u16);
+      }
+      iloc.write_u16(0); // "Data reference index" = 0
+      iloc.write_u16(1); // One extent
+      if in_idat[i] {
+        write_sized(&mut iloc, idat_offset);
+        idat_offset += item.data.len();
+      } else {
+        // Allocate space for the content position, but we'll need to come back and fill it in later
+        let marker = if large { iloc.mark_u64() } else { iloc.mark_u32() };
+        content_pos_markers.push((marker, mdat_offset));
+        mdat_offset += item.data.len();
+      }
+      write_sized(&mut iloc, item.data.len()); // Content length
+    }
     drop(iloc);
 
     // "Item info" box
     let mut iinf = meta.open_box_with_version(b"iinf", 0, 0);
-    iinf.write_u16(1); // One item
+    iinf.write_u16(items.len() as u16);
     // "infe" box per item
-    {
-      let mut infe = iinf.open_box_with_version(b"infe", 2, 0);
-      infe.write_u16(1);            // Item index 1
+    for (i, item) in items.iter().enumerate() {
+      // Flag 1 marks hidden items
+      let mut infe = iinf.open_box_with_version(b"infe", 2, item.hidden as u32);
+      infe.write_u16(i as u16 + 1); // Item ID
       infe.write_u16(0);            // "Protection" = 0
-      infe.write_bytes(b"av01");    // This stream is AV1 :)
-      infe.write_bytes(b"Color\0"); // and it's the main colour data, not, say, alpha data
-      drop(infe);
+      infe.write_bytes(&item.item_type);
+      infe.write_bytes(item.name.as_bytes());
+      infe.write_u8(0);
     }
     drop(iinf);
 
+    // "Item reference" box
+    if !references.is_empty() {
+      let mut iref = meta.open_box_with_version(b"iref", 0, 0);
+      for reference in references {
+        let mut reference_box = iref.open_box(&reference.reference_type);
+        reference_box.write_u16(reference.from);
+        reference_box.write_u16(reference.to.len() as u16);
+        for &to in &reference.to {
+          reference_box.write_u16(to);
+        }
+      }
+      drop(iref);
+    }
+
     // "Image properties" box
     let mut iprp = meta.open_box(b"iprp");
     {
       // "Image property container" box
       let mut ipco = iprp.open_box(b"ipco");
-      {
-        // "Image spatial extent" box
-        let mut ispe = ipco.open_box_with_version(b"ispe", 0, 0);
-        ispe.write_u32(crop_width as u32);
-        ispe.write_u32(crop_height as u32);
-        drop(ispe);
-
-        // "Pixel information" box
-        let mut pixi = ipco.open_box_with_version(b"pixi", 0, 0);
-        pixi.write_u8(3); // 3 channels...
-        pixi.write_u8(8);
-        pixi.write_u8(8);
-        pixi.write_u8(8); // ...each of which is 8 bits per pixel
-        drop(pixi);
-
-        // AV1-specific info box
-        #[allow(non_snake_case)]
-        let mut av1C = ipco.open_box(b"av1C");
-        av1C.write_u8(0x81);       // Custom version field: 1 bit marker that must be 1 + 7-bit version = 1
-        av1C.write_u8(0x1F);       // Profile 0, level 31 (== unconstrained)
-        av1C.write_u8(0b00001100); // Main tier, 8bpp, not monochrome, 4:2:0 subsampling, chroma sample position unknown
-        av1C.write_u8(0x00);       // No presentation delay info
-        drop(av1C);
-
-        // Colour info box
-        let mut colr = ipco.open_box(b"colr");
-        colr.write_bytes(b"nclx"); // Required subtype
-        colr.write_u16(color_primaries);
-        colr.write_u16(transfer_function);
-        colr.write_u16(matrix_coefficients);
-        colr.write_u8(0);  // TV colour range (change to 0x80 for full-range)
-        drop(colr);
+      for property in &properties {
+        property.write(&mut ipco);
       }
       drop(ipco);
 
-      // "Image property mapping association" box
-      let mut ipma = iprp.open_box_with_version(b"ipma", 0, 0);
-      ipma.write_u32(1); // One item
-
-      ipma.write_u16(1); // Item ID 1:
-      ipma.write_u8(4); // Four associations
-      // Associations - 1 byte each
-      // Each has a 1-bit flag (0x80 bit) indicating whether the association is mandatory,
-      // and a 7-bit ID which presumably indexes into the 'ipco' table above
-      ipma.write_u8(1);
-      ipma.write_u8(2);
-      ipma.write_u8(0x83);
-      ipma.write_u8(4);
+      // "Image property mapping association" box, associating each item with its properties
+      // Property indices are 1-based, and take 7 bits, unless flag 1 is set to make them 15 bits
+      // Items with no properties, such as Exif data, are left out
+      let wide = properties.len() > 127;
+      let mut ipma = iprp.open_box_with_version(b"ipma", 0, wide as u32);
+      ipma.write_u32(item_property_indices.iter().filter(|indices| !indices.is_empty()).count() as u32);
+      for (i, indices) in item_property_indices.iter().enumerate() {
+        if indices.is_empty() {
+          continue;
+        }
+        ipma.write_u16(i as u16 + 1);
+        ipma.write_u8(indices.len() as u8); // Number of associations
+        // Each association has a 1-bit flag (top bit) saying whether the property is essential,
+        // then the index of the property in the 'ipco' box
+        for &index in indices {
+          let essential = properties[index].essential();
+          if wide {
+            ipma.write_u16(((essential as u16) << 15) | (index + 1) as u16);
+          } else {
+            ipma.write_u8(((essential as u8) << 7) | (index + 1) as u8);
+          }
+        }
+      }
       drop(ipma);
     }
     drop(iprp);
+
+    // "Groups list" box
+    if !groups.is_empty() {
+      let mut grpl = meta.open_box(b"grpl");
+      for group in groups {
+        let mut group_box = grpl.open_box_with_version(&group.group_type, 0, 0);
+        group_box.write_u32(group.group_id);
+        group_box.write_u32(group.entities.len() as u32);
+        for &entity in &group.entities {
+          group_box.write_u32(entity);
+        }
+      }
+      drop(grpl);
+    }
+
+    if use_idat {
+      // The 'idat' box sits inside the 'meta' box, and both have 32-bit sizes, so together
+      // with the rest of the metadata, the inline items must come to less than 4GB
+      let idat_size: usize = items.iter().zip(&in_idat).filter(|(_, &inline)| inline)
+                                  .map(|(item, _)| item.data.len()).sum();
+      let meta_size = meta.get_file_pos() + 8 + idat_size;
+      if meta_size > u32::MAX as usize {
+        return Err(ConfigError::TooLarge(format!("The 'meta' box would take {} bytes, more than its 4GB limit, so data this large must be stored in the 'mdat' box",
+                                                 meta_size)));
+      }
+      let mut idat = meta.open_box(b"idat");
+      for (i, item) in items.iter().enumerate() {
+        if in_idat[i] {
+          idat.write_bytes(item.data);
+        }
+      }
+      drop(idat);
+    }
   }
   drop(meta);
 
-  // Finally, the 'mdat' box contains the image data itself
-  let mut mdat = avif.open_box(b"mdat");
-  let content_pos = mdat.get_file_pos() as u32;
-  mdat.write_bytes(av1_data);
-  drop(mdat);
+  // The 'mdat' box contains everything else
+  if !use_mdat {
+    avif.append(header);
+    return Ok(avif.finalize());
+  }
 
-  avif.write_u32_at_marker(content_pos_marker, content_pos);
+  // Returns the position of the image data
+  let write_mdat = |avif: &mut ISOBMFFWriter| {
+    let mut mdat = if large { avif.open_large_box(b"mdat") } else { avif.open_box(b"mdat") };
+    let content_pos = mdat.get_file_pos();
+    for (i, item) in items.iter().enumerate() {
+      if !in_idat[i] {
+        mdat.write_bytes(item.data);
+      }
+    }
+    return content_pos;
+  };
+  let (meta_pos, content_pos) = if avif_config.mdat_first {
+    let content_pos = write_mdat(&mut avif);
+    (avif.append(header), content_pos)
+  } else {
+    let meta_pos = avif.append(header);
+    // Pad the headers out to the requested size, so that the image data starts at a
+    // known offset
+    let header_size = avif.get_file_pos();
+    if avif_config.header_size != 0 && avif_config.header_size != header_size {
+      // A 'free' box takes at least 8 bytes, for its own header
+      if header_size + 8 > avif_config.header_size {
+        return Err(ConfigError::Invalid(format!("The AVIF headers take {} bytes, so can't be padded to {} bytes",
+                                                header_size, avif_config.header_size)));
+      }
+      avif.write_free_box(avif_config.header_size - header_size);
+    }
+    (meta_pos, write_mdat(&mut avif))
+  };
+
+  for (marker, offset) in content_pos_markers {
+    if large {
+      avif.write_u64_at_marker(meta_pos + marker, (content_pos + offset) as u64);
+    } else {
+      avif.write_u32_at_marker(meta_pos + marker, (content_pos + offset) as u32);
+    }
+  }
+
+  return Ok(avif.finalize());
+}
 
-  return avif.finalize();
+fn invalid_data(msg: String) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// Extract the AV1 data for the primary item of an AVIF file, ie. the reverse of pack_avif()
+// This handles any file layout allowed by the 'iloc' box, not just the one we generate,
+// but doesn't look at the item type or properties.
+pub fn unpack_avif(data: &[u8]) -> Result<Vec<u8>, io::Error> {
+  let mut primary_item = None;
+  let mut iloc = None;
+  let mut idat = None;
+
+  // Find the 'meta' box, then the boxes we need inside it
+  let mut r = ISOBMFFReader::new(data);
+  while r.remaining() > 0 {
+    let header = r.read_box_header()?;
+    if &header.typ == b"meta" {
+      let mut meta = r.sub_reader(header.start + header.header_size, header.end());
+      meta.read_version_and_flags()?;
+      while meta.remaining() > 0 {
+        let child = meta.read_box_header()?;
+        let mut b = meta.sub_reader(child.start + child.header_size, child.end());
+        match &child.typ {
+          b"pitm" => {
+            let (version, _) = b.read_version_and_flags()?;
+            primary_item = Some(if version == 0 { b.read_u16()? as u32 } else { b.read_u32()? });
+          },
+          b"iloc" => {
+            iloc = Some(b.read_iloc()?);
+          },
+          b"idat" => {
+            idat = Some((b.pos(), child.end()));
+          },
+          _ => {}
+        }
+        meta.seek(child.end());
+      }
+    }
+    r.seek(header.end());
+  }
+
+  let Some(item_id) = primary_item else {
+    return Err(invalid_data("No primary item ('pitm' box)".into()));
+  };
+  let Some(location) = iloc.as_ref().and_then(|iloc| iloc.items.iter().find(|item| item.item_id == item_id)) else {
+    return Err(invalid_data(format!("No location information for primary item {}", item_id)));
+  };
+
+  let (base, limit) = match location.construction_method {
+    0 => (0, data.len()),
+    1 => idat.ok_or_else(|| invalid_data(format!("Item {} is stored in an 'idat' box, but there isn't one", item_id)))?,
+    method => {
+      return Err(invalid_data(format!("Item {} uses unsupported construction method {}", item_id, method)));
+    }
+  };
+
+  let mut item_data = Vec::new();
+  for &(offset, length) in &location.extents {
+    let start = base + (location.base_offset + offset) as usize;
+    let end = if length == 0 { limit } else { start + length as usize };
+    if start > limit || end > limit {
+      return Err(invalid_data(format!("Extent at offset {} with length {} extends past the end of the data", start, length)));
+    }
+    item_data.extend_from_slice(&data[start..end]);
+  }
+
+  Ok(item_data)
+}
+
+// Header fields and payload position of one OBU
+pub struct ObuInfo {
+  pub obu_type: usize,
+  pub forbidden: bool,
+  // Temporal and spatial IDs, if there is an extension header
+  pub layer: Option<(u64, u64)>,
+  // Start and end of the payload, which follows the header
+  pub start: usize,
+  pub end: usize,
+}
+
+// Parse the header of the OBU starting at `pos`
+pub fn read_obu(data: &[u8], pos: usize) -> Result<ObuInfo, io::Error> {
+  let mut r = BitReader::new(&data[pos..]);
+  let forbidden = r.read_bool()?;
+  let obu_type = r.read_bits(4)? as usize;
+  let extension_flag = r.read_bool()?;
+  let has_size_field = r.read_bool()?;
+  r.read_bit()?;
+  let mut layer = None;
+  if extension_flag {
+    let temporal_id = r.read_bits(3)?;
+    let spatial_id = r.read_bits(2)?;
+    r.read_bits(3)?;
+    layer = Some((temporal_id, spatial_id));
+  }
+  let obu_size = if has_size_field {
+    r.read_leb128()? as usize
+  } else {
+    data.len() - pos - r.byte_pos()
+  };
+  let start = pos + r.byte_pos();
+  let end = start + obu_size;
+  if end > data.len() {
+    return Err(invalid_data(format!("OBU at offset {} extends past the end of the data", pos)));
+  }
+  Ok(ObuInfo {
+    obu_type: obu_type,
+    forbidden: forbidden,
+    layer: layer,
+    start: start,
+    end: end,
+  })
+}
+
+// Replace the tile data of the frame in `av1_data` with `tile_data`, keeping every other
+// OBU as it is. This is for re-encoding part of an image (see AV1Encoder::reencode_region()),
+// so the new tile data must have been coded with the same frame header as the old:
+// `frame_header` is checked against the one in the stream, as a mismatch wouldn't decode.
+// With only one tile, this replaces the whole of the frame's tile data.
+pub fn replace_tile_data(av1_data: &[u8], frame_header: &[u8], tile_data: &[u8]) -> Result<Vec<u8>, io::Error> {
+  let mut out = Vec::with_capacity(av1_data.len());
+  let mut replaced = false;
+  let mut pos = 0;
+  while pos < av1_data.len() {
+    let obu = read_obu(av1_data, pos)?;
+    let payload = &av1_data[obu.start..obu.end];
+    let obu_type = obu.obu_type as u8;
+    let header_matches = match obu_type {
+      OBU_FRAME_HEADER => payload == frame_header,
+      OBU_FRAME => payload.starts_with(frame_header),
+      _ => true,
+    };
+    if !header_matches {
+      return Err(invalid_data(format!("Frame header at offset {} doesn't match the encoder's settings", pos)));
+    }
+
+    let extension = obu.layer.map(|(temporal_id, spatial_id)| ObuExtension {
+      temporal_id: temporal_id as u8,
+      spatial_id: spatial_id as u8,
+    });
+    match obu_type {
+      OBU_FRAME | OBU_TILE_GROUP => {
+        if replaced {
+          return Err(invalid_data(format!("Unexpected second tile group at offset {}", pos)));
+        }
+        write_obu_header(&mut out, obu_type, extension);
+        if obu_type == OBU_FRAME {
+          write_obu_size(&mut out, frame_header.len() + tile_data.len());
+          out.extend_from_slice(frame_header);
+        } else {
+          write_obu_size(&mut out, tile_data.len());
+        }
+        out.extend_from_slice(tile_data);
+        replaced = true;
+      },
+      _ => out.extend_from_slice(&av1_data[pos..obu.end]),
+    }
+    pos = obu.end;
+  }
+
+  if !replaced {
+    return Err(invalid_data("No tile data found".into()));
+  }
+  return Ok(out);
 }
@@ -0,0 +1,307 @@
+// Copyright (c) 2024-2025, The tinyavif contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+// Human-readable dumps of .avif and .obu files, for debugging interoperability problems
+//
+// For AVIF files, this prints the box tree, with the contents of the boxes we know about
+// (item info, properties, av1C, etc.), then the OBU headers of the primary image item.
+// This is intentionally lenient: anything it doesn't understand is shown as just a
+// type and size, rather than treated as an error.
+
+use std::fmt::Write as _;
+use std::io;
+
+use crate::bitcode::BitReader;
+use crate::hls::{read_obu, unpack_avif};
+use crate::isobmff::{BoxHeader, ISOBMFFReader};
+
+// Boxes which contain only other boxes
+const CONTAINER_BOXES: [&[u8; 4]; 4] = [b"iprp", b"ipco", b"dinf", b"grpl"];
+
+const OBU_TYPE_NAMES: [&str; 16] = [
+  "OBU_RESERVED_0", "OBU_SEQUENCE_HEADER", "OBU_TEMPORAL_DELIMITER", "OBU_FRAME_HEADER",
+  "OBU_TILE_GROUP", "OBU_METADATA", "OBU_FRAME", "OBU_REDUNDANT_FRAME_HEADER",
+  "OBU_TILE_LIST", "OBU_RESERVED_9", "OBU_RESERVED_10", "OBU_RESERVED_11",
+  "OBU_RESERVED_12", "OBU_RESERVED_13", "OBU_RESERVED_14", "OBU_PADDING",
+];
+
+fn invalid_data(msg: String) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// Inspect a file, appending the results to `out`
+// If an error occurs, `out` contains everything up to that point
+pub fn inspect(data: &[u8], out: &mut String) -> Result<(), io::Error> {
+  if data.len() >= 8 && &data[4..8] == b"ftyp" {
+    inspect_avif(data, out)
+  } else {
+    writeln!(out, "Raw OBU stream ({} bytes)", data.len()).unwrap();
+    inspect_obus(data, out)
+  }
+}
+
+fn inspect_avif(data: &[u8], out: &mut String) -> Result<(), io::Error> {
+  let mut r = ISOBMFFReader::new(data);
+  inspect_boxes(&mut r, data.len(), 0, out)?;
+
+  let item_data = unpack_avif(data)?;
+  writeln!(out, "\nPrimary item: {} bytes", item_data.len()).unwrap();
+  inspect_obus(&item_data, out)
+}
+
+fn inspect_boxes(r: &mut ISOBMFFReader, end: usize, depth: usize, out: &mut String) -> Result<(), io::Error> {
+  while r.pos() < end {
+    let header = r.read_box_header()?;
+    let content_start = header.start + header.header_size;
+    let mut b = r.sub_reader(content_start, header.end());
+
+    write!(out, "{}{} ({} bytes)", "  ".repeat(depth), header.typ_str(), header.size).unwrap();
+    inspect_box(&header, &mut b, depth, out)?;
+    r.seek(header.end());
+  }
+  Ok(())
+}
+
+// Print the details of a single box, then recurse into any children
+// The reader is positioned at the start of the box contents
+fn inspect_box(header: &BoxHeader, b: &mut ISOBMFFReader, depth: usize, out: &mut String) -> Result<(), io::Error> {
+  let end = header.end();
+
+  match &header.typ {
+    b"ftyp" => {
+      let major = b.read_bytes(4)?;
+      let minor = b.read_u32()?;
+      let mut brands = Vec::new();
+      while b.remaining() >= 4 {
+        brands.push(String::from_utf8_lossy(b.read_bytes(4)?).into_owned());
+      }
+      writeln!(out, ": major brand '{}', version {}, compatible brands {}",
+               String::from_utf8_lossy(major), minor, brands.join(", ")).unwrap();
+    },
+    b"meta" => {
+      let (version, flags) = b.read_version_and_flags()?;
+      writeln!(out, ": version {}, flags {}", version, flags).unwrap();
+      inspect_boxes(b, end, depth + 1, out)?;
+    },
+    b"hdlr" => {
+      b.read_version_and_flags()?;
+      b.read_u32()?; // pre_defined
+      let handler = b.read_bytes(4)?;
+      b.read_bytes(12)?; // reserved
+      let name = b.read_cstring().unwrap_or_default();
+      writeln!(out, ": handler '{}', name \"{}\"", String::from_utf8_lossy(handler), name).unwrap();
+    },
+    b"pitm" => {
+      let (version, _) = b.read_version_and_flags()?;
+      let item_id = if version == 0 { b.read_u16()? as u32 } else { b.read_u32()? };
+      writeln!(out, ": primary item {}", item_id).unwrap();
+    },
+    b"iloc" => {
+      let iloc = b.read_iloc()?;
+      writeln!(out, ": version {}, {} item(s), offset/length/base offset sizes {}/{}/{}", iloc.version,
+               iloc.items.len(), iloc.offset_size, iloc.length_size, iloc.base_offset_size).unwrap();
+      for item in &iloc.items {
+        writeln!(out, "{}item {}: construction method {}, base offset {}, extents (offset, length) {:?}",
+                 "  ".repeat(depth + 1), item.item_id, item.construction_method, item.base_offset, item.extents).unwrap();
+      }
+    },
+    b"iinf" => {
+      let (version, _) = b.read_version_and_flags()?;
+      let entry_count = if version == 0 { b.read_u16()? as u32 } else { b.read_u32()? };
+      writeln!(out, ": {} item(s)", entry_count).unwrap();
+      inspect_boxes(b, end, depth + 1, out)?;
+    },
+    b"infe" => {
+      let (version, flags) = b.read_version_and_flags()?;
+      if version < 2 {
+        writeln!(out, ": version {} (not decoded)", version).unwrap();
+      } else {
+        let item_id = if version == 2 { b.read_u16()? as u32 } else { b.read_u32()? };
+        let protection = b.read_u16()?;
+        let item_type = b.read_bytes(4)?;
+        let name = b.read_cstring().unwrap_or_default();
+        let hidden = if flags & 1 != 0 { ", hidden" } else { "" };
+        writeln!(out, ": item {}, type '{}', name \"{}\", protection index {}{}",
+                 item_id, String::from_utf8_lossy(item_type), name, protection, hidden).unwrap();
+      }
+    },
+    b"iref" => {
+      let (version, _) = b.read_version_and_flags()?;
+      writeln!(out).unwrap();
+      while b.pos() < end {
+        let ref_header = b.read_box_header()?;
+        let from_id = if version == 0 { b.read_u16()? as u32 } else { b.read_u32()? };
+        let count = b.read_u16()?;
+        let mut to_ids = Vec::new();
+        for _ in 0..count {
+          to_ids.push(if version == 0 { b.read_u16()? as u32 } else { b.read_u32()? });
+        }
+        writeln!(out, "{}'{}' reference from item {} to {:?}", "  ".repeat(depth + 1),
+                 ref_header.typ_str(), from_id, to_ids).unwrap();
+        b.seek(ref_header.end());
+      }
+    },
+    b"ispe" => {
+      b.read_version_and_flags()?;
+      let width = b.read_u32()?;
+      let height = b.read_u32()?;
+      writeln!(out, ": {}x{}", width, height).unwrap();
+    },
+    b"pixi" => {
+      b.read_version_and_flags()?;
+      let num_channels = b.read_u8()?;
+      let depths = b.read_bytes(num_channels as usize)?;
+      writeln!(out, ": {} channel(s), bit depths {:?}", num_channels, depths).unwrap();
+    },
+    b"av1C" => {
+      let marker_version = b.read_u8()?;
+      let profile_level = b.read_u8()?;
+      let flags = b.read_u8()?;
+      let delay = b.read_u8()?;
+      let config_obus = b.remaining();
+      writeln!(out, ": marker {}, version {}", marker_version >> 7, marker_version & 0x7F).unwrap();
+      let indent = "  ".repeat(depth + 1);
+      writeln!(out, "{}profile {}, level {}, tier {}", indent, profile_level >> 5, profile_level & 31, flags >> 7).unwrap();
+      writeln!(out, "{}high_bitdepth {}, twelve_bit {}, monochrome {}, subsampling {}x{}, chroma sample position {}",
+               indent, (flags >> 6) & 1, (flags >> 5) & 1, (flags >> 4) & 1, (flags >> 3) & 1, (flags >> 2) & 1, flags & 3).unwrap();
+      if delay & 0x10 != 0 {
+        writeln!(out, "{}initial presentation delay {}", indent, (delay & 15) + 1).unwrap();
+      }
+      if config_obus > 0 {
+        writeln!(out, "{}{} bytes of configuration OBUs", indent, config_obus).unwrap();
+      }
+    },
+    b"colr" => {
+      let colour_type = b.read_bytes(4)?;
+      if colour_type == b"nclx" {
+        let primaries = b.read_u16()?;
+        let transfer = b.read_u16()?;
+        let matrix = b.read_u16()?;
+        let full_range = b.read_u8()? >> 7;
+        writeln!(out, ": nclx, primaries {}, transfer {}, matrix {}, full range {}",
+                 primaries, transfer, matrix, full_range).unwrap();
+      } else {
+        writeln!(out, ": '{}', {} bytes", String::from_utf8_lossy(colour_type), b.remaining()).unwrap();
+      }
+    },
+    b"auxC" => {
+      b.read_version_and_flags()?;
+      let aux_type = b.read_cstring()?;
+      writeln!(out, ": \"{}\"", aux_type).unwrap();
+    },
+    b"ipma" => {
+      let (version, flags) = b.read_version_and_flags()?;
+      let entry_count = b.read_u32()?;
+      writeln!(out, ": {} item(s)", entry_count).unwrap();
+      for _ in 0..entry_count {
+        let item_id = if version < 1 { b.read_u16()? as u32 } else { b.read_u32()? };
+        let count = b.read_u8()?;
+        let mut associations = Vec::new();
+        for _ in 0..count {
+          let (essential, index) = if flags & 1 != 0 {
+            let value = b.read_u16()?;
+            (value >> 15, value & 0x7FFF)
+          } else {
+            let value = b.read_u8()? as u16;
+            (value >> 7, value & 0x7F)
+          };
+          associations.push(format!("{}{}", index, if essential != 0 { "!" } else { "" }));
+        }
+        writeln!(out, "{}item {}: properties {} (! = essential)", "  ".repeat(depth + 1), item_id, associations.join(", ")).unwrap();
+      }
+    },
+    typ if CONTAINER_BOXES.contains(&typ) => {
+      writeln!(out).unwrap();
+      inspect_boxes(b, end, depth + 1, out)?;
+    },
+    _ => {
+      writeln!(out).unwrap();
+    }
+  }
+
+  Ok(())
+}
+
+fn inspect_obus(data: &[u8], out: &mut String) -> Result<(), io::Error> {
+  let mut pos = 0;
+  while pos < data.len() {
+    let obu = read_obu(data, pos)?;
+    let extension = match obu.layer {
+      Some((temporal_id, spatial_id)) => format!(", temporal_id {}, spatial_id {}", temporal_id, spatial_id),
+      None => String::new(),
+    };
+
+    write!(out, "  {} ({} bytes at offset {}{}{})", OBU_TYPE_NAMES[obu.obu_type], obu.end - obu.start, obu.start,
+           extension, if obu.forbidden { ", forbidden bit set!" } else { "" }).unwrap();
+    if obu.obu_type == 1 {
+      inspect_sequence_header(&data[obu.start..obu.end], out)?;
+    }
+    writeln!(out).unwrap();
+
+    pos = obu.end;
+  }
+  Ok(())
+}
+
+// Total size of each type of OBU in a stream of OBUs, including their headers,
+// as (type name, bytes) in order of first appearance
+pub fn obu_sizes(data: &[u8]) -> Result<Vec<(&'static str, usize)>, io::Error> {
+  let mut sizes: Vec<(&'static str, usize)> = Vec::new();
+  let mut pos = 0;
+  while pos < data.len() {
+    let obu = read_obu(data, pos)?;
+    let name = OBU_TYPE_NAMES[obu.obu_type];
+    match sizes.iter_mut().find(|(n, _)| *n == name) {
+      Some((_, size)) => *size += obu.end - pos,
+      None => sizes.push((name, obu.end - pos)),
+    }
+    pos = obu.end;
+  }
+  Ok(sizes)
+}
+
+// Print the start of a sequence header. Only the reduced still picture header is decoded fully
+fn inspect_sequence_header(payload: &[u8], out: &mut String) -> Result<(), io::Error> {
+  let mut r = BitReader::new(payload);
+  let profile = r.read_bits(3)?;
+  let still_picture = r.read_bit()?;
+  let reduced_still_picture_header = r.read_bit()?;
+  write!(out, ": profile {}, still_picture {}, reduced_still_picture_header {}",
+         profile, still_picture, reduced_still_picture_header).unwrap();
+  if reduced_still_picture_header != 0 {
+    let level = r.read_bits(5)?;
+    let width_bits = r.read_bits(4)? as usize + 1;
+    let height_bits = r.read_bits(4)? as usize + 1;
+    let width = r.read_bits(width_bits)? + 1;
+    let height = r.read_bits(height_bits)? + 1;
+    write!(out, ", level {}, {}x{}", level, width, height).unwrap();
+  } else {
+    let timing_info_present = r.read_bit()?;
+    if timing_info_present != 0 {
+      let num_units_in_display_tick = r.read_bits(32)?;
+      let time_scale = r.read_bits(32)?;
+      write!(out, ", timing {}/{}", time_scale, num_units_in_display_tick).unwrap();
+      if r.read_bool()? {
+        let num_ticks_per_picture = r.read_uvlc()? as u64 + 1;
+        write!(out, " with {} tick(s) per picture", num_ticks_per_picture).unwrap();
+      }
+      let decoder_model_info_present = r.read_bit()?;
+      if decoder_model_info_present != 0 {
+        write!(out, ", decoder model present").unwrap();
+        r.read_bits(47)?; // decoder_model_info()
+      }
+    }
+    r.read_bit()?; // initial_display_delay_present_flag
+    let operating_points = r.read_bits(5)? + 1;
+    let operating_point_idc = r.read_bits(12)?;
+    let level = r.read_bits(5)?;
+    write!(out, ", {} operating point(s), first has idc 0x{:03x} and level {}", operating_points, operating_point_idc, level).unwrap();
+  }
+  Ok(())
+}
@@ -7,7 +7,9 @@
 // Media Patent License 1.0 was not distributed with this source code in the
 // PATENTS file, you can obtain it at www.aomedia.org/license/patent.
 
-use byteorder::{BigEndian, WriteBytesExt};
+use std::io;
+
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
 
 pub struct ISOBMFFWriter {
   data: Vec<u8>
@@ -17,11 +19,13 @@ pub struct ISOBMFFWriter {
 // This holds a mutable reference to the underlying data, ensuring that
 // the box must be closed (by dropping it) before any data which is
 // supposed to be outside the box can be written.
-// It also holds the index for the 4-byte length field for this box,
-// which will be filled in when the box is closed.
+// It also holds the index for the length field for this box, which will be
+// filled in when the box is closed. This is 4 bytes, or 8 bytes for boxes
+// opened with open_large_box().
 pub struct ISOBMFFBox<'a> {
   w: &'a mut ISOBMFFWriter,
-  size_pos: usize
+  size_pos: usize,
+  large: bool
 }
 
 impl ISOBMFFWriter {
@@ -41,7 +45,25 @@ impl ISOBMFFWriter {
 
     return ISOBMFFBox {
       w: self,
-      size_pos: size_pos
+      size_pos: size_pos,
+      large: false
+    };
+  }
+
+  // Open a box which may be 4GB or larger
+  // The size is coded as the special value 1, then the real size as a 64-bit value
+  pub fn open_large_box<'a>(&'a mut self, typ: &[u8]) -> ISOBMFFBox<'a> {
+    let size_pos = self.data.len();
+
+    assert!(typ.len() == 4);
+    self.data.write_u32::<BigEndian>(1).unwrap();
+    self.data.extend_from_slice(typ);
+    self.data.write_u64::<BigEndian>(0).unwrap();
+
+    return ISOBMFFBox {
+      w: self,
+      size_pos: size_pos,
+      large: true
     };
   }
 
@@ -58,7 +80,8 @@ impl ISOBMFFWriter {
 
     return ISOBMFFBox {
       w: self,
-      size_pos: size_pos
+      size_pos: size_pos,
+      large: false
     };
   }
 
@@ -66,6 +89,24 @@ impl ISOBMFFWriter {
     self.data.len()
   }
 
+  // Append the contents of a separately-built writer
+  // This allows boxes to be built in a different order from the one they appear in the file,
+  // or to be measured before deciding where they go. Returns the position the data was placed
+  // at, which must be added to any positions (eg. markers) taken from `other`.
+  pub fn append(&mut self, other: ISOBMFFWriter) -> usize {
+    let pos = self.data.len();
+    self.data.extend_from_slice(&other.data);
+    return pos;
+  }
+
+  // Write a 'free' box with the given total size, including its 8-byte header, eg. to pad
+  // the following data to an aligned position or to leave space to be filled in later
+  pub fn write_free_box(&mut self, size: usize) {
+    assert!(size >= 8);
+    let mut free = self.open_box(b"free");
+    free.write_bytes(&vec![0; size - 8]);
+  }
+
   pub fn write_u32_at_marker(&mut self, pos: usize, value: u32) {
     assert!(self.data.len() >= pos + 4);
     self.data[pos]     = ((value >> 24) & 0xFF) as u8;
@@ -74,6 +115,11 @@ impl ISOBMFFWriter {
     self.data[pos + 3] = (value         & 0xFF) as u8;
   }
 
+  pub fn write_u64_at_marker(&mut self, pos: usize, value: u64) {
+    assert!(self.data.len() >= pos + 8);
+    BigEndian::write_u64(&mut self.data[pos .. pos + 8], value);
+  }
+
   pub fn finalize(self) -> Box<[u8]> {
     return self.data.into_boxed_slice();
   }
@@ -90,7 +136,8 @@ impl<'a> ISOBMFFBox<'a> {
 
     return ISOBMFFBox {
       w: self.w,
-      size_pos: size_pos
+      size_pos: size_pos,
+      large: false
     };
   }
 
@@ -107,7 +154,8 @@ impl<'a> ISOBMFFBox<'a> {
 
     return ISOBMFFBox {
       w: self.w,
-      size_pos: size_pos
+      size_pos: size_pos,
+      large: false
     };
   }
 
@@ -121,6 +169,12 @@ impl<'a> ISOBMFFBox<'a> {
     return marker;
   }
 
+  pub fn mark_u64(&mut self) -> usize {
+    let marker = self.w.data.len();
+    self.write_u64(0);
+    return marker;
+  }
+
   pub fn write_u8(&mut self, value: u8) {
     self.w.data.write_u8(value).unwrap();
   }
@@ -133,6 +187,10 @@ impl<'a> ISOBMFFBox<'a> {
     self.w.data.write_u32::<BigEndian>(value).unwrap();
   }
 
+  pub fn write_u64(&mut self, value: u64) {
+    self.w.data.write_u64::<BigEndian>(value).unwrap();
+  }
+
   pub fn write_bytes(&mut self, value: &[u8]) {
     self.w.data.extend_from_slice(value);
   }
@@ -145,9 +203,211 @@ impl<'a> Drop for ISOBMFFBox<'a> {
     // i.e. it includes the size field itself, the type, and the version and flags if present
     let cur_pos = self.w.data.len();
     let total_size = cur_pos - self.size_pos;
+    if self.large {
+      self.w.write_u64_at_marker(self.size_pos + 8, total_size as u64);
+      return;
+    }
+    assert!(total_size <= u32::MAX as usize, "Box is too large for a 32-bit size field");
     self.w.data[self.size_pos] = ((total_size >> 24) & 0xFF) as u8;
     self.w.data[self.size_pos + 1] = ((total_size >> 16) & 0xFF) as u8;
     self.w.data[self.size_pos + 2] = ((total_size >> 8) & 0xFF) as u8;
     self.w.data[self.size_pos + 3] = (total_size & 0xFF) as u8;
   }
 }
+
+// Reader for ISOBMFF data, used when inspecting existing files
+// This just provides the primitive operations (big-endian integers and box headers);
+// interpreting the contents of each box is up to the caller.
+pub struct ISOBMFFReader<'a> {
+  data: &'a [u8],
+  pos: usize
+}
+
+pub struct BoxHeader {
+  pub typ: [u8; 4],
+  // Position of the start of the box, ie. of the size field
+  pub start: usize,
+  // Total size of the box, including the header
+  pub size: usize,
+  // Size of the header (8 or 16 bytes), not including any version and flags
+  pub header_size: usize,
+}
+
+impl BoxHeader {
+  pub fn typ_str(&self) -> String {
+    String::from_utf8_lossy(&self.typ).into_owned()
+  }
+
+  pub fn end(&self) -> usize {
+    self.start + self.size
+  }
+}
+
+// Contents of an 'iloc' (item location) box
+pub struct ItemLocationBox {
+  pub version: u8,
+  pub offset_size: usize,
+  pub length_size: usize,
+  pub base_offset_size: usize,
+  pub items: Vec<ItemLocation>,
+}
+
+pub struct ItemLocation {
+  pub item_id: u32,
+  // 0 = offsets are relative to the start of the file, 1 = relative to the 'idat' box
+  pub construction_method: u16,
+  pub base_offset: u64,
+  // (offset, length) of each extent. A length of 0 means "to the end of the data"
+  pub extents: Vec<(u64, u64)>,
+}
+
+fn eof_error() -> io::Error {
+  io::Error::new(io::ErrorKind::UnexpectedEof, "Unexpected end of data")
+}
+
+impl<'a> ISOBMFFReader<'a> {
+  pub fn new(data: &'a [u8]) -> Self {
+    Self {
+      data: data,
+      pos: 0
+    }
+  }
+
+  // Create a reader over a sub-range of the data, eg. the contents of one box
+  // Positions reported by the new reader are still relative to the start of `data`
+  pub fn sub_reader(&self, start: usize, end: usize) -> Self {
+    Self {
+      data: &self.data[..end],
+      pos: start
+    }
+  }
+
+  pub fn pos(&self) -> usize {
+    self.pos
+  }
+
+  pub fn remaining(&self) -> usize {
+    self.data.len() - self.pos
+  }
+
+  pub fn seek(&mut self, pos: usize) {
+    self.pos = pos;
+  }
+
+  pub fn read_bytes(&mut self, n: usize) -> Result<&'a [u8], io::Error> {
+    if self.remaining() < n {
+      return Err(eof_error());
+    }
+    let bytes = &self.data[self.pos .. self.pos + n];
+    self.pos += n;
+    Ok(bytes)
+  }
+
+  pub fn read_u8(&mut self) -> Result<u8, io::Error> {
+    Ok(self.read_bytes(1)?[0])
+  }
+
+  pub fn read_u16(&mut self) -> Result<u16, io::Error> {
+    Ok(BigEndian::read_u16(self.read_bytes(2)?))
+  }
+
+  pub fn read_u32(&mut self) -> Result<u32, io::Error> {
+    Ok(BigEndian::read_u32(self.read_bytes(4)?))
+  }
+
+  pub fn read_u64(&mut self) -> Result<u64, io::Error> {
+    Ok(BigEndian::read_u64(self.read_bytes(8)?))
+  }
+
+  // Read an unsigned integer of 0, 4, or 8 bytes, as used in the 'iloc' box
+  pub fn read_sized(&mut self, nbytes: usize) -> Result<u64, io::Error> {
+    match nbytes {
+      0 => Ok(0),
+      4 => Ok(self.read_u32()? as u64),
+      8 => self.read_u64(),
+      _ => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid field size {}", nbytes))),
+    }
+  }
+
+  // Read a null-terminated string
+  pub fn read_cstring(&mut self) -> Result<String, io::Error> {
+    let rest = &self.data[self.pos..];
+    let len = rest.iter().position(|&b| b == 0).ok_or_else(eof_error)?;
+    let s = String::from_utf8_lossy(&rest[..len]).into_owned();
+    self.pos += len + 1;
+    Ok(s)
+  }
+
+  // Read version and flags, for boxes which have them
+  pub fn read_version_and_flags(&mut self) -> Result<(u8, u32), io::Error> {
+    let value = self.read_u32()?;
+    Ok(((value >> 24) as u8, value & 0xFFFFFF))
+  }
+
+  pub fn read_box_header(&mut self) -> Result<BoxHeader, io::Error> {
+    let start = self.pos;
+    let mut size = self.read_u32()? as usize;
+    let typ: [u8; 4] = self.read_bytes(4)?.try_into().unwrap();
+    let mut header_size = 8;
+    if size == 1 {
+      // 64-bit size
+      size = self.read_u64()? as usize;
+      header_size = 16;
+    } else if size == 0 {
+      // Box extends to the end of the data
+      size = self.data.len() - start;
+    }
+    if size < header_size || start + size > self.data.len() {
+      return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                format!("Box '{}' at offset {} has invalid size {}", String::from_utf8_lossy(&typ), start, size)));
+    }
+    Ok(BoxHeader {
+      typ: typ,
+      start: start,
+      size: size,
+      header_size: header_size,
+    })
+  }
+
+  // Parse the contents of an 'iloc' box, starting just after the box header
+  pub fn read_iloc(&mut self) -> Result<ItemLocationBox, io::Error> {
+    let (version, _) = self.read_version_and_flags()?;
+    let sizes = self.read_u8()?;
+    let offset_size = (sizes >> 4) as usize;
+    let length_size = (sizes & 15) as usize;
+    let sizes = self.read_u8()?;
+    let base_offset_size = (sizes >> 4) as usize;
+    let index_size = if version >= 1 { (sizes & 15) as usize } else { 0 };
+    let item_count = if version < 2 { self.read_u16()? as u32 } else { self.read_u32()? };
+
+    let mut items = Vec::new();
+    for _ in 0..item_count {
+      let item_id = if version < 2 { self.read_u16()? as u32 } else { self.read_u32()? };
+      let construction_method = if version >= 1 { self.read_u16()? & 15 } else { 0 };
+      self.read_u16()?; // data_reference_index
+      let base_offset = self.read_sized(base_offset_size)?;
+      let extent_count = self.read_u16()?;
+      let mut extents = Vec::new();
+      for _ in 0..extent_count {
+        self.read_sized(index_size)?; // extent_index
+        let offset = self.read_sized(offset_size)?;
+        let length = self.read_sized(length_size)?;
+        extents.push((offset, length));
+      }
+      items.push(ItemLocation {
+        item_id: item_id,
+        construction_method: construction_method,
+        base_offset: base_offset,
+        extents: extents,
+      });
+    }
+
+    Ok(ItemLocationBox {
+      version: version,
+      offset_size: offset_size,
+      length_size: length_size,
+      base_offset_size: base_offset_size,
+      items: items,
+    })
+  }
+}
@@ -0,0 +1,121 @@
+// Copyright (c) 2024-2025, The tinyavif contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+// AV1 levels and tiers, as defined in Annex A of the spec
+//
+// Levels are named "X.Y", and are coded as seq_level_idx = (X - 2) * 4 + Y.
+// The special value 31 means that no level constraints apply.
+//
+// By default ("auto"), we signal the lowest level which the image fits in, as some
+// hardware decoders refuse to decode streams with level 31.
+
+pub const LEVEL_AUTO: &str = "auto";
+
+use crate::config::{ConfigError, EncoderConfig};
+
+pub const SEQ_LEVEL_MAX: u8 = 31;
+
+// The first level which has a high tier
+pub const MIN_HIGH_TIER_LEVEL: u8 = 8; // Level 4.0
+
+pub struct LevelLimits {
+  pub seq_level_idx: u8,
+  pub max_pic_size: usize,
+  pub max_h_size: usize,
+  pub max_v_size: usize,
+  pub max_tiles: usize,
+  pub max_tile_cols: usize,
+}
+
+// Limits for each defined level. Levels which the spec reserves are not listed
+pub const LEVEL_LIMITS: [LevelLimits; 14] = [
+  LevelLimits { seq_level_idx: 0,  max_pic_size: 147456,   max_h_size: 2048,  max_v_size: 1152, max_tiles: 8,   max_tile_cols: 4 },
+  LevelLimits { seq_level_idx: 1,  max_pic_size: 278784,   max_h_size: 2816,  max_v_size: 1584, max_tiles: 8,   max_tile_cols: 4 },
+  LevelLimits { seq_level_idx: 4,  max_pic_size: 665856,   max_h_size: 4352,  max_v_size: 2448, max_tiles: 16,  max_tile_cols: 6 },
+  LevelLimits { seq_level_idx: 5,  max_pic_size: 1065024,  max_h_size: 5504,  max_v_size: 3096, max_tiles: 16,  max_tile_cols: 6 },
+  LevelLimits { seq_level_idx: 8,  max_pic_size: 2359296,  max_h_size: 6144,  max_v_size: 3456, max_tiles: 32,  max_tile_cols: 8 },
+  LevelLimits { seq_level_idx: 9,  max_pic_size: 2359296,  max_h_size: 6144,  max_v_size: 3456, max_tiles: 32,  max_tile_cols: 8 },
+  LevelLimits { seq_level_idx: 12, max_pic_size: 8912896,  max_h_size: 8192,  max_v_size: 4352, max_tiles: 64,  max_tile_cols: 8 },
+  LevelLimits { seq_level_idx: 13, max_pic_size: 8912896,  max_h_size: 8192,  max_v_size: 4352, max_tiles: 64,  max_tile_cols: 8 },
+  LevelLimits { seq_level_idx: 14, max_pic_size: 8912896,  max_h_size: 8192,  max_v_size: 4352, max_tiles: 64,  max_tile_cols: 8 },
+  LevelLimits { seq_level_idx: 15, max_pic_size: 8912896,  max_h_size: 8192,  max_v_size: 4352, max_tiles: 64,  max_tile_cols: 8 },
+  LevelLimits { seq_level_idx: 16, max_pic_size: 35651584, max_h_size: 16384, max_v_size: 8704, max_tiles: 128, max_tile_cols: 16 },
+  LevelLimits { seq_level_idx: 17, max_pic_size: 35651584, max_h_size: 16384, max_v_size: 8704, max_tiles: 128, max_tile_cols: 16 },
+  LevelLimits { seq_level_idx: 18, max_pic_size: 35651584, max_h_size: 16384, max_v_size: 8704, max_tiles: 128, max_tile_cols: 16 },
+  LevelLimits { seq_level_idx: 19, max_pic_size: 35651584, max_h_size: 16384, max_v_size: 8704, max_tiles: 128, max_tile_cols: 16 },
+];
+
+// Parse a level name, either "X.Y", "X" (meaning X.0), or "max"
+pub fn parse_level(name: &str) -> Option<u8> {
+  if name == "max" {
+    return Some(SEQ_LEVEL_MAX);
+  }
+  let (major, minor) = name.split_once('.').unwrap_or((name, "0"));
+  let major: u8 = major.parse().ok()?;
+  let minor: u8 = minor.parse().ok()?;
+  if !(2..=7).contains(&major) || minor > 3 {
+    return None;
+  }
+  let seq_level_idx = (major - 2) * 4 + minor;
+  return level_limits(seq_level_idx).map(|_| seq_level_idx);
+}
+
+pub fn level_name(seq_level_idx: u8) -> String {
+  if seq_level_idx == SEQ_LEVEL_MAX {
+    "max".into()
+  } else {
+    format!("{}.{}", 2 + seq_level_idx / 4, seq_level_idx % 4)
+  }
+}
+
+pub fn level_limits(seq_level_idx: u8) -> Option<&'static LevelLimits> {
+  LEVEL_LIMITS.iter().find(|limits| limits.seq_level_idx == seq_level_idx)
+}
+
+fn fits_level(limits: &LevelLimits, width: usize, height: usize, tile_cols: usize, tile_rows: usize) -> bool {
+  width * height <= limits.max_pic_size &&
+    width <= limits.max_h_size &&
+    height <= limits.max_v_size &&
+    tile_cols * tile_rows <= limits.max_tiles &&
+    tile_cols <= limits.max_tile_cols
+}
+
+// Lowest level which allows an image of the given size, or SEQ_LEVEL_MAX if none does
+pub fn min_level(width: usize, height: usize, tile_cols: usize, tile_rows: usize) -> u8 {
+  match LEVEL_LIMITS.iter().find(|l| fits_level(l, width, height, tile_cols, tile_rows)) {
+    Some(l) => l.seq_level_idx,
+    None => SEQ_LEVEL_MAX,
+  }
+}
+
+// Check that an image of the given size can be encoded within the configured level.
+// This is the single place where level constraints are checked; the resulting level
+// and tier are written to both the sequence header and the av1C box.
+pub fn check_level(config: &EncoderConfig, width: usize, height: usize) -> Result<(), ConfigError> {
+  let seq_level_idx = config.seq_level_idx(width, height);
+  if seq_level_idx == SEQ_LEVEL_MAX {
+    return Ok(());
+  }
+  let limits = level_limits(seq_level_idx).unwrap();
+
+  // TODO: Also check the compressed size against the minimum compression ratio,
+  // once we have rate control which could do something about it
+  if fits_level(limits, width, height, config.tile_cols, config.tile_rows) {
+    return Ok(());
+  }
+
+  let problem = format!("Image size {}x{} with {}x{} tiles exceeds the limits of level {} (at most {} pixels, {}x{}, {} tiles, {} tile columns)",
+                        width, height, config.tile_cols, config.tile_rows, level_name(seq_level_idx),
+                        limits.max_pic_size, limits.max_h_size, limits.max_v_size, limits.max_tiles, limits.max_tile_cols);
+  let suggestion = match LEVEL_LIMITS.iter().find(|l| fits_level(l, width, height, config.tile_cols, config.tile_rows)) {
+    Some(l) => format!("use --level {} or higher", level_name(l.seq_level_idx)),
+    None => "no defined level allows this, so use --level max".into(),
+  };
+  Err(ConfigError::TooLarge(format!("{}; {}", problem, suggestion)))
+}
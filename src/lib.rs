@@ -0,0 +1,92 @@
+// Copyright (c) 2024-2025, The tinyavif contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+#![allow(dead_code)]
+#![allow(unused_variables)]
+#![allow(unused_imports)]
+#![allow(unreachable_code)]
+
+// Disable name styling checks, so that we can name things in line with the AV1 spec
+#![allow(non_camel_case_types)]
+#![allow(non_snake_case)]
+#![allow(non_upper_case_globals)]
+
+// Similarly, the code here is deliberately written to mirror the structure of the spec
+// and of libaom, so disable clippy lints which would fight against that style
+#![allow(clippy::needless_return)]
+#![allow(clippy::redundant_field_names)]
+#![allow(clippy::needless_range_loop)]
+#![allow(clippy::precedence)]
+#![allow(clippy::assign_op_pattern)]
+#![allow(clippy::manual_range_contains)]
+#![allow(clippy::manual_is_multiple_of)]
+#![allow(clippy::needless_borrow)]
+#![allow(clippy::unnecessary_cast)]
+#![allow(clippy::type_complexity)]
+#![allow(clippy::upper_case_acronyms)]
+#![allow(clippy::write_with_newline)]
+#![allow(clippy::new_without_default)]
+#![allow(clippy::too_many_arguments)]
+
+// Public API: configuration, input/output formats, and the encoder itself
+pub mod analysis;
+pub mod array2d;
+pub mod av1_encoder;
+pub mod buildinfo;
+pub mod chromaconv;
+pub mod classify;
+pub mod colorconv;
+pub mod config;
+pub mod debugviz;
+pub mod decode;
+pub mod enums;
+pub mod frame;
+pub mod gainmap;
+pub mod hls;
+pub mod inspect;
+pub mod levels;
+pub mod memory;
+pub mod metrics;
+pub mod pgm;
+pub mod png;
+pub mod prefilter;
+pub mod scaling;
+pub mod stats;
+pub mod threads;
+pub mod y4m;
+
+// Support for the command line tool: self-tests, benchmarks, the conformance runner and
+// progress display. These need files, processes and a clock, which targets such as
+// wasm32-unknown-unknown don't have, so they're only built with the `cli` feature
+#[cfg(feature = "cli")]
+pub mod bench;
+#[cfg(feature = "cli")]
+pub mod conformance;
+#[cfg(feature = "cli")]
+pub mod progress;
+#[cfg(feature = "cli")]
+pub mod selftest;
+#[cfg(feature = "cli")]
+pub mod testutil;
+
+// Internal building blocks
+mod bitcode;
+mod cdf;
+mod consts;
+mod entropycode;
+mod isobmff;
+mod palette;
+mod ratecost;
+mod recon;
+mod roi;
+mod segmentation;
+mod simd;
+mod tile;
+mod txfm;
+mod util;
@@ -7,143 +7,1803 @@
 // Media Patent License 1.0 was not distributed with this source code in the
 // PATENTS file, you can obtain it at www.aomedia.org/license/patent.
 
-#![allow(dead_code)]
-#![allow(unused_variables)]
-#![allow(unused_imports)]
-#![allow(unreachable_code)]
-
-// Disable name styling checks, so that we can name things in line with the AV1 spec
-#![allow(non_camel_case_types)]
-#![allow(non_snake_case)]
-#![allow(non_upper_case_globals)]
-
-mod array2d;
-mod av1_encoder;
-mod bitcode;
-mod cdf;
-mod consts;
-mod entropycode;
-mod enums;
-mod frame;
-mod hls;
-mod isobmff;
-mod recon;
-mod txfm;
-mod util;
-mod y4m;
+// See lib.rs for the reasoning behind these
+#![allow(clippy::needless_return)]
+#![allow(clippy::redundant_field_names)]
 
+use std::fmt::Write as _;
 use std::io::prelude::*;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::IsTerminal;
 use std::fs::File;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
-use crate::av1_encoder::AV1Encoder;
-use crate::hls::*;
-use crate::y4m::Y4MReader;
+use tinyavif::analysis::FirstPassStats;
+use tinyavif::av1_encoder::{check_memory, check_streaming, encode_plane_item, search_qindex, AV1Encoder, EncodeError,
+                            EncodedImage, QualityTarget};
+use tinyavif::bench::{run_benchmarks, BENCHMARKS};
+use tinyavif::buildinfo::build_info;
+use tinyavif::chromaconv::ChromaConversion;
+use tinyavif::classify::detect_tune;
+use tinyavif::colorconv::ColorConversion;
+use tinyavif::config::{quality_to_qindex, AV1Profile, ChromaFilter, ChromaSamplePosition, ChromaSubsampling,
+                       ColorPreset, ConfigError, EncoderConfig, ObuLayout, Tier, Tune, MAX_QUALITY};
+use tinyavif::conformance::{check_case, default_corpus, load_corpus, DecoderKind, ExternalDecoder, DEFAULT_QINDEXES};
+use tinyavif::debugviz::render_partition_overlay;
+use tinyavif::decode::{decode_obus, DecodeError};
+use tinyavif::frame::{Frame, Plane};
+use tinyavif::gainmap::GainMapMetadata;
+use tinyavif::hls::*;
+use tinyavif::inspect::{inspect, obu_sizes};
+use tinyavif::metrics::{frame_psnr, plane_ssim};
+use tinyavif::pgm::read_pgm;
+use tinyavif::png::write_png;
+use tinyavif::progress::ProgressTracker;
+use tinyavif::scaling::{downscale_2x, fit_within, resize};
+use tinyavif::selftest::run_self_tests;
+use tinyavif::stats::*;
+use tinyavif::threads::ThreadPool;
+use tinyavif::y4m::Deinterlace;
+use tinyavif::y4m::Y4MError;
+use tinyavif::y4m::Y4MWriter;
+use tinyavif::y4m::Y4MReader;
 
-use clap::Parser;
+use clap::{Args, Parser, Subcommand};
+
+// Exit codes, one per category of failure, so that scripts can tell what went wrong
+// without parsing the error message. These are part of the command line interface,
+// so existing values must not be renumbered.
+#[derive(Clone, Copy)]
+enum ErrorKind {
+  // Something went wrong inside tinyavif, eg. the output failed verification.
+  // These are bugs, and also cover any panics
+  Internal = 1,
+  // Invalid command line arguments or encoder settings. This is the same code
+  // which clap uses for the errors it detects itself
+  Usage = 2,
+  // The input file is malformed
+  InvalidInput = 3,
+  // The input and settings are valid, but need features tinyavif doesn't support yet
+  Unsupported = 4,
+  // Failed to read or write a file
+  Io = 5,
+  // The image is larger than AV1, the encoder, or the chosen level allows. Unlike usage
+  // errors, these can be fixed by scaling the image down, eg. with --max-dimension
+  SizeLimit = 6,
+}
+
+impl ErrorKind {
+  fn name(self) -> &'static str {
+    match self {
+      ErrorKind::Internal => "internal",
+      ErrorKind::Usage => "usage",
+      ErrorKind::InvalidInput => "invalid_input",
+      ErrorKind::Unsupported => "unsupported",
+      ErrorKind::Io => "io",
+      ErrorKind::SizeLimit => "size_limit",
+    }
+  }
+}
+
+impl From<&ConfigError> for ErrorKind {
+  fn from(e: &ConfigError) -> Self {
+    match e {
+      ConfigError::Io(_) => ErrorKind::Io,
+      ConfigError::Parse(_) | ConfigError::Invalid(_) => ErrorKind::Usage,
+      ConfigError::Unsupported(_) => ErrorKind::Unsupported,
+      ConfigError::TooLarge(_) => ErrorKind::SizeLimit,
+    }
+  }
+}
+
+impl From<&DecodeError> for ErrorKind {
+  fn from(e: &DecodeError) -> Self {
+    match e {
+      DecodeError::Io(_) => ErrorKind::Io,
+      DecodeError::Invalid(_) => ErrorKind::InvalidInput,
+      DecodeError::Unsupported(_) => ErrorKind::Unsupported,
+    }
+  }
+}
+
+// Errors from parsing a file: malformed data is the input's fault,
+// anything else is a problem reading it
+fn parse_error_kind(e: &std::io::Error) -> ErrorKind {
+  match e.kind() {
+    std::io::ErrorKind::InvalidData | std::io::ErrorKind::UnexpectedEof => ErrorKind::InvalidInput,
+    std::io::ErrorKind::Unsupported => ErrorKind::Unsupported,
+    _ => ErrorKind::Io,
+  }
+}
+
+fn y4m_error_kind(e: &Y4MError) -> ErrorKind {
+  match e {
+    Y4MError::Io(e) => parse_error_kind(e),
+    Y4MError::Interlaced { .. } => ErrorKind::Unsupported,
+    _ => ErrorKind::InvalidInput,
+  }
+}
+
+// Message for an error reading the input image, pointing at the option which handles it
+fn input_error_message(e: &Y4MError) -> String {
+  match e {
+    Y4MError::Interlaced { .. } => format!("{} (see --deinterlace)", e),
+    _ => e.to_string(),
+  }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ErrorFormat {
+  Text,
+  Json,
+}
+
+impl FromStr for ErrorFormat {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "text" => Ok(ErrorFormat::Text),
+      "json" => Ok(ErrorFormat::Json),
+      _ => Err(format!("Unknown error format \"{}\", must be \"text\" or \"json\"", s)),
+    }
+  }
+}
+
+// Options shared by the main command and all subcommands
+#[derive(Args)]
+struct ErrorArgs {
+  /// How to report errors: "text" prints a message, "json" prints a single JSON
+  /// object to stderr, with the exit code, category and message [default: text]
+  #[arg(long)]
+  error_format: Option<ErrorFormat>,
+}
+
+// Set once at startup, before the command line is fully parsed, so that even
+// errors from the argument parser itself can be reported as JSON
+static JSON_ERRORS: AtomicBool = AtomicBool::new(false);
+
+// Look for --error-format ahead of the real argument parsing
+fn scan_error_format() -> ErrorFormat {
+  let args: Vec<String> = std::env::args().collect();
+  for (i, arg) in args.iter().enumerate() {
+    let value = if arg == "--error-format" {
+      args.get(i + 1).map(|v| v.as_str())
+    } else {
+      arg.strip_prefix("--error-format=")
+    };
+    if let Some(format) = value.and_then(|v| v.parse().ok()) {
+      return format;
+    }
+  }
+  return ErrorFormat::Text;
+}
+
+fn json_escape(s: &str) -> String {
+  let mut escaped = String::new();
+  for c in s.chars() {
+    match c {
+      '"' => escaped.push_str("\\\""),
+      '\\' => escaped.push_str("\\\\"),
+      '\n' => escaped.push_str("\\n"),
+      '\r' => escaped.push_str("\\r"),
+      '\t' => escaped.push_str("\\t"),
+      c if (c as u32) < 0x20 => { write!(escaped, "\\u{:04x}", c as u32).unwrap(); },
+      c => escaped.push(c),
+    }
+  }
+  return escaped;
+}
+
+fn report_error(kind: ErrorKind, message: &str) {
+  if JSON_ERRORS.load(Ordering::Relaxed) {
+    eprintln!("{{\"error\": {{\"code\": {}, \"category\": \"{}\", \"message\": \"{}\"}}}}",
+              kind as i32, kind.name(), json_escape(message));
+  } else {
+    println!("Error: {}", message);
+  }
+}
+
+// Report an error and exit with the code for its category
+fn fail(kind: ErrorKind, message: String) -> ! {
+  report_error(kind, &message);
+  exit(kind as i32);
+}
+
+// Parse the arguments for the main command or a subcommand, reporting any
+// problems in the selected error format
+fn parse_args<T: Parser, I: IntoIterator<Item = String>>(args: I) -> T {
+  T::try_parse_from(args).unwrap_or_else(|e| {
+    // --help and --version also come through here, and aren't errors
+    if !e.use_stderr() || !JSON_ERRORS.load(Ordering::Relaxed) {
+      e.exit();
+    }
+    let message = e.to_string();
+    let first_line = message.lines().next().unwrap_or("");
+    fail(ErrorKind::Usage, first_line.trim_start_matches("error: ").to_string());
+  })
+}
+
+// Parse a --chroma-qoffset value, either "N" for both coefficient types or "DC,AC"
+fn parse_chroma_qoffset(value: &str) -> Result<(i8, i8), String> {
+  let parse_one = |s: &str| s.trim().parse::<i8>().map_err(|e| format!("invalid offset \"{}\": {}", s, e));
+  match value.split_once(',') {
+    None => {
+      let offset = parse_one(value)?;
+      Ok((offset, offset))
+    },
+    Some((dc, ac)) => Ok((parse_one(dc)?, parse_one(ac)?)),
+  }
+}
+
+// Parse a four-character file brand, eg. "avif"
+fn parse_brand(value: &str) -> Result<[u8; 4], String> {
+  let invalid = || format!("invalid brand \"{}\", brands are four ASCII characters", value);
+  let brand: [u8; 4] = value.as_bytes().try_into().map_err(|_| invalid())?;
+  if !brand.iter().all(|c| c.is_ascii_graphic() || *c == b' ') {
+    return Err(invalid());
+  }
+  Ok(brand)
+}
+
+// Parse a name to be stored as a null-terminated string in an AVIF box
+fn parse_box_name(value: &str) -> Result<String, String> {
+  if value.contains('\0') {
+    return Err("names can't contain null characters".into());
+  }
+  Ok(value.into())
+}
+
+// Parse a --max-memory value: a number of bytes, optionally followed by K, M or G for
+// units of 1024 bytes, 1024 KiB or 1024 MiB
+fn parse_byte_size(value: &str) -> Result<usize, String> {
+  let invalid = || format!("expected a number of bytes, eg. 512M or 2G, got \"{}\"", value);
+  let (number, scale) = match value.char_indices().last() {
+    Some((i, 'K' | 'k')) => (&value[..i], 1 << 10),
+    Some((i, 'M' | 'm')) => (&value[..i], 1 << 20),
+    Some((i, 'G' | 'g')) => (&value[..i], 1 << 30),
+    _ => (value, 1),
+  };
+  let number = number.parse::<usize>().map_err(|_| invalid())?;
+  let bytes = number.checked_mul(scale).ok_or_else(invalid)?;
+  if bytes == 0 {
+    return Err("the memory limit must be greater than zero".into());
+  }
+  Ok(bytes)
+}
+
+// Parse a --resize value of the form "WxH"
+fn parse_size(value: &str) -> Result<(usize, usize), String> {
+  let invalid = || format!("expected WIDTHxHEIGHT, eg. 1920x1080, got \"{}\"", value);
+  let (width, height) = value.split_once('x').ok_or_else(invalid)?;
+  let width = width.parse::<usize>().map_err(|_| invalid())?;
+  let height = height.parse::<usize>().map_err(|_| invalid())?;
+  if width == 0 || height == 0 || width > 65536 || height > 65536 {
+    return Err(format!("size {}x{} is out of range, each dimension must be between 1 and 65536", width, height));
+  }
+  Ok((width, height))
+}
+
+// Frame rate as "N" or "N/D" frames per second, returned as (time_scale, num_units_in_display_tick)
+fn parse_frame_rate(value: &str) -> Result<(u32, u32), String> {
+  let invalid = || format!("expected a frame rate as N or N/D, eg. 25 or 30000/1001, got \"{}\"", value);
+  let (num, den) = value.split_once('/').unwrap_or((value, "1"));
+  let num = num.parse::<u32>().map_err(|_| invalid())?;
+  let den = den.parse::<u32>().map_err(|_| invalid())?;
+  if num == 0 || den == 0 {
+    return Err(format!("frame rate {} must be greater than zero", value));
+  }
+  Ok((num, den))
+}
 
 #[derive(Parser)]
-#[command(override_usage = "tinyavif <INPUT> [-o <OUTPUT>] [--qindex <QINDEX>]")]
-struct CommandlineArgs {
+#[command(name = "tinyavif", arg_required_else_help = true, subcommand_value_name = "COMMAND",
+          after_help = "Running `tinyavif <INPUT> [OPTIONS]` with no command is the same as `tinyavif encode`")]
+struct Cli {
+  #[command(subcommand)]
+  command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+  /// Encode a Y4M image to AVIF, IVF or raw AV1 (the default command)
+  // Boxed, as it has far more options than the other commands
+  Encode(Box<EncodeArgs>),
+  /// Decode a file produced by tinyavif back to Y4M
+  Decode(DecodeArgs),
+  /// Print the structure of an AVIF or AV1 file
+  Inspect(InspectArgs),
+  /// Compare an image against a reference, giving its PSNR and SSIM
+  Metrics(MetricsArgs),
+  /// Time the encoder's hot spots
+  Bench(BenchArgs),
+  /// Check the encoder's output against reference decoders
+  Conformance(ConformanceArgs),
+  /// Run the built-in checks of the entropy coder and encoder output
+  Selftest(SelftestArgs),
+}
+
+// Names of the subcommands. Anything else in the position of the subcommand is taken
+// as the start of the arguments to `encode`
+const COMMAND_NAMES: [&str; 8] = ["encode", "decode", "inspect", "metrics", "bench", "conformance", "selftest", "help"];
+
+// Arguments for `tinyavif [encode] <INPUT>`
+#[derive(Args)]
+#[command(override_usage = "tinyavif [encode] <INPUT> [-o <OUTPUT>] [--qindex <QINDEX>]")]
+struct EncodeArgs {
   /// Input file, must end in .y4m
+  #[arg(required_unless_present = "capabilities")]
+  input: Option<PathBuf>,
+  /// Output file, must end in .obu, .ivf or .avif [default: <input>.avif]
+  #[arg(short, long)]
+  output: Option<PathBuf>,
+  /// Quantizer to use. Valid range is 1-255, inclusive [default: 35]
+  #[arg(short, long)]
+  qindex: Option<u8>,
+  /// Quality from 0 (worst) to 100 (best), as an alternative to --qindex. The mapping
+  /// depends on --tune, as artifacts are more visible in screen content
+  #[arg(long, conflicts_with = "qindex")]
+  quality: Option<u8>,
+  /// Pick the highest qindex which gives at least this luma PSNR (in dB), using
+  /// several test encodes
+  #[arg(long, conflicts_with_all = ["qindex", "quality", "stream"])]
+  target_psnr: Option<f64>,
+  /// Pick the highest qindex which gives at least this luma SSIM (from 0 to 1), using
+  /// several test encodes
+  #[arg(long, conflicts_with_all = ["qindex", "quality", "stream", "target_psnr"])]
+  target_ssim: Option<f64>,
+  /// Set the colour primaries, transfer function and matrix coefficients together for a
+  /// common colour space: srgb (1/13/6), bt709 (1/1/1), p3 (12/13/6) or rec2020-pq (9/16/9)
+  #[arg(long, value_name = "PRESET", conflicts_with_all = ["color_primaries", "transfer_function", "matrix_coefficients"])]
+  cicp: Option<ColorPreset>,
+  /// Color primaries [default: 2]
+  #[arg(long)]
+  color_primaries: Option<u16>,
+  /// Transfer function [default: 2]
+  #[arg(long)]
+  transfer_function: Option<u16>,
+  /// Matrix coefficients [default: 2]
+  #[arg(long)]
+  matrix_coefficients: Option<u16>,
+  /// Only write the colour description into the AVIF container, not the AV1 sequence
+  /// header, eg. to match the output of older versions
+  #[arg(long)]
+  no_obu_color_description: bool,
+  /// Position of the chroma samples relative to luma: unknown, vertical (MPEG-2 style)
+  /// or colocated [default: unknown]
+  #[arg(long)]
+  chroma_sample_position: Option<ChromaSamplePosition>,
+  /// Filter used to downsample the chroma of 4:2:2 and 4:4:4 input to 4:2:0: box,
+  /// bilinear or lanczos [default: bilinear]
+  #[arg(long)]
+  chroma_filter: Option<ChromaFilter>,
+  /// Signal that pixel values use the full 0-255 range, as in screenshots and sRGB
+  /// images, rather than the limited "TV" range
+  #[arg(long)]
+  full_range: bool,
+  /// Signal that the image should be displayed at this size, rather than at its own size.
+  /// This is only written into the AV1 frame header, which many players ignore
+  #[arg(long, value_name = "WxH", value_parser = parse_size)]
+  render_size: Option<(usize, usize)>,
+  /// Code the image at a reduced width of 8/N times its real width, from 9 to 16, and have
+  /// the decoder upscale it horizontally. This saves bits on images with little
+  /// horizontal detail. 8 turns superres off [default: 8]
+  #[arg(long, value_name = "N")]
+  superres_denominator: Option<u8>,
+  /// AV1 level to conform to, eg. 5.1, "auto" for the lowest level which fits the image,
+  /// or "max" for no constraints [default: auto]
+  #[arg(long)]
+  level: Option<String>,
+  /// AV1 tier: main or high [default: main]. The high tier needs --full-headers, and
+  /// level 4.0 or higher
+  #[arg(long)]
+  tier: Option<Tier>,
+  /// AV1 profile to signal: main, high, or professional (or 0, 1, 2), or auto for the
+  /// lowest one which supports the bit depth and chroma subsampling [default: auto]
+  #[arg(long, value_name = "PROFILE")]
+  av1_profile: Option<AV1Profile>,
+  /// Tag the frame with an OBU extension header, with this temporal layer ID (0-7).
+  /// Useful when the image is to be embedded into a layered AV1 stream
+  #[arg(long)]
+  temporal_id: Option<u8>,
+  /// Tag the frame with an OBU extension header, with this spatial layer ID (0-3)
+  #[arg(long)]
+  spatial_id: Option<u8>,
+  /// Signal timing info in the sequence header, with this frame rate (N or N/D frames per
+  /// second). Some players need this to handle image sequences
+  #[arg(long, value_name = "RATE", value_parser = parse_frame_rate)]
+  timing_info: Option<(u32, u32)>,
+  /// Also describe the decoder buffer model in the sequence header. Requires --timing-info
+  #[arg(long, requires = "timing_info")]
+  decoder_model: bool,
+  /// Use the full sequence and frame headers, rather than the simplified still picture
+  /// ones, for decoders which don't handle those properly
+  #[arg(long)]
+  full_headers: bool,
+  /// How to split the frame into OBUs: frame (one OBU_FRAME), or separate
+  /// (OBU_FRAME_HEADER + OBU_TILE_GROUP) [default: frame]
+  #[arg(long)]
+  obu_layout: Option<ObuLayout>,
+  /// Major brand for the AVIF file's 'ftyp' box [default: avif]
+  #[arg(long, value_name = "BRAND", value_parser = parse_brand)]
+  major_brand: Option<[u8; 4]>,
+  /// Comma-separated list of compatible brands for the AVIF file's 'ftyp' box, replacing
+  /// the automatic choice. By default, miaf and the AVIF profile brands (MA1B, MA1A) are
+  /// only listed if the image meets their constraints
+  #[arg(long, value_name = "BRANDS", value_parser = parse_brand, value_delimiter = ',')]
+  brands: Option<Vec<[u8; 4]>>,
+  /// Name of the program which wrote the AVIF file, stored in its 'hdlr' box [default: tinyavif]
+  #[arg(long, value_name = "NAME", value_parser = parse_box_name)]
+  handler_name: Option<String>,
+  /// Name of the image item in the AVIF file [default: Color]
+  #[arg(long, value_name = "NAME", value_parser = parse_box_name)]
+  item_name: Option<String>,
+  /// Where to store the image data in the AVIF file: mdat (after the headers), or idat
+  /// (inside the 'meta' box, for small images) [default: mdat]
+  #[arg(long)]
+  item_storage: Option<ItemStorage>,
+  /// Put the image data before the metadata in the AVIF file, rather than after it
+  #[arg(long)]
+  mdat_first: bool,
+  /// Pad the AVIF headers with a 'free' box to exactly this many bytes, so that viewers
+  /// downloading the file in pieces can fetch all of the headers in one request of this
+  /// size, and the image data always starts at the same offset
+  #[arg(long, value_name = "BYTES", conflicts_with = "mdat_first")]
+  header_size: Option<usize>,
+  /// Store items other than the main image, such as Exif data, inside the 'meta' box when
+  /// they're no larger than this many bytes, even without --item-storage idat [default: 1024]
+  #[arg(long, value_name = "BYTES")]
+  inline_limit: Option<usize>,
+  /// File of Exif metadata to attach to the image: a TIFF header and the data after it,
+  /// optionally preceded by "Exif" and two zero bytes, as in JPEG files
+  #[arg(long, value_name = "FILE")]
+  exif: Option<PathBuf>,
+  /// Grayscale image (PGM or Y4M) to store as a depth map, in an auxiliary image item.
+  /// Can be any size, and needs .avif output
+  #[arg(long, value_name = "FILE")]
+  depth_map: Option<PathBuf>,
+  /// Grayscale image (PGM or Y4M) to store as a gain map, so that viewers which support
+  /// gain maps can show an HDR version of the image. Black leaves the image as it is, and
+  /// white brightens it by --gain-map-max stops. Can be any size, and needs .avif output
+  #[arg(long, value_name = "FILE")]
+  gain_map: Option<PathBuf>,
+  /// Gain, in stops, which black in the gain map stands for [default: 0]
+  #[arg(long, value_name = "STOPS", requires = "gain_map", allow_hyphen_values = true)]
+  gain_map_min: Option<f64>,
+  /// Gain, in stops, which white in the gain map stands for [default: 2]
+  #[arg(long, value_name = "STOPS", requires = "gain_map")]
+  gain_map_max: Option<f64>,
+  /// Gamma which the gain map was encoded with [default: 1]
+  #[arg(long, value_name = "GAMMA", requires = "gain_map")]
+  gain_map_gamma: Option<f64>,
+  /// Display headroom, in stops above SDR white, needed to show the HDR version in full.
+  /// Displays with less headroom get part of the gain [default: --gain-map-max]
+  #[arg(long, value_name = "STOPS", requires = "gain_map")]
+  hdr_headroom: Option<f64>,
+  /// Load encoder settings from a profile (.toml), or from tinyavif.toml in the current
+  /// directory if no file is given. Any settings given on the command line override the
+  /// values from the profile
+  #[arg(long, visible_alias = "config", value_name = "FILE", num_args = 0..=1,
+        default_missing_value = DEFAULT_PRESET_FILE)]
+  preset_file: Option<PathBuf>,
+  /// Save the final encoder settings to a profile (.toml), for use with --preset-file
+  #[arg(long)]
+  save_preset: Option<PathBuf>,
+  /// Scale the image down (if needed) so that neither its width nor its height is
+  /// larger than this, keeping the aspect ratio
+  #[arg(long, value_name = "N", conflicts_with = "resize")]
+  max_dimension: Option<usize>,
+  /// Scale the image to exactly this size before encoding
+  #[arg(long, value_name = "WxH", value_parser = parse_size)]
+  resize: Option<(usize, usize)>,
+  /// What to do with interlaced input: reject it, weave (encode both fields together, as
+  /// if the frame were progressive), or bob (keep only the first field, interpolating the
+  /// other's lines) [default: reject]
+  #[arg(long, value_name = "MODE")]
+  deinterlace: Option<Deinterlace>,
+  /// Read and encode the image one superblock row at a time, to reduce memory use for
+  /// very large images. Can't be combined with options which need the whole image at once
+  #[arg(long, conflicts_with_all = ["resize", "max_dimension", "mode_map", "debug_heatmap", "debug_partitions",
+                                  "verify", "audit"])]
+  stream: bool,
+  /// Produce byte-identical output on every platform, by using portable versions of the
+  /// maths functions in --resize, --max-dimension, --denoise, and --sharpen
+  #[arg(long)]
+  deterministic: bool,
+  /// Maximum number of threads to use. The output is the same whatever the number of
+  /// threads [default: one per physical CPU core]
+  #[arg(long, value_name = "N")]
+  threads: Option<usize>,
+  /// Limit on the memory used for encoding, eg. 512M or 2G. Images which would need
+  /// more are streamed (see --stream) if possible, and otherwise rejected
+  #[arg(long, value_name = "BYTES", value_parser = parse_byte_size)]
+  max_memory: Option<usize>,
+  /// Generate an image pyramid with this many levels: the full-size image, then
+  /// versions scaled by 1/2, 1/4, etc. The scaled versions are written alongside
+  /// the main output, with their width added to the name (eg. image-640w.avif)
+  #[arg(long, default_value_t = 1)]
+  pyramid: usize,
+  /// Number of encoding passes. With 2 passes, the image is analysed first, and the
+  /// results are used to adjust the quantizer per superblock, and to try the identity
+  /// transform for luma in textured superblocks [default: 1]
+  #[arg(long)]
+  passes: Option<u8>,
+  /// Content type to optimize for: psnr (photographs and other natural images),
+  /// screen (screenshots, diagrams and text; enables palette mode), ssim (natural
+  /// images, favouring flat areas where errors are most visible), or auto to pick
+  /// between psnr and screen based on the image [default: auto]
+  #[arg(long)]
+  tune: Option<Tune>,
+  /// Sharpness, from 0 to 7. Higher values keep more fine detail and texture,
+  /// at the cost of a larger file for the same qindex [default: 0]
+  #[arg(long)]
+  sharpness: Option<u8>,
+  /// qindex offset for chroma, from -64 to 63. Either a single value applied to both
+  /// the DC and AC coefficients, or two values as DC,AC [default: 0]
+  #[arg(long, value_name = "OFFSET", value_parser = parse_chroma_qoffset, allow_hyphen_values = true)]
+  chroma_qoffset: Option<(i8, i8)>,
+  /// Y4M file whose luma plane marks the foreground of the image with bright pixels, and
+  /// the background with dark ones. The foreground is coded at the usual qindex, and the
+  /// background at --background-qindex. Must be the same size as the input
+  #[arg(long, value_name = "FILE", requires = "background_qindex", conflicts_with_all = ["target_psnr", "target_ssim"])]
+  foreground_mask: Option<PathBuf>,
+  /// qindex for the background, from 1 to 255, when using --foreground-mask
+  #[arg(long, requires = "foreground_mask")]
+  background_qindex: Option<u8>,
+  /// Grayscale image (PGM or Y4M) marking how important each area of the image is.
+  /// Mid-gray (128) keeps the usual qindex, brighter areas get a lower qindex (down to
+  /// 31 lower for white), and darker areas a higher one (up to 32 higher for black).
+  /// Applied per 64x64 superblock, and stretched to cover the image if it's a different size
+  #[arg(long, value_name = "FILE", conflicts_with_all = ["target_psnr", "target_ssim"])]
+  roi_map: Option<PathBuf>,
+  /// Blur the image slightly before encoding, to remove noise which would otherwise
+  /// cost bits to preserve. Strength from 0 (off) to 10 [default: 0]
+  #[arg(long)]
+  denoise: Option<u8>,
+  /// Sharpen the image before encoding, to offset the softening caused by compression.
+  /// Strength from 0 (off) to 10 [default: 0]
+  #[arg(long)]
+  sharpen: Option<u8>,
+  /// Save the first-pass analysis results to this file (requires --passes 2)
+  #[arg(long)]
+  first_pass_stats: Option<PathBuf>,
+  /// Use first-pass analysis results saved with --first-pass-stats, rather than
+  /// analysing the image again. Only applies to the full-size image (requires --passes 2)
+  #[arg(long, value_name = "FILE")]
+  load_first_pass_stats: Option<PathBuf>,
+  /// Print encoding statistics, such as how often each prediction mode was used
+  /// and how many bits were spent on each kind of syntax element
+  #[arg(long, visible_alias = "verbose")]
+  stats: bool,
+  /// Write a machine-readable summary of the encode to this file, as JSON: the image
+  /// size, qindex, bytes per OBU type, bits per syntax element, quality per plane,
+  /// and time taken by each stage
+  #[arg(long, value_name = "PATH")]
+  stats_json: Option<PathBuf>,
+  /// Write a false-colour image (.y4m) showing the prediction mode chosen for each block
+  #[arg(long)]
+  mode_map: Option<PathBuf>,
+  /// Write a false-colour image (.png or .y4m) showing how much distortion there is
+  /// in each 8x8 block, from blue (least) to red (most)
+  #[arg(long, value_name = "PATH")]
+  debug_heatmap: Option<PathBuf>,
+  /// Write an image (.png or .y4m) of the source with the partition grid drawn over it,
+  /// and each block tinted by the luma prediction mode chosen for it
+  #[arg(long, value_name = "PATH")]
+  debug_partitions: Option<PathBuf>,
+  /// Debugging aid: check the output against an independent decoder after every
+  /// superblock, stopping at the first block which doesn't match. Very slow
+  #[arg(long)]
+  audit: bool,
+  /// Debugging aid: after encoding, parse the output again and check that every
+  /// block's coefficients decode to the values which were meant to be coded
+  #[arg(long)]
+  verify_coeffs: bool,
+  /// Debugging aid: write a log of every symbol in the tile data to this file, giving
+  /// the syntax element, context, value, and cost in bits of each one
+  #[arg(long, value_name = "PATH")]
+  trace: Option<PathBuf>,
+  /// Decode the output with the built-in decoder, and report its PSNR against the
+  /// encoder's own reconstruction (which should be infinite)
+  #[arg(long)]
+  verify: bool,
+  /// Print information about how this binary was built (compiler, target, SIMD support), then exit
+  #[arg(long)]
+  capabilities: bool,
+  #[command(flatten)]
+  errors: ErrorArgs,
+}
+
+// Arguments for `tinyavif inspect <FILE>`
+#[derive(Args)]
+#[command(override_usage = "tinyavif inspect <INPUT>")]
+struct InspectArgs {
+  /// File to inspect (.avif or .obu)
   input: PathBuf,
-  /// Output file, must end in .obu or .avif [default: <input>.avif]
+  #[command(flatten)]
+  errors: ErrorArgs,
+}
+
+// Arguments for `tinyavif decode <FILE>`
+#[derive(Args)]
+#[command(override_usage = "tinyavif decode <INPUT> [-o <OUTPUT>]")]
+struct DecodeArgs {
+  /// File to decode (.avif or .obu). Only files produced by tinyavif are supported
+  input: PathBuf,
+  /// Output file, must end in .y4m [default: <input>.y4m]
   #[arg(short, long)]
   output: Option<PathBuf>,
-  /// Quantizer to use. Valid range is 1-255, inclusive
-  #[arg(short, long, default_value_t = 35)]
-  qindex: u8,
-  /// Color primaries
-  #[arg(long, default_value_t = 2)]
-  color_primaries: u16,
-  /// Transfer function
-  #[arg(long, default_value_t = 2)]
-  transfer_function: u16,
-  /// Matrix coefficients
-  #[arg(long, default_value_t = 2)]
-  matrix_coefficients: u16,
+  #[command(flatten)]
+  errors: ErrorArgs,
+}
+
+// Arguments for `tinyavif metrics <REFERENCE> <INPUT>`
+#[derive(Args)]
+#[command(override_usage = "tinyavif metrics <REFERENCE> <INPUT>")]
+struct MetricsArgs {
+  /// Original image (.y4m, or .avif or .obu produced by tinyavif)
+  reference: PathBuf,
+  /// Image to measure against it, in any of the same formats. Must be the same size
+  input: PathBuf,
+  #[command(flatten)]
+  errors: ErrorArgs,
+}
+
+// Arguments for `tinyavif selftest`
+#[derive(Args)]
+struct SelftestArgs {
+  #[command(flatten)]
+  errors: ErrorArgs,
+}
+
+// Arguments for `tinyavif bench [FILTER]`
+#[derive(Args)]
+#[command(override_usage = "tinyavif bench [FILTER] [--time <SECONDS>]")]
+struct BenchArgs {
+  /// Only run the benchmarks whose names contain this string
+  filter: Option<String>,
+  /// Minimum time to spend measuring each benchmark, in seconds [default: 1]
+  #[arg(long, value_name = "SECONDS")]
+  time: Option<f64>,
+  #[command(flatten)]
+  errors: ErrorArgs,
+}
+
+// Arguments for `tinyavif conformance [INPUTS]...`
+#[derive(Args)]
+#[command(override_usage = "tinyavif conformance [INPUTS]... [--qindex <LIST>]")]
+struct ConformanceArgs {
+  /// Images (.y4m) to test with, or directories of them [default: built-in synthetic images]
+  inputs: Vec<PathBuf>,
+  /// qindexes to encode each image at, separated by commas [default: 1,20,35,100,180,255]
+  #[arg(long, value_name = "LIST", value_delimiter = ',')]
+  qindex: Vec<u8>,
+  /// Load the other encoder settings from a profile (.toml)
+  #[arg(long)]
+  preset_file: Option<PathBuf>,
+  /// Command to run dav1d with [default: dav1d, skipped if not found]
+  #[arg(long, value_name = "COMMAND")]
+  dav1d: Option<PathBuf>,
+  /// Command to run aomdec with [default: aomdec, skipped if not found]
+  #[arg(long, value_name = "COMMAND")]
+  aomdec: Option<PathBuf>,
+  /// Directory to write the encoded streams and decoded images to, which is kept
+  /// afterwards. By default, a temporary directory is used, and only kept if
+  /// anything fails to match
+  #[arg(long, value_name = "DIR")]
+  work_dir: Option<PathBuf>,
+  /// Number of cases to check at once [default: one per CPU core]
+  #[arg(long, value_name = "N")]
+  threads: Option<usize>,
+  #[command(flatten)]
+  errors: ErrorArgs,
+}
+
+// Settings profile which `--preset-file` loads when not given a file, so that a directory
+// of images can carry the settings they should be converted with. It's only loaded when
+// asked for, so that the same command line always gives the same output
+const DEFAULT_PRESET_FILE: &str = "tinyavif.toml";
+
+// Build the encoder configuration: start from the defaults or a loaded profile,
+// then apply any explicit command line options on top
+fn build_config(args: &EncodeArgs) -> EncoderConfig {
+  let mut config = match &args.preset_file {
+    None => EncoderConfig::default(),
+    Some(path) => load_profile(path),
+  };
+
+  if let Some(qindex) = args.qindex {
+    config.qindex = qindex;
+  }
+  if let Some(passes) = args.passes {
+    config.passes = passes;
+  }
+  if let Some(tune) = args.tune {
+    config.tune = tune;
+  }
+  if let Some(quality) = args.quality {
+    if quality > MAX_QUALITY {
+      fail(ErrorKind::Usage, format!("Invalid quality {}, must be between 0 and {}", quality, MAX_QUALITY));
+    }
+    config.qindex = quality_to_qindex(quality, config.tune);
+  }
+  if let Some(sharpness) = args.sharpness {
+    config.sharpness = sharpness;
+  }
+  if let Some(background_qindex) = args.background_qindex {
+    if background_qindex == 0 {
+      fail(ErrorKind::Usage, "Invalid background qindex 0, must be between 1 and 255".into());
+    }
+    config.background_qindex = background_qindex;
+  }
+  if let Some((dc_offset, ac_offset)) = args.chroma_qoffset {
+    config.chroma_dc_qoffset = dc_offset;
+    config.chroma_ac_qoffset = ac_offset;
+  }
+  if let Some(denoise) = args.denoise {
+    config.denoise = denoise;
+  }
+  if let Some(sharpen) = args.sharpen {
+    config.sharpen = sharpen;
+  }
+  if let Some(level) = &args.level {
+    config.level = level.clone();
+  }
+  if let Some(tier) = args.tier {
+    config.tier = tier;
+  }
+  if let Some(profile) = args.av1_profile {
+    config.av1_profile = profile;
+  }
+  if args.temporal_id.is_some() || args.spatial_id.is_some() {
+    config.obu_extension = true;
+    if let Some(temporal_id) = args.temporal_id {
+      config.temporal_id = temporal_id;
+    }
+    if let Some(spatial_id) = args.spatial_id {
+      config.spatial_id = spatial_id;
+    }
+  }
+  if let Some((time_scale, num_units_in_display_tick)) = args.timing_info {
+    config.time_scale = time_scale;
+    config.num_units_in_display_tick = num_units_in_display_tick;
+  }
+  if args.decoder_model {
+    config.decoder_model = true;
+  }
+  if args.full_headers {
+    config.full_headers = true;
+  }
+  if args.deterministic {
+    config.deterministic = true;
+  }
+  if let Some(threads) = args.threads {
+    config.threads = threads;
+  }
+  if let Some(max_memory) = args.max_memory {
+    config.max_memory = max_memory;
+  }
+  if let Some(obu_layout) = args.obu_layout {
+    config.obu_layout = obu_layout;
+  }
+  if let Some(preset) = args.cicp {
+    (config.color_primaries, config.transfer_function, config.matrix_coefficients) = preset.cicp();
+  }
+  if let Some(color_primaries) = args.color_primaries {
+    config.color_primaries = color_primaries;
+  }
+  if let Some(transfer_function) = args.transfer_function {
+    config.transfer_function = transfer_function;
+  }
+  if let Some(matrix_coefficients) = args.matrix_coefficients {
+    config.matrix_coefficients = matrix_coefficients;
+  }
+  if args.no_obu_color_description {
+    config.obu_color_description = false;
+  }
+  if let Some(chroma_sample_position) = args.chroma_sample_position {
+    config.chroma_sample_position = chroma_sample_position;
+  }
+  if let Some(chroma_filter) = args.chroma_filter {
+    config.chroma_filter = chroma_filter;
+  }
+  if args.full_range {
+    config.full_range = true;
+  }
+  if let Some((width, height)) = args.render_size {
+    config.render_width = width;
+    config.render_height = height;
+  }
+  if let Some(denominator) = args.superres_denominator {
+    config.superres_denominator = denominator;
+  }
+
+  if let Err(e) = config.validate() {
+    fail((&e).into(), e.to_string());
+  }
+
+  return config;
 }
 
 fn main() {
-  let args = CommandlineArgs::parse();
+  if scan_error_format() == ErrorFormat::Json {
+    JSON_ERRORS.store(true, Ordering::Relaxed);
+  }
+
+  // Panics are internal errors too, so give them the same exit code and format
+  let default_hook = std::panic::take_hook();
+  std::panic::set_hook(Box::new(move |info| {
+    if JSON_ERRORS.load(Ordering::Relaxed) {
+      report_error(ErrorKind::Internal, &info.to_string());
+    } else {
+      default_hook(info);
+    }
+    exit(ErrorKind::Internal as i32);
+  }));
+
+  // `tinyavif <INPUT> [OPTIONS]` is short for `tinyavif encode <INPUT> [OPTIONS]`
+  let mut argv: Vec<String> = std::env::args().collect();
+  if let Some(first) = argv.get(1) {
+    if !COMMAND_NAMES.contains(&first.as_str()) && first != "-h" && first != "--help" {
+      argv.insert(1, "encode".into());
+    }
+  }
+
+  let cli: Cli = parse_args(argv);
+  match cli.command {
+    Command::Encode(args) => run_encode(&args),
+    Command::Decode(args) => run_decode(&args),
+    Command::Inspect(args) => run_inspect(&args.input),
+    Command::Metrics(args) => run_metrics(&args),
+    Command::Bench(args) => run_bench(&args),
+    Command::Conformance(args) => run_conformance(&args),
+    Command::Selftest(_) => run_selftest(),
+  }
+}
+
+fn run_encode(args: &EncodeArgs) {
+
+  if args.capabilities {
+    println!("{}", build_info());
+    return;
+  }
+
+  let config = build_config(args);
+
+  if let Some(path) = &args.save_preset {
+    if let Err(e) = std::fs::write(path, config.to_toml()) {
+      fail(ErrorKind::Io, format!("Failed to save settings profile {}: {}", path.display(), e));
+    }
+  }
+
+  if args.first_pass_stats.is_some() && config.passes != 2 {
+    fail(ErrorKind::Usage, "--first-pass-stats requires --passes 2".into());
+  }
+  if args.load_first_pass_stats.is_some() && config.passes != 2 {
+    fail(ErrorKind::Usage, "--load-first-pass-stats requires --passes 2".into());
+  }
+
+  if args.max_dimension == Some(0) {
+    fail(ErrorKind::Usage, "--max-dimension must be at least 1".into());
+  }
+
+  if args.stream && args.pyramid > 1 {
+    fail(ErrorKind::Usage, "--stream cannot be used with --pyramid".into());
+  }
+
+  if args.stream && args.deinterlace == Some(Deinterlace::Bob) {
+    fail(ErrorKind::Usage, "--stream cannot be used with --deinterlace bob".into());
+  }
+
+  let gain_map_metadata = args.gain_map.as_ref().map(|_| {
+    let gain_map_max = args.gain_map_max.unwrap_or(2.0);
+    let mut metadata = GainMapMetadata::new(args.gain_map_min.unwrap_or(0.0), gain_map_max,
+                                            args.hdr_headroom.unwrap_or(gain_map_max));
+    metadata.gamma = args.gain_map_gamma.unwrap_or(1.0);
+    if let Err(e) = metadata.validate() {
+      fail((&e).into(), e.to_string());
+    }
+    metadata
+  });
 
-  let input_path = args.input;
+  let input_path = args.input.clone().unwrap();
 
   match input_path.extension() {
     None => {
-      println!("Error: Input file must end in .y4m");
-      exit(2);
+      fail(ErrorKind::Usage, "Input file must end in .y4m".into());
     },
     Some(ext_osstr) => {
       let ext = ext_osstr.to_str().unwrap();
       if ext != "y4m" {
-        println!("Error: Input file must end in .y4m");
-        exit(2);
+        fail(ErrorKind::Usage, "Input file must end in .y4m".into());
       }
     }
   }
 
-  let output_path = args.output.unwrap_or_else(|| {
+  let output_path = args.output.clone().unwrap_or_else(|| {
     input_path.with_extension("avif")
   });
 
   let output_ext = match output_path.extension() {
     None => {
-      println!("Error: Output file must end in .obu or .avif");
-      exit(2);
+      fail(ErrorKind::Usage, "Output file must end in .obu, .ivf or .avif".into());
     },
     Some(ext_osstr) => {
       let ext = ext_osstr.to_str().unwrap();
-      if ext != "obu" && ext != "avif" {
-        println!("Error: Output file must end in .obu or .avif");
-        exit(2);
+      if ext != "obu" && ext != "ivf" && ext != "avif" {
+        fail(ErrorKind::Usage, "Output file must end in .obu, .ivf or .avif".into());
       }
-      ext
+      ext.to_string()
     }
   };
+  let output_ext = output_ext.as_str();
 
-  let base_qindex = args.qindex;
+  for (option, path) in [("--depth-map", &args.depth_map), ("--exif", &args.exif), ("--gain-map", &args.gain_map)] {
+    if path.is_some() && output_ext != "avif" {
+      fail(ErrorKind::Usage, format!("{} needs .avif output", option));
+    }
+  }
 
-  let mut y4m = Y4MReader::new(File::open(input_path).unwrap()).unwrap();
-  let source = y4m.read_frame().unwrap();
+  // Everything from opening the input onwards is counted in the total time for --stats-json
+  let start = Instant::now();
+  let input_file = File::open(&input_path).unwrap_or_else(|e| {
+    fail(ErrorKind::Io, format!("Failed to open {}: {}", input_path.display(), e));
+  });
+  let mut y4m = Y4MReader::new(input_file).unwrap_or_else(|e| {
+    fail(parse_error_kind(&e), format!("Failed to read {}: {}", input_path.display(), e));
+  });
+  y4m.set_chroma_conversion(ChromaConversion::from_config(&config));
+  y4m.set_deinterlace(args.deinterlace.unwrap_or(Deinterlace::Reject));
 
-  // Check that the image will fit in one tile
-  let crop_width = source.y().crop_width();
-  let crop_height = source.y().crop_height();
-  let padded_width = source.y().width();
-  let padded_height = source.y().height();
+  // If the input says which range it uses, make sure that we signal the same thing
+  match (y4m.header().color_range.as_deref(), config.full_range) {
+    (Some("FULL"), false) => {
+      fail(ErrorKind::Usage, format!("{} is full range, so needs --full-range", input_path.display()));
+    },
+    (Some("LIMITED"), true) => {
+      fail(ErrorKind::Usage, format!("{} is limited range, so can't be encoded with --full-range", input_path.display()));
+    },
+    _ => {}
+  }
 
-  if padded_width > 4096 || padded_width * padded_height > 4096 * 2304 {
-    println!("Error: image size {}x{} (padded to {}x{}) is too large to fit in a single tile",
-             crop_width, crop_height, padded_width, padded_height);
-    exit(2);
+  let exif = args.exif.as_ref().map(|path| read_exif(path));
+  let mask = args.foreground_mask.as_ref().map(|path| read_mask(path, y4m.header().width, y4m.header().height));
+  let roi_map = args.roi_map.as_ref().map(|path| read_map(path));
+  let first_pass_stats = args.load_first_pass_stats.as_ref().map(|path| {
+    let text = std::fs::read_to_string(path).unwrap_or_else(|e| {
+      fail(ErrorKind::Io, format!("Failed to load first-pass statistics {}: {}", path.display(), e));
+    });
+    FirstPassStats::from_text(&text).unwrap_or_else(|e| {
+      fail((&e).into(), format!("Failed to load first-pass statistics {}: {}", path.display(), e));
+    })
+  });
+  // Extra images to store alongside the main one, which are encoded once the settings
+  // are final
+  let mut extra_images = Vec::new();
+  if let Some(path) = &args.depth_map {
+    extra_images.push((read_map(path), AvifItemKind::Auxiliary(AUX_TYPE_DEPTH.into())));
+  }
+  if let Some(path) = &args.gain_map {
+    extra_images.push((read_map(path), AvifItemKind::GainMap(gain_map_metadata.unwrap())));
+  }
+  let maps = RegionMaps {
+    foreground_mask: mask.as_ref().map(|mask| mask.y()),
+    roi_map: roi_map.as_ref().map(|map| map.y()),
+    first_pass_stats: first_pass_stats.as_ref(),
+  };
+
+  // Work out the size to encode at, if rescaling the input, so that the memory needed
+  // can be checked before reading the image
+  let input_width = y4m.header().width;
+  let input_height = y4m.header().height;
+  let target_size = match (args.resize, args.max_dimension) {
+    (Some(size), _) => Some(size),
+    (None, Some(max_dimension)) => Some(fit_within(input_width, input_height, max_dimension)),
+    (None, None) => None,
+  };
+  let (width, height) = target_size.unwrap_or((input_width, input_height));
+
+  if stream_within_memory_limit(&config, width, height, args) {
+    let extras = AvifExtras { items: encode_extra_items(&extra_images, &config), exif: exif };
+    encode_streaming(&config, &mut y4m, &maps, &extras, &output_path, output_ext, args, start);
+    return;
+  }
+
+  let mut source = y4m.read_frame().unwrap_or_else(|e| {
+    fail(y4m_error_kind(&e), input_error_message(&e));
+  });
+  let read_time = start.elapsed();
+
+  // Optionally rescale the input before doing anything else with it
+  let resize_start = Instant::now();
+  if let Some((width, height)) = target_size {
+    if (width, height) != (input_width, input_height) {
+      *source = resize(&source, width, height, config.deterministic);
+    }
+  }
+  let resize_time = resize_start.elapsed();
+
+  // Pick the tuning from the image, if not given. Streamed images can't be looked at
+  // in advance, so are always tuned for photos
+  let mut config = config;
+  if config.tune == Tune::Auto {
+    config.tune = detect_tune(&source);
+    // The quality scale depends on the tuning
+    if let Some(quality) = args.quality {
+      config.qindex = quality_to_qindex(quality, config.tune);
+    }
   }
 
+  // Choose the qindex, if aiming for a target quality. Smaller pyramid levels use
+  // the same qindex
+  let target = match (args.target_psnr, args.target_ssim) {
+    (Some(psnr), _) => Some(QualityTarget::Psnr(psnr)),
+    (None, Some(ssim)) => Some(QualityTarget::Ssim(ssim)),
+    (None, None) => None,
+  };
+  let search_start = Instant::now();
+  if let Some(target) = target {
+    config.qindex = search_qindex(&config, &source, target).unwrap_or_else(|e| {
+      fail(ErrorKind::Internal, format!("Failed to search for qindex: {}", e));
+    });
+    eprintln!("Chose qindex {} to meet the target quality", config.qindex);
+  }
+  let search_time = search_start.elapsed();
+
+  // The extra images are the same for every pyramid level, as viewers stretch them to fit
+  let extras = AvifExtras { items: encode_extra_items(&extra_images, &config), exif: exif };
+
+  // Sizes of the pyramid levels, which stop at 1x1, for the progress bar
+  let mut level_pixels = Vec::new();
+  let (mut level_width, mut level_height) = (source.y().crop_width(), source.y().crop_height());
+  for _ in 0..args.pyramid {
+    level_pixels.push(level_width * level_height);
+    if (level_width, level_height) == (1, 1) {
+      break;
+    }
+    (level_width, level_height) = (level_width.div_ceil(2), level_height.div_ceil(2));
+  }
+  let progress = progress_bar(level_pixels);
+
+  // Encode each level of the image pyramid (by default, just the full-size image),
+  // reusing the already-decoded source to generate each downscaled version
+  let mut level_source = source;
+  for level in 0..args.pyramid {
+    if level > 0 {
+      if level_source.y().crop_width() == 1 && level_source.y().crop_height() == 1 {
+        println!("Warning: Image is too small for more than {} pyramid levels", level);
+        break;
+      }
+      *level_source = downscale_2x(&level_source);
+    }
+
+    let level_path = if level == 0 {
+      output_path.clone()
+    } else {
+      let stem = output_path.file_stem().unwrap().to_str().unwrap();
+      let name = format!("{}-{}w.{}", stem, level_source.y().crop_width(), output_ext);
+      output_path.with_file_name(name)
+    };
+    let crop_width = level_source.y().crop_width();
+    let crop_height = level_source.y().crop_height();
+    // Loaded first-pass stats only match the full-size image, so smaller levels run
+    // their own first pass
+    let level_maps = RegionMaps { first_pass_stats: maps.first_pass_stats.filter(|_| level == 0), ..maps };
+    let (encoded, output) = encode_to_file(&config, crop_width, crop_height, &level_maps, &extras,
+                                           |encoder| encoder.encode_image(&level_source),
+                                           &level_path, output_ext, args, progress.as_ref());
+    let stats = &encoded.stats;
+
+    // Statistics and debug output are only generated for the full-size image
+    if level == 0 {
+      if args.stats {
+        print_stats(stats);
+      }
+      if let Some(path) = &args.stats_json {
+        let report = StatsReport {
+          input_path: &input_path,
+          input_width: input_width,
+          input_height: input_height,
+          output_path: &level_path,
+          width: crop_width,
+          height: crop_height,
+          config: &config,
+          encoded: &encoded,
+          output: &output,
+          source: Some(&level_source),
+          times: vec![("read", read_time), ("resize", resize_time), ("qindex_search", search_time)],
+          total_time: start.elapsed(),
+        };
+        write_stats_json(path, &report);
+      }
+      if let Some(path) = &args.mode_map {
+        let overlay = render_mode_map(&level_source, stats);
+        write_y4m(path, &overlay);
+      }
+      if let Some(path) = &args.debug_heatmap {
+        let rgb = render_distortion_heatmap(&level_source, encoded.recon.as_ref().unwrap());
+        write_debug_image(path, crop_width, crop_height, &rgb);
+      }
+      if let Some(path) = &args.debug_partitions {
+        let rgb = render_partition_overlay(&level_source, stats);
+        write_debug_image(path, crop_width, crop_height, &rgb);
+      }
+      if let Some(path) = &args.trace {
+        write_trace(path, encoded.trace.as_ref().unwrap());
+      }
+      if let Some(path) = &args.first_pass_stats {
+        if let Err(e) = std::fs::write(path, encoded.first_pass.as_ref().unwrap().to_text()) {
+          fail(ErrorKind::Io, format!("Failed to save first-pass statistics {}: {}", path.display(), e));
+        }
+      }
+    }
+  }
+}
+
+// Whether to stream the image: either because --stream was given, or because reading
+// the whole image in would go over --max-memory. Fails if the image can't be encoded
+// within the limit either way. `width` and `height` are the size the image will be
+// encoded at, after any resizing
+fn stream_within_memory_limit(config: &EncoderConfig, width: usize, height: usize, args: &EncodeArgs) -> bool {
+  if config.max_memory == 0 {
+    return args.stream;
+  }
+  let whole_image = match check_memory(config, width, height, args.stream) {
+    Ok(()) => return args.stream,
+    Err(e) if args.stream => fail((&e).into(), e.to_string()),
+    Err(e) => e,
+  };
+
+  // Otherwise, try streaming instead, which is only possible if nothing needs the
+  // whole image at once
+  let whole_image_options = [
+    ("--resize", args.resize.is_some()),
+    ("--max-dimension", args.max_dimension.is_some()),
+    ("--pyramid", args.pyramid > 1),
+    ("--deinterlace bob", args.deinterlace == Some(Deinterlace::Bob)),
+    ("--target-psnr", args.target_psnr.is_some()),
+    ("--target-ssim", args.target_ssim.is_some()),
+    ("--mode-map", args.mode_map.is_some()),
+    ("--debug-heatmap", args.debug_heatmap.is_some()),
+    ("--debug-partitions", args.debug_partitions.is_some()),
+    ("--verify", args.verify),
+    ("--audit", args.audit),
+  ];
+  let reason = match whole_image_options.iter().find(|&&(_, used)| used) {
+    Some((option, _)) => Some(format!("{} needs the whole image at once", option)),
+    None => check_streaming(config, width).err().map(|e| e.to_string()),
+  };
+  if let Some(reason) = reason {
+    fail((&whole_image).into(), format!("{}, and it can't be streamed instead: {}", whole_image, reason));
+  }
+  if let Err(e) = check_memory(config, width, height, true) {
+    fail((&e).into(), e.to_string());
+  }
+  eprintln!("Streaming the image, to stay within --max-memory");
+  return true;
+}
+
+// Encode the first frame of the input one superblock row at a time (see --stream)
+#[allow(clippy::too_many_arguments)]
+fn encode_streaming(config: &EncoderConfig, y4m: &mut Y4MReader<File>, maps: &RegionMaps, extras: &AvifExtras,
+                    output_path: &Path, output_ext: &str, args: &EncodeArgs, start: Instant) {
+  let crop_width = y4m.header().width;
+  let crop_height = y4m.header().height;
+  let mut frame_rows = y4m.read_frame_rows().unwrap_or_else(|e| {
+    fail(y4m_error_kind(&e), input_error_message(&e));
+  });
+
+  let (encoded, output) = encode_to_file(config, crop_width, crop_height, maps, extras, |encoder| {
+    if let Err(e) = encoder.check_streaming() {
+      fail((&e).into(), e.to_string());
+    }
+    encoder.encode_image_streaming(&mut frame_rows)
+  }, output_path, output_ext, args, progress_bar(vec![crop_width * crop_height]).as_ref());
+
+  if args.stats {
+    print_stats(&encoded.stats);
+  }
+  if let Some(path) = &args.stats_json {
+    // The source isn't kept when streaming, so the quality can't be measured
+    let report = StatsReport {
+      input_path: args.input.as_ref().unwrap(),
+      input_width: crop_width,
+      input_height: crop_height,
+      output_path: output_path,
+      width: crop_width,
+      height: crop_height,
+      config: config,
+      encoded: &encoded,
+      output: &output,
+      source: None,
+      times: Vec::new(),
+      total_time: start.elapsed(),
+    };
+    write_stats_json(path, &report);
+  }
+  if let Some(path) = &args.trace {
+    write_trace(path, encoded.trace.as_ref().unwrap());
+  }
+}
+
+// Images with at least this many pixels, in total, get a progress bar while encoding
+const PROGRESS_MIN_PIXELS: usize = 4_000_000;
+
+// Progress bar for encoding images of the given sizes, redrawn in place on stderr, if
+// they're large enough that it's worth showing how far along we are
+fn progress_bar(image_pixels: Vec<usize>) -> Option<ProgressTracker> {
+  if !std::io::stderr().is_terminal() || image_pixels.iter().sum::<usize>() < PROGRESS_MIN_PIXELS {
+    return None;
+  }
+  // The line gets shorter when the ETA does, so clear the rest of it each time
+  return Some(ProgressTracker::new(image_pixels, |progress| eprint!("\rEncoding {}\x1b[K", progress)));
+}
+
+// Maps which set the quality of each part of the image, passed on to each encoder
+struct RegionMaps<'a> {
+  foreground_mask: Option<&'a Plane>,
+  roi_map: Option<&'a Plane>,
+  // First-pass stats loaded with --load-first-pass-stats, which steer the encode in the
+  // same way as running the first pass would
+  first_pass_stats: Option<&'a FirstPassStats>,
+}
+
+// What to store in an AVIF file alongside the image itself
+struct AvifExtras {
+  items: Vec<AvifItem>,
+  exif: Option<Vec<u8>>,
+}
+
+// What encode_to_file() wrote out, for --stats-json
+struct OutputInfo {
+  // Total bytes of each type of OBU, including their headers
+  obu_sizes: Vec<(&'static str, usize)>,
+  file_size: usize,
+  // Time taken to pack the output into its container and write it
+  write_time: Duration,
+}
+
+// Encode an image with the given settings, using `encode` to run the encoder itself,
+// then write it out in the format given by `output_ext`
+#[allow(clippy::too_many_arguments)]
+fn encode_to_file<F>(config: &EncoderConfig, crop_width: usize, crop_height: usize, maps: &RegionMaps,
+                     extras: &AvifExtras, encode: F, output_path: &Path, output_ext: &str,
+                     args: &EncodeArgs, progress: Option<&ProgressTracker>) -> (EncodedImage, OutputInfo)
+  where F: FnOnce(&AV1Encoder) -> Result<EncodedImage, EncodeError> {
   // Generate AV1 data
-  let encoder = AV1Encoder::new(crop_width, crop_height);
+  let mut encoder = AV1Encoder::new(config, crop_width, crop_height).unwrap_or_else(|e| {
+    fail((&e).into(), e.to_string());
+  });
+  encoder.set_audit(args.audit);
+  encoder.set_verify_coeffs(args.verify_coeffs);
+  encoder.set_trace(args.trace.is_some());
+  if let Some(mask) = maps.foreground_mask {
+    // The background qindex is required along with the mask, so this can't fail
+    encoder.set_foreground_mask(mask).unwrap();
+  }
+  if let Some(roi_map) = maps.roi_map {
+    encoder.set_roi_map(roi_map);
+  }
+  if let Some(stats) = maps.first_pass_stats {
+    encoder.set_first_pass_stats(stats.clone()).unwrap_or_else(|e| {
+      fail((&e).into(), format!("Can't use the loaded first-pass statistics: {}", e));
+    });
+  }
+
+  if let Some(progress) = progress {
+    encoder.set_progress_callback(progress.encoder_callback());
+  }
+
   let sequence_header = encoder.generate_sequence_header();
-  let frame_header = encoder.generate_frame_header(base_qindex, false);
-  let tile_data = encoder.encode_image(&source, base_qindex);
+  let frame_header = encoder.generate_frame_header(config.obu_layout == ObuLayout::Separate);
+  let encoded = encode(&encoder).unwrap_or_else(|e| {
+    match e {
+      EncodeError::Io(e) => {
+        fail(parse_error_kind(&e), format!("Failed to read {}: {}", args.input.as_ref().unwrap().display(), e));
+      },
+      // AV1Encoder::new() has already checked the settings, so this can only be the
+      // memory limit
+      EncodeError::Config(e) => fail((&e).into(), e.to_string()),
+      // The command line tool never cancels encodes
+      EncodeError::Cancelled | EncodeError::InvalidInput(_) => unreachable!(),
+    }
+  });
+  if let Some(progress) = progress {
+    progress.image_done();
+    eprintln!();
+  }
 
   // Pack into higher-level structure and write out
-  let av1_data = pack_obus(&sequence_header, &frame_header, &tile_data, true);
+  let write_start = Instant::now();
+  let av1_data = pack_obus(&sequence_header, &frame_header, &encoded.tile_data, true, config.extension_header(),
+                           config.obu_layout);
 
-  match output_ext {
-    "obu" => {
-      // Write OBU data directly, with no further wrapping
-      let mut obu_file = File::create(output_path).unwrap();
-      obu_file.write_all(&av1_data).unwrap();
-    },
+  // Verification isn't counted as part of writing the output
+  let pack_time = write_start.elapsed();
+  if args.verify {
+    verify_output(&av1_data, encoded.recon.as_ref().unwrap(), output_path);
+  }
+  let write_start = Instant::now();
+
+  let file_data = match output_ext {
+    // Write OBU data directly, with no further wrapping
+    "obu" => av1_data.to_vec(),
+    // Wrap OBU data in an IVF container, for AV1 analysis tools
+    "ivf" => pack_ivf(&av1_data, crop_width, crop_height).to_vec(),
+    // Wrap OBU data in an AVIF container
     "avif" => {
-      // Wrap OBU data in an AVIF container
-      let avif_data = pack_avif(&av1_data, crop_width, crop_height,
-                                args.color_primaries,
-                                args.transfer_function,
-                                args.matrix_coefficients);
-      let mut avif_file = File::create(output_path).unwrap();
-      avif_file.write_all(&avif_data).unwrap();
+      let mut avif_config = AvifConfig::for_image(config, crop_width, crop_height);
+      if let Some(major) = args.major_brand {
+        avif_config.brands.major = major;
+      }
+      if let Some(compatible) = &args.brands {
+        avif_config.brands.compatible = compatible.clone();
+      }
+      if let Some(name) = &args.handler_name {
+        avif_config.handler_name = name.clone();
+      }
+      if let Some(name) = &args.item_name {
+        avif_config.item_name = name.clone();
+      }
+      if let Some(storage) = args.item_storage {
+        avif_config.storage = storage;
+      }
+      if let Some(limit) = args.inline_limit {
+        avif_config.inline_limit = limit;
+      }
+      avif_config.mdat_first = args.mdat_first;
+      if let Some(size) = args.header_size {
+        avif_config.header_size = size;
+      }
+      avif_config.exif = extras.exif.clone();
+      let avif = pack_avif(&av1_data, crop_width, crop_height, config, &extras.items, &avif_config);
+      avif.unwrap_or_else(|e| {
+        fail((&e).into(), e.to_string());
+      }).to_vec()
     },
     _ => { unreachable!() }
+  };
+  if let Err(e) = File::create(output_path).and_then(|mut file| file.write_all(&file_data)) {
+    fail(ErrorKind::Io, format!("Failed to write {}: {}", output_path.display(), e));
+  }
+
+  let output = OutputInfo {
+    // We generated these OBUs ourselves, so they're always well formed
+    obu_sizes: obu_sizes(&av1_data).unwrap(),
+    file_size: file_data.len(),
+    write_time: pack_time + write_start.elapsed(),
+  };
+  return (encoded, output);
+}
+
+// Decode the generated OBUs, and check that the result matches what the encoder
+// thinks the decoder will see
+fn verify_output(av1_data: &[u8], recon: &Frame, output_path: &Path) {
+  // Any failure here means the encoder produced something it can't read back,
+  // which is a bug rather than a problem with the input
+  let decoded = decode_obus(av1_data).unwrap_or_else(|e| {
+    fail(ErrorKind::Internal, format!("Failed to decode {}: {}", output_path.display(), e));
+  });
+
+  let [y_psnr, u_psnr, v_psnr] = frame_psnr(&decoded, recon);
+  println!("Verify {}: PSNR vs. encoder recon: Y {:.2} dB, U {:.2} dB, V {:.2} dB",
+           output_path.display(), y_psnr, u_psnr, v_psnr);
+  if y_psnr.is_finite() || u_psnr.is_finite() || v_psnr.is_finite() {
+    fail(ErrorKind::Internal, "Decoded image does not match the encoder's reconstruction".into());
+  }
+}
+
+// Print the structure of an existing .avif or .obu file
+fn run_inspect(path: &Path) {
+  let data = std::fs::read(path).unwrap_or_else(|e| {
+    fail(ErrorKind::Io, format!("Failed to read {}: {}", path.display(), e));
+  });
+
+  let mut report = String::new();
+  let result = inspect(&data, &mut report);
+  // Print whatever was parsed successfully, even if there was an error later on
+  print!("{}", report);
+  if let Err(e) = result {
+    fail(parse_error_kind(&e), format!("Failed to parse {}: {}", path.display(), e));
+  }
+}
+
+// Decode a .avif or .obu file produced by tinyavif back to .y4m
+fn run_decode(args: &DecodeArgs) {
+  let output_path = args.output.clone().unwrap_or_else(|| {
+    args.input.with_extension("y4m")
+  });
+  if output_path.extension().and_then(|ext| ext.to_str()) != Some("y4m") {
+    fail(ErrorKind::Usage, "Output file must end in .y4m".into());
+  }
+
+  let decoded = read_av1_image(&args.input);
+  write_y4m(&output_path, &decoded);
+}
+
+// Decode an .avif or .obu file with the built-in decoder
+fn read_av1_image(path: &Path) -> Frame {
+  let data = std::fs::read(path).unwrap_or_else(|e| {
+    fail(ErrorKind::Io, format!("Failed to read {}: {}", path.display(), e));
+  });
+
+  // AVIF files start with an 'ftyp' box; anything else is treated as raw OBUs
+  let av1_data = if data.len() >= 8 && &data[4..8] == b"ftyp" {
+    unpack_avif(&data).unwrap_or_else(|e| {
+      fail(parse_error_kind(&e), format!("Failed to parse {}: {}", path.display(), e));
+    })
+  } else {
+    data
+  };
+
+  return decode_obus(&av1_data).unwrap_or_else(|e| {
+    fail((&e).into(), format!("Failed to decode {}: {}", path.display(), e));
+  });
+}
+
+// Compare an image against a reference. Either can be a .y4m file, or a file which the
+// built-in decoder can read
+fn run_metrics(args: &MetricsArgs) {
+  let read_image = |path: &Path| {
+    if path.extension().and_then(|ext| ext.to_str()) != Some("y4m") {
+      return read_av1_image(path);
+    }
+    let frame = File::open(path).map_err(Y4MError::from).and_then(|file| Y4MReader::new(file)?.read_frame());
+    return *frame.unwrap_or_else(|e| {
+      fail(y4m_error_kind(&e), format!("Failed to read {}: {}", path.display(), e));
+    });
+  };
+  let reference = read_image(&args.reference);
+  let image = read_image(&args.input);
+
+  for plane in 0..3 {
+    let size = |frame: &Frame| (frame.plane(plane).crop_width(), frame.plane(plane).crop_height());
+    if size(&reference) != size(&image) {
+      fail(ErrorKind::Usage, format!("{} is {}x{}, but the reference {} is {}x{}", args.input.display(),
+                                     image.y().crop_width(), image.y().crop_height(), args.reference.display(),
+                                     reference.y().crop_width(), reference.y().crop_height()));
+    }
+  }
+
+  let [y_psnr, u_psnr, v_psnr] = frame_psnr(&reference, &image);
+  let ssim = plane_ssim(reference.y(), image.y());
+  println!("PSNR: Y {:.2} dB, U {:.2} dB, V {:.2} dB", y_psnr, u_psnr, v_psnr);
+  println!("SSIM: Y {:.4}", ssim);
+}
+
+fn run_selftest() {
+  match run_self_tests() {
+    Ok(checks) => println!("Self-test passed ({} checks)", checks),
+    Err(e) => fail(ErrorKind::Internal, e.to_string()),
+  }
+}
+
+fn run_bench(args: &BenchArgs) {
+  let seconds = args.time.unwrap_or(1.0);
+  if !(seconds > 0.0 && seconds <= 3600.0) {
+    fail(ErrorKind::Usage, format!("Invalid benchmark time {}, must be greater than 0 and at most 3600 seconds", seconds));
+  }
+  let results = run_benchmarks(args.filter.as_deref(), Duration::from_secs_f64(seconds));
+  if results.is_empty() {
+    fail(ErrorKind::Usage, format!("No benchmarks match \"{}\", the benchmarks are: {}",
+                                   args.filter.as_deref().unwrap_or(""), BENCHMARKS.join(", ")));
+  }
+  for result in results {
+    let nanos = result.time_per_iteration.as_nanos();
+    let time = if nanos >= 1_000_000 {
+      format!("{:.3} ms", nanos as f64 / 1e6)
+    } else if nanos >= 1_000 {
+      format!("{:.3} us", nanos as f64 / 1e3)
+    } else {
+      format!("{} ns", nanos)
+    };
+    println!("{:<26} {:>12} per iteration ({} iterations)", result.name, time, result.iterations);
+  }
+}
+
+// Encode a corpus of images at several qindexes, and check that the built-in decoder and
+// any external decoders which are available all give the encoder's reconstruction
+fn run_conformance(args: &ConformanceArgs) {
+  let mut config = match &args.preset_file {
+    None => EncoderConfig::default(),
+    Some(path) => load_profile(path),
+  };
+  if let Some(threads) = args.threads {
+    config.threads = threads;
+  }
+  if let Err(e) = config.validate() {
+    fail((&e).into(), e.to_string());
+  }
+  // Cases are run in parallel, so each one gets a single thread
+  let pool = ThreadPool::new(config.threads);
+  config.threads = 1;
+
+  let qindexes = if args.qindex.is_empty() { DEFAULT_QINDEXES.to_vec() } else { args.qindex.clone() };
+  if qindexes.contains(&0) {
+    fail(ErrorKind::Usage, "Invalid qindex 0, must be between 1 and 255".into());
+  }
+
+  let corpus = if args.inputs.is_empty() {
+    default_corpus()
+  } else {
+    load_corpus(&args.inputs).unwrap_or_else(|e| {
+      fail(parse_error_kind(&e), format!("Failed to read the corpus: {}", e));
+    })
+  };
+  if corpus.is_empty() {
+    fail(ErrorKind::Usage, "No .y4m files found in the corpus".into());
+  }
+
+  // Decoders which weren't asked for explicitly are skipped if they aren't installed
+  let mut decoders = Vec::new();
+  for (kind, command) in [(DecoderKind::Dav1d, &args.dav1d), (DecoderKind::Aomdec, &args.aomdec)] {
+    let decoder = ExternalDecoder {
+      kind: kind,
+      command: command.clone().unwrap_or_else(|| kind.name().into()),
+    };
+    if decoder.is_available() {
+      decoders.push(decoder);
+    } else if command.is_some() {
+      fail(ErrorKind::Usage, format!("Failed to run {}", decoder.command.display()));
+    } else {
+      println!("{} not found, skipping it", kind.name());
+    }
+  }
+
+  let work_dir = args.work_dir.clone().unwrap_or_else(|| {
+    std::env::temp_dir().join(format!("tinyavif-conformance-{}", std::process::id()))
+  });
+  if let Err(e) = std::fs::create_dir_all(&work_dir) {
+    fail(ErrorKind::Io, format!("Failed to create {}: {}", work_dir.display(), e));
+  }
+
+  let cases: Vec<_> = corpus.iter().flat_map(|(name, source)| {
+    qindexes.iter().map(move |&qindex| (name, source, qindex))
+  }).collect();
+  let results = pool.map(&cases, |&(name, source, qindex)| {
+    check_case(&config, name, source, qindex, &decoders, &work_dir)
+  });
+
+  let mut failures = 0;
+  for (&(name, _, qindex), result) in cases.iter().zip(results) {
+    let result = result.unwrap_or_else(|e| {
+      match e {
+        EncodeError::Io(e) => fail(ErrorKind::Io, format!("Failed to write to {}: {}", work_dir.display(), e)),
+        EncodeError::Config(e) => fail((&e).into(), format!("Can't encode {}: {}", name, e)),
+        e => fail(ErrorKind::Internal, format!("Failed to encode {}: {}", name, e)),
+      }
+    });
+    let outcomes: Vec<_> = result.outcomes.iter().map(|(decoder, outcome)| format!("{} {}", decoder, outcome)).collect();
+    println!("{:<24} qindex {:<3}  {}", name, qindex, outcomes.join(", "));
+    if !result.passed() {
+      failures += 1;
+    }
+  }
+  let cases = cases.len();
+
+  println!("{} of {} cases matched", cases - failures, cases);
+  if failures > 0 {
+    println!("Streams and decoded images are in {}", work_dir.display());
+    fail(ErrorKind::Internal, format!("{} cases did not match the encoder's reconstruction", failures));
+  }
+  if args.work_dir.is_none() {
+    // Nothing to investigate, so there's no point keeping the files
+    let _ = std::fs::remove_dir_all(&work_dir);
+  }
+}
+
+// Write an RGB debug image, as PNG or Y4M depending on the file extension
+fn write_debug_image(path: &Path, width: usize, height: usize, rgb: &[u8]) {
+  if path.extension().and_then(|ext| ext.to_str()) == Some("png") {
+    let result = File::create(path).and_then(|file| write_png(&mut BufWriter::new(file), width, height, rgb));
+    if let Err(e) = result {
+      fail(ErrorKind::Io, format!("Failed to write {}: {}", path.display(), e));
+    }
+  } else {
+    let conversion = ColorConversion::from_config(&EncoderConfig::default()).unwrap();
+    write_y4m(path, &conversion.rgb_to_frame(rgb, width, height, width * 3, 3));
+  }
+}
+
+// Everything recorded by --stats-json, for one encoded image
+struct StatsReport<'a> {
+  input_path: &'a Path,
+  // Size of the input, before any resizing
+  input_width: usize,
+  input_height: usize,
+  output_path: &'a Path,
+  // Size of the encoded image
+  width: usize,
+  height: usize,
+  config: &'a EncoderConfig,
+  encoded: &'a EncodedImage,
+  output: &'a OutputInfo,
+  // Image the encoder was given, to measure the quality against. None if not available
+  source: Option<&'a Frame>,
+  // Time taken by each stage before encoding started
+  times: Vec<(&'static str, Duration)>,
+  total_time: Duration,
+}
+
+// Print the --stats output, followed by the build details, so that differences between
+// two sets of statistics can be told apart from differences between builds
+fn print_stats(stats: &EncodeStats) {
+  print!("{}", stats);
+  println!("Build:");
+  for line in build_info().to_string().lines() {
+    println!("  {}", line);
+  }
+}
+
+// Number formatted for JSON, which has no infinities (eg. the PSNR of a lossless image) or NaNs
+fn json_number(value: f64) -> String {
+  if value.is_finite() {
+    format!("{}", value)
+  } else {
+    "null".into()
+  }
+}
+
+// List of "name": value pairs, as the body of a JSON object
+fn json_fields<T: AsRef<str>>(fields: &[(&str, T)]) -> String {
+  fields.iter().map(|(name, value)| format!("\"{}\": {}", name, value.as_ref())).collect::<Vec<_>>().join(", ")
+}
+
+// JSON array of strings
+fn json_string_list(items: &[&str]) -> String {
+  format!("[{}]", items.iter().map(|item| format!("\"{}\"", json_escape(item))).collect::<Vec<_>>().join(", "))
+}
+
+// Write the --stats-json summary. The layout is meant to stay stable, so that tools
+// can compare encodes from different versions; "version" is increased if it ever
+// has to change incompatibly
+fn write_stats_json(path: &Path, report: &StatsReport) {
+  let config = report.config;
+  let stats = &report.encoded.stats;
+  let mut json = String::new();
+  writeln!(json, "{{").unwrap();
+  writeln!(json, "  \"version\": 1,").unwrap();
+  let build = build_info();
+  writeln!(json, "  \"build\": {{{}}},", json_fields(&[
+    ("version", format!("\"{}\"", build.version)),
+    ("rustc", format!("\"{}\"", json_escape(build.rustc_version))),
+    ("profile", format!("\"{}\"", build.profile)),
+    ("target_arch", format!("\"{}\"", build.target_arch)),
+    ("target_os", format!("\"{}\"", build.target_os)),
+    ("features", json_string_list(&build.features)),
+    ("target_features", json_string_list(&build.target_features)),
+    ("simd", format!("\"{:?}\"", build.simd_level)),
+  ])).unwrap();
+  writeln!(json, "  \"input\": {{{}}},", json_fields(&[
+    ("path", format!("\"{}\"", json_escape(&report.input_path.display().to_string()))),
+    ("width", report.input_width.to_string()),
+    ("height", report.input_height.to_string()),
+  ])).unwrap();
+  writeln!(json, "  \"output\": {{{}}},", json_fields(&[
+    ("path", format!("\"{}\"", json_escape(&report.output_path.display().to_string()))),
+    ("width", report.width.to_string()),
+    ("height", report.height.to_string()),
+    ("bit_depth", config.bit_depth.to_string()),
+    ("subsampling", format!("\"{}\"", config.subsampling.name())),
+    ("bytes", report.output.file_size.to_string()),
+  ])).unwrap();
+  writeln!(json, "  \"qindex\": {},", config.qindex).unwrap();
+
+  let obu_bytes: Vec<_> = report.output.obu_sizes.iter().map(|&(name, size)| (name, size.to_string())).collect();
+  writeln!(json, "  \"obu_bytes\": {{{}}},", json_fields(&obu_bytes)).unwrap();
+  let tile_bits: Vec<_> = ALL_BIT_CATEGORIES.iter()
+    .map(|&category| (category.name(), json_number(stats.category_bits(category)))).collect();
+  writeln!(json, "  \"tile_bits\": {{{}}},", json_fields(&tile_bits)).unwrap();
+
+  // Coefficient bits and quality of each plane
+  let planes = if config.subsampling == ChromaSubsampling::Monochrome { 1 } else { 3 };
+  let images = report.source.zip(report.encoded.recon.as_ref());
+  let psnr = images.map(|(source, recon)| frame_psnr(source, recon));
+  let plane_json: Vec<_> = (0..planes).map(|plane| {
+    let ssim = images.map(|(source, recon)| plane_ssim(source.plane(plane), recon.plane(plane)));
+    let fields = json_fields(&[
+      ("coeff_bits", json_number(stats.category_bits(BitCategory::coeffs(plane)))),
+      ("psnr", psnr.map_or("null".into(), |psnr| json_number(psnr[plane]))),
+      ("ssim", ssim.map_or("null".into(), json_number)),
+    ]);
+    (["y", "u", "v"][plane], format!("{{{}}}", fields))
+  }).collect();
+  writeln!(json, "  \"planes\": {{{}}},", json_fields(&plane_json)).unwrap();
+
+  // Times in seconds
+  let stage_times = &stats.stage_times;
+  let mut times = report.times.clone();
+  times.extend([
+    ("prefilter", stage_times.prefilter),
+    ("first_pass", stage_times.first_pass),
+    ("tile_coding", stage_times.tile_coding),
+    ("write", report.output.write_time),
+    ("total", report.total_time),
+  ]);
+  let times: Vec<_> = times.iter().map(|&(name, time)| (name, json_number(time.as_secs_f64()))).collect();
+  writeln!(json, "  \"times\": {{{}}}", json_fields(&times)).unwrap();
+  writeln!(json, "}}").unwrap();
+
+  if let Err(e) = std::fs::write(path, json) {
+    fail(ErrorKind::Io, format!("Failed to write {}: {}", path.display(), e));
+  }
+}
+
+fn write_trace(path: &Path, trace: &str) {
+  if let Err(e) = std::fs::write(path, trace) {
+    fail(ErrorKind::Io, format!("Failed to write {}: {}", path.display(), e));
+  }
+}
+
+// Read a foreground mask, which must match the size of the input image
+fn read_mask(path: &Path, width: usize, height: usize) -> Box<Frame> {
+  let mask = File::open(path).map_err(Y4MError::from).and_then(|file| Y4MReader::new(file)?.read_frame());
+  let mask = mask.unwrap_or_else(|e| {
+    fail(y4m_error_kind(&e), format!("Failed to read {}: {}", path.display(), e));
+  });
+  if (mask.y().crop_width(), mask.y().crop_height()) != (width, height) {
+    fail(ErrorKind::Usage, format!("Foreground mask {} is {}x{}, but the input is {}x{}", path.display(),
+                                   mask.y().crop_width(), mask.y().crop_height(), width, height));
+  }
+  return mask;
+}
+
+// Encode the images given to --depth-map and --gain-map. Gain map metadata has already
+// been checked, so any failure is our own
+fn encode_extra_items(extra_images: &[(Frame, AvifItemKind)], config: &EncoderConfig) -> Vec<AvifItem> {
+  return extra_images.iter().map(|(image, kind)| {
+    encode_plane_item(image.y(), kind.clone(), config).unwrap_or_else(|e| {
+      fail(ErrorKind::Internal, format!("Failed to encode extra image: {}", e));
+    })
+  }).collect();
+}
+
+// Load a settings profile, for --preset-file
+fn load_profile(path: &Path) -> EncoderConfig {
+  let text = std::fs::read_to_string(path).unwrap_or_else(|e| {
+    fail(ErrorKind::Io, format!("Failed to load settings profile {}: {}", path.display(), e));
+  });
+  return EncoderConfig::from_toml(&text).unwrap_or_else(|e| {
+    fail((&e).into(), format!("Failed to load settings profile {}: {}", path.display(), e));
+  });
+}
+
+// Read the Exif data for --exif, which must be in the form pack_avif() expects
+fn read_exif(path: &Path) -> Vec<u8> {
+  let exif = std::fs::read(path).unwrap_or_else(|e| {
+    fail(ErrorKind::Io, format!("Failed to read {}: {}", path.display(), e));
+  });
+  if exif_tiff_header_offset(&exif).is_none() {
+    fail(ErrorKind::InvalidInput, format!("{} is not Exif data: it must start with a TIFF header, optionally \
+                                           after \"Exif\\0\\0\"", path.display()));
+  }
+  return exif;
+}
+
+// Read a grayscale map for --roi-map, --depth-map or --gain-map, from a PGM file or the luma plane of
+// a Y4M file. Unlike a foreground mask, these can be any size
+fn read_map(path: &Path) -> Frame {
+  let result = if path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("pgm")) {
+    File::open(path).and_then(|file| read_pgm(&mut BufReader::new(file))).map_err(Y4MError::from)
+  } else {
+    File::open(path).map_err(Y4MError::from).and_then(|file| Y4MReader::new(file)?.read_frame()).map(|frame| *frame)
+  };
+  return result.unwrap_or_else(|e| {
+    fail(y4m_error_kind(&e), format!("Failed to read {}: {}", path.display(), e));
+  });
+}
+
+fn write_y4m(path: &Path, frame: &Frame) {
+  let result = File::create(path).and_then(|file| {
+    let mut y4m = Y4MWriter::new(file, frame.y().crop_width(), frame.y().crop_height())?;
+    y4m.write_frame(frame)
+  });
+  if let Err(e) = result {
+    fail(ErrorKind::Io, format!("Failed to write {}: {}", path.display(), e));
   }
 }
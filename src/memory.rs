@@ -0,0 +1,70 @@
+// Copyright (c) 2024-2025, The tinyavif contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+// Accounting for the encoder's memory use
+//
+// Nearly all of the memory an encode needs is in a few large buffers: copies of the
+// image (the source, any filtered or downscaled versions, and the reconstruction), the
+// per-4x4 mode info, the per-block decisions waiting to be written, and the output. Their
+// sizes only depend on the image size and the settings, so they can be added up before
+// encoding, to check them against a limit (EncoderConfig::max_memory) and fail, or switch
+// to streaming, before allocating any of them.
+
+use std::fmt;
+
+use crate::frame::FrameGeometry;
+
+// Memory held by an 8-bit 4:2:0 frame of the given size, including its padding
+pub fn frame_bytes(width: usize, height: usize) -> usize {
+  let size = FrameGeometry::new(width, height);
+  return size.y_width * size.y_height + 2 * size.uv_width * size.uv_height;
+}
+
+// Format a number of bytes for messages, eg. "12.3 MiB"
+pub fn format_bytes(bytes: usize) -> String {
+  const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+  if bytes < 1024 {
+    return format!("{} bytes", bytes);
+  }
+  let mut value = bytes as f64 / 1024.0;
+  let mut unit = 0;
+  while value >= 1024.0 && unit + 1 < UNITS.len() {
+    value /= 1024.0;
+    unit += 1;
+  }
+  return format!("{:.1} {}", value, UNITS[unit]);
+}
+
+// The buffers which an encode needs at once, as returned by memory_estimate() in
+// av1_encoder.rs
+#[derive(Clone, Debug, Default)]
+pub struct MemoryEstimate {
+  // What each buffer is for, and its size in bytes
+  pub buffers: Vec<(&'static str, usize)>,
+}
+
+impl MemoryEstimate {
+  pub fn add(&mut self, name: &'static str, bytes: usize) {
+    if bytes > 0 {
+      self.buffers.push((name, bytes));
+    }
+  }
+
+  pub fn total(&self) -> usize {
+    return self.buffers.iter().map(|&(_, bytes)| bytes).sum();
+  }
+}
+
+// Eg. "36.6 MiB (source 11.0 MiB, recon 11.0 MiB, ...)"
+impl fmt::Display for MemoryEstimate {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let buffers: Vec<_> = self.buffers.iter().map(|&(name, bytes)| format!("{} {}", name, format_bytes(bytes))).collect();
+    write!(f, "{} ({})", format_bytes(self.total()), buffers.join(", "))
+  }
+}
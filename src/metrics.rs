@@ -0,0 +1,123 @@
+// Copyright (c) 2024-2025, The tinyavif contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+// Image quality metrics
+
+use crate::array2d::{Array2D, Slice2D};
+use crate::frame::{Frame, Plane};
+
+// Check that two planes have the same crop size, and return it
+// Metrics only ever look at the crop region, as the padding isn't part of the image, and
+// including it would skew the results for images whose size isn't a multiple of 8.
+fn crop_size(a: &Plane, b: &Plane) -> (usize, usize) {
+  assert!(a.crop_width() == b.crop_width() && a.crop_height() == b.crop_height(),
+          "Can't compare a {}x{} plane against a {}x{} plane",
+          a.crop_width(), a.crop_height(), b.crop_width(), b.crop_height());
+  return (a.crop_width(), a.crop_height());
+}
+
+// Sum of squared errors over the crop region of two planes of the same size
+pub fn plane_sse(a: &Plane, b: &Plane) -> u64 {
+  let (width, height) = crop_size(a, b);
+
+  let mut sse = 0u64;
+  for row in 0..height {
+    let a_row = &a.pixels()[row][0..width];
+    let b_row = &b.pixels()[row][0..width];
+    for (&p, &q) in a_row.iter().zip(b_row) {
+      let diff = (p as i64) - (q as i64);
+      sse += (diff * diff) as u64;
+    }
+  }
+  return sse;
+}
+
+// Sum of squared errors between two blocks of the same size
+pub fn block_sse(a: Slice2D<u8>, b: Slice2D<u8>) -> u64 {
+  assert!(a.rows() == b.rows() && a.cols() == b.cols());
+  let mut sse = 0u64;
+  for i in 0..a.rows() {
+    for (&p, &q) in a[i].iter().zip(&b[i]) {
+      let diff = (p as i64) - (q as i64);
+      sse += (diff * diff) as u64;
+    }
+  }
+  return sse;
+}
+
+// Peak signal-to-noise ratio in dB, for 8-bit samples
+// Identical images give a PSNR of infinity
+pub fn psnr(sse: u64, num_pixels: usize) -> f64 {
+  if sse == 0 {
+    return f64::INFINITY;
+  }
+  let mse = (sse as f64) / (num_pixels as f64);
+  return 10.0 * (255.0 * 255.0 / mse).log10();
+}
+
+// Structural similarity (SSIM) of `b`, using `a` as the reference, from 0 to 1
+// This compares the mean, variance, and covariance of the pixels in 8x8 windows, spaced 4
+// pixels apart, and averages the result. Only the crop region is compared.
+pub fn plane_ssim(a: &Plane, b: &Plane) -> f64 {
+  let (width, height) = crop_size(a, b);
+
+  // Stabilizing constants from the original SSIM paper, for 8-bit samples
+  let c1 = (0.01 * 255.0) * (0.01 * 255.0);
+  let c2 = (0.03 * 255.0) * (0.03 * 255.0);
+
+  // Images smaller than a window are treated as a single window
+  let window_width = width.min(8);
+  let window_height = height.min(8);
+  let n = (window_width * window_height) as f64;
+
+  let mut total = 0.0;
+  let mut count = 0;
+  for row in (0 ..= height - window_height).step_by(4) {
+    for col in (0 ..= width - window_width).step_by(4) {
+      let mut sum_a = 0u64;
+      let mut sum_b = 0u64;
+      let mut sum_aa = 0u64;
+      let mut sum_bb = 0u64;
+      let mut sum_ab = 0u64;
+      for i in row .. row + window_height {
+        let a_row = &a.pixels()[i][col .. col + window_width];
+        let b_row = &b.pixels()[i][col .. col + window_width];
+        for (&p, &q) in a_row.iter().zip(b_row) {
+          let (p, q) = (p as u64, q as u64);
+          sum_a += p;
+          sum_b += q;
+          sum_aa += p * p;
+          sum_bb += q * q;
+          sum_ab += p * q;
+        }
+      }
+      let mean_a = sum_a as f64 / n;
+      let mean_b = sum_b as f64 / n;
+      let var_a = sum_aa as f64 / n - mean_a * mean_a;
+      let var_b = sum_bb as f64 / n - mean_b * mean_b;
+      let covar = sum_ab as f64 / n - mean_a * mean_b;
+      total += ((2.0 * mean_a * mean_b + c1) * (2.0 * covar + c2)) /
+               ((mean_a * mean_a + mean_b * mean_b + c1) * (var_a + var_b + c2));
+      count += 1;
+    }
+  }
+  return total / count as f64;
+}
+
+// PSNR of each plane of `b`, using `a` as the reference
+// Only the crop region is compared
+pub fn frame_psnr(a: &Frame, b: &Frame) -> [f64; 3] {
+  let mut result = [0.0; 3];
+  for plane in 0..3 {
+    let a_plane = a.plane(plane);
+    let sse = plane_sse(a_plane, b.plane(plane));
+    result[plane] = psnr(sse, a_plane.crop_width() * a_plane.crop_height());
+  }
+  return result;
+}
@@ -0,0 +1,391 @@
+// Copyright (c) 2024-2025, The tinyavif contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+// Palette mode, one of AV1's screen content tools
+//
+// Instead of predicting a block from its neighbours, a palette block sends a short
+// list of colours (2 to 8 of them), then codes each pixel as an index into that list.
+// This suits screenshots, diagrams and text, where blocks often contain only a few
+// distinct colours with sharp edges between them - which are expensive to code as
+// DCT residuals.
+//
+// Luma has its own palette. U and V share a palette of (U, V) pairs, with a single
+// colour index map for both planes.
+//
+// This module holds the pieces of palette syntax which are shared between the encoder
+// and the decoder; the block-level flags, whose contexts depend on the neighbouring
+// blocks, are handled alongside the other mode info.
+
+use bytemuck::Zeroable;
+
+use crate::array2d::{Array2D, Slice2D, Slice2DMut};
+use crate::cdf::*;
+use crate::entropycode::{EntropyReader, SymbolWriter};
+use crate::util::*;
+
+pub const PALETTE_MIN_SIZE: usize = 2;
+pub const PALETTE_MAX_SIZE: usize = 8;
+
+// Number of already-coded neighbours used to build the colour context
+const PALETTE_NUM_NEIGHBORS: usize = 3;
+const PALETTE_COLOR_HASH_MULTIPLIERS: [usize; PALETTE_NUM_NEIGHBORS] = [1, 2, 2];
+// Maps each possible hash value to a colour context. Only the hashes 2 and 5-8 can occur
+const PALETTE_COLOR_CONTEXT: [usize; 9] = [usize::MAX, usize::MAX, 0, usize::MAX, usize::MAX, 4, 3, 2, 1];
+
+// The palette for one plane type of one block
+// For luma only channel 0 is used; for chroma, channel 0 holds the U values and
+// channel 1 holds the corresponding V values.
+#[derive(Zeroable, Clone, Copy, PartialEq)]
+pub struct Palette {
+  // Number of colours, or 0 if the block doesn't use a palette
+  pub size: usize,
+  pub colors: [[u8; PALETTE_MAX_SIZE]; 2],
+}
+
+// Find the distinct colours in a block, if there are few enough for a palette
+// `channels` is [Y] or [U, V]. The palette is sorted by the first channel, as
+// required by the bitstream, and is returned along with the colour index map.
+pub fn find_palette(channels: &[Slice2D<u8>]) -> Option<(Palette, Array2D<u8>)> {
+  let h = channels[0].rows();
+  let w = channels[0].cols();
+  let mut entries: Vec<[u8; 2]> = Vec::with_capacity(PALETTE_MAX_SIZE);
+  for i in 0..h {
+    for j in 0..w {
+      let mut entry = [0; 2];
+      for (c, pixels) in channels.iter().enumerate() {
+        entry[c] = pixels[i][j];
+      }
+      if !entries.contains(&entry) {
+        if entries.len() == PALETTE_MAX_SIZE {
+          return None;
+        }
+        entries.push(entry);
+      }
+    }
+  }
+  if entries.len() < PALETTE_MIN_SIZE {
+    // A flat block is better off with DC_PRED
+    return None;
+  }
+  entries.sort();
+
+  let mut palette = Palette::zeroed();
+  palette.size = entries.len();
+  for (k, entry) in entries.iter().enumerate() {
+    palette.colors[0][k] = entry[0];
+    palette.colors[1][k] = entry[1];
+  }
+
+  let mut map = Array2D::zeroed(h, w);
+  for i in 0..h {
+    for j in 0..w {
+      let mut entry = [0; 2];
+      for (c, pixels) in channels.iter().enumerate() {
+        entry[c] = pixels[i][j];
+      }
+      map[i][j] = entries.iter().position(|&e| e == entry).unwrap() as u8;
+    }
+  }
+
+  return Some((palette, map));
+}
+
+// Palette prediction: fill a block of one channel according to the colour index map
+pub fn predict_palette(mut pixels: Slice2DMut<u8>, colors: &[u8], map: &Array2D<u8>) {
+  for i in 0..map.rows() {
+    for j in 0..map.cols() {
+      pixels[i][j] = colors[map[i][j] as usize];
+    }
+  }
+}
+
+// get_palette_cache(): merge the (sorted) first-channel colours of the above and left
+// palettes, removing duplicates. The caller is responsible for deciding which
+// neighbours are available; in particular, the above block is not used across
+// superblock row boundaries.
+pub fn palette_cache(above: Option<&Palette>, left: Option<&Palette>) -> Vec<u8> {
+  let above = above.map_or(&[][..], |p| &p.colors[0][..p.size]);
+  let left = left.map_or(&[][..], |p| &p.colors[0][..p.size]);
+
+  let mut cache = Vec::with_capacity(above.len() + left.len());
+  let mut above_idx = 0;
+  let mut left_idx = 0;
+  while above_idx < above.len() && left_idx < left.len() {
+    let above_c = above[above_idx];
+    let left_c = left[left_idx];
+    if left_c < above_c {
+      if cache.last() != Some(&left_c) {
+        cache.push(left_c);
+      }
+      left_idx += 1;
+    } else {
+      if cache.last() != Some(&above_c) {
+        cache.push(above_c);
+      }
+      above_idx += 1;
+      if left_c == above_c {
+        left_idx += 1;
+      }
+    }
+  }
+  for &c in above[above_idx..].iter().chain(&left[left_idx..]) {
+    if cache.last() != Some(&c) {
+      cache.push(c);
+    }
+  }
+  return cache;
+}
+
+// The spec's CeilLog2(), which is defined to be 0 for inputs 0 and 1
+fn ceil_log2_or_zero(x: usize) -> u32 {
+  if x < 2 { 0 } else { ceil_log2(x) }
+}
+
+// Number of bits needed to represent `value` as an unsigned literal
+fn bits_needed(value: usize) -> u32 {
+  if value == 0 { 0 } else { floor_log2(value) + 1 }
+}
+
+// Write the colours of a palette
+// `plane_type` is 0 for luma, 1 for chroma. `cache` is the result of palette_cache()
+pub fn write_palette_colors<W: SymbolWriter>(w: &mut W, palette: &Palette, cache: &[u8], plane_type: usize) {
+  let n = palette.size;
+  w.trace_label("palette_colors", plane_type);
+
+  // The first channel (Y or U) is sorted. Colours which are also in the cache are flagged,
+  // and the rest are sent as an initial value followed by (sorted) deltas.
+  // The decoder sorts the combined list afterwards.
+  let mut remaining = palette.colors[0][..n].to_vec();
+  let mut num_cached = 0;
+  for &c in cache {
+    if num_cached == n {
+      break;
+    }
+    let pos = remaining.iter().position(|&x| x == c);
+    w.write_literal(pos.is_some() as u32, 1); // use_palette_color_cache
+    if let Some(pos) = pos {
+      remaining.remove(pos);
+      num_cached += 1;
+    }
+  }
+
+  if !remaining.is_empty() {
+    w.write_literal(remaining[0] as u32, 8);
+  }
+  if remaining.len() > 1 {
+    // Luma colours are distinct, so the deltas are coded minus 1. Chroma palettes can
+    // repeat a U value (with different V values), so their deltas can be 0.
+    let delta_offset = if plane_type == 0 { 1 } else { 0 };
+    let max_delta = remaining.windows(2).map(|pair| (pair[1] - pair[0]) as usize - delta_offset).max().unwrap();
+    let min_bits = 8 - 3;
+    let mut bits = max(min_bits, bits_needed(max_delta));
+    w.write_literal(bits - min_bits, 2); // palette_num_extra_bits
+
+    for pair in remaining.windows(2) {
+      let delta = (pair[1] - pair[0]) as usize - delta_offset;
+      w.write_literal(delta as u32, bits);
+      // The bit count shrinks once the remaining range of values gets small enough
+      let range = 256 - (pair[1] as usize) - delta_offset;
+      bits = min(bits, ceil_log2_or_zero(range));
+    }
+  }
+
+  if plane_type == 1 {
+    // V values are unsorted, so can either be sent as literals, or as signed deltas
+    // (modulo 256) if that's cheaper
+    let v = &palette.colors[1][..n];
+    let deltas: Vec<i32> = v.windows(2).map(|pair| {
+      let d = (pair[1] as i32) - (pair[0] as i32);
+      // Pick the shortest way around
+      if d > 128 { d - 256 } else if d < -128 { d + 256 } else { d }
+    }).collect();
+    let min_bits = 8 - 4;
+    let max_delta = deltas.iter().map(|&d| unsigned_abs(d) as usize).max().unwrap();
+    let bits = max(min_bits, bits_needed(max_delta));
+    let literal_bits = 8 * n as u32;
+    let delta_bits = 2 + 8 + deltas.iter().map(|&d| bits + (d != 0) as u32).sum::<u32>();
+
+    if bits <= min_bits + 3 && delta_bits < literal_bits {
+      w.write_literal(1, 1); // delta_encode_palette_colors_v
+      w.write_literal(bits - min_bits, 2);
+      w.write_literal(v[0] as u32, 8);
+      for &d in &deltas {
+        w.write_literal(unsigned_abs(d), bits);
+        if d != 0 {
+          w.write_literal((d < 0) as u32, 1);
+        }
+      }
+    } else {
+      w.write_literal(0, 1); // delta_encode_palette_colors_v
+      for &c in v {
+        w.write_literal(c as u32, 8);
+      }
+    }
+  }
+}
+
+// Read the colours of a palette of the given size, the inverse of write_palette_colors()
+pub fn read_palette_colors(r: &mut EntropyReader, size: usize, cache: &[u8], plane_type: usize) -> Palette {
+  let mut palette = Palette::zeroed();
+  palette.size = size;
+
+  let colors = &mut palette.colors[0];
+  let mut idx = 0;
+  for &c in cache {
+    if idx == size {
+      break;
+    }
+    if r.read_literal(1) != 0 {
+      colors[idx] = c;
+      idx += 1;
+    }
+  }
+  if idx < size {
+    colors[idx] = r.read_literal(8) as u8;
+    idx += 1;
+  }
+  if idx < size {
+    let delta_offset = if plane_type == 0 { 1 } else { 0 };
+    let min_bits = 8 - 3;
+    let mut bits = min_bits + r.read_literal(2);
+    while idx < size {
+      let delta = r.read_literal(bits) as usize + delta_offset;
+      colors[idx] = min(colors[idx - 1] as usize + delta, 255) as u8;
+      let range = 256 - (colors[idx] as usize) - delta_offset;
+      bits = min(bits, ceil_log2_or_zero(range));
+      idx += 1;
+    }
+  }
+  colors[..size].sort();
+
+  if plane_type == 1 {
+    let v = &mut palette.colors[1];
+    if r.read_literal(1) != 0 {
+      // delta_encode_palette_colors_v
+      let min_bits = 8 - 4;
+      let bits = min_bits + r.read_literal(2);
+      v[0] = r.read_literal(8) as u8;
+      for idx in 1..size {
+        let mut delta = r.read_literal(bits) as i32;
+        if delta != 0 && r.read_literal(1) != 0 {
+          delta = -delta;
+        }
+        let mut val = (v[idx - 1] as i32) + delta;
+        if val < 0 {
+          val += 256;
+        }
+        if val >= 256 {
+          val -= 256;
+        }
+        v[idx] = clamp(val, 0, 255) as u8;
+      }
+    } else {
+      for c in v[..size].iter_mut() {
+        *c = r.read_literal(8) as u8;
+      }
+    }
+  }
+
+  return palette;
+}
+
+// get_palette_color_context(): rank the palette indices by how often they occur among
+// the left, above-left and above neighbours of position (row, col), and derive a context
+// from the pattern of neighbours. Returns the context and the ranked index order; the
+// symbol coded for each pixel is its position in this order.
+pub fn color_context(map: &Array2D<u8>, row: usize, col: usize, n: usize) -> (usize, [u8; PALETTE_MAX_SIZE]) {
+  let mut scores = [0usize; PALETTE_MAX_SIZE];
+  let mut order = [0, 1, 2, 3, 4, 5, 6, 7];
+  if col > 0 {
+    scores[map[row][col - 1] as usize] += 2;
+  }
+  if row > 0 && col > 0 {
+    scores[map[row - 1][col - 1] as usize] += 1;
+  }
+  if row > 0 {
+    scores[map[row - 1][col] as usize] += 2;
+  }
+
+  // Partial insertion sort, to move the highest-scoring indices to the front.
+  // Ties are broken in favour of the lower index
+  for i in 0..PALETTE_NUM_NEIGHBORS {
+    let mut max_score = scores[i];
+    let mut max_idx = i;
+    for j in i + 1 .. n {
+      if scores[j] > max_score {
+        max_score = scores[j];
+        max_idx = j;
+      }
+    }
+    if max_idx != i {
+      let max_color_order = order[max_idx];
+      for k in (i + 1 ..= max_idx).rev() {
+        scores[k] = scores[k - 1];
+        order[k] = order[k - 1];
+      }
+      scores[i] = max_score;
+      order[i] = max_color_order;
+    }
+  }
+
+  let hash: usize = (0..PALETTE_NUM_NEIGHBORS).map(|i| scores[i] * PALETTE_COLOR_HASH_MULTIPLIERS[i]).sum();
+  return (PALETTE_COLOR_CONTEXT[hash], order);
+}
+
+fn color_cdf(plane_type: usize, n: usize, ctx: usize) -> &'static [u16] {
+  match (plane_type, n) {
+    (0, 2) => &palette_size_2_y_color_cdf[ctx],
+    (0, 3) => &palette_size_3_y_color_cdf[ctx],
+    (0, 4) => &palette_size_4_y_color_cdf[ctx],
+    (0, 5) => &palette_size_5_y_color_cdf[ctx],
+    (0, 6) => &palette_size_6_y_color_cdf[ctx],
+    (0, 7) => &palette_size_7_y_color_cdf[ctx],
+    (0, 8) => &palette_size_8_y_color_cdf[ctx],
+    (1, 2) => &palette_size_2_uv_color_cdf[ctx],
+    (1, 3) => &palette_size_3_uv_color_cdf[ctx],
+    (1, 4) => &palette_size_4_uv_color_cdf[ctx],
+    (1, 5) => &palette_size_5_uv_color_cdf[ctx],
+    (1, 6) => &palette_size_6_uv_color_cdf[ctx],
+    (1, 7) => &palette_size_7_uv_color_cdf[ctx],
+    (1, 8) => &palette_size_8_uv_color_cdf[ctx],
+    _ => panic!("Invalid palette size {}", n)
+  }
+}
+
+// Write a colour index map. The first index is coded directly, then the rest are
+// coded in wavefront order (along anti-diagonals), so that the above and left
+// neighbours of each pixel are always available for the context.
+pub fn write_color_map<W: SymbolWriter>(w: &mut W, map: &Array2D<u8>, n: usize, plane_type: usize) {
+  let h = map.rows();
+  let wd = map.cols();
+  w.trace_label("color_index_map", plane_type);
+  w.write_ns(map[0][0] as u32, n as u32);
+  for i in 1 .. h + wd - 1 {
+    for j in (i.saturating_sub(h - 1) ..= min(i, wd - 1)).rev() {
+      let (ctx, order) = color_context(map, i - j, j, n);
+      let symbol = order.iter().position(|&idx| idx == map[i - j][j]).unwrap();
+      w.trace_label("palette_color_idx", ctx);
+      w.write_symbol(symbol, color_cdf(plane_type, n, ctx));
+    }
+  }
+}
+
+// Read a colour index map of the given size, the inverse of write_color_map()
+pub fn read_color_map(r: &mut EntropyReader, n: usize, plane_type: usize, h: usize, wd: usize) -> Array2D<u8> {
+  let mut map = Array2D::zeroed(h, wd);
+  map[0][0] = r.read_ns(n as u32) as u8;
+  for i in 1 .. h + wd - 1 {
+    for j in (i.saturating_sub(h - 1) ..= min(i, wd - 1)).rev() {
+      let (ctx, order) = color_context(&map, i - j, j, n);
+      let symbol = r.read_symbol(color_cdf(plane_type, n, ctx));
+      map[i - j][j] = order[symbol];
+    }
+  }
+  return map;
+}
@@ -0,0 +1,88 @@
+// Copyright (c) 2024-2025, The tinyavif contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+// Reader for binary PGM (grayscale Netpbm) images, as used for masks
+//
+// The format is a text header of "P5", the width, the height, and the maximum value,
+// separated by whitespace (with `#` comments allowed), then a single whitespace character
+// and one byte per pixel. 16-bit images (maximum value above 255) aren't supported.
+
+use std::io;
+use std::io::prelude::*;
+
+use crate::frame::Frame;
+use crate::util::*;
+
+// Largest width or height we accept, as for Y4M files
+const MAX_DIMENSION: usize = 65536;
+
+fn invalid(message: &str) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, format!("Invalid PGM file: {}", message))
+}
+
+// Read the next header field, skipping whitespace and comments before it. The single
+// whitespace character after the field is consumed too.
+fn read_field<R: BufRead>(r: &mut R) -> Result<String, io::Error> {
+  let mut field = String::new();
+  let mut in_comment = false;
+  loop {
+    let mut byte = [0u8];
+    if r.read(&mut byte)? == 0 {
+      return Err(invalid("header ends early"));
+    }
+    let c = byte[0] as char;
+    if in_comment {
+      in_comment = c != '\n';
+    } else if c == '#' && field.is_empty() {
+      in_comment = true;
+    } else if c.is_ascii_whitespace() {
+      if !field.is_empty() {
+        return Ok(field);
+      }
+    } else if field.len() >= 16 {
+      return Err(invalid("header field is too long"));
+    } else {
+      field.push(c);
+    }
+  }
+}
+
+fn read_number<R: BufRead>(r: &mut R, name: &str, max: usize) -> Result<usize, io::Error> {
+  let field = read_field(r)?;
+  return match field.parse::<usize>() {
+    Ok(value) if value >= 1 && value <= max => Ok(value),
+    _ => Err(invalid(&format!("{} \"{}\" is not a number from 1 to {}", name, field, max))),
+  };
+}
+
+// Read a PGM image into the luma plane of a new frame. The chroma planes are left at zero.
+// Images with a maximum value below 255 are scaled up to the full 8-bit range.
+pub fn read_pgm<R: BufRead>(r: &mut R) -> Result<Frame, io::Error> {
+  if read_field(r)? != "P5" {
+    return Err(invalid("not a binary PGM image (P5)"));
+  }
+  let width = read_number(r, "width", MAX_DIMENSION)?;
+  let height = read_number(r, "height", MAX_DIMENSION)?;
+  let max_value = read_number(r, "maximum value", 255)?;
+
+  let mut frame = Frame::new(height, width);
+  let pixels = frame.y_mut().pixels_mut();
+  for i in 0..height {
+    let row = &mut pixels[i][..width];
+    r.read_exact(row).map_err(|e| match e.kind() {
+      io::ErrorKind::UnexpectedEof => invalid("pixel data ends early"),
+      _ => e,
+    })?;
+    for p in row.iter_mut() {
+      *p = (min(*p as usize, max_value) * 255 / max_value) as u8;
+    }
+  }
+  frame.y_mut().fill_padding();
+  return Ok(frame);
+}
@@ -0,0 +1,98 @@
+// Copyright (c) 2024-2025, The tinyavif contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+// Minimal PNG writer, for debug images which are easier to view as PNG than as Y4M
+//
+// This writes 8-bit RGB images without any compression: the image data is wrapped in
+// "stored" deflate blocks, which every PNG reader understands. The files are large, but
+// this avoids needing a compression library.
+
+use std::io;
+use std::io::prelude::*;
+
+use byteorder::{BigEndian, LittleEndian, WriteBytesExt};
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+
+// Largest amount of data in one stored deflate block
+const MAX_STORED_BLOCK: usize = 65535;
+
+// Write an RGB image, given as 3 bytes per pixel with no padding between rows
+pub fn write_png<W: Write>(w: &mut W, width: usize, height: usize, rgb: &[u8]) -> Result<(), io::Error> {
+  assert!(width > 0 && height > 0);
+  assert!(rgb.len() == width * height * 3);
+
+  w.write_all(&PNG_SIGNATURE)?;
+
+  let mut ihdr = Vec::new();
+  ihdr.write_u32::<BigEndian>(width as u32)?;
+  ihdr.write_u32::<BigEndian>(height as u32)?;
+  ihdr.write_u8(8)?; // Bit depth
+  ihdr.write_u8(2)?; // Colour type 2 = RGB
+  ihdr.write_u8(0)?; // Compression method (the only one defined)
+  ihdr.write_u8(0)?; // Filter method (the only one defined)
+  ihdr.write_u8(0)?; // Not interlaced
+  write_chunk(w, b"IHDR", &ihdr)?;
+
+  // Each row is preceded by its filter type, which is always 0 (no filtering) here
+  let mut raw = Vec::with_capacity(height * (1 + width * 3));
+  for row in rgb.chunks(width * 3) {
+    raw.push(0);
+    raw.extend_from_slice(row);
+  }
+
+  // zlib stream: header, stored deflate blocks, then the Adler-32 checksum of the raw data
+  let mut idat = vec![0x78, 0x01];
+  let num_blocks = raw.len().div_ceil(MAX_STORED_BLOCK);
+  for (i, block) in raw.chunks(MAX_STORED_BLOCK).enumerate() {
+    let is_final = i == num_blocks - 1;
+    idat.push(is_final as u8); // BFINAL flag, then block type 0 (stored)
+    idat.write_u16::<LittleEndian>(block.len() as u16)?;
+    idat.write_u16::<LittleEndian>(!(block.len() as u16))?;
+    idat.extend_from_slice(block);
+  }
+  idat.write_u32::<BigEndian>(adler32(&raw))?;
+  write_chunk(w, b"IDAT", &idat)?;
+
+  write_chunk(w, b"IEND", &[])?;
+  Ok(())
+}
+
+// Each chunk is its length, type, and data, followed by a CRC of the type and data
+fn write_chunk<W: Write>(w: &mut W, typ: &[u8; 4], data: &[u8]) -> Result<(), io::Error> {
+  w.write_u32::<BigEndian>(data.len() as u32)?;
+  w.write_all(typ)?;
+  w.write_all(data)?;
+  let crc = crc32(&[&typ[..], data].concat());
+  w.write_u32::<BigEndian>(crc)?;
+  Ok(())
+}
+
+// CRC-32 as used by PNG (and zip, gzip, etc.), computed a bit at a time
+fn crc32(data: &[u8]) -> u32 {
+  let mut crc = 0xFFFFFFFFu32;
+  for &byte in data {
+    crc ^= byte as u32;
+    for _ in 0..8 {
+      let mask = (crc & 1).wrapping_neg();
+      crc = (crc >> 1) ^ (0xEDB88320 & mask);
+    }
+  }
+  return !crc;
+}
+
+fn adler32(data: &[u8]) -> u32 {
+  let mut a = 1u32;
+  let mut b = 0u32;
+  for &byte in data {
+    a = (a + byte as u32) % 65521;
+    b = (b + a) % 65521;
+  }
+  return (b << 16) | a;
+}
@@ -0,0 +1,143 @@
+// Copyright (c) 2024-2025, The tinyavif contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+// Pre-filters, which adjust the source image before it is encoded
+//
+// Each filter takes a strength from 1 to 10 (0 in the config means the filter is off).
+// Filters run in the order listed by prefilters(), each on the output of the last.
+
+use crate::array2d::Array2D;
+use crate::config::EncoderConfig;
+use crate::frame::{Frame, Plane};
+use crate::util::*;
+
+// Largest strength accepted by any filter
+pub const MAX_PREFILTER_STRENGTH: u8 = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Prefilter {
+  // Gaussian blur, to remove sensor noise and film grain, which is expensive to code
+  // and which the encoder would otherwise spend bits trying to preserve
+  Denoise(u8),
+  // Unsharp mask on the luma plane, to counteract the softening caused by quantization
+  Sharpen(u8),
+}
+
+impl Prefilter {
+  // See EncoderConfig::deterministic for what `deterministic` does
+  pub fn apply(&self, src: &Frame, deterministic: bool) -> Frame {
+    let mut dst = Frame::new(src.y().crop_height(), src.y().crop_width());
+    match *self {
+      Prefilter::Denoise(strength) => {
+        // Chroma is at half resolution, so uses half the blur radius
+        let sigma = 0.15 * strength as f32;
+        for plane in 0..3 {
+          let plane_sigma = if plane > 0 { sigma / 2.0 } else { sigma };
+          let blurred = gaussian_blur(src.plane(plane), plane_sigma, deterministic);
+          write_plane(dst.plane_mut(plane), |i, j| blurred[i][j]);
+        }
+      },
+      Prefilter::Sharpen(strength) => {
+        // Sharpening chroma tends to produce colour fringes around edges, so leave it alone
+        let amount = 0.1 * strength as f32;
+        let src_y = src.y().pixels();
+        let blurred = gaussian_blur(src.y(), 1.0, deterministic);
+        write_plane(dst.y_mut(), |i, j| {
+          let pixel = src_y[i][j] as f32;
+          pixel + amount * (pixel - blurred[i][j])
+        });
+        for plane in 1..3 {
+          let src_pixels = src.plane(plane).pixels();
+          write_plane(dst.plane_mut(plane), |i, j| src_pixels[i][j] as f32);
+        }
+      },
+    }
+    return dst;
+  }
+}
+
+// The filters selected by the given config, in the order they should be applied
+pub fn prefilters(config: &EncoderConfig) -> Vec<Prefilter> {
+  let mut filters = Vec::new();
+  if config.denoise > 0 {
+    filters.push(Prefilter::Denoise(config.denoise));
+  }
+  if config.sharpen > 0 {
+    filters.push(Prefilter::Sharpen(config.sharpen));
+  }
+  return filters;
+}
+
+// Run all of the selected filters over the source image
+// Returns None if there is nothing to do, so that the caller can keep using the original.
+pub fn apply_prefilters(config: &EncoderConfig, source: &Frame) -> Option<Frame> {
+  let mut result: Option<Frame> = None;
+  for filter in prefilters(config) {
+    let input = result.as_ref().unwrap_or(source);
+    result = Some(filter.apply(input, config.deterministic));
+  }
+  return result;
+}
+
+// Fill in the crop region of a plane from a function giving the (unrounded) value of
+// each pixel, then update the padding to match
+fn write_plane<F: Fn(usize, usize) -> f32>(dst: &mut Plane, f: F) {
+  let crop_width = dst.crop_width();
+  let crop_height = dst.crop_height();
+  let pixels = dst.pixels_mut();
+  for i in 0..crop_height {
+    for j in 0..crop_width {
+      pixels[i][j] = f(i, j).round().clamp(0.0, 255.0) as u8;
+    }
+  }
+  dst.fill_padding();
+}
+
+// Separable Gaussian blur over the crop region of a plane, extending the edge pixels
+// outwards where the kernel overlaps the edge of the image
+fn gaussian_blur(src: &Plane, sigma: f32, deterministic: bool) -> Array2D<f32> {
+  let crop_width = src.crop_width();
+  let crop_height = src.crop_height();
+  let src_pixels = src.pixels();
+
+  // Truncate the kernel at 3 standard deviations, which keeps over 99% of its weight
+  let radius = (3.0 * sigma).ceil() as isize;
+  let exp = if deterministic { portable_exp } else { f32::exp };
+  let mut kernel: Vec<f32> = (-radius ..= radius).map(|k| exp(-((k * k) as f32) / (2.0 * sigma * sigma)))
+                                                 .collect();
+  let total: f32 = kernel.iter().sum();
+  for weight in kernel.iter_mut() {
+    *weight /= total;
+  }
+
+  let mut tmp = Array2D::<f32>::zeroed(crop_height, crop_width);
+  for i in 0..crop_height {
+    for j in 0..crop_width {
+      let mut sum = 0.0;
+      for (k, weight) in kernel.iter().enumerate() {
+        let col = clamp(j as isize + k as isize - radius, 0, crop_width as isize - 1) as usize;
+        sum += weight * src_pixels[i][col] as f32;
+      }
+      tmp[i][j] = sum;
+    }
+  }
+
+  let mut result = Array2D::<f32>::zeroed(crop_height, crop_width);
+  for i in 0..crop_height {
+    for j in 0..crop_width {
+      let mut sum = 0.0;
+      for (k, weight) in kernel.iter().enumerate() {
+        let row = clamp(i as isize + k as isize - radius, 0, crop_height as isize - 1) as usize;
+        sum += weight * tmp[row][j];
+      }
+      result[i][j] = sum;
+    }
+  }
+  return result;
+}
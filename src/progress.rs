@@ -0,0 +1,164 @@
+// Copyright (c) 2024-2025, The tinyavif contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+// Progress reporting for encodes of several images
+//
+// The encoder's progress callback (see AV1Encoder::set_progress_callback()) only knows
+// about the image it's encoding. A ProgressTracker follows a whole batch of images, such
+// as the levels of an image pyramid or the frames of a sequence, and turns those
+// callbacks into the progress of the batch, its throughput, and an estimate of the time
+// remaining.
+//
+// Work is counted in pixels, so that a large image counts for more than a small one, and
+// the estimate stays sensible when the images differ in size.
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::av1_encoder::EncodeProgress;
+
+// How far through a batch of images we are
+#[derive(Clone, Copy, Debug)]
+pub struct BatchProgress {
+  pub images_done: usize,
+  pub images: usize,
+  pub pixels_done: usize,
+  pub pixels: usize,
+  // Time since the tracker was created
+  pub elapsed: Duration,
+}
+
+impl BatchProgress {
+  pub fn fraction(&self) -> f64 {
+    if self.pixels == 0 { 1.0 } else { self.pixels_done as f64 / self.pixels as f64 }
+  }
+
+  pub fn percent(&self) -> f64 {
+    100.0 * self.fraction()
+  }
+
+  // Images finished per second so far
+  pub fn images_per_second(&self) -> f64 {
+    let seconds = self.elapsed.as_secs_f64();
+    if seconds > 0.0 { self.images_done as f64 / seconds } else { 0.0 }
+  }
+
+  // Estimated time until the batch is finished, assuming the rest of it is encoded at the
+  // same rate (in pixels per second) as it has been so far. None until any pixels are done
+  pub fn eta(&self) -> Option<Duration> {
+    let fraction = self.fraction();
+    if fraction <= 0.0 {
+      return None;
+    }
+    return Some(self.elapsed.mul_f64((1.0 - fraction) / fraction));
+  }
+}
+
+// Format a duration as m:ss, or h:mm:ss from an hour up
+fn format_duration(duration: Duration) -> String {
+  let seconds = duration.as_secs();
+  if seconds >= 3600 {
+    format!("{}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60)
+  } else {
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+  }
+}
+
+// One-line summary, eg. "[##########..........]  50.0%  2/4 images  1.25 images/s  ETA 0:03"
+// The image count and throughput are left out for a single image
+impl fmt::Display for BatchProgress {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    const BAR_WIDTH: usize = 30;
+    let filled = (self.fraction() * BAR_WIDTH as f64).round() as usize;
+    write!(f, "[{}{}] {:5.1}%", "#".repeat(filled), ".".repeat(BAR_WIDTH - filled), self.percent())?;
+    if self.images > 1 {
+      write!(f, "  {}/{} images  {:.2} images/s", self.images_done, self.images, self.images_per_second())?;
+    }
+    match self.eta() {
+      Some(eta) => write!(f, "  ETA {}", format_duration(eta)),
+      None => write!(f, "  ETA -:--"),
+    }
+  }
+}
+
+struct TrackerState {
+  // Size of each image, in the order they're encoded
+  image_pixels: Vec<usize>,
+  images_done: usize,
+  // Pixels in finished images, and in the finished superblock rows of the current one
+  finished_pixels: usize,
+  current_pixels: usize,
+  start: Instant,
+}
+
+// Tracks a batch of images, which are encoded one after another
+// Clones share the same state, so one can be handed to each encoder's progress callback.
+#[derive(Clone)]
+pub struct ProgressTracker {
+  state: Arc<Mutex<TrackerState>>,
+  display: Arc<dyn Fn(&BatchProgress) + Send + Sync>,
+}
+
+impl ProgressTracker {
+  // Track a batch of images with the given numbers of pixels. `display` is called with the
+  // batch's progress each time it changes, eg. to redraw a progress bar
+  pub fn new<F: Fn(&BatchProgress) + Send + Sync + 'static>(image_pixels: Vec<usize>, display: F) -> Self {
+    Self {
+      state: Arc::new(Mutex::new(TrackerState {
+        image_pixels: image_pixels,
+        images_done: 0,
+        finished_pixels: 0,
+        current_pixels: 0,
+        start: Instant::now(),
+      })),
+      display: Arc::new(display),
+    }
+  }
+
+  // Callback to pass to AV1Encoder::set_progress_callback() for the current image
+  pub fn encoder_callback(&self) -> impl Fn(&EncodeProgress) + Send + Sync + 'static {
+    let tracker = self.clone();
+    return move |progress| tracker.update(|state| {
+      let pixels = state.image_pixels.get(state.images_done).copied().unwrap_or(0);
+      state.current_pixels = pixels * progress.sb_rows_done / progress.sb_rows;
+    });
+  }
+
+  // Mark the current image as finished, and move on to the next one
+  pub fn image_done(&self) {
+    self.update(|state| {
+      state.finished_pixels += state.image_pixels.get(state.images_done).copied().unwrap_or(0);
+      state.current_pixels = 0;
+      state.images_done += 1;
+    });
+  }
+
+  pub fn progress(&self) -> BatchProgress {
+    return Self::snapshot(&self.state.lock().unwrap());
+  }
+
+  fn snapshot(state: &TrackerState) -> BatchProgress {
+    BatchProgress {
+      images_done: state.images_done,
+      images: state.image_pixels.len(),
+      pixels_done: state.finished_pixels + state.current_pixels,
+      pixels: state.image_pixels.iter().sum(),
+      elapsed: state.start.elapsed(),
+    }
+  }
+
+  // The display function is called with the lock held, so that updates from different
+  // threads are shown in order
+  fn update<F: FnOnce(&mut TrackerState)>(&self, change: F) {
+    let mut state = self.state.lock().unwrap();
+    change(&mut state);
+    (self.display)(&Self::snapshot(&state));
+  }
+}
@@ -0,0 +1,135 @@
+// Copyright (c) 2024-2025, The tinyavif contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+// Rate estimation
+//
+// This allows encoder decisions (modes, partitions, quantization) to estimate
+// how many bits a set of symbols would take, without running the real EntropyWriter.
+//
+// All costs are fixed-point numbers of bits, with COST_SHIFT fractional bits.
+// The costs are computed using integer arithmetic only, so that any decisions
+// based on them are identical on all platforms.
+
+use crate::consts::ac_q;
+use crate::entropycode::SymbolWriter;
+use crate::util::*;
+
+pub const COST_SHIFT: u32 = 9;
+pub const ONE_BIT: u32 = 1 << COST_SHIFT;
+
+// The entropy coder guarantees each symbol a minimum amount of range, roughly
+// 4 / range of the total. Since the range is always in [32768, 65536), this works out
+// to between 2 and 4 units of probability (where 32768 = 100%); we use the midpoint.
+const EC_MIN_PROB: u32 = 3;
+
+// Compute log2(value) as a fixed-point number with COST_SHIFT fractional bits
+//
+// This works by normalizing value into [1, 2), then repeatedly squaring it:
+// each time the result exceeds 2, that means the next fractional bit of the
+// logarithm is a 1.
+pub fn log2_fixed(value: u32) -> u32 {
+  assert!(value > 0);
+  let int_part = floor_log2(value);
+
+  // Normalize value into [1, 2), as a 1.30 fixed-point number
+  let mut y = (value as u64) << (30 - int_part);
+  let mut result = int_part << COST_SHIFT;
+  for i in (0..COST_SHIFT).rev() {
+    y = (y * y) >> 30;
+    if y >= (2 << 30) {
+      y >>= 1;
+      result |= 1 << i;
+    }
+  }
+  return result;
+}
+
+// Cost of a symbol with the given probability, where 32768 = 100%
+pub fn prob_cost(prob: u32) -> u32 {
+  assert!(prob <= 32768);
+  let prob = max(prob, 1);
+  return (15 << COST_SHIFT) - log2_fixed(prob);
+}
+
+// Cost of coding `symbol` using the given CDF
+// The probability is computed the same way as in EntropyWriter::write_symbol(), which
+// only uses the top 9 bits of each CDF value, then adds the minimum probability.
+pub fn symbol_cost(symbol: usize, cdf: &[u16]) -> u32 {
+  let num_symbols = cdf.len() + 1;
+  assert!(symbol < num_symbols);
+
+  let inv_lo = if symbol == 0 { 32768 } else { 32768 - (cdf[symbol - 1] as u32) };
+  let inv_hi = if symbol == num_symbols - 1 { 0 } else { 32768 - (cdf[symbol] as u32) };
+  let prob = (((inv_lo >> 6) - (inv_hi >> 6)) << 6) + EC_MIN_PROB;
+  return prob_cost(min(prob, 32768));
+}
+
+// Cost of a single bit, where p_zero is the probability of the bit being 0
+pub fn bit_cost(value: usize, p_zero: u16) -> u32 {
+  symbol_cost(value, &[p_zero])
+}
+
+// Combined rate-distortion cost of a decision, as a distortion with COST_SHIFT fractional bits.
+// `rate` is converted into an equivalent distortion using the usual approximation that lambda,
+// the slope of the rate-distortion curve, is proportional to the square of the quantizer step
+// size. For our transforms, the step size in the pixel domain is roughly ac_q / 8. The
+// quantizers scale with the pixel values at higher bit depths, so this holds for those too.
+pub fn rd_cost(sse: u64, rate: u64, qindex: u8, bit_depth: u8) -> u64 {
+  let ac_q = ac_q(qindex, bit_depth) as u64;
+  let lambda = (ac_q * ac_q) >> 6;
+  return (sse << COST_SHIFT) + lambda * rate;
+}
+
+// Cost of an N-bit literal
+pub fn literal_cost(nbits: u32) -> u32 {
+  nbits * ONE_BIT
+}
+
+// Cost of a Golomb-coded value, as written by SymbolWriter::write_golomb()
+pub fn golomb_cost(value: u32) -> u32 {
+  let length = floor_log2(value + 1);
+  literal_cost(2 * length + 1)
+}
+
+// A SymbolWriter which just adds up the cost of everything written to it
+// This allows syntax-writing code to be reused for rate estimation
+pub struct RateCounter {
+  cost: u64
+}
+
+impl RateCounter {
+  pub fn new() -> Self {
+    Self {
+      cost: 0
+    }
+  }
+
+  // Total cost so far, in units of 1/ONE_BIT bits
+  pub fn cost(&self) -> u64 {
+    self.cost
+  }
+
+  // Total cost so far, rounded to the nearest whole bit
+  pub fn bits(&self) -> u64 {
+    round2(self.cost, COST_SHIFT)
+  }
+}
+
+impl SymbolWriter for RateCounter {
+  fn write_symbol(&mut self, symbol: usize, cdf: &[u16]) {
+    self.cost += symbol_cost(symbol, cdf) as u64;
+  }
+
+  // Literal bits always cost exactly one bit each, so skip the per-bit calculation
+  fn write_literal(&mut self, value: u32, nbits: u32) {
+    assert!(nbits <= 32);
+    assert!(nbits == 32 || value < (1 << nbits));
+    self.cost += literal_cost(nbits) as u64;
+  }
+}
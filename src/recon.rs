@@ -9,22 +9,25 @@
 
 // Reconstruction functions
 
-use crate::array2d::Array2D;
+use std::ops::{Index, IndexMut};
+
+use crate::array2d::{Array2D, Slice2D, Slice2DMut};
 use crate::consts::*;
+use crate::enums::*;
 use crate::txfm::*;
 use crate::util::*;
 
-// Predictions - only DC_PRED for now
+// Predictions
 pub fn dc_predict(pixels: &mut Array2D<u8>, y0: usize, x0: usize, h: usize, w: usize) {
   // For now, as we only ever use one tile, we can infer the haveLeft and haveAbove flags as:
   let haveLeft = x0 > 0;
   let haveAbove = y0 > 0;
 
+  // The above row is contiguous in memory, so sum it as a slice, which the
+  // compiler can vectorize. The left column has to be gathered one pixel at a time.
   let mut sum = 0usize;
   if haveAbove {
-    for j in 0..w {
-      sum += pixels[y0 - 1][x0 + j] as usize;
-    }
+    sum += pixels[y0 - 1][x0 .. x0 + w].iter().map(|&p| p as usize).sum::<usize>();
   }
   if haveLeft {
     for i in 0..h {
@@ -43,7 +46,405 @@ pub fn dc_predict(pixels: &mut Array2D<u8>, y0: usize, x0: usize, h: usize, w: u
   };
 
   let pred = clamp(avg, 0, 255) as u8;
-  pixels.fill_region(y0, x0, h, w, &pred);
+  for i in 0..h {
+    pixels[y0 + i][x0 .. x0 + w].fill(pred);
+  }
+}
+
+// Information about the surroundings of a block which the non-DC intra predictors need,
+// beyond what can be inferred from the block position
+#[derive(Clone, Copy)]
+pub struct IntraNeighbours {
+  // Whether the blocks to the above-right and below-left have been coded yet, in which
+  // case the directional predictors can extend the edges into them
+  pub have_above_right: bool,
+  pub have_below_left: bool,
+
+  // Whether the above or left block uses one of the SMOOTH modes, which selects
+  // a different set of edge filter strengths
+  pub smooth_neighbour: bool,
+
+  // Value of enable_intra_edge_filter from the sequence header
+  pub edge_filter: bool,
+}
+
+// For our fixed partitioning, where each 64x64 superblock is split all the way down to
+// 8x8 blocks in Z order, work out whether the blocks to the above-right and below-left of
+// an 8x8 block have been coded yet. Positions and sizes are in units of 8x8 blocks.
+//
+// Each 8x8 block holds exactly one luma and one chroma transform block, so the result
+// applies to every plane.
+pub fn intra_neighbour_availability(block_row: usize, block_col: usize,
+                                    block_rows: usize, block_cols: usize) -> (bool, bool) {
+  const SB_BLOCKS: usize = 8;
+  let same_sb_earlier = |r: usize, c: usize| {
+    z_order(r % SB_BLOCKS, c % SB_BLOCKS) < z_order(block_row % SB_BLOCKS, block_col % SB_BLOCKS)
+  };
+
+  // The whole superblock row above is available, but the superblock to the right isn't
+  let have_above_right = if block_row == 0 || block_col + 1 >= block_cols {
+    false
+  } else if (block_row - 1) / SB_BLOCKS < block_row / SB_BLOCKS {
+    true
+  } else if (block_col + 1) / SB_BLOCKS > block_col / SB_BLOCKS {
+    false
+  } else {
+    same_sb_earlier(block_row - 1, block_col + 1)
+  };
+
+  // The superblock to the left is available, but not the superblock row below
+  let have_below_left = if block_col == 0 || block_row + 1 >= block_rows ||
+                           (block_row + 1) / SB_BLOCKS > block_row / SB_BLOCKS {
+    false
+  } else if (block_col - 1) / SB_BLOCKS < block_col / SB_BLOCKS {
+    true
+  } else {
+    same_sb_earlier(block_row + 1, block_col - 1)
+  };
+
+  return (have_above_right, have_below_left);
+}
+
+// Position of a block within the Z order traversal of a superblock
+fn z_order(row: usize, col: usize) -> usize {
+  let mut index = 0;
+  for bit in 0..3 {
+    index |= ((row >> bit) & 1) << (2 * bit + 1);
+    index |= ((col >> bit) & 1) << (2 * bit);
+  }
+  return index;
+}
+
+// One edge (the above row or left column) of neighbouring pixels
+// The spec indexes these from -1 (the top-left pixel), or from -2 after upsampling,
+// so store them with an offset
+struct Edge {
+  pixels: Vec<i32>
+}
+
+const EDGE_OFFSET: isize = 2;
+
+impl Index<isize> for Edge {
+  type Output = i32;
+  fn index(&self, i: isize) -> &i32 {
+    &self.pixels[(i + EDGE_OFFSET) as usize]
+  }
+}
+
+impl IndexMut<isize> for Edge {
+  fn index_mut(&mut self, i: isize) -> &mut i32 {
+    &mut self.pixels[(i + EDGE_OFFSET) as usize]
+  }
+}
+
+// Gather the above row and left column for a block, with any unavailable pixels filled in
+// as per the edge preparation process in the spec (section 7.11.2).
+// Each edge holds w + h pixels plus the top-left pixel, with room to be upsampled later.
+fn intra_edges(pixels: &Array2D<u8>, n: &IntraNeighbours,
+               y0: usize, x0: usize, h: usize, w: usize) -> (Edge, Edge) {
+  // As in dc_predict(), we can infer these flags from the block position
+  let haveLeft = x0 > 0;
+  let haveAbove = y0 > 0;
+
+  let maxX = pixels.cols() - 1;
+  let maxY = pixels.rows() - 1;
+
+  let len = (2 * (w + h)) + (EDGE_OFFSET as usize) + 1;
+  let mut above = Edge { pixels: vec![0; len] };
+  let mut left = Edge { pixels: vec![0; len] };
+
+  let aboveLimit = min(maxX, x0 + (if n.have_above_right { 2 * w } else { w }) - 1);
+  let leftLimit = min(maxY, y0 + (if n.have_below_left { 2 * h } else { h }) - 1);
+  for i in 0 .. w + h {
+    above[i as isize] = if haveAbove {
+      pixels[y0 - 1][min(aboveLimit, x0 + i)] as i32
+    } else if haveLeft {
+      pixels[y0][x0 - 1] as i32
+    } else {
+      127
+    };
+
+    left[i as isize] = if haveLeft {
+      pixels[min(leftLimit, y0 + i)][x0 - 1] as i32
+    } else if haveAbove {
+      pixels[y0 - 1][x0] as i32
+    } else {
+      129
+    };
+  }
+
+  let top_left = match (haveAbove, haveLeft) {
+    (true, true) => pixels[y0 - 1][x0 - 1] as i32,
+    (true, false) => pixels[y0 - 1][x0] as i32,
+    (false, true) => pixels[y0][x0 - 1] as i32,
+    (false, false) => 128
+  };
+  above[-1] = top_left;
+  left[-1] = top_left;
+
+  return (above, left);
+}
+
+// SMOOTH_PRED: a weighted blend of the above row and left column, each interpolated
+// towards the opposite edge's far pixel (bottom-left or top-right respectively)
+pub fn smooth_predict(pixels: &mut Array2D<u8>, n: &IntraNeighbours, y0: usize, x0: usize, h: usize, w: usize) {
+  let (above, left) = intra_edges(pixels, n, y0, x0, h, w);
+  let weights_y = sm_weights(h);
+  let weights_x = sm_weights(w);
+  let bottom_left = left[h as isize - 1];
+  let top_right = above[w as isize - 1];
+
+  for i in 0..h {
+    for j in 0..w {
+      let wy = weights_y[i] as i32;
+      let wx = weights_x[j] as i32;
+      let pred = wy * above[j as isize] + (256 - wy) * bottom_left +
+                 wx * left[i as isize] + (256 - wx) * top_right;
+      pixels[y0 + i][x0 + j] = round2(pred, 9) as u8;
+    }
+  }
+}
+
+// PAETH_PRED: each pixel copies whichever of its above, left, and top-left neighbours
+// is closest to the gradient estimate above + left - top_left
+pub fn paeth_predict(pixels: &mut Array2D<u8>, n: &IntraNeighbours, y0: usize, x0: usize, h: usize, w: usize) {
+  let (above, left) = intra_edges(pixels, n, y0, x0, h, w);
+  let top_left = above[-1];
+
+  for i in 0..h {
+    for j in 0..w {
+      let above = above[j as isize];
+      let left = left[i as isize];
+      let base = above + left - top_left;
+      let pLeft = abs(base - left);
+      let pTop = abs(base - above);
+      let pTopLeft = abs(base - top_left);
+      let pred = if pLeft <= pTop && pLeft <= pTopLeft {
+        left
+      } else if pTop <= pTopLeft {
+        above
+      } else {
+        top_left
+      };
+      pixels[y0 + i][x0 + j] = pred as u8;
+    }
+  }
+}
+
+// Directional prediction (V_PRED, H_PRED, and D45_PRED to D67_PRED), which projects
+// the neighbouring pixels across the block at an angle. The angle is the base angle
+// for the mode, adjusted by angle_delta steps of 3 degrees.
+// This follows the spec's directional intra prediction process (section 7.11.2.4)
+pub fn directional_predict(pixels: &mut Array2D<u8>, n: &IntraNeighbours, mode: PredictionMode, angle_delta: i8,
+                           y0: usize, x0: usize, h: usize, w: usize) {
+  let (mut above, mut left) = intra_edges(pixels, n, y0, x0, h, w);
+  let haveLeft = x0 > 0;
+  let haveAbove = y0 > 0;
+  let maxX = pixels.cols() - 1;
+  let maxY = pixels.rows() - 1;
+
+  let pAngle = mode_to_angle[mode as usize] + (angle_delta as i32) * ANGLE_STEP;
+
+  // The edges are smoothed for steep angles, and upsampled for shallow angles in
+  // small blocks, so that the interpolation below is more accurate
+  let mut upsampleAbove = 0;
+  let mut upsampleLeft = 0;
+  if n.edge_filter {
+    let filterType = n.smooth_neighbour;
+    if pAngle != 90 && pAngle != 180 {
+      if pAngle > 90 && pAngle < 180 && (w + h) >= 24 {
+        // Filter the top-left pixel using its two neighbours
+        let s = left[0] * 5 + above[-1] * 6 + above[0] * 5;
+        above[-1] = round2(s, 4);
+        left[-1] = above[-1];
+      }
+      if haveAbove {
+        let strength = intra_edge_filter_strength(w, h, filterType, pAngle - 90);
+        let numPx = min(w, maxX - x0 + 1) + (if pAngle < 90 { h } else { 0 }) + 1;
+        intra_edge_filter(&mut above, numPx, strength);
+      }
+      if haveLeft {
+        let strength = intra_edge_filter_strength(w, h, filterType, pAngle - 180);
+        let numPx = min(h, maxY - y0 + 1) + (if pAngle > 180 { w } else { 0 }) + 1;
+        intra_edge_filter(&mut left, numPx, strength);
+      }
+    }
+    if use_intra_edge_upsample(w, h, filterType, pAngle - 90) {
+      upsampleAbove = 1;
+      intra_edge_upsample(&mut above, w + (if pAngle < 90 { h } else { 0 }));
+    }
+    if use_intra_edge_upsample(w, h, filterType, pAngle - 180) {
+      upsampleLeft = 1;
+      intra_edge_upsample(&mut left, h + (if pAngle > 180 { w } else { 0 }));
+    }
+  }
+
+  // Interpolate between the two edge pixels closest to where the projection lands,
+  // with a position given in units of 1/32 pixel
+  let interpolate = |edge: &Edge, base: i32, shift: i32| {
+    round2(edge[base as isize] * (32 - shift) + edge[base as isize + 1] * shift, 5)
+  };
+
+  let dx = if pAngle < 90 {
+    dr_intra_derivative[pAngle as usize] as i32
+  } else if pAngle > 90 && pAngle < 180 {
+    dr_intra_derivative[(180 - pAngle) as usize] as i32
+  } else {
+    0
+  };
+  let dy = if pAngle > 90 && pAngle < 180 {
+    dr_intra_derivative[(pAngle - 90) as usize] as i32
+  } else if pAngle > 180 {
+    dr_intra_derivative[(270 - pAngle) as usize] as i32
+  } else {
+    0
+  };
+
+  for i in 0..h as i32 {
+    for j in 0..w as i32 {
+      let pred = if pAngle < 90 {
+        // Projects from the above row only, which may run out to the right
+        let idx = (i + 1) * dx;
+        let base = (idx >> (6 - upsampleAbove)) + (j << upsampleAbove);
+        let shift = ((idx << upsampleAbove) >> 1) & 0x1F;
+        let maxBaseX = ((w + h - 1) << upsampleAbove) as i32;
+        if base < maxBaseX {
+          interpolate(&above, base, shift)
+        } else {
+          above[maxBaseX as isize]
+        }
+      } else if pAngle > 90 && pAngle < 180 {
+        // Projects from the above row where possible, otherwise from the left column
+        let idx = (j << 6) - (i + 1) * dx;
+        let base = idx >> (6 - upsampleAbove);
+        if base >= -(1 << upsampleAbove) {
+          let shift = ((idx << upsampleAbove) >> 1) & 0x1F;
+          interpolate(&above, base, shift)
+        } else {
+          let idx = (i << 6) - (j + 1) * dy;
+          let base = idx >> (6 - upsampleLeft);
+          let shift = ((idx << upsampleLeft) >> 1) & 0x1F;
+          interpolate(&left, base, shift)
+        }
+      } else if pAngle > 180 {
+        // Projects from the left column only
+        let idx = (j + 1) * dy;
+        let base = (idx >> (6 - upsampleLeft)) + (i << upsampleLeft);
+        let shift = ((idx << upsampleLeft) >> 1) & 0x1F;
+        interpolate(&left, base, shift)
+      } else if pAngle == 90 {
+        above[j as isize]
+      } else {
+        left[i as isize]
+      };
+      pixels[y0 + i as usize][x0 + j as usize] = pred as u8;
+    }
+  }
+}
+
+// Strength (0 = off, up to 3) of the filter applied to an edge, given the difference
+// between the prediction angle and the edge's own direction
+fn intra_edge_filter_strength(w: usize, h: usize, filterType: bool, delta: i32) -> usize {
+  let d = abs(delta);
+  let blkWh = w + h;
+  let mut strength = 0;
+  if !filterType {
+    if blkWh <= 8 {
+      if d >= 56 { strength = 1; }
+    } else if blkWh <= 16 {
+      if d >= 40 { strength = 1; }
+    } else if blkWh <= 24 {
+      if d >= 8 { strength = 1; }
+      if d >= 16 { strength = 2; }
+      if d >= 32 { strength = 3; }
+    } else if blkWh <= 32 {
+      if d >= 1 { strength = 1; }
+      if d >= 4 { strength = 2; }
+      if d >= 32 { strength = 3; }
+    } else {
+      if d >= 1 { strength = 3; }
+    }
+  } else {
+    if blkWh <= 8 {
+      if d >= 40 { strength = 1; }
+      if d >= 64 { strength = 2; }
+    } else if blkWh <= 16 {
+      if d >= 20 { strength = 1; }
+      if d >= 48 { strength = 2; }
+    } else if blkWh <= 24 {
+      if d >= 4 { strength = 3; }
+    } else {
+      if d >= 1 { strength = 3; }
+    }
+  }
+  return strength;
+}
+
+// Smooth the first `sz` pixels of an edge, starting from the top-left pixel at index -1
+fn intra_edge_filter(edge: &mut Edge, sz: usize, strength: usize) {
+  if strength == 0 {
+    return;
+  }
+
+  let orig: Vec<i32> = (0..sz).map(|i| edge[i as isize - 1]).collect();
+  for i in 1..sz {
+    let mut s = 0;
+    for j in 0..INTRA_EDGE_TAPS {
+      let k = clamp((i + j) as isize - 2, 0, sz as isize - 1) as usize;
+      s += intra_edge_kernel[strength - 1][j] * orig[k];
+    }
+    edge[i as isize - 1] = (s + 8) >> 4;
+  }
+}
+
+fn use_intra_edge_upsample(w: usize, h: usize, filterType: bool, delta: i32) -> bool {
+  let d = abs(delta);
+  let blkWh = w + h;
+  if d <= 0 || d >= 40 {
+    false
+  } else if !filterType {
+    blkWh <= 16
+  } else {
+    blkWh <= 8
+  }
+}
+
+// Double the resolution of the first `numPx` pixels of an edge, so that it runs from
+// index -2 to 2 * numPx - 2
+fn intra_edge_upsample(edge: &mut Edge, numPx: usize) {
+  let mut dup = vec![0; numPx + 3];
+  dup[0] = edge[-1];
+  for i in -1 .. numPx as isize {
+    dup[(i + 2) as usize] = edge[i];
+  }
+  dup[numPx + 2] = edge[numPx as isize - 1];
+
+  edge[-2] = dup[0];
+  for i in 0..numPx {
+    let s = -dup[i] + 9 * dup[i + 1] + 9 * dup[i + 2] - dup[i + 3];
+    let s = clamp(round2(s, 4), 0, 255);
+    edge[2 * i as isize - 1] = s;
+    edge[2 * i as isize] = dup[i + 2];
+  }
+}
+
+// Generate the intra prediction for a block using the given mode
+// The angle delta is only used by the directional modes
+pub fn intra_predict(pixels: &mut Array2D<u8>, n: &IntraNeighbours, mode: PredictionMode, angle_delta: i8,
+                     y0: usize, x0: usize, h: usize, w: usize) {
+  match mode {
+    PredictionMode::DC_PRED => dc_predict(pixels, y0, x0, h, w),
+    PredictionMode::SMOOTH_PRED => smooth_predict(pixels, n, y0, x0, h, w),
+    PredictionMode::PAETH_PRED => paeth_predict(pixels, n, y0, x0, h, w),
+    _ if is_directional_mode(mode) => directional_predict(pixels, n, mode, angle_delta, y0, x0, h, w),
+    _ => panic!("Unsupported intra prediction mode {:?}", mode)
+  }
+}
+
+// Check whether a source block exactly matches its prediction, so that the residual
+// is zero and the transform pipeline can be skipped entirely
+pub fn matches_prediction(source: Slice2D<u8>, pred: Slice2D<u8>) -> bool {
+  (0..source.rows()).all(|i| source[i] == pred[i])
 }
 
 // Transform pipeline:
@@ -53,57 +454,119 @@ pub fn dc_predict(pixels: &mut Array2D<u8>, y0: usize, x0: usize, h: usize, w: u
 
 // Calculate the residual (forward-transformed difference) between a given source image
 // and the corresponding prediction
-pub fn compute_residual(source: &Array2D<u8>, pred: &Array2D<u8>,
-                    y0: usize, x0: usize, h: usize, w: usize) -> Array2D<i32> {
-  let mut residual = Array2D::new_with(
-    h, w,
-    |i, j| (source[y0 + i][x0 + j] as i32) - (pred[y0 + i][x0 + j] as i32)
-  );
+pub fn compute_residual(source: Slice2D<u8>, pred: Slice2D<u8>, tx_type: TxType) -> Array2D<i32> {
+  let mut residual = Array2D::zeroed(source.rows(), source.cols());
+  compute_residual_into(source, pred, tx_type, &mut residual);
+  return residual;
+}
 
-  fwd_txfm2d(&mut residual, h, w);
+// As compute_residual(), but writing into an existing array of the same size as the block,
+// so that callers can reuse one array for many blocks
+pub fn compute_residual_into(source: Slice2D<u8>, pred: Slice2D<u8>, tx_type: TxType, residual: &mut Array2D<i32>) {
+  let h = source.rows();
+  let w = source.cols();
+  assert!(pred.rows() == h && pred.cols() == w);
+  assert!(residual.rows() == h && residual.cols() == w);
 
-  return residual;
+  // Work one row at a time, using slices, so that the inner loop has no bounds checks
+  // and can be vectorized
+  for i in 0..h {
+    for ((r, &s), &p) in residual[i].iter_mut().zip(&source[i]).zip(&pred[i]) {
+      *r = (s as i32) - (p as i32);
+    }
+  }
+
+  fwd_txfm2d(residual, h, w, tx_type);
 }
 
-// Quantize the coefficients in a given transform block
-pub fn quantize(residual: &mut Array2D<i32>, qindex: u8) {
-  let dc_q = qindex_to_dc_q[qindex as usize];
-  let ac_q = qindex_to_ac_q[qindex as usize];
+// Rounding bias used when quantizing, as a fraction of the quantizer step size in units of 1/128.
+// 64 rounds to nearest, with halves toward zero. Smaller values round more coefficients down,
+// which usually saves more bits than it costs in quality (see for example QuantizationContext
+// in rav1e). The base value is tuned per qindex, and higher sharpness settings move it back
+// toward 64, to keep more fine detail.
+pub fn quant_rounding_bias(qindex: u8, sharpness: u8) -> i32 {
+  let base = quant_rounding_bias_table[(qindex >> 5) as usize] as i32;
+  return base + (64 - base) * (sharpness as i32) / 7;
+}
+
+// Quantizer step sizes for the DC and AC coefficients of one plane
+#[derive(Clone, Copy)]
+pub struct Quantizer {
+  dc_q: i32,
+  ac_q: i32,
+  bit_depth: u8,
+}
 
+impl Quantizer {
+  // `dc_delta` and `ac_delta` are the frame-level qindex offsets for the plane, which are
+  // applied as in the spec's get_dc_quant() and get_ac_quant()
+  pub fn new(qindex: u8, dc_delta: i8, ac_delta: i8, bit_depth: u8) -> Self {
+    let dc_qindex = clamp(qindex as i32 + dc_delta as i32, 0, 255);
+    let ac_qindex = clamp(qindex as i32 + ac_delta as i32, 0, 255);
+    Self {
+      dc_q: dc_q(dc_qindex as u8, bit_depth),
+      ac_q: ac_q(ac_qindex as u8, bit_depth),
+      bit_depth: bit_depth,
+    }
+  }
+
+  pub fn dc_q(&self) -> i32 {
+    self.dc_q
+  }
+}
+
+// Quantize the coefficients in a given transform block
+pub fn quantize(residual: &mut Array2D<i32>, quantizer: &Quantizer, bias: i32) {
   residual.map(|i, j, coeff| {
-    let q = if i == 0 && j == 0 { dc_q } else { ac_q };
-    // Divide coeff by q, with rounding to nearest, halves toward 0
-    // A smaller bias can even be used, essentially rounding values slightly
-    // above half toward zero as well, to improve the average rate-distortion tradeoff -
-    // see for example QuantizationContext in rav1e.
-    // But here we take the simplest option.
+    let q = if i == 0 && j == 0 { quantizer.dc_q } else { quantizer.ac_q };
+    // Divide coeff by q, rounding up if the remainder is at least (1 - bias/128) * q
     let abs = abs(coeff);
     let sign = signum(coeff);
-    sign * ((abs + (q-1)/2) / q)
+    sign * ((abs + ((q * bias - 1) >> 7)) / q)
   });
 }
 
-pub fn dequantize(residual: &mut Array2D<i32>, qindex: u8) {
-  let dc_q = qindex_to_dc_q[qindex as usize];
-  let ac_q = qindex_to_ac_q[qindex as usize];
-
+pub fn dequantize(residual: &mut Array2D<i32>, quantizer: &Quantizer) {
+  // The spec limits the product to 24 bits, then clamps the result to the range
+  // which the inverse transform's input can hold at this bit depth
+  let max_value = (1 << (7 + quantizer.bit_depth)) - 1;
   residual.map(|i, j, coeff| {
-    let q = if i == 0 && j == 0 { dc_q } else { ac_q };
-    // Simply scale the quantized coefficient by the appropriate Q
-    coeff * q
+    let q = if i == 0 && j == 0 { quantizer.dc_q } else { quantizer.ac_q };
+    let dq = (abs(coeff) * q) & 0xFFFFFF;
+    clamp(signum(coeff) * dq, -max_value - 1, max_value)
   });
 }
 
+// Quantized coefficients are kept as i16 between analysis and coding, to halve the memory
+// they take. At 8 bits per sample they're far inside that range, but check anyway, as a
+// silently wrapped coefficient would produce a valid but badly wrong bitstream
+pub fn narrow_coeffs(coeffs: &Array2D<i32>) -> Array2D<i16> {
+  return Array2D::new_with(coeffs.rows(), coeffs.cols(), |i, j| {
+    let coeff = coeffs[i][j];
+    i16::try_from(coeff).unwrap_or_else(|_| panic!("Quantized coefficient {} at ({}, {}) doesn't fit in 16 bits", coeff, i, j))
+  });
+}
+
+// Expand stored coefficients back to i32, into an existing array of the same size
+pub fn widen_coeffs_into(coeffs: &Array2D<i16>, dst: &mut Array2D<i32>) {
+  assert!(dst.rows() == coeffs.rows() && dst.cols() == coeffs.cols());
+  for i in 0..coeffs.rows() {
+    for (d, &c) in dst[i].iter_mut().zip(&coeffs[i]) {
+      *d = c as i32;
+    }
+  }
+}
+
 // Apply a residual to a prediction (in recon) to generate a fully reconstructed block
-// Note: This consumes the residual array, pass in a clone if you want to keep
-// the original array intact
-pub fn apply_residual(recon: &mut Array2D<u8>, mut residual: Array2D<i32>,
-                  y0: usize, x0: usize, h: usize, w: usize) {
-  inv_txfm2d(&mut residual, h, w);
+// Note: The inverse transform is done in place, so this overwrites the residual array
+pub fn apply_residual(mut recon: Slice2DMut<u8>, residual: &mut Array2D<i32>, tx_type: TxType) {
+  let h = recon.rows();
+  let w = recon.cols();
+  inv_txfm2d(residual, h, w, tx_type);
 
   for i in 0..h {
-    for j in 0..w {
-      recon[y0 + i][x0 + j] = clamp((recon[y0 + i][x0 + j] as i32) + residual[i][j], 0, 255) as u8;
+    for (p, &r) in recon[i].iter_mut().zip(&residual[i]) {
+      *p = clamp((*p as i32) + r, 0, 255) as u8;
     }
   }
 }
@@ -0,0 +1,52 @@
+// Copyright (c) 2024-2025, The tinyavif contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+// Region-of-interest maps
+//
+// A region-of-interest map is a grayscale image which says how important each part of
+// the image is: mid-gray (128) is coded at the usual qindex, brighter areas at a lower
+// qindex (so with more detail), and darker areas at a higher one. It's applied per
+// superblock, using superblock-level delta-q, so can be combined with the first pass's
+// own adjustments (see analysis.rs) and with a foreground mask (see segmentation.rs).
+
+use crate::array2d::Array2D;
+use crate::frame::Plane;
+use crate::util::*;
+
+// Map value which leaves the qindex unchanged
+pub const ROI_NEUTRAL: u8 = 128;
+
+// The qindex changes by 1 for every this many steps away from ROI_NEUTRAL, which allows
+// offsets from -31 (for 255) to +32 (for 0)
+const ROI_STEPS_PER_QINDEX: i32 = 4;
+
+// qindex offset for a map value
+pub fn roi_qindex_offset(value: u8) -> i32 {
+  return (ROI_NEUTRAL as i32 - value as i32) / ROI_STEPS_PER_QINDEX;
+}
+
+// Find the qindex offset for each superblock of a frame coded at `crop_width` x `crop_height`,
+// which has `sb_rows` x `sb_cols` superblocks, from a region-of-interest map.
+// The map is stretched to cover the frame, and each superblock uses the brightest value
+// within it, so that superblocks which are partly inside an important region keep its detail.
+pub fn roi_offset_map(map: &Plane, crop_width: usize, crop_height: usize,
+                      sb_rows: usize, sb_cols: usize) -> Array2D<i8> {
+  let map_width = map.crop_width();
+  let map_height = map.crop_height();
+  let pixels = map.pixels();
+
+  return Array2D::new_with(sb_rows, sb_cols, |sb_row, sb_col| {
+    let y0 = min(sb_row * 64 * map_height / crop_height, map_height - 1);
+    let y1 = clamp((sb_row * 64 + 64) * map_height / crop_height, y0 + 1, map_height);
+    let x0 = min(sb_col * 64 * map_width / crop_width, map_width - 1);
+    let x1 = clamp((sb_col * 64 + 64) * map_width / crop_width, x0 + 1, map_width);
+    let brightest = (y0..y1).map(|i| *pixels[i][x0..x1].iter().max().unwrap()).max().unwrap();
+    roi_qindex_offset(brightest) as i8
+  });
+}
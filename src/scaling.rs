@@ -0,0 +1,258 @@
+// Copyright (c) 2024-2025, The tinyavif contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+// Image scaling
+
+use crate::array2d::Array2D;
+use crate::consts::*;
+use crate::frame::{Frame, Plane};
+use crate::util::*;
+
+// Halve the size of a plane using a 2x2 box filter
+// `dst` must already have the right crop size, ie. half the source crop size, rounded up.
+// When the source has an odd width or height, the last output column/row is produced by
+// duplicating the last source column/row.
+fn downscale_plane_2x(src: &Plane, dst: &mut Plane) {
+  let src_crop_width = src.crop_width();
+  let src_crop_height = src.crop_height();
+  let dst_crop_width = dst.crop_width();
+  let dst_crop_height = dst.crop_height();
+  assert!(dst_crop_width == src_crop_width.div_ceil(2));
+  assert!(dst_crop_height == src_crop_height.div_ceil(2));
+
+  let src_pixels = src.pixels();
+  let dst_pixels = dst.pixels_mut();
+  for i in 0..dst_crop_height {
+    let row0 = &src_pixels[2*i];
+    let row1 = &src_pixels[min(2*i + 1, src_crop_height - 1)];
+    for j in 0..dst_crop_width {
+      let j0 = 2*j;
+      let j1 = min(2*j + 1, src_crop_width - 1);
+      let sum = row0[j0] as u32 + row0[j1] as u32 + row1[j0] as u32 + row1[j1] as u32;
+      dst_pixels[i][j] = round2(sum, 2) as u8;
+    }
+  }
+
+  dst.fill_padding();
+}
+
+// Halve the size of a frame in each dimension, rounding up
+pub fn downscale_2x(src: &Frame) -> Frame {
+  let width = src.y().crop_width().div_ceil(2);
+  let height = src.y().crop_height().div_ceil(2);
+
+  let mut dst = Frame::new(height, width);
+  for plane in 0..3 {
+    downscale_plane_2x(src.plane(plane), dst.plane_mut(plane));
+  }
+  return dst;
+}
+
+// Deinterlace a frame by keeping one field, ie. every other row, starting from the top
+// row, or from the second row if `keep_bottom` is set, and replacing each row of the other
+// field with the average of the kept rows above and below it. Chroma rows alternate
+// between the fields in the same way, so are treated alike.
+pub fn bob_deinterlace(frame: &mut Frame, keep_bottom: bool) {
+  let parity = keep_bottom as usize;
+  for plane in 0..3 {
+    let crop_height = frame.plane(plane).crop_height();
+    let crop_width = frame.plane(plane).crop_width();
+    let pixels = frame.plane_mut(plane).pixels_mut();
+    for i in (1 - parity..crop_height).step_by(2) {
+      let above = i.checked_sub(1);
+      let below = if i + 1 < crop_height { Some(i + 1) } else { None };
+      for j in 0..crop_width {
+        pixels[i][j] = match (above, below) {
+          (Some(above), Some(below)) => round2(pixels[above][j] as u32 + pixels[below][j] as u32, 1) as u8,
+          (Some(row), None) | (None, Some(row)) => pixels[row][j],
+          // A single row, belonging to the other field, so there's nothing to interpolate from
+          (None, None) => pixels[i][j],
+        };
+      }
+    }
+    frame.plane_mut(plane).fill_padding();
+  }
+}
+
+// Number of source pixels on each side of the centre which contribute to an output
+// pixel when upscaling. When downscaling, this is stretched by the scale factor
+const LANCZOS_RADIUS: f32 = 3.0;
+
+fn sinc(x: f32, sin: fn(f32) -> f32) -> f32 {
+  if x == 0.0 {
+    return 1.0;
+  }
+  let pi_x = std::f32::consts::PI * x;
+  return sin(pi_x) / pi_x;
+}
+
+pub fn lanczos(x: f32, sin: fn(f32) -> f32) -> f32 {
+  if x.abs() >= LANCZOS_RADIUS {
+    return 0.0;
+  }
+  return sinc(x, sin) * sinc(x / LANCZOS_RADIUS, sin);
+}
+
+// Filter taps for one output position: the first source position used, and
+// the (normalized) weight for each source position from there on
+struct FilterTaps {
+  start: isize,
+  weights: Vec<f32>,
+}
+
+// Compute the taps for every output position along one dimension
+// Source and destination are aligned so that their outer edges match up, treating
+// pixel i as covering the range [i, i+1), as in most other image scalers
+fn lanczos_taps(src_size: usize, dst_size: usize, deterministic: bool) -> Vec<FilterTaps> {
+  let sin = if deterministic { portable_sin } else { f32::sin };
+  let scale = src_size as f32 / dst_size as f32;
+  let stretch = if scale > 1.0 { scale } else { 1.0 };
+  let support = LANCZOS_RADIUS * stretch;
+
+  let mut taps = Vec::with_capacity(dst_size);
+  for i in 0..dst_size {
+    let centre = (i as f32 + 0.5) * scale - 0.5;
+    let start = (centre - support).ceil() as isize;
+    let end = (centre + support).floor() as isize;
+
+    let mut weights: Vec<f32> = (start ..= end).map(|k| lanczos((k as f32 - centre) / stretch, sin)).collect();
+    let total: f32 = weights.iter().sum();
+    for weight in weights.iter_mut() {
+      *weight /= total;
+    }
+    taps.push(FilterTaps { start: start, weights: weights });
+  }
+  return taps;
+}
+
+// Resample a plane to the crop size of `dst`, using a separable Lanczos-3 filter
+// Pixels outside the source image are taken from the nearest edge pixel.
+fn resize_plane(src: &Plane, dst: &mut Plane, deterministic: bool) {
+  let src_crop_width = src.crop_width();
+  let src_crop_height = src.crop_height();
+  let dst_crop_width = dst.crop_width();
+  let dst_crop_height = dst.crop_height();
+
+  let horizontal_taps = lanczos_taps(src_crop_width, dst_crop_width, deterministic);
+  let vertical_taps = lanczos_taps(src_crop_height, dst_crop_height, deterministic);
+
+  // Horizontal pass, into an intermediate buffer which keeps full precision
+  let src_pixels = src.pixels();
+  let mut tmp = Array2D::<f32>::zeroed(src_crop_height, dst_crop_width);
+  for i in 0..src_crop_height {
+    let src_row = &src_pixels[i];
+    for (j, taps) in horizontal_taps.iter().enumerate() {
+      let mut sum = 0.0;
+      for (k, weight) in taps.weights.iter().enumerate() {
+        let col = clamp(taps.start + k as isize, 0, src_crop_width as isize - 1) as usize;
+        sum += weight * src_row[col] as f32;
+      }
+      tmp[i][j] = sum;
+    }
+  }
+
+  // Vertical pass
+  let dst_pixels = dst.pixels_mut();
+  for (i, taps) in vertical_taps.iter().enumerate() {
+    for j in 0..dst_crop_width {
+      let mut sum = 0.0;
+      for (k, weight) in taps.weights.iter().enumerate() {
+        let row = clamp(taps.start + k as isize, 0, src_crop_height as isize - 1) as usize;
+        sum += weight * tmp[row][j];
+      }
+      dst_pixels[i][j] = sum.round().clamp(0.0, 255.0) as u8;
+    }
+  }
+
+  dst.fill_padding();
+}
+
+// Resample a frame to the given luma size
+// With `deterministic` set, the result is the same on every platform (see EncoderConfig::deterministic)
+pub fn resize(src: &Frame, width: usize, height: usize, deterministic: bool) -> Frame {
+  assert!(width > 0 && height > 0);
+
+  let mut dst = Frame::new(height, width);
+  for plane in 0..3 {
+    resize_plane(src.plane(plane), dst.plane_mut(plane), deterministic);
+  }
+  return dst;
+}
+
+// The size to scale an image to, so that neither dimension is larger than `max_dimension`
+// The aspect ratio is kept as closely as possible, and images which already fit
+// are left at their original size.
+pub fn fit_within(width: usize, height: usize, max_dimension: usize) -> (usize, usize) {
+  let longest = max(width, height);
+  if longest <= max_dimension {
+    return (width, height);
+  }
+  let scaled = |size: usize| max((size * max_dimension + longest / 2) / longest, 1);
+  return (scaled(width), scaled(height));
+}
+
+// Width at which a frame of the given width is coded, when superres is used with the
+// given denominator (the spec's superres_params() and compute_image_size())
+pub fn superres_coded_width(upscaled_width: usize, denominator: usize) -> usize {
+  let width = (upscaled_width * SUPERRES_NUM + denominator / 2) / denominator;
+  return max(width, min(upscaled_width, 16));
+}
+
+// Upscale one plane horizontally, as in the spec's upscaling process
+// Positions are computed in fixed point, from a starting offset and step chosen so that
+// the source and destination are centred on each other, and each output pixel is an
+// 8-tap filter of the source pixels around its position.
+fn superres_upscale_plane(src: &Plane, downscaled_width: usize, dst: &mut Plane) {
+  let downscaled_width = downscaled_width as i64;
+  let upscaled_width = dst.crop_width() as i64;
+  let scale_bits = SUPERRES_SCALE_BITS;
+
+  let step = ((downscaled_width << scale_bits) + upscaled_width / 2) / upscaled_width;
+  let err = upscaled_width * step - (downscaled_width << scale_bits);
+  let initial_subpel = (-((upscaled_width - downscaled_width) << (scale_bits - 1)) + upscaled_width / 2) /
+                       upscaled_width + (1 << (SUPERRES_EXTRA_BITS - 1)) - err / 2;
+  let initial_subpel = initial_subpel & ((1 << scale_bits) - 1);
+
+  // The filter may read past the crop region of the source, up to the end of the coded
+  // (8x8-aligned) area, but no further
+  let max_x = src.width() as isize - 1;
+
+  let src_pixels = src.pixels();
+  let dst_pixels = dst.pixels_mut();
+  for i in 0..src.crop_height() {
+    let src_row = &src_pixels[i];
+    for j in 0..upscaled_width as usize {
+      let pos = -(1 << scale_bits) + initial_subpel + (j as i64) * step;
+      let pos_px = (pos >> scale_bits) as isize;
+      let phase = ((pos & ((1 << scale_bits) - 1)) >> SUPERRES_EXTRA_BITS) as usize;
+      let mut sum = 0;
+      for k in 0..SUPERRES_FILTER_TAPS {
+        let x = clamp(pos_px + k as isize - SUPERRES_FILTER_OFFSET, 0, max_x) as usize;
+        sum += src_row[x] as i32 * upscale_filter[phase][k];
+      }
+      dst_pixels[i][j] = clamp(round2(sum, 7), 0, 255) as u8;
+    }
+  }
+
+  dst.fill_padding();
+}
+
+// Upscale a frame which was coded with superres at `downscaled_width` to its full width
+// This is part of the decoding process, so must exactly match what decoders do. Only
+// the crop height of the source frame is used, as its padding may hold reconstructed
+// pixels which the upscaling filter reads past the crop width.
+pub fn superres_upscale(src: &Frame, downscaled_width: usize, upscaled_width: usize) -> Frame {
+  let mut dst = Frame::new(src.y().crop_height(), upscaled_width);
+  for plane in 0..3 {
+    let subsampling = (plane > 0) as usize;
+    let plane_width = (downscaled_width + subsampling) >> subsampling;
+    superres_upscale_plane(src.plane(plane), plane_width, dst.plane_mut(plane));
+  }
+  return dst;
+}
@@ -0,0 +1,153 @@
+// Copyright (c) 2024-2025, The tinyavif contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+// Segmentation
+//
+// AV1 lets each block be assigned to one of up to 8 segments, and each segment can adjust
+// some of the parameters used to code its blocks. We only use the alternative quantizer
+// feature (SEG_LVL_ALT_Q), which adds a per-segment offset to the qindex, to code the
+// foreground and background of an image at different qualities.
+//
+// Segment IDs are coded per block, relative to a prediction from the neighbouring blocks.
+// Skipped blocks don't code a segment ID at all, but take the predicted one. That's
+// harmless, as a block with no residual looks the same whatever its qindex.
+
+use crate::array2d::Array2D;
+use crate::consts::*;
+use crate::frame::Plane;
+use crate::util::*;
+
+// Segments used with a foreground mask. The foreground is coded at the frame's qindex,
+// so only the background segment has any features enabled
+pub const FOREGROUND_SEGMENT: u8 = 0;
+pub const BACKGROUND_SEGMENT: u8 = 1;
+
+// Mask pixels at or above this value are part of the foreground
+pub const MASK_THRESHOLD: u8 = 128;
+
+// The segmentation parameters signalled in the frame header
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SegmentationParams {
+  // Value of each feature for each segment, or None if the feature is disabled
+  pub features: [[Option<i32>; SEG_LVL_MAX]; MAX_SEGMENTS],
+}
+
+impl SegmentationParams {
+  // Segmentation with every feature disabled
+  pub fn new() -> Self {
+    Self {
+      features: [[None; SEG_LVL_MAX]; MAX_SEGMENTS]
+    }
+  }
+
+  // Code the foreground at `qindex` and the background at `background_qindex`
+  pub fn foreground_background(qindex: u8, background_qindex: u8) -> Self {
+    let mut params = Self::new();
+    params.features[BACKGROUND_SEGMENT as usize][SEG_LVL_ALT_Q] = Some(background_qindex as i32 - qindex as i32);
+    return params;
+  }
+
+  // Highest segment ID which has any feature enabled (the spec's LastActiveSegId)
+  // Segment IDs are coded in the range 0 ..= this value
+  pub fn last_active_segment(&self) -> u8 {
+    let active = (0..MAX_SEGMENTS).rev().find(|&i| self.features[i].iter().any(|f| f.is_some()));
+    return active.unwrap_or(0) as u8;
+  }
+
+  // qindex for a block in the given segment, where `qindex` is the frame qindex, or the
+  // superblock qindex if delta-q is enabled (the spec's get_qindex(0, segment_id))
+  pub fn qindex(&self, qindex: u8, segment_id: u8) -> u8 {
+    match self.features[segment_id as usize][SEG_LVL_ALT_Q] {
+      Some(delta) => clamp(qindex as i32 + delta, 0, 255) as u8,
+      None => qindex,
+    }
+  }
+}
+
+// Predict a block's segment ID from the above-left, above, and left blocks, where
+// available. Returns the prediction and the context for coding the actual ID.
+pub fn predict_segment_id(above_left: Option<u8>, above: Option<u8>, left: Option<u8>) -> (u8, usize) {
+  let prediction = match (above, left) {
+    (None, None) => 0,
+    (None, Some(left)) => left,
+    (Some(above), None) => above,
+    (Some(above), Some(left)) => if above_left == Some(above) { above } else { left },
+  };
+
+  // above_left is only available if both other blocks are
+  let ctx = match above_left {
+    None => 0,
+    Some(above_left) if Some(above_left) == above && Some(above_left) == left => 2,
+    Some(above_left) if Some(above_left) == above || Some(above_left) == left || above == left => 1,
+    Some(_) => 0,
+  };
+  return (prediction, ctx);
+}
+
+// Map a segment ID in the range 0..max to a code, such that IDs close to the prediction
+// `reference` get small codes
+pub fn neg_interleave(x: usize, reference: usize, max: usize) -> usize {
+  assert!(x < max && reference < max);
+  if reference == 0 {
+    return x;
+  }
+  if reference >= max - 1 {
+    return max - 1 - x;
+  }
+  let diff = x as isize - reference as isize;
+  let limit = if 2 * reference < max { reference } else { max - reference - 1 };
+  if diff.unsigned_abs() <= limit {
+    return if diff > 0 { 2 * diff as usize - 1 } else { 2 * (-diff) as usize };
+  }
+  return if 2 * reference < max { x } else { max - 1 - x };
+}
+
+// Inverse of neg_interleave() (the spec's neg_deinterleave())
+// A corrupt stream can code values which don't correspond to any valid segment ID, so
+// the result may be out of range, and needs clamping
+pub fn neg_deinterleave(diff: usize, reference: usize, max: usize) -> isize {
+  let (diff, reference, max) = (diff as isize, reference as isize, max as isize);
+  if reference == 0 {
+    return diff;
+  }
+  if reference >= max - 1 {
+    return max - diff - 1;
+  }
+  let limit = if 2 * reference < max { 2 * reference } else { 2 * (max - reference - 1) };
+  if diff <= limit {
+    return if diff & 1 != 0 { reference + (diff + 1) / 2 } else { reference - diff / 2 };
+  }
+  return if 2 * reference < max { diff } else { max - diff - 1 };
+}
+
+// Assign each 8x8 block of a frame coded at `crop_width` x `crop_height` to the foreground
+// or background segment, based on a mask where bright pixels mark the foreground.
+// The mask is stretched to cover the frame, and a block counts as foreground if any
+// mask pixel within it does, so that the edges of the foreground keep their detail.
+// The result has one entry per 4x4 unit, for `mi_rows` x `mi_cols` units.
+pub fn foreground_segment_map(mask: &Plane, crop_width: usize, crop_height: usize,
+                              mi_rows: usize, mi_cols: usize) -> Array2D<u8> {
+  let mask_width = mask.crop_width();
+  let mask_height = mask.crop_height();
+  let pixels = mask.pixels();
+
+  let mut map = Array2D::zeroed(mi_rows, mi_cols);
+  for mi_row in (0..mi_rows).step_by(2) {
+    let y0 = min(mi_row * 4 * mask_height / crop_height, mask_height - 1);
+    let y1 = clamp((mi_row * 4 + 8) * mask_height / crop_height, y0 + 1, mask_height);
+    for mi_col in (0..mi_cols).step_by(2) {
+      let x0 = min(mi_col * 4 * mask_width / crop_width, mask_width - 1);
+      let x1 = clamp((mi_col * 4 + 8) * mask_width / crop_width, x0 + 1, mask_width);
+      let foreground = (y0..y1).any(|i| pixels[i][x0..x1].iter().any(|&p| p >= MASK_THRESHOLD));
+      let segment = if foreground { FOREGROUND_SEGMENT } else { BACKGROUND_SEGMENT };
+      map.fill_region(mi_row, mi_col, min(2, mi_rows - mi_row), min(2, mi_cols - mi_col), &segment);
+    }
+  }
+  return map;
+}
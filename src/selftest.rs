@@ -0,0 +1,562 @@
+// Copyright (c) 2024-2025, The tinyavif contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+// Built-in checks of the entropy coder, run with `tinyavif selftest`
+//
+// A mistake in EntropyWriter usually still produces a plausible-looking file, and only
+// shows up as corruption part way through decoding. So before changing it, these checks
+// should pass, and should still pass afterwards:
+//
+// * Known symbol sequences, with the exact bytes which they must code to. These were checked
+//   by decoding them with EntropyReader, which follows the spec's decoding process, and any
+//   change to the output for them means the coder's behaviour has changed.
+// * Round trips through EntropyReader of literals, Golomb codes and NS(n) codes at the edges
+//   of their ranges, and of symbols with extreme probabilities, which are where carry
+//   propagation and renormalization bugs tend to hide.
+// * CDF adaptation: known symbol sequences, with the CDFs which they must adapt to. These were
+//   worked out separately, by following the spec's symbol decoding process step by step.
+//   Then a long round trip of adaptive symbols, which only decodes correctly if the writer
+//   and reader adapt their CDFs in exactly the same way.
+//
+// The same goes for the su(n), uvlc() and ns(n) codes which BitWriter uses for the
+// sequence and frame headers, which are checked against known bytes and round-tripped
+// through BitReader.
+//
+// The SIMD versions of the 8-point DCT (see txfm.rs) are checked against the scalar code,
+// through the whole 2D transform, on pseudo-random blocks and on blocks at the edges of the
+// range each transform sees. A mismatch there would corrupt the output without any error,
+// and --verify wouldn't notice, as the decoder shares the same transforms. This only checks
+// the SIMD code for the current CPU; TINYAVIF_NO_SIMD makes it compare the scalar code
+// against itself.
+//
+// 16-bit images are reduced to 8 bits with an ordered dither (see colorconv.rs), which
+// should keep their precision on average. This is checked on a ramp through every
+// fraction of an 8-bit step.
+//
+// There is also a check that encoding a synthetic image in deterministic mode (see
+// EncoderConfig::deterministic) gives exactly the expected file. This should give the
+// same result on every platform, and only changes when the encoder's output does.
+
+use std::cmp::min;
+use std::fmt;
+
+use crate::array2d::Array2D;
+use crate::av1_encoder::encode_from_yuv_buffers;
+use crate::bitcode::{BitReader, BitWriter};
+use crate::cdf::*;
+use crate::colorconv::{ColorConversion, Matrix};
+use crate::config::{ChromaSamplePosition, EncoderConfig, Tune};
+use crate::entropycode::{update_cdf, EntropyReader, EntropyWriter, SymbolWriter};
+use crate::enums::TxType;
+use crate::scaling::resize;
+use crate::testutil::{gradient_frame, XorShift32};
+use crate::txfm::txfm2d;
+
+// One syntax element, as written by SymbolWriter
+#[derive(Clone, Copy, Debug)]
+enum Op {
+  Symbol(usize, &'static [u16]),
+  Bit(usize, u16),
+  Literal(u32, u32),
+  Golomb(u32),
+  Ns(u32, u32),
+}
+
+impl Op {
+  fn write(&self, w: &mut EntropyWriter) {
+    match *self {
+      Op::Symbol(symbol, cdf) => w.write_symbol(symbol, cdf),
+      Op::Bit(value, p_zero) => w.write_bit(value, p_zero),
+      Op::Literal(value, nbits) => w.write_literal(value, nbits),
+      Op::Golomb(value) => w.write_golomb(value),
+      Op::Ns(value, n) => w.write_ns(value, n),
+    }
+  }
+
+  // Read this element back, returning the value which was decoded
+  fn read(&self, r: &mut EntropyReader) -> u32 {
+    match *self {
+      Op::Symbol(_, cdf) => r.read_symbol(cdf) as u32,
+      Op::Bit(_, p_zero) => r.read_bit(p_zero) as u32,
+      Op::Literal(_, nbits) => r.read_literal(nbits),
+      Op::Golomb(_) => r.read_golomb(),
+      Op::Ns(_, n) => r.read_ns(n),
+    }
+  }
+
+  fn value(&self) -> u32 {
+    match *self {
+      Op::Symbol(symbol, _) => symbol as u32,
+      Op::Bit(value, _) => value as u32,
+      Op::Literal(value, _) => value,
+      Op::Golomb(value) => value,
+      Op::Ns(value, _) => value,
+    }
+  }
+}
+
+#[derive(Debug)]
+pub struct SelfTestError {
+  pub check: String,
+  pub message: String,
+}
+
+impl fmt::Display for SelfTestError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "Self-test \"{}\" failed: {}", self.check, self.message)
+  }
+}
+
+impl std::error::Error for SelfTestError {}
+
+// Known symbol sequences and the bytes they code to
+const VECTORS: &[(&str, &[Op], &[u8])] = &[
+  ("empty", &[], &[0x80]),
+  ("single zero bit", &[Op::Bit(0, 16384)], &[0x20]),
+  ("single one bit", &[Op::Bit(1, 16384)], &[0xC0]),
+  ("literal 0xA5", &[Op::Literal(0xA5, 8)], &[0xA5, 0x80]),
+  ("skewed bits", &[Op::Bit(0, 32000), Op::Bit(1, 32000), Op::Bit(0, 100), Op::Bit(1, 100)], &[0xF4, 0x22]),
+  ("partition symbols", &[Op::Symbol(3, &partition_64x64_cdf[0]), Op::Symbol(3, &partition_64x64_cdf[3]),
+                          Op::Symbol(0, &partition_64x64_cdf[1]), Op::Symbol(9, &partition_64x64_cdf[2])],
+   &[0xC0, 0x10]),
+  ("block header", &[Op::Symbol(0, &skip_cdf[0]), Op::Symbol(1, &skip_cdf[2]), Op::Symbol(0, &y_mode_cdf[0][0]),
+                     Op::Symbol(12, &uv_mode_cfl_allowed_cdf[0]), Op::Symbol(3, &delta_q_abs_cdf), Op::Literal(5, 3)],
+   &[0x6E, 0x10, 0xA0]),
+  ("golomb", &[Op::Golomb(0), Op::Golomb(1), Op::Golomb(14), Op::Golomb(1000)], &[0xA1, 0xDA, 0x2D, 0xA6]),
+  ("ns", &[Op::Ns(0, 5), Op::Ns(4, 5), Op::Ns(2, 3), Op::Ns(255, 256)], &[0x40, 0x35]),
+];
+
+// Starting CDFs, symbol sequences, and the adaptive CDFs (including the final count) which
+// they must lead to
+const CDF_UPDATE_VECTORS: &[(&str, &[u16], &[usize], &[u16])] = &[
+  ("binary CDF adaptation", &[16384], &[0, 1, 0, 0, 1, 1, 1, 0], &[16194, 8]),
+  ("CDF adaptation past the count limit", &[16384], &[1; 40], &[3106, 32]),
+  ("4-symbol CDF adaptation", &[8192, 16384, 24576],
+   &[0, 3, 2, 2, 1, 0, 0, 3, 2, 2, 1, 0, 0, 3, 2, 2, 1, 0, 0, 3, 2, 2, 1, 0, 0, 3, 2, 2, 1, 0, 0, 3, 2, 2,
+     1, 0, 0, 3, 2, 2],
+   &[9582, 15842, 25830, 32]),
+  ("y mode CDF adaptation", &y_mode_cdf[0][0], &[0, 0, 12, 3, 0, 9, 9, 0, 1, 12, 0, 0, 5, 0, 0, 0, 11, 0, 2, 0],
+   &[16915, 18496, 20305, 21459, 21719, 22835, 23238, 24042, 24577, 28242, 28767, 30030, 20]),
+];
+
+// One header syntax element, as written by BitWriter
+#[derive(Clone, Copy, Debug)]
+enum HeaderOp {
+  Su(i64, usize),
+  Uvlc(u32),
+  Ns(u64, u64),
+}
+
+impl HeaderOp {
+  fn write(&self, w: &mut BitWriter) {
+    match *self {
+      HeaderOp::Su(value, nbits) => w.write_su(value, nbits),
+      HeaderOp::Uvlc(value) => w.write_uvlc(value),
+      HeaderOp::Ns(value, n) => w.write_ns(value, n),
+    }
+  }
+
+  // Read this element back, returning whether it decoded to the value which was written
+  fn read_matches(&self, r: &mut BitReader) -> bool {
+    match *self {
+      HeaderOp::Su(value, nbits) => r.read_su(nbits).is_ok_and(|v| v == value),
+      HeaderOp::Uvlc(value) => r.read_uvlc().is_ok_and(|v| v == value),
+      HeaderOp::Ns(value, n) => r.read_ns(n).is_ok_and(|v| v == value),
+    }
+  }
+}
+
+// Known header element sequences and the bytes they code to (including the trailing bit)
+const HEADER_VECTORS: &[(&str, &[HeaderOp], &[u8])] = &[
+  ("su", &[HeaderOp::Su(-1, 7), HeaderOp::Su(63, 7), HeaderOp::Su(-64, 7)], &[0xFE, 0xFE, 0x04]),
+  ("uvlc", &[HeaderOp::Uvlc(0), HeaderOp::Uvlc(1), HeaderOp::Uvlc(2), HeaderOp::Uvlc(7)], &[0xA6, 0x22]),
+  ("header ns", &[HeaderOp::Ns(0, 5), HeaderOp::Ns(4, 5), HeaderOp::Ns(0, 1), HeaderOp::Ns(255, 256)],
+   &[0x3F, 0xFC]),
+];
+
+// Check everything, returning the number of checks which were run
+pub fn run_self_tests() -> Result<usize, SelfTestError> {
+  let mut checks = 0;
+
+  for &(name, ops, expected) in VECTORS {
+    let actual = encode(ops);
+    if &actual[..] != expected {
+      return Err(SelfTestError {
+        check: name.into(),
+        message: format!("coded as {:02X?}, expected {:02X?}", actual, expected),
+      });
+    }
+    round_trip(name, ops)?;
+    checks += 1;
+  }
+
+  // Literals of every length, at the ends of their ranges
+  for nbits in 0..=32 {
+    let max = if nbits == 32 { u32::MAX } else { (1u32 << nbits) - 1 };
+    let ops = [Op::Literal(0, nbits), Op::Literal(max, nbits), Op::Literal(max & 0x55555555, nbits),
+               Op::Literal(max >> 1, nbits)];
+    round_trip(&format!("literal, {} bits", nbits), &ops)?;
+    checks += 1;
+  }
+
+  // Golomb codes either side of each change in length, up to the largest codable value
+  let mut golomb = vec![Op::Golomb(0)];
+  for length in 1..32 {
+    let boundary = (1u32 << length) - 1;
+    golomb.extend([Op::Golomb(boundary - 1), Op::Golomb(boundary), Op::Golomb(boundary + 1)]);
+  }
+  golomb.push(Op::Golomb(u32::MAX - 1));
+  round_trip("golomb boundaries", &golomb)?;
+  checks += 1;
+
+  // NS(n) for small n, where the one-bit-shorter codes are used, and around powers of 2
+  let mut ns = Vec::new();
+  for n in (1..=40).chain([255, 256, 257, 1 << 16]) {
+    ns.extend([Op::Ns(0, n), Op::Ns(n / 2, n), Op::Ns(n - 1, n)]);
+  }
+  round_trip("ns", &ns)?;
+  checks += 1;
+
+  for &(name, ops, expected) in HEADER_VECTORS {
+    let actual = encode_header(ops);
+    if &actual[..] != expected {
+      return Err(SelfTestError {
+        check: name.into(),
+        message: format!("coded as {:02X?}, expected {:02X?}", actual, expected),
+      });
+    }
+    header_round_trip(name, ops)?;
+    checks += 1;
+  }
+
+  // Header codes at the edges of their ranges
+  let mut header = Vec::new();
+  for nbits in 1..=32 {
+    let limit = 1i64 << (nbits - 1);
+    header.extend([HeaderOp::Su(-limit, nbits), HeaderOp::Su(limit - 1, nbits), HeaderOp::Su(0, nbits)]);
+  }
+  for length in 1..32 {
+    let boundary = (1u32 << length) - 1;
+    header.extend([HeaderOp::Uvlc(boundary - 1), HeaderOp::Uvlc(boundary), HeaderOp::Uvlc(boundary + 1)]);
+  }
+  header.push(HeaderOp::Uvlc(u32::MAX - 1));
+  for n in (1..=40).chain([255, 256, 257, 1 << 16]) {
+    header.extend([HeaderOp::Ns(0, n), HeaderOp::Ns(n / 2, n), HeaderOp::Ns(n - 1, n)]);
+  }
+  header_round_trip("header syntax", &header)?;
+  checks += 1;
+
+  // Very likely and very unlikely symbols, which stress the range renormalization, and long
+  // runs of them, which make carries propagate through many 0xFF bytes
+  for (name, p_zero) in [("likely zeros", 32767), ("unlikely zeros", 1), ("even", 16384)] {
+    for value in 0..2 {
+      let ops = vec![Op::Bit(value, p_zero); 1000];
+      round_trip(&format!("{}, 1000 x {}", name, value), &ops)?;
+      checks += 1;
+    }
+  }
+
+  // Every symbol of the largest alphabet we use, in a pseudo-random mix with other elements
+  let mut rng = XorShift32::new(0x12345678);
+  let mut mixed = Vec::new();
+  for _ in 0..10000 {
+    let state = rng.next_u32();
+    let op = match state % 5 {
+      0 => Op::Symbol((state >> 8) as usize % 13, &uv_mode_cfl_allowed_cdf[(state >> 16) as usize % 13]),
+      1 => Op::Bit(((state >> 8) & 1) as usize, 1 + (state >> 9) as u16 % 32767),
+      2 => Op::Literal(state >> 12, 20),
+      3 => Op::Golomb(state >> (8 + (state >> 8) % 24)),
+      _ => Op::Ns((state >> 8) % 1000, 1000),
+    };
+    mixed.push(op);
+  }
+  round_trip("mixed", &mixed)?;
+  checks += 1;
+
+  for &(name, initial, symbols, expected) in CDF_UPDATE_VECTORS {
+    let mut cdf = initial.to_vec();
+    cdf.push(0);
+    for &symbol in symbols {
+      update_cdf(symbol, &mut cdf);
+    }
+    if cdf != expected {
+      return Err(SelfTestError {
+        check: name.into(),
+        message: format!("adapted to {:?}, expected {:?}", cdf, expected),
+      });
+    }
+    checks += 1;
+  }
+
+  adaptive_round_trip("adaptive symbols")?;
+  checks += 1;
+
+  for (txh, txw) in [(8, 8), (16, 8), (8, 16)] {
+    for inverse in [false, true] {
+      simd_transform(txh, txw, inverse)?;
+      checks += 1;
+    }
+  }
+
+  dither_ramp("16-bit dither ramp")?;
+  checks += 1;
+
+  for &(name, tune, expected) in DETERMINISTIC_HASHES {
+    let hash = fnv1a(&encode_synthetic_image(tune));
+    if hash != expected {
+      return Err(SelfTestError {
+        check: name.into(),
+        message: format!("output hash is {:016x}, expected {:016x}", hash, expected),
+      });
+    }
+    checks += 1;
+  }
+
+  return Ok(checks);
+}
+
+// Hashes of the output of encode_synthetic_image()
+const DETERMINISTIC_HASHES: &[(&str, Tune, u64)] = &[
+  ("deterministic output, tune psnr", Tune::Psnr, 0xec1e5ac2f440cd82),
+  ("deterministic output, tune screen", Tune::Screen, 0xeacffab89c3ded81),
+];
+
+// Encode a pseudo-random image, through every part of the encoder which uses floating point
+fn encode_synthetic_image(tune: Tune) -> Vec<u8> {
+  // Smooth gradients, to give the transforms something to do, plus noise
+  let source = gradient_frame(100, 75, 0x9E3779B9);
+  let source = resize(&source, 90, 70, true);
+
+  let config = EncoderConfig::builder()
+    .qindex(80)
+    .tune(tune)
+    .passes(2)
+    .denoise(2)
+    .sharpen(3)
+    .deterministic(true)
+    .build()
+    .unwrap();
+  let mut planes = [Vec::new(), Vec::new(), Vec::new()];
+  for (plane, data) in planes.iter_mut().enumerate() {
+    source.plane(plane).write_to(data).unwrap();
+  }
+  return encode_from_yuv_buffers(&planes[0], &planes[1], &planes[2], 90, 70, &config).unwrap();
+}
+
+// Check that the SIMD and scalar versions of a DCT_DCT transform give exactly the same
+// results. Forward transforms are given residuals, which are at most 255 either way;
+// inverse transforms are given coefficients anywhere in i32, as they clamp their input.
+fn simd_transform(txh: usize, txw: usize, inverse: bool) -> Result<(), SelfTestError> {
+  let limit = if inverse { i32::MAX } else { 255 };
+  let mut rng = XorShift32::new(0x2545F491);
+  let mut blocks: Vec<Array2D<i32>> = Vec::new();
+  for _ in 0..200 {
+    let mut block = Array2D::zeroed(txh, txw);
+    // Mostly values in the range which can really occur, but some anywhere in it
+    let range = if rng.next_u32() % 4 == 0 { limit as i64 } else { 255 };
+    for i in 0..txh {
+      for j in 0..txw {
+        block[i][j] = ((rng.next_u32() as i64) % (2 * range + 1) - range) as i32;
+      }
+    }
+    blocks.push(block);
+  }
+  // Flat blocks, and checkerboards and stripes, at both ends of the range
+  let patterns: [fn(usize, usize) -> bool; 4] = [|_, _| true, |i, j| (i + j) % 2 == 0, |i, _| i % 2 == 0,
+                                                |_, j| j % 2 == 0];
+  for pattern in patterns {
+    for (high, low) in [(limit, -limit), (-limit, limit), (limit, 0), (-limit, 0)] {
+      let mut block = Array2D::zeroed(txh, txw);
+      for i in 0..txh {
+        for j in 0..txw {
+          block[i][j] = if pattern(i, j) { high } else { low };
+        }
+      }
+      blocks.push(block);
+    }
+  }
+
+  let name = format!("SIMD {} {}x{} DCT", if inverse { "inverse" } else { "forward" }, txw, txh);
+  for (index, block) in blocks.iter().enumerate() {
+    let mut scalar = block.clone();
+    txfm2d(&mut scalar, txh, txw, TxType::DCT_DCT, inverse, false);
+    let mut simd = block.clone();
+    txfm2d(&mut simd, txh, txw, TxType::DCT_DCT, inverse, true);
+    if simd != scalar {
+      return Err(SelfTestError {
+        check: name,
+        message: format!("block {} ({:?}) transformed to {:?}, expected {:?}", index, block, simd, scalar),
+      });
+    }
+  }
+  return Ok(());
+}
+
+// Convert a 16-bit greyscale ramp, which is flat across each 4x4 tile and steps up by
+// 1/16 of an 8-bit level from each tile to the next, then check that each tile dithers to
+// a mix of the two nearest 8-bit levels with exactly the right average
+fn dither_ramp(name: &str) -> Result<(), SelfTestError> {
+  const BASE: usize = 100;
+  let (width, height) = (64, 8);
+  let conversion = ColorConversion {
+    matrix: Matrix::Bt601,
+    full_range: true,
+    chroma_sample_position: ChromaSamplePosition::Unknown,
+  };
+  // The 16-bit value for an 8-bit level with 4 fractional bits
+  let level = |j: usize| ((((BASE << 4) + j / 4) as u32 * 65535 + 2040) / 4080) as u16;
+  let data: Vec<u16> = (0..height).flat_map(|_| (0..width).map(level)).collect();
+  let frame = conversion.gray16_to_frame(&data, width, height, width);
+
+  let y = frame.y().pixels();
+  for tile_row in 0..height / 4 {
+    for tile_col in 0..width / 4 {
+      let mut sum = 0;
+      for i in tile_row * 4 .. tile_row * 4 + 4 {
+        for j in tile_col * 4 .. tile_col * 4 + 4 {
+          let value = y[i][j] as usize;
+          if value != BASE && value != BASE + 1 {
+            return Err(SelfTestError {
+              check: name.into(),
+              message: format!("pixel ({}, {}) is {}, expected {} or {}", i, j, value, BASE, BASE + 1),
+            });
+          }
+          sum += value;
+        }
+      }
+      // The fraction in this tile is tile_col / 16, so that many pixels should round up
+      if sum != 16 * BASE + tile_col {
+        return Err(SelfTestError {
+          check: name.into(),
+          message: format!("4x4 tile at ({}, {}) has {} pixels rounded up, expected {}",
+                           tile_row * 4, tile_col * 4, sum - 16 * BASE, tile_col),
+        });
+      }
+    }
+  }
+  return Ok(());
+}
+
+// 64-bit FNV-1a hash
+fn fnv1a(data: &[u8]) -> u64 {
+  let mut hash = 0xcbf29ce484222325u64;
+  for &byte in data {
+    hash ^= byte as u64;
+    hash = hash.wrapping_mul(0x100000001b3);
+  }
+  return hash;
+}
+
+fn encode(ops: &[Op]) -> Box<[u8]> {
+  let mut w = EntropyWriter::new();
+  for op in ops {
+    op.write(&mut w);
+  }
+  return w.finalize();
+}
+
+// Code a sequence of elements, then check that they decode to the same values
+fn encode_header(ops: &[HeaderOp]) -> Box<[u8]> {
+  let mut w = BitWriter::new();
+  for op in ops {
+    op.write(&mut w);
+  }
+  return w.finalize(true);
+}
+
+fn header_round_trip(name: &str, ops: &[HeaderOp]) -> Result<(), SelfTestError> {
+  let data = encode_header(ops);
+  let mut r = BitReader::new(&data);
+  for (i, op) in ops.iter().enumerate() {
+    if !op.read_matches(&mut r) {
+      return Err(SelfTestError {
+        check: name.into(),
+        message: format!("element {} ({:?}) decoded wrongly", i, op),
+      });
+    }
+  }
+  return Ok(());
+}
+
+fn round_trip(name: &str, ops: &[Op]) -> Result<(), SelfTestError> {
+  let data = encode(ops);
+  let mut r = EntropyReader::new(&data);
+  for (i, op) in ops.iter().enumerate() {
+    let value = op.read(&mut r);
+    if value != op.value() {
+      return Err(SelfTestError {
+        check: name.into(),
+        message: format!("element {} ({:?}) decoded as {}", i, op, value),
+      });
+    }
+  }
+  return Ok(());
+}
+
+// Code a pseudo-random mix of symbols with adaptive CDFs of several sizes, and literals,
+// then check that they decode to the same values and leave the reader with the same CDFs.
+// The symbols are skewed towards low values, so that the CDFs move a long way from where
+// they start
+fn adaptive_round_trip(name: &str) -> Result<(), SelfTestError> {
+  let initial_cdfs: [&[u16]; 3] = [&skip_cdf[0], &partition_8x8_cdf[0], &uv_mode_cfl_allowed_cdf[0]];
+  let new_cdfs = || -> Vec<Vec<u16>> {
+    initial_cdfs.iter().map(|cdf| {
+      let mut cdf = cdf.to_vec();
+      cdf.push(0);
+      cdf
+    }).collect()
+  };
+
+  // Each element is (which CDF, symbol), or (None, 8-bit literal)
+  let mut rng = XorShift32::new(0x9E3779B9);
+  let mut elements = Vec::new();
+  for _ in 0..10000 {
+    let state = rng.next_u32();
+    let which = (state % 4) as usize;
+    if which < 3 {
+      let num_symbols = initial_cdfs[which].len() + 1;
+      let symbol = min((state >> 8) as usize % num_symbols, (state >> 16) as usize % num_symbols);
+      elements.push((Some(which), symbol));
+    } else {
+      elements.push((None, (state >> 8) as usize & 0xFF));
+    }
+  }
+
+  let mut cdfs = new_cdfs();
+  let mut w = EntropyWriter::new();
+  for &(which, value) in &elements {
+    match which {
+      Some(which) => w.write_symbol_with_update(value, &mut cdfs[which]),
+      None => w.write_literal(value as u32, 8),
+    }
+  }
+  let data = w.finalize();
+
+  let mut reader_cdfs = new_cdfs();
+  let mut r = EntropyReader::new(&data);
+  for (i, &(which, value)) in elements.iter().enumerate() {
+    let decoded = match which {
+      Some(which) => r.read_symbol_with_update(&mut reader_cdfs[which]),
+      None => r.read_literal(8) as usize,
+    };
+    if decoded != value {
+      return Err(SelfTestError {
+        check: name.into(),
+        message: format!("element {} decoded as {}, expected {}", i, decoded, value),
+      });
+    }
+  }
+  if reader_cdfs != cdfs {
+    return Err(SelfTestError {
+      check: name.into(),
+      message: "writer and reader CDFs differ after coding".into(),
+    });
+  }
+  return Ok(());
+}
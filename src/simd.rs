@@ -0,0 +1,165 @@
+// Copyright (c) 2024-2025, The tinyavif contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+// SIMD support: runtime CPU feature detection, plus a minimal abstraction over
+// vectors of 32-bit integers, so that kernels can be written once and then
+// instantiated for each instruction set.
+//
+// The kernels themselves live next to their scalar equivalents (eg. in txfm.rs),
+// and must produce bit-identical results to them.
+
+#[cfg(target_arch = "x86")]
+use std::arch::x86::*;
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::*;
+#[cfg(target_arch = "aarch64")]
+use std::arch::aarch64::*;
+
+use std::env;
+use std::sync::OnceLock;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SimdLevel {
+  Scalar,
+  Sse41,
+  Avx2,
+  Neon,
+}
+
+// Detect the best available instruction set, once per process
+//
+// Setting the environment variable TINYAVIF_NO_SIMD (to any value) forces the scalar
+// code paths, which is useful for checking that the SIMD paths are bit-exact
+pub fn simd_level() -> SimdLevel {
+  static LEVEL: OnceLock<SimdLevel> = OnceLock::new();
+  *LEVEL.get_or_init(|| {
+    if env::var_os("TINYAVIF_NO_SIMD").is_some() {
+      return SimdLevel::Scalar;
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+      if is_x86_feature_detected!("avx2") {
+        return SimdLevel::Avx2;
+      }
+      if is_x86_feature_detected!("sse4.1") {
+        return SimdLevel::Sse41;
+      }
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    {
+      if std::arch::is_aarch64_feature_detected!("neon") {
+        return SimdLevel::Neon;
+      }
+    }
+
+    SimdLevel::Scalar
+  })
+}
+
+// A vector of LANES 32-bit signed integers
+// All arithmetic wraps on overflow, matching the wrapping arithmetic used in the
+// scalar transform code.
+//
+// Safety: These functions may only be called from code which has checked that the
+// relevant instruction set is available. They are marked #[inline(always)] so that
+// they get inlined into the calling #[target_feature] function.
+pub trait I32Vec: Copy {
+  const LANES: usize;
+
+  unsafe fn load(ptr: *const i32) -> Self;
+  unsafe fn store(self, ptr: *mut i32);
+  unsafe fn splat(value: i32) -> Self;
+
+  unsafe fn add(self, other: Self) -> Self;
+  unsafe fn sub(self, other: Self) -> Self;
+  unsafe fn mul(self, other: Self) -> Self;
+  // Arithmetic shift right
+  unsafe fn shr(self, shift: u32) -> Self;
+  unsafe fn min(self, other: Self) -> Self;
+  unsafe fn max(self, other: Self) -> Self;
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl I32Vec for __m256i {
+  const LANES: usize = 8;
+
+  #[inline(always)]
+  unsafe fn load(ptr: *const i32) -> Self { _mm256_loadu_si256(ptr as *const __m256i) }
+  #[inline(always)]
+  unsafe fn store(self, ptr: *mut i32) { _mm256_storeu_si256(ptr as *mut __m256i, self) }
+  #[inline(always)]
+  unsafe fn splat(value: i32) -> Self { _mm256_set1_epi32(value) }
+
+  #[inline(always)]
+  unsafe fn add(self, other: Self) -> Self { _mm256_add_epi32(self, other) }
+  #[inline(always)]
+  unsafe fn sub(self, other: Self) -> Self { _mm256_sub_epi32(self, other) }
+  #[inline(always)]
+  unsafe fn mul(self, other: Self) -> Self { _mm256_mullo_epi32(self, other) }
+  #[inline(always)]
+  unsafe fn shr(self, shift: u32) -> Self { _mm256_sra_epi32(self, _mm_cvtsi32_si128(shift as i32)) }
+  #[inline(always)]
+  unsafe fn min(self, other: Self) -> Self { _mm256_min_epi32(self, other) }
+  #[inline(always)]
+  unsafe fn max(self, other: Self) -> Self { _mm256_max_epi32(self, other) }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl I32Vec for __m128i {
+  const LANES: usize = 4;
+
+  #[inline(always)]
+  unsafe fn load(ptr: *const i32) -> Self { _mm_loadu_si128(ptr as *const __m128i) }
+  #[inline(always)]
+  unsafe fn store(self, ptr: *mut i32) { _mm_storeu_si128(ptr as *mut __m128i, self) }
+  #[inline(always)]
+  unsafe fn splat(value: i32) -> Self { _mm_set1_epi32(value) }
+
+  #[inline(always)]
+  unsafe fn add(self, other: Self) -> Self { _mm_add_epi32(self, other) }
+  #[inline(always)]
+  unsafe fn sub(self, other: Self) -> Self { _mm_sub_epi32(self, other) }
+  #[inline(always)]
+  unsafe fn mul(self, other: Self) -> Self { _mm_mullo_epi32(self, other) }
+  #[inline(always)]
+  unsafe fn shr(self, shift: u32) -> Self { _mm_sra_epi32(self, _mm_cvtsi32_si128(shift as i32)) }
+  #[inline(always)]
+  unsafe fn min(self, other: Self) -> Self { _mm_min_epi32(self, other) }
+  #[inline(always)]
+  unsafe fn max(self, other: Self) -> Self { _mm_max_epi32(self, other) }
+}
+
+#[cfg(target_arch = "aarch64")]
+impl I32Vec for int32x4_t {
+  const LANES: usize = 4;
+
+  #[inline(always)]
+  unsafe fn load(ptr: *const i32) -> Self { vld1q_s32(ptr) }
+  #[inline(always)]
+  unsafe fn store(self, ptr: *mut i32) { vst1q_s32(ptr, self) }
+  #[inline(always)]
+  unsafe fn splat(value: i32) -> Self { vdupq_n_s32(value) }
+
+  #[inline(always)]
+  unsafe fn add(self, other: Self) -> Self { vaddq_s32(self, other) }
+  #[inline(always)]
+  unsafe fn sub(self, other: Self) -> Self { vsubq_s32(self, other) }
+  #[inline(always)]
+  unsafe fn mul(self, other: Self) -> Self { vmulq_s32(self, other) }
+  // NEON only has a left shift by a vector of signed amounts; shifting left by
+  // a negative amount performs an arithmetic right shift
+  #[inline(always)]
+  unsafe fn shr(self, shift: u32) -> Self { vshlq_s32(self, vdupq_n_s32(-(shift as i32))) }
+  #[inline(always)]
+  unsafe fn min(self, other: Self) -> Self { vminq_s32(self, other) }
+  #[inline(always)]
+  unsafe fn max(self, other: Self) -> Self { vmaxq_s32(self, other) }
+}
@@ -0,0 +1,311 @@
+// Copyright (c) 2024-2025, The tinyavif contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+// Statistics gathered while encoding, for users and for encoder developers
+
+use std::fmt;
+use std::time::Duration;
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Instant;
+
+use crate::array2d::Array2D;
+use crate::enums::*;
+use crate::frame::Frame;
+use crate::metrics::{block_sse, psnr};
+use crate::ratecost::COST_SHIFT;
+use crate::util::*;
+
+// Kinds of syntax element which the tile data is made up of, for bit accounting
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BitCategory {
+  Partition = 0,
+  Skip,
+  SegmentId,
+  DeltaQ,
+  YMode,
+  UVMode,
+  Palette,
+  YCoeffs,
+  UCoeffs,
+  VCoeffs,
+}
+
+pub const BIT_CATEGORIES: usize = 10;
+
+pub const ALL_BIT_CATEGORIES: [BitCategory; BIT_CATEGORIES] = [
+  BitCategory::Partition,
+  BitCategory::Skip,
+  BitCategory::SegmentId,
+  BitCategory::DeltaQ,
+  BitCategory::YMode,
+  BitCategory::UVMode,
+  BitCategory::Palette,
+  BitCategory::YCoeffs,
+  BitCategory::UCoeffs,
+  BitCategory::VCoeffs,
+];
+
+impl BitCategory {
+  pub fn name(self) -> &'static str {
+    match self {
+      BitCategory::Partition => "partition",
+      BitCategory::Skip => "skip",
+      BitCategory::SegmentId => "segment_id",
+      BitCategory::DeltaQ => "delta_q",
+      BitCategory::YMode => "y_mode",
+      BitCategory::UVMode => "uv_mode",
+      BitCategory::Palette => "palette",
+      BitCategory::YCoeffs => "y_coeffs",
+      BitCategory::UCoeffs => "u_coeffs",
+      BitCategory::VCoeffs => "v_coeffs",
+    }
+  }
+
+  // Category for the coefficients of the given plane
+  pub fn coeffs(plane: usize) -> Self {
+    [BitCategory::YCoeffs, BitCategory::UCoeffs, BitCategory::VCoeffs][plane]
+  }
+}
+
+// Wall-clock time spent in each stage of encode_image(). Stages which didn't run take no time
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StageTimes {
+  pub prefilter: Duration,
+  pub first_pass: Duration,
+  // Analysing and writing every superblock, including the entropy coder flush
+  pub tile_coding: Duration,
+}
+
+// Times a stage of the encode, for StageTimes. wasm32-unknown-unknown has no clock, and
+// Instant::now() panics there, so on that target every stage takes no time
+pub struct Stopwatch {
+  #[cfg(not(target_arch = "wasm32"))]
+  start: Instant,
+}
+
+impl Stopwatch {
+  pub fn start() -> Self {
+    Self {
+      #[cfg(not(target_arch = "wasm32"))]
+      start: Instant::now(),
+    }
+  }
+
+  pub fn elapsed(&self) -> Duration {
+    #[cfg(not(target_arch = "wasm32"))]
+    return self.start.elapsed();
+    #[cfg(target_arch = "wasm32")]
+    return Duration::ZERO;
+  }
+}
+
+pub struct EncodeStats {
+  // Number of blocks which used each prediction mode
+  pub y_mode_counts: [u64; INTRA_MODES],
+  pub uv_mode_counts: [u64; UV_INTRA_MODES],
+
+  // Number of chroma blocks (counting U and V separately) which were flat and exactly
+  // matched their prediction, so were coded without running the transforms
+  pub uniform_chroma_blocks: u64,
+
+  // Number of blocks which used palette mode, for luma and for chroma
+  pub palette_blocks: [u64; 2],
+
+  // Number of blocks where every plane quantized to zero, so were coded with skip = 1
+  pub skipped_blocks: u64,
+
+  // Number of transform blocks with any nonzero coefficients, and how many of those
+  // had only a DC coefficient, so were coded with the DC-only fast path
+  pub coded_tx_blocks: u64,
+  pub dc_only_tx_blocks: u64,
+
+  // Number of luma transform blocks with nonzero coefficients which used the identity
+  // transform rather than the DCT (only tried with two-pass encoding)
+  pub identity_tx_blocks: u64,
+
+  // Bits spent on each category of syntax element, in units of 1/(1 << COST_SHIFT) bits
+  // These are measured from the entropy coder's state, so add up to the size of the tile data,
+  // apart from the few bits needed to flush the entropy coder at the end
+  pub bits: [u64; BIT_CATEGORIES],
+
+  // Luma prediction mode for each 4x4 luma unit
+  pub y_mode_map: Array2D<PredictionMode>,
+
+  // Size of the block covering each 4x4 luma unit, in luma pixels, as chosen by partitioning
+  pub block_size_map: Array2D<u8>,
+
+  // Time taken by each stage of the encode. Not printed with the other statistics,
+  // as it varies from run to run
+  pub stage_times: StageTimes,
+}
+
+impl EncodeStats {
+  pub fn new(mi_rows: usize, mi_cols: usize) -> Self {
+    Self {
+      y_mode_counts: [0; INTRA_MODES],
+      uv_mode_counts: [0; UV_INTRA_MODES],
+      uniform_chroma_blocks: 0,
+      palette_blocks: [0; 2],
+      skipped_blocks: 0,
+      coded_tx_blocks: 0,
+      dc_only_tx_blocks: 0,
+      identity_tx_blocks: 0,
+      bits: [0; BIT_CATEGORIES],
+      y_mode_map: Array2D::zeroed(mi_rows, mi_cols),
+      block_size_map: Array2D::zeroed(mi_rows, mi_cols),
+      stage_times: StageTimes::default(),
+    }
+  }
+
+  pub fn num_blocks(&self) -> u64 {
+    self.y_mode_counts.iter().sum()
+  }
+
+  // Bits spent on the given category of syntax element
+  pub fn category_bits(&self, category: BitCategory) -> f64 {
+    self.bits[category as usize] as f64 / (1 << COST_SHIFT) as f64
+  }
+}
+
+// Print a histogram of how often each mode was used, skipping unused modes
+fn write_histogram(f: &mut fmt::Formatter, counts: &[u64]) -> fmt::Result {
+  const BAR_WIDTH: u64 = 40;
+  let total: u64 = counts.iter().sum();
+  for (mode, &count) in counts.iter().enumerate() {
+    if count == 0 {
+      continue;
+    }
+    let percent = 100.0 * (count as f64) / (total as f64);
+    let bar_len = (count * BAR_WIDTH).div_ceil(total) as usize;
+    writeln!(f, "  {:<14} {:>8} ({:5.1}%) {}",
+             format!("{:?}", ALL_PREDICTION_MODES[mode]), count, percent, "#".repeat(bar_len))?;
+  }
+  Ok(())
+}
+
+// Print how many bytes were spent on each category of syntax element
+fn write_bit_accounting(f: &mut fmt::Formatter, bits: &[u64; BIT_CATEGORIES]) -> fmt::Result {
+  let total: u64 = bits.iter().sum();
+  writeln!(f, "Bit usage: {:.1} bytes in total", total as f64 / (8 << COST_SHIFT) as f64)?;
+  for category in ALL_BIT_CATEGORIES {
+    let bits = bits[category as usize];
+    let percent = if total == 0 { 0.0 } else { 100.0 * (bits as f64) / (total as f64) };
+    writeln!(f, "  {:<14} {:>10.1} bytes ({:5.1}%)", category.name(), bits as f64 / (8 << COST_SHIFT) as f64, percent)?;
+  }
+  Ok(())
+}
+
+impl fmt::Display for EncodeStats {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    writeln!(f, "Blocks: {}", self.num_blocks())?;
+    writeln!(f, "Luma prediction modes:")?;
+    write_histogram(f, &self.y_mode_counts)?;
+    writeln!(f, "Chroma prediction modes:")?;
+    write_histogram(f, &self.uv_mode_counts)?;
+    writeln!(f, "Uniform chroma blocks (transforms skipped): {} of {}", self.uniform_chroma_blocks, 2 * self.num_blocks())?;
+    writeln!(f, "Palette blocks: {} luma, {} chroma", self.palette_blocks[0], self.palette_blocks[1])?;
+    writeln!(f, "Skipped blocks (no residual coded): {} of {}", self.skipped_blocks, self.num_blocks())?;
+    let dc_only_percent = if self.coded_tx_blocks == 0 {
+      0.0
+    } else {
+      100.0 * self.dc_only_tx_blocks as f64 / self.coded_tx_blocks as f64
+    };
+    writeln!(f, "DC-only transform blocks (fast path): {} of {} ({:.1}%)", self.dc_only_tx_blocks,
+             self.coded_tx_blocks, dc_only_percent)?;
+    writeln!(f, "Identity transform blocks (luma): {}", self.identity_tx_blocks)?;
+    write_bit_accounting(f, &self.bits)
+  }
+}
+
+// (U, V) colour for each luma mode in the mode map
+// Similar modes get similar colours: the directional modes are spread around the
+// colour wheel according to their angle, and the other modes use distinct colours
+const MODE_COLORS: [(u8, u8); INTRA_MODES] = [
+  (128, 128), // DC_PRED: grey
+  (90, 50),   // V_PRED
+  (200, 90),  // H_PRED
+  (60, 110),  // D45_PRED
+  (160, 60),  // D135_PRED
+  (110, 40),  // D113_PRED
+  (190, 70),  // D157_PRED
+  (200, 150), // D203_PRED
+  (70, 70),   // D67_PRED
+  (60, 200),  // SMOOTH_PRED
+  (90, 220),  // SMOOTH_V_PRED
+  (40, 170),  // SMOOTH_H_PRED
+  (220, 220), // PAETH_PRED
+];
+
+// Render a false-colour image showing which luma mode each block used,
+// overlaid on a dimmed copy of the source image
+pub fn render_mode_map(source: &Frame, stats: &EncodeStats) -> Frame {
+  let mut overlay = Frame::new(source.y().crop_height(), source.y().crop_width());
+
+  let src_y = source.y().pixels();
+  overlay.y_mut().pixels_mut().fill_with(|i, j| 64 + src_y[i][j] / 2);
+
+  let mode_map = &stats.y_mode_map;
+  for plane in 1..3 {
+    let pixels = overlay.plane_mut(plane).pixels_mut();
+    // Each 4x4 luma unit corresponds to a 2x2 chroma unit
+    pixels.fill_with(|i, j| {
+      let mode = mode_map[min(i / 2, mode_map.rows() - 1)][min(j / 2, mode_map.cols() - 1)] as usize;
+      let (u, v) = MODE_COLORS[min(mode, INTRA_MODES - 1)];
+      if plane == 1 { u } else { v }
+    });
+  }
+
+  return overlay;
+}
+
+// Block PSNRs at which the distortion heat map is at the cold and hot ends of its scale
+const HEATMAP_COLD_PSNR: f64 = 50.0;
+const HEATMAP_HOT_PSNR: f64 = 20.0;
+
+// Colours along the heat map scale, from least to most distortion: blue, green, yellow, red
+const HEATMAP_COLORS: [[f64; 3]; 4] = [[0.0, 0.0, 255.0], [0.0, 200.0, 0.0], [255.0, 230.0, 0.0], [255.0, 0.0, 0.0]];
+
+// Render a false-colour RGB image showing the distortion of each 8x8 block of `recon`,
+// as measured by its luma PSNR against `source`, overlaid on a greyscale copy of the source
+// Returns 3 bytes per pixel, at the crop size of the source.
+pub fn render_distortion_heatmap(source: &Frame, recon: &Frame) -> Vec<u8> {
+  let width = source.y().crop_width();
+  let height = source.y().crop_height();
+  assert!(recon.y().crop_width() == width && recon.y().crop_height() == height);
+  let src_y = source.y().pixels();
+  let recon_y = recon.y().pixels();
+
+  let mut rgb = vec![0u8; width * height * 3];
+  for block_row in (0..height).step_by(8) {
+    for block_col in (0..width).step_by(8) {
+      let rows = min(8, height - block_row);
+      let cols = min(8, width - block_col);
+      let sse = block_sse(src_y.slice(block_row, block_col, rows, cols),
+                          recon_y.slice(block_row, block_col, rows, cols));
+
+      // Position along the colour scale, from 0.0 (cold) to 1.0 (hot)
+      let block_psnr = psnr(sse, rows * cols);
+      let t = ((HEATMAP_COLD_PSNR - block_psnr) / (HEATMAP_COLD_PSNR - HEATMAP_HOT_PSNR)).clamp(0.0, 1.0);
+      let segment = min((t * 3.0) as usize, 2);
+      let frac = t * 3.0 - segment as f64;
+      let (c0, c1) = (HEATMAP_COLORS[segment], HEATMAP_COLORS[segment + 1]);
+
+      for i in block_row .. block_row + rows {
+        for j in block_col .. block_col + cols {
+          let grey = src_y[i][j] as f64;
+          for c in 0..3 {
+            let color = c0[c] + (c1[c] - c0[c]) * frac;
+            rgb[(i * width + j) * 3 + c] = (0.6 * color + 0.4 * grey).round() as u8;
+          }
+        }
+      }
+    }
+  }
+  return rgb;
+}
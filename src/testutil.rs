@@ -0,0 +1,89 @@
+// Copyright (c) 2024-2025, The tinyavif contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+// Synthetic images, for the self-tests and benchmarks, and for anyone checking
+// the encoder without suitable test images to hand
+//
+// Every generator is deterministic: the same arguments always give the same image,
+// on every platform.
+
+use crate::frame::Frame;
+
+// Small, fast pseudo-random number generator (xorshift32). Not suitable for anything
+// other than making test data
+#[derive(Clone, Copy, Debug)]
+pub struct XorShift32 {
+  state: u32,
+}
+
+impl XorShift32 {
+  // The seed must be nonzero, otherwise every output is 0
+  pub fn new(seed: u32) -> Self {
+    assert!(seed != 0);
+    Self {
+      state: seed
+    }
+  }
+
+  pub fn next_u32(&mut self) -> u32 {
+    self.state ^= self.state << 13;
+    self.state ^= self.state >> 17;
+    self.state ^= self.state << 5;
+    return self.state;
+  }
+}
+
+// Fill the crop region of each plane using `pixel(plane, row, col)`, then fill the padding
+fn generate<F>(width: usize, height: usize, mut pixel: F) -> Frame
+  where F: FnMut(usize, usize, usize) -> u8 {
+  let mut frame = Frame::new(height, width);
+  for plane in 0..3 {
+    let p = frame.plane_mut(plane);
+    let (crop_width, crop_height) = (p.crop_width(), p.crop_height());
+    let pixels = p.pixels_mut();
+    for i in 0..crop_height {
+      for j in 0..crop_width {
+        pixels[i][j] = pixel(plane, i, j);
+      }
+    }
+    p.fill_padding();
+  }
+  return frame;
+}
+
+// Smooth diagonal gradients plus a little noise, roughly like a photo: most blocks
+// have some detail to code, but the transforms compact it well
+pub fn gradient_frame(width: usize, height: usize, seed: u32) -> Frame {
+  let mut rng = XorShift32::new(seed);
+  return generate(width, height, |plane, i, j| {
+    ((i * 3 + j * 2 + plane * 40) as u32 + (rng.next_u32() & 31)) as u8
+  });
+}
+
+// Uniform noise over the full range, the worst case for the encoder: every block is
+// expensive to code, and nothing predicts well
+pub fn noise_frame(width: usize, height: usize, seed: u32) -> Frame {
+  let mut rng = XorShift32::new(seed);
+  return generate(width, height, |_, _, _| (rng.next_u32() >> 24) as u8);
+}
+
+// Flat areas with hard edges and a few colours, like a screenshot of a user interface:
+// a grid of 24x24 "windows" in different colours, each with a one-pixel border
+pub fn screen_frame(width: usize, height: usize) -> Frame {
+  const COLORS: [[u8; 3]; 4] = [[235, 128, 128], [41, 240, 110], [145, 54, 34], [82, 90, 240]];
+  return generate(width, height, |plane, i, j| {
+    // Chroma planes are half size for 4:2:0
+    let (y, x) = if plane == 0 { (i, j) } else { (2 * i, 2 * j) };
+    if y % 24 == 0 || x % 24 == 0 {
+      return if plane == 0 { 16 } else { 128 };
+    }
+    let window = (y / 24 + 3 * (x / 24)) % COLORS.len();
+    COLORS[window][plane]
+  });
+}
@@ -0,0 +1,110 @@
+// Copyright (c) 2024-2025, The tinyavif contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+// Running work on several threads
+//
+// Everything which runs in parallel decides how many threads to use through a ThreadPool,
+// so that one setting (EncoderConfig::threads, or --threads on the command line) limits
+// them all. The pool doesn't keep threads around between uses: each call starts scoped
+// threads, which is cheap next to the work we give them (rows of superblocks, or whole
+// images), and lets them borrow the caller's data.
+
+use std::cmp::min;
+use std::sync::{Mutex, OnceLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Limit on the number of threads which can be asked for
+pub const MAX_THREADS: usize = 256;
+
+// Number of threads to use when none is given: one per physical CPU core, as the
+// encoder gets no faster from running on both SMT siblings of a core. Where the physical
+// cores can't be counted, this falls back to the logical cores which the OS reports.
+// Either way, it's no more than the process is allowed to run on at once.
+pub fn default_threads() -> usize {
+  static THREADS: OnceLock<usize> = OnceLock::new();
+  *THREADS.get_or_init(|| {
+    let logical = std::thread::available_parallelism().map_or(1, |n| n.get());
+    match physical_cores() {
+      Some(physical) => min(physical, logical),
+      None => logical,
+    }
+  })
+}
+
+// Count the physical cores listed in /proc/cpuinfo: the distinct pairs of package
+// ("physical id") and core ("core id") among the logical CPUs. Not every platform
+// lists these, in which case this returns None
+#[cfg(target_os = "linux")]
+fn physical_cores() -> Option<usize> {
+  let cpuinfo = std::fs::read_to_string("/proc/cpuinfo").ok()?;
+  let mut cores = std::collections::HashSet::new();
+  for cpu in cpuinfo.split("\n\n") {
+    let field = |name: &str| cpu.lines().find_map(|line| {
+      let (key, value) = line.split_once(':')?;
+      (key.trim() == name).then(|| value.trim().to_string())
+    });
+    if let Some(core_id) = field("core id") {
+      cores.insert((field("physical id"), core_id));
+    }
+  }
+  return if cores.is_empty() { None } else { Some(cores.len()) };
+}
+
+#[cfg(not(target_os = "linux"))]
+fn physical_cores() -> Option<usize> {
+  None
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ThreadPool {
+  threads: usize,
+}
+
+impl ThreadPool {
+  // A pool with the given number of threads, or default_threads() for 0
+  pub fn new(threads: usize) -> Self {
+    Self {
+      threads: if threads == 0 { default_threads() } else { threads },
+    }
+  }
+
+  pub fn threads(&self) -> usize {
+    return self.threads;
+  }
+
+  // Call `f` on each item, running up to threads() calls at once, and return the results
+  // in the same order as the items. With one thread, everything runs on the current thread
+  pub fn map<T, R, F>(&self, items: &[T], f: F) -> Vec<R>
+    where T: Sync, R: Send, F: Fn(&T) -> R + Sync {
+    let threads = min(self.threads, items.len());
+    if threads <= 1 {
+      return items.iter().map(f).collect();
+    }
+
+    // Items are handed out in order, to whichever thread is free next
+    let next_item = AtomicUsize::new(0);
+    let results: Vec<Mutex<Option<R>>> = items.iter().map(|_| Mutex::new(None)).collect();
+    std::thread::scope(|scope| {
+      for _ in 0..threads {
+        scope.spawn(|| {
+          loop {
+            let index = next_item.fetch_add(1, Ordering::SeqCst);
+            if index >= items.len() {
+              break;
+            }
+            let result = f(&items[index]);
+            *results[index].lock().unwrap() = Some(result);
+          }
+        });
+      }
+    });
+    // Any panic on the threads is passed on when the scope ends, so every item has a result
+    return results.into_iter().map(|result| result.into_inner().unwrap().unwrap()).collect();
+  }
+}
@@ -0,0 +1,1600 @@
+// Copyright (c) 2024-2025, The tinyavif contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+// Encoding of a single tile, in two phases
+//
+// TileAnalyzer makes every decision about each block - prediction modes, palettes, and
+// quantized coefficients - and reconstructs the block, producing a BlockDecision. TileWriter
+// then codes a sequence of BlockDecisions into the bitstream. The writer makes no decisions of
+// its own, and the analyzer never looks at the bitstream, so the two are only connected through
+// BlockDecision and the rate estimates which the analyzer makes using the same syntax functions
+// (write_uv_mode(), write_coeffs(), etc.) as the writer.
+//
+// Because we don't adapt CDFs, the rate of a block only depends on its neighbours' mode info,
+// never on what has been written before it. This means that:
+// * A whole superblock (or more) can be analysed before any of it is written, so decisions can
+//   look ahead at later blocks. Coefficients may even be changed after analysis (eg. by a
+//   trellis pass), as the writer works out the coefficient contexts from the coefficients it
+//   is given, as long as the reconstruction is updated to match.
+// * Different superblock rows can be analysed on different threads, with a single writer
+//   coding the results in order (see AV1Encoder::encode_tile_wavefront()).
+//
+// Decisions must be passed to the writer in coding order: superblocks in raster order, and
+// the blocks within each superblock in the order returned by analyze_superblock().
+
+use bytemuck::Zeroable;
+
+use crate::analysis::{masking_weight, MASKING_WEIGHT_SHIFT};
+use crate::array2d::Array2D;
+use crate::av1_encoder::get_qctx;
+use crate::cdf::*;
+use crate::consts::*;
+use crate::decode::CoeffBlock;
+use crate::entropycode::{EntropyWriter, SymbolWriter};
+use crate::enums::*;
+use crate::frame::Frame;
+use crate::metrics::block_sse;
+use crate::palette::*;
+use crate::ratecost::{rd_cost, symbol_cost, RateCounter};
+use crate::recon::*;
+use crate::segmentation::*;
+use crate::stats::{BitCategory, EncodeStats};
+use crate::util::*;
+
+// Weight of the distortion when choosing how to round DC coefficients, as a shift.
+// The DC predictor copies any error in a block's average into the predictions of the
+// blocks to its right and below, and from them further on, so an error in the DC costs
+// much more than its own block's distortion. The weight was found by testing.
+const DC_DISTORTION_SHIFT: u32 = 4;
+
+// Frame-level settings which affect how each block is chosen and coded
+#[derive(Clone)]
+pub struct TileParams {
+  // Size of the whole image, in 4x4 units
+  pub mi_rows: usize,
+  pub mi_cols: usize,
+
+  pub base_qindex: u8,
+  // qindex offsets for the DC and AC coefficients of each plane
+  pub delta_q: [[i8; 2]; 3],
+  // Bit depth of the image, which selects the quantizer tables
+  pub bit_depth: u8,
+  // Whether the qindex can change per superblock
+  pub delta_q_present: bool,
+  // Segmentation, which can further adjust the qindex of each block
+  pub segmentation: Option<SegmentationParams>,
+  // Whether palette mode is allowed
+  pub screen_content_tools: bool,
+  pub enable_intra_edge_filter: bool,
+  // Sharpness setting, which affects the quantizer rounding
+  pub sharpness: u8,
+  // Reference activity for activity masking with --tune ssim (see analysis.rs), or None
+  // to weight the distortion of every block equally
+  pub masking_reference: Option<u32>,
+  // Superblocks where luma should try the identity transform as well as the DCT, as
+  // chosen by the first pass (see analysis.rs), or None to always use the DCT
+  pub tx_search_map: Option<Array2D<bool>>,
+}
+
+// "Mode info" unit - a struct representing the state of a single 4x4 luma pixel unit.
+// The values in here can be used as contexts when encoding later blocks
+#[derive(Zeroable, Clone)]
+pub struct ModeInfo {
+  // "Level context" for each plane
+  // This is the sum of absolute values of the coefficients in each block,
+  // capped at 63, and is used as part of the context for coefficient sizes
+  //
+  // Note: For luma, this is only used when a transform is smaller than its block,
+  // which this encoder doesn't do yet. But it is required for chroma.
+  level_ctx: [u8; 3],
+
+  // Whether this block was coded with skip = 1, ie. with no residual in any plane
+  skip: bool,
+
+  // Segment this block belongs to, which is always 0 without segmentation
+  segment_id: u8,
+
+  // Prediction modes used for this block
+  y_mode: PredictionMode,
+  uv_mode: PredictionMode,
+  // Angle deltas for luma and chroma, only used with the directional modes
+  angle_delta: [i8; 2],
+
+  // Luma transform type, which is signalled whenever the luma residual isn't all zero.
+  // The chroma transform type follows from uv_mode instead
+  tx_type: TxType,
+
+  // Sign of the DC coefficient for each plane
+  // This is stored differently to what the spec says: we store
+  // -1 if the DC coefficient is negative, 0 if zero, 1 if positive.
+  // This way, we can compare the number of nearby +ve and -ve DC coefficients by
+  // simply summing this value over nearby blocks.
+  dc_sign: [i8; 3],
+
+  // Palettes used for luma and chroma, if any
+  palette: [Palette; 2],
+}
+
+// Everything decided about one 8x8 block by TileAnalyzer, which is all that
+// TileWriter needs in order to code it
+pub struct BlockDecision {
+  // Position within the whole image, in 4x4 units
+  pub mi_row: usize,
+  pub mi_col: usize,
+  // qindex of the superblock containing this block. With segmentation, the block
+  // itself may be coded at a different qindex
+  pub qindex: u8,
+
+  // Mode info for the block. The writer recalculates the coefficient contexts
+  // (level_ctx and dc_sign) from `coeffs`, so these may be left out of date
+  pub mode_info: ModeInfo,
+
+  // Colour index map for each plane type which uses a palette
+  pub color_maps: [Option<Array2D<u8>>; 2],
+
+  // Quantized coefficients for each plane, narrowed to i16 (see narrow_coeffs()) as
+  // a whole superblock row of these can be waiting to be coded
+  pub coeffs: [Array2D<i16>; 3],
+
+  // Number of chroma planes which exactly matched their prediction (for statistics)
+  pub uniform_chroma_planes: u64,
+}
+
+// Analysis state for a single tile, or for a band of superblock rows within it
+pub struct TileAnalyzer<'a> {
+  params: &'a TileParams,
+
+  // Per-superblock qindex for the whole image, if delta-q is enabled
+  qindex_map: Option<&'a Array2D<u8>>,
+  // Per-4x4 segment IDs which we'd like to use for the whole image, if segmentation is
+  // enabled. Skipped blocks end up in the predicted segment instead
+  segment_map: Option<&'a Array2D<u8>>,
+
+  // Mode info per 4x4 luma pixel unit
+  mode_info: Array2D<ModeInfo>,
+
+  // Position of mode_info, source, and recon within the whole image, in 4x4 units
+  // This is only nonzero when these only cover a band of rows, and all other
+  // coordinates are relative to the band
+  mi_row_offset: usize,
+
+  // Source frame, or the band of it being analysed
+  // This is the image we are trying to reproduce
+  // This must be pre-padded to a multiple of 8x8 luma pixels, not the crop size
+  source: &'a Frame,
+
+  // Reconstructed frame
+  recon: Frame,
+
+  // Reused for every block, so that analysis doesn't allocate in its inner loops
+  scratch: BlockScratch,
+}
+
+// Working space for analysing one block. Arrays indexed by plane type are for the
+// luma and chroma transform block sizes
+#[derive(Default)]
+struct BlockScratch {
+  // Quantized coefficients for each plane
+  coeffs: [Array2D<i32>; 3],
+  // Dequantized coefficients, which are inverse transformed in place into a residual
+  residual: [Array2D<i32>; 2],
+  // Trial reconstruction of a block, to measure its error without touching the frame
+  recon: [Array2D<u8>; 2],
+  // Quantized luma coefficients with a second transform type, while choosing between them
+  candidate: Array2D<i32>,
+}
+
+impl BlockScratch {
+  fn new(bsize: usize) -> Self {
+    let sizes = [bsize, bsize / 2];
+    Self {
+      coeffs: std::array::from_fn(|plane| Array2D::zeroed(sizes[min(plane, 1)], sizes[min(plane, 1)])),
+      residual: sizes.map(|size| Array2D::zeroed(size, size)),
+      recon: sizes.map(|size| Array2D::zeroed(size, size)),
+      candidate: Array2D::zeroed(bsize, bsize),
+    }
+  }
+}
+
+// Memory held by one TileAnalyzer's BlockScratch, for memory_estimate()
+pub fn block_scratch_bytes() -> usize {
+  let pixels = 8 * 8 + 4 * 4;
+  return (pixels + 4 * 4 + 8 * 8) * std::mem::size_of::<i32>() + pixels * std::mem::size_of::<i32>() + pixels;
+}
+
+// Memory held by the BlockDecision for one 8x8 block, including its coefficients and, at
+// most, two colour index maps, for memory_estimate()
+pub fn block_decision_bytes() -> usize {
+  let pixels = 8 * 8 + 4 * 4;
+  return std::mem::size_of::<BlockDecision>() + (pixels + 4 * 4) * std::mem::size_of::<i16>() + pixels;
+}
+
+// The reconstruction and mode info of a finished superblock, which later superblocks
+// predict from. This lets the superblock row above a band be analysed elsewhere
+// (by an earlier band, or on another thread) and then copied into it.
+pub struct SuperblockContext {
+  recon: [Array2D<u8>; 3],
+  mode_info: Array2D<ModeInfo>,
+}
+
+// The buffers which a TileAnalyzer needs, kept from one image to be reused for the next,
+// rather than allocated again (see AV1Encoder::encode_into()). Buffers of the wrong size
+// are ignored.
+#[derive(Default)]
+pub struct TileBuffers {
+  pub mode_info: Option<Array2D<ModeInfo>>,
+  pub recon: Option<Frame>,
+}
+
+impl TileBuffers {
+  // Take the mode info buffer if it's the right size, cleared, or allocate a new one
+  pub fn take_mode_info(&mut self, mi_rows: usize, mi_cols: usize) -> Array2D<ModeInfo> {
+    match self.mode_info.take() {
+      Some(mut mode_info) if mode_info.rows() == mi_rows && mode_info.cols() == mi_cols => {
+        mode_info.fill_zeroed();
+        mode_info
+      },
+      _ => Array2D::zeroed(mi_rows, mi_cols),
+    }
+  }
+
+  // Take the recon buffer if it's the right (padded) size, cleared, or allocate a new one
+  pub fn take_recon(&mut self, height: usize, width: usize) -> Frame {
+    match self.recon.take() {
+      Some(mut recon) if recon.y().height() == height && recon.y().width() == width => {
+        recon.clear();
+        recon
+      },
+      _ => Frame::new(height, width),
+    }
+  }
+}
+
+impl<'a> TileAnalyzer<'a> {
+  // Start analysing `source`, which covers the whole image from mi_row_offset down
+  // (but may end early). The superblock row above (if any) must be copied in with
+  // set_superblock() before the row below it is analysed
+  pub fn new(params: &'a TileParams, source: &'a Frame, qindex_map: Option<&'a Array2D<u8>>,
+             segment_map: Option<&'a Array2D<u8>>, mi_row_offset: usize) -> Self {
+    return Self::with_buffers(params, source, qindex_map, segment_map, mi_row_offset, TileBuffers::default());
+  }
+
+  // As new(), but reusing the buffers from an earlier TileAnalyzer where possible
+  pub fn with_buffers(params: &'a TileParams, source: &'a Frame, qindex_map: Option<&'a Array2D<u8>>,
+                      segment_map: Option<&'a Array2D<u8>>, mi_row_offset: usize,
+                      mut buffers: TileBuffers) -> Self {
+    let height = source.y().height();
+    let width = source.y().width();
+    assert!(width == params.mi_cols * 4);
+    assert!(mi_row_offset * 4 + height <= params.mi_rows * 4);
+    Self {
+      params: params,
+      qindex_map: qindex_map,
+      segment_map: segment_map,
+      mode_info: buffers.take_mode_info(height / 4, width / 4),
+      mi_row_offset: mi_row_offset,
+      source: source,
+      recon: buffers.take_recon(height, width),
+      scratch: BlockScratch::new(8),
+    }
+  }
+
+  // The reconstructed image, relative to mi_row_offset
+  pub fn recon(&self) -> &Frame {
+    &self.recon
+  }
+
+  pub fn into_recon(self) -> Frame {
+    self.recon
+  }
+
+  pub fn into_buffers(self) -> TileBuffers {
+    TileBuffers {
+      mode_info: Some(self.mode_info),
+      recon: Some(self.recon),
+    }
+  }
+
+  // Analyse one superblock, returning the decisions for each of its blocks in coding order
+  // sb_row is relative to mi_row_offset
+  pub fn analyze_superblock(&mut self, sb_row: usize, sb_col: usize) -> Vec<BlockDecision> {
+    let mut decisions = Vec::with_capacity(64);
+    self.analyze_partition(sb_row * 16, sb_col * 16, 64, &mut decisions);
+    return decisions;
+  }
+
+  fn analyze_partition(&mut self, mi_row: usize, mi_col: usize, bsize: usize, decisions: &mut Vec<BlockDecision>) {
+    // Always split down to 8x8 blocks, following the same order as TileWriter::write_partition()
+    if bsize == 8 {
+      decisions.push(self.analyze_block(mi_row, mi_col, bsize));
+    } else {
+      let mi_rows = self.mode_info.rows();
+      let mi_cols = self.mode_info.cols();
+
+      let sub_rows = if (mi_row + bsize/8) < mi_rows { 2 } else { 1 };
+      let sub_cols = if (mi_col + bsize/8) < mi_cols { 2 } else { 1 };
+
+      let offset = bsize / 8;
+      for i in 0..sub_rows {
+        for j in 0..sub_cols {
+          self.analyze_partition(mi_row + i*offset, mi_col + j*offset, bsize/2, decisions);
+        }
+      }
+    }
+  }
+
+  fn analyze_block(&mut self, mi_row: usize, mi_col: usize, bsize: usize) -> BlockDecision {
+    assert!(bsize == 8);
+
+    // Allocate a ModeInfo struct to hold information about the current block
+    let mut this_mi = ModeInfo::zeroed();
+
+    // The qindex for this block. Any change is signalled after the skip flag, but we
+    // need to know the final value up front in order to decide whether to skip
+    let sb_qindex = match self.qindex_map {
+      Some(qindex_map) => qindex_map[(self.mi_row_offset + mi_row) / 16][mi_col / 16],
+      None => self.params.base_qindex,
+    };
+    let segment_id = self.segment_map.map_or(0, |segment_map| segment_map[self.mi_row_offset + mi_row][mi_col]);
+    let qindex = match &self.params.segmentation {
+      Some(segmentation) => segmentation.qindex(sb_qindex, segment_id),
+      None => sb_qindex,
+    };
+
+    // The scratch space is moved out while it's in use, so that it can be borrowed
+    // alongside the rest of the analyzer
+    let mut scratch = std::mem::take(&mut self.scratch);
+
+    // Luma always uses DC_PRED, while chroma picks from the other modes based on a quick
+    // error check
+    this_mi.y_mode = PredictionMode::DC_PRED;
+    (this_mi.uv_mode, this_mi.angle_delta[1]) = self.choose_uv_mode(mi_row, mi_col, bsize, this_mi.y_mode, qindex,
+                                                                    &mut scratch.residual[1]);
+
+    // Predict and quantize each plane
+    let mut skip_txfm = [false; 3];
+    let mut uniform_chroma_planes = 0;
+    for plane in 0..3 {
+      let subsampling = if plane > 0 { 1 } else { 0 };
+      let y0 = (mi_row * 4) >> subsampling;
+      let x0 = (mi_col * 4) >> subsampling;
+      let h = bsize >> subsampling;
+      let w = bsize >> subsampling;
+
+      let plane_type = if plane > 0 { 1 } else { 0 };
+      let mode = if plane > 0 { this_mi.uv_mode } else { this_mi.y_mode };
+      let tx_type = if plane > 0 { uv_tx_type(mode) } else { TxType::DCT_DCT };
+      let neighbours = self.intra_neighbours(mi_row, mi_col, plane_type);
+      intra_predict(self.recon.plane_mut(plane).pixels_mut(), &neighbours, mode, this_mi.angle_delta[plane_type],
+                    y0, x0, h, w);
+
+      // Fast path for flat chroma, which is common in screenshots and scanned documents:
+      // if the source block exactly matches its prediction, all coefficients are zero,
+      // so there's no need to run the transforms
+      skip_txfm[plane] = plane > 0 && matches_prediction(self.source.plane(plane).pixels().slice(y0, x0, h, w),
+                                                         self.recon.plane(plane).pixels().slice(y0, x0, h, w));
+      let coeffs = &mut scratch.coeffs[plane];
+      if skip_txfm[plane] {
+        uniform_chroma_planes += 1;
+        coeffs.fill_zeroed();
+      } else {
+        compute_residual_into(self.source.plane(plane).pixels().slice(y0, x0, h, w),
+                              self.recon.plane(plane).pixels().slice(y0, x0, h, w), tx_type, coeffs);
+        let dc_coeff = coeffs[0][0];
+        quantize(coeffs, &self.quantizer(plane, qindex), self.rounding_bias(qindex));
+        self.choose_dc_level(plane, mi_row, mi_col, bsize, qindex, tx_type, dc_coeff, coeffs,
+                             &mut scratch.residual[plane_type], &mut scratch.recon[plane_type]);
+      }
+    }
+
+    // In the superblocks picked out by the first pass, luma may be cheaper to code with
+    // the identity transform
+    if self.search_tx_type(mi_row, mi_col) {
+      this_mi.tx_type = self.choose_y_tx_type(mi_row, mi_col, bsize, qindex, &mut scratch);
+    }
+
+    // Palette mode replaces the intra prediction for any plane type where it's cheaper
+    let mut color_maps = [None, None];
+    if self.params.screen_content_tools {
+      self.choose_palettes(mi_row, mi_col, bsize, &mut this_mi, &mut scratch.coeffs, &mut skip_txfm, &mut color_maps);
+    }
+
+    // If every coefficient in every plane is zero, the block will be coded with skip = 1
+    let skip = scratch.coeffs.iter().all(|residual| (0..residual.rows()).all(|i| residual[i].iter().all(|&c| c == 0)));
+    this_mi.skip = skip;
+    // Skipped blocks don't signal a segment ID, and so are put in the predicted segment
+    this_mi.segment_id = if skip && self.params.segmentation.is_some() {
+      predict_segment_id_at(&self.mode_info, mi_row, mi_col).0
+    } else {
+      segment_id
+    };
+
+    // Finish reconstructing the block, and work out the contexts which later blocks will
+    // use for their coefficients. A skipped block leaves these at zero, the same as an
+    // all-zero transform block would, and leaves the prediction unchanged
+    if !skip {
+      for (plane, coeffs) in scratch.coeffs.iter().enumerate() {
+        let subsampling = if plane > 0 { 1 } else { 0 };
+        let y0 = (mi_row * 4) >> subsampling;
+        let x0 = (mi_col * 4) >> subsampling;
+        let h = bsize >> subsampling;
+        let w = bsize >> subsampling;
+
+        let level: i32 = (0..coeffs.rows()).map(|i| coeffs[i].iter().map(|&c| abs(c)).sum::<i32>()).sum();
+        this_mi.level_ctx[plane] = min(level, 63) as u8;
+        this_mi.dc_sign[plane] = signum(coeffs[0][0]) as i8;
+
+        // A zero residual leaves the prediction unchanged
+        if !skip_txfm[plane] {
+          let tx_type = if plane > 0 { uv_tx_type(this_mi.uv_mode) } else { this_mi.tx_type };
+          let residual = &mut scratch.residual[if plane > 0 { 1 } else { 0 }];
+          residual.copy_from(coeffs.slice(0, 0, h, w));
+          dequantize(residual, &self.quantizer(plane, qindex));
+          apply_residual(self.recon.plane_mut(plane).pixels_mut().slice_mut(y0, x0, h, w), residual, tx_type);
+        }
+      }
+    }
+
+    // Save mode info
+    self.mode_info.fill_region(mi_row, mi_col, bsize/4, bsize/4, &this_mi);
+
+    let coeffs = std::array::from_fn(|plane| narrow_coeffs(&scratch.coeffs[plane]));
+    self.scratch = scratch;
+
+    return BlockDecision {
+      mi_row: self.mi_row_offset + mi_row,
+      mi_col: mi_col,
+      qindex: sb_qindex,
+      mode_info: this_mi,
+      color_maps: color_maps,
+      coeffs: coeffs,
+      uniform_chroma_planes: uniform_chroma_planes,
+    };
+  }
+
+  // Copy out a finished superblock, to pass to the next superblock row
+  pub fn get_superblock(&self, sb_row: usize, sb_col: usize) -> SuperblockContext {
+    let recon = std::array::from_fn(|plane| {
+      let subsampling = if plane > 0 { 1 } else { 0 };
+      let size = 64 >> subsampling;
+      let pixels = self.recon.plane(plane).pixels();
+      let y0 = sb_row * size;
+      let x0 = sb_col * size;
+      Array2D::new_with(min(size, pixels.rows() - y0), min(size, pixels.cols() - x0), |i, j| pixels[y0 + i][x0 + j])
+    });
+    let mi_row = sb_row * 16;
+    let mi_col = sb_col * 16;
+    let mode_info = Array2D::new_with(min(16, self.mode_info.rows() - mi_row), min(16, self.mode_info.cols() - mi_col),
+                                      |i, j| self.mode_info[mi_row + i][mi_col + j].clone());
+    return SuperblockContext {
+      recon: recon,
+      mode_info: mode_info,
+    };
+  }
+
+  // Fill in a superblock which was analysed elsewhere, for use as a neighbour of later ones
+  pub fn set_superblock(&mut self, sb_row: usize, sb_col: usize, sb: &SuperblockContext) {
+    for plane in 0..3 {
+      let subsampling = if plane > 0 { 1 } else { 0 };
+      let size = 64 >> subsampling;
+      let pixels = self.recon.plane_mut(plane).pixels_mut();
+      let src = &sb.recon[plane];
+      for i in 0..src.rows() {
+        pixels[sb_row * size + i][sb_col * size ..][.. src.cols()].copy_from_slice(&src[i]);
+      }
+    }
+    for i in 0..sb.mode_info.rows() {
+      self.mode_info[sb_row * 16 + i][sb_col * 16 ..][.. sb.mode_info.cols()].clone_from_slice(&sb.mode_info[i]);
+    }
+  }
+
+  // Pick the chroma prediction mode (and angle delta) with the lowest rate-distortion cost,
+  // counting the mode signalling, the U and V coefficients, and the error remaining after
+  // reconstruction. Chroma blocks are only 4x4, so it's cheap to fully code every candidate.
+  // DC_PRED is tried first, so it wins any ties. `residual` is scratch space for a chroma block.
+  fn choose_uv_mode(&mut self, mi_row: usize, mi_col: usize, bsize: usize, y_mode: PredictionMode,
+                    qindex: u8, residual: &mut Array2D<i32>) -> (PredictionMode, i8) {
+    let y0 = mi_row * 2;
+    let x0 = mi_col * 2;
+    let h = bsize / 2;
+    let w = bsize / 2;
+    let neighbours = self.intra_neighbours(mi_row, mi_col, 1);
+
+    let mut candidates = vec![(PredictionMode::DC_PRED, 0), (PredictionMode::SMOOTH_PRED, 0),
+                              (PredictionMode::PAETH_PRED, 0)];
+    for &mode in ALL_PREDICTION_MODES.iter().filter(|&&mode| is_directional_mode(mode)) {
+      for angle_delta in -MAX_ANGLE_DELTA ..= MAX_ANGLE_DELTA {
+        candidates.push((mode, angle_delta));
+      }
+    }
+
+    let mut best = candidates[0];
+    let mut best_cost = u64::MAX;
+    for (mode, angle_delta) in candidates {
+      let mut mode_rate = RateCounter::new();
+      write_uv_mode(&mut mode_rate, y_mode, mode, angle_delta);
+      let mut rate = mode_rate.cost();
+      let mut sse = 0;
+      for plane in 1..3 {
+        // The reconstruction is only scratch space here, as it's predicted again once
+        // the mode has been chosen
+        intra_predict(self.recon.plane_mut(plane).pixels_mut(), &neighbours, mode, angle_delta, y0, x0, h, w);
+        compute_residual_into(self.source.plane(plane).pixels().slice(y0, x0, h, w),
+                              self.recon.plane(plane).pixels().slice(y0, x0, h, w), uv_tx_type(mode), residual);
+        let quantizer = self.quantizer(plane, qindex);
+        quantize(residual, &quantizer, self.rounding_bias(qindex));
+        rate += self.estimate_coeff_cost(plane, mi_row, mi_col, bsize, residual, uv_tx_type(mode));
+        dequantize(residual, &quantizer);
+        apply_residual(self.recon.plane_mut(plane).pixels_mut().slice_mut(y0, x0, h, w), residual, uv_tx_type(mode));
+        sse += block_sse(self.source.plane(plane).pixels().slice(y0, x0, h, w),
+                         self.recon.plane(plane).pixels().slice(y0, x0, h, w));
+      }
+      let cost = self.block_rd_cost(mi_row, mi_col, sse, rate, qindex);
+      if cost < best_cost {
+        best = (mode, angle_delta);
+        best_cost = cost;
+      }
+    }
+    return best;
+  }
+
+  // Choose whether to round the DC coefficient of a quantized block down or up, whichever
+  // has the lower rate-distortion cost. `dc_coeff` is the DC coefficient before quantization,
+  // and the prediction must be in the reconstruction. `residual` and `recon` are scratch
+  // space of the block's size.
+  //
+  // Besides this block's own coefficients, rounding a small DC coefficient down to zero
+  // changes the context which the blocks to the right and below use for their DC signs.
+  // In smooth gradients the residual DC tends to have the same sign over large areas, so
+  // we assume those blocks will share this block's sign, and add what coding their sign
+  // would cost in each case. Only half of that is counted, as many of them will end up
+  // with no DC coefficient to code a sign for.
+  #[allow(clippy::too_many_arguments)]
+  fn choose_dc_level(&self, plane: usize, mi_row: usize, mi_col: usize, bsize: usize, qindex: u8,
+                     tx_type: TxType, dc_coeff: i32, coeffs: &mut Array2D<i32>, residual: &mut Array2D<i32>,
+                     recon: &mut Array2D<u8>) {
+    if dc_coeff == 0 {
+      return;
+    }
+    let subsampling = if plane > 0 { 1 } else { 0 };
+    let y0 = (mi_row * 4) >> subsampling;
+    let x0 = (mi_col * 4) >> subsampling;
+    let h = bsize >> subsampling;
+    let w = bsize >> subsampling;
+    let source = self.source.plane(plane).pixels().slice(y0, x0, h, w);
+    let pred = self.recon.plane(plane).pixels().slice(y0, x0, h, w);
+
+    let quantizer = self.quantizer(plane, qindex);
+    let sign = signum(dc_coeff);
+    let round_down = abs(dc_coeff) / quantizer.dc_q();
+
+    let mut best_level = coeffs[0][0];
+    let mut best_cost = u64::MAX;
+    for level in [round_down, round_down + 1] {
+      coeffs[0][0] = sign * level;
+      let rate = self.estimate_coeff_cost(plane, mi_row, mi_col, bsize, coeffs, tx_type) +
+                 self.neighbour_dc_sign_cost(plane, mi_row, mi_col, bsize, signum(level) * sign, sign) / 2;
+
+      residual.copy_from(coeffs.slice(0, 0, h, w));
+      dequantize(residual, &quantizer);
+      recon.copy_from(pred);
+      apply_residual(recon.slice_mut(0, 0, h, w), residual, tx_type);
+      let sse = block_sse(source, recon.slice(0, 0, h, w));
+
+      let cost = self.block_rd_cost(mi_row, mi_col, sse << DC_DISTORTION_SHIFT, rate, qindex);
+      if cost < best_cost {
+        best_level = coeffs[0][0];
+        best_cost = cost;
+      }
+    }
+    coeffs[0][0] = best_level;
+  }
+
+  // Whether to try other luma transform types than the DCT in the superblock containing
+  // the block at (mi_row, mi_col)
+  fn search_tx_type(&self, mi_row: usize, mi_col: usize) -> bool {
+    match &self.params.tx_search_map {
+      Some(tx_search_map) => tx_search_map[(self.mi_row_offset + mi_row) / 16][mi_col / 16],
+      None => false,
+    }
+  }
+
+  // Choose between the DCT and the identity transform for the luma residual of a block,
+  // whichever has the lower rate-distortion cost. The prediction must be in the
+  // reconstruction, and the quantized DCT coefficients in scratch.coeffs[0], which are
+  // replaced if the identity transform wins. The DCT is tried first, so wins any ties,
+  // including when both quantize to zero.
+  fn choose_y_tx_type(&self, mi_row: usize, mi_col: usize, bsize: usize, qindex: u8,
+                      scratch: &mut BlockScratch) -> TxType {
+    let y0 = mi_row * 4;
+    let x0 = mi_col * 4;
+    compute_residual_into(self.source.y().pixels().slice(y0, x0, bsize, bsize),
+                          self.recon.y().pixels().slice(y0, x0, bsize, bsize), TxType::IDTX, &mut scratch.candidate);
+    quantize(&mut scratch.candidate, &self.quantizer(0, qindex), self.rounding_bias(qindex));
+    // The transform type isn't signalled for an all-zero block, so that would really be a
+    // choice to drop the residual, which isn't what we're deciding here
+    if (0..bsize).all(|i| scratch.candidate[i].iter().all(|&c| c == 0)) {
+      return TxType::DCT_DCT;
+    }
+
+    let dct_cost = self.y_tx_rd_cost(mi_row, mi_col, bsize, qindex, TxType::DCT_DCT, &scratch.coeffs[0],
+                                     &mut scratch.residual[0], &mut scratch.recon[0]);
+    let identity_cost = self.y_tx_rd_cost(mi_row, mi_col, bsize, qindex, TxType::IDTX, &scratch.candidate,
+                                          &mut scratch.residual[0], &mut scratch.recon[0]);
+    if identity_cost < dct_cost {
+      std::mem::swap(&mut scratch.coeffs[0], &mut scratch.candidate);
+      return TxType::IDTX;
+    }
+    return TxType::DCT_DCT;
+  }
+
+  // Rate-distortion cost of coding the luma residual of a block as `coeffs`, quantized
+  // after transforming with `tx_type`. `residual` and `recon` are scratch space of the
+  // block's size.
+  #[allow(clippy::too_many_arguments)]
+  fn y_tx_rd_cost(&self, mi_row: usize, mi_col: usize, bsize: usize, qindex: u8, tx_type: TxType,
+                  coeffs: &Array2D<i32>, residual: &mut Array2D<i32>, recon: &mut Array2D<u8>) -> u64 {
+    let y0 = mi_row * 4;
+    let x0 = mi_col * 4;
+    let source = self.source.y().pixels().slice(y0, x0, bsize, bsize);
+    let pred = self.recon.y().pixels().slice(y0, x0, bsize, bsize);
+
+    let rate = self.estimate_coeff_cost(0, mi_row, mi_col, bsize, coeffs, tx_type);
+    residual.copy_from(coeffs.slice(0, 0, bsize, bsize));
+    dequantize(residual, &self.quantizer(0, qindex));
+    recon.copy_from(pred);
+    apply_residual(recon.slice_mut(0, 0, bsize, bsize), residual, tx_type);
+    let sse = block_sse(source, recon.slice(0, 0, bsize, bsize));
+    // Any error in the block's average is copied on by DC_PRED, as in choose_dc_level(),
+    // so gets the same extra weight there
+    let mut error_sum = 0i64;
+    for i in 0..bsize {
+      error_sum += source[i].iter().zip(&recon[i]).map(|(&s, &r)| s as i64 - r as i64).sum::<i64>();
+    }
+    let dc_sse = (error_sum * error_sum) as u64 / (bsize * bsize) as u64;
+    return self.block_rd_cost(mi_row, mi_col, sse + (dc_sse << DC_DISTORTION_SHIFT), rate, qindex);
+  }
+
+  // Cost of coding DC sign `expected` in the blocks directly to the right of and below
+  // a block whose DC sign is `dc_sign`. The other neighbours of those blocks are taken
+  // from the mode info as it is now, which counts any not yet analysed as zero.
+  fn neighbour_dc_sign_cost(&self, plane: usize, mi_row: usize, mi_col: usize, bsize: usize,
+                            dc_sign: i32, expected: i32) -> u64 {
+    let txsize = if plane > 0 { bsize/2 } else { bsize };
+    let ptype = if plane > 0 { 1 } else { 0 };
+    let qctx = get_qctx(self.params.base_qindex);
+    let shared_units = (txsize / 4) as i32;
+
+    let mut net_signs = Vec::with_capacity(2);
+    if mi_col + bsize/4 < self.mode_info.cols() {
+      let above: i32 = above_units(&self.mode_info, plane, mi_row, mi_col + bsize/4, txsize)
+                         .map(|mi| mi.dc_sign[plane] as i32).sum();
+      net_signs.push(above + dc_sign * shared_units);
+    }
+    if self.mi_row_offset + mi_row + bsize/4 < self.params.mi_rows {
+      let left: i32 = left_units(&self.mode_info, plane, mi_row + bsize/4, mi_col, txsize)
+                        .map(|mi| mi.dc_sign[plane] as i32).sum();
+      net_signs.push(left + dc_sign * shared_units);
+    }
+
+    let sign = if expected < 0 { 1 } else { 0 };
+    return net_signs.iter().map(|&net_sign| {
+      let dc_sign_ctx = if net_sign == 0 { 0 } else if net_sign < 0 { 1 } else { 2 };
+      symbol_cost(sign, &dc_sign_cdf[qctx][ptype][dc_sign_ctx]) as u64
+    }).sum();
+  }
+
+  // Rate-distortion cost of a decision about the block at (mi_row, mi_col), with the
+  // distortion weighted for activity masking if that is enabled
+  fn block_rd_cost(&self, mi_row: usize, mi_col: usize, sse: u64, rate: u64, qindex: u8) -> u64 {
+    let sse = match self.params.masking_reference {
+      Some(reference) => {
+        let weight = masking_weight(reference, self.source.y().pixels(), mi_row * 4, mi_col * 4);
+        (sse * weight as u64) >> MASKING_WEIGHT_SHIFT
+      },
+      None => sse,
+    };
+    return rd_cost(sse, rate, qindex, self.params.bit_depth);
+  }
+
+  // Quantizer for one plane of a block coded at the given qindex
+  fn quantizer(&self, plane: usize, qindex: u8) -> Quantizer {
+    let [dc_delta, ac_delta] = self.params.delta_q[plane];
+    return Quantizer::new(qindex, dc_delta, ac_delta, self.params.bit_depth);
+  }
+
+  // Quantizer rounding bias for the given qindex, taking the sharpness setting into account
+  fn rounding_bias(&self, qindex: u8) -> i32 {
+    quant_rounding_bias(qindex, self.params.sharpness)
+  }
+
+  // Information about the blocks around the current one, which the non-DC intra
+  // predictors depend on
+  fn intra_neighbours(&self, mi_row: usize, mi_col: usize, plane_type: usize) -> IntraNeighbours {
+    let (have_above_right, have_below_left) = intra_neighbour_availability(mi_row / 2, mi_col / 2,
+                                                                           self.mode_info.rows() / 2,
+                                                                           self.mode_info.cols() / 2);
+    let is_smooth = |mi: &ModeInfo| is_smooth_mode(if plane_type > 0 { mi.uv_mode } else { mi.y_mode });
+    let smooth_neighbour = (mi_row > 0 && is_smooth(&self.mode_info[mi_row - 1][mi_col])) ||
+                           (mi_col > 0 && is_smooth(&self.mode_info[mi_row][mi_col - 1]));
+    return IntraNeighbours {
+      have_above_right: have_above_right,
+      have_below_left: have_below_left,
+      smooth_neighbour: smooth_neighbour,
+      edge_filter: self.params.enable_intra_edge_filter
+    };
+  }
+
+  // Try palette mode for luma and for chroma, and use it wherever it takes fewer bits
+  // than the intra prediction plus a residual. Our palettes reproduce the source block exactly,
+  // so this is never worse for quality either.
+  // For any plane type which uses a palette, this updates the prediction, replaces the
+  // residuals with zeros, and stores the colour index map to be coded.
+  fn choose_palettes(&mut self, mi_row: usize, mi_col: usize, bsize: usize, this_mi: &mut ModeInfo,
+                     residuals: &mut [Array2D<i32>], skip_txfm: &mut [bool; 3],
+                     color_maps: &mut [Option<Array2D<u8>>; 2]) {
+    let no_palette = Palette::zeroed();
+
+    // Luma
+    let y0 = mi_row * 4;
+    let x0 = mi_col * 4;
+    if let Some((palette, map)) = find_palette(&[self.source.y().pixels().slice(y0, x0, bsize, bsize)]) {
+      let mut dc_cost = RateCounter::new();
+      write_palette_y_info(&mut dc_cost, &self.mode_info, mi_row, mi_col, &no_palette);
+      let dc_cost = dc_cost.cost() + self.estimate_coeff_cost(0, mi_row, mi_col, bsize, &residuals[0],
+                                                              this_mi.tx_type);
+
+      let zero = Array2D::zeroed(bsize, bsize);
+      let mut palette_cost = RateCounter::new();
+      write_palette_y_info(&mut palette_cost, &self.mode_info, mi_row, mi_col, &palette);
+      write_color_map(&mut palette_cost, &map, palette.size, 0);
+      let palette_cost = palette_cost.cost() + self.estimate_coeff_cost(0, mi_row, mi_col, bsize, &zero,
+                                                                        TxType::DCT_DCT);
+
+      if palette_cost < dc_cost {
+        predict_palette(self.recon.y_mut().pixels_mut().slice_mut(y0, x0, bsize, bsize), &palette.colors[0], &map);
+        this_mi.palette[0] = palette;
+        this_mi.tx_type = TxType::DCT_DCT;
+        residuals[0].fill_zeroed();
+        skip_txfm[0] = true;
+        color_maps[0] = Some(map);
+      }
+    }
+
+    // Chroma, using a shared palette for U and V
+    let y0 = mi_row * 2;
+    let x0 = mi_col * 2;
+    let h = bsize / 2;
+    let w = bsize / 2;
+    let y_palette_size = this_mi.palette[0].size;
+    if let Some((palette, map)) = find_palette(&[self.source.u().pixels().slice(y0, x0, h, w),
+                                                self.source.v().pixels().slice(y0, x0, h, w)]) {
+      // A chroma palette requires uv_mode = DC_PRED, so the mode signalling cost has to
+      // be included in the comparison, as the chosen mode may be something else
+      let mut no_palette_cost = RateCounter::new();
+      write_uv_mode(&mut no_palette_cost, this_mi.y_mode, this_mi.uv_mode, this_mi.angle_delta[1]);
+      if this_mi.uv_mode == PredictionMode::DC_PRED {
+        write_palette_uv_info(&mut no_palette_cost, &self.mode_info, mi_row, mi_col, y_palette_size, &no_palette);
+      }
+      let no_palette_cost = no_palette_cost.cost() +
+                            self.estimate_coeff_cost(1, mi_row, mi_col, bsize, &residuals[1], uv_tx_type(this_mi.uv_mode)) +
+                            self.estimate_coeff_cost(2, mi_row, mi_col, bsize, &residuals[2], uv_tx_type(this_mi.uv_mode));
+
+      let zero = Array2D::zeroed(h, w);
+      let mut palette_cost = RateCounter::new();
+      write_uv_mode(&mut palette_cost, this_mi.y_mode, PredictionMode::DC_PRED, 0);
+      write_palette_uv_info(&mut palette_cost, &self.mode_info, mi_row, mi_col, y_palette_size, &palette);
+      write_color_map(&mut palette_cost, &map, palette.size, 1);
+      let palette_cost = palette_cost.cost() +
+                         self.estimate_coeff_cost(1, mi_row, mi_col, bsize, &zero, TxType::DCT_DCT) +
+                         self.estimate_coeff_cost(2, mi_row, mi_col, bsize, &zero, TxType::DCT_DCT);
+
+      if palette_cost < no_palette_cost {
+        this_mi.uv_mode = PredictionMode::DC_PRED;
+        this_mi.angle_delta[1] = 0;
+        for plane in 1..3 {
+          predict_palette(self.recon.plane_mut(plane).pixels_mut().slice_mut(y0, x0, h, w), &palette.colors[plane - 1],
+                          &map);
+          residuals[plane].fill_zeroed();
+          skip_txfm[plane] = true;
+        }
+        this_mi.palette[1] = palette;
+        color_maps[1] = Some(map);
+      }
+    }
+  }
+
+  // Estimate the cost of coding a block of quantized coefficients, in units of
+  // 1/(1 << COST_SHIFT) bits, without writing anything to the bitstream.
+  // The contexts used are the same as TileWriter would use at this point. `tx_type` is
+  // only signalled for luma, so is ignored for chroma.
+  pub fn estimate_coeff_cost(&self, plane: usize, mi_row: usize, mi_col: usize, bsize: usize,
+                             coeffs: &Array2D<i32>, tx_type: TxType) -> u64 {
+    let mut counter = RateCounter::new();
+    let mut scratch_mi = ModeInfo::zeroed();
+    scratch_mi.tx_type = tx_type;
+    write_coeffs(&mut counter, &self.mode_info, self.params.base_qindex,
+                 plane, mi_row, mi_col, bsize, &mut scratch_mi, coeffs);
+    return counter.cost();
+  }
+}
+
+// Bitstream state for a single tile, which codes the blocks chosen by TileAnalyzer
+// As only the above and left neighbours are used as contexts, just two superblock rows
+// of mode info are kept.
+pub struct TileWriter {
+  params: TileParams,
+
+  bitstream: EntropyWriter,
+
+  // qindex for the current block. Changes to this are signalled at the start of
+  // each superblock, and are coded relative to the previous value
+  current_qindex: u8,
+  // Whether we still need to signal the delta-q for the current superblock
+  read_deltas: bool,
+
+  // Mode info for the current and previous superblock rows, starting at mi_row_offset
+  mode_info: Array2D<ModeInfo>,
+  mi_row_offset: usize,
+
+  stats: EncodeStats,
+
+  // Copy of each transform block's quantized coefficients, if verification is enabled
+  coeff_log: Option<Vec<CoeffBlock>>,
+
+  // The current block's coefficients, widened back to i32, for luma and chroma
+  coeffs: [Array2D<i32>; 2],
+}
+
+impl TileWriter {
+  // If `log_coeffs` is set, a copy of every block's coefficients is kept, for checking
+  // against what a decoder reads back. If `trace` is set, every symbol written is logged.
+  pub fn new(params: &TileParams, log_coeffs: bool, trace: bool) -> Self {
+    let mut bitstream = EntropyWriter::new();
+    if trace {
+      bitstream.enable_trace();
+    }
+    Self {
+      params: params.clone(),
+      bitstream: bitstream,
+      current_qindex: params.base_qindex,
+      read_deltas: false,
+      mode_info: Array2D::zeroed(32, params.mi_cols),
+      mi_row_offset: 0,
+      stats: EncodeStats::new(params.mi_rows, params.mi_cols),
+      coeff_log: if log_coeffs { Some(Vec::new()) } else { None },
+      coeffs: [Array2D::zeroed(8, 8), Array2D::zeroed(4, 4)],
+    }
+  }
+
+  // The tile data written so far
+  pub fn bitstream(&self) -> &EntropyWriter {
+    &self.bitstream
+  }
+
+  // Finish the tile, returning the tile data, the statistics, and the coefficient log
+  // and syntax trace (if enabled)
+  pub fn finish(mut self) -> (Box<[u8]>, EncodeStats, Option<Vec<CoeffBlock>>, Option<String>) {
+    self.stats.bits = self.bitstream.bit_counts();
+    let trace = self.bitstream.take_trace();
+    return (self.bitstream.finalize(), self.stats, self.coeff_log, trace);
+  }
+
+  // Write one superblock, given the decisions for its blocks in coding order
+  // Superblocks must be written in raster order
+  pub fn write_superblock(&mut self, sb_row: usize, sb_col: usize, decisions: &[BlockDecision]) {
+    // Once a third superblock row is reached, the oldest one is no longer needed
+    if sb_row * 16 >= self.mi_row_offset + 32 {
+      // The new rows are cleared, so that any below the bottom of the frame read
+      // as zero in write_coeffs(), the same as in TileAnalyzer
+      for i in 0..16 {
+        for j in 0..self.params.mi_cols {
+          self.mode_info[i][j] = std::mem::replace(&mut self.mode_info[i + 16][j], ModeInfo::zeroed());
+        }
+      }
+      self.mi_row_offset += 16;
+    }
+
+    // If delta-q is enabled, it is signalled in the first block of each superblock
+    self.read_deltas = self.params.delta_q_present;
+
+    if self.bitstream.is_tracing() {
+      self.bitstream.trace_note(&format!("superblock sb_row {} sb_col {}", sb_row, sb_col));
+    }
+
+    let mut decisions = decisions.iter();
+    self.write_partition(sb_row * 16, sb_col * 16, 64, &mut decisions);
+    assert!(decisions.next().is_none());
+  }
+
+  fn write_partition(&mut self, mi_row: usize, mi_col: usize, bsize: usize,
+                     decisions: &mut std::slice::Iter<BlockDecision>) {
+    // Always split down to 8x8 blocks
+    // For each partition symbol, the context depends on whether the above and/or left
+    // blocks are partitioned to a size smaller than what we're currently considering.
+    // For blocks at one of the frame edges, the missing neighbour is assumed to be
+    // the maximum possible size.
+    //
+    // Because we always split down to the same size, this ends up implying that the
+    // context is:
+    //
+    // Current partition is 8x8: context = 0
+    // Otherwise:
+    //   Top-left corner: context = 0
+    //   Left edge: context = 1
+    //   Top edge: context = 2
+    //   Everywhere else: context = 3
+    self.bitstream.set_category(BitCategory::Partition);
+    if bsize == 8 {
+      self.bitstream.trace_label("partition", 0);
+      // As every block is 8x8, the context here is always 0
+      self.bitstream.write_symbol(0, &partition_8x8_cdf[0]); // PARTITION_NONE
+      let decision = decisions.next().unwrap();
+      assert!(decision.mi_row == mi_row && decision.mi_col == mi_col);
+      self.write_block(decision, bsize);
+    } else {
+      let sub_rows = if (mi_row + bsize/8) < self.params.mi_rows { 2 } else { 1 };
+      let sub_cols = if (mi_col + bsize/8) < self.params.mi_cols { 2 } else { 1 };
+
+      let above_ctx = if mi_row > 0 { 1 } else { 0 };
+      let left_ctx = if mi_col > 0 { 1 } else { 0 };
+      let ctx = 2 * left_ctx + above_ctx;
+
+      let cdf = match bsize {
+        16 => &partition_16x16_cdf[ctx],
+        32 => &partition_32x32_cdf[ctx],
+        64 => &partition_64x64_cdf[ctx],
+        _ => panic!("Reached an unexpected partition size")
+      };
+
+      if sub_rows > 1 && sub_cols > 1 {
+        // Normal case, all partitions are available
+        // Always choose PARTITION_SPLIT
+        self.bitstream.trace_label("partition", ctx);
+        self.bitstream.write_symbol(3, cdf);
+      } else if sub_cols > 1 {
+        // The bottom edge of the frame falls in the top half of this partition, so
+        // we must split horizontally. The only useful choice is whether to split the
+        // in-bounds part in half vertically.
+        //
+        // Thus we use a binary CDF to pick between PARTITION_HORZ (0) or PARTITION_SPLIT (1).
+        // The probability of PARTITION_SPLIT is calculated by summing the probabilities
+        // of the following options using the original CDF:
+        let p_split = get_prob(Partition::VERT as usize, cdf) +
+                      get_prob(Partition::SPLIT as usize, cdf) +
+                      get_prob(Partition::HORZ_A as usize, cdf) +
+                      get_prob(Partition::VERT_A as usize, cdf) +
+                      get_prob(Partition::VERT_B as usize, cdf) +
+                      get_prob(Partition::VERT_4 as usize, cdf);
+        self.bitstream.trace_label("partition", ctx);
+        self.bitstream.write_bit(1, 32768 - p_split);
+      } else if sub_rows > 1 {
+        // The right edge of the frame falls in the left half of this partition, so
+        // we must split vertically. The only useful choice is whether to split the
+        // in-bounds part in half horizontally.
+        //
+        // Thus we use a binary CDF to pick between PARTITION_VERT (0) or PARTITION_SPLIT (1).
+        // The probability of PARTITION_SPLIT is calculated by summing the probabilities
+        // of the following options using the original CDF:
+        let p_split = get_prob(Partition::HORZ as usize, cdf) +
+                      get_prob(Partition::SPLIT as usize, cdf) +
+                      get_prob(Partition::HORZ_A as usize, cdf) +
+                      get_prob(Partition::HORZ_B as usize, cdf) +
+                      get_prob(Partition::VERT_A as usize, cdf) +
+                      get_prob(Partition::HORZ_4 as usize, cdf);
+        self.bitstream.trace_label("partition", ctx);
+        self.bitstream.write_bit(1, 32768 - p_split);
+      } else {
+        // The bottom-right corner of the frame falls in the top-left quadrant of this partition,
+        // so PARTITION_SPLIT is forced. Therefore we don't need to signal anything.
+      }
+
+      let offset = bsize / 8;
+      for i in 0..sub_rows {
+        for j in 0..sub_cols {
+          self.write_partition(mi_row + i*offset, mi_col + j*offset, bsize/2, decisions);
+        }
+      }
+    }
+  }
+
+  fn write_block(&mut self, decision: &BlockDecision, bsize: usize) {
+    let mut this_mi = decision.mode_info.clone();
+    // Neighbouring mode info is looked up relative to the rows we've kept
+    let mi_row = decision.mi_row - self.mi_row_offset;
+    let mi_col = decision.mi_col;
+
+    if self.bitstream.is_tracing() {
+      self.bitstream.trace_note(&format!("block mi_row {} mi_col {} size {}x{} qindex {}",
+                                         decision.mi_row, mi_col, bsize, bsize, decision.qindex));
+    }
+
+    // For skip, the context is the number of above and left blocks which were skipped,
+    // with unavailable blocks counting as not skipped
+    let skip = this_mi.skip;
+    let mut skip_ctx = 0;
+    if decision.mi_row > 0 && self.mode_info[mi_row - 1][mi_col].skip {
+      skip_ctx += 1;
+    }
+    if mi_col > 0 && self.mode_info[mi_row][mi_col - 1].skip {
+      skip_ctx += 1;
+    }
+    self.bitstream.set_category(BitCategory::Skip);
+    self.bitstream.trace_label("skip", skip_ctx);
+    self.bitstream.write_symbol(skip as usize, &skip_cdf[skip_ctx]);
+
+    if let Some(segmentation) = &self.params.segmentation {
+      // intra_segment_id(), after the skip flag as we only use the alt-q feature
+      let (prediction, ctx) = predict_segment_id_at(&self.mode_info, mi_row, mi_col);
+      if skip {
+        assert!(this_mi.segment_id == prediction, "Skipped blocks must use the predicted segment ID");
+      } else {
+        let max = segmentation.last_active_segment() as usize + 1;
+        let code = neg_interleave(this_mi.segment_id as usize, prediction as usize, max);
+        self.bitstream.set_category(BitCategory::SegmentId);
+        self.bitstream.trace_label("segment_id", ctx);
+        self.bitstream.write_symbol(code, &segment_id_cdf[ctx]);
+      }
+    }
+
+    // The delta-q is still signalled for skipped blocks, as our blocks are always
+    // smaller than a superblock
+    if self.read_deltas {
+      self.bitstream.set_category(BitCategory::DeltaQ);
+      self.encode_delta_qindex(decision.qindex);
+      self.read_deltas = false;
+    }
+    assert!(self.current_qindex == decision.qindex);
+
+    // For intra_frame_y_mode, the context depends on the above and left Y modes,
+    // defaulting to DC_PRED if those aren't present
+    // As we always choose DC_PRED, this context is always 0
+    // intra_frame_y_mode(context=0,0) = DC_PRED
+    self.bitstream.set_category(BitCategory::YMode);
+    self.bitstream.trace_label("y_mode", 0);
+    self.bitstream.write_symbol(0, &y_mode_cdf[0][0]);
+
+    self.bitstream.set_category(BitCategory::UVMode);
+    write_uv_mode(&mut self.bitstream, this_mi.y_mode, this_mi.uv_mode, this_mi.angle_delta[1]);
+
+    if self.params.screen_content_tools {
+      // palette_mode_info()
+      self.bitstream.set_category(BitCategory::Palette);
+      // Palettes can only be signalled for plane types which use DC_PRED
+      write_palette_y_info(&mut self.bitstream, &self.mode_info, mi_row, mi_col, &this_mi.palette[0]);
+      if this_mi.uv_mode == PredictionMode::DC_PRED {
+        write_palette_uv_info(&mut self.bitstream, &self.mode_info, mi_row, mi_col,
+                              this_mi.palette[0].size, &this_mi.palette[1]);
+      }
+
+      // palette_tokens()
+      for (plane_type, map) in decision.color_maps.iter().enumerate() {
+        if let Some(map) = map {
+          write_color_map(&mut self.bitstream, map, this_mi.palette[plane_type].size, plane_type);
+        }
+      }
+    }
+
+    // Encode residuals
+    for (plane, stored_coeffs) in decision.coeffs.iter().enumerate() {
+      let coeffs = &mut self.coeffs[if plane > 0 { 1 } else { 0 }];
+      widen_coeffs_into(stored_coeffs, coeffs);
+      if let Some(log) = &mut self.coeff_log {
+        log.push(CoeffBlock {
+          plane: plane,
+          mi_row: decision.mi_row,
+          mi_col: mi_col,
+          coeffs: coeffs.clone(),
+        });
+      }
+
+      // A skipped block leaves the level and DC sign contexts at zero, the same as
+      // an all-zero transform block would
+      this_mi.level_ctx[plane] = 0;
+      this_mi.dc_sign[plane] = 0;
+      if skip {
+        continue;
+      }
+
+      self.bitstream.set_category(BitCategory::coeffs(plane));
+      if self.bitstream.is_tracing() {
+        self.bitstream.trace_note(&format!("residual plane {}", plane));
+      }
+      let eob = write_coeffs(&mut self.bitstream, &self.mode_info, self.params.base_qindex,
+                             plane, mi_row, mi_col, bsize, &mut this_mi, coeffs);
+      if eob > 0 {
+        self.stats.coded_tx_blocks += 1;
+      }
+      if eob == 1 {
+        self.stats.dc_only_tx_blocks += 1;
+      }
+      if plane == 0 && eob > 0 && this_mi.tx_type == TxType::IDTX {
+        self.stats.identity_tx_blocks += 1;
+      }
+    }
+
+    self.stats.y_mode_counts[this_mi.y_mode as usize] += 1;
+    self.stats.uv_mode_counts[this_mi.uv_mode as usize] += 1;
+    self.stats.uniform_chroma_blocks += decision.uniform_chroma_planes;
+    for plane_type in 0..2 {
+      if this_mi.palette[plane_type].size > 0 {
+        self.stats.palette_blocks[plane_type] += 1;
+      }
+    }
+    if skip {
+      self.stats.skipped_blocks += 1;
+    }
+    self.stats.y_mode_map.fill_region(decision.mi_row, mi_col, bsize/4, bsize/4, &this_mi.y_mode);
+    self.stats.block_size_map.fill_region(decision.mi_row, mi_col, bsize/4, bsize/4, &(bsize as u8));
+
+    // Save mode info
+    self.mode_info.fill_region(mi_row, mi_col, bsize/4, bsize/4, &this_mi);
+  }
+
+  // Signal a change of qindex, relative to the previous superblock
+  fn encode_delta_qindex(&mut self, target_qindex: u8) {
+    let delta = (target_qindex as i32) - (self.current_qindex as i32);
+    let delta_abs = unsigned_abs(delta) as usize;
+
+    // Values 0-2 are coded directly. Larger values are coded as a 3-bit length,
+    // followed by the remaining bits (excluding the implicit leading 1 bit).
+    self.bitstream.trace_label("delta_q_abs", 0);
+    if delta_abs < 3 {
+      self.bitstream.write_symbol(delta_abs, &delta_q_abs_cdf);
+    } else {
+      self.bitstream.write_symbol(3, &delta_q_abs_cdf);
+      // delta_abs = (1 << rem_bits) + 1 + abs_bits, where 0 <= abs_bits < (1 << rem_bits)
+      let rem_bits = floor_log2(delta_abs - 1);
+      let abs_bits = delta_abs - (1 << rem_bits) - 1;
+      self.bitstream.trace_label("delta_q_rem_bits", 0);
+      self.bitstream.write_literal(rem_bits - 1, 3);
+      self.bitstream.trace_label("delta_q_abs_bits", 0);
+      self.bitstream.write_literal(abs_bits as u32, rem_bits);
+    }
+
+    if delta_abs > 0 {
+      let sign = if delta < 0 { 1 } else { 0 };
+      self.bitstream.trace_label("delta_q_sign_bit", 0);
+      self.bitstream.write_literal(sign, 1);
+    }
+
+    self.current_qindex = target_qindex;
+  }
+}
+
+// uv_mode and, for the directional modes, angle_delta_uv
+fn write_uv_mode<W: SymbolWriter>(w: &mut W, y_mode: PredictionMode, uv_mode: PredictionMode, angle_delta: i8) {
+  // For uv_mode, the context is simply y_mode combined with whether CFL is allowed,
+  // which it always is for 8x8 blocks
+  w.trace_label("uv_mode", y_mode as usize);
+  w.write_symbol(uv_mode as usize, &uv_mode_cfl_allowed_cdf[y_mode as usize]);
+  if is_directional_mode(uv_mode) {
+    let cdf = &angle_delta_cdf[uv_mode as usize - PredictionMode::V_PRED as usize];
+    w.trace_label("angle_delta_uv", uv_mode as usize);
+    w.write_symbol((angle_delta + MAX_ANGLE_DELTA) as usize, cdf);
+  }
+}
+
+// The palette cache for one plane type: the colours used by the above and left blocks
+// Predicted segment ID and its context, from the neighbouring blocks' segment IDs
+fn predict_segment_id_at(mode_info: &Array2D<ModeInfo>, mi_row: usize, mi_col: usize) -> (u8, usize) {
+  let above = if mi_row > 0 { Some(mode_info[mi_row - 1][mi_col].segment_id) } else { None };
+  let left = if mi_col > 0 { Some(mode_info[mi_row][mi_col - 1].segment_id) } else { None };
+  let above_left = if mi_row > 0 && mi_col > 0 { Some(mode_info[mi_row - 1][mi_col - 1].segment_id) } else { None };
+  predict_segment_id(above_left, above, left)
+}
+
+fn get_palette_cache(mode_info: &Array2D<ModeInfo>, mi_row: usize, mi_col: usize, plane_type: usize) -> Vec<u8> {
+  // The above block is only used if it's in the same superblock row, so that hardware
+  // decoders only need to store palettes for one row of blocks
+  let above = if (mi_row * 4) % 64 != 0 { Some(&mode_info[mi_row - 1][mi_col].palette[plane_type]) } else { None };
+  let left = if mi_col > 0 { Some(&mode_info[mi_row][mi_col - 1].palette[plane_type]) } else { None };
+  palette_cache(above, left)
+}
+
+// The luma part of palette_mode_info(): whether a palette is used, and if so, its colours
+fn write_palette_y_info<W: SymbolWriter>(w: &mut W, mode_info: &Array2D<ModeInfo>, mi_row: usize, mi_col: usize,
+                                         palette: &Palette) {
+  // The context is the number of above and left blocks which use a luma palette. The CDFs
+  // also depend on the block size, but we only use 8x8 blocks, which are size context 0
+  let mut ctx = 0;
+  if mi_row > 0 && mode_info[mi_row - 1][mi_col].palette[0].size > 0 {
+    ctx += 1;
+  }
+  if mi_col > 0 && mode_info[mi_row][mi_col - 1].palette[0].size > 0 {
+    ctx += 1;
+  }
+  w.trace_label("has_palette_y", ctx);
+  w.write_symbol((palette.size > 0) as usize, &palette_y_mode_cdf[0][ctx]);
+  if palette.size > 0 {
+    w.trace_label("palette_size_y_minus_2", 0);
+    w.write_symbol(palette.size - PALETTE_MIN_SIZE, &palette_y_size_cdf[0]);
+    let cache = get_palette_cache(mode_info, mi_row, mi_col, 0);
+    write_palette_colors(w, palette, &cache, 0);
+  }
+}
+
+// The chroma part of palette_mode_info()
+fn write_palette_uv_info<W: SymbolWriter>(w: &mut W, mode_info: &Array2D<ModeInfo>, mi_row: usize, mi_col: usize,
+                                          y_palette_size: usize, palette: &Palette) {
+  // The context is whether this block uses a luma palette
+  let ctx = (y_palette_size > 0) as usize;
+  w.trace_label("has_palette_uv", ctx);
+  w.write_symbol((palette.size > 0) as usize, &palette_uv_mode_cdf[ctx]);
+  if palette.size > 0 {
+    w.trace_label("palette_size_uv_minus_2", 0);
+    w.write_symbol(palette.size - PALETTE_MIN_SIZE, &palette_uv_size_cdf[0]);
+    let cache = get_palette_cache(mode_info, mi_row, mi_col, 1);
+    write_palette_colors(w, palette, &cache, 1);
+  }
+}
+
+// The mode info for each 4x4 unit of `plane` directly above a transform block, for the
+// contexts which look at all of them. For chroma, each 4x4 unit covers 2x2 mode info
+// units, so every other one is used. Units outside the frame are left out.
+fn above_units(mode_info: &Array2D<ModeInfo>, plane: usize, mi_row: usize, mi_col: usize,
+               txsize: usize) -> impl Iterator<Item = &ModeInfo> {
+  let step = if plane > 0 { 2 } else { 1 };
+  (0..txsize/4).map(move |k| mi_col + k * step)
+               .filter(move |&col| mi_row > 0 && col < mode_info.cols())
+               .map(move |col| &mode_info[mi_row - 1][col])
+}
+
+// As above_units(), for the units directly left of a transform block
+fn left_units(mode_info: &Array2D<ModeInfo>, plane: usize, mi_row: usize, mi_col: usize,
+              txsize: usize) -> impl Iterator<Item = &ModeInfo> {
+  let step = if plane > 0 { 2 } else { 1 };
+  (0..txsize/4).map(move |k| mi_row + k * step)
+               .filter(move |&row| mi_col > 0 && row < mode_info.rows())
+               .map(move |row| &mode_info[row][mi_col - 1])
+}
+
+// Context for all_zero, from the level and DC sign contexts of the 4x4 units along the
+// above and left edges of a `txsize` transform within a `bsize` block
+fn all_zero_context(mode_info: &Array2D<ModeInfo>, plane: usize, mi_row: usize, mi_col: usize,
+                    bsize: usize, txsize: usize) -> usize {
+  let residual_size = if plane > 0 { bsize/2 } else { bsize };
+
+  if plane == 0 {
+    // For luma, this is short-circuited to 0 for max-size transforms (ie, transform
+    // size == block size), which is always the case for now. Otherwise it depends on
+    // the largest level context above and to the left.
+    if txsize == residual_size {
+      return 0;
+    }
+    let top = above_units(mode_info, plane, mi_row, mi_col, txsize).map(|mi| mi.level_ctx[0]).max().unwrap_or(0);
+    let left = left_units(mode_info, plane, mi_row, mi_col, txsize).map(|mi| mi.level_ctx[0]).max().unwrap_or(0);
+    return if top == 0 && left == 0 {
+      1
+    } else if top == 0 || left == 0 {
+      2 + (max(top, left) > 3) as usize
+    } else if max(top, left) <= 3 {
+      4
+    } else if min(top, left) <= 3 {
+      5
+    } else {
+      6
+    };
+  }
+
+  // For chroma, it's whether any unit above, and any unit to the left, had nonzero coefficients.
+  // There's a separate set of contexts for when the residual is split into several transforms
+  let above = above_units(mode_info, plane, mi_row, mi_col, txsize)
+                .any(|mi| mi.level_ctx[plane] != 0 || mi.dc_sign[plane] != 0);
+  let left = left_units(mode_info, plane, mi_row, mi_col, txsize)
+               .any(|mi| mi.level_ctx[plane] != 0 || mi.dc_sign[plane] != 0);
+  let split = if residual_size > txsize { 3 } else { 0 };
+  return 7 + (above as usize) + (left as usize) + split;
+}
+
+// Core coefficient coding logic, shared between actually writing coefficients and
+// rate estimation. `w` can be anything which accepts symbols.
+// Returns the end of block position: 0 if every coefficient is zero, 1 if only DC is nonzero
+fn write_coeffs<W: SymbolWriter>(w: &mut W, mode_info: &Array2D<ModeInfo>, base_qindex: u8,
+                                 plane: usize, mi_row: usize, mi_col: usize, bsize: usize, this_mi: &mut ModeInfo,
+                                 coeffs: &Array2D<i32>) -> usize {
+  // Each plane uses a single transform covering the whole block, so chroma transforms
+  // are half the size of luma ones. Only 4x4 and 8x8 transforms are supported so far.
+  let txsize = if plane > 0 { bsize/2 } else { bsize };
+  let txs_ctx = floor_log2(txsize) as usize - 2;
+  assert!(txs_ctx < SUPPORTED_TX_SIZES, "No support for {}x{} transforms", txsize, txsize);
+  let num_coeffs = txsize * txsize;
+  assert!(coeffs.rows() == txsize);
+  assert!(coeffs.cols() == txsize);
+
+  let scan = default_scan(txsize, txsize);
+
+  let qctx = get_qctx(base_qindex);
+
+  let ptype = if plane == 0 { 0 } else { 1 };
+
+  // Find the "end of block" location
+  // This is one past the last nonzero coefficient, or 0 if all coeffs are zero
+  let mut eob = 0;
+  let mut culLevel = 0; // "Cumulative level", gets stored into this_mi.level_ctx
+  for c in 0..num_coeffs {
+    let (row, col) = scan[c];
+    let coeff = coeffs[row as usize][col as usize];
+    culLevel += abs(coeff);
+    if coeff != 0 {
+      eob = c + 1;
+    }
+  }
+  this_mi.level_ctx[plane] = min(culLevel, 63) as u8;
+
+  let all_zero = eob == 0;
+
+  // The all_zero symbol has a complex dependency on the nearby transform coefficients
+  let all_zero_ctx = all_zero_context(mode_info, plane, mi_row, mi_col, bsize, txsize);
+
+  w.trace_label("all_zero", all_zero_ctx);
+  w.write_symbol(all_zero as usize, &all_zero_cdf[qctx][txs_ctx][all_zero_ctx]);
+  if all_zero {
+    return eob;
+  }
+
+  // Transform type - only coded for luma
+  // As we selected the reduced transform set in the frame header,
+  // we end up looking at the TX_SET_INTRA_2 set (see tx_set_intra_2_index()).
+  // The CDF depends on the transform size and the luma mode, which is always DC_PRED
+  if plane == 0 {
+    w.trace_label("intra_tx_type", 0);
+    w.write_symbol(tx_set_intra_2_index(this_mi.tx_type),
+                   &intra_tx_type_set2_cdf[txs_ctx][PredictionMode::DC_PRED as usize]);
+  }
+
+  // Blocks where only the DC coefficient survives quantization are very common at high
+  // qindex, and most of the contexts below are then known without looking at any
+  // neighbouring coefficients, so these take a shorter path which codes the same symbols
+  if eob == 1 {
+    write_dc_only_coeffs(w, mode_info, plane, mi_row, mi_col, txsize, qctx, txs_ctx, this_mi, coeffs[0][0]);
+    return eob;
+  }
+
+  // Number of coefficients, encoded as a logarithmic class + value within that class
+  // Here, the contexts are qindex, plane type, and (for 16x16 and smaller)
+  // whether the selected transform type is 1D (last context = 1) or 2D
+  // (last context = 0). We only use DCT_DCT and IDTX, which both count as 2D transforms
+  //
+  // The EOB is split into a class plus optional extra bits. Each class has the following range:
+  // Class 0 => EOB = 1
+  // Class 1 => EOB = 2
+  // Class 2 => EOB = 3-4
+  // Class 3 => EOB = 5-8
+  // ...
+  // up to a maximum class which depends on the transform size
+  // For 4x4 the largest class is class 4 (EOB = 9-16), for 8x8 it's class 6 (EOB = 33-64)
+  // The CDFs also depend on the transform class, which is always 2D for DCT_DCT and IDTX
+  let eob_class = ceil_log2(eob) as usize;
+  let eob_class_cdf: &[u16] = match num_coeffs {
+    16 => &eob_class_16_cdf[qctx][ptype][0],
+    64 => &eob_class_64_cdf[qctx][ptype][0],
+    _ => unreachable!(),
+  };
+  w.trace_label("eob_pt", ptype);
+  w.write_symbol(eob_class, eob_class_cdf);
+
+  if eob_class > 1 {
+    let eob_class_low = (1 << (eob_class - 1)) + 1;
+    let eob_class_hi = 1 << eob_class;
+    assert!(eob_class_low <= eob && eob <= eob_class_hi);
+
+    // EOB classes 2+ require extra bits
+    // The first extra bit is coded with a special CDF, the rest are literal bits
+    // Context = (qctx, tx size, ptype, eob_class - 2)
+    let first_extra_bit_cdf = &eob_extra_cdf[qctx][txs_ctx][ptype][eob_class - 2];
+    let eob_shift = eob_class - 2;
+    let extra_bit = ((eob - eob_class_low) >> eob_shift) & 1;
+    w.trace_label("eob_extra", eob_class - 2);
+    w.write_symbol(extra_bit, first_extra_bit_cdf);
+
+    // Write any remaining bits as a literal
+    // Note: The AV1 decoder spec gives a more detailed process here,
+    // but it's just writing individual bits from high to low,
+    // which is exactly what write_literal() does
+    let remainder = eob - eob_class_low - (extra_bit << eob_shift);
+    let remainder_bits = eob_class - 2;
+    w.trace_label("eob_extra_bit", 0);
+    w.write_literal(remainder as u32, remainder_bits as u32);
+  }
+
+  // Write "base range" for each coefficient, in high-to-low index order
+  for c in (0..eob).rev() {
+    // Split coefficient into absolute value and sign, as these are coded separately
+    let (row, col) = scan[c];
+    let coeff = coeffs[row as usize][col as usize];
+    let abs_value = unsigned_abs(coeff) as usize;
+
+    // Code coeff_base symbol, which can indicate values 0, 1, 2, or 3+
+    if c == eob - 1 {
+      // Last nonzero coefficient, so we know this can't be zero
+      // Therefore we use a separate set of CDFs and contexts
+      let base_eob_ctx = if c == 0 {
+        0
+      } else if c <= num_coeffs/8 {
+        1
+      } else if c <= num_coeffs/4 {
+        2
+      } else {
+        3
+      };
+      assert!(abs_value >= 1);
+      let coded_value = min(abs_value - 1, 2);
+      w.trace_label("coeff_base_eob", base_eob_ctx);
+      w.write_symbol(coded_value, &coeff_base_eob_cdf[qctx][txs_ctx][ptype][base_eob_ctx]);
+    } else {
+      // Context depends on the base values of coefficients below and to the right,
+      // which have already been encoded
+      let base_ctx = if c == 0 {
+        0
+      } else {
+        let mut mag = 0;
+
+        for (row_off, col_off) in Sig_Ref_Diff_Offset {
+          let ref_row = (row + row_off) as usize;
+          let ref_col = (col + col_off) as usize;
+          if ref_row < txsize && ref_col < txsize {
+            mag += min(abs(coeffs[ref_row][ref_col]), 3);
+          }
+        }
+
+        let mag_part = min(round2(mag, 1), 4) as usize;
+        let loc_part = Coeff_Base_Ctx_Offset_8x8[min(row, 4) as usize][min(col, 4) as usize] as usize;
+        mag_part + loc_part
+      };
+
+      let coded_value = min(abs_value, 3);
+      w.trace_label("coeff_base", base_ctx);
+      w.write_symbol(coded_value, &coeff_base_cdf[qctx][txs_ctx][ptype][base_ctx]);
+    }
+
+    // If coeff_base is 3, we can encode up to 4 symbols to increment the
+    // absolute value further. This can directly encode values up to 14,
+    // or the value 15 for all larger coefficients, in which case the remainder
+    // is Golomb encoded in a separate pass
+    if abs_value > 2 {
+      // All four coeff_br symbols use the same context and CDF, so compute that first
+      let br_ctx = {
+        let mut mag = 0;
+
+        for (row_off, col_off) in Mag_Ref_Offset {
+          let ref_row = (row + row_off) as usize;
+          let ref_col = (col + col_off) as usize;
+          if ref_row < txsize && ref_col < txsize {
+            mag += min(abs(coeffs[ref_row][ref_col]), 15);
+          }
+        }
+
+        let mag_part = min(round2(mag, 1), 6) as usize;
+        let loc_part = if c == 0 {
+          0
+        } else if row < 2 && col < 2 {
+          7
+        } else {
+          14
+        };
+        mag_part + loc_part
+      };
+
+      // Now encode the coeff_br symbols
+      let mut level = 3;
+      for _ in 0..4 {
+        let coeff_br = min(abs_value - level, 3);
+        w.trace_label("coeff_br", br_ctx);
+        w.write_symbol(coeff_br as usize, &coeff_br_cdf[qctx][txs_ctx][ptype][br_ctx]);
+        level += coeff_br;
+        if coeff_br < 3 {
+          break;
+        }
+      }
+    }
+  }
+
+  // Code DC sign + golomb bits
+  write_dc_sign(w, mode_info, plane, mi_row, mi_col, txsize, qctx, this_mi, coeffs[0][0]);
+
+  // Code sign + golomb bits for the rest of coefficients
+  // Note that this is done in low-to-high index order, in contrast to the earlier loop
+  for c in 1..eob {
+    let (row, col) = scan[c];
+    let coeff = coeffs[row as usize][col as usize];
+    if coeff != 0 {
+      let sign = if coeff < 0 { 1 } else { 0 };
+      w.trace_label("sign_bit", 0);
+      w.write_literal(sign, 1);
+    }
+
+    if abs(coeff) >= 15 {
+      w.trace_label("golomb_length_bit", 0);
+      w.write_golomb(unsigned_abs(coeff) - 15);
+    }
+  }
+
+  return eob;
+}
+
+// The rest of write_coeffs() for a block whose only nonzero coefficient is DC (eob = 1).
+// The last (and only) coefficient is at scan position 0, and its coeff_br context only
+// depends on AC coefficients, which are all zero, so every context is fixed except for
+// the DC sign
+#[allow(clippy::too_many_arguments)]
+fn write_dc_only_coeffs<W: SymbolWriter>(w: &mut W, mode_info: &Array2D<ModeInfo>, plane: usize,
+                                         mi_row: usize, mi_col: usize, txsize: usize, qctx: usize,
+                                         txs_ctx: usize, this_mi: &mut ModeInfo, dc_coeff: i32) {
+  let ptype = if plane == 0 { 0 } else { 1 };
+  let eob_class_cdf: &[u16] = match txsize {
+    4 => &eob_class_16_cdf[qctx][ptype][0],
+    8 => &eob_class_64_cdf[qctx][ptype][0],
+    _ => unreachable!(),
+  };
+  w.trace_label("eob_pt", ptype);
+  w.write_symbol(0, eob_class_cdf);
+
+  let abs_value = unsigned_abs(dc_coeff) as usize;
+  assert!(abs_value >= 1);
+  w.trace_label("coeff_base_eob", 0);
+  w.write_symbol(min(abs_value - 1, 2), &coeff_base_eob_cdf[qctx][txs_ctx][ptype][0]);
+
+  let mut level = 3;
+  if abs_value > 2 {
+    for _ in 0..4 {
+      let coeff_br = min(abs_value - level, 3);
+      w.trace_label("coeff_br", 0);
+      w.write_symbol(coeff_br, &coeff_br_cdf[qctx][txs_ctx][ptype][0]);
+      level += coeff_br;
+      if coeff_br < 3 {
+        break;
+      }
+    }
+  }
+
+  write_dc_sign(w, mode_info, plane, mi_row, mi_col, txsize, qctx, this_mi, dc_coeff);
+}
+
+// Code the sign and any Golomb-coded remainder of the DC coefficient, and store its sign
+// for use as context by later blocks
+#[allow(clippy::too_many_arguments)]
+fn write_dc_sign<W: SymbolWriter>(w: &mut W, mode_info: &Array2D<ModeInfo>, plane: usize, mi_row: usize,
+                                  mi_col: usize, txsize: usize, qctx: usize, this_mi: &mut ModeInfo, dc_coeff: i32) {
+  let ptype = if plane == 0 { 0 } else { 1 };
+  if dc_coeff != 0 {
+    // The DC sign context depends on whether there are more +ve signs, more -ve signs,
+    // or an equal number, among all above and left 4x4 units of this plane.
+    //
+    // Also, for the chroma planes, in theory we're only meant to look at the blocks which are "chroma references",
+    // i.e. the ones which contain an MI unit with odd mi_row and mi_col. This matters if we ever support 4x4
+    // block sizes, but as we currently don't, that's just every block.
+    //
+    // As we store the DC sign in ModeInfo::dc_sign as -1 / 0 / +1, we can do this by
+    // simply summing the DC signs of all surrounding units
+    let net_neighbour_sign: i8 = above_units(mode_info, plane, mi_row, mi_col, txsize)
+                                   .chain(left_units(mode_info, plane, mi_row, mi_col, txsize))
+                                   .map(|mi| mi.dc_sign[plane]).sum();
+
+    // Map result to the appropriate context
+    let dc_sign_ctx = if net_neighbour_sign == 0 {
+      0
+    } else if net_neighbour_sign < 0 {
+      1
+    } else {
+      2
+    };
+
+    let sign = if dc_coeff < 0 { 1 } else { 0 };
+    w.trace_label("dc_sign", dc_sign_ctx);
+    w.write_symbol(sign, &dc_sign_cdf[qctx][ptype][dc_sign_ctx]);
+  }
+  if abs(dc_coeff) >= 15 {
+    w.trace_label("golomb_length_bit", 0);
+    w.write_golomb(unsigned_abs(dc_coeff) - 15);
+  }
+
+  // Store DC sign for reference by later blocks
+  this_mi.dc_sign[plane] = signum(dc_coeff) as i8;
+}
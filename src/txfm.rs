@@ -7,10 +7,24 @@
 // Media Patent License 1.0 was not distributed with this source code in the
 // PATENTS file, you can obtain it at www.aomedia.org/license/patent.
 
-// Forward and inverse DCT4 and DCT8 transforms
+// Forward and inverse DCT4, DCT8, DCT16, ADST4 and identity8 transforms, and the 2D transforms built from them
+//
+// Each 1D transform is a type implementing Txfm1D, and the 2D transforms are written once,
+// generically over the row and column transforms. The shared parts (the shifts, clamps, stage
+// ranges, and rectangular scaling) live in the 2D code, so adding a new kind or length of
+// transform only needs its 1D kernels and an entry in the size dispatch in txfm2d().
+
+#[cfg(target_arch = "x86")]
+use std::arch::x86::*;
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::*;
+#[cfg(target_arch = "aarch64")]
+use std::arch::aarch64::*;
 
 use crate::array2d::Array2D;
 use crate::consts::*;
+use crate::enums::TxType;
+use crate::simd::*;
 use crate::util::*;
 
 fn cospi_arr(cos_bit: u32) -> &'static [i32; 64] {
@@ -18,6 +32,11 @@ fn cospi_arr(cos_bit: u32) -> &'static [i32; 64] {
   &av1_cospi_arr_data[(cos_bit - 10) as usize]
 }
 
+fn sinpi_arr(cos_bit: u32) -> &'static [i32; 5] {
+  assert!(10 <= cos_bit && cos_bit <= 13);
+  &av1_sinpi_arr_data[(cos_bit - 10) as usize]
+}
+
 fn clamp_value(value: i32, range_bits: u32) -> i32 {
   assert!(0 < range_bits);
   assert!(range_bits <= 32);
@@ -162,6 +181,148 @@ fn fwd_dct8(arr: &mut [i32], cos_bit: u32, _stage_range: &[u32]) {
   arr.copy_from_slice(&stage5);
 }
 
+// In-place 16-point forward DCT
+fn fwd_dct16(arr: &mut [i32], cos_bit: u32, _stage_range: &[u32]) {
+  assert!(arr.len() == 16);
+
+  let cospi = cospi_arr(cos_bit);
+
+  let stage1 = [
+    arr[0] + arr[15],
+    arr[1] + arr[14],
+    arr[2] + arr[13],
+    arr[3] + arr[12],
+    arr[4] + arr[11],
+    arr[5] + arr[10],
+    arr[6] + arr[9],
+    arr[7] + arr[8],
+    -arr[8] + arr[7],
+    -arr[9] + arr[6],
+    -arr[10] + arr[5],
+    -arr[11] + arr[4],
+    -arr[12] + arr[3],
+    -arr[13] + arr[2],
+    -arr[14] + arr[1],
+    -arr[15] + arr[0],
+  ];
+
+  let stage2 = [
+    stage1[0] + stage1[7],
+    stage1[1] + stage1[6],
+    stage1[2] + stage1[5],
+    stage1[3] + stage1[4],
+    -stage1[4] + stage1[3],
+    -stage1[5] + stage1[2],
+    -stage1[6] + stage1[1],
+    -stage1[7] + stage1[0],
+    stage1[8],
+    stage1[9],
+    half_btf(-cospi[32], stage1[10], cospi[32], stage1[13], cos_bit),
+    half_btf(-cospi[32], stage1[11], cospi[32], stage1[12], cos_bit),
+    half_btf(cospi[32], stage1[12], cospi[32], stage1[11], cos_bit),
+    half_btf(cospi[32], stage1[13], cospi[32], stage1[10], cos_bit),
+    stage1[14],
+    stage1[15],
+  ];
+
+  let stage3 = [
+    stage2[0] + stage2[3],
+    stage2[1] + stage2[2],
+    -stage2[2] + stage2[1],
+    -stage2[3] + stage2[0],
+    stage2[4],
+    half_btf(-cospi[32], stage2[5], cospi[32], stage2[6], cos_bit),
+    half_btf(cospi[32], stage2[6], cospi[32], stage2[5], cos_bit),
+    stage2[7],
+    stage2[8] + stage2[11],
+    stage2[9] + stage2[10],
+    -stage2[10] + stage2[9],
+    -stage2[11] + stage2[8],
+    -stage2[12] + stage2[15],
+    -stage2[13] + stage2[14],
+    stage2[14] + stage2[13],
+    stage2[15] + stage2[12],
+  ];
+
+  let stage4 = [
+    half_btf(cospi[32], stage3[0], cospi[32], stage3[1], cos_bit),
+    half_btf(-cospi[32], stage3[1], cospi[32], stage3[0], cos_bit),
+    half_btf(cospi[48], stage3[2], cospi[16], stage3[3], cos_bit),
+    half_btf(cospi[48], stage3[3], -cospi[16], stage3[2], cos_bit),
+    stage3[4] + stage3[5],
+    -stage3[5] + stage3[4],
+    -stage3[6] + stage3[7],
+    stage3[7] + stage3[6],
+    stage3[8],
+    half_btf(-cospi[16], stage3[9], cospi[48], stage3[14], cos_bit),
+    half_btf(-cospi[48], stage3[10], -cospi[16], stage3[13], cos_bit),
+    stage3[11],
+    stage3[12],
+    half_btf(cospi[48], stage3[13], -cospi[16], stage3[10], cos_bit),
+    half_btf(cospi[16], stage3[14], cospi[48], stage3[9], cos_bit),
+    stage3[15],
+  ];
+
+  let stage5 = [
+    stage4[0],
+    stage4[1],
+    stage4[2],
+    stage4[3],
+    half_btf(cospi[56], stage4[4], cospi[8], stage4[7], cos_bit),
+    half_btf(cospi[24], stage4[5], cospi[40], stage4[6], cos_bit),
+    half_btf(cospi[24], stage4[6], -cospi[40], stage4[5], cos_bit),
+    half_btf(cospi[56], stage4[7], -cospi[8], stage4[4], cos_bit),
+    stage4[8] + stage4[9],
+    -stage4[9] + stage4[8],
+    -stage4[10] + stage4[11],
+    stage4[11] + stage4[10],
+    stage4[12] + stage4[13],
+    -stage4[13] + stage4[12],
+    -stage4[14] + stage4[15],
+    stage4[15] + stage4[14],
+  ];
+
+  let stage6 = [
+    stage5[0],
+    stage5[1],
+    stage5[2],
+    stage5[3],
+    stage5[4],
+    stage5[5],
+    stage5[6],
+    stage5[7],
+    half_btf(cospi[60], stage5[8], cospi[4], stage5[15], cos_bit),
+    half_btf(cospi[28], stage5[9], cospi[36], stage5[14], cos_bit),
+    half_btf(cospi[44], stage5[10], cospi[20], stage5[13], cos_bit),
+    half_btf(cospi[12], stage5[11], cospi[52], stage5[12], cos_bit),
+    half_btf(cospi[12], stage5[12], -cospi[52], stage5[11], cos_bit),
+    half_btf(cospi[44], stage5[13], -cospi[20], stage5[10], cos_bit),
+    half_btf(cospi[28], stage5[14], -cospi[36], stage5[9], cos_bit),
+    half_btf(cospi[60], stage5[15], -cospi[4], stage5[8], cos_bit),
+  ];
+
+  let stage7 = [
+    stage6[0],
+    stage6[8],
+    stage6[4],
+    stage6[12],
+    stage6[2],
+    stage6[10],
+    stage6[6],
+    stage6[14],
+    stage6[1],
+    stage6[9],
+    stage6[5],
+    stage6[13],
+    stage6[3],
+    stage6[11],
+    stage6[7],
+    stage6[15],
+  ];
+
+  arr.copy_from_slice(&stage7);
+}
+
 // In-place 4-point inverse DCT
 fn inv_dct4(arr: &mut [i32], cos_bit: u32, stage_range: &[u32]) {
   assert!(arr.len() == 4);
@@ -193,6 +354,61 @@ fn inv_dct4(arr: &mut [i32], cos_bit: u32, stage_range: &[u32]) {
   arr.copy_from_slice(&stage3);
 }
 
+// In-place 4-point forward ADST
+// Unlike the DCTs, this isn't built from butterflies, so has no stage ranges to apply
+fn fwd_adst4(arr: &mut [i32], cos_bit: u32, _stage_range: &[u32]) {
+  assert!(arr.len() == 4);
+
+  let sinpi = sinpi_arr(cos_bit);
+  let (x0, x1, x2, x3) = (arr[0], arr[1], arr[2], arr[3]);
+
+  let s0 = sinpi[1] * x0;
+  let s1 = sinpi[4] * x0;
+  let s2 = sinpi[2] * x1;
+  let s3 = sinpi[1] * x1;
+  let s4 = sinpi[3] * x2;
+  let s5 = sinpi[4] * x3;
+  let s6 = sinpi[2] * x3;
+  let s7 = x0 + x1 - x3;
+
+  let x0 = s0 + s2 + s5;
+  let x1 = sinpi[3] * s7;
+  let x2 = s1 - s3 + s6;
+  let x3 = s4;
+
+  arr[0] = round2(x0 + x3, cos_bit);
+  arr[1] = round2(x1, cos_bit);
+  arr[2] = round2(x2 - x3, cos_bit);
+  arr[3] = round2(x2 - x0 + x3, cos_bit);
+}
+
+// In-place 4-point inverse ADST
+fn inv_adst4(arr: &mut [i32], cos_bit: u32, _stage_range: &[u32]) {
+  assert!(arr.len() == 4);
+
+  let sinpi = sinpi_arr(cos_bit);
+  let (x0, x1, x2, x3) = (arr[0], arr[1], arr[2], arr[3]);
+
+  let s0 = sinpi[1] * x0 + sinpi[4] * x2 + sinpi[2] * x3;
+  let s1 = sinpi[2] * x0 - sinpi[1] * x2 - sinpi[4] * x3;
+  let s2 = sinpi[3] * (x0 - x2 + x3);
+  let s3 = sinpi[3] * x1;
+
+  arr[0] = round2(s0 + s3, cos_bit);
+  arr[1] = round2(s1 + s3, cos_bit);
+  arr[2] = round2(s2, cos_bit);
+  arr[3] = round2(s0 + s1 - s3, cos_bit);
+}
+
+// In-place 8-point identity transform, which is the same forwards and backwards: it only
+// scales the values, to match the DCT's gain
+fn identity8(arr: &mut [i32], _cos_bit: u32, _stage_range: &[u32]) {
+  assert!(arr.len() == 8);
+  for value in arr.iter_mut() {
+    *value = ((*value as i64) * 2) as i32;
+  }
+}
+
 // In-place 8-point inverse DCT
 fn inv_dct8(arr: &mut [i32], cos_bit: u32, stage_range: &[u32]) {
   assert!(arr.len() == 8);
@@ -258,79 +474,555 @@ fn inv_dct8(arr: &mut [i32], cos_bit: u32, stage_range: &[u32]) {
   arr.copy_from_slice(&stage5);
 }
 
-// Perform a 2D forward transform composed of two 1D transforms
-// R = row transform (applied first)
-// C = col transform (applied second)
-pub fn fwd_txfm2d(residual: &mut Array2D<i32>, txh: usize, txw: usize) {
-  assert!(residual.rows() == txh);
-  assert!(residual.cols() == txw);
+// In-place 16-point inverse DCT
+fn inv_dct16(arr: &mut [i32], cos_bit: u32, stage_range: &[u32]) {
+  assert!(arr.len() == 16);
+
+  let cospi = cospi_arr(cos_bit);
+
+  let stage1 = [
+    arr[0],
+    arr[8],
+    arr[4],
+    arr[12],
+    arr[2],
+    arr[10],
+    arr[6],
+    arr[14],
+    arr[1],
+    arr[9],
+    arr[5],
+    arr[13],
+    arr[3],
+    arr[11],
+    arr[7],
+    arr[15],
+  ];
+
+  let stage2 = [
+    stage1[0],
+    stage1[1],
+    stage1[2],
+    stage1[3],
+    stage1[4],
+    stage1[5],
+    stage1[6],
+    stage1[7],
+    half_btf(cospi[60], stage1[8], -cospi[4], stage1[15], cos_bit),
+    half_btf(cospi[28], stage1[9], -cospi[36], stage1[14], cos_bit),
+    half_btf(cospi[44], stage1[10], -cospi[20], stage1[13], cos_bit),
+    half_btf(cospi[12], stage1[11], -cospi[52], stage1[12], cos_bit),
+    half_btf(cospi[52], stage1[11], cospi[12], stage1[12], cos_bit),
+    half_btf(cospi[20], stage1[10], cospi[44], stage1[13], cos_bit),
+    half_btf(cospi[36], stage1[9], cospi[28], stage1[14], cos_bit),
+    half_btf(cospi[4], stage1[8], cospi[60], stage1[15], cos_bit),
+  ];
+
+  let stage3 = [
+    stage2[0],
+    stage2[1],
+    stage2[2],
+    stage2[3],
+    half_btf(cospi[56], stage2[4], -cospi[8], stage2[7], cos_bit),
+    half_btf(cospi[24], stage2[5], -cospi[40], stage2[6], cos_bit),
+    half_btf(cospi[40], stage2[5], cospi[24], stage2[6], cos_bit),
+    half_btf(cospi[8], stage2[4], cospi[56], stage2[7], cos_bit),
+    clamp_value(stage2[8] + stage2[9], stage_range[3]),
+    clamp_value(stage2[8] - stage2[9], stage_range[3]),
+    clamp_value(-stage2[10] + stage2[11], stage_range[3]),
+    clamp_value(stage2[10] + stage2[11], stage_range[3]),
+    clamp_value(stage2[12] + stage2[13], stage_range[3]),
+    clamp_value(stage2[12] - stage2[13], stage_range[3]),
+    clamp_value(-stage2[14] + stage2[15], stage_range[3]),
+    clamp_value(stage2[14] + stage2[15], stage_range[3]),
+  ];
+
+  let stage4 = [
+    half_btf(cospi[32], stage3[0], cospi[32], stage3[1], cos_bit),
+    half_btf(cospi[32], stage3[0], -cospi[32], stage3[1], cos_bit),
+    half_btf(cospi[48], stage3[2], -cospi[16], stage3[3], cos_bit),
+    half_btf(cospi[16], stage3[2], cospi[48], stage3[3], cos_bit),
+    clamp_value(stage3[4] + stage3[5], stage_range[4]),
+    clamp_value(stage3[4] - stage3[5], stage_range[4]),
+    clamp_value(-stage3[6] + stage3[7], stage_range[4]),
+    clamp_value(stage3[6] + stage3[7], stage_range[4]),
+    stage3[8],
+    half_btf(-cospi[16], stage3[9], cospi[48], stage3[14], cos_bit),
+    half_btf(-cospi[48], stage3[10], -cospi[16], stage3[13], cos_bit),
+    stage3[11],
+    stage3[12],
+    half_btf(-cospi[16], stage3[10], cospi[48], stage3[13], cos_bit),
+    half_btf(cospi[48], stage3[9], cospi[16], stage3[14], cos_bit),
+    stage3[15],
+  ];
+
+  let stage5 = [
+    clamp_value(stage4[0] + stage4[3], stage_range[5]),
+    clamp_value(stage4[1] + stage4[2], stage_range[5]),
+    clamp_value(stage4[1] - stage4[2], stage_range[5]),
+    clamp_value(stage4[0] - stage4[3], stage_range[5]),
+    stage4[4],
+    half_btf(-cospi[32], stage4[5], cospi[32], stage4[6], cos_bit),
+    half_btf(cospi[32], stage4[5], cospi[32], stage4[6], cos_bit),
+    stage4[7],
+    clamp_value(stage4[8] + stage4[11], stage_range[5]),
+    clamp_value(stage4[9] + stage4[10], stage_range[5]),
+    clamp_value(stage4[9] - stage4[10], stage_range[5]),
+    clamp_value(stage4[8] - stage4[11], stage_range[5]),
+    clamp_value(-stage4[12] + stage4[15], stage_range[5]),
+    clamp_value(-stage4[13] + stage4[14], stage_range[5]),
+    clamp_value(stage4[13] + stage4[14], stage_range[5]),
+    clamp_value(stage4[12] + stage4[15], stage_range[5]),
+  ];
+
+  let stage6 = [
+    clamp_value(stage5[0] + stage5[7], stage_range[6]),
+    clamp_value(stage5[1] + stage5[6], stage_range[6]),
+    clamp_value(stage5[2] + stage5[5], stage_range[6]),
+    clamp_value(stage5[3] + stage5[4], stage_range[6]),
+    clamp_value(stage5[3] - stage5[4], stage_range[6]),
+    clamp_value(stage5[2] - stage5[5], stage_range[6]),
+    clamp_value(stage5[1] - stage5[6], stage_range[6]),
+    clamp_value(stage5[0] - stage5[7], stage_range[6]),
+    stage5[8],
+    stage5[9],
+    half_btf(-cospi[32], stage5[10], cospi[32], stage5[13], cos_bit),
+    half_btf(-cospi[32], stage5[11], cospi[32], stage5[12], cos_bit),
+    half_btf(cospi[32], stage5[11], cospi[32], stage5[12], cos_bit),
+    half_btf(cospi[32], stage5[10], cospi[32], stage5[13], cos_bit),
+    stage5[14],
+    stage5[15],
+  ];
+
+  let mut stage7 = [0; 16];
+  for k in 0..8 {
+    stage7[k] = clamp_value(stage6[k] + stage6[15 - k], stage_range[7]);
+    stage7[15 - k] = clamp_value(stage6[k] - stage6[15 - k], stage_range[7]);
+  }
+
+  arr.copy_from_slice(&stage7);
+}
+
+// SIMD versions of the 8-point DCTs
+// These work on an array of 8 vectors, where vector k holds element k of several
+// independent 1D transforms (one per lane). Apart from that, they exactly mirror
+// fwd_dct8() and inv_dct8() above, and must stay bit-exact with them.
+#[inline(always)]
+unsafe fn half_btf_vec<V: I32Vec>(w0: i32, in0: V, w1: i32, in1: V, cos_bit: u32) -> V {
+  let tmp = V::splat(w0).mul(in0).add(V::splat(w1).mul(in1));
+  return tmp.add(V::splat(1 << (cos_bit - 1))).shr(cos_bit);
+}
+
+#[inline(always)]
+unsafe fn clamp_vec<V: I32Vec>(value: V, range_bits: u32) -> V {
+  let min_ = -(1i64 << (range_bits - 1));
+  let max_ = (1i64 << (range_bits - 1)) - 1;
+  return value.max(V::splat(min_ as i32)).min(V::splat(max_ as i32));
+}
+
+#[inline(always)]
+unsafe fn fwd_dct8_vec<V: I32Vec>(arr: &mut [V; 8], cos_bit: u32) {
+  let cospi = cospi_arr(cos_bit);
+
+  let stage1 = [
+    arr[0].add(arr[7]),
+    arr[1].add(arr[6]),
+    arr[2].add(arr[5]),
+    arr[3].add(arr[4]),
+    arr[3].sub(arr[4]),
+    arr[2].sub(arr[5]),
+    arr[1].sub(arr[6]),
+    arr[0].sub(arr[7]),
+  ];
+
+  let stage2 = [
+    stage1[0].add(stage1[3]),
+    stage1[1].add(stage1[2]),
+    stage1[1].sub(stage1[2]),
+    stage1[0].sub(stage1[3]),
+    stage1[4],
+    half_btf_vec(-cospi[32], stage1[5], cospi[32], stage1[6], cos_bit),
+    half_btf_vec(cospi[32], stage1[6], cospi[32], stage1[5], cos_bit),
+    stage1[7],
+  ];
+
+  let stage3 = [
+    half_btf_vec(cospi[32], stage2[0], cospi[32], stage2[1], cos_bit),
+    half_btf_vec(-cospi[32], stage2[1], cospi[32], stage2[0], cos_bit),
+    half_btf_vec(cospi[48], stage2[2], cospi[16], stage2[3], cos_bit),
+    half_btf_vec(cospi[48], stage2[3], -cospi[16], stage2[2], cos_bit),
+    stage2[4].add(stage2[5]),
+    stage2[4].sub(stage2[5]),
+    stage2[7].sub(stage2[6]),
+    stage2[7].add(stage2[6]),
+  ];
+
+  let stage4 = [
+    stage3[0],
+    stage3[1],
+    stage3[2],
+    stage3[3],
+    half_btf_vec(cospi[56], stage3[4], cospi[8], stage3[7], cos_bit),
+    half_btf_vec(cospi[24], stage3[5], cospi[40], stage3[6], cos_bit),
+    half_btf_vec(cospi[24], stage3[6], -cospi[40], stage3[5], cos_bit),
+    half_btf_vec(cospi[56], stage3[7], -cospi[8], stage3[4], cos_bit),
+  ];
+
+  *arr = [
+    stage4[0],
+    stage4[4],
+    stage4[2],
+    stage4[6],
+    stage4[1],
+    stage4[5],
+    stage4[3],
+    stage4[7],
+  ];
+}
 
-  let txsz_idx;
-  let fwd_txfm: &dyn Fn(&mut [i32], u32, &[u32]);
-  if txh == 8 && txw == 8 {
-    txsz_idx = 1;
-    fwd_txfm = &fwd_dct8;
-  } else if txh == 4 && txw == 4 {
-    txsz_idx = 0;
-    fwd_txfm = &fwd_dct4;
+#[inline(always)]
+unsafe fn inv_dct8_vec<V: I32Vec>(arr: &mut [V; 8], cos_bit: u32, stage_range: &[u32]) {
+  let cospi = cospi_arr(cos_bit);
+
+  let stage1 = [
+    arr[0],
+    arr[4],
+    arr[2],
+    arr[6],
+    arr[1],
+    arr[5],
+    arr[3],
+    arr[7],
+  ];
+
+  let stage2 = [
+    stage1[0],
+    stage1[1],
+    stage1[2],
+    stage1[3],
+    half_btf_vec(cospi[56], stage1[4], -cospi[8], stage1[7], cos_bit),
+    half_btf_vec(cospi[24], stage1[5], -cospi[40], stage1[6], cos_bit),
+    half_btf_vec(cospi[40], stage1[5], cospi[24], stage1[6], cos_bit),
+    half_btf_vec(cospi[8], stage1[4], cospi[56], stage1[7], cos_bit)
+  ];
+
+  let stage3 = [
+    half_btf_vec(cospi[32], stage2[0], cospi[32], stage2[1], cos_bit),
+    half_btf_vec(cospi[32], stage2[0], -cospi[32], stage2[1], cos_bit),
+    half_btf_vec(cospi[48], stage2[2], -cospi[16], stage2[3], cos_bit),
+    half_btf_vec(cospi[16], stage2[2], cospi[48], stage2[3], cos_bit),
+    clamp_vec(stage2[4].add(stage2[5]), stage_range[3]),
+    clamp_vec(stage2[4].sub(stage2[5]), stage_range[3]),
+    clamp_vec(stage2[7].sub(stage2[6]), stage_range[3]),
+    clamp_vec(stage2[6].add(stage2[7]), stage_range[3]),
+  ];
+
+  let stage4 = [
+    clamp_vec(stage3[0].add(stage3[3]), stage_range[4]),
+    clamp_vec(stage3[1].add(stage3[2]), stage_range[4]),
+    clamp_vec(stage3[1].sub(stage3[2]), stage_range[4]),
+    clamp_vec(stage3[0].sub(stage3[3]), stage_range[4]),
+    stage3[4],
+    half_btf_vec(-cospi[32], stage3[5], cospi[32], stage3[6], cos_bit),
+    half_btf_vec(cospi[32], stage3[5], cospi[32], stage3[6], cos_bit),
+    stage3[7],
+  ];
+
+  *arr = [
+    clamp_vec(stage4[0].add(stage4[7]), stage_range[5]),
+    clamp_vec(stage4[1].add(stage4[6]), stage_range[5]),
+    clamp_vec(stage4[2].add(stage4[5]), stage_range[5]),
+    clamp_vec(stage4[3].add(stage4[4]), stage_range[5]),
+    clamp_vec(stage4[3].sub(stage4[4]), stage_range[5]),
+    clamp_vec(stage4[2].sub(stage4[5]), stage_range[5]),
+    clamp_vec(stage4[1].sub(stage4[6]), stage_range[5]),
+    clamp_vec(stage4[0].sub(stage4[7]), stage_range[5]),
+  ];
+}
+
+// Apply an 8-point DCT to every column of an 8-row block at once
+// Each row of the block is loaded as a series of vectors, so that each lane
+// ends up processing a separate column
+#[inline(always)]
+unsafe fn dct8_cols<V: I32Vec>(block: &mut Array2D<i32>, inverse: bool, cos_bit: u32, stage_range: &[u32]) {
+  for lane in (0..block.cols()).step_by(V::LANES) {
+    let mut v = [V::splat(0); 8];
+    for k in 0..8 {
+      v[k] = V::load(block[k][lane..].as_ptr());
+    }
+    if inverse {
+      inv_dct8_vec(&mut v, cos_bit, stage_range);
+    } else {
+      fwd_dct8_vec(&mut v, cos_bit);
+    }
+    for k in 0..8 {
+      v[k].store(block[k][lane..].as_mut_ptr());
+    }
+  }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "avx2")]
+unsafe fn dct8_cols_avx2(block: &mut Array2D<i32>, inverse: bool, cos_bit: u32, stage_range: &[u32]) {
+  dct8_cols::<__m256i>(block, inverse, cos_bit, stage_range);
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "sse4.1")]
+unsafe fn dct8_cols_sse41(block: &mut Array2D<i32>, inverse: bool, cos_bit: u32, stage_range: &[u32]) {
+  dct8_cols::<__m128i>(block, inverse, cos_bit, stage_range);
+}
+
+#[cfg(target_arch = "aarch64")]
+#[target_feature(enable = "neon")]
+unsafe fn dct8_cols_neon(block: &mut Array2D<i32>, inverse: bool, cos_bit: u32, stage_range: &[u32]) {
+  dct8_cols::<int32x4_t>(block, inverse, cos_bit, stage_range);
+}
+
+// Largest number of lanes in any of the vector types used above. Blocks passed to
+// dct8_cols_simd() must have a multiple of this many columns
+const DCT8_COLS_ALIGN: usize = 8;
+
+// Runtime dispatch for the above
+// There must be a SIMD implementation for the current CPU (see Dct<8>::has_simd())
+fn dct8_cols_simd(block: &mut Array2D<i32>, inverse: bool, cos_bit: u32, stage_range: &[u32]) {
+  assert!(block.rows() == 8 && block.cols() % DCT8_COLS_ALIGN == 0);
+  assert!(stage_range.len() >= 6);
+
+  // Safety: simd_level() only returns a given level if the CPU supports it
+  match simd_level() {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    SimdLevel::Avx2 => unsafe { dct8_cols_avx2(block, inverse, cos_bit, stage_range) },
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    SimdLevel::Sse41 => unsafe { dct8_cols_sse41(block, inverse, cos_bit, stage_range) },
+    #[cfg(target_arch = "aarch64")]
+    SimdLevel::Neon => unsafe { dct8_cols_neon(block, inverse, cos_bit, stage_range) },
+    _ => unreachable!(),
+  }
+}
+
+
+// A 1D transform, used for the rows or columns of a 2D transform
+// Each kind and length of transform is its own type, so that the 2D transform code is
+// compiled separately for each combination, with the 1D kernels inlined into it.
+trait Txfm1D {
+  // Number of points
+  const N: usize;
+  // Index into the per-length tables in consts.rs
+  const TABLE_IDX: usize;
+
+  // In-place forward and inverse transforms of N values
+  fn fwd(arr: &mut [i32], cos_bit: u32, stage_range: &[u32]);
+  fn inv(arr: &mut [i32], cos_bit: u32, stage_range: &[u32]);
+
+  // Whether cols_simd() can transform a block with this many columns on the current CPU
+  fn has_simd(_cols: usize) -> bool {
+    false
+  }
+
+  // Transform every column of a block at once, using SIMD. Must give exactly the same
+  // results as fwd() or inv() on each column. Only called if has_simd() returned true
+  fn cols_simd(_block: &mut Array2D<i32>, _inverse: bool, _cos_bit: u32, _stage_range: &[u32]) {
+    unreachable!();
+  }
+}
+
+// N-point DCT
+struct Dct<const N: usize>;
+
+impl Txfm1D for Dct<4> {
+  const N: usize = 4;
+  const TABLE_IDX: usize = 0;
+
+  fn fwd(arr: &mut [i32], cos_bit: u32, stage_range: &[u32]) {
+    fwd_dct4(arr, cos_bit, stage_range);
+  }
+
+  fn inv(arr: &mut [i32], cos_bit: u32, stage_range: &[u32]) {
+    inv_dct4(arr, cos_bit, stage_range);
+  }
+}
+
+impl Txfm1D for Dct<8> {
+  const N: usize = 8;
+  const TABLE_IDX: usize = 1;
+
+  fn fwd(arr: &mut [i32], cos_bit: u32, stage_range: &[u32]) {
+    fwd_dct8(arr, cos_bit, stage_range);
+  }
+
+  fn inv(arr: &mut [i32], cos_bit: u32, stage_range: &[u32]) {
+    inv_dct8(arr, cos_bit, stage_range);
+  }
+
+  fn has_simd(cols: usize) -> bool {
+    simd_level() != SimdLevel::Scalar && cols % DCT8_COLS_ALIGN == 0
+  }
+
+  fn cols_simd(block: &mut Array2D<i32>, inverse: bool, cos_bit: u32, stage_range: &[u32]) {
+    dct8_cols_simd(block, inverse, cos_bit, stage_range);
+  }
+}
+
+impl Txfm1D for Dct<16> {
+  const N: usize = 16;
+  const TABLE_IDX: usize = 2;
+
+  fn fwd(arr: &mut [i32], cos_bit: u32, stage_range: &[u32]) {
+    fwd_dct16(arr, cos_bit, stage_range);
+  }
+
+  fn inv(arr: &mut [i32], cos_bit: u32, stage_range: &[u32]) {
+    inv_dct16(arr, cos_bit, stage_range);
+  }
+}
+
+// N-point ADST
+struct Adst<const N: usize>;
+
+// The ADST shares the DCT's shifts, so uses the same table entries
+impl Txfm1D for Adst<4> {
+  const N: usize = 4;
+  const TABLE_IDX: usize = 0;
+
+  fn fwd(arr: &mut [i32], cos_bit: u32, stage_range: &[u32]) {
+    fwd_adst4(arr, cos_bit, stage_range);
+  }
+
+  fn inv(arr: &mut [i32], cos_bit: u32, stage_range: &[u32]) {
+    inv_adst4(arr, cos_bit, stage_range);
+  }
+}
+
+// N-point identity transform
+struct Identity<const N: usize>;
+
+// The identity transform uses the same shifts as the DCT of the same length
+impl Txfm1D for Identity<8> {
+  const N: usize = 8;
+  const TABLE_IDX: usize = 1;
+
+  fn fwd(arr: &mut [i32], cos_bit: u32, stage_range: &[u32]) {
+    identity8(arr, cos_bit, stage_range);
+  }
+
+  fn inv(arr: &mut [i32], cos_bit: u32, stage_range: &[u32]) {
+    identity8(arr, cos_bit, stage_range);
+  }
+}
+
+fn txfm_1d<T: Txfm1D>(arr: &mut [i32], inverse: bool, cos_bit: u32, stage_range: &[u32]) {
+  if inverse {
+    T::inv(arr, cos_bit, stage_range);
   } else {
-    todo!();
+    T::fwd(arr, cos_bit, stage_range);
   }
+}
 
-  let cos_bit_col = 13; // For both 4x4 and 8x8 forward transforms, less for some other sizes
-  let cos_bit_row = 13; // For both 4x4 and 8x8 forward transforms, less for some other sizes
+// Apply a 1D transform to every column of a block, using SIMD if `simd` is set and
+// there's a SIMD version for the current CPU
+fn txfm_cols<T: Txfm1D>(block: &mut Array2D<i32>, inverse: bool, cos_bit: u32, stage_range: &[u32], simd: bool) {
+  assert!(block.rows() == T::N);
+  if simd && T::has_simd(block.cols()) {
+    T::cols_simd(block, inverse, cos_bit, stage_range);
+    return;
+  }
+  // Transpose so that each column is contiguous in memory
+  let mut transposed = block.transpose();
+  for j in 0..block.cols() {
+    txfm_1d::<T>(&mut transposed[j], inverse, cos_bit, stage_range);
+  }
+  transposed.transpose_into(block);
+}
+
+// Apply a 1D transform to every row of a block, as txfm_cols()
+fn txfm_rows<T: Txfm1D>(block: &mut Array2D<i32>, inverse: bool, cos_bit: u32, stage_range: &[u32], simd: bool) {
+  assert!(block.cols() == T::N);
+  if simd && T::has_simd(block.rows()) {
+    // The SIMD versions work on columns, so transpose first
+    let mut transposed = block.transpose();
+    T::cols_simd(&mut transposed, inverse, cos_bit, stage_range);
+    transposed.transpose_into(block);
+    return;
+  }
+  for i in 0..block.rows() {
+    txfm_1d::<T>(&mut block[i], inverse, cos_bit, stage_range);
+  }
+}
+
+// Index into the per-size tables in consts.rs
+fn txfm_2d_idx(txh: usize, txw: usize) -> usize {
+  match (txw, txh) {
+    (4, 4) => 0,
+    (8, 8) => 1,
+    (4, 8) => 2,
+    (8, 4) => 3,
+    (8, 16) => 4,
+    (16, 8) => 5,
+    _ => panic!("Unsupported transform size {}x{}", txw, txh),
+  }
+}
+
+// Whether a transform needs the extra sqrt(2) scaling for 2:1 rectangles
+// (4:1 rectangles are handled by the shifts alone)
+fn is_rect_2to1(txh: usize, txw: usize) -> bool {
+  txh == 2 * txw || txw == 2 * txh
+}
+
+// Multiply by a fixed-point factor with NewSqrt2Bits fractional bits, with rounding
+fn scale_array(arr: &mut [i32], factor: i32) {
+  for i in 0 .. arr.len() {
+    arr[i] = round2((arr[i] as i64) * (factor as i64), NewSqrt2Bits) as i32;
+  }
+}
+
+// Perform a 2D forward transform composed of two 1D transforms
+// R = row transform (applied second)
+// C = col transform (applied first)
+// The shifts between the two are elementwise, so are applied to the whole block at once
+fn fwd_txfm2d_impl<C: Txfm1D, R: Txfm1D>(residual: &mut Array2D<i32>, simd: bool) {
+  let (txh, txw) = (C::N, R::N);
+  let shift = &av1_txfm_fwd_shift[txfm_2d_idx(txh, txw)];
+
+  let cos_bit_col = 13; // For all forward transforms up to 16x16, less for some larger sizes
+  let cos_bit_row = 13; // For all forward transforms up to 8x16 / 16x8, less for some larger sizes
 
   let bd = 8;
-  let stages = av1_txfm_stages[txsz_idx];
-  let shift = &av1_txfm_fwd_shift[txsz_idx];
-  let stage_ranges = &av1_txfm_fwd_range_mult2[txsz_idx];
+  let stages_col = av1_txfm_stages[C::TABLE_IDX];
+  let stages_row = av1_txfm_stages[R::TABLE_IDX];
+  let stage_ranges_col = &av1_txfm_fwd_range_mult2[C::TABLE_IDX];
+  let stage_ranges_row = &av1_txfm_fwd_range_mult2[R::TABLE_IDX];
 
-  let mut stage_range_col = vec![0u32; stages];
-  let mut stage_range_row = vec![0u32; stages];
+  // Fixed-size arrays, as this runs for every block and shouldn't allocate
+  let mut stage_range_col = [0u32; MAX_TXFM_STAGES];
+  let mut stage_range_row = [0u32; MAX_TXFM_STAGES];
 
-  for i in 0..stages {
-    stage_range_col[i] = (round2(stage_ranges[i], 1) + shift[0] + bd + 1) as u32;
+  for i in 0..stages_col {
+    stage_range_col[i] = (round2(stage_ranges_col[i], 1) + shift[0] + bd + 1) as u32;
   }
-  for i in 0..stages {
-    stage_range_row[i] = (round2(stage_ranges[stages - 1] + stage_ranges[i], 1) + shift[0] + shift[1] + bd + 1) as u32;
+  for i in 0..stages_row {
+    stage_range_row[i] = (round2(stage_ranges_col[stages_col - 1] + stage_ranges_row[i], 1) + shift[0] + shift[1] + bd + 1) as u32;
   }
 
   // Column transforms
-  let mut transposed = residual.transpose();
-  for j in 0..txw {
-    let col = &mut transposed[j];
-    round_shift_array(col, -shift[0]);
-    fwd_txfm(col, cos_bit_col, &stage_range_col);
-    round_shift_array(col, -shift[1]);
-  }
+  round_shift_array(residual.rows_mut(0, txh), -shift[0]);
+  txfm_cols::<C>(residual, false, cos_bit_col, &stage_range_col[..stages_col], simd);
+  round_shift_array(residual.rows_mut(0, txh), -shift[1]);
 
   // Row transforms
-  transposed.transpose_into(residual);
-  for i in 0..txh {
-    let row = &mut residual[i];
-    fwd_txfm(row, cos_bit_row, &stage_range_row);
-    round_shift_array(row, -shift[2]);
+  txfm_rows::<R>(residual, false, cos_bit_row, &stage_range_row[..stages_row], simd);
+  round_shift_array(residual.rows_mut(0, txh), -shift[2]);
+  if is_rect_2to1(txh, txw) {
+    scale_array(residual.rows_mut(0, txh), NewSqrt2);
   }
 }
 
-// Perform a 2D forward transform composed of two 1D transforms
+// Perform a 2D inverse transform composed of two 1D transforms
 // R = row transform (applied first)
 // C = col transform (applied second)
-pub fn inv_txfm2d(residual: &mut Array2D<i32>, txh: usize, txw: usize) {
-  assert!(residual.rows() == txh);
-  assert!(residual.cols() == txw);
-
-  let txsz_idx;
-  let inv_txfm: &dyn Fn(&mut [i32], u32, &[u32]);
-  if txh == 8 && txw == 8 {
-    txsz_idx = 1;
-    inv_txfm = &inv_dct8;
-  } else if txh == 4 && txw == 4 {
-    txsz_idx = 0;
-    inv_txfm = &inv_dct4;
-  } else {
-    todo!();
-  }
+fn inv_txfm2d_impl<C: Txfm1D, R: Txfm1D>(residual: &mut Array2D<i32>, simd: bool) {
+  let (txh, txw) = (C::N, R::N);
+  let shift = &av1_txfm_inv_shift[txfm_2d_idx(txh, txw)];
 
   let cos_bit_col = 12; // For all inverse transform sizes
   let cos_bit_row = 12; // For all inverse transform sizes
@@ -338,37 +1030,67 @@ pub fn inv_txfm2d(residual: &mut Array2D<i32>, txh: usize, txw: usize) {
   let bd = 8;
   let opt_range_row = 16;
   let opt_range_col = 16;
-  let stages = av1_txfm_stages[txsz_idx];
-  let shift = &av1_txfm_inv_shift[txsz_idx];
-  // TODO: I think this is just all zeros?
-  //let stage_ranges = &av1_txfm_inv_range_mult2[txsz_idx];
+  let stages = max(av1_txfm_stages[C::TABLE_IDX], av1_txfm_stages[R::TABLE_IDX]);
 
-  let mut stage_range_row = vec![0u32; stages];
-  let mut stage_range_col = vec![0u32; stages];
-
-  for i in 0..stages {
-    stage_range_row[i] = (/*stage_ranges[i] + */ av1_txfm_inv_start_range[txsz_idx] + (bd as i32) + 1) as u32;
-  }
-  for i in 0..stages {
-    stage_range_col[i] = (/*stage_ranges[i] + */ av1_txfm_inv_start_range[txsz_idx] + shift[0] + (bd as i32) + 1) as u32;
-  }
+  // As in libaom, intermediate values are clamped to a fixed range, rather than one
+  // derived from the maximum range of each stage. Ranges derived from the stage sizes
+  // are too tight for 16-point transforms, where they would clip valid values
+  let stage_range_row = [opt_range_row; MAX_TXFM_STAGES];
+  let stage_range_col = [opt_range_col; MAX_TXFM_STAGES];
 
   // Row transforms
-  for i in 0..txh {
-    let row = &mut residual[i];
-    clamp_array(row, bd + 8);
-    inv_txfm(row, cos_bit_col, &stage_range_col);
-    round_shift_array(row, -shift[0]);
+  if is_rect_2to1(txh, txw) {
+    scale_array(residual.rows_mut(0, txh), NewInvSqrt2);
   }
+  clamp_array(residual.rows_mut(0, txh), bd + 8);
+  txfm_rows::<R>(residual, true, cos_bit_row, &stage_range_row[..stages], simd);
+  round_shift_array(residual.rows_mut(0, txh), -shift[0]);
 
   // Column transforms
-  let mut transposed = residual.transpose();
-  for j in 0..txw {
-    let col = &mut transposed[j];
-    clamp_array(col, max(bd + 6, 16));
-    inv_txfm(col, cos_bit_row, &stage_range_row);
-    round_shift_array(col, -shift[1]);
-  }
+  clamp_array(residual.rows_mut(0, txh), max(bd + 6, 16));
+  txfm_cols::<C>(residual, true, cos_bit_col, &stage_range_col[..stages], simd);
+  round_shift_array(residual.rows_mut(0, txh), -shift[1]);
+}
+
+// Generate each supported combination of row and column transform
+// Only 4x4 blocks support the ADST, as only chroma (which is always 4x4) uses it, and only
+// 8x8 blocks support the identity transform, as only luma (which is always 8x8) uses it
+// `simd` is only cleared by the self-tests, which check that the SIMD transforms give
+// exactly the same results as the scalar ones
+pub(crate) fn txfm2d(residual: &mut Array2D<i32>, txh: usize, txw: usize, tx_type: TxType, inverse: bool, simd: bool) {
+  assert!(residual.rows() == txh);
+  assert!(residual.cols() == txw);
+
+  let f: fn(&mut Array2D<i32>, bool) = match (txh, txw, tx_type, inverse) {
+    (4, 4, TxType::DCT_DCT, false) => fwd_txfm2d_impl::<Dct<4>, Dct<4>>,
+    (4, 4, TxType::ADST_DCT, false) => fwd_txfm2d_impl::<Adst<4>, Dct<4>>,
+    (4, 4, TxType::DCT_ADST, false) => fwd_txfm2d_impl::<Dct<4>, Adst<4>>,
+    (4, 4, TxType::ADST_ADST, false) => fwd_txfm2d_impl::<Adst<4>, Adst<4>>,
+    (8, 8, TxType::DCT_DCT, false) => fwd_txfm2d_impl::<Dct<8>, Dct<8>>,
+    (8, 8, TxType::IDTX, false) => fwd_txfm2d_impl::<Identity<8>, Identity<8>>,
+    (8, 4, TxType::DCT_DCT, false) => fwd_txfm2d_impl::<Dct<8>, Dct<4>>,
+    (4, 8, TxType::DCT_DCT, false) => fwd_txfm2d_impl::<Dct<4>, Dct<8>>,
+    (16, 8, TxType::DCT_DCT, false) => fwd_txfm2d_impl::<Dct<16>, Dct<8>>,
+    (8, 16, TxType::DCT_DCT, false) => fwd_txfm2d_impl::<Dct<8>, Dct<16>>,
+    (4, 4, TxType::DCT_DCT, true) => inv_txfm2d_impl::<Dct<4>, Dct<4>>,
+    (4, 4, TxType::ADST_DCT, true) => inv_txfm2d_impl::<Adst<4>, Dct<4>>,
+    (4, 4, TxType::DCT_ADST, true) => inv_txfm2d_impl::<Dct<4>, Adst<4>>,
+    (4, 4, TxType::ADST_ADST, true) => inv_txfm2d_impl::<Adst<4>, Adst<4>>,
+    (8, 8, TxType::DCT_DCT, true) => inv_txfm2d_impl::<Dct<8>, Dct<8>>,
+    (8, 8, TxType::IDTX, true) => inv_txfm2d_impl::<Identity<8>, Identity<8>>,
+    (8, 4, TxType::DCT_DCT, true) => inv_txfm2d_impl::<Dct<8>, Dct<4>>,
+    (4, 8, TxType::DCT_DCT, true) => inv_txfm2d_impl::<Dct<4>, Dct<8>>,
+    (16, 8, TxType::DCT_DCT, true) => inv_txfm2d_impl::<Dct<16>, Dct<8>>,
+    (8, 16, TxType::DCT_DCT, true) => inv_txfm2d_impl::<Dct<8>, Dct<16>>,
+    _ => panic!("Unsupported transform {:?} of size {}x{}", tx_type, txw, txh),
+  };
+  f(residual, simd);
+}
+
+pub fn fwd_txfm2d(residual: &mut Array2D<i32>, txh: usize, txw: usize, tx_type: TxType) {
+  txfm2d(residual, txh, txw, tx_type, false, true);
+}
 
-  transposed.transpose_into(residual);
+pub fn inv_txfm2d(residual: &mut Array2D<i32>, txh: usize, txw: usize, tx_type: TxType) {
+  txfm2d(residual, txh, txw, tx_type, true, true);
 }
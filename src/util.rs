@@ -22,18 +22,30 @@ pub fn write_be_bytes<W: Write>(w: &mut W, value: u64, nbytes: usize) {
   }
 }
 
-// Write a value in AV1's LEB128 format
+// Write a value in AV1's LEB128 format, using as few bytes as possible
 // In this format, each byte provides 7 bits of the value,
 // along with a flag bit which indicates whether there are more bytes to read
 // Also, in contrast to everything else here, this value is little-endian
-pub fn write_leb128<W: Write>(w: &mut W, mut value: usize) {
-  if value == 0 {
-    w.write_u8(0).unwrap();
-    return;
-  }
+//
+// Any u64 can be written, taking up to 10 bytes. Note that AV1 itself limits these
+// values to 32 bits, and decoders read at most 8 bytes; see write_obu_size() in hls.rs
+pub fn write_leb128<W: Write>(w: &mut W, value: u64) {
+  write_leb128_fixed(w, value, leb128_size(value));
+}
 
-  while value != 0 {
-    let more_flag = if (value >> 7) > 0 { 0x80 } else { 0x00 };
+// Number of bytes write_leb128() uses for a value
+pub fn leb128_size(value: u64) -> usize {
+  let bits = 64 - value.leading_zeros() as usize;
+  return max(bits.div_ceil(7), 1);
+}
+
+// Write a value in LEB128 format using exactly `nbytes` bytes, padding with
+// 0x80 bytes if needed. This allows a size field to be reserved before the size is
+// known, then filled in later by writing to the reserved part of the buffer.
+pub fn write_leb128_fixed<W: Write>(w: &mut W, mut value: u64, nbytes: usize) {
+  assert!(nbytes >= leb128_size(value), "Value {} doesn't fit in a {}-byte leb128 field", value, nbytes);
+  for i in 0..nbytes {
+    let more_flag = if i + 1 < nbytes { 0x80 } else { 0x00 };
     w.write_u8(more_flag | (value & 0x7F) as u8).unwrap();
     value >>= 7;
   }
@@ -304,3 +316,39 @@ pub fn get_prob(symbol: usize, cdf: &[u16]) -> u16 {
     cdf[symbol] - cdf[symbol - 1]
   }
 }
+
+// Versions of sin() and exp() which give the same results on every platform
+// The standard library's versions call into the platform's maths library, which may round
+// differently. These only use basic arithmetic, which IEEE 754 defines exactly, and work in
+// f64 so that the result is accurate to within rounding once converted back to f32.
+pub fn portable_sin(x: f32) -> f32 {
+  // Reduce to [-pi, pi], then sum the Taylor series, which converges quickly over that range
+  let two_pi = 2.0 * std::f64::consts::PI;
+  let x = x as f64;
+  let r = x - (x / two_pi).round() * two_pi;
+  let r2 = r * r;
+  let mut term = r;
+  let mut sum = r;
+  for n in 1..12 {
+    term *= -r2 / ((2 * n) * (2 * n + 1)) as f64;
+    sum += term;
+  }
+  return sum as f32;
+}
+
+pub fn portable_exp(x: f32) -> f32 {
+  // Split x into k * ln(2) + r, with |r| <= ln(2) / 2, so that exp(x) = 2^k * exp(r)
+  // Outside this range, the result rounds to 0 or infinity once converted to f32 anyway
+  let x = (x as f64).clamp(-110.0, 110.0);
+  let k = (x / std::f64::consts::LN_2).round();
+  let r = x - k * std::f64::consts::LN_2;
+  let mut term = 1.0;
+  let mut sum = 1.0;
+  for n in 1..16 {
+    term *= r / n as f64;
+    sum += term;
+  }
+  // Build 2^k directly from its bit pattern
+  let scale = f64::from_bits(((k as i64 + 1023) as u64) << 52);
+  return (sum * scale) as f32;
+}
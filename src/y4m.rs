@@ -7,20 +7,170 @@
 // Media Patent License 1.0 was not distributed with this source code in the
 // PATENTS file, you can obtain it at www.aomedia.org/license/patent.
 
+use std::fmt;
 use std::io;
 use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::str::FromStr;
 
 use byteorder::{ReadBytesExt, WriteBytesExt};
 
-use crate::frame::Frame;
+use crate::array2d::Array2D;
+use crate::chromaconv::ChromaConversion;
+use crate::config::{ChromaSubsampling, EncoderConfig};
+use crate::frame::{Frame, RowSource};
+use crate::scaling::bob_deinterlace;
 
 const Y4M_FILE_MAGIC: &str = "YUV4MPEG2 ";
 const Y4M_FRAME_MAGIC: &str = "FRAME";
 
+// Maximum length of a FRAME line that we'll accept
+// Real-world frame headers are almost always just "FRAME\n"; this limit exists so that
+// if we get out of sync with the input (eg. due to a truncated frame), we produce
+// an error promptly instead of scanning through the rest of the file looking for a newline
+const MAX_FRAME_HEADER_LEN: usize = 256;
+
+// Largest width or height we accept, which is also the largest that AV1 can represent
+// Rejecting larger values here means malformed headers can't make us allocate huge frames
+const MAX_DIMENSION: usize = 65536;
+
+// Parameters from the stream header line
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Y4MHeader {
+  pub width: usize,
+  pub height: usize,
+
+  // Raw values of other stream header parameters, if present
+  // These are used to validate any parameters repeated in the FRAME lines
+  pub interlace: Option<String>,
+  pub colorspace: Option<String>,
+  pub frame_rate: Option<String>,
+  pub aspect_ratio: Option<String>,
+
+  // Colour range from the XCOLORRANGE extension written by ffmpeg: "FULL" or "LIMITED"
+  pub color_range: Option<String>,
+}
+
+// Field order of a frame, from the interlacing parameter
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FieldOrder {
+  Progressive,
+  TopFirst,
+  BottomFirst,
+}
+
+impl FieldOrder {
+  pub fn name(self) -> &'static str {
+    match self {
+      FieldOrder::Progressive => "progressive",
+      FieldOrder::TopFirst => "top field first",
+      FieldOrder::BottomFirst => "bottom field first",
+    }
+  }
+}
+
+// What to do with interlaced frames. Encoding the two fields as one image, as if they
+// were progressive, gives combing artifacts wherever anything moved between the fields
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Deinterlace {
+  // Fail with Y4MError::Interlaced
+  Reject,
+  // Encode the frame as it is, with the fields woven together. This is fine when nothing
+  // moved between the fields, eg. for film transferred to interlaced video
+  Weave,
+  // Keep the first field, and interpolate the other field's lines from it (see
+  // scaling::bob_deinterlace()). This halves the vertical resolution, but has no combing
+  Bob,
+}
+
+impl FromStr for Deinterlace {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "reject" => Ok(Deinterlace::Reject),
+      "weave" => Ok(Deinterlace::Weave),
+      "bob" => Ok(Deinterlace::Bob),
+      _ => Err(format!("Unknown deinterlacing mode \"{}\", must be \"reject\", \"weave\" or \"bob\"", s)),
+    }
+  }
+}
+
+impl Y4MHeader {
+  // Field order of every frame, or None for mixed-mode streams, where each FRAME line
+  // gives its own. Unknown ordering (I?) is treated as progressive, as most files which
+  // use it are.
+  pub fn field_order(&self) -> Result<Option<FieldOrder>, io::Error> {
+    match self.interlace.as_deref() {
+      None | Some("p") | Some("?") => Ok(Some(FieldOrder::Progressive)),
+      Some("t") => Ok(Some(FieldOrder::TopFirst)),
+      Some("b") => Ok(Some(FieldOrder::BottomFirst)),
+      Some("m") => Ok(None),
+      Some(interlace) => Err(invalid_header(format!("Unknown Y4M interlacing mode I{}", interlace))),
+    }
+  }
+
+  // Chroma layout of the frames, from the colourspace parameter. The 4:2:0 variants only
+  // differ in where the chroma samples sit, so are read the same way. Anything else
+  // (eg. high bit depths, or alpha) is rejected, rather than misread.
+  pub fn subsampling(&self) -> Result<ChromaSubsampling, io::Error> {
+    match self.colorspace.as_deref() {
+      None | Some("420") | Some("420jpeg") | Some("420mpeg2") | Some("420paldv") => Ok(ChromaSubsampling::Yuv420),
+      Some("422") => Ok(ChromaSubsampling::Yuv422),
+      Some("444") => Ok(ChromaSubsampling::Yuv444),
+      Some("mono") => Ok(ChromaSubsampling::Monochrome),
+      Some(colorspace) => Err(io::Error::new(io::ErrorKind::Unsupported,
+                                             format!("Unsupported Y4M colourspace C{}", colorspace))),
+    }
+  }
+}
+
 pub struct Y4MReader<R> {
   inner: R,
-  width: usize,
-  height: usize
+  header: Y4MHeader,
+  // How 4:2:2 and 4:4:4 frames are converted to 4:2:0
+  chroma_conversion: ChromaConversion,
+  deinterlace: Deinterlace,
+  // Field order of the frame being read
+  field_order: FieldOrder,
+
+  // Number of frames read so far, for error reporting
+  frame_count: usize,
+}
+
+#[derive(Debug)]
+pub enum Y4MError {
+  Io(io::Error),
+  // The FRAME line for the given frame (counting from 0) was malformed,
+  // or had parameters which are inconsistent with the stream header
+  BadFrameHeader { frame: usize, reason: String },
+  // The given frame is interlaced, and the reader was set to reject interlaced frames
+  Interlaced { frame: usize, field_order: FieldOrder },
+}
+
+impl fmt::Display for Y4MError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Y4MError::Io(e) => write!(f, "{}", e),
+      Y4MError::BadFrameHeader { frame, reason } => write!(f, "Invalid Y4M header for frame {}: {}", frame, reason),
+      Y4MError::Interlaced { frame, field_order } => {
+        write!(f, "Frame {} is interlaced ({}), so needs deinterlacing before it can be encoded", frame, field_order.name())
+      },
+    }
+  }
+}
+
+impl std::error::Error for Y4MError {}
+
+// Error for a malformed Y4M file header
+fn invalid_header(reason: String) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+impl From<io::Error> for Y4MError {
+  fn from(e: io::Error) -> Self {
+    Y4MError::Io(e)
+  }
 }
 
 pub struct Y4MWriter<W> {
@@ -30,12 +180,14 @@ pub struct Y4MWriter<W> {
 }
 
 fn read_decimal<R: Read>(r: &mut R) -> Result<(usize, u8), io::Error> {
-  let mut v = 0;
+  let mut v: usize = 0;
   loop {
     let byte = r.read_u8()?;
     match byte {
       b'0' ..= b'9' => {
-        v = 10*v + (byte - b'0') as usize;
+        v = v.checked_mul(10).and_then(|v| v.checked_add((byte - b'0') as usize)).ok_or_else(|| {
+          invalid_header("Number too large in Y4M header".into())
+        })?;
       },
       _ => {
         // Non-digit, stop parsing
@@ -47,7 +199,7 @@ fn read_decimal<R: Read>(r: &mut R) -> Result<(usize, u8), io::Error> {
 }
 
 // Read next character, expecting it to be whitespace
-// Returns the character if it's whitespace, panics if not
+// Returns the character if it's whitespace, an error if not
 fn expect_whitespace<R: Read>(r: &mut R) -> Result<u8, io::Error> {
   let byte = r.read_u8()?;
   match byte {
@@ -55,110 +207,340 @@ fn expect_whitespace<R: Read>(r: &mut R) -> Result<u8, io::Error> {
       return Ok(byte);
     },
     _ => {
-      panic!("Unexpected byte {} in Y4M file", byte);
+      return Err(invalid_header(format!("Unexpected byte {} in Y4M file", byte)));
     }
   }
 }
 
-// Skip forward until we find a whitespace character
-// Returns the first whitespace character found
-fn find_whitespace<R: Read>(r: &mut R) -> Result<u8, io::Error> {
+// Read forward until we find a whitespace character
+// Returns the characters read, and the first whitespace character found
+fn read_token<R: Read>(r: &mut R) -> Result<(String, u8), io::Error> {
+  let mut token = Vec::new();
   loop {
     let byte = r.read_u8()?;
     match byte {
       b' ' | b'\t' | b'\n' => {
-        return Ok(byte);
+        return Ok((String::from_utf8_lossy(&token).into_owned(), byte));
+      },
+      b'\r' => {
+        continue;
       },
       _ => {
+        token.push(byte);
+      }
+    }
+  }
+}
+
+// Check whether a per-frame interlacing tag is well-formed
+// This takes the form I<presentation><temporal><spatial>, as defined by mjpegtools,
+// where presentation is one of t/T/b/B/1/2/3, temporal is p or i, and
+// spatial is p, i, or ?
+fn is_valid_frame_interlace(value: &str) -> bool {
+  let bytes = value.as_bytes();
+  bytes.len() == 3 &&
+    b"tTbB123".contains(&bytes[0]) &&
+    b"pi".contains(&bytes[1]) &&
+    b"pi?".contains(&bytes[2])
+}
+
+// Read and parse the stream header line, leaving `r` positioned at the first FRAME line
+fn read_header<R: Read>(inner: &mut R) -> Result<Y4MHeader, io::Error> {
+  let mut file_magic = [0u8; 10];
+  inner.read_exact(&mut file_magic)?;
+  if file_magic != Y4M_FILE_MAGIC.as_bytes() {
+    return Err(invalid_header("Invalid file header".into()));
+  }
+
+  let mut header = Y4MHeader::default();
+
+  // Parse parameter line
+  loop {
+    match inner.read_u8()? {
+      b'\n' => {
+        // End of parameter line
+        break;
+      },
+      b' ' | b'\t' => {
+        // Skip whitespace
         continue;
+      },
+      b'W' => {
+        let byte;
+        (header.width, byte) = read_decimal(inner)?;
+        match byte {
+          b'\n' => { break; },
+          b' ' | b'\t' | b'\r' => { continue; }
+          _ => { return Err(invalid_header(format!("Unexpected byte {} in Y4M file", byte))); }
+        }
+      },
+      b'H' => {
+        let byte;
+        (header.height, byte) = read_decimal(inner)?;
+        match byte {
+          b'\n' => { break; },
+          b' ' | b'\t' | b'\r' => { continue; }
+          _ => { return Err(invalid_header(format!("Unexpected byte {} in Y4M file", byte))); }
+        }
+      },
+      tag => {
+        // Other parameters, which we don't need to interpret, but which
+        // we keep the raw values of so that we can validate the FRAME lines
+        let (value, byte) = read_token(inner)?;
+        match tag {
+          b'I' => { header.interlace = Some(value); },
+          b'C' => { header.colorspace = Some(value); },
+          b'F' => { header.frame_rate = Some(value); },
+          b'A' => { header.aspect_ratio = Some(value); },
+          b'X' => {
+            if let Some(range) = value.strip_prefix("COLORRANGE=") {
+              header.color_range = Some(range.to_string());
+            }
+          },
+          _ => {}
+        }
+        if byte == b'\n' {
+          break;
+        }
       }
     }
   }
+
+  if header.width == 0 || header.height == 0 || header.width > MAX_DIMENSION || header.height > MAX_DIMENSION {
+    // Didn't find a width/height parameter, or it was out of range
+    return Err(invalid_header(format!("Invalid Y4M size {}x{}", header.width, header.height)));
+  }
+  header.subsampling()?;
+  header.field_order()?;
+
+  Ok(header)
+}
+
+impl Y4MReader<()> {
+  // Parse a stream header from memory, without reading any frames
+  // This never panics, whatever the input, so is suitable for fuzzing.
+  pub fn parse_header(data: &[u8]) -> Result<Y4MHeader, io::Error> {
+    let mut cursor = data;
+    read_header(&mut cursor)
+  }
 }
 
 impl<R: Read> Y4MReader<R> {
   pub fn new(mut inner: R) -> Result<Self, io::Error> {
-    // Read header line
-    let mut file_magic = [0u8; 10];
-    inner.read_exact(&mut file_magic)?;
-    if file_magic != Y4M_FILE_MAGIC.as_bytes() {
-      panic!("Invalid file header");
+    let header = read_header(&mut inner)?;
+    Ok(Y4MReader {
+      inner: inner,
+      header: header,
+      chroma_conversion: ChromaConversion::from_config(&EncoderConfig::default()),
+      deinterlace: Deinterlace::Reject,
+      field_order: FieldOrder::Progressive,
+      frame_count: 0,
+    })
+  }
+
+  pub fn header(&self) -> &Y4MHeader {
+    &self.header
+  }
+
+  // Set how 4:2:2 and 4:4:4 input is converted to 4:2:0, which should match the chroma
+  // sample position that the image will be encoded with
+  pub fn set_chroma_conversion(&mut self, conversion: ChromaConversion) {
+    self.chroma_conversion = conversion;
+  }
+
+  // Set what to do with interlaced frames. By default they're rejected
+  pub fn set_deinterlace(&mut self, deinterlace: Deinterlace) {
+    self.deinterlace = deinterlace;
+  }
+
+  // Check the field order of the frame whose header has just been read, returning
+  // whether it needs to be bob deinterlaced
+  fn check_interlacing(&self) -> Result<bool, Y4MError> {
+    if self.field_order == FieldOrder::Progressive {
+      return Ok(false);
+    }
+    match self.deinterlace {
+      Deinterlace::Reject => Err(Y4MError::Interlaced { frame: self.frame_count, field_order: self.field_order }),
+      Deinterlace::Weave => Ok(false),
+      Deinterlace::Bob => Ok(true),
     }
+  }
 
-    let mut width = 0;
-    let mut height = 0;
+  // Read and validate a FRAME line
+  // Frame lines can carry parameters, though in practice only the per-frame
+  // interlacing tag (I) and comments (X) are meaningful. Anything else must match
+  // the stream header, as we don't support changing the frame format mid-stream.
+  fn read_frame_header(&mut self) -> Result<(), Y4MError> {
+    let frame = self.frame_count;
+    let bad_header = |reason: String| Y4MError::BadFrameHeader { frame: frame, reason: reason };
 
-    // Parse parameter line
+    let mut line = Vec::new();
     loop {
-      match inner.read_u8()? {
-        b'\n' => {
-          // End of parameter line
-          break;
-        },
-        b' ' | b'\t' => {
-          // Skip whitespace
-          continue;
+      let byte = self.inner.read_u8()?;
+      if byte == b'\n' {
+        break;
+      }
+      if line.len() >= MAX_FRAME_HEADER_LEN {
+        return Err(bad_header(format!("FRAME line is longer than {} bytes", MAX_FRAME_HEADER_LEN)));
+      }
+      line.push(byte);
+    }
+
+    let line = String::from_utf8_lossy(&line);
+    let mut tokens = line.split([' ', '\t', '\r']).filter(|token| !token.is_empty());
+    // Frames in mixed-mode streams without an interlacing tag are taken to be progressive
+    self.field_order = self.header.field_order()?.unwrap_or(FieldOrder::Progressive);
+
+    if tokens.next() != Some(Y4M_FRAME_MAGIC) {
+      return Err(bad_header("Missing FRAME marker".into()));
+    }
+
+    for token in tokens {
+      // The tag is a single ASCII character; anything else is an unknown tag
+      let tag_len = token.chars().next().map_or(0, |c| c.len_utf8());
+      let (tag, value) = token.split_at(tag_len);
+      match tag {
+        "I" => {
+          if !is_valid_frame_interlace(value) {
+            return Err(bad_header(format!("Malformed interlacing tag {}", token)));
+          }
+          // Per-frame interlacing is only allowed for mixed-mode streams
+          if self.header.interlace.as_deref() != Some("m") {
+            return Err(bad_header(format!("Frame sets interlacing tag {}, but the stream header does not declare mixed interlacing (Im)", token)));
+          }
+          // The first character gives the field order; 1, 2 and 3 are progressive frames,
+          // shown for that many frame periods
+          self.field_order = match value.as_bytes()[0] {
+            b't' | b'T' => FieldOrder::TopFirst,
+            b'b' | b'B' => FieldOrder::BottomFirst,
+            _ => FieldOrder::Progressive,
+          };
         },
-        b'W' => {
-          let byte;
-          (width, byte) = read_decimal(&mut inner)?;
-          match byte {
-            b'\n' => { break; },
-            b' ' | b'\t' | b'\r' => { continue; }
-            _ => { panic!("Unexpected byte {} in Y4M file", byte); }
+        "W" | "H" => {
+          let expected = if tag == "W" { self.header.width } else { self.header.height };
+          if value.parse::<usize>().ok() != Some(expected) {
+            return Err(bad_header(format!("Frame parameter {} does not match stream {}{}", token, tag, expected)));
           }
         },
-        b'H' => {
-          let byte;
-          (height, byte) = read_decimal(&mut inner)?;
-          match byte {
-            b'\n' => { break; },
-            b' ' | b'\t' | b'\r' => { continue; }
-            _ => { panic!("Unexpected byte {} in Y4M file", byte); }
+        "C" | "F" | "A" => {
+          let expected = match tag {
+            "C" => &self.header.colorspace,
+            "F" => &self.header.frame_rate,
+            _ => &self.header.aspect_ratio,
+          };
+          if expected.as_deref() != Some(value) {
+            return Err(bad_header(format!("Frame parameter {} does not match the stream header", token)));
           }
         },
         _ => {
-          // Other parameters that we aren't parsing yet
-          // Just skip until we find whitespace
-          if find_whitespace(&mut inner)? == b'\n' {
-            break;
-          }
+          // Comments (X) and unknown tags are ignored
         }
       }
     }
 
-    if width == 0 || height == 0 {
-      // Didn't find a width/height parameter, or it was zero
-      panic!("Invalid Y4M size {}x{}", width, height);
+    Ok(())
+  }
+
+  pub fn read_frame(&mut self) -> Result<Box<Frame>, Y4MError> {
+    self.read_frame_header()?;
+    let bob = self.check_interlacing()?;
+
+    // Read actual frame data
+    let mut frame = Frame::new(self.header.height, self.header.width);
+    frame.y_mut().read_from(&mut self.inner)?;
+    match self.header.subsampling()? {
+      ChromaSubsampling::Yuv420 => {
+        frame.u_mut().read_from(&mut self.inner)?;
+        frame.v_mut().read_from(&mut self.inner)?;
+      },
+      ChromaSubsampling::Monochrome => {
+        frame.u_mut().pixels_mut().fill_with(|_, _| 128);
+        frame.v_mut().pixels_mut().fill_with(|_, _| 128);
+      },
+      subsampling => {
+        let subsampled_x = subsampling == ChromaSubsampling::Yuv422;
+        let chroma_width = if subsampled_x { self.header.width.div_ceil(2) } else { self.header.width };
+        let mut chroma = Array2D::zeroed(self.header.height, chroma_width);
+        for plane in 1..3 {
+          self.inner.read_exact(chroma.rows_mut(0, self.header.height))?;
+          self.chroma_conversion.downsample_plane(&chroma, subsampled_x, frame.plane_mut(plane));
+        }
+      },
+    }
+    if bob {
+      bob_deinterlace(&mut frame, self.field_order == FieldOrder::BottomFirst);
     }
 
-    Ok(Y4MReader {
-      inner: inner,
-      width: width,
-      height: height
+    self.frame_count += 1;
+    Ok(Box::new(frame))
+  }
+}
+
+impl<R: Read + Seek> Y4MReader<R> {
+  // Start reading the next frame a few rows at a time, rather than all at once
+  // The planes of each frame are stored one after another, so this needs to seek around
+  // within the file. Once the returned FrameRows is dropped, the reader is positioned
+  // at the start of the following frame.
+  pub fn read_frame_rows(&mut self) -> Result<FrameRows<'_, R>, Y4MError> {
+    // Converting other chroma layouts needs rows either side of each one, so isn't
+    // supported here
+    let subsampling = self.header.subsampling()?;
+    if subsampling != ChromaSubsampling::Yuv420 {
+      return Err(Y4MError::Io(io::Error::new(io::ErrorKind::Unsupported,
+        format!("Only 4:2:0 input can be read a few rows at a time, not {}", subsampling.name()))));
+    }
+    self.read_frame_header()?;
+    if self.check_interlacing()? {
+      return Err(Y4MError::Io(io::Error::new(io::ErrorKind::Unsupported,
+        "Bob deinterlacing can't be done while reading a few rows at a time".to_string())));
+    }
+    let start = self.inner.stream_position()?;
+    self.frame_count += 1;
+    Ok(FrameRows {
+      reader: self,
+      start: start,
     })
   }
+}
+
+// Row-by-row access to a single frame, see Y4MReader::read_frame_rows()
+pub struct FrameRows<'a, R: Read + Seek> {
+  reader: &'a mut Y4MReader<R>,
+  // File offset of the frame's pixel data
+  start: u64,
+}
+
+impl<'a, R: Read + Seek> FrameRows<'a, R> {
+  // Byte offset of each plane within the frame, and the size of the whole frame
+  fn layout(&self) -> ([u64; 3], u64) {
+    let y_size = (self.reader.header.width * self.reader.header.height) as u64;
+    let uv_size = (self.reader.header.width.div_ceil(2) * self.reader.header.height.div_ceil(2)) as u64;
+    return ([0, y_size, y_size + uv_size], y_size + 2 * uv_size);
+  }
+}
 
-  pub fn read_frame(&mut self) -> Result<Box<Frame>, io::Error> {
-    // Read frame line
-    // Technically this can have parameters, but they aren't useful to us.
-    // So just check the magic number to ensure we're in the right place
-    // and skip the rest of the line
-    let mut frame_magic = [0u8; 5];
-    self.inner.read_exact(&mut frame_magic)?;
-    if frame_magic != Y4M_FRAME_MAGIC.as_bytes() {
-      panic!("Invalid frame header");
+impl<'a, R: Read + Seek> RowSource for FrameRows<'a, R> {
+  fn read_rows(&mut self, y0: usize, dst: &mut Frame, dst_y0: usize) -> Result<(), io::Error> {
+    let (offsets, _) = self.layout();
+    for plane in 0..3 {
+      let subsampling = if plane > 0 { 1 } else { 0 };
+      let dst_plane = dst.plane_mut(plane);
+      let width = dst_plane.crop_width() as u64;
+      let dst_row = dst_y0 >> subsampling;
+      let count = dst_plane.crop_height() - dst_row;
+      let pos = self.start + offsets[plane] + width * (y0 >> subsampling) as u64;
+      self.reader.inner.seek(SeekFrom::Start(pos))?;
+      dst_plane.read_rows_from(&mut self.reader.inner, dst_row, count)?;
     }
-  
-    while self.inner.read_u8()? != b'\n' {}
-  
-    // Read actual frame data
-    let mut frame = Frame::new(self.height, self.width);
-    frame.y_mut().read_from(&mut self.inner)?;
-    frame.u_mut().read_from(&mut self.inner)?;
-    frame.v_mut().read_from(&mut self.inner)?;
+    Ok(())
+  }
+}
 
-    Ok(Box::new(frame))
+impl<'a, R: Read + Seek> Drop for FrameRows<'a, R> {
+  fn drop(&mut self) {
+    // Errors are ignored here, as they'll show up again when reading the next frame
+    let (_, frame_size) = self.layout();
+    let _ = self.reader.inner.seek(SeekFrom::Start(self.start + frame_size));
   }
 }
 
@@ -175,8 +557,9 @@ impl<W: Write> Y4MWriter<W> {
   }
 
   pub fn write_frame(&mut self, frame: &Frame) -> Result<(), io::Error> {
-    assert!(frame.y().width() == self.width);
-    assert!(frame.y().height() == self.height);
+    // Only the visible (cropped) region of each plane is written out
+    assert!(frame.y().crop_width() == self.width);
+    assert!(frame.y().crop_height() == self.height);
 
     self.inner.write_all(Y4M_FRAME_MAGIC.as_bytes())?;
     self.inner.write_u8(b'\n')?;
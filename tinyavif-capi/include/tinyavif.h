@@ -0,0 +1,102 @@
+/*
+ * Copyright (c) 2024-2025, The tinyavif contributors. All rights reserved
+ *
+ * This source code is subject to the terms of the BSD 2 Clause License and
+ * the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+ * was not distributed with this source code in the LICENSE file, you can
+ * obtain it at www.aomedia.org/license/software. If the Alliance for Open
+ * Media Patent License 1.0 was not distributed with this source code in the
+ * PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+ */
+
+/* C API for tinyavif. Build with `cargo build --release -p tinyavif-capi`,
+ * then link against libtinyavif_capi. */
+
+#ifndef TINYAVIF_H
+#define TINYAVIF_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef enum TinyavifError {
+  TINYAVIF_OK = 0,
+  TINYAVIF_ERROR_NULL_POINTER = 1,
+  /* A setting in TinyavifConfig is out of range */
+  TINYAVIF_ERROR_INVALID_CONFIG = 2,
+  /* The settings are valid AV1, but tinyavif can't encode them yet */
+  TINYAVIF_ERROR_UNSUPPORTED = 3,
+  /* The image size, stride, or channel count is invalid */
+  TINYAVIF_ERROR_INVALID_INPUT = 4,
+  TINYAVIF_ERROR_INTERNAL = 5,
+  /* The image is too large for AV1, for a single tile, or for the level */
+  TINYAVIF_ERROR_TOO_LARGE = 6,
+} TinyavifError;
+
+typedef enum TinyavifTune {
+  TINYAVIF_TUNE_PSNR = 0,
+  TINYAVIF_TUNE_SCREEN = 1,
+  /* Pick one of the above based on the image (the default) */
+  TINYAVIF_TUNE_AUTO = 2,
+  /* Like TINYAVIF_TUNE_PSNR, but spending more bits on flat areas to improve SSIM */
+  TINYAVIF_TUNE_SSIM = 3,
+} TinyavifTune;
+
+/* Encoder settings. Always initialize with tinyavif_config_default() before
+ * changing individual fields. See src/config.rs for what each one does. */
+typedef struct TinyavifConfig {
+  uint8_t qindex;
+  uint8_t speed;
+  uint8_t passes;
+  uint8_t tune; /* A TinyavifTune value */
+  uint8_t sharpness;
+  int8_t chroma_dc_qoffset;
+  int8_t chroma_ac_qoffset;
+  uint8_t denoise;
+  uint8_t sharpen;
+  uint16_t color_primaries;
+  uint16_t transfer_function;
+  uint16_t matrix_coefficients;
+  uint8_t full_range; /* 0 for limited ("TV") range, 1 for full range */
+} TinyavifConfig;
+
+void tinyavif_config_default(TinyavifConfig *config);
+
+/* Encode an 8-bit 4:2:0 image to an AVIF file. Each chroma plane is
+ * ceil(width/2) x ceil(height/2) pixels. Strides are in bytes.
+ *
+ * On success, *out_data and *out_size are set to the AVIF file, which must be
+ * released with tinyavif_free(). On failure they are set to NULL and 0. */
+TinyavifError tinyavif_encode_yuv420(const uint8_t *y, size_t y_stride,
+                                     const uint8_t *u, size_t u_stride,
+                                     const uint8_t *v, size_t v_stride,
+                                     uint32_t width, uint32_t height,
+                                     const TinyavifConfig *config,
+                                     uint8_t **out_data, size_t *out_size);
+
+/* Encode an 8-bit interleaved RGB (channels = 3) or RGBA (channels = 4) image
+ * to an AVIF file. Alpha is ignored. The image is converted to YUV with the
+ * matrix given by config->matrix_coefficients: 1 (BT.709), 5 or 6 (BT.601),
+ * or 9 (BT.2020). The default of 2 (unspecified) uses BT.601.
+ *
+ * Outputs are as for tinyavif_encode_yuv420(). */
+TinyavifError tinyavif_encode_rgb(const uint8_t *rgb, size_t stride,
+                                  uint32_t width, uint32_t height,
+                                  uint32_t channels,
+                                  const TinyavifConfig *config,
+                                  uint8_t **out_data, size_t *out_size);
+
+void tinyavif_free(uint8_t *data, size_t size);
+
+/* Static, human-readable description of an error code. Values which aren't
+ * TinyavifError codes give "Unknown error" */
+const char *tinyavif_error_string(int error);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* TINYAVIF_H */
@@ -0,0 +1,240 @@
+// Copyright (c) 2024-2025, The tinyavif contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+// C API for tinyavif, declared in include/tinyavif.h
+//
+// Every function which takes pointers is unsafe: the caller is responsible for passing
+// buffers of the sizes documented in the header. Panics are caught at this boundary and
+// reported as TINYAVIF_ERROR_INTERNAL, since unwinding into C is undefined behaviour.
+
+#![allow(clippy::missing_safety_doc)]
+
+use std::ffi::{c_char, c_int};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+
+use tinyavif::av1_encoder::{encode_frame, encode_from_rgb, EncodeError};
+use tinyavif::config::{ConfigError, EncoderConfig, Tune};
+use tinyavif::frame::Frame;
+
+// Must match TinyavifError in the header
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TinyavifError {
+  Ok = 0,
+  NullPointer = 1,
+  InvalidConfig = 2,
+  Unsupported = 3,
+  InvalidInput = 4,
+  Internal = 5,
+  TooLarge = 6,
+}
+
+// Must match TinyavifTune in the header
+pub const TINYAVIF_TUNE_PSNR: u8 = 0;
+pub const TINYAVIF_TUNE_SCREEN: u8 = 1;
+pub const TINYAVIF_TUNE_AUTO: u8 = 2;
+pub const TINYAVIF_TUNE_SSIM: u8 = 3;
+
+// Must match TinyavifConfig in the header
+// This is a fixed-layout subset of EncoderConfig, covering the settings which make
+// sense for a single image encoded in memory.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct TinyavifConfig {
+  pub qindex: u8,
+  pub speed: u8,
+  pub passes: u8,
+  pub tune: u8,
+  pub sharpness: u8,
+  pub chroma_dc_qoffset: i8,
+  pub chroma_ac_qoffset: i8,
+  pub denoise: u8,
+  pub sharpen: u8,
+  pub color_primaries: u16,
+  pub transfer_function: u16,
+  pub matrix_coefficients: u16,
+  pub full_range: u8,
+}
+
+impl TinyavifConfig {
+  fn from_config(config: &EncoderConfig) -> Self {
+    Self {
+      qindex: config.qindex,
+      speed: config.speed,
+      passes: config.passes,
+      tune: match config.tune {
+        Tune::Psnr => TINYAVIF_TUNE_PSNR,
+        Tune::Screen => TINYAVIF_TUNE_SCREEN,
+        Tune::Auto => TINYAVIF_TUNE_AUTO,
+        Tune::Ssim => TINYAVIF_TUNE_SSIM,
+      },
+      sharpness: config.sharpness,
+      chroma_dc_qoffset: config.chroma_dc_qoffset,
+      chroma_ac_qoffset: config.chroma_ac_qoffset,
+      denoise: config.denoise,
+      sharpen: config.sharpen,
+      color_primaries: config.color_primaries,
+      transfer_function: config.transfer_function,
+      matrix_coefficients: config.matrix_coefficients,
+      full_range: config.full_range as u8,
+    }
+  }
+
+  fn to_config(self) -> Result<EncoderConfig, TinyavifError> {
+    let tune = match self.tune {
+      TINYAVIF_TUNE_PSNR => Tune::Psnr,
+      TINYAVIF_TUNE_SCREEN => Tune::Screen,
+      TINYAVIF_TUNE_AUTO => Tune::Auto,
+      TINYAVIF_TUNE_SSIM => Tune::Ssim,
+      _ => return Err(TinyavifError::InvalidConfig),
+    };
+    EncoderConfig::builder()
+      .qindex(self.qindex)
+      .speed(self.speed)
+      .passes(self.passes)
+      .tune(tune)
+      .sharpness(self.sharpness)
+      .chroma_qoffset(self.chroma_dc_qoffset, self.chroma_ac_qoffset)
+      .denoise(self.denoise)
+      .sharpen(self.sharpen)
+      .color_description(self.color_primaries, self.transfer_function, self.matrix_coefficients)
+      .full_range(self.full_range != 0)
+      .build()
+      .map_err(|e| config_error(&e))
+  }
+}
+
+fn config_error(e: &ConfigError) -> TinyavifError {
+  match e {
+    ConfigError::Unsupported(_) => TinyavifError::Unsupported,
+    ConfigError::TooLarge(_) => TinyavifError::TooLarge,
+    ConfigError::Io(_) | ConfigError::Parse(_) | ConfigError::Invalid(_) => TinyavifError::InvalidConfig,
+  }
+}
+
+fn encode_error(e: &EncodeError) -> TinyavifError {
+  match e {
+    EncodeError::Config(e) => config_error(e),
+    EncodeError::InvalidInput(_) => TinyavifError::InvalidInput,
+    EncodeError::Cancelled | EncodeError::Io(_) => TinyavifError::Internal,
+  }
+}
+
+// Run an encode, catching any panics, and hand the result over to the caller
+unsafe fn run_encode<F>(config: *const TinyavifConfig, out_data: *mut *mut u8, out_size: *mut usize,
+                        encode: F) -> TinyavifError
+  where F: FnOnce(&EncoderConfig) -> Result<Vec<u8>, EncodeError> {
+  if config.is_null() || out_data.is_null() || out_size.is_null() {
+    return TinyavifError::NullPointer;
+  }
+  *out_data = ptr::null_mut();
+  *out_size = 0;
+
+  let config = match (*config).to_config() {
+    Ok(config) => config,
+    Err(e) => return e,
+  };
+  // Nothing is shared between encodes, so there's no state which a panic could leave broken
+  let result = match catch_unwind(AssertUnwindSafe(|| encode(&config))) {
+    Ok(result) => result,
+    Err(_) => return TinyavifError::Internal,
+  };
+  match result {
+    Ok(data) => {
+      let data = data.into_boxed_slice();
+      *out_size = data.len();
+      *out_data = Box::into_raw(data) as *mut u8;
+      TinyavifError::Ok
+    },
+    Err(e) => encode_error(&e),
+  }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tinyavif_config_default(config: *mut TinyavifConfig) {
+  if !config.is_null() {
+    *config = TinyavifConfig::from_config(&EncoderConfig::default());
+  }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tinyavif_encode_yuv420(y: *const u8, y_stride: usize, u: *const u8, u_stride: usize,
+                                                v: *const u8, v_stride: usize, width: u32, height: u32,
+                                                config: *const TinyavifConfig, out_data: *mut *mut u8,
+                                                out_size: *mut usize) -> TinyavifError {
+  if y.is_null() || u.is_null() || v.is_null() {
+    return TinyavifError::NullPointer;
+  }
+  let width = width as usize;
+  let height = height as usize;
+  let uv_width = width.div_ceil(2);
+  let uv_height = height.div_ceil(2);
+  if y_stride < width || u_stride < uv_width || v_stride < uv_width {
+    return TinyavifError::InvalidInput;
+  }
+
+  run_encode(config, out_data, out_size, |config| {
+    // The last row of each plane doesn't need to be padded out to the full stride
+    let plane = |data: *const u8, stride: usize, width: usize, height: usize| {
+      slice::from_raw_parts(data, if height == 0 { 0 } else { stride * (height - 1) + width })
+    };
+    let source = Frame::from_planes(plane(y, y_stride, width, height), plane(u, u_stride, uv_width, uv_height),
+                                    plane(v, v_stride, uv_width, uv_height), width, height,
+                                    [y_stride, u_stride, v_stride])
+      .map_err(|e| EncodeError::InvalidInput(e.to_string()))?;
+    encode_frame(&source, config)
+  })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tinyavif_encode_rgb(rgb: *const u8, stride: usize, width: u32, height: u32, channels: u32,
+                                             config: *const TinyavifConfig, out_data: *mut *mut u8,
+                                             out_size: *mut usize) -> TinyavifError {
+  if rgb.is_null() {
+    return TinyavifError::NullPointer;
+  }
+  let width = width as usize;
+  let height = height as usize;
+  let channels = channels as usize;
+  if (channels != 3 && channels != 4) || stride < width * channels {
+    return TinyavifError::InvalidInput;
+  }
+
+  run_encode(config, out_data, out_size, |config| {
+    let len = if height == 0 { 0 } else { stride * (height - 1) + width * channels };
+    let data = slice::from_raw_parts(rgb, len);
+    encode_from_rgb(data, width, height, stride, channels, config)
+  })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tinyavif_free(data: *mut u8, size: usize) {
+  if !data.is_null() {
+    drop(Box::from_raw(ptr::slice_from_raw_parts_mut(data, size)));
+  }
+}
+
+// Takes a plain int rather than a TinyavifError, as C callers can pass any value, and
+// a value which isn't one of the enum's would be undefined behaviour as a Rust enum
+#[no_mangle]
+pub extern "C" fn tinyavif_error_string(error: c_int) -> *const c_char {
+  let message: &'static [u8] = match error {
+    0 => b"Success\0",
+    1 => b"A required pointer was NULL\0",
+    2 => b"Invalid encoder settings\0",
+    3 => b"Encoder settings are not supported yet\0",
+    4 => b"Image data does not match the given size\0",
+    5 => b"Internal encoder error\0",
+    6 => b"Image is too large to encode with these settings\0",
+    _ => b"Unknown error\0",
+  };
+  message.as_ptr() as *const c_char
+}